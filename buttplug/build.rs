@@ -1,3 +1,5 @@
+use std::process::Command;
+
 fn main() {
   prost_build::compile_protos(
     &[
@@ -7,4 +9,25 @@ fn main() {
     &["src/device/protocol/thehandy"],
   )
   .unwrap();
+
+  // Record the git commit for BuildInfo, unless whoever is building us has
+  // already set it (packagers building from a tarball, for instance). Not
+  // being in a git checkout, or not having git, is fine.
+  //
+  // Asking to rerun on anything turns off rerunning on every file change, so
+  // list the inputs we still care about too.
+  println!("cargo:rerun-if-env-changed=BUTTPLUG_GIT_HASH");
+  println!("cargo:rerun-if-changed=build.rs");
+  println!("cargo:rerun-if-changed=src/device/protocol/thehandy");
+  if std::env::var("BUTTPLUG_GIT_HASH").is_err() {
+    if let Ok(output) = Command::new("git")
+      .args(&["rev-parse", "--short", "HEAD"])
+      .output()
+    {
+      let hash = String::from_utf8_lossy(&output.stdout).trim().to_owned();
+      if output.status.success() && !hash.is_empty() {
+        println!("cargo:rustc-env=BUTTPLUG_GIT_HASH={}", hash);
+      }
+    }
+  }
 }
@@ -8,14 +8,15 @@
 //! Implementation of internal Buttplug Client event loop.
 
 use super::{
-  client_message_sorter::ClientMessageSorter,
   device::{ButtplugClientDevice, ButtplugClientDeviceEvent},
-  ButtplugClientEvent, ButtplugClientMessageFuturePair,
+  ButtplugClientError, ButtplugClientEvent, ButtplugClientMessageFuturePair,
+  ButtplugServerMessageStateShared,
 };
 use crate::{
-  connector::{ButtplugConnector, ButtplugConnectorStateShared},
+  connector::{ButtplugConnector, ButtplugConnectorError, ButtplugConnectorStateShared},
   core::{
     errors::{ButtplugDeviceError, ButtplugError},
+    message_sorter::{ClientMessageSorter, SortedMessage},
     messages::{
       ButtplugCurrentSpecClientMessage, ButtplugCurrentSpecServerMessage, ButtplugDeviceMessage,
       ButtplugMessageValidator, DeviceList, DeviceMessageInfo,
@@ -38,6 +39,9 @@ pub(super) enum ButtplugClientRequest {
   /// Given a DeviceList message, update the inner loop values and create
   /// events for additions.
   HandleDeviceList(DeviceList),
+  /// Given the DeviceList received after reconnecting, drop devices the server
+  /// no longer has, add new ones, and tell the client which devices survived.
+  HandleReconnectDeviceList(DeviceList),
   /// Client request to send a message via the connector.
   ///
   /// Bundled future should have reply set and waker called when this is
@@ -45,6 +49,17 @@ pub(super) enum ButtplugClientRequest {
   Message(ButtplugClientMessageFuturePair),
 }
 
+/// Why a [ButtplugClientEventLoop] stopped running.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(super) enum ButtplugClientEventLoopExit {
+  /// The client disconnected or was dropped.
+  ClientDisconnect,
+  /// The connector closed underneath us. If the loop was created with
+  /// reconnection on, the device map was kept so it can be reconciled once a
+  /// new connection is up.
+  ConnectorClosed,
+}
+
 /// Event loop for running [ButtplugClient] connections.
 ///
 /// Acts as a hub for communication between the connector and [ButtplugClient]
@@ -93,7 +108,10 @@ where
   from_client_sender: broadcast::Sender<ButtplugClientRequest>,
   /// Receives incoming messages from client instances.
   from_client_receiver: broadcast::Receiver<ButtplugClientRequest>,
-  sorter: ClientMessageSorter,
+  sorter: ClientMessageSorter<ButtplugServerMessageStateShared>,
+  /// If true, keep devices around when the connector closes, as the client is
+  /// going to try to reconnect.
+  reconnect: bool,
 }
 
 impl<ConnectorType> ButtplugClientEventLoop<ConnectorType>
@@ -113,6 +131,7 @@ where
     to_client_sender: broadcast::Sender<ButtplugClientEvent>,
     from_client_sender: broadcast::Sender<ButtplugClientRequest>,
     device_map: Arc<DashMap<u32, Arc<ButtplugClientDevice>>>,
+    reconnect: bool,
   ) -> Self {
     trace!("Creating ButtplugClientEventLoop instance.");
     Self {
@@ -124,6 +143,7 @@ where
       from_connector_receiver,
       connector,
       sorter: ClientMessageSorter::default(),
+      reconnect,
     }
   }
 
@@ -185,6 +205,17 @@ where
     self.send_client_event(ButtplugClientEvent::DeviceRemoved(device));
   }
 
+  /// Marks all devices as belonging to a disconnected client, and lets their
+  /// event streams know.
+  fn disconnect_client_devices(&mut self) {
+    self.device_map.iter().for_each(|device| {
+      device.value().set_client_connected(false);
+      device
+        .value()
+        .queue_event(ButtplugClientDeviceEvent::ClientDisconnect);
+    });
+  }
+
   /// Parse device messages from the connector.
   ///
   /// Since the event loop maintains the state of all devices reported from the
@@ -192,16 +223,40 @@ where
   /// and update its map accordingly. After that, it will pass the information
   /// on as a [ButtplugClientEvent] to the [ButtplugClient].
   async fn parse_connector_message(&mut self, msg: ButtplugCurrentSpecServerMessage) {
-    if self.sorter.maybe_resolve_result(&msg) {
-      trace!("Message future found, returning");
-      return;
-    }
+    let msg = match self.sorter.sort(msg) {
+      SortedMessage::Event(msg) => msg,
+      SortedMessage::Reply(msg, mut waker) => {
+        trace!("Message future found, returning");
+        if let Err(e) = msg.is_valid() {
+          error!("Message not valid: {:?} - Error: {}", msg, e);
+          waker.set_reply(Err(ButtplugClientError::ButtplugError(e.into())));
+        } else if let ButtplugCurrentSpecServerMessage::Error(e) = msg {
+          waker.set_reply(Err(e.original_error().into()))
+        } else {
+          waker.set_reply(Ok(msg))
+        }
+        return;
+      }
+      SortedMessage::Orphan(msg) => {
+        error!("Dropping reply to a request we aren't waiting on: {:?}", msg);
+        return;
+      }
+    };
     if let Err(e) = msg.is_valid() {
       error!("Message not valid: {:?} - Error: {}", msg, e);
       self.send_client_event(ButtplugClientEvent::Error(ButtplugError::from(e)));
       return;
     }
     trace!("Message future not found, assuming server event.");
+    if let ButtplugCurrentSpecServerMessage::Log(log) = msg {
+      // Don't log these. If the server is in process, it could be forwarding
+      // our own log records back to us.
+      self.send_client_event(ButtplugClientEvent::Log(
+        log.log_level().clone(),
+        log.log_message().clone(),
+      ));
+      return;
+    }
     info!("{:?}", msg);
     match msg {
       ButtplugCurrentSpecServerMessage::DeviceAdded(dev) => {
@@ -244,6 +299,15 @@ where
             ));
         }
       }
+      ButtplugCurrentSpecServerMessage::SensorReading(msg) => {
+        if let Some(device) = self.device_map.get(&msg.device_index()) {
+          device
+            .value()
+            .queue_event(ButtplugClientDeviceEvent::Message(
+              ButtplugCurrentSpecServerMessage::from(msg),
+            ));
+        }
+      }
       ButtplugCurrentSpecServerMessage::Error(e) => {
         self.send_client_event(ButtplugClientEvent::Error(e.into()));
       }
@@ -262,7 +326,7 @@ where
     }
 
     trace!("Sending message to connector: {:?}", msg_fut.msg);
-    self.sorter.register_future(&mut msg_fut);
+    self.sorter.register(&mut msg_fut.msg, msg_fut.waker.clone());
     // TODO What happens if the connector isn't connected?
     self.connector.send(msg_fut.msg).await.unwrap();
   }
@@ -298,19 +362,58 @@ where
         }
         true
       }
+      ButtplugClientRequest::HandleReconnectDeviceList(device_list) => {
+        trace!("Device list received after reconnect, reconciling map.");
+        let stale_indexes: Vec<u32> = self
+          .device_map
+          .iter()
+          .filter(|device| {
+            !device_list
+              .devices()
+              .iter()
+              .any(|info| device.value().matches_device_info(info))
+          })
+          .map(|device| *device.key())
+          .collect();
+        stale_indexes
+          .iter()
+          .for_each(|index| self.disconnect_device(*index));
+        let mut survivors = vec![];
+        for d in device_list.devices() {
+          if let Some(device) = self.device_map.get(&d.device_index) {
+            device.value().set_client_connected(true);
+            survivors.push(device.value().clone());
+            continue;
+          }
+          let device = self.create_client_device(&d);
+          self.send_client_event(ButtplugClientEvent::DeviceAdded(device));
+        }
+        self.send_client_event(ButtplugClientEvent::Reconnected(survivors));
+        true
+      }
     }
   }
 
   /// Runs the event loop, returning once either the client or connector drops.
-  pub async fn run(&mut self) {
+  pub async fn run(&mut self) -> ButtplugClientEventLoopExit {
     debug!("Running client event loop.");
     loop {
       select! {
         event = self.from_connector_receiver.recv().fuse() => match event {
           None => {
             info!("Connector disconnected, exiting loop.");
-            self.send_client_event(ButtplugClientEvent::ServerDisconnect);
-            return;
+            for (_, mut waker) in self.sorter.cancel_all() {
+              waker.set_reply(Err(ButtplugConnectorError::ConnectorChannelClosed.into()));
+            }
+            if self.reconnect {
+              self.connected_status.store(false, Ordering::SeqCst);
+              self.device_map.iter().for_each(|val| val.value().set_client_connected(false));
+              self.send_client_event(ButtplugClientEvent::Reconnecting);
+            } else {
+              self.disconnect_client_devices();
+              self.send_client_event(ButtplugClientEvent::ServerDisconnect);
+            }
+            return ButtplugClientEventLoopExit::ConnectorClosed;
           }
           Some(msg) => {
             self.parse_connector_message(msg).await;
@@ -320,9 +423,9 @@ where
           Err(_) => {
             info!("Client disconnected, exiting loop.");
             self.connected_status.store(false, Ordering::SeqCst);
-            self.device_map.iter().for_each(|val| val.value().set_client_connected(false));
+            self.disconnect_client_devices();
             self.send_client_event(ButtplugClientEvent::ServerDisconnect);
-            return;
+            return ButtplugClientEventLoopExit::ClientDisconnect;
           }
           Ok(msg) => {
             if !self.parse_client_request(msg).await {
@@ -341,5 +444,6 @@ where
     self.send_client_event(ButtplugClientEvent::ServerDisconnect);
 
     debug!("Exiting client event loop.");
+    ButtplugClientEventLoopExit::ClientDisconnect
   }
 }
@@ -18,11 +18,12 @@ use crate::{
       ButtplugCurrentSpecServerMessage, ButtplugMessage, DeviceMessageAttributes,
       DeviceMessageAttributesMap, DeviceMessageInfo, LinearCmd, RSSILevelCmd, RawReadCmd,
       RawSubscribeCmd, RawUnsubscribeCmd, RawWriteCmd, RotateCmd, RotationSubcommand,
-      StopDeviceCmd, VectorSubcommand, VibrateCmd, VibrateSubcommand,
+      SensorSubscribeCmd, SensorUnsubscribeCmd, StopDeviceCmd, VectorSubcommand, VibrateCmd,
+      VibrateSubcommand,
     },
   },
   device::Endpoint,
-  util::stream::convert_broadcast_receiver_to_stream,
+  util::stream::convert_broadcast_receiver_to_filtered_stream,
 };
 use futures::{future, Stream};
 use std::{
@@ -30,9 +31,10 @@ use std::{
   convert::TryFrom,
   fmt,
   sync::{
-    atomic::{AtomicBool, Ordering},
-    Arc,
+    atomic::{AtomicBool, AtomicU64, Ordering},
+    Arc, Mutex,
   },
+  time::{Duration, Instant},
 };
 use tokio::sync::broadcast;
 use tracing_futures::Instrument;
@@ -113,6 +115,64 @@ macro_rules! check_message_support {
   };
 }
 
+// Raw messages are only in the allowed messages if the server allows them,
+// and then only for the endpoints the device has, so check both before
+// sending anything.
+macro_rules! check_raw_endpoint {
+  ($self:ident, $msg:expr, $endpoint:expr) => {
+    match $self.allowed_messages.get(&$msg) {
+      None => {
+        return $self.create_boxed_future_client_error(
+          ButtplugDeviceError::RawMessagesNotAllowed($self.name.clone()).into(),
+        );
+      }
+      Some(attributes) => {
+        if !attributes
+          .endpoints
+          .as_ref()
+          .map_or(false, |endpoints| endpoints.contains(&$endpoint))
+        {
+          return $self.create_boxed_future_client_error(
+            ButtplugDeviceError::InvalidEndpoint($endpoint).into(),
+          );
+        }
+      }
+    }
+  };
+}
+
+/// Last value read from a device, along with when it was read.
+struct CachedReading<T: Copy> {
+  reading: Mutex<Option<(T, Instant)>>,
+}
+
+impl<T: Copy> Default for CachedReading<T> {
+  fn default() -> Self {
+    Self {
+      reading: Mutex::new(None),
+    }
+  }
+}
+
+impl<T: Copy> CachedReading<T> {
+  fn set(&self, value: T) {
+    *self.reading.lock().unwrap() = Some((value, Instant::now()));
+  }
+
+  fn last(&self) -> Option<T> {
+    self.reading.lock().unwrap().map(|(value, _)| value)
+  }
+
+  fn fresh(&self, max_age: Duration) -> Option<T> {
+    self
+      .reading
+      .lock()
+      .unwrap()
+      .filter(|(_, read_at)| read_at.elapsed() <= max_age)
+      .map(|(value, _)| value)
+  }
+}
+
 pub type ButtplugClientDeviceMessageType = ButtplugCurrentSpecDeviceMessageType;
 pub type ClientDeviceMessageAttributesMap =
   HashMap<ButtplugCurrentSpecDeviceMessageType, DeviceMessageAttributes>;
@@ -158,6 +218,13 @@ pub struct ButtplugClientDevice {
   /// [ButtplugClientDevice] instance is still connected to the
   /// [ButtplugServer][crate::server::ButtplugServer].
   client_connected: Arc<AtomicBool>,
+  /// Bumped every time the device is stopped through the client, so
+  /// wrappers that remember what they've sent (like
+  /// [ButtplugClientDeviceThrottle][super::ButtplugClientDeviceThrottle]) know
+  /// to forget it.
+  stop_count: Arc<AtomicU64>,
+  battery_level: Arc<CachedReading<f64>>,
+  rssi_level: Arc<CachedReading<i32>>,
 }
 
 unsafe impl Send for ButtplugClientDevice {}
@@ -199,6 +266,9 @@ impl ButtplugClientDevice {
       internal_event_sender: event_sender,
       device_connected,
       client_connected,
+      stop_count: Arc::new(AtomicU64::new(0)),
+      battery_level: Arc::new(CachedReading::default()),
+      rssi_level: Arc::new(CachedReading::default()),
     }
   }
 
@@ -264,9 +334,26 @@ impl ButtplugClientDevice {
     )
   }
 
+  /// Stream of events for this device only: removal, client disconnection,
+  /// and readings the server sends for it.
   pub fn event_stream(&self) -> Box<dyn Stream<Item = ButtplugClientDeviceEvent> + Send + Unpin> {
-    Box::new(Box::pin(convert_broadcast_receiver_to_stream(
+    Box::new(Box::pin(convert_broadcast_receiver_to_filtered_stream(
       self.internal_event_sender.subscribe(),
+      Some,
+    )))
+  }
+
+  /// Stream of just the messages the server sends for this device, i.e.
+  /// sensor and raw readings.
+  pub fn message_stream(
+    &self,
+  ) -> Box<dyn Stream<Item = ButtplugCurrentSpecServerMessage> + Send + Unpin> {
+    Box::new(Box::pin(convert_broadcast_receiver_to_filtered_stream(
+      self.internal_event_sender.subscribe(),
+      |event| match event {
+        ButtplugClientDeviceEvent::Message(msg) => Some(msg),
+        _ => None,
+      },
     )))
   }
 
@@ -457,13 +544,17 @@ impl ButtplugClientDevice {
     self.send_message_expect_ok(msg)
   }
 
+  /// Reads the battery level from the device, as a value from 0.0 (empty) to
+  /// 1.0 (full).
   pub fn battery_level(&self) -> ButtplugClientResultFuture<f64> {
     check_message_support!(self, ButtplugCurrentSpecDeviceMessageType::BatteryLevelCmd);
     let msg = ButtplugCurrentSpecClientMessage::BatteryLevelCmd(BatteryLevelCmd::new(self.index));
     let send_fut = self.send_message(msg);
+    let cache = self.battery_level.clone();
     Box::pin(async move {
       match send_fut.await? {
         ButtplugCurrentSpecServerMessage::BatteryLevelReading(reading) => {
+          cache.set(reading.battery_level());
           Ok(reading.battery_level())
         }
         ButtplugCurrentSpecServerMessage::Error(err) => Err(ButtplugError::from(err).into()),
@@ -478,13 +569,36 @@ impl ButtplugClientDevice {
     })
   }
 
+  /// Like [ButtplugClientDevice::battery_level], but returns the last level
+  /// read if it's no older than `max_age`, instead of asking the device again.
+  /// Battery levels change slowly, so UIs polling this can save themselves a
+  /// lot of device traffic.
+  pub fn cached_battery_level(&self, max_age: Duration) -> ButtplugClientResultFuture<f64> {
+    match self.battery_level.fresh(max_age) {
+      Some(level) => Box::pin(future::ready(Ok(level))),
+      None => self.battery_level(),
+    }
+  }
+
+  /// The last battery level read from the device, if any, without asking the
+  /// device.
+  pub fn last_battery_level(&self) -> Option<f64> {
+    self.battery_level.last()
+  }
+
+  /// Reads the signal strength of the device, in dBm. Values are negative,
+  /// with values closer to 0 meaning a stronger signal.
   pub fn rssi_level(&self) -> ButtplugClientResultFuture<i32> {
     check_message_support!(self, ButtplugCurrentSpecDeviceMessageType::RSSILevelCmd);
     let msg = ButtplugCurrentSpecClientMessage::RSSILevelCmd(RSSILevelCmd::new(self.index));
     let send_fut = self.send_message(msg);
+    let cache = self.rssi_level.clone();
     Box::pin(async move {
       match send_fut.await? {
-        ButtplugCurrentSpecServerMessage::RSSILevelReading(reading) => Ok(reading.rssi_level()),
+        ButtplugCurrentSpecServerMessage::RSSILevelReading(reading) => {
+          cache.set(reading.rssi_level());
+          Ok(reading.rssi_level())
+        }
         ButtplugCurrentSpecServerMessage::Error(err) => Err(ButtplugError::from(err).into()),
         msg => Err(
           ButtplugError::from(ButtplugMessageError::UnexpectedMessageType(format!(
@@ -497,13 +611,50 @@ impl ButtplugClientDevice {
     })
   }
 
+  /// Like [ButtplugClientDevice::rssi_level], but returns the last level read
+  /// if it's no older than `max_age`.
+  pub fn cached_rssi_level(&self, max_age: Duration) -> ButtplugClientResultFuture<i32> {
+    match self.rssi_level.fresh(max_age) {
+      Some(level) => Box::pin(future::ready(Ok(level))),
+      None => self.rssi_level(),
+    }
+  }
+
+  /// The last signal strength read from the device, if any, without asking
+  /// the device.
+  pub fn last_rssi_level(&self) -> Option<i32> {
+    self.rssi_level.last()
+  }
+
+  /// True if the server allows raw messages to this device. Servers only do
+  /// so if they were started with raw messages enabled.
+  pub fn raw_messages_allowed(&self) -> bool {
+    self
+      .allowed_messages
+      .contains_key(&ButtplugCurrentSpecDeviceMessageType::RawWriteCmd)
+  }
+
+  /// Endpoints that raw messages can be sent to. Empty if raw messages aren't
+  /// allowed.
+  pub fn raw_endpoints(&self) -> Vec<Endpoint> {
+    self
+      .allowed_messages
+      .get(&ButtplugCurrentSpecDeviceMessageType::RawWriteCmd)
+      .and_then(|attributes| attributes.endpoints.clone())
+      .unwrap_or_default()
+  }
+
   pub fn raw_write(
     &self,
     endpoint: Endpoint,
     data: Vec<u8>,
     write_with_response: bool,
   ) -> ButtplugClientResultFuture {
-    check_message_support!(self, ButtplugCurrentSpecDeviceMessageType::RawWriteCmd);
+    check_raw_endpoint!(
+      self,
+      ButtplugCurrentSpecDeviceMessageType::RawWriteCmd,
+      endpoint
+    );
     let msg = ButtplugCurrentSpecClientMessage::RawWriteCmd(RawWriteCmd::new(
       self.index,
       endpoint,
@@ -519,7 +670,11 @@ impl ButtplugClientDevice {
     expected_length: u32,
     timeout: u32,
   ) -> ButtplugClientResultFuture<Vec<u8>> {
-    check_message_support!(self, ButtplugCurrentSpecDeviceMessageType::RawReadCmd);
+    check_raw_endpoint!(
+      self,
+      ButtplugCurrentSpecDeviceMessageType::RawReadCmd,
+      endpoint
+    );
     let msg = ButtplugCurrentSpecClientMessage::RawReadCmd(RawReadCmd::new(
       self.index,
       endpoint,
@@ -543,16 +698,21 @@ impl ButtplugClientDevice {
   }
 
   pub fn raw_subscribe(&self, endpoint: Endpoint) -> ButtplugClientResultFuture {
-    check_message_support!(self, ButtplugCurrentSpecDeviceMessageType::RawSubscribeCmd);
+    check_raw_endpoint!(
+      self,
+      ButtplugCurrentSpecDeviceMessageType::RawSubscribeCmd,
+      endpoint
+    );
     let msg =
       ButtplugCurrentSpecClientMessage::RawSubscribeCmd(RawSubscribeCmd::new(self.index, endpoint));
     self.send_message_expect_ok(msg)
   }
 
   pub fn raw_unsubscribe(&self, endpoint: Endpoint) -> ButtplugClientResultFuture {
-    check_message_support!(
+    check_raw_endpoint!(
       self,
-      ButtplugCurrentSpecDeviceMessageType::RawUnsubscribeCmd
+      ButtplugCurrentSpecDeviceMessageType::RawUnsubscribeCmd,
+      endpoint
     );
     let msg = ButtplugCurrentSpecClientMessage::RawUnsubscribeCmd(RawUnsubscribeCmd::new(
       self.index, endpoint,
@@ -560,10 +720,41 @@ impl ButtplugClientDevice {
     self.send_message_expect_ok(msg)
   }
 
+  /// Subscribes to a device sensor. Readings arrive as
+  /// [SensorReading][crate::core::messages::SensorReading] messages on the
+  /// device event stream until unsubscribed.
+  pub fn subscribe_sensor(&self, sensor_index: u32) -> ButtplugClientResultFuture {
+    check_message_support!(
+      self,
+      ButtplugCurrentSpecDeviceMessageType::SensorSubscribeCmd
+    );
+    let msg = ButtplugCurrentSpecClientMessage::SensorSubscribeCmd(SensorSubscribeCmd::new(
+      self.index,
+      sensor_index,
+    ));
+    self.send_message_expect_ok(msg)
+  }
+
+  /// Ends a subscription started with
+  /// [subscribe_sensor][ButtplugClientDevice::subscribe_sensor]. No more
+  /// readings for the sensor are sent once this resolves.
+  pub fn unsubscribe_sensor(&self, sensor_index: u32) -> ButtplugClientResultFuture {
+    check_message_support!(
+      self,
+      ButtplugCurrentSpecDeviceMessageType::SensorSubscribeCmd
+    );
+    let msg = ButtplugCurrentSpecClientMessage::SensorUnsubscribeCmd(SensorUnsubscribeCmd::new(
+      self.index,
+      sensor_index,
+    ));
+    self.send_message_expect_ok(msg)
+  }
+
   /// Commands device to stop all movement.
   pub fn stop(&self) -> ButtplugClientResultFuture {
     // Everything *should* support StopDeviceCmd but let's just make sure.
     check_message_support!(self, ButtplugCurrentSpecDeviceMessageType::StopDeviceCmd);
+    self.record_stop();
     // All devices accept StopDeviceCmd
     self.send_message_expect_ok(StopDeviceCmd::new(self.index).into())
   }
@@ -580,6 +771,23 @@ impl ButtplugClientDevice {
     self.client_connected.store(connected, Ordering::SeqCst);
   }
 
+  pub(super) fn record_stop(&self) {
+    self.stop_count.fetch_add(1, Ordering::SeqCst);
+  }
+
+  /// Number of times the device has been stopped through the client.
+  pub(super) fn stop_count(&self) -> u64 {
+    self.stop_count.load(Ordering::SeqCst)
+  }
+
+  /// True if the server is describing this same device, i.e. same index, name
+  /// and messages. Used on reconnect to decide which devices survived.
+  pub(super) fn matches_device_info(&self, info: &DeviceMessageInfo) -> bool {
+    self.index == info.device_index
+      && self.name == info.device_name
+      && self.allowed_messages == convert_to_client_device_map(&info.device_messages)
+  }
+
   pub(super) fn queue_event(&self, event: ButtplugClientDeviceEvent) {
     if self.internal_event_sender.receiver_count() == 0 {
       error!("No handlers for device event, dropping event: {:?}", event);
@@ -0,0 +1,280 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2022 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Firmware updates over raw endpoints.
+//!
+//! Raw writes are all it takes to push a firmware image to a device, but it's
+//! easy to get wrong: images have to be split into writes the transport can
+//! take, BLE stacks drop writes without response that come in faster than the
+//! device can handle, and a half written image can leave a device unusable.
+//! [ButtplugClientFirmwareUpdater] takes care of chunking, write types and
+//! read back verification, and reports progress along the way, so DFU tools
+//! for supported devices can be built on top of it.
+//!
+//! This only moves bytes. Getting the device into its bootloader, image
+//! formats, and any commands needed around the transfer are up to the tool,
+//! using the usual raw messages. The server has to allow raw messages.
+
+use super::{device::ButtplugClientDevice, ButtplugClientError, ButtplugClientResultFuture};
+use crate::{
+  core::errors::{ButtplugDeviceError, ButtplugError},
+  device::{Endpoint, WriteType},
+  util::{async_manager, stream::convert_broadcast_receiver_to_stream},
+};
+use futures::{future, Stream};
+use std::{iter, sync::Arc, time::Duration};
+use tokio::sync::broadcast;
+
+/// Chunk size used unless another one is set. Fits in a single BLE write at
+/// the default MTU, so it works everywhere, if slowly.
+pub const DEFAULT_FIRMWARE_CHUNK_SIZE: usize = 20;
+// With automatic write types, every this many chunks one is written with
+// response, so the device gets a chance to catch up.
+const AUTO_WRITE_ACK_INTERVAL: usize = 16;
+
+/// Progress of a firmware update. Chunks are counted from 0.
+#[derive(Clone, Debug, PartialEq)]
+pub enum FirmwareUpdateEvent {
+  Started {
+    total_bytes: usize,
+    total_chunks: usize,
+  },
+  /// The chunk was written. `bytes_written` counts every chunk so far.
+  ChunkWritten {
+    chunk: usize,
+    bytes_written: usize,
+  },
+  /// The chunk was read back, and passed verification.
+  ChunkVerified {
+    chunk: usize,
+  },
+  Finished,
+  /// The update stopped at `chunk`. The device may be left with part of the
+  /// new image.
+  Failed {
+    chunk: usize,
+    error: String,
+  },
+}
+
+type VerifyFn = dyn Fn(usize, &[u8], &[u8]) -> Result<(), String> + Send + Sync;
+
+#[derive(Clone)]
+struct Verification {
+  endpoint: Endpoint,
+  timeout_ms: u32,
+  check: Arc<VerifyFn>,
+}
+
+/// Writes firmware images to a device endpoint in chunks.
+///
+/// Unless a write type is set, chunks are written without response for
+/// speed, except for every 16th chunk and the last one, which are written
+/// with response so the device can't fall behind, and the update only
+/// finishes once the device has the whole image. With verification on, every
+/// chunk is written with response before it's read back.
+///
+/// Everything that can be checked up front (raw messages being allowed, the
+/// endpoints existing, the image not being empty) is checked before anything
+/// is written. The update stops at the first write, read or verification
+/// that fails.
+#[derive(Clone)]
+pub struct ButtplugClientFirmwareUpdater {
+  device: Arc<ButtplugClientDevice>,
+  endpoint: Endpoint,
+  chunk_size: usize,
+  write_type: Option<WriteType>,
+  chunk_delay: Duration,
+  verification: Option<Verification>,
+  event_sender: broadcast::Sender<FirmwareUpdateEvent>,
+}
+
+impl ButtplugClientFirmwareUpdater {
+  /// Creates an updater writing to `endpoint`, usually
+  /// [Endpoint::Firmware].
+  pub fn new(device: Arc<ButtplugClientDevice>, endpoint: Endpoint) -> Self {
+    let (event_sender, _) = broadcast::channel(256);
+    Self {
+      device,
+      endpoint,
+      chunk_size: DEFAULT_FIRMWARE_CHUNK_SIZE,
+      write_type: None,
+      chunk_delay: Duration::ZERO,
+      verification: None,
+      event_sender,
+    }
+  }
+
+  /// Sets the most bytes sent per write.
+  pub fn chunk_size(mut self, chunk_size: usize) -> Self {
+    self.chunk_size = chunk_size;
+    self
+  }
+
+  /// Writes every chunk with this write type, instead of picking one.
+  pub fn write_type(mut self, write_type: WriteType) -> Self {
+    self.write_type = Some(write_type);
+    self
+  }
+
+  /// Waits this long after each chunk, for devices that need time to commit
+  /// chunks to flash.
+  pub fn chunk_delay(mut self, chunk_delay: Duration) -> Self {
+    self.chunk_delay = chunk_delay;
+    self
+  }
+
+  /// Reads back each chunk from `endpoint` after it's written, and passes
+  /// the chunk index, what was written and what was read to `check`. Errors
+  /// returned by `check` stop the update. What a device returns on read back
+  /// is up to the device (the chunk itself, a checksum, a status byte...),
+  /// so `check` has to know how to compare them.
+  pub fn verify_with<F>(mut self, endpoint: Endpoint, timeout_ms: u32, check: F) -> Self
+  where
+    F: Fn(usize, &[u8], &[u8]) -> Result<(), String> + Send + Sync + 'static,
+  {
+    self.verification = Some(Verification {
+      endpoint,
+      timeout_ms,
+      check: Arc::new(check),
+    });
+    self
+  }
+
+  /// Stream of [FirmwareUpdateEvent]s for updates run by this updater.
+  pub fn event_stream(&self) -> impl Stream<Item = FirmwareUpdateEvent> {
+    convert_broadcast_receiver_to_stream(self.event_sender.subscribe())
+  }
+
+  /// Writes `image` to the device.
+  pub fn update(&self, image: &[u8]) -> ButtplugClientResultFuture {
+    if let Err(err) = self.check_update(image) {
+      return Box::pin(future::ready(Err(err.into())));
+    }
+    let updater = self.clone();
+    let image = image.to_vec();
+    Box::pin(async move { updater.transfer(&image).await })
+  }
+
+  fn check_update(&self, image: &[u8]) -> Result<(), ButtplugError> {
+    if image.is_empty() {
+      return Err(
+        ButtplugDeviceError::FirmwareUpdateError("Firmware image is empty".to_owned()).into(),
+      );
+    }
+    if self.chunk_size == 0 {
+      return Err(
+        ButtplugDeviceError::FirmwareUpdateError("Chunk size has to be at least 1".to_owned())
+          .into(),
+      );
+    }
+    if !self.device.raw_messages_allowed() {
+      return Err(ButtplugDeviceError::RawMessagesNotAllowed(self.device.name.clone()).into());
+    }
+    let endpoints = self.device.raw_endpoints();
+    let verify_endpoint = self.verification.as_ref().map(|verify| verify.endpoint);
+    for endpoint in iter::once(self.endpoint).chain(verify_endpoint) {
+      if !endpoints.contains(&endpoint) {
+        return Err(ButtplugDeviceError::InvalidEndpoint(endpoint).into());
+      }
+    }
+    Ok(())
+  }
+
+  fn write_type_for(&self, chunk: usize, total_chunks: usize) -> WriteType {
+    if let Some(write_type) = self.write_type {
+      return write_type;
+    }
+    if self.verification.is_some()
+      || chunk + 1 == total_chunks
+      || (chunk + 1) % AUTO_WRITE_ACK_INTERVAL == 0
+    {
+      WriteType::WithResponse
+    } else {
+      WriteType::WithoutResponse
+    }
+  }
+
+  async fn transfer(&self, image: &[u8]) -> Result<(), ButtplugClientError> {
+    let total_chunks = (image.len() + self.chunk_size - 1) / self.chunk_size;
+    info!(
+      "Updating firmware of {}: {} bytes in {} chunks",
+      self.device.name,
+      image.len(),
+      total_chunks
+    );
+    self.send_event(FirmwareUpdateEvent::Started {
+      total_bytes: image.len(),
+      total_chunks,
+    });
+    let mut bytes_written = 0;
+    for (index, chunk) in image.chunks(self.chunk_size).enumerate() {
+      bytes_written += chunk.len();
+      if let Err(err) = self
+        .transfer_chunk(index, chunk, total_chunks, bytes_written)
+        .await
+      {
+        error!(
+          "Firmware update of {} failed at chunk {}: {}",
+          self.device.name, index, err
+        );
+        self.send_event(FirmwareUpdateEvent::Failed {
+          chunk: index,
+          error: err.to_string(),
+        });
+        return Err(err);
+      }
+      if !self.chunk_delay.is_zero() {
+        async_manager::sleep(self.chunk_delay).await;
+      }
+    }
+    info!("Firmware update of {} finished", self.device.name);
+    self.send_event(FirmwareUpdateEvent::Finished);
+    Ok(())
+  }
+
+  async fn transfer_chunk(
+    &self,
+    index: usize,
+    chunk: &[u8],
+    total_chunks: usize,
+    bytes_written: usize,
+  ) -> Result<(), ButtplugClientError> {
+    let write_type = self.write_type_for(index, total_chunks);
+    self
+      .device
+      .raw_write(self.endpoint, chunk.to_vec(), write_type.with_response())
+      .await?;
+    self.send_event(FirmwareUpdateEvent::ChunkWritten {
+      chunk: index,
+      bytes_written,
+    });
+    if let Some(verification) = &self.verification {
+      let read_back = self
+        .device
+        .raw_read(
+          verification.endpoint,
+          chunk.len() as u32,
+          verification.timeout_ms,
+        )
+        .await?;
+      (verification.check)(index, chunk, &read_back).map_err(|reason| {
+        ButtplugError::from(ButtplugDeviceError::FirmwareUpdateError(format!(
+          "Verification of chunk {} failed: {}",
+          index, reason
+        )))
+      })?;
+      self.send_event(FirmwareUpdateEvent::ChunkVerified { chunk: index });
+    }
+    Ok(())
+  }
+
+  fn send_event(&self, event: FirmwareUpdateEvent) {
+    // No one listening is fine.
+    let _ = self.event_sender.send(event);
+  }
+}
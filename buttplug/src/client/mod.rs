@@ -7,23 +7,32 @@
 
 //! Communications API for accessing Buttplug Servers
 pub mod client_event_loop;
-mod client_message_sorter;
 pub mod device;
+mod firmware_update;
+mod throttle;
 
-use client_event_loop::{ButtplugClientEventLoop, ButtplugClientRequest};
+use client_event_loop::{
+  ButtplugClientEventLoop, ButtplugClientEventLoopExit, ButtplugClientRequest,
+};
 pub use device::{
   ButtplugClientDevice, ButtplugClientDeviceEvent, ButtplugClientDeviceMessageType, LinearCommand,
   RotateCommand, VibrateCommand,
 };
+pub use firmware_update::{
+  ButtplugClientFirmwareUpdater, FirmwareUpdateEvent, DEFAULT_FIRMWARE_CHUNK_SIZE,
+};
+pub use throttle::ButtplugClientDeviceThrottle;
 
 use crate::{
-  connector::{ButtplugConnector, ButtplugConnectorError, ButtplugConnectorFuture},
+  connector::{
+    ButtplugConnector, ButtplugConnectorError, ButtplugConnectorFuture, ButtplugReconnectOptions,
+  },
   core::{
     errors::{ButtplugError, ButtplugHandshakeError},
     messages::{
-      ButtplugCurrentSpecClientMessage, ButtplugCurrentSpecServerMessage,
-      ButtplugMessageSpecVersion, Ping, RequestDeviceList, RequestServerInfo, StartScanning,
-      StopAllDevices, StopScanning,
+      ButtplugCurrentSpecClientMessage, ButtplugCurrentSpecServerMessage, LogLevel, Ping,
+      RequestDeviceList, RequestLog, RequestServerInfo, StartScanning, StopAllDevices,
+      StopScanning, BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION,
     },
   },
   util::{
@@ -34,12 +43,12 @@ use crate::{
 };
 use dashmap::DashMap;
 use futures::{
-  future::{self, BoxFuture},
+  future::{self, AbortHandle, Abortable, BoxFuture},
   Stream,
 };
 use std::sync::{
   atomic::{AtomicBool, Ordering},
-  Arc,
+  Arc, Mutex as StdMutex,
 };
 use thiserror::Error;
 use tokio::sync::{broadcast, mpsc, Mutex};
@@ -77,7 +86,7 @@ pub(crate) type ButtplugServerMessageFuture = ButtplugFuture<ButtplugServerMessa
 /// [ButtplugClientMessageFuturePair] type. We can then expect the connector to
 /// get the response from the server, match it with our message (using something
 /// like the
-/// [ClientConnectorMessageSorter][crate::connector::ClientConnectorMessageSorter]),
+/// [ClientMessageSorter][crate::core::message_sorter::ClientMessageSorter]),
 /// and set the reply in the waker we've sent along. This will resolve the
 /// future we're waiting on and allow us to continue execution.
 #[derive(Clone)]
@@ -136,9 +145,21 @@ pub enum ButtplugClientEvent {
   ServerConnect,
   /// Emitted when a client connector detects that the server has disconnected.
   ServerDisconnect,
+  /// Emitted when the connection drops on a client connected with
+  /// [ButtplugClient::connect_with_reconnect]. Devices stay in the device list
+  /// but can't be used until the client is reconnected.
+  Reconnecting,
+  /// Emitted once a reconnecting client is connected again and has refreshed
+  /// its device list. Includes the devices that are still the same on the
+  /// server, which can be used again as is. Devices that went away get a
+  /// DeviceRemoved event and new ones a DeviceAdded event before this.
+  Reconnected(Vec<Arc<ButtplugClientDevice>>),
   /// Emitted when an error that cannot be matched to a request is received from
   /// the server.
   Error(ButtplugError),
+  /// Emitted for each log record the server forwards, after logs have been
+  /// requested with [ButtplugClient::request_log].
+  Log(LogLevel, String),
 }
 
 impl Unpin for ButtplugClientEvent {}
@@ -171,8 +192,18 @@ pub struct ButtplugClient {
   connected: Arc<AtomicBool>,
   _client_span: Arc<Mutex<Option<Span>>>,
   device_map: Arc<DashMap<u32, Arc<ButtplugClientDevice>>>,
+  /// True while trying to reconnect after the connection dropped.
+  reconnecting: Arc<AtomicBool>,
+  /// Stops the running reconnect task, if any.
+  reconnect_abort: Arc<StdMutex<Option<AbortHandle>>>,
+  /// Stops the event loop of the latest connection, dropping its connector.
+  event_loop_abort: Arc<StdMutex<Option<AbortHandle>>>,
 }
 
+/// Called by the client event loop when its connector closes, to start
+/// reconnecting.
+type ButtplugReconnectHandler = Arc<dyn Fn() -> BoxFuture<'static, ()> + Send + Sync>;
+
 unsafe impl Send for ButtplugClient {}
 // Not actually sure this should be sync, but trying to call handshake breaks
 // without it.
@@ -190,23 +221,195 @@ impl ButtplugClient {
       _client_span: Arc::new(Mutex::new(None)),
       connected: Arc::new(AtomicBool::new(false)),
       device_map: Arc::new(DashMap::new()),
+      reconnecting: Arc::new(AtomicBool::new(false)),
+      reconnect_abort: Arc::new(StdMutex::new(None)),
+      event_loop_abort: Arc::new(StdMutex::new(None)),
+    }
+  }
+
+  /// Another handle to the same client state, for tasks that outlive the
+  /// call that spawned them.
+  fn clone_handle(&self) -> Self {
+    Self {
+      client_name: self.client_name.clone(),
+      server_name: self.server_name.clone(),
+      event_stream: self.event_stream.clone(),
+      message_sender: self.message_sender.clone(),
+      connected: self.connected.clone(),
+      _client_span: self._client_span.clone(),
+      device_map: self.device_map.clone(),
+      reconnecting: self.reconnecting.clone(),
+      reconnect_abort: self.reconnect_abort.clone(),
+      event_loop_abort: self.event_loop_abort.clone(),
     }
   }
 
   pub async fn connect<ConnectorType>(
     &self,
-    mut connector: ConnectorType,
+    connector: ConnectorType,
   ) -> Result<(), ButtplugClientError>
   where
     ConnectorType: ButtplugConnector<ButtplugCurrentSpecClientMessage, ButtplugCurrentSpecServerMessage>
       + 'static,
   {
-    if self.connected() {
+    if self.connected() || self.reconnecting.load(Ordering::SeqCst) {
       return Err(ButtplugClientError::ButtplugConnectorError(
         ButtplugConnectorError::ConnectorAlreadyConnected,
       ));
     }
+    self.connect_internal(connector, None).await?;
+    self.run_handshake(false).await
+  }
+
+  /// Connects like [ButtplugClient::connect], but reconnects on its own if the
+  /// connection to the server drops.
+  ///
+  /// Connectors can only be connected once, so this takes a function that
+  /// builds a new connector for every attempt. On disconnect, the client emits
+  /// [ButtplugClientEvent::Reconnecting], then keeps trying to connect with
+  /// the backoff in `options`. Once connected, it redoes the handshake,
+  /// refreshes the device list and emits [ButtplugClientEvent::Reconnected].
+  /// If it runs out of attempts, or [ButtplugClient::disconnect] is called
+  /// while reconnecting, all devices are removed and
+  /// [ButtplugClientEvent::ServerDisconnect] is emitted.
+  ///
+  /// Only the first connection attempt is made here, and its error returned.
+  pub async fn connect_with_reconnect<ConnectorType, F>(
+    &self,
+    connector_factory: F,
+    options: ButtplugReconnectOptions,
+  ) -> Result<(), ButtplugClientError>
+  where
+    ConnectorType: ButtplugConnector<ButtplugCurrentSpecClientMessage, ButtplugCurrentSpecServerMessage>
+      + 'static,
+    F: Fn() -> ConnectorType + Send + Sync + 'static,
+  {
+    if self.connected() || self.reconnecting.load(Ordering::SeqCst) {
+      return Err(ButtplugClientError::ButtplugConnectorError(
+        ButtplugConnectorError::ConnectorAlreadyConnected,
+      ));
+    }
+    let connector_factory = Arc::new(connector_factory);
+    let handler = self.reconnect_handler(connector_factory.clone(), options);
+    self
+      .connect_internal(connector_factory(), Some(handler))
+      .await?;
+    self.run_handshake(false).await
+  }
+
+  fn reconnect_handler<ConnectorType, F>(
+    &self,
+    connector_factory: Arc<F>,
+    options: ButtplugReconnectOptions,
+  ) -> ButtplugReconnectHandler
+  where
+    ConnectorType: ButtplugConnector<ButtplugCurrentSpecClientMessage, ButtplugCurrentSpecServerMessage>
+      + 'static,
+    F: Fn() -> ConnectorType + Send + Sync + 'static,
+  {
+    let client = self.clone_handle();
+    Arc::new(move || {
+      // Only one reconnect loop at a time. If the connection drops while a
+      // reconnect attempt is handshaking, the running loop just carries on.
+      if client.reconnecting.swap(true, Ordering::SeqCst) {
+        return Box::pin(future::ready(())) as BoxFuture<'static, ()>;
+      }
+      let client = client.clone_handle();
+      let connector_factory = connector_factory.clone();
+      let options = options.clone();
+      let (abort_handle, abort_registration) = AbortHandle::new_pair();
+      *client.reconnect_abort.lock().unwrap() = Some(abort_handle);
+      Box::pin(async move {
+        let reconnect = client.run_reconnect(connector_factory, options);
+        // Aborted by disconnect(), which does the cleanup itself.
+        let _ = Abortable::new(reconnect, abort_registration).await;
+      })
+    })
+  }
+
+  async fn run_reconnect<ConnectorType, F>(
+    &self,
+    connector_factory: Arc<F>,
+    options: ButtplugReconnectOptions,
+  ) where
+    ConnectorType: ButtplugConnector<ButtplugCurrentSpecClientMessage, ButtplugCurrentSpecServerMessage>
+      + 'static,
+    F: Fn() -> ConnectorType + Send + Sync + 'static,
+  {
+    let mut attempt = 0;
+    while options.max_attempts.map_or(true, |max| attempt < max) {
+      async_manager::sleep(options.delay_for_attempt(attempt)).await;
+      attempt += 1;
+      info!("Trying to reconnect to server, attempt {}.", attempt);
+      let handler = self.reconnect_handler(connector_factory.clone(), options.clone());
+      if let Err(e) = self
+        .connect_internal(connector_factory(), Some(handler))
+        .await
+      {
+        warn!("Reconnect attempt {} failed: {:?}", attempt, e);
+        continue;
+      }
+      // disconnect() may have come in while this attempt was connecting on
+      // another thread, before it could stop the new event loop.
+      if !self.reconnecting.load(Ordering::SeqCst) {
+        self.abandon_event_loop();
+        return;
+      }
+      match self.run_handshake(true).await {
+        Ok(()) => {
+          info!("Reconnected to server.");
+          self.reconnecting.store(false, Ordering::SeqCst);
+          return;
+        }
+        Err(e) => {
+          warn!("Handshake failed while reconnecting: {:?}", e);
+          // Don't leave this attempt's connector and event loop running
+          // next to the next attempt's.
+          self.abandon_event_loop();
+        }
+      }
+    }
+    info!("Giving up on reconnecting to server.");
+    self.reconnecting.store(false, Ordering::SeqCst);
+    self.remove_all_devices();
+  }
 
+  /// Removes every device, as the server is gone for good, and emits
+  /// [ButtplugClientEvent::ServerDisconnect].
+  fn remove_all_devices(&self) {
+    let devices = self.devices();
+    self.device_map.clear();
+    for device in devices {
+      device.set_device_connected(false);
+      device.queue_event(ButtplugClientDeviceEvent::DeviceRemoved);
+      let _ = self
+        .event_stream
+        .send(ButtplugClientEvent::DeviceRemoved(device));
+    }
+    let _ = self.event_stream.send(ButtplugClientEvent::ServerDisconnect);
+  }
+
+  /// Stops the event loop of the latest connection without emitting any
+  /// events, dropping its connector. Used to throw away connections that
+  /// never finished their handshake.
+  fn abandon_event_loop(&self) {
+    if let Some(abort_handle) = self.event_loop_abort.lock().unwrap().take() {
+      abort_handle.abort();
+    }
+    self.connected.store(false, Ordering::SeqCst);
+  }
+
+  /// Connects the connector and starts an event loop for it. If a reconnect
+  /// handler is given, the event loop runs it when the connector closes.
+  async fn connect_internal<ConnectorType>(
+    &self,
+    mut connector: ConnectorType,
+    reconnect_handler: Option<ButtplugReconnectHandler>,
+  ) -> Result<(), ButtplugClientError>
+  where
+    ConnectorType: ButtplugConnector<ButtplugCurrentSpecClientMessage, ButtplugCurrentSpecServerMessage>
+      + 'static,
+  {
     // TODO I cannot remember why this is here or what it does.
     *self._client_span.lock().await = {
       let span = span!(Level::INFO, "Client");
@@ -227,17 +430,30 @@ impl ButtplugClient {
       self.event_stream.clone(),
       self.message_sender.clone(),
       self.device_map.clone(),
+      reconnect_handler.is_some(),
     );
 
     // Start the event loop before we run the handshake.
-    async_manager::spawn(
+    let (abort_handle, abort_registration) = AbortHandle::new_pair();
+    *self.event_loop_abort.lock().unwrap() = Some(abort_handle);
+    async_manager::spawn_named(
+      "client event loop",
       async move {
-        client_event_loop.run().await;
+        // Only the loop itself can be aborted. Once it's exited, the task may
+        // be running the reconnect, which has its own abort handle.
+        let exit = Abortable::new(client_event_loop.run(), abort_registration).await;
+        // Let go of the old connector before we start making new ones.
+        drop(client_event_loop);
+        if let (Ok(ButtplugClientEventLoopExit::ConnectorClosed), Some(handler)) =
+          (exit, reconnect_handler)
+        {
+          handler().await;
+        }
       }
       .instrument(tracing::info_span!("Client Loop Span")),
     )
     .unwrap();
-    self.run_handshake().await
+    Ok(())
   }
 
   /// Convenience function for creating in-process connectors.
@@ -285,52 +501,10 @@ impl ButtplugClient {
       Ok(conn) => conn,
       Err(err) => return Err(ButtplugClientError::ButtplugError(err)),
     };
-    #[cfg(feature = "btleplug-manager")]
-    {
-      use crate::server::comm_managers::btleplug::BtlePlugCommunicationManagerBuilder;
-      connector
-        .server_ref()
-        .add_comm_manager(BtlePlugCommunicationManagerBuilder::default())
-        .unwrap();
-    }
-    #[cfg(feature = "serial-manager")]
-    {
-      use crate::server::comm_managers::serialport::SerialPortCommunicationManagerBuilder;
-      connector
-        .server_ref()
-        .add_comm_manager(SerialPortCommunicationManagerBuilder::default())
-        .unwrap();
-    }
-    #[cfg(feature = "lovense-connect-service-manager")]
-    {
-      use crate::server::comm_managers::lovense_connect_service::LovenseConnectServiceCommunicationManagerBuilder;
-      connector
-        .server_ref()
-        .add_comm_manager(LovenseConnectServiceCommunicationManagerBuilder::default())
-        .unwrap();
-    }
-    #[cfg(feature = "lovense-dongle-manager")]
-    {
-      use crate::server::comm_managers::lovense_dongle::{
-        LovenseHIDDongleCommunicationManagerBuilder, LovenseSerialDongleCommunicationManagerBuilder,
-      };
-      connector
-        .server_ref()
-        .add_comm_manager(LovenseHIDDongleCommunicationManagerBuilder::default())
-        .unwrap();
-      connector
-        .server_ref()
-        .add_comm_manager(LovenseSerialDongleCommunicationManagerBuilder::default())
-        .unwrap();
-    }
-    #[cfg(all(feature = "xinput-manager", target_os = "windows"))]
-    {
-      use crate::server::comm_managers::xinput::XInputDeviceCommunicationManagerBuilder;
-      connector
-        .server_ref()
-        .add_comm_manager(XInputDeviceCommunicationManagerBuilder::default())
-        .unwrap();
-    }
+    connector
+      .server_ref()
+      .add_default_comm_managers()
+      .unwrap();
     self.connect(connector).await
   }
 
@@ -340,12 +514,15 @@ impl ButtplugClient {
   /// the struct, then tries to run connect and execute the Buttplug protocol
   /// handshake. Will return a connected and ready to use ButtplugClient is all
   /// goes well.
-  async fn run_handshake(&self) -> ButtplugClientResult {
+  ///
+  /// When `reconnecting` is true, the device list is reconciled with the
+  /// devices the client already knows about instead of just added.
+  async fn run_handshake(&self, reconnecting: bool) -> ButtplugClientResult {
     // Run our handshake
     info!("Running handshake with server.");
     let msg = self
       .send_message_ignore_connect_status(
-        RequestServerInfo::new(&self.client_name, ButtplugMessageSpecVersion::Version2).into(),
+        RequestServerInfo::new(&self.client_name, BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION).into(),
       )
       .await?;
 
@@ -365,13 +542,16 @@ impl ButtplugClient {
         .send_message(RequestDeviceList::default().into())
         .await?;
       if let ButtplugCurrentSpecServerMessage::DeviceList(m) = msg {
-        self
-          .send_message_to_event_loop(ButtplugClientRequest::HandleDeviceList(m))
-          .await?;
+        let request = if reconnecting {
+          ButtplugClientRequest::HandleReconnectDeviceList(m)
+        } else {
+          ButtplugClientRequest::HandleDeviceList(m)
+        };
+        self.send_message_to_event_loop(request).await?;
       }
       Ok(())
     } else {
-      self.disconnect().await?;
+      self.abandon_event_loop();
       Err(ButtplugClientError::ButtplugError(
         ButtplugHandshakeError::UnexpectedHandshakeMessageReceived(format!("{:?}", msg)).into(),
       ))
@@ -388,6 +568,17 @@ impl ButtplugClient {
   /// Returns Err(ButtplugClientError) if disconnection fails. It can be assumed
   /// that even on failure, the client will be disconnected.
   pub fn disconnect(&self) -> ButtplugClientResultFuture {
+    // If we're reconnecting, there's no server to tell. Stop the reconnect
+    // task, throw away whatever connection it was in the middle of making,
+    // and let go of the devices it was holding on to.
+    if self.reconnecting.swap(false, Ordering::SeqCst) {
+      if let Some(abort_handle) = self.reconnect_abort.lock().unwrap().take() {
+        abort_handle.abort();
+      }
+      self.abandon_event_loop();
+      self.remove_all_devices();
+      return Box::pin(future::ready(Ok(())));
+    }
     if !self.connected() {
       return Box::pin(future::ready(Err(
         ButtplugConnectorError::ConnectorNotConnected.into(),
@@ -428,9 +619,22 @@ impl ButtplugClient {
   /// Returns Err([ButtplugClientError]) if request fails due to issues with
   /// DeviceManagers on the server, disconnection, etc.
   pub fn stop_all_devices(&self) -> ButtplugClientResultFuture {
+    for device in self.device_map.iter() {
+      device.record_stop();
+    }
     self.send_message_expect_ok(StopAllDevices::default().into())
   }
 
+  /// Asks the server to forward its log records at or above the given level,
+  /// as [ButtplugClientEvent::Log] events. [LogLevel::Off] stops forwarding.
+  ///
+  /// Servers only have logs to forward if the application running them has
+  /// set up a
+  /// [ButtplugLogLayer][crate::server::log_forwarding::ButtplugLogLayer].
+  pub fn request_log(&self, level: LogLevel) -> ButtplugClientResultFuture {
+    self.send_message_expect_ok(RequestLog::new(level).into())
+  }
+
   pub fn event_stream(&self) -> impl Stream<Item = ButtplugClientEvent> {
     let stream = convert_broadcast_receiver_to_stream(self.event_stream.subscribe());
     // We can either Box::pin here or force the user to pin_mut!() on their
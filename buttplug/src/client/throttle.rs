@@ -0,0 +1,276 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2022 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Rate limiting and deduplication of device commands.
+
+use super::{
+  device::{ButtplugClientDevice, RotateCommand, VibrateCommand},
+  ButtplugClientResultFuture,
+};
+use crate::{core::messages::ButtplugCurrentSpecDeviceMessageType, util::async_manager};
+use futures::{future, lock::Mutex as AsyncMutex, select, FutureExt};
+use std::{
+  collections::HashMap,
+  sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, Mutex,
+  },
+  time::{Duration, Instant},
+};
+use tokio_util::sync::CancellationToken;
+
+type FeatureSender<T> = fn(&ButtplugClientDevice, HashMap<u32, T>) -> ButtplugClientResultFuture;
+
+/// Per feature values for one message type, tracking what's been sent and
+/// what's waiting for the interval to pass.
+struct ThrottledFeatures<T> {
+  sent: HashMap<u32, T>,
+  pending: HashMap<u32, T>,
+  last_sent_at: Option<Instant>,
+  flush_scheduled: bool,
+  /// Cancelled when the device is stopped, to drop flushes that are waiting
+  /// or under way. Replaced after every cancel.
+  flush_token: CancellationToken,
+}
+
+impl<T> Default for ThrottledFeatures<T> {
+  fn default() -> Self {
+    Self {
+      sent: HashMap::new(),
+      pending: HashMap::new(),
+      last_sent_at: None,
+      flush_scheduled: false,
+      flush_token: CancellationToken::new(),
+    }
+  }
+}
+
+impl<T: Copy> ThrottledFeatures<T> {
+  /// Forgets everything and cancels any flush, e.g. after the device was
+  /// stopped, so the next command is sent even if it matches the last one.
+  fn clear(&mut self) {
+    self.sent.clear();
+    self.pending.clear();
+    self.flush_token.cancel();
+    self.flush_token = CancellationToken::new();
+    self.flush_scheduled = false;
+  }
+
+  fn take_pending(&mut self) -> HashMap<u32, T> {
+    let pending: HashMap<u32, T> = self.pending.drain().collect();
+    self
+      .sent
+      .extend(pending.iter().map(|(index, value)| (*index, *value)));
+    self.last_sent_at = Some(Instant::now());
+    pending
+  }
+}
+
+/// Wraps a [ButtplugClientDevice] so that rapid vibrate/rotate calls, like the
+/// ones coming from a UI slider being dragged, don't flood the server.
+///
+/// - Commands that would set features to the values they were last set to
+///   aren't sent at all.
+/// - At most one command per message type is sent every `min_interval`.
+///   Commands that come in sooner are merged per feature and sent together
+///   once the interval has passed, so only the latest value for each feature
+///   goes out.
+///
+/// Futures for commands that get merged or dropped resolve right away with
+/// Ok. Errors from a delayed send have nowhere to go, so they're only logged.
+/// [ButtplugClientDeviceThrottle::stop] is never delayed, and drops anything
+/// waiting to be sent. Stopping the device some other way (through the
+/// device itself, or [ButtplugClient::stop_all_devices]) does the same the
+/// next time the throttle is used.
+///
+/// [ButtplugClient::stop_all_devices]: super::ButtplugClient::stop_all_devices
+pub struct ButtplugClientDeviceThrottle {
+  device: Arc<ButtplugClientDevice>,
+  min_interval: Duration,
+  vibrate: Arc<Mutex<ThrottledFeatures<f64>>>,
+  rotate: Arc<Mutex<ThrottledFeatures<(f64, bool)>>>,
+  /// Held while a flush sends, so stops can wait for it.
+  flush_lock: Arc<AsyncMutex<()>>,
+  /// The device's stop count as of the last time we cleared.
+  stop_count: AtomicU64,
+}
+
+impl ButtplugClientDeviceThrottle {
+  pub fn new(device: Arc<ButtplugClientDevice>, min_interval: Duration) -> Self {
+    let stop_count = AtomicU64::new(device.stop_count());
+    Self {
+      device,
+      min_interval,
+      vibrate: Arc::new(Mutex::new(ThrottledFeatures::default())),
+      rotate: Arc::new(Mutex::new(ThrottledFeatures::default())),
+      flush_lock: Arc::new(AsyncMutex::new(())),
+      stop_count,
+    }
+  }
+
+  /// The wrapped device, for commands that aren't throttled.
+  pub fn device(&self) -> &Arc<ButtplugClientDevice> {
+    &self.device
+  }
+
+  pub fn vibrate(&self, speed_cmd: VibrateCommand) -> ButtplugClientResultFuture {
+    let feature_count = self.feature_count(ButtplugCurrentSpecDeviceMessageType::VibrateCmd);
+    let speeds = match &speed_cmd {
+      VibrateCommand::Speed(speed) => Some((0..feature_count).map(|i| (i, *speed)).collect()),
+      VibrateCommand::SpeedVec(vec) => feature_values(vec, feature_count),
+      VibrateCommand::SpeedMap(map) => feature_map_values(map, feature_count),
+    };
+    match speeds {
+      Some(speeds) => self.throttle(&self.vibrate, speeds, |device, speeds| {
+        device.vibrate(VibrateCommand::SpeedMap(speeds))
+      }),
+      // Let the device build the error for invalid commands.
+      None => self.device.vibrate(speed_cmd),
+    }
+  }
+
+  pub fn rotate(&self, rotate_cmd: RotateCommand) -> ButtplugClientResultFuture {
+    let feature_count = self.feature_count(ButtplugCurrentSpecDeviceMessageType::RotateCmd);
+    let rotations = match &rotate_cmd {
+      RotateCommand::Rotate(speed, clockwise) => Some(
+        (0..feature_count)
+          .map(|i| (i, (*speed, *clockwise)))
+          .collect(),
+      ),
+      RotateCommand::RotateVec(vec) => feature_values(vec, feature_count),
+      RotateCommand::RotateMap(map) => feature_map_values(map, feature_count),
+    };
+    match rotations {
+      Some(rotations) => self.throttle(&self.rotate, rotations, |device, rotations| {
+        device.rotate(RotateCommand::RotateMap(rotations))
+      }),
+      None => self.device.rotate(rotate_cmd),
+    }
+  }
+
+  /// Stops the device right away, dropping any commands waiting to be sent.
+  pub fn stop(&self) -> ButtplugClientResultFuture {
+    let fut = self.device.stop();
+    self.clear_if_stopped();
+    let flush_lock = self.flush_lock.clone();
+    Box::pin(async move {
+      // A flush that was mid-send when it got cancelled has to be dropped
+      // before the stop goes out, or it could land after the stop.
+      drop(flush_lock.lock().await);
+      fut.await
+    })
+  }
+
+  /// Forgets what's been sent and cancels flushes if the device has been
+  /// stopped since the last time we checked.
+  fn clear_if_stopped(&self) {
+    let stop_count = self.device.stop_count();
+    if self.stop_count.swap(stop_count, Ordering::SeqCst) != stop_count {
+      self.vibrate.lock().unwrap().clear();
+      self.rotate.lock().unwrap().clear();
+    }
+  }
+
+  fn feature_count(&self, message_type: ButtplugCurrentSpecDeviceMessageType) -> u32 {
+    self
+      .device
+      .allowed_messages
+      .get(&message_type)
+      .and_then(|attributes| attributes.feature_count)
+      .unwrap_or(0)
+  }
+
+  fn throttle<T>(
+    &self,
+    features: &Arc<Mutex<ThrottledFeatures<T>>>,
+    values: HashMap<u32, T>,
+    send: FeatureSender<T>,
+  ) -> ButtplugClientResultFuture
+  where
+    T: PartialEq + Copy + Send + 'static,
+  {
+    self.clear_if_stopped();
+    let mut state = features.lock().unwrap();
+    for (index, value) in values {
+      if state.sent.get(&index) == Some(&value) {
+        state.pending.remove(&index);
+      } else {
+        state.pending.insert(index, value);
+      }
+    }
+    // If a flush is already scheduled, it'll pick up what we just added.
+    if state.pending.is_empty() || state.flush_scheduled {
+      return Box::pin(future::ready(Ok(())));
+    }
+    let wait = state.last_sent_at.map_or(Duration::ZERO, |sent_at| {
+      self.min_interval.saturating_sub(sent_at.elapsed())
+    });
+    if wait == Duration::ZERO {
+      let values = state.take_pending();
+      return send(&self.device, values);
+    }
+    state.flush_scheduled = true;
+    let token = state.flush_token.clone();
+    let device = self.device.clone();
+    let features = features.clone();
+    let flush_lock = self.flush_lock.clone();
+    async_manager::spawn(async move {
+      select! {
+        _ = token.cancelled().fuse() => return,
+        _ = async_manager::sleep(wait).fuse() => {}
+      }
+      let _flushing = flush_lock.lock().await;
+      let values = {
+        let mut state = features.lock().unwrap();
+        if token.is_cancelled() {
+          return;
+        }
+        state.flush_scheduled = false;
+        if state.pending.is_empty() {
+          return;
+        }
+        state.take_pending()
+      };
+      select! {
+        _ = token.cancelled().fuse() => {}
+        result = send(&device, values).fuse() => {
+          if let Err(e) = result {
+            error!(
+              "Throttled command to device {} failed: {:?}",
+              device.name, e
+            );
+          }
+        }
+      }
+    })
+    .unwrap();
+    Box::pin(future::ready(Ok(())))
+  }
+}
+
+fn feature_values<T: Copy>(values: &[T], feature_count: u32) -> Option<HashMap<u32, T>> {
+  if values.len() as u32 > feature_count {
+    return None;
+  }
+  Some(
+    values
+      .iter()
+      .enumerate()
+      .map(|(index, value)| (index as u32, *value))
+      .collect(),
+  )
+}
+
+fn feature_map_values<T: Copy>(
+  values: &HashMap<u32, T>,
+  feature_count: u32,
+) -> Option<HashMap<u32, T>> {
+  if values.keys().any(|index| *index >= feature_count) {
+    return None;
+  }
+  Some(values.clone())
+}
@@ -104,7 +104,7 @@ impl ButtplugConnector<ButtplugCurrentSpecClientMessage, ButtplugCurrentSpecServ
       self.server_outbound_sender = message_sender;
       let server_recv = self.server.event_stream();
       Box::pin(async move {
-        async_manager::spawn(async move {
+        async_manager::spawn_named("in process connector event loop", async move {
           info!("Starting In Process Client Connector Event Sender Loop");
           pin_mut!(server_recv);
           while let Some(event) = server_recv.next().await {
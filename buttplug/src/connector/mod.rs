@@ -76,11 +76,13 @@
 
 #[cfg(all(feature = "server", feature = "client"))]
 mod in_process_connector;
+mod reconnect;
 pub mod remote_connector;
 pub mod transport;
 
 #[cfg(all(feature = "server", feature = "client"))]
 pub use in_process_connector::ButtplugInProcessClientConnector;
+pub use reconnect::ButtplugReconnectOptions;
 pub use remote_connector::{
   ButtplugRemoteClientConnector, ButtplugRemoteConnector, ButtplugRemoteServerConnector,
 };
@@ -187,4 +189,9 @@ where
   /// If the connector is not currently connected, or an error happens during
   /// the send operation, this will return a [ButtplugConnectorError]
   fn send(&self, msg: OutboundMessageType) -> ButtplugConnectorResultFuture;
+  /// Identity token the other side sent while connecting, if the transport
+  /// supports them and there was one.
+  fn identity_token(&self) -> Option<String> {
+    None
+  }
 }
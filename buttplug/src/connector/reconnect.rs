@@ -0,0 +1,65 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2022 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Settings for clients that reconnect on their own when the connection drops.
+
+use std::time::Duration;
+
+/// Backoff settings for
+/// [ButtplugClient::connect_with_reconnect][crate::client::ButtplugClient::connect_with_reconnect].
+///
+/// When the connection to the server drops, the client waits `initial_delay`
+/// before its first attempt, doubling the wait after each failure up to
+/// `max_delay`. If `max_attempts` is set and that many attempts fail, the
+/// client gives up and acts as if the server had disconnected normally.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ButtplugReconnectOptions {
+  pub initial_delay: Duration,
+  pub max_delay: Duration,
+  pub max_attempts: Option<u32>,
+}
+
+impl Default for ButtplugReconnectOptions {
+  fn default() -> Self {
+    Self {
+      initial_delay: Duration::from_millis(500),
+      max_delay: Duration::from_secs(30),
+      max_attempts: None,
+    }
+  }
+}
+
+impl ButtplugReconnectOptions {
+  /// Time to wait before the given attempt, starting at 0.
+  pub fn delay_for_attempt(&self, attempt: u32) -> Duration {
+    let factor = 2u32.checked_pow(attempt).unwrap_or(u32::MAX);
+    self
+      .initial_delay
+      .checked_mul(factor)
+      .map_or(self.max_delay, |delay| delay.min(self.max_delay))
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::ButtplugReconnectOptions;
+  use std::time::Duration;
+
+  #[test]
+  fn test_reconnect_backoff() {
+    let options = ButtplugReconnectOptions {
+      initial_delay: Duration::from_millis(100),
+      max_delay: Duration::from_secs(1),
+      max_attempts: None,
+    };
+    assert_eq!(options.delay_for_attempt(0), Duration::from_millis(100));
+    assert_eq!(options.delay_for_attempt(1), Duration::from_millis(200));
+    assert_eq!(options.delay_for_attempt(3), Duration::from_millis(800));
+    assert_eq!(options.delay_for_attempt(4), Duration::from_secs(1));
+    assert_eq!(options.delay_for_attempt(100), Duration::from_secs(1));
+  }
+}
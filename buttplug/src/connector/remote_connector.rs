@@ -13,6 +13,7 @@ use crate::{
   core::messages::{
     serializer::{
       ButtplugClientJSONSerializer, ButtplugMessageSerializer, ButtplugSerializedMessage,
+      ButtplugServerSerializer,
     },
     ButtplugClientMessage, ButtplugCurrentSpecClientMessage, ButtplugCurrentSpecServerMessage,
     ButtplugMessage, ButtplugServerMessage,
@@ -20,7 +21,10 @@ use crate::{
   util::async_manager,
 };
 use futures::{future::BoxFuture, FutureExt};
-use std::marker::PhantomData;
+use std::{
+  marker::PhantomData,
+  sync::{Arc, Mutex},
+};
 use tokio::sync::mpsc::{channel, Receiver, Sender};
 
 enum ButtplugRemoteConnectorMessage<T>
@@ -55,9 +59,12 @@ async fn remote_connector_event_loop<
   transport_outgoing_sender: Sender<ButtplugSerializedMessage>,
   // Takes data coming in from the transport.
   mut transport_incoming_recv: Receiver<ButtplugTransportIncomingMessage>,
+  // Holds the identity token, if the transport gets one.
+  identity_token: Arc<Mutex<Option<String>>>,
 ) where
   TransportType: ButtplugConnectorTransport + 'static,
   SerializerType: ButtplugMessageSerializer<Inbound = InboundMessageType, Outbound = OutboundMessageType>
+    + Default
     + 'static,
   OutboundMessageType: ButtplugMessage + 'static,
   InboundMessageType: ButtplugMessage + 'static,
@@ -122,6 +129,20 @@ async fn remote_connector_event_loop<
           }
           // TODO We should probably make connecting an event?
           ButtplugTransportIncomingMessage::Connected => {}
+          ButtplugTransportIncomingMessage::Subprotocol(subprotocol) => {
+            // The transport only agrees to subprotocols it was told about, so
+            // this means it was set up for a different serializer.
+            if let Err(e) = serializer.set_subprotocol(&subprotocol) {
+              error!("Cannot use subprotocol, closing connection: {}", e);
+              if let Err(e) = transport.disconnect().await {
+                error!("Error disconnecting transport: {:?}", e);
+              }
+              break;
+            }
+          }
+          ButtplugTransportIncomingMessage::IdentityToken(token) => {
+            *identity_token.lock().unwrap() = Some(token);
+          }
           // TODO We should probably figure out what this even does?
           ButtplugTransportIncomingMessage::Error(_) => {}
         }
@@ -165,9 +186,12 @@ pub type ButtplugRemoteClientConnector<
   ButtplugCurrentSpecServerMessage,
 >;
 
-pub type ButtplugRemoteServerConnector<TransportType, SerializerType> = ButtplugRemoteConnector<
+/// Server side remote connector. Each connection gets its own
+/// [ButtplugServerSerializer], which picks the message format from the
+/// subprotocol the transport agreed on, if any.
+pub type ButtplugRemoteServerConnector<TransportType> = ButtplugRemoteConnector<
   TransportType,
-  SerializerType,
+  ButtplugServerSerializer,
   ButtplugServerMessage,
   ButtplugClientMessage,
 >;
@@ -180,6 +204,7 @@ pub struct ButtplugRemoteConnector<
 > where
   TransportType: ButtplugConnectorTransport + 'static,
   SerializerType: ButtplugMessageSerializer<Inbound = InboundMessageType, Outbound = OutboundMessageType>
+    + Default
     + 'static,
   OutboundMessageType: ButtplugMessage + 'static,
   InboundMessageType: ButtplugMessage + 'static,
@@ -196,6 +221,9 @@ pub struct ButtplugRemoteConnector<
   transport: Option<TransportType>,
   /// Sender for forwarding outgoing messages to the connector event loop.
   event_loop_sender: Option<Sender<ButtplugRemoteConnectorMessage<OutboundMessageType>>>,
+  /// Identity token the transport got while connecting. Set by the event loop,
+  /// before it passes on any messages.
+  identity_token: Arc<Mutex<Option<String>>>,
   dummy_serializer: PhantomData<SerializerType>,
 }
 
@@ -204,6 +232,7 @@ impl<TransportType, SerializerType, OutboundMessageType, InboundMessageType>
 where
   TransportType: ButtplugConnectorTransport + 'static,
   SerializerType: ButtplugMessageSerializer<Inbound = InboundMessageType, Outbound = OutboundMessageType>
+    + Default
     + 'static,
   OutboundMessageType: ButtplugMessage + 'static,
   InboundMessageType: ButtplugMessage + 'static,
@@ -212,6 +241,7 @@ where
     Self {
       transport: Some(transport),
       event_loop_sender: None,
+      identity_token: Arc::new(Mutex::new(None)),
       dummy_serializer: PhantomData::default(),
     }
   }
@@ -228,6 +258,7 @@ impl<TransportType, SerializerType, OutboundMessageType, InboundMessageType>
 where
   TransportType: ButtplugConnectorTransport + 'static,
   SerializerType: ButtplugMessageSerializer<Inbound = InboundMessageType, Outbound = OutboundMessageType>
+    + Default
     + 'static,
   OutboundMessageType: ButtplugMessage + 'static,
   InboundMessageType: ButtplugMessage + 'static,
@@ -241,6 +272,7 @@ where
       let transport = self.transport.take().unwrap();
       let (connector_outgoing_sender, connector_outgoing_receiver) = channel(256);
       self.event_loop_sender = Some(connector_outgoing_sender);
+      let identity_token = self.identity_token.clone();
       Box::pin(async move {
         let (transport_outgoing_sender, transport_outgoing_receiver) = channel(256);
         let (transport_incoming_sender, transport_incoming_receiver) = channel(256);
@@ -251,7 +283,7 @@ where
           // If we connect successfully, we get back the channel from the transport
           // to send outgoing messages and receieve incoming events, all serialized.
           Ok(()) => {
-            async_manager::spawn(async move {
+            async_manager::spawn_named("remote connector event loop", async move {
               remote_connector_event_loop::<
                 TransportType,
                 SerializerType,
@@ -263,6 +295,7 @@ where
                 transport,
                 transport_outgoing_sender,
                 transport_incoming_receiver,
+                identity_token,
               )
               .await
             })
@@ -304,4 +337,8 @@ where
       ButtplugConnectorError::ConnectorNotConnected.into()
     }
   }
+
+  fn identity_token(&self) -> Option<String> {
+    self.identity_token.lock().unwrap().clone()
+  }
 }
@@ -17,6 +17,12 @@ use thiserror::Error;
 pub enum ButtplugTransportIncomingMessage {
   /// Send when connection is established.
   Connected,
+  /// Subprotocol agreed on with the other side while connecting, which
+  /// picks the serializer format. Sent before any messages.
+  Subprotocol(String),
+  /// Identity token the other side sent while connecting, for handing a
+  /// server session from one client to the next. Sent before any messages.
+  IdentityToken(String),
   /// Serialized version of message we received from remote server.
   Message(ButtplugSerializedMessage),
   // TODO Implement binary message at some point.
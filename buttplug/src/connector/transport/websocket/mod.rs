@@ -1,6 +1,10 @@
 pub mod websocket_client;
 pub mod websocket_server;
 
+/// Handshake request header clients put their identity token in, see
+/// [ButtplugWebsocketClientTransport::with_identity_token].
+pub const IDENTITY_TOKEN_HEADER: &str = "Buttplug-Identity-Token";
+
 pub use async_tungstenite::tungstenite::Error as TungsteniteError;
 pub use websocket_client::ButtplugWebsocketClientTransport;
 
@@ -7,6 +7,7 @@
 
 //! Handling of websockets using async-tungstenite
 
+use super::IDENTITY_TOKEN_HEADER;
 use crate::{
   connector::{
     transport::{
@@ -18,7 +19,10 @@ use crate::{
   core::messages::serializer::ButtplugSerializedMessage,
   util::async_manager,
 };
-use async_tungstenite::{tokio::connect_async_with_tls_connector, tungstenite::protocol::Message};
+use async_tungstenite::{
+  tokio::connect_async_with_tls_connector,
+  tungstenite::{client::IntoClientRequest, http::HeaderValue, protocol::Message},
+};
 use futures::{future::BoxFuture, FutureExt, SinkExt, StreamExt};
 use std::sync::Arc;
 use tokio::sync::{
@@ -36,6 +40,9 @@ pub struct ButtplugWebsocketClientTransport {
   /// If true, bypass certificate verification. Should be true for self-signed
   /// certs.
   bypass_cert_verify: bool,
+  /// Token sent to the server in the handshake request, for picking up a
+  /// session another client handed off.
+  identity_token: Option<String>,
   /// Internally held sender, used for when disconnect is called.
   disconnect_notifier: Arc<Notify>,
 }
@@ -46,6 +53,7 @@ impl ButtplugWebsocketClientTransport {
       should_use_tls,
       address: address.to_owned(),
       bypass_cert_verify,
+      identity_token: None,
       disconnect_notifier: Arc::new(Notify::new()),
     }
   }
@@ -69,6 +77,17 @@ impl ButtplugWebsocketClientTransport {
   pub fn new_secure_connector(address: &str, bypass_cert_verify: bool) -> Self {
     ButtplugWebsocketClientTransport::create(address, true, bypass_cert_verify)
   }
+
+  /// Sends `token` to the server in the websocket handshake request.
+  ///
+  /// Servers with a handoff timeout use this to let a client take over the
+  /// session of the one that just disconnected with the same token. It's sent
+  /// as a header instead of being part of RequestServerInfo, so the handshake
+  /// message stays as the spec has it.
+  pub fn with_identity_token(mut self, token: &str) -> Self {
+    self.identity_token = Some(token.to_owned());
+    self
+  }
 }
 
 impl ButtplugConnectorTransport for ButtplugWebsocketClientTransport {
@@ -101,12 +120,27 @@ impl ButtplugConnectorTransport for ButtplugWebsocketClientTransport {
       None
     };
     let address = self.address.clone();
+    let identity_token = self.identity_token.clone();
 
     Box::pin(async move {
-      match connect_async_with_tls_connector(&address, tls_connector).await {
+      let mut request = address.into_client_request().map_err(|err| {
+        ButtplugConnectorError::TransportSpecificError(
+          ButtplugConnectorTransportSpecificError::TungsteniteError(err),
+        )
+      })?;
+      if let Some(token) = identity_token {
+        let value = HeaderValue::from_str(&token).map_err(|_| {
+          ButtplugConnectorError::ConnectorGenericError(
+            "Identity token isn't a valid header value".to_owned(),
+          )
+        })?;
+        request.headers_mut().insert(IDENTITY_TOKEN_HEADER, value);
+      }
+      match connect_async_with_tls_connector(request, tls_connector).await {
         Ok((stream, _)) => {
           let (mut writer, mut reader) = stream.split();
-          async_manager::spawn(
+          async_manager::spawn_named(
+            "websocket client send loop",
             async move {
               loop {
                 select! {
@@ -138,7 +172,8 @@ impl ButtplugConnectorTransport for ButtplugWebsocketClientTransport {
             .instrument(tracing::info_span!("Websocket Send Task")),
           )
           .unwrap();
-          async_manager::spawn(
+          async_manager::spawn_named(
+            "websocket client receive loop",
             async move {
               while let Some(response) = reader.next().await {
                 trace!("Websocket receiving: {:?}", response);
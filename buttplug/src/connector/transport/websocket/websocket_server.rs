@@ -1,3 +1,4 @@
+use super::IDENTITY_TOKEN_HEADER;
 use crate::{
   connector::{
     transport::{
@@ -9,6 +10,10 @@ use crate::{
   core::messages::serializer::ButtplugSerializedMessage,
   util::async_manager,
 };
+use async_tungstenite::tungstenite::{
+  handshake::server::{ErrorResponse, Request, Response},
+  http::{header::SEC_WEBSOCKET_PROTOCOL, HeaderValue},
+};
 use futures::{future::BoxFuture, AsyncRead, AsyncWrite, FutureExt, SinkExt, StreamExt};
 use std::sync::Arc;
 use tokio::net::TcpListener;
@@ -24,6 +29,29 @@ pub struct ButtplugWebsocketServerTransportOptions {
   /// Insecure port for listening for websocket connections. Secure ports were
   /// removed, but this name was left as is to minimize code breakage.
   pub ws_insecure_port: u16,
+  /// Subprotocols to agree to if the client asks for them, in order of
+  /// preference. The one picked is handed to the connector's serializer, so
+  /// these need to be ones it supports, e.g.
+  /// `core::messages::serializer::BUTTPLUG_SERVER_SUBPROTOCOLS`. Clients that
+  /// don't ask for any of these are still accepted, and the serializer works
+  /// out what they speak from their handshake.
+  pub ws_subprotocols: Vec<String>,
+}
+
+/// First of our subprotocols the client asked for, if any.
+fn select_subprotocol(request: &Request, subprotocols: &[String]) -> Option<String> {
+  let requested: Vec<&str> = request
+    .headers()
+    .get_all(SEC_WEBSOCKET_PROTOCOL)
+    .iter()
+    .filter_map(|value| value.to_str().ok())
+    .flat_map(|value| value.split(','))
+    .map(str::trim)
+    .collect();
+  subprotocols
+    .iter()
+    .find(|subprotocol| requested.contains(&subprotocol.as_str()))
+    .cloned()
 }
 
 async fn run_connection_loop<S>(
@@ -158,6 +186,7 @@ impl ButtplugConnectorTransport for ButtplugWebsocketServerTransport {
     let request_receiver_clone = request_receiver.clone();
     let response_sender_clone = incoming_sender.clone();
     let disconnect_notifier_clone = disconnect_notifier.clone();
+    let subprotocols = self.options.ws_subprotocols.clone();
     let fut = async move {
       // Create the event loop and TCP listener we'll accept connections on.
       let try_socket = TcpListener::bind(&addr).await;
@@ -166,14 +195,52 @@ impl ButtplugConnectorTransport for ButtplugWebsocketServerTransport {
       debug!("Websocket Insecure: Listening on: {}", addr);
       if let Ok((stream, _)) = listener.accept().await {
         info!("Websocket Insecure: Got connection");
-        let ws_fut = async_tungstenite::tokio::accept_async(stream);
+        let mut subprotocol = None;
+        let mut identity_token = None;
+        let ws_fut = async_tungstenite::tokio::accept_hdr_async(
+          stream,
+          |request: &Request, mut response: Response| -> Result<Response, ErrorResponse> {
+            subprotocol = select_subprotocol(request, &subprotocols);
+            identity_token = request
+              .headers()
+              .get(IDENTITY_TOKEN_HEADER)
+              .and_then(|value| value.to_str().ok())
+              .map(str::to_owned);
+            if let Some(selected) = &subprotocol {
+              // Subprotocol names are tokens, so they're always valid headers.
+              response
+                .headers_mut()
+                .insert(SEC_WEBSOCKET_PROTOCOL, HeaderValue::from_str(selected).unwrap());
+            }
+            Ok(response)
+          },
+        );
         let ws_stream = ws_fut.await.map_err(|err| {
           error!("Websocket server accept error: {:?}", err);
           ButtplugConnectorError::TransportSpecificError(
             ButtplugConnectorTransportSpecificError::TungsteniteError(err),
           )
         })?;
-        async_manager::spawn(async move {
+        if let Some(subprotocol) = subprotocol {
+          info!("Websocket Insecure: Using subprotocol {}", subprotocol);
+          if response_sender_clone
+            .send(ButtplugTransportIncomingMessage::Subprotocol(subprotocol))
+            .await
+            .is_err()
+          {
+            return Err(ButtplugConnectorError::ConnectorNotConnected);
+          }
+        }
+        if let Some(identity_token) = identity_token {
+          if response_sender_clone
+            .send(ButtplugTransportIncomingMessage::IdentityToken(identity_token))
+            .await
+            .is_err()
+          {
+            return Err(ButtplugConnectorError::ConnectorNotConnected);
+          }
+        }
+        async_manager::spawn_named("websocket server connection loop", async move {
           run_connection_loop(
             ws_stream,
             (*request_receiver_clone.lock().await).take().unwrap(),
@@ -133,6 +133,12 @@ pub enum ButtplugDeviceError {
   DeviceCommunicationError(String),
   /// Device does not have endpoint {0}
   InvalidEndpoint(Endpoint),
+  /// Invalid endpoint name {0}: {1}
+  InvalidEndpointName(String, String),
+  /// Raw messages are not allowed for device {0}. The server has to be started with raw messages enabled.
+  RawMessagesNotAllowed(String),
+  /// Firmware update failed: {0}
+  FirmwareUpdateError(String),
   /// Device does not handle command type: {0}
   UnhandledCommand(String),
   #[cfg(feature = "server")]
@@ -144,12 +150,18 @@ pub enum ButtplugDeviceError {
   DeviceSpecificError(String),
   /// No device available at index {0}
   DeviceNotAvailable(u32),
+  /// Devices were stopped by a scheduled stop, commands are refused until it is cancelled.
+  DevicesStoppedBySchedule,
   /// Device scanning already started.
   DeviceScanningAlreadyStarted,
   /// Device scanning already stopped.
   DeviceScanningAlreadyStopped,
   /// Device permission error: {0}
   DevicePermissionError(String),
+  /// Bluetooth is not usable due to missing system permissions: {0} To fix this: {1}
+  BluetoothPermissionError(String, String),
+  /// Device {0} has to be bonded (paired) with the operating system before it can be used. To fix this: {1}
+  DeviceBondingRequired(String, String),
   /// {0}
   ProtocolAttributesNotFound(String),
   /// Protocol {0} not implemented in library
@@ -162,6 +174,10 @@ pub enum ButtplugDeviceError {
   UntypedDeserializedError(String),
   /// Device Configuration File Error: {0}
   DeviceConfigurationFileError(String),
+  /// User configuration override for protocol {0}, identifier {1}, message {2} is invalid: {3}
+  UserConfigurationOverrideError(String, String, String, String),
+  /// Device script {0} refused the command: {1}
+  DeviceScriptError(String, String),
 }
 
 /// Unknown errors occur in exceptional circumstances where no other error type
@@ -0,0 +1,236 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2022 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Matching of server replies to the client requests that caused them.
+
+use super::messages::ButtplugMessage;
+use std::{
+  collections::HashMap,
+  time::{Duration, Instant},
+};
+
+/// Message sorting and pairing for client implementations.
+///
+/// Whenever a client sends the server a request message, the server will
+/// always send back a response message with the same `id` field. Any message
+/// that comes from the server without an originating client message
+/// ([DeviceAdded][crate::core::messages::DeviceAdded],
+/// [Log][crate::core::messages::Log], etc...) will have an `id` of 0 and is
+/// considered an *event*, meaning something happened on the server that was not
+/// directly tied to a client request.
+///
+/// The sorter hands out message `id`s for outgoing requests, and keeps
+/// whatever the client needs to deliver the reply (a future, a callback
+/// handle, etc) until the reply shows up. Incoming messages are put through
+/// [sort][ClientMessageSorter::sort], with one of 3 outcomes:
+///
+/// - If the message `id` is 0, it's an event.
+/// - If there is a request waiting with a matching `id`, it's that request's
+///   reply.
+/// - Otherwise it's an orphan: a reply to a request we never sent, or one we
+///   already gave up on. These shouldn't be treated as events.
+///
+/// If the sorter was created with a timeout, requests that haven't been
+/// answered in time are returned by [expire][ClientMessageSorter::expire].
+/// Nothing is timed out unless that's called, so clients decide when to check
+/// (see [next_deadline][ClientMessageSorter::next_deadline]).
+pub struct ClientMessageSorter<P> {
+  /// Requests waiting for a reply, keyed by message `id`.
+  pending: HashMap<u32, PendingRequest<P>>,
+  /// Next message `id` to try handing out.
+  current_id: u32,
+  timeout: Option<Duration>,
+}
+
+struct PendingRequest<P> {
+  value: P,
+  deadline: Option<Instant>,
+}
+
+/// Where an incoming message belongs, see [ClientMessageSorter::sort].
+#[derive(Debug)]
+pub enum SortedMessage<M, P> {
+  /// Unsolicited message from the server, with an `id` of 0.
+  Event(M),
+  /// Reply to a request, along with what was registered for the request.
+  Reply(M, P),
+  /// Message with an `id` that doesn't match any waiting request.
+  Orphan(M),
+}
+
+impl<P> ClientMessageSorter<P> {
+  /// Creates a sorter that times out requests waiting for longer than
+  /// `timeout`, or never times them out if it's None.
+  pub fn new(timeout: Option<Duration>) -> Self {
+    Self {
+      pending: HashMap::new(),
+      // As a client we can't send message `id` of 0 (0 is reserved for system
+      // incoming messages).
+      current_id: 1,
+      timeout,
+    }
+  }
+
+  /// Sets the `id` of an outgoing request, and stores `value` until its reply
+  /// comes in. Returns the `id` used.
+  pub fn register<M>(&mut self, msg: &mut M, value: P) -> u32
+  where
+    M: ButtplugMessage,
+  {
+    let id = self.next_id();
+    trace!("Setting message id to {}", id);
+    msg.set_id(id);
+    self.pending.insert(
+      id,
+      PendingRequest {
+        value,
+        deadline: self.timeout.map(|timeout| Instant::now() + timeout),
+      },
+    );
+    id
+  }
+
+  /// Works out whether an incoming message is an event, a reply or an
+  /// orphan. Replies are removed from the waiting requests.
+  pub fn sort<M>(&mut self, msg: M) -> SortedMessage<M, P>
+  where
+    M: ButtplugMessage,
+  {
+    let id = msg.id();
+    if id == 0 {
+      return SortedMessage::Event(msg);
+    }
+    match self.pending.remove(&id) {
+      Some(request) => {
+        trace!("Resolved id {} to a waiting request.", id);
+        SortedMessage::Reply(msg, request.value)
+      }
+      None => {
+        warn!("Message id {} doesn't match any waiting request.", id);
+        SortedMessage::Orphan(msg)
+      }
+    }
+  }
+
+  /// Removes and returns requests that have waited longer than the timeout.
+  /// Their replies will be orphans if they still show up.
+  pub fn expire(&mut self) -> Vec<(u32, P)> {
+    let now = Instant::now();
+    let expired: Vec<u32> = self
+      .pending
+      .iter()
+      .filter(|(_, request)| request.deadline.map_or(false, |deadline| deadline <= now))
+      .map(|(id, _)| *id)
+      .collect();
+    expired
+      .into_iter()
+      .filter_map(|id| self.pending.remove(&id).map(|request| (id, request.value)))
+      .collect()
+  }
+
+  /// When the next waiting request will time out, if any will.
+  pub fn next_deadline(&self) -> Option<Instant> {
+    self
+      .pending
+      .values()
+      .filter_map(|request| request.deadline)
+      .min()
+  }
+
+  /// Removes and returns every waiting request, for when the connection goes
+  /// away and the replies will never arrive.
+  pub fn cancel_all(&mut self) -> Vec<(u32, P)> {
+    self
+      .pending
+      .drain()
+      .map(|(id, request)| (id, request.value))
+      .collect()
+  }
+
+  /// Number of requests still waiting for a reply.
+  pub fn pending_count(&self) -> usize {
+    self.pending.len()
+  }
+
+  // Ids wrap around instead of overflowing, skipping 0 and any id that's
+  // still waiting on a reply.
+  fn next_id(&mut self) -> u32 {
+    loop {
+      let id = self.current_id;
+      self.current_id = self.current_id.wrapping_add(1);
+      if id != 0 && !self.pending.contains_key(&id) {
+        return id;
+      }
+    }
+  }
+}
+
+impl<P> Default for ClientMessageSorter<P> {
+  /// Creates a sorter that never times out requests.
+  fn default() -> Self {
+    Self::new(None)
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::{ClientMessageSorter, SortedMessage};
+  use crate::core::messages::{self, ButtplugMessage, DeviceRemoved, Ping};
+  use std::{thread, time::Duration};
+
+  #[test]
+  fn test_sort_replies_events_and_orphans() {
+    let mut sorter = ClientMessageSorter::default();
+    let mut ping = Ping::default();
+    let id = sorter.register(&mut ping, "ping");
+    assert_eq!(id, 1);
+    assert_eq!(ping.id(), 1);
+    assert_eq!(sorter.pending_count(), 1);
+    assert!(matches!(
+      sorter.sort(DeviceRemoved::new(0)),
+      SortedMessage::Event(_)
+    ));
+    assert!(matches!(
+      sorter.sort(messages::Ok::new(1)),
+      SortedMessage::Reply(_, "ping")
+    ));
+    // A second reply for the same id has nothing to go to.
+    assert!(matches!(
+      sorter.sort(messages::Ok::new(1)),
+      SortedMessage::Orphan(_)
+    ));
+    assert_eq!(sorter.pending_count(), 0);
+  }
+
+  #[test]
+  fn test_ids_skip_zero_and_waiting_requests() {
+    let mut sorter = ClientMessageSorter::default();
+    sorter.current_id = u32::MAX;
+    assert_eq!(sorter.register(&mut Ping::default(), ()), u32::MAX);
+    assert_eq!(sorter.register(&mut Ping::default(), ()), 1);
+    sorter.current_id = u32::MAX;
+    assert_eq!(sorter.register(&mut Ping::default(), ()), 2);
+  }
+
+  #[test]
+  fn test_expire_and_cancel() {
+    let mut sorter = ClientMessageSorter::new(Some(Duration::from_millis(10)));
+    let id = sorter.register(&mut Ping::default(), "first");
+    assert!(sorter.expire().is_empty());
+    assert!(sorter.next_deadline().is_some());
+    thread::sleep(Duration::from_millis(20));
+    sorter.register(&mut Ping::default(), "second");
+    assert_eq!(sorter.expire(), vec![(id, "first")]);
+    // A reply after the timeout is an orphan.
+    assert!(matches!(
+      sorter.sort(messages::Ok::new(id)),
+      SortedMessage::Orphan(_)
+    ));
+    assert_eq!(sorter.cancel_all().len(), 1);
+    assert!(sorter.next_deadline().is_none());
+  }
+}
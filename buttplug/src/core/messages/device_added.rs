@@ -5,7 +5,10 @@
 // Licensed under the BSD 3-Clause license. See LICENSE file in the project root
 // for full license information.
 
-use super::device_message_info::{DeviceMessageInfoV0, DeviceMessageInfoV1};
+use super::device_message_info::{
+  v3_attributes_map, v3_attributes_map_from, DeviceMessageInfoV0, DeviceMessageInfoV1,
+  DeviceMessageInfoV2,
+};
 use super::*;
 
 #[cfg(feature = "serialize-json")]
@@ -13,6 +16,7 @@ use serde::{Deserialize, Serialize};
 
 #[derive(Default, ButtplugMessage, Clone, Debug, PartialEq)]
 #[cfg_attr(feature = "serialize-json", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "json-schema", derive(schemars::JsonSchema))]
 pub struct DeviceAdded {
   #[cfg_attr(feature = "serialize-json", serde(rename = "Id"))]
   id: u32,
@@ -20,7 +24,14 @@ pub struct DeviceAdded {
   device_index: u32,
   #[cfg_attr(feature = "serialize-json", serde(rename = "DeviceName"))]
   device_name: String,
-  #[cfg_attr(feature = "serialize-json", serde(rename = "DeviceMessages"))]
+  #[cfg_attr(
+    feature = "serialize-json",
+    serde(
+      rename = "DeviceMessages",
+      serialize_with = "v3_attributes_map",
+      deserialize_with = "v3_attributes_map_from"
+    )
+  )]
   device_messages: DeviceMessageAttributesMap,
 }
 
@@ -59,6 +70,48 @@ impl ButtplugMessageValidator for DeviceAdded {
 
 #[derive(Default, ButtplugMessage, Clone, Debug, PartialEq)]
 #[cfg_attr(feature = "serialize-json", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "json-schema", derive(schemars::JsonSchema))]
+pub struct DeviceAddedV2 {
+  #[cfg_attr(feature = "serialize-json", serde(rename = "Id"))]
+  id: u32,
+  #[cfg_attr(feature = "serialize-json", serde(rename = "DeviceIndex"))]
+  device_index: u32,
+  #[cfg_attr(feature = "serialize-json", serde(rename = "DeviceName"))]
+  device_name: String,
+  #[cfg_attr(feature = "serialize-json", serde(rename = "DeviceMessages"))]
+  device_messages: DeviceMessageAttributesMap,
+}
+
+impl DeviceAddedV2 {
+  pub fn device_messages(&self) -> &DeviceMessageAttributesMap {
+    &self.device_messages
+  }
+}
+
+impl From<DeviceAdded> for DeviceAddedV2 {
+  fn from(msg: DeviceAdded) -> Self {
+    let id = msg.id();
+    let dmi = DeviceMessageInfo::from(msg);
+    let dmiv2 = DeviceMessageInfoV2::from(dmi);
+
+    Self {
+      id,
+      device_index: dmiv2.device_index,
+      device_name: dmiv2.device_name,
+      device_messages: dmiv2.device_messages,
+    }
+  }
+}
+
+impl ButtplugMessageValidator for DeviceAddedV2 {
+  fn is_valid(&self) -> Result<(), ButtplugMessageError> {
+    self.is_system_id(self.id)
+  }
+}
+
+#[derive(Default, ButtplugMessage, Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serialize-json", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "json-schema", derive(schemars::JsonSchema))]
 pub struct DeviceAddedV1 {
   #[cfg_attr(feature = "serialize-json", serde(rename = "Id"))]
   id: u32,
@@ -93,6 +146,7 @@ impl ButtplugMessageValidator for DeviceAddedV1 {
 
 #[derive(Default, ButtplugMessage, Clone, Debug, PartialEq)]
 #[cfg_attr(feature = "serialize-json", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "json-schema", derive(schemars::JsonSchema))]
 pub struct DeviceAddedV0 {
   #[cfg_attr(feature = "serialize-json", serde(rename = "Id"))]
   id: u32,
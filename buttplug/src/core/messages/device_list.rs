@@ -5,13 +5,14 @@
 // Licensed under the BSD 3-Clause license. See LICENSE file in the project root
 // for full license information.
 
-use super::device_message_info::{DeviceMessageInfoV0, DeviceMessageInfoV1};
+use super::device_message_info::{DeviceMessageInfoV0, DeviceMessageInfoV1, DeviceMessageInfoV2};
 use super::*;
 #[cfg(feature = "serialize-json")]
 use serde::{Deserialize, Serialize};
 
 #[derive(Default, Clone, Debug, PartialEq, ButtplugMessage)]
 #[cfg_attr(feature = "serialize-json", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "json-schema", derive(schemars::JsonSchema))]
 pub struct DeviceList {
   #[cfg_attr(feature = "serialize-json", serde(rename = "Id"))]
   id: u32,
@@ -37,6 +38,36 @@ impl ButtplugMessageValidator for DeviceList {
 
 #[derive(Default, Clone, Debug, PartialEq, ButtplugMessage)]
 #[cfg_attr(feature = "serialize-json", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "json-schema", derive(schemars::JsonSchema))]
+pub struct DeviceListV2 {
+  #[cfg_attr(feature = "serialize-json", serde(rename = "Id"))]
+  id: u32,
+  #[cfg_attr(feature = "serialize-json", serde(rename = "Devices"))]
+  devices: Vec<DeviceMessageInfoV2>,
+}
+
+impl From<DeviceList> for DeviceListV2 {
+  fn from(msg: DeviceList) -> Self {
+    let mut devices = vec![];
+    for d in msg.devices {
+      devices.push(DeviceMessageInfoV2::from(d));
+    }
+    Self {
+      id: msg.id,
+      devices,
+    }
+  }
+}
+
+impl ButtplugMessageValidator for DeviceListV2 {
+  fn is_valid(&self) -> Result<(), ButtplugMessageError> {
+    self.is_not_system_id(self.id)
+  }
+}
+
+#[derive(Default, Clone, Debug, PartialEq, ButtplugMessage)]
+#[cfg_attr(feature = "serialize-json", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "json-schema", derive(schemars::JsonSchema))]
 pub struct DeviceListV1 {
   #[cfg_attr(feature = "serialize-json", serde(rename = "Id"))]
   id: u32,
@@ -65,6 +96,7 @@ impl ButtplugMessageValidator for DeviceListV1 {
 
 #[derive(Default, Clone, Debug, PartialEq, ButtplugMessage)]
 #[cfg_attr(feature = "serialize-json", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "json-schema", derive(schemars::JsonSchema))]
 pub struct DeviceListV0 {
   #[cfg_attr(feature = "serialize-json", serde(rename = "Id"))]
   id: u32,
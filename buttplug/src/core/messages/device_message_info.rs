@@ -7,10 +7,162 @@
 
 use super::*;
 #[cfg(feature = "serialize-json")]
-use serde::{Deserialize, Serialize, Serializer};
-use std::collections::{BTreeMap, HashMap};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use once_cell::sync::Lazy;
+use std::{
+  collections::{hash_map, BTreeMap, HashMap},
+  iter::FromIterator,
+  ops::{Deref, DerefMut},
+  sync::{Arc, Mutex, Weak},
+};
 
-pub type DeviceMessageAttributesMap = HashMap<ButtplugDeviceMessageType, DeviceMessageAttributes>;
+type AttributesHashMap = HashMap<ButtplugDeviceMessageType, DeviceMessageAttributes>;
+
+// Maps handed out by DeviceMessageAttributesMap::interned(). Only a few
+// distinct maps are alive at once (roughly one per device model connected), so
+// a list is plenty.
+static INTERNED_ATTRIBUTE_MAPS: Lazy<Mutex<Vec<Weak<AttributesHashMap>>>> =
+  Lazy::new(|| Mutex::new(vec![]));
+
+/// Message attributes of a device, keyed by message type.
+///
+/// Attribute maps get copied into every DeviceAdded and DeviceList message,
+/// as well as each protocol, so the map is kept behind an [Arc] and clones
+/// share it. It derefs to a [HashMap] for reading, and is copied on the first
+/// write to a shared map, so changing one copy (e.g. applying user config
+/// overrides) never affects the others.
+#[derive(Clone, Debug, Default)]
+pub struct DeviceMessageAttributesMap {
+  attributes: Arc<AttributesHashMap>,
+}
+
+impl DeviceMessageAttributesMap {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Returns a map sharing its storage with any other interned map with the
+  /// same contents, so devices of the same model don't each hold a copy.
+  pub fn interned(self) -> Self {
+    let mut interned = INTERNED_ATTRIBUTE_MAPS.lock().unwrap();
+    interned.retain(|attributes| attributes.strong_count() > 0);
+    for attributes in interned.iter().filter_map(Weak::upgrade) {
+      if Arc::ptr_eq(&attributes, &self.attributes) || *attributes == *self.attributes {
+        return Self { attributes };
+      }
+    }
+    interned.push(Arc::downgrade(&self.attributes));
+    self
+  }
+
+  /// True if both maps share the same storage.
+  pub fn ptr_eq(&self, other: &Self) -> bool {
+    Arc::ptr_eq(&self.attributes, &other.attributes)
+  }
+}
+
+impl Deref for DeviceMessageAttributesMap {
+  type Target = AttributesHashMap;
+
+  fn deref(&self) -> &Self::Target {
+    &self.attributes
+  }
+}
+
+impl DerefMut for DeviceMessageAttributesMap {
+  fn deref_mut(&mut self) -> &mut Self::Target {
+    Arc::make_mut(&mut self.attributes)
+  }
+}
+
+impl PartialEq for DeviceMessageAttributesMap {
+  fn eq(&self, other: &Self) -> bool {
+    self.ptr_eq(other) || self.attributes == other.attributes
+  }
+}
+
+impl From<AttributesHashMap> for DeviceMessageAttributesMap {
+  fn from(attributes: AttributesHashMap) -> Self {
+    Self {
+      attributes: Arc::new(attributes),
+    }
+  }
+}
+
+impl FromIterator<(ButtplugDeviceMessageType, DeviceMessageAttributes)>
+  for DeviceMessageAttributesMap
+{
+  fn from_iter<T>(iter: T) -> Self
+  where
+    T: IntoIterator<Item = (ButtplugDeviceMessageType, DeviceMessageAttributes)>,
+  {
+    AttributesHashMap::from_iter(iter).into()
+  }
+}
+
+impl Extend<(ButtplugDeviceMessageType, DeviceMessageAttributes)> for DeviceMessageAttributesMap {
+  fn extend<T>(&mut self, iter: T)
+  where
+    T: IntoIterator<Item = (ButtplugDeviceMessageType, DeviceMessageAttributes)>,
+  {
+    let mut iter = iter.into_iter().peekable();
+    // Don't unshare the map for nothing.
+    if iter.peek().is_some() {
+      self.deref_mut().extend(iter);
+    }
+  }
+}
+
+impl IntoIterator for DeviceMessageAttributesMap {
+  type Item = (ButtplugDeviceMessageType, DeviceMessageAttributes);
+  type IntoIter = hash_map::IntoIter<ButtplugDeviceMessageType, DeviceMessageAttributes>;
+
+  fn into_iter(self) -> Self::IntoIter {
+    Arc::try_unwrap(self.attributes)
+      .unwrap_or_else(|attributes| (*attributes).clone())
+      .into_iter()
+  }
+}
+
+impl<'a> IntoIterator for &'a DeviceMessageAttributesMap {
+  type Item = (&'a ButtplugDeviceMessageType, &'a DeviceMessageAttributes);
+  type IntoIter = hash_map::Iter<'a, ButtplugDeviceMessageType, DeviceMessageAttributes>;
+
+  fn into_iter(self) -> Self::IntoIter {
+    self.attributes.iter()
+  }
+}
+
+// Always (de)serializable, like DeviceMessageAttributes, as device
+// configuration files use it too.
+impl serde::Serialize for DeviceMessageAttributesMap {
+  fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+  where
+    S: serde::Serializer,
+  {
+    serde::Serialize::serialize(&*self.attributes, serializer)
+  }
+}
+
+impl<'de> serde::Deserialize<'de> for DeviceMessageAttributesMap {
+  fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+  where
+    D: serde::Deserializer<'de>,
+  {
+    <AttributesHashMap as serde::Deserialize>::deserialize(deserializer).map(Self::from)
+  }
+}
+
+#[cfg(feature = "json-schema")]
+impl schemars::JsonSchema for DeviceMessageAttributesMap {
+  fn schema_name() -> String {
+    AttributesHashMap::schema_name()
+  }
+
+  fn json_schema(generator: &mut schemars::gen::SchemaGenerator) -> schemars::schema::Schema {
+    AttributesHashMap::json_schema(generator)
+  }
+}
 
 fn ordered_map<S>(value: &DeviceMessageAttributesMap, serializer: S) -> Result<S::Ok, S::Error>
 where
@@ -20,8 +172,40 @@ where
   ordered.serialize(serializer)
 }
 
+/// Serializes attributes in the spec v3 format, where feature info lives in
+/// per feature descriptors.
+pub(super) fn v3_attributes_map<S>(
+  value: &DeviceMessageAttributesMap,
+  serializer: S,
+) -> Result<S::Ok, S::Error>
+where
+  S: Serializer,
+{
+  let ordered: BTreeMap<_, _> = value
+    .iter()
+    .map(|(message_type, attributes)| (message_type, attributes.as_v3(*message_type)))
+    .collect();
+  ordered.serialize(serializer)
+}
+
+/// Deserializes spec v3 attributes, filling in the flat attributes from the
+/// feature descriptors so code reading either form works.
+pub(super) fn v3_attributes_map_from<'de, D>(
+  deserializer: D,
+) -> Result<DeviceMessageAttributesMap, D::Error>
+where
+  D: Deserializer<'de>,
+{
+  let mut attributes_map = DeviceMessageAttributesMap::deserialize(deserializer)?;
+  for attributes in attributes_map.values_mut() {
+    attributes.fill_flat_attributes();
+  }
+  Ok(attributes_map)
+}
+
 #[derive(Clone, Debug, PartialEq)]
 #[cfg_attr(feature = "serialize-json", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "json-schema", derive(schemars::JsonSchema))]
 pub struct DeviceMessageInfo {
   #[cfg_attr(feature = "serialize-json", serde(rename = "DeviceIndex"))]
   pub device_index: u32,
@@ -29,7 +213,11 @@ pub struct DeviceMessageInfo {
   pub device_name: String,
   #[cfg_attr(
     feature = "serialize-json",
-    serde(rename = "DeviceMessages", serialize_with = "ordered_map")
+    serde(
+      rename = "DeviceMessages",
+      serialize_with = "v3_attributes_map",
+      deserialize_with = "v3_attributes_map_from"
+    )
   )]
   pub device_messages: DeviceMessageAttributesMap,
   // We need to store off the original device messages we had passed in, as we
@@ -67,6 +255,45 @@ impl From<DeviceAdded> for DeviceMessageInfo {
 
 #[derive(Clone, Debug, PartialEq)]
 #[cfg_attr(feature = "serialize-json", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "json-schema", derive(schemars::JsonSchema))]
+pub struct DeviceMessageInfoV2 {
+  #[cfg_attr(feature = "serialize-json", serde(rename = "DeviceIndex"))]
+  pub device_index: u32,
+  #[cfg_attr(feature = "serialize-json", serde(rename = "DeviceName"))]
+  pub device_name: String,
+  #[cfg_attr(
+    feature = "serialize-json",
+    serde(rename = "DeviceMessages", serialize_with = "ordered_map")
+  )]
+  pub device_messages: DeviceMessageAttributesMap,
+}
+
+impl From<DeviceAdded> for DeviceMessageInfoV2 {
+  fn from(device_added: DeviceAdded) -> Self {
+    let dmi = DeviceMessageInfo::from(device_added);
+    DeviceMessageInfoV2::from(dmi)
+  }
+}
+
+impl From<DeviceMessageInfo> for DeviceMessageInfoV2 {
+  fn from(device_message_info: DeviceMessageInfo) -> Self {
+    // Same messages as v3, but feature descriptors get flattened back down
+    // into FeatureCount/StepCount/SensorType.
+    Self {
+      device_index: device_message_info.device_index,
+      device_name: device_message_info.device_name,
+      device_messages: device_message_info
+        .device_messages
+        .iter()
+        .map(|(message_type, attributes)| (*message_type, attributes.as_v2()))
+        .collect(),
+    }
+  }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serialize-json", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "json-schema", derive(schemars::JsonSchema))]
 pub struct DeviceMessageInfoV1 {
   #[cfg_attr(feature = "serialize-json", serde(rename = "DeviceIndex"))]
   pub device_index: u32,
@@ -102,6 +329,7 @@ impl From<DeviceMessageInfo> for DeviceMessageInfoV1 {
       ButtplugDeviceMessageType::RawUnsubscribeCmd,
       ButtplugDeviceMessageType::BatteryLevelCmd,
       ButtplugDeviceMessageType::RSSILevelCmd,
+      ButtplugDeviceMessageType::SensorSubscribeCmd,
     ];
     for t in &v2_message_types {
       dmi_v1.device_messages.remove(t);
@@ -111,20 +339,35 @@ impl From<DeviceMessageInfo> for DeviceMessageInfoV1 {
     // preserve.
     for attributes in &mut dmi_v1.device_messages.values_mut() {
       *attributes = DeviceMessageAttributes {
-        feature_count: attributes.feature_count,
+        feature_count: attributes.as_v2().feature_count,
         ..Default::default()
       };
     }
 
-    // If VibrateCmd is listed, append SingleMotorVibrateCmd
-    if dmi_v1
-      .device_messages
-      .contains_key(&ButtplugDeviceMessageType::VibrateCmd)
-    {
-      dmi_v1.device_messages.insert(
+    // The server translates legacy messages into the generic messages that
+    // replaced them, so list each legacy message the device can take that
+    // way.
+    let legacy_message_types = [
+      (
+        ButtplugDeviceMessageType::VibrateCmd,
         ButtplugDeviceMessageType::SingleMotorVibrateCmd,
-        DeviceMessageAttributes::default(),
-      );
+      ),
+      (
+        ButtplugDeviceMessageType::LinearCmd,
+        ButtplugDeviceMessageType::FleshlightLaunchFW12Cmd,
+      ),
+      (
+        ButtplugDeviceMessageType::RotateCmd,
+        ButtplugDeviceMessageType::VorzeA10CycloneCmd,
+      ),
+    ];
+    for (generic_type, legacy_type) in &legacy_message_types {
+      if dmi_v1.device_messages.contains_key(generic_type) {
+        dmi_v1
+          .device_messages
+          .entry(*legacy_type)
+          .or_insert_with(DeviceMessageAttributes::default);
+      }
     }
 
     dmi_v1
@@ -133,6 +376,7 @@ impl From<DeviceMessageInfo> for DeviceMessageInfoV1 {
 
 #[derive(Clone, Debug, PartialEq)]
 #[cfg_attr(feature = "serialize-json", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "json-schema", derive(schemars::JsonSchema))]
 pub struct DeviceMessageInfoV0 {
   #[cfg_attr(feature = "serialize-json", serde(rename = "DeviceIndex"))]
   pub device_index: u32,
@@ -168,8 +412,8 @@ impl From<DeviceMessageInfoV1> for DeviceMessageInfoV0 {
     device_messages.retain(|x| !v1_message_types.contains(x));
     device_messages.sort();
 
-    // SingleMotorVibrateCmd is added as part of the V1 conversion, so we
-    // can expect we'll have it here.
+    // Legacy messages are added as part of the V1 conversion, so we can
+    // expect we'll have them here.
     Self {
       device_name: device_message_info.device_name,
       device_index: device_message_info.device_index,
@@ -177,3 +421,46 @@ impl From<DeviceMessageInfoV1> for DeviceMessageInfoV0 {
     }
   }
 }
+
+#[cfg(test)]
+mod test {
+  use crate::core::messages::{
+    ButtplugDeviceMessageType, DeviceMessageAttributes, DeviceMessageAttributesMap,
+  };
+
+  fn vibrate_attributes(feature_count: u32) -> DeviceMessageAttributesMap {
+    vec![(
+      ButtplugDeviceMessageType::VibrateCmd,
+      DeviceMessageAttributes {
+        feature_count: Some(feature_count),
+        ..Default::default()
+      },
+    )]
+    .into_iter()
+    .collect()
+  }
+
+  #[test]
+  fn test_attributes_map_copy_on_write() {
+    let original = vibrate_attributes(2);
+    let mut copy = original.clone();
+    assert!(copy.ptr_eq(&original));
+    copy.insert(
+      ButtplugDeviceMessageType::StopDeviceCmd,
+      DeviceMessageAttributes::default(),
+    );
+    assert!(!copy.ptr_eq(&original));
+    assert_eq!(original.len(), 1);
+    assert_eq!(copy.len(), 2);
+  }
+
+  #[test]
+  fn test_attributes_map_interning() {
+    let first = vibrate_attributes(3).interned();
+    let second = vibrate_attributes(3).interned();
+    assert!(first.ptr_eq(&second));
+    let different = vibrate_attributes(4).interned();
+    assert!(!different.ptr_eq(&first));
+    assert_eq!(different, vibrate_attributes(4));
+  }
+}
@@ -11,6 +11,7 @@ use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Default, ButtplugMessage, Clone, PartialEq)]
 #[cfg_attr(feature = "serialize-json", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "json-schema", derive(schemars::JsonSchema))]
 pub struct DeviceRemoved {
   #[cfg_attr(feature = "serialize-json", serde(rename = "Id"))]
   id: u32,
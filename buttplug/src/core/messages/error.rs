@@ -16,6 +16,7 @@ use serde_repr::{Deserialize_repr, Serialize_repr};
 /// Buttplug [Error] message.
 #[derive(Debug, Clone, PartialEq, Copy)]
 #[cfg_attr(feature = "serialize-json", derive(Serialize_repr, Deserialize_repr))]
+#[cfg_attr(feature = "json-schema", derive(schemars::JsonSchema_repr))]
 #[repr(u8)]
 pub enum ErrorCode {
   ErrorUnknown = 0,
@@ -32,6 +33,7 @@ pub enum ErrorCode {
 // ButtplugMessageValidator.
 #[derive(Debug, Clone, ButtplugMessage, ButtplugMessageValidator)]
 #[cfg_attr(feature = "serialize-json", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "json-schema", derive(schemars::JsonSchema))]
 pub struct Error {
   /// Message Id, used for matching message pairs in remote connection instances.
   #[cfg_attr(feature = "serialize-json", serde(rename = "Id"))]
@@ -106,6 +108,7 @@ impl From<ButtplugError> for Error {
 
 #[derive(Debug, Clone, PartialEq, ButtplugMessage, ButtplugMessageValidator)]
 #[cfg_attr(feature = "serialize-json", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "json-schema", derive(schemars::JsonSchema))]
 pub struct ErrorV0 {
   /// Message Id, used for matching message pairs in remote connection instances.
   #[cfg_attr(feature = "serialize-json", serde(rename = "Id"))]
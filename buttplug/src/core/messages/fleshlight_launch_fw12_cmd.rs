@@ -11,6 +11,7 @@ use serde::{Deserialize, Serialize};
 
 #[derive(Debug, ButtplugDeviceMessage, PartialEq, Clone)]
 #[cfg_attr(feature = "serialize-json", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "json-schema", derive(schemars::JsonSchema))]
 pub struct FleshlightLaunchFW12Cmd {
   #[cfg_attr(feature = "serialize-json", serde(rename = "Id"))]
   id: u32,
@@ -44,12 +45,12 @@ impl FleshlightLaunchFW12Cmd {
 impl ButtplugMessageValidator for FleshlightLaunchFW12Cmd {
   fn is_valid(&self) -> Result<(), ButtplugMessageError> {
     self.is_not_system_id(self.id)?;
-    if !(0..99).contains(&self.speed) {
+    if !(0..=99).contains(&self.speed) {
       Err(ButtplugMessageError::InvalidMessageContents(format!(
         "FleshlightFW12Cmd speed {} invalid, should be between 0 and 99",
         self.speed
       )))
-    } else if !(0..99).contains(&self.position) {
+    } else if !(0..=99).contains(&self.position) {
       Err(ButtplugMessageError::InvalidMessageContents(format!(
         "FleshlightFW12Cmd position {} invalid, should be between 0 and 99",
         self.position
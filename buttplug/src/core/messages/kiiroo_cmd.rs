@@ -12,6 +12,7 @@ use serde::{Deserialize, Serialize};
 // Dear god this needs to be deprecated
 #[derive(Debug, ButtplugDeviceMessage, PartialEq, Clone)]
 #[cfg_attr(feature = "serialize-json", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "json-schema", derive(schemars::JsonSchema))]
 pub struct KiirooCmd {
   #[cfg_attr(feature = "serialize-json", serde(rename = "Id"))]
   id: u32,
@@ -11,6 +11,7 @@ use serde::{Deserialize, Serialize};
 
 #[derive(Debug, PartialEq, Clone)]
 #[cfg_attr(feature = "serialize-json", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "json-schema", derive(schemars::JsonSchema))]
 pub struct VectorSubcommand {
   #[cfg_attr(feature = "serialize-json", serde(rename = "Index"))]
   pub index: u32,
@@ -44,6 +45,7 @@ impl VectorSubcommand {
 
 #[derive(Debug, ButtplugDeviceMessage, PartialEq, Clone)]
 #[cfg_attr(feature = "serialize-json", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "json-schema", derive(schemars::JsonSchema))]
 pub struct LinearCmd {
   #[cfg_attr(feature = "serialize-json", serde(rename = "Id"))]
   id: u32,
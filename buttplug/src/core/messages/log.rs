@@ -11,6 +11,7 @@ use serde::{Deserialize, Serialize};
 
 #[derive(Debug, ButtplugMessage, PartialEq, Clone)]
 #[cfg_attr(feature = "serialize-json", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "json-schema", derive(schemars::JsonSchema))]
 pub struct Log {
   #[cfg_attr(feature = "serialize-json", serde(rename = "Id"))]
   id: u32,
@@ -28,6 +29,14 @@ impl Log {
       log_message: log_message.to_owned(),
     }
   }
+
+  pub fn log_level(&self) -> &LogLevel {
+    &self.log_level
+  }
+
+  pub fn log_message(&self) -> &String {
+    &self.log_message
+  }
 }
 
 impl ButtplugMessageValidator for Log {
@@ -12,6 +12,7 @@ use tracing::Level;
 
 #[derive(Debug, PartialEq, Clone, Ord, PartialOrd, Eq)]
 #[cfg_attr(feature = "serialize-json", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "json-schema", derive(schemars::JsonSchema))]
 pub enum LogLevel {
   Off = 0,
   Fatal,
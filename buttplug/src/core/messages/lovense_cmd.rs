@@ -14,6 +14,7 @@ use serde::{Deserialize, Serialize};
 // it.
 #[derive(Debug, ButtplugDeviceMessage, PartialEq, Clone)]
 #[cfg_attr(feature = "serialize-json", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "json-schema", derive(schemars::JsonSchema))]
 pub struct LovenseCmd {
   #[cfg_attr(feature = "serialize-json", serde(rename = "Id"))]
   id: u32,
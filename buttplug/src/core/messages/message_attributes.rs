@@ -5,6 +5,7 @@
 // Licensed under the BSD 3-Clause license. See LICENSE file in the project root
 // for full license information.
 
+use super::ButtplugDeviceMessageType;
 use crate::device::Endpoint;
 use serde::{Deserialize, Serialize};
 
@@ -17,6 +18,7 @@ use serde::{Deserialize, Serialize};
 // accessors to everything.
 
 #[derive(Clone, Debug, PartialEq, Default, Serialize, Deserialize)]
+#[cfg_attr(feature = "json-schema", derive(schemars::JsonSchema))]
 pub struct DeviceMessageAttributes {
   #[serde(rename = "FeatureCount")]
   #[serde(skip_serializing_if = "Option::is_none")]
@@ -30,6 +32,9 @@ pub struct DeviceMessageAttributes {
   #[serde(rename = "MaxDuration")]
   #[serde(skip_serializing_if = "Option::is_none")]
   pub max_duration: Option<Vec<u32>>,
+  #[serde(rename = "SensorType")]
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub sensor_type: Option<Vec<SensorType>>,
   /*
   // Unimplemented attributes
   #[serde(rename = "Patterns")]
@@ -43,4 +48,238 @@ pub struct DeviceMessageAttributes {
   #[serde(rename = "FeatureOrder")]
   #[serde(skip)]
   pub feature_order: Option<Vec<u32>>,
+  /// Per feature descriptors, used from spec v3 on. When these aren't set,
+  /// they're built from the flat attributes above, see
+  /// [DeviceMessageAttributes::features_for].
+  #[serde(rename = "Features")]
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub features: Option<Vec<DeviceFeatureDescriptor>>,
+}
+
+impl DeviceMessageAttributes {
+  /// Returns the per feature descriptors for these attributes. If none were
+  /// set explicitly, they're built from FeatureCount/StepCount/SensorType, so
+  /// attributes that only use the older flat format still describe their
+  /// features. Message types that don't address features return None.
+  pub fn features_for(
+    &self,
+    message_type: ButtplugDeviceMessageType,
+  ) -> Option<Vec<DeviceFeatureDescriptor>> {
+    if self.features.is_some() {
+      return self.features.clone();
+    }
+    let actuator_type = match message_type {
+      ButtplugDeviceMessageType::VibrateCmd => Some(ActuatorType::Vibrate),
+      ButtplugDeviceMessageType::RotateCmd => Some(ActuatorType::Rotate),
+      ButtplugDeviceMessageType::LinearCmd => Some(ActuatorType::Position),
+      ButtplugDeviceMessageType::SensorSubscribeCmd => None,
+      _ => return None,
+    };
+    let feature_count = self
+      .feature_count
+      .map(|count| count as usize)
+      .or_else(|| self.step_count.as_ref().map(|steps| steps.len()))
+      .or_else(|| self.sensor_type.as_ref().map(|sensors| sensors.len()))
+      .unwrap_or(0);
+    Some(
+      (0..feature_count)
+        .map(|index| DeviceFeatureDescriptor {
+          descriptor: String::new(),
+          actuator_type,
+          sensor_type: self
+            .sensor_type
+            .as_ref()
+            .and_then(|sensors| sensors.get(index).copied()),
+          step_range: self
+            .step_count
+            .as_ref()
+            .and_then(|steps| steps.get(index))
+            .map(|steps| (0, *steps)),
+          duplicate_of: None,
+          stop_behavior: None,
+        })
+        .collect(),
+    )
+  }
+
+  /// Fills in FeatureCount/StepCount/SensorType from the feature descriptors,
+  /// for anything that still reads the flat attributes (spec v2 and older
+  /// clients, command managers, etc). Flat attributes that are already set
+  /// are left alone.
+  pub fn fill_flat_attributes(&mut self) {
+    let features = match &self.features {
+      Some(features) if !features.is_empty() => features,
+      _ => return,
+    };
+    if self.feature_count.is_none() {
+      self.feature_count = Some(features.len() as u32);
+    }
+    if self.step_count.is_none() && features.iter().all(|f| f.step_range.is_some()) {
+      self.step_count = Some(
+        features
+          .iter()
+          .map(|feature| feature.step_count().unwrap())
+          .collect(),
+      );
+    }
+    if self.sensor_type.is_none() && features.iter().all(|f| f.sensor_type.is_some()) {
+      self.sensor_type = Some(features.iter().map(|f| f.sensor_type.unwrap()).collect());
+    }
+  }
+
+  /// Returns these attributes with any fields set in `overrides` replacing
+  /// ours. Fields the override leaves out are inherited. If only StepCount is
+  /// overridden, the step ranges of our feature descriptors are updated to
+  /// match, so both spec versions see the same steps.
+  pub fn merge(&self, overrides: &DeviceMessageAttributes) -> Self {
+    let mut merged = self.clone();
+    merged.fill_flat_attributes();
+    if overrides.features.is_some() {
+      merged.features = overrides.features.clone();
+      merged.feature_count = None;
+      merged.step_count = None;
+      merged.sensor_type = None;
+      merged.fill_flat_attributes();
+    }
+    if let Some(feature_count) = overrides.feature_count {
+      merged.feature_count = Some(feature_count);
+    }
+    if let Some(step_count) = &overrides.step_count {
+      merged.step_count = Some(step_count.clone());
+      if overrides.features.is_none() {
+        if let Some(features) = merged.features.as_mut() {
+          for (feature, steps) in features.iter_mut().zip(step_count) {
+            let min = feature.step_range.map_or(0, |(min, _)| min);
+            feature.step_range = Some((min, *steps));
+          }
+        }
+      }
+    }
+    if overrides.endpoints.is_some() {
+      merged.endpoints = overrides.endpoints.clone();
+    }
+    if overrides.max_duration.is_some() {
+      merged.max_duration = overrides.max_duration.clone();
+    }
+    if overrides.sensor_type.is_some() {
+      merged.sensor_type = overrides.sensor_type.clone();
+    }
+    merged
+  }
+
+  /// Attributes as sent in spec v3, with feature info only in the feature
+  /// descriptors.
+  pub fn as_v3(&self, message_type: ButtplugDeviceMessageType) -> Self {
+    let features = self.features_for(message_type);
+    if features.is_none() {
+      return Self {
+        features: None,
+        ..self.clone()
+      };
+    }
+    Self {
+      feature_count: None,
+      step_count: None,
+      sensor_type: None,
+      features,
+      ..self.clone()
+    }
+  }
+
+  /// Attributes as sent in spec v2, with feature info only in the flat
+  /// attributes.
+  pub fn as_v2(&self) -> Self {
+    let mut attributes = self.clone();
+    attributes.fill_flat_attributes();
+    attributes.features = None;
+    attributes
+  }
+}
+
+/// Describes a single feature (motor, rotator, sensor, etc) of a device.
+#[derive(Clone, Debug, PartialEq, Default, Serialize, Deserialize)]
+#[cfg_attr(feature = "json-schema", derive(schemars::JsonSchema))]
+pub struct DeviceFeatureDescriptor {
+  /// Human readable name of the feature, e.g. "Clitoral vibrator". May be
+  /// empty if the device configuration doesn't name it.
+  #[serde(rename = "FeatureDescriptor")]
+  #[serde(default)]
+  pub descriptor: String,
+  #[serde(rename = "ActuatorType")]
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub actuator_type: Option<ActuatorType>,
+  #[serde(rename = "SensorType")]
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub sensor_type: Option<SensorType>,
+  /// Lowest and highest step the feature can be set to. Commands are still
+  /// sent as 0.0-1.0, which is mapped onto this range.
+  #[serde(rename = "StepRange")]
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub step_range: Option<(u32, u32)>,
+  /// Index of another feature this one duplicates, for devices that expose
+  /// the same physical output more than once. Lets clients hide the copy.
+  #[serde(rename = "DuplicateOf")]
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub duplicate_of: Option<u32>,
+  /// What the feature does when the device is stopped. Only read from the
+  /// device configuration, clients don't see it.
+  #[serde(rename = "StopBehavior")]
+  #[serde(skip_serializing)]
+  pub stop_behavior: Option<StopBehavior>,
+}
+
+impl DeviceFeatureDescriptor {
+  /// Step count as used in spec v2's StepCount attribute, i.e. the top of the
+  /// step range.
+  pub fn step_count(&self) -> Option<u32> {
+    self.step_range.map(|(_, max)| max)
+  }
+}
+
+/// Kind of output a device feature has.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[cfg_attr(feature = "json-schema", derive(schemars::JsonSchema))]
+pub enum ActuatorType {
+  Vibrate,
+  Rotate,
+  Oscillate,
+  Constrict,
+  Inflate,
+  Position,
+}
+
+/// What a feature does when its device is stopped. Features without one in
+/// the device configuration use [StopBehavior::Stop].
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "json-schema", derive(schemars::JsonSchema))]
+pub enum StopBehavior {
+  /// Vibrators, rotators and the like are set to 0. Position features have
+  /// nothing to set, so they stay wherever their last move takes them.
+  Stop,
+  /// Position features move to `Position` (0.0-1.0) over `Duration`
+  /// milliseconds, e.g. to bring a stroker back home slowly instead of
+  /// leaving it where it was. Other features treat this as
+  /// [StopBehavior::Stop].
+  MoveTo {
+    #[serde(rename = "Position")]
+    position: f64,
+    #[serde(rename = "Duration")]
+    duration: u32,
+  },
+}
+
+impl Default for StopBehavior {
+  fn default() -> Self {
+    StopBehavior::Stop
+  }
+}
+
+/// Kind of data a device sensor reports, used in the SensorType attribute of
+/// SensorSubscribeCmd and in SensorReading.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[cfg_attr(feature = "json-schema", derive(schemars::JsonSchema))]
+pub enum SensorType {
+  Pressure,
+  Position,
+  Accelerometer,
 }
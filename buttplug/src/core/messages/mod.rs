@@ -42,6 +42,9 @@ mod rotate_cmd;
 mod rssi_level_cmd;
 mod rssi_level_reading;
 mod scanning_finished;
+mod sensor_reading;
+mod sensor_subscribe_cmd;
+mod sensor_unsubscribe_cmd;
 pub mod serializer;
 mod server_info;
 mod single_motor_vibrate_cmd;
@@ -56,9 +59,9 @@ mod vorze_a10_cyclone_cmd;
 pub use self::log::Log;
 pub use battery_level_cmd::BatteryLevelCmd;
 pub use battery_level_reading::BatteryLevelReading;
-pub use device_added::{DeviceAdded, DeviceAddedV0, DeviceAddedV1};
-pub use device_list::{DeviceList, DeviceListV0, DeviceListV1};
-pub use device_message_info::{DeviceMessageAttributesMap, DeviceMessageInfo};
+pub use device_added::{DeviceAdded, DeviceAddedV0, DeviceAddedV1, DeviceAddedV2};
+pub use device_list::{DeviceList, DeviceListV0, DeviceListV1, DeviceListV2};
+pub use device_message_info::{DeviceMessageAttributesMap, DeviceMessageInfo, DeviceMessageInfoV2};
 pub use device_removed::DeviceRemoved;
 pub use error::{Error, ErrorCode, ErrorV0};
 pub use fleshlight_launch_fw12_cmd::FleshlightLaunchFW12Cmd;
@@ -66,7 +69,9 @@ pub use kiiroo_cmd::KiirooCmd;
 pub use linear_cmd::{LinearCmd, VectorSubcommand};
 pub use log_level::LogLevel;
 pub use lovense_cmd::LovenseCmd;
-pub use message_attributes::DeviceMessageAttributes;
+pub use message_attributes::{
+  ActuatorType, DeviceFeatureDescriptor, DeviceMessageAttributes, SensorType, StopBehavior,
+};
 pub use ok::Ok;
 pub use ping::Ping;
 pub use raw_read_cmd::RawReadCmd;
@@ -81,6 +86,9 @@ pub use rotate_cmd::{RotateCmd, RotationSubcommand};
 pub use rssi_level_cmd::RSSILevelCmd;
 pub use rssi_level_reading::RSSILevelReading;
 pub use scanning_finished::ScanningFinished;
+pub use sensor_reading::SensorReading;
+pub use sensor_subscribe_cmd::SensorSubscribeCmd;
+pub use sensor_unsubscribe_cmd::SensorUnsubscribeCmd;
 pub use server_info::{ServerInfo, ServerInfoV0};
 pub use single_motor_vibrate_cmd::SingleMotorVibrateCmd;
 pub use start_scanning::StartScanning;
@@ -103,10 +111,12 @@ use std::convert::TryFrom;
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Display)]
 #[repr(u32)]
 #[cfg_attr(feature = "serialize-json", derive(Serialize_repr, Deserialize_repr))]
+#[cfg_attr(feature = "json-schema", derive(schemars::JsonSchema_repr))]
 pub enum ButtplugMessageSpecVersion {
   Version0 = 0,
   Version1 = 1,
   Version2 = 2,
+  Version3 = 3,
 }
 
 /// Message Id for events sent from the server, which are not in response to a
@@ -115,7 +125,7 @@ pub const BUTTPLUG_SERVER_EVENT_ID: u32 = 0;
 
 /// The current latest version of the spec implemented by the library.
 pub const BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION: ButtplugMessageSpecVersion =
-  ButtplugMessageSpecVersion::Version2;
+  ButtplugMessageSpecVersion::Version3;
 
 /// Base trait for all Buttplug Protocol Message Structs. Handles management of
 /// message ids, as well as implementing conveinence functions for converting
@@ -183,6 +193,7 @@ pub trait ButtplugDeviceMessage: ButtplugMessage {
 /// Used in [MessageAttributes][crate::core::messages::MessageAttributes] for denoting message
 /// capabilties.
 #[derive(Copy, Debug, Clone, PartialEq, Eq, Hash, Display, Serialize, Deserialize)]
+#[cfg_attr(feature = "json-schema", derive(schemars::JsonSchema))]
 pub enum ButtplugDeviceMessageType {
   VibrateCmd,
   LinearCmd,
@@ -194,6 +205,7 @@ pub enum ButtplugDeviceMessageType {
   RawUnsubscribeCmd,
   BatteryLevelCmd,
   RSSILevelCmd,
+  SensorSubscribeCmd,
   // Deprecated generic commands
   SingleMotorVibrateCmd,
   // Deprecated device specific commands
@@ -219,6 +231,7 @@ impl Ord for ButtplugDeviceMessageType {
 /// Used in [MessageAttributes][crate::core::messages::MessageAttributes] for denoting message
 /// capabilties. Only contains message that are valid in the current version of the spec.
 #[derive(Copy, Debug, Clone, PartialEq, Eq, Hash, Display, Serialize, Deserialize)]
+#[cfg_attr(feature = "json-schema", derive(schemars::JsonSchema))]
 pub enum ButtplugCurrentSpecDeviceMessageType {
   // Generic commands
   //
@@ -236,6 +249,7 @@ pub enum ButtplugCurrentSpecDeviceMessageType {
   RawUnsubscribeCmd,
   BatteryLevelCmd,
   RSSILevelCmd,
+  SensorSubscribeCmd,
 }
 
 // Ordering for ButtplugCurrentDeviceMessageType should be lexicographic, for
@@ -278,6 +292,9 @@ impl TryFrom<ButtplugDeviceMessageType> for ButtplugCurrentSpecDeviceMessageType
       ButtplugDeviceMessageType::RSSILevelCmd => {
         Ok(ButtplugCurrentSpecDeviceMessageType::RSSILevelCmd)
       }
+      ButtplugDeviceMessageType::SensorSubscribeCmd => {
+        Ok(ButtplugCurrentSpecDeviceMessageType::SensorSubscribeCmd)
+      }
       _ => Err(ButtplugMessageError::MessageConversionError(
         "Device message deprecated, does not exist in current version of protocol.".to_owned(),
       )),
@@ -306,6 +323,9 @@ impl From<ButtplugCurrentSpecDeviceMessageType> for ButtplugDeviceMessageType {
         ButtplugDeviceMessageType::BatteryLevelCmd
       }
       ButtplugCurrentSpecDeviceMessageType::RSSILevelCmd => ButtplugDeviceMessageType::RSSILevelCmd,
+      ButtplugCurrentSpecDeviceMessageType::SensorSubscribeCmd => {
+        ButtplugDeviceMessageType::SensorSubscribeCmd
+      }
     }
   }
 }
@@ -344,6 +364,8 @@ pub enum ButtplugClientMessage {
   // Sensor commands
   BatteryLevelCmd(BatteryLevelCmd),
   RSSILevelCmd(RSSILevelCmd),
+  SensorSubscribeCmd(SensorSubscribeCmd),
+  SensorUnsubscribeCmd(SensorUnsubscribeCmd),
   // Deprecated generic commands
   SingleMotorVibrateCmd(SingleMotorVibrateCmd),
   // Deprecated device specific commands
@@ -387,12 +409,86 @@ pub enum ButtplugServerMessage {
   // Sensor Reading Messages
   BatteryLevelReading(BatteryLevelReading),
   RSSILevelReading(RSSILevelReading),
+  SensorReading(SensorReading),
 }
 
 /// Type alias for the latest version of client-to-server messages.
-pub type ButtplugCurrentSpecClientMessage = ButtplugSpecV2ClientMessage;
+pub type ButtplugCurrentSpecClientMessage = ButtplugSpecV3ClientMessage;
 /// Type alias for the latest version of server-to-client messages.
-pub type ButtplugCurrentSpecServerMessage = ButtplugSpecV2ServerMessage;
+pub type ButtplugCurrentSpecServerMessage = ButtplugSpecV3ServerMessage;
+
+/// Represents all client-to-server messages in v3 of the Buttplug Spec
+#[derive(
+  Debug,
+  Clone,
+  PartialEq,
+  ButtplugMessage,
+  ButtplugMessageValidator,
+  ButtplugClientMessageType,
+  FromSpecificButtplugMessage,
+  TryFromButtplugClientMessage,
+)]
+#[cfg_attr(feature = "serialize-json", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "json-schema", derive(schemars::JsonSchema))]
+pub enum ButtplugSpecV3ClientMessage {
+  // Handshake messages
+  RequestServerInfo(RequestServerInfo),
+  Ping(Ping),
+  // Logging messages
+  RequestLog(RequestLog),
+  // Device enumeration messages
+  StartScanning(StartScanning),
+  StopScanning(StopScanning),
+  RequestDeviceList(RequestDeviceList),
+  // Generic commands
+  StopAllDevices(StopAllDevices),
+  VibrateCmd(VibrateCmd),
+  LinearCmd(LinearCmd),
+  RotateCmd(RotateCmd),
+  RawWriteCmd(RawWriteCmd),
+  RawReadCmd(RawReadCmd),
+  StopDeviceCmd(StopDeviceCmd),
+  RawSubscribeCmd(RawSubscribeCmd),
+  RawUnsubscribeCmd(RawUnsubscribeCmd),
+  // Sensor commands
+  BatteryLevelCmd(BatteryLevelCmd),
+  RSSILevelCmd(RSSILevelCmd),
+  SensorSubscribeCmd(SensorSubscribeCmd),
+  SensorUnsubscribeCmd(SensorUnsubscribeCmd),
+}
+
+/// Represents all server-to-client messages in v3 of the Buttplug Spec
+#[derive(
+  Debug,
+  Clone,
+  PartialEq,
+  ButtplugMessage,
+  ButtplugMessageValidator,
+  ButtplugServerMessageType,
+  FromSpecificButtplugMessage,
+  TryFromButtplugServerMessage,
+)]
+#[cfg_attr(feature = "serialize-json", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "json-schema", derive(schemars::JsonSchema))]
+pub enum ButtplugSpecV3ServerMessage {
+  // Status messages
+  Ok(Ok),
+  Error(Error),
+  Log(Log),
+  // Handshake messages
+  ServerInfo(ServerInfo),
+  // Device enumeration messages
+  DeviceList(DeviceList),
+  DeviceAdded(DeviceAdded),
+  DeviceRemoved(DeviceRemoved),
+  ScanningFinished(ScanningFinished),
+  // Generic commands
+  RawReading(RawReading),
+  // Sensor commands
+  BatteryLevelReading(BatteryLevelReading),
+  RSSILevelReading(RSSILevelReading),
+  SensorReading(SensorReading),
+}
 
 /// Represents all client-to-server messages in v2 of the Buttplug Spec
 #[derive(
@@ -406,6 +502,7 @@ pub type ButtplugCurrentSpecServerMessage = ButtplugSpecV2ServerMessage;
   TryFromButtplugClientMessage,
 )]
 #[cfg_attr(feature = "serialize-json", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "json-schema", derive(schemars::JsonSchema))]
 pub enum ButtplugSpecV2ClientMessage {
   // Handshake messages
   RequestServerInfo(RequestServerInfo),
@@ -438,9 +535,9 @@ pub enum ButtplugSpecV2ClientMessage {
   ButtplugMessageValidator,
   ButtplugServerMessageType,
   FromSpecificButtplugMessage,
-  TryFromButtplugServerMessage,
 )]
 #[cfg_attr(feature = "serialize-json", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "json-schema", derive(schemars::JsonSchema))]
 pub enum ButtplugSpecV2ServerMessage {
   // Status messages
   Ok(Ok),
@@ -448,8 +545,8 @@ pub enum ButtplugSpecV2ServerMessage {
   // Handshake messages
   ServerInfo(ServerInfo),
   // Device enumeration messages
-  DeviceList(DeviceList),
-  DeviceAdded(DeviceAdded),
+  DeviceList(DeviceListV2),
+  DeviceAdded(DeviceAddedV2),
   DeviceRemoved(DeviceRemoved),
   ScanningFinished(ScanningFinished),
   // Generic commands
@@ -459,6 +556,42 @@ pub enum ButtplugSpecV2ServerMessage {
   RSSILevelReading(RSSILevelReading),
 }
 
+impl TryFrom<ButtplugServerMessage> for ButtplugSpecV2ServerMessage {
+  type Error = ButtplugMessageError;
+  fn try_from(msg: ButtplugServerMessage) -> Result<Self, ButtplugMessageError> {
+    match msg {
+      ButtplugServerMessage::Ok(msg) => Ok(ButtplugSpecV2ServerMessage::Ok(msg)),
+      ButtplugServerMessage::Error(msg) => Ok(ButtplugSpecV2ServerMessage::Error(msg)),
+      ButtplugServerMessage::ServerInfo(msg) => Ok(ButtplugSpecV2ServerMessage::ServerInfo(msg)),
+      ButtplugServerMessage::DeviceList(msg) => {
+        Ok(ButtplugSpecV2ServerMessage::DeviceList(msg.into()))
+      }
+      ButtplugServerMessage::DeviceAdded(msg) => {
+        Ok(ButtplugSpecV2ServerMessage::DeviceAdded(msg.into()))
+      }
+      ButtplugServerMessage::DeviceRemoved(msg) => {
+        Ok(ButtplugSpecV2ServerMessage::DeviceRemoved(msg))
+      }
+      ButtplugServerMessage::ScanningFinished(msg) => {
+        Ok(ButtplugSpecV2ServerMessage::ScanningFinished(msg))
+      }
+      ButtplugServerMessage::RawReading(msg) => Ok(ButtplugSpecV2ServerMessage::RawReading(msg)),
+      ButtplugServerMessage::BatteryLevelReading(msg) => {
+        Ok(ButtplugSpecV2ServerMessage::BatteryLevelReading(msg))
+      }
+      ButtplugServerMessage::RSSILevelReading(msg) => {
+        Ok(ButtplugSpecV2ServerMessage::RSSILevelReading(msg))
+      }
+      // Log and SensorReading were added in v3, so v2 clients get an error.
+      _ => Err(ButtplugMessageError::VersionError(
+        "ButtplugServerMessage".to_owned(),
+        format!("{:?}", msg),
+        "ButtplugSpecV2ServerMessage".to_owned(),
+      )),
+    }
+  }
+}
+
 /// Represents all client-to-server messages in v1 of the Buttplug Spec
 #[derive(
   Debug,
@@ -470,6 +603,7 @@ pub enum ButtplugSpecV2ServerMessage {
   TryFromButtplugClientMessage,
 )]
 #[cfg_attr(feature = "serialize-json", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "json-schema", derive(schemars::JsonSchema))]
 pub(crate) enum ButtplugSpecV1ClientMessage {
   // Handshake messages
   RequestServerInfo(RequestServerInfo),
@@ -498,6 +632,7 @@ pub(crate) enum ButtplugSpecV1ClientMessage {
   Debug, Clone, PartialEq, ButtplugMessage, ButtplugMessageValidator, ButtplugServerMessageType,
 )]
 #[cfg_attr(feature = "serialize-json", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "json-schema", derive(schemars::JsonSchema))]
 pub(crate) enum ButtplugSpecV1ServerMessage {
   // Status messages
   Ok(Ok),
@@ -557,6 +692,7 @@ impl TryFrom<ButtplugServerMessage> for ButtplugSpecV1ServerMessage {
   TryFromButtplugClientMessage,
 )]
 #[cfg_attr(feature = "serialize-json", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "json-schema", derive(schemars::JsonSchema))]
 pub(crate) enum ButtplugSpecV0ClientMessage {
   RequestLog(RequestLog),
   Ping(Ping),
@@ -583,6 +719,7 @@ pub(crate) enum ButtplugSpecV0ClientMessage {
   Debug, Clone, PartialEq, ButtplugMessage, ButtplugMessageValidator, ButtplugServerMessageType,
 )]
 #[cfg_attr(feature = "serialize-json", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "json-schema", derive(schemars::JsonSchema))]
 pub(crate) enum ButtplugSpecV0ServerMessage {
   // Status messages
   Ok(Ok),
@@ -677,4 +814,30 @@ pub enum ButtplugDeviceCommandMessageUnion {
   RawUnsubscribeCmd(RawUnsubscribeCmd),
   BatteryLevelCmd(BatteryLevelCmd),
   RSSILevelCmd(RSSILevelCmd),
+  SensorSubscribeCmd(SensorSubscribeCmd),
+  SensorUnsubscribeCmd(SensorUnsubscribeCmd),
+}
+
+impl ButtplugDeviceCommandMessageUnion {
+  /// Name of the message type, as used in the protocol spec.
+  pub fn message_name(&self) -> &'static str {
+    match self {
+      ButtplugDeviceCommandMessageUnion::FleshlightLaunchFW12Cmd(_) => "FleshlightLaunchFW12Cmd",
+      ButtplugDeviceCommandMessageUnion::SingleMotorVibrateCmd(_) => "SingleMotorVibrateCmd",
+      ButtplugDeviceCommandMessageUnion::VorzeA10CycloneCmd(_) => "VorzeA10CycloneCmd",
+      ButtplugDeviceCommandMessageUnion::KiirooCmd(_) => "KiirooCmd",
+      ButtplugDeviceCommandMessageUnion::VibrateCmd(_) => "VibrateCmd",
+      ButtplugDeviceCommandMessageUnion::LinearCmd(_) => "LinearCmd",
+      ButtplugDeviceCommandMessageUnion::RotateCmd(_) => "RotateCmd",
+      ButtplugDeviceCommandMessageUnion::RawWriteCmd(_) => "RawWriteCmd",
+      ButtplugDeviceCommandMessageUnion::RawReadCmd(_) => "RawReadCmd",
+      ButtplugDeviceCommandMessageUnion::StopDeviceCmd(_) => "StopDeviceCmd",
+      ButtplugDeviceCommandMessageUnion::RawSubscribeCmd(_) => "RawSubscribeCmd",
+      ButtplugDeviceCommandMessageUnion::RawUnsubscribeCmd(_) => "RawUnsubscribeCmd",
+      ButtplugDeviceCommandMessageUnion::BatteryLevelCmd(_) => "BatteryLevelCmd",
+      ButtplugDeviceCommandMessageUnion::RSSILevelCmd(_) => "RSSILevelCmd",
+      ButtplugDeviceCommandMessageUnion::SensorSubscribeCmd(_) => "SensorSubscribeCmd",
+      ButtplugDeviceCommandMessageUnion::SensorUnsubscribeCmd(_) => "SensorUnsubscribeCmd",
+    }
+  }
 }
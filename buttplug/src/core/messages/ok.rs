@@ -13,6 +13,7 @@ use serde::{Deserialize, Serialize};
 /// Protocol Spec](https://buttplug-spec.docs.buttplug.io/status.html#ok).
 #[derive(Debug, PartialEq, ButtplugMessage, Clone)]
 #[cfg_attr(feature = "serialize-json", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "json-schema", derive(schemars::JsonSchema))]
 pub struct Ok {
   /// Message Id, used for matching message pairs in remote connection instances.
   #[cfg_attr(feature = "serialize-json", serde(rename = "Id"))]
@@ -14,6 +14,7 @@ use serde::{Deserialize, Serialize};
 // subscription and won't have a matching task Id in that case.
 #[derive(Debug, ButtplugDeviceMessage, ButtplugMessageValidator, PartialEq, Clone)]
 #[cfg_attr(feature = "serialize-json", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "json-schema", derive(schemars::JsonSchema))]
 pub struct RawReading {
   #[cfg_attr(feature = "serialize-json", serde(rename = "Id"))]
   id: u32,
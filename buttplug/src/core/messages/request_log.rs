@@ -11,6 +11,7 @@ use serde::{Deserialize, Serialize};
 
 #[derive(Debug, ButtplugMessage, PartialEq, Clone)]
 #[cfg_attr(feature = "serialize-json", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "json-schema", derive(schemars::JsonSchema))]
 pub struct RequestLog {
   #[cfg_attr(feature = "serialize-json", serde(rename = "Id"))]
   id: u32,
@@ -22,6 +23,10 @@ impl RequestLog {
   pub fn new(log_level: LogLevel) -> Self {
     Self { id: 1, log_level }
   }
+
+  pub fn log_level(&self) -> &LogLevel {
+    &self.log_level
+  }
 }
 
 impl ButtplugMessageValidator for RequestLog {
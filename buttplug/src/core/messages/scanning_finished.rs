@@ -11,9 +11,25 @@ use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Default, ButtplugMessage, Clone, PartialEq)]
 #[cfg_attr(feature = "serialize-json", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "json-schema", derive(schemars::JsonSchema))]
 pub struct ScanningFinished {
   #[cfg_attr(feature = "serialize-json", serde(rename = "Id"))]
   id: u32,
+  /// Comm manager errors that happened during the scan. These aren't part of
+  /// the protocol, so they're only available to in-process consumers of
+  /// server events, and are dropped when the message is serialized.
+  #[cfg_attr(feature = "serialize-json", serde(skip))]
+  errors: Vec<String>,
+}
+
+impl ScanningFinished {
+  pub fn new_with_errors(errors: Vec<String>) -> Self {
+    Self { id: 0, errors }
+  }
+
+  pub fn errors(&self) -> &Vec<String> {
+    &self.errors
+  }
 }
 
 impl ButtplugMessageValidator for ScanningFinished {
@@ -0,0 +1,78 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2021 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+use super::*;
+#[cfg(feature = "serialize-json")]
+use serde::{Deserialize, Serialize};
+
+// Like RawReading, this is emitted as part of a subscription, so it will have
+// an Id of 0.
+#[derive(Debug, ButtplugDeviceMessage, ButtplugMessageValidator, PartialEq, Clone)]
+#[cfg_attr(feature = "serialize-json", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "json-schema", derive(schemars::JsonSchema))]
+pub struct SensorReading {
+  #[cfg_attr(feature = "serialize-json", serde(rename = "Id"))]
+  id: u32,
+  #[cfg_attr(feature = "serialize-json", serde(rename = "DeviceIndex"))]
+  device_index: u32,
+  #[cfg_attr(feature = "serialize-json", serde(rename = "SensorIndex"))]
+  sensor_index: u32,
+  #[cfg_attr(feature = "serialize-json", serde(rename = "SensorType"))]
+  sensor_type: SensorType,
+  #[cfg_attr(feature = "serialize-json", serde(rename = "Data"))]
+  data: Vec<i32>,
+}
+
+impl SensorReading {
+  pub fn new(
+    device_index: u32,
+    sensor_index: u32,
+    sensor_type: SensorType,
+    data: Vec<i32>,
+  ) -> Self {
+    Self {
+      id: BUTTPLUG_SERVER_EVENT_ID,
+      device_index,
+      sensor_index,
+      sensor_type,
+      data,
+    }
+  }
+
+  pub fn sensor_index(&self) -> u32 {
+    self.sensor_index
+  }
+
+  pub fn sensor_type(&self) -> SensorType {
+    self.sensor_type
+  }
+
+  pub fn data(&self) -> &Vec<i32> {
+    &self.data
+  }
+}
+
+#[cfg(feature = "serialize-json")]
+#[cfg(test)]
+mod test {
+  use crate::core::messages::{ButtplugCurrentSpecServerMessage, SensorReading, SensorType};
+
+  #[test]
+  fn test_sensor_reading_serialize() {
+    let union = ButtplugCurrentSpecServerMessage::SensorReading(SensorReading::new(
+      0,
+      1,
+      SensorType::Accelerometer,
+      vec![3, -2],
+    ));
+    let js = serde_json::to_string(&union).unwrap();
+    let reading_str = "{\"SensorReading\":{\"Id\":0,\"DeviceIndex\":0,\"SensorIndex\":1,\"SensorType\":\"Accelerometer\",\"Data\":[3,-2]}}";
+    assert_eq!(js, reading_str);
+    let deserialized: ButtplugCurrentSpecServerMessage = serde_json::from_str(reading_str).unwrap();
+    assert_eq!(deserialized, union);
+  }
+}
@@ -0,0 +1,562 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2022 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Property tests for JSON serialization. Generated messages are serialized,
+//! deserialized and serialized again in every spec version they exist in, and
+//! both passes need to produce the same JSON and pass schema and message
+//! validation. Out of range values need to be rejected by both.
+
+use super::{
+  json_serializer::{create_message_validator, deserialize_to_message, vec_to_protocol_json},
+  ButtplugMessageSerializer, ButtplugSerializedMessage, ButtplugServerJSONSerializer,
+};
+use crate::{
+  core::messages::{
+    self, ButtplugClientMessage, ButtplugDeviceMessageType, ButtplugMessage,
+    ButtplugMessageSpecVersion, ButtplugMessageValidator, ButtplugServerMessage,
+    ButtplugSpecV0ClientMessage, ButtplugSpecV0ServerMessage, ButtplugSpecV1ClientMessage,
+    ButtplugSpecV1ServerMessage, ButtplugSpecV2ClientMessage, ButtplugSpecV2ServerMessage,
+    ButtplugSpecV3ClientMessage, ButtplugSpecV3ServerMessage, DeviceMessageAttributes,
+    DeviceMessageAttributesMap, DeviceMessageInfo, ErrorCode, LogLevel, SensorType,
+  },
+  device::Endpoint,
+};
+use proptest::{prelude::*, test_runner::TestRunner};
+use serde::{de::DeserializeOwned, Serialize};
+use std::{convert::TryFrom, fmt::Debug};
+
+const SPEC_VERSIONS: [ButtplugMessageSpecVersion; 4] = [
+  ButtplugMessageSpecVersion::Version0,
+  ButtplugMessageSpecVersion::Version1,
+  ButtplugMessageSpecVersion::Version2,
+  ButtplugMessageSpecVersion::Version3,
+];
+
+fn with_id<M: ButtplugMessage>(mut msg: M, id: u32) -> M {
+  msg.set_id(id);
+  msg
+}
+
+fn client_id() -> impl Strategy<Value = u32> {
+  prop_oneof![Just(1u32), Just(u32::MAX), 1u32..]
+}
+
+fn spec_version() -> impl Strategy<Value = ButtplugMessageSpecVersion> {
+  prop::sample::select(SPEC_VERSIONS.to_vec())
+}
+
+fn log_level() -> impl Strategy<Value = LogLevel> {
+  prop::sample::select(vec![
+    LogLevel::Off,
+    LogLevel::Fatal,
+    LogLevel::Error,
+    LogLevel::Warn,
+    LogLevel::Info,
+    LogLevel::Debug,
+    LogLevel::Trace,
+  ])
+}
+
+fn endpoint() -> impl Strategy<Value = Endpoint> {
+  prop::sample::select(vec![
+    Endpoint::Command,
+    Endpoint::Firmware,
+    Endpoint::Rx,
+    Endpoint::Tx,
+    Endpoint::TxMode,
+    Endpoint::TxVibrate,
+  ])
+}
+
+fn sensor_type() -> impl Strategy<Value = SensorType> {
+  prop::sample::select(vec![
+    SensorType::Pressure,
+    SensorType::Position,
+    SensorType::Accelerometer,
+  ])
+}
+
+// Values are kept to a few decimal places, so they're written and parsed back
+// exactly without relying on serde_json's float_roundtrip feature.
+fn command_value() -> impl Strategy<Value = f64> {
+  prop_oneof![
+    Just(0.0),
+    Just(1.0),
+    (0u32..=1000).prop_map(|value| value as f64 / 1000.0)
+  ]
+}
+
+fn out_of_range_value() -> impl Strategy<Value = f64> {
+  prop_oneof![
+    (1001u32..=100_000).prop_map(|value| value as f64 / 1000.0),
+    (1u32..=100_000).prop_map(|value| -(value as f64) / 1000.0),
+  ]
+}
+
+fn client_message() -> impl Strategy<Value = ButtplugClientMessage> {
+  prop_oneof![
+    client_id().prop_map(|id| with_id(messages::Ping::default(), id).into()),
+    (client_id(), log_level()).prop_map(|(id, level)| with_id(
+      messages::RequestLog::new(level),
+      id
+    )
+    .into()),
+    (client_id(), any::<String>(), spec_version()).prop_map(|(id, name, version)| with_id(
+      messages::RequestServerInfo::new(&name, version),
+      id
+    )
+    .into()),
+    client_id().prop_map(|id| with_id(messages::StartScanning::default(), id).into()),
+    client_id().prop_map(|id| with_id(messages::StopScanning::default(), id).into()),
+    client_id().prop_map(|id| with_id(messages::RequestDeviceList::default(), id).into()),
+    client_id().prop_map(|id| with_id(messages::StopAllDevices::default(), id).into()),
+    (
+      client_id(),
+      any::<u32>(),
+      prop::collection::vec((any::<u32>(), command_value()), 1..4)
+    )
+      .prop_map(|(id, index, speeds)| with_id(
+        messages::VibrateCmd::new(
+          index,
+          speeds
+            .into_iter()
+            .map(|(feature, speed)| messages::VibrateSubcommand::new(feature, speed))
+            .collect()
+        ),
+        id
+      )
+      .into()),
+    (
+      client_id(),
+      any::<u32>(),
+      prop::collection::vec((any::<u32>(), any::<u32>(), command_value()), 1..4)
+    )
+      .prop_map(|(id, index, vectors)| with_id(
+        messages::LinearCmd::new(
+          index,
+          vectors
+            .into_iter()
+            .map(
+              |(feature, duration, position)| messages::VectorSubcommand::new(
+                feature, duration, position
+              )
+            )
+            .collect()
+        ),
+        id
+      )
+      .into()),
+    (
+      client_id(),
+      any::<u32>(),
+      prop::collection::vec((any::<u32>(), command_value(), any::<bool>()), 1..4)
+    )
+      .prop_map(|(id, index, rotations)| with_id(
+        messages::RotateCmd::new(
+          index,
+          rotations
+            .into_iter()
+            .map(
+              |(feature, speed, clockwise)| messages::RotationSubcommand::new(
+                feature, speed, clockwise
+              )
+            )
+            .collect()
+        ),
+        id
+      )
+      .into()),
+    (
+      client_id(),
+      any::<u32>(),
+      endpoint(),
+      prop::collection::vec(any::<u8>(), 1..16),
+      any::<bool>()
+    )
+      .prop_map(|(id, index, endpoint, data, with_response)| with_id(
+        messages::RawWriteCmd::new(index, endpoint, data, with_response),
+        id
+      )
+      .into()),
+    (
+      client_id(),
+      any::<u32>(),
+      endpoint(),
+      any::<u32>(),
+      any::<u32>()
+    )
+      .prop_map(|(id, index, endpoint, length, timeout)| with_id(
+        messages::RawReadCmd::new(index, endpoint, length, timeout),
+        id
+      )
+      .into()),
+    (client_id(), any::<u32>()).prop_map(|(id, index)| with_id(
+      messages::StopDeviceCmd::new(index),
+      id
+    )
+    .into()),
+    (client_id(), any::<u32>(), endpoint()).prop_map(|(id, index, endpoint)| with_id(
+      messages::RawSubscribeCmd::new(index, endpoint),
+      id
+    )
+    .into()),
+    (client_id(), any::<u32>(), endpoint()).prop_map(|(id, index, endpoint)| with_id(
+      messages::RawUnsubscribeCmd::new(index, endpoint),
+      id
+    )
+    .into()),
+    (client_id(), any::<u32>()).prop_map(|(id, index)| with_id(
+      messages::BatteryLevelCmd::new(index),
+      id
+    )
+    .into()),
+    (client_id(), any::<u32>()).prop_map(|(id, index)| with_id(
+      messages::RSSILevelCmd::new(index),
+      id
+    )
+    .into()),
+    (client_id(), any::<u32>(), any::<u32>()).prop_map(|(id, index, sensor)| with_id(
+      messages::SensorSubscribeCmd::new(index, sensor),
+      id
+    )
+    .into()),
+    (client_id(), any::<u32>(), any::<u32>()).prop_map(|(id, index, sensor)| with_id(
+      messages::SensorUnsubscribeCmd::new(index, sensor),
+      id
+    )
+    .into()),
+    (client_id(), any::<u32>(), command_value()).prop_map(|(id, index, speed)| with_id(
+      messages::SingleMotorVibrateCmd::new(index, speed),
+      id
+    )
+    .into()),
+    (client_id(), any::<u32>(), 0u8..=99, 0u8..=99).prop_map(|(id, index, position, speed)| {
+      with_id(
+        messages::FleshlightLaunchFW12Cmd::new(index, position, speed),
+        id,
+      )
+      .into()
+    }),
+    (client_id(), any::<u32>(), any::<String>()).prop_map(|(id, index, command)| with_id(
+      messages::LovenseCmd::new(index, &command),
+      id
+    )
+    .into()),
+    (client_id(), any::<u32>(), any::<String>()).prop_map(|(id, index, command)| with_id(
+      messages::KiirooCmd::new(index, &command),
+      id
+    )
+    .into()),
+    (client_id(), any::<u32>(), 0u32..=99, any::<bool>()).prop_map(
+      |(id, index, speed, clockwise)| with_id(
+        messages::VorzeA10CycloneCmd::new(index, speed, clockwise),
+        id
+      )
+      .into()
+    ),
+  ]
+}
+
+fn device_messages() -> impl Strategy<Value = DeviceMessageAttributesMap> {
+  (
+    prop::collection::vec(1u32..=100, 1..4),
+    1u32..=4,
+    any::<bool>(),
+  )
+    .prop_map(|(vibrator_steps, rotators, stoppable)| {
+      let mut device_messages = DeviceMessageAttributesMap::new();
+      device_messages.insert(
+        ButtplugDeviceMessageType::VibrateCmd,
+        DeviceMessageAttributes {
+          feature_count: Some(vibrator_steps.len() as u32),
+          step_count: Some(vibrator_steps),
+          ..Default::default()
+        },
+      );
+      device_messages.insert(
+        ButtplugDeviceMessageType::RotateCmd,
+        DeviceMessageAttributes {
+          feature_count: Some(rotators),
+          ..Default::default()
+        },
+      );
+      if stoppable {
+        device_messages.insert(
+          ButtplugDeviceMessageType::StopDeviceCmd,
+          DeviceMessageAttributes::default(),
+        );
+      }
+      device_messages
+    })
+}
+
+fn server_message() -> impl Strategy<Value = ButtplugServerMessage> {
+  prop_oneof![
+    client_id().prop_map(|id| messages::Ok::new(id).into()),
+    (
+      any::<u32>(),
+      prop::sample::select(vec![
+        ErrorCode::ErrorUnknown,
+        ErrorCode::ErrorHandshake,
+        ErrorCode::ErrorPing,
+        ErrorCode::ErrorMessage,
+        ErrorCode::ErrorDevice,
+      ]),
+      any::<String>()
+    )
+      .prop_map(
+        |(id, code, message)| with_id(messages::Error::new(code, &message, None), id).into()
+      ),
+    (log_level(), any::<String>())
+      .prop_map(|(level, message)| messages::Log::new(level, &message).into()),
+    (client_id(), any::<String>(), spec_version(), any::<u32>()).prop_map(
+      |(id, name, version, max_ping_time)| with_id(
+        messages::ServerInfo::new(&name, version, max_ping_time),
+        id
+      )
+      .into()
+    ),
+    (
+      client_id(),
+      prop::collection::vec((any::<u32>(), any::<String>(), device_messages()), 0..3)
+    )
+      .prop_map(|(id, devices)| with_id(
+        messages::DeviceList::new(
+          devices
+            .into_iter()
+            .map(|(index, name, device_messages)| DeviceMessageInfo::new(
+              index,
+              &name,
+              device_messages
+            ))
+            .collect()
+        ),
+        id
+      )
+      .into()),
+    (any::<u32>(), any::<String>(), device_messages()).prop_map(
+      |(index, name, device_messages)| messages::DeviceAdded::new(index, &name, &device_messages)
+        .into()
+    ),
+    any::<u32>().prop_map(|index| messages::DeviceRemoved::new(index).into()),
+    Just(messages::ScanningFinished::new_with_errors(vec![]).into()),
+    (
+      client_id(),
+      any::<u32>(),
+      endpoint(),
+      prop::collection::vec(any::<u8>(), 1..16)
+    )
+      .prop_map(|(id, index, endpoint, data)| with_id(
+        messages::RawReading::new(index, endpoint, data),
+        id
+      )
+      .into()),
+    (client_id(), any::<u32>(), command_value()).prop_map(|(id, index, level)| with_id(
+      messages::BatteryLevelReading::new(index, level),
+      id
+    )
+    .into()),
+    (client_id(), any::<u32>(), i32::MIN..=0).prop_map(|(id, index, level)| with_id(
+      messages::RSSILevelReading::new(index, level),
+      id
+    )
+    .into()),
+    (
+      any::<u32>(),
+      any::<u32>(),
+      sensor_type(),
+      prop::collection::vec(any::<i32>(), 0..4)
+    )
+      .prop_map(
+        |(index, sensor, sensor_type, data)| messages::SensorReading::new(
+          index,
+          sensor,
+          sensor_type,
+          data
+        )
+        .into()
+      ),
+  ]
+}
+
+// Returns a server side serializer that's been through the handshake for the
+// version.
+fn server_serializer(version: ButtplugMessageSpecVersion) -> ButtplugServerJSONSerializer {
+  let serializer = ButtplugServerJSONSerializer::default();
+  let handshake = vec_to_protocol_json(vec![ButtplugSpecV2ClientMessage::RequestServerInfo(
+    messages::RequestServerInfo::new("Roundtrip Test", version),
+  )]);
+  serializer
+    .deserialize(ButtplugSerializedMessage::Text(handshake))
+    .unwrap();
+  serializer
+}
+
+fn check_client_roundtrip<T>(version: ButtplugMessageSpecVersion)
+where
+  T: TryFrom<ButtplugClientMessage>
+    + Into<ButtplugClientMessage>
+    + Serialize
+    + DeserializeOwned
+    + Clone
+    + Debug,
+  <T as TryFrom<ButtplugClientMessage>>::Error: Debug,
+{
+  let serializer = server_serializer(version);
+  let strategy = client_message().prop_filter_map("Message not in spec version", |msg| {
+    T::try_from(msg.clone())
+      .ok()
+      .map(|spec_msg| (msg, spec_msg))
+  });
+  TestRunner::default()
+    .run(&strategy, |(msg, spec_msg)| {
+      prop_assert!(msg.is_valid().is_ok());
+      let json = vec_to_protocol_json(vec![spec_msg]);
+      let parsed = serializer
+        .deserialize(ButtplugSerializedMessage::Text(json.clone()))
+        .unwrap();
+      prop_assert_eq!(&parsed, &vec![msg]);
+      prop_assert!(parsed[0].is_valid().is_ok());
+      let reserialized = vec_to_protocol_json(
+        parsed
+          .into_iter()
+          .map(|msg| T::try_from(msg).unwrap())
+          .collect(),
+      );
+      prop_assert_eq!(json, reserialized);
+      Ok(())
+    })
+    .unwrap();
+}
+
+fn check_server_roundtrip<T>(version: ButtplugMessageSpecVersion)
+where
+  T: TryFrom<ButtplugServerMessage>
+    + ButtplugMessageValidator
+    + Serialize
+    + DeserializeOwned
+    + Clone
+    + Debug,
+{
+  let serializer = server_serializer(version);
+  let validator = create_message_validator();
+  // Messages that don't exist in a spec version are sent as errors, which is
+  // covered by the message downgrade tests.
+  let strategy = server_message().prop_filter("Message not in spec version", |msg| {
+    T::try_from(msg.clone()).is_ok()
+  });
+  TestRunner::default()
+    .run(&strategy, |msg| {
+      prop_assert!(msg.is_valid().is_ok());
+      let json = match serializer.serialize(vec![msg]) {
+        ButtplugSerializedMessage::Text(json) => json,
+        ButtplugSerializedMessage::Binary(_) => panic!("JSON serializer should output text"),
+      };
+      let parsed = deserialize_to_message::<T>(&validator, json.clone()).unwrap();
+      prop_assert_eq!(parsed.len(), 1);
+      prop_assert!(parsed[0].is_valid().is_ok());
+      prop_assert_eq!(json, vec_to_protocol_json(parsed));
+      Ok(())
+    })
+    .unwrap();
+}
+
+#[test]
+fn test_client_message_roundtrip() {
+  check_client_roundtrip::<ButtplugSpecV0ClientMessage>(ButtplugMessageSpecVersion::Version0);
+  check_client_roundtrip::<ButtplugSpecV1ClientMessage>(ButtplugMessageSpecVersion::Version1);
+  check_client_roundtrip::<ButtplugSpecV2ClientMessage>(ButtplugMessageSpecVersion::Version2);
+  check_client_roundtrip::<ButtplugSpecV3ClientMessage>(ButtplugMessageSpecVersion::Version3);
+}
+
+#[test]
+fn test_server_message_roundtrip() {
+  check_server_roundtrip::<ButtplugSpecV0ServerMessage>(ButtplugMessageSpecVersion::Version0);
+  check_server_roundtrip::<ButtplugSpecV1ServerMessage>(ButtplugMessageSpecVersion::Version1);
+  check_server_roundtrip::<ButtplugSpecV2ServerMessage>(ButtplugMessageSpecVersion::Version2);
+  check_server_roundtrip::<ButtplugSpecV3ServerMessage>(ButtplugMessageSpecVersion::Version3);
+}
+
+fn out_of_range_client_message() -> impl Strategy<Value = ButtplugClientMessage> {
+  prop_oneof![
+    (client_id(), any::<u32>(), out_of_range_value()).prop_map(|(id, index, speed)| with_id(
+      messages::VibrateCmd::new(index, vec![messages::VibrateSubcommand::new(0, speed)]),
+      id
+    )
+    .into()),
+    (client_id(), any::<u32>(), out_of_range_value()).prop_map(|(id, index, position)| with_id(
+      messages::LinearCmd::new(
+        index,
+        vec![messages::VectorSubcommand::new(0, 500, position)]
+      ),
+      id
+    )
+    .into()),
+    (client_id(), any::<u32>(), out_of_range_value()).prop_map(|(id, index, speed)| with_id(
+      messages::RotateCmd::new(
+        index,
+        vec![messages::RotationSubcommand::new(0, speed, true)]
+      ),
+      id
+    )
+    .into()),
+    (client_id(), any::<u32>(), out_of_range_value()).prop_map(|(id, index, speed)| with_id(
+      messages::SingleMotorVibrateCmd::new(index, speed),
+      id
+    )
+    .into()),
+    (client_id(), any::<u32>(), 100u8..).prop_map(|(id, index, speed)| with_id(
+      messages::FleshlightLaunchFW12Cmd::new(index, 50, speed),
+      id
+    )
+    .into()),
+    (client_id(), any::<u32>(), 100u8..).prop_map(|(id, index, position)| with_id(
+      messages::FleshlightLaunchFW12Cmd::new(index, position, 50),
+      id
+    )
+    .into()),
+  ]
+}
+
+#[test]
+fn test_out_of_range_client_messages_rejected() {
+  // Every message here exists in spec v1, so they all go through the same
+  // serializer.
+  let serializer = server_serializer(ButtplugMessageSpecVersion::Version1);
+  TestRunner::default()
+    .run(&out_of_range_client_message(), |msg| {
+      prop_assert!(msg.is_valid().is_err());
+      let json = vec_to_protocol_json(vec![ButtplugSpecV1ClientMessage::try_from(msg).unwrap()]);
+      prop_assert!(serializer
+        .deserialize(ButtplugSerializedMessage::Text(json))
+        .is_err());
+      Ok(())
+    })
+    .unwrap();
+}
+
+proptest! {
+  #[test]
+  fn test_client_system_id_rejected(msg in client_message()) {
+    prop_assert!(with_id(msg, 0).is_valid().is_err());
+  }
+
+  #[test]
+  fn test_vorze_speed_out_of_range_rejected(speed in 100u32.., index in any::<u32>()) {
+    prop_assert!(messages::VorzeA10CycloneCmd::new(index, speed, true).is_valid().is_err());
+  }
+
+  #[test]
+  fn test_reading_out_of_range_rejected(
+    battery_level in out_of_range_value(),
+    rssi_level in 1i32..,
+    index in any::<u32>()
+  ) {
+    prop_assert!(messages::BatteryLevelReading::new(index, battery_level).is_valid().is_err());
+    prop_assert!(messages::RSSILevelReading::new(index, rssi_level).is_valid().is_err());
+  }
+}
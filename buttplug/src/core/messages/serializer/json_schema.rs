@@ -0,0 +1,160 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2022 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! JSON Schema for each version of the message spec, generated from the
+//! message types themselves.
+//!
+//! Unlike the hand written schema the serializers validate against, these
+//! can't drift from what the library actually sends and accepts, so they're
+//! the thing to generate bindings or validators for other languages from.
+//!
+//! ```no_run
+//! use buttplug::core::messages::{serializer::message_schema, ButtplugMessageSpecVersion};
+//!
+//! let schema = message_schema(ButtplugMessageSpecVersion::Version3);
+//! println!("{}", serde_json::to_string_pretty(&schema).unwrap());
+//! ```
+
+use crate::core::messages::{
+  ButtplugMessageSpecVersion, ButtplugSpecV0ClientMessage, ButtplugSpecV0ServerMessage,
+  ButtplugSpecV1ClientMessage, ButtplugSpecV1ServerMessage, ButtplugSpecV2ClientMessage,
+  ButtplugSpecV2ServerMessage, ButtplugSpecV3ClientMessage, ButtplugSpecV3ServerMessage,
+};
+use schemars::{
+  gen::SchemaSettings,
+  schema::{
+    ArrayValidation, InstanceType, Metadata, RootSchema, Schema, SchemaObject, SubschemaValidation,
+  },
+  JsonSchema,
+};
+
+/// Returns the JSON Schema for messages of a spec version, as sent over the
+/// wire: an array of one or more client or server messages.
+///
+/// Device message attributes are described in full in every version, as the
+/// attributes type is shared between them, even though older versions only
+/// send some of the attributes.
+pub fn message_schema(version: ButtplugMessageSpecVersion) -> RootSchema {
+  match version {
+    ButtplugMessageSpecVersion::Version0 => {
+      schema_for::<ButtplugSpecV0ClientMessage, ButtplugSpecV0ServerMessage>(version)
+    }
+    ButtplugMessageSpecVersion::Version1 => {
+      schema_for::<ButtplugSpecV1ClientMessage, ButtplugSpecV1ServerMessage>(version)
+    }
+    ButtplugMessageSpecVersion::Version2 => {
+      schema_for::<ButtplugSpecV2ClientMessage, ButtplugSpecV2ServerMessage>(version)
+    }
+    ButtplugMessageSpecVersion::Version3 => {
+      schema_for::<ButtplugSpecV3ClientMessage, ButtplugSpecV3ServerMessage>(version)
+    }
+  }
+}
+
+fn schema_for<Client, Server>(version: ButtplugMessageSpecVersion) -> RootSchema
+where
+  Client: JsonSchema,
+  Server: JsonSchema,
+{
+  let mut generator = SchemaSettings::draft07().into_generator();
+  // Client and server messages never share a name, so a message can only
+  // ever match one of these.
+  let message = SchemaObject {
+    subschemas: Some(Box::new(SubschemaValidation {
+      one_of: Some(vec![
+        generator.subschema_for::<Client>(),
+        generator.subschema_for::<Server>(),
+      ]),
+      ..Default::default()
+    })),
+    ..Default::default()
+  };
+  let schema = SchemaObject {
+    metadata: Some(Box::new(Metadata {
+      title: Some(format!("Buttplug Message Spec v{}", version as u32)),
+      ..Default::default()
+    })),
+    instance_type: Some(InstanceType::Array.into()),
+    array: Some(Box::new(ArrayValidation {
+      items: Some(Schema::Object(message).into()),
+      min_items: Some(1),
+      ..Default::default()
+    })),
+    ..Default::default()
+  };
+  RootSchema {
+    meta_schema: generator.settings().meta_schema.clone(),
+    definitions: generator.take_definitions(),
+    schema,
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::message_schema;
+  use crate::{
+    core::messages::{
+      serializer::{
+        ButtplugMessageSerializer, ButtplugSerializedMessage, ButtplugServerJSONSerializer,
+      },
+      ButtplugDeviceMessageType, ButtplugMessageSpecVersion, ButtplugServerMessage, DeviceAdded,
+      DeviceMessageAttributes, DeviceMessageAttributesMap, Ok,
+    },
+    util::json::JSONValidator,
+  };
+
+  fn validator(version: ButtplugMessageSpecVersion) -> JSONValidator {
+    JSONValidator::new(&serde_json::to_string(&message_schema(version)).unwrap())
+  }
+
+  #[test]
+  fn test_message_schema_validates_messages() {
+    let mut attributes = DeviceMessageAttributesMap::new();
+    attributes.insert(
+      ButtplugDeviceMessageType::VibrateCmd,
+      DeviceMessageAttributes {
+        feature_count: Some(2),
+        step_count: Some(vec![20, 20]),
+        ..Default::default()
+      },
+    );
+    for version in [
+      ButtplugMessageSpecVersion::Version0,
+      ButtplugMessageSpecVersion::Version1,
+      ButtplugMessageSpecVersion::Version2,
+      ButtplugMessageSpecVersion::Version3,
+    ] {
+      let validator = validator(version);
+      let serializer = ButtplugServerJSONSerializer::default();
+      let handshake = format!(
+        "[{{\"RequestServerInfo\":{{\"Id\":1,\"ClientName\":\"Test\",\"MessageVersion\":{}}}}}]",
+        version as u32
+      );
+      assert!(validator.validate(&handshake).is_ok());
+      serializer.deserialize(handshake.into()).unwrap();
+      let messages: Vec<ButtplugServerMessage> = vec![
+        Ok::new(1).into(),
+        DeviceAdded::new(0, "Test Device", &attributes).into(),
+      ];
+      let serialized = match serializer.serialize(messages) {
+        ButtplugSerializedMessage::Text(text) => text,
+        _ => panic!("JSON serializer should only output text"),
+      };
+      assert!(
+        validator.validate(&serialized).is_ok(),
+        "v{} schema rejected {}",
+        version as u32,
+        serialized
+      );
+    }
+    let validator = validator(ButtplugMessageSpecVersion::Version3);
+    assert!(validator
+      .validate("[{\"NotAMessage\":{\"Id\":1}}]")
+      .is_err());
+    assert!(validator.validate("[]").is_err());
+  }
+}
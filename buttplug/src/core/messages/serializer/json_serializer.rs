@@ -7,7 +7,8 @@ use crate::{
       ButtplugCurrentSpecServerMessage, ButtplugMessage, ButtplugMessageSpecVersion,
       ButtplugServerMessage, ButtplugSpecV0ClientMessage, ButtplugSpecV0ServerMessage,
       ButtplugSpecV1ClientMessage, ButtplugSpecV1ServerMessage, ButtplugSpecV2ClientMessage,
-      ButtplugSpecV2ServerMessage,
+      ButtplugSpecV2ServerMessage, ButtplugSpecV3ClientMessage, ButtplugSpecV3ServerMessage,
+      BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION,
     },
   },
   util::json::JSONValidator,
@@ -23,6 +24,18 @@ static MESSAGE_JSON_SCHEMA: &str =
 pub fn create_message_validator() -> JSONValidator {
   JSONValidator::new(MESSAGE_JSON_SCHEMA)
 }
+/// Websocket subprotocols [ButtplugServerJSONSerializer] supports, in order
+/// of preference. `buttplug-json` takes the spec version from the client's
+/// RequestServerInfo, like connections that don't ask for a subprotocol. The
+/// others fix the spec version from the start.
+pub const BUTTPLUG_JSON_SUBPROTOCOLS: &[&str] = &[
+  "buttplug-json",
+  "buttplug-json-v3",
+  "buttplug-json-v2",
+  "buttplug-json-v1",
+  "buttplug-json-v0",
+];
+
 pub struct ButtplugServerJSONSerializer {
   pub(super) message_version: RefCell<Option<messages::ButtplugMessageSpecVersion>>,
   validator: JSONValidator,
@@ -52,7 +65,7 @@ where
   serde_json::to_string(&msg).unwrap()
 }
 
-fn deserialize_to_message<T>(
+pub(super) fn deserialize_to_message<T>(
   validator: &JSONValidator,
   msg: String,
 ) -> Result<Vec<T>, ButtplugSerializerError>
@@ -109,6 +122,17 @@ fn serialize_to_version(
         .collect();
       vec_to_protocol_json(msg_vec)
     }
+    ButtplugMessageSpecVersion::Version3 => {
+      let msg_vec: Vec<ButtplugSpecV3ServerMessage> = msgs
+        .iter()
+        .cloned()
+        .map(|msg| match ButtplugSpecV3ServerMessage::try_from(msg) {
+          Ok(msgv3) => msgv3,
+          Err(err) => ButtplugSpecV3ServerMessage::Error(ButtplugError::from(err).into()),
+        })
+        .collect();
+      vec_to_protocol_json(msg_vec)
+    }
   })
 }
 
@@ -155,6 +179,13 @@ impl ButtplugMessageSerializer for ButtplugServerJSONSerializer {
             .map(|m| m.into())
             .collect()
         }
+        ButtplugMessageSpecVersion::Version3 => {
+          deserialize_to_message::<ButtplugSpecV3ClientMessage>(&self.validator, msg)?
+            .iter()
+            .cloned()
+            .map(|m| m.into())
+            .collect()
+        }
       });
     }
     // instead of using if/else here, return in the if, which drops the borrow.
@@ -180,7 +211,7 @@ impl ButtplugMessageSerializer for ButtplugServerJSONSerializer {
       // RequestServerInfo message (so we can't set up our known spec
       // version), just encode to the latest and return.
       if let ButtplugServerMessage::Error(_) = &msgs[0] {
-        serialize_to_version(ButtplugMessageSpecVersion::Version2, msgs)
+        serialize_to_version(BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION, msgs)
       } else {
         // If we don't even have enough info to know which message
         // version to convert to, consider this a handshake error.
@@ -192,6 +223,20 @@ impl ButtplugMessageSerializer for ButtplugServerJSONSerializer {
       }
     }
   }
+
+  fn set_subprotocol(&self, subprotocol: &str) -> Result<(), ButtplugSerializerError> {
+    let version = match subprotocol {
+      "buttplug-json" => None,
+      "buttplug-json-v0" => Some(ButtplugMessageSpecVersion::Version0),
+      "buttplug-json-v1" => Some(ButtplugMessageSpecVersion::Version1),
+      "buttplug-json-v2" => Some(ButtplugMessageSpecVersion::Version2),
+      "buttplug-json-v3" => Some(ButtplugMessageSpecVersion::Version3),
+      _ => return Err(ButtplugSerializerError::UnknownSubprotocol(subprotocol.to_owned())),
+    };
+    info!("Client asked for subprotocol {}", subprotocol);
+    *self.message_version.borrow_mut() = version;
+    Ok(())
+  }
 }
 
 pub struct ButtplugClientJSONSerializer {
@@ -232,7 +277,7 @@ impl ButtplugMessageSerializer for ButtplugClientJSONSerializer {
 #[cfg(test)]
 mod test {
   use super::*;
-  use crate::core::messages::{RequestServerInfo, BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION};
+  use crate::core::messages::RequestServerInfo;
 
   #[test]
   fn test_correct_message_version() {
@@ -267,6 +312,103 @@ mod test {
     assert!(msg.is_err());
   }
 
+  #[test]
+  fn test_subprotocol_message_version() {
+    let json = r#"[{
+            "VibrateCmd": {
+                "Id": 2,
+                "DeviceIndex": 0,
+                "Speeds": [{ "Index": 0, "Speed": 0.5 }]
+            }
+        }]"#;
+    // Without a subprotocol, the spec version has to come from a handshake.
+    let serializer = ButtplugServerJSONSerializer::default();
+    assert!(matches!(
+      serializer.deserialize(ButtplugSerializedMessage::Text(json.to_owned())),
+      Err(ButtplugSerializerError::MessageSpecVersionNotReceived)
+    ));
+    serializer.set_subprotocol("buttplug-json-v1").unwrap();
+    assert_eq!(
+      *serializer.message_version.borrow(),
+      Some(ButtplugMessageSpecVersion::Version1)
+    );
+    assert!(serializer
+      .deserialize(ButtplugSerializedMessage::Text(json.to_owned()))
+      .is_ok());
+    assert!(matches!(
+      serializer.set_subprotocol("buttplug-cbor"),
+      Err(ButtplugSerializerError::UnknownSubprotocol(_))
+    ));
+  }
+
+  #[test]
+  fn test_v2_sensor_subscriptions_rejected() {
+    let json = r#"[{
+            "SensorSubscribeCmd": {
+                "Id": 2,
+                "DeviceIndex": 0,
+                "SensorIndex": 0
+            }
+        }]"#;
+    let serializer = ButtplugServerJSONSerializer::default();
+    serializer.set_subprotocol("buttplug-json-v2").unwrap();
+    assert!(serializer
+      .deserialize(ButtplugSerializedMessage::Text(json.to_owned()))
+      .is_err());
+    serializer.set_subprotocol("buttplug-json-v3").unwrap();
+    assert!(serializer
+      .deserialize(ButtplugSerializedMessage::Text(json.to_owned()))
+      .is_ok());
+  }
+
+  #[test]
+  fn test_v2_request_log_rejected() {
+    let json = r#"[{
+            "RequestLog": {
+                "Id": 2,
+                "LogLevel": "Info"
+            }
+        }]"#;
+    let serializer = ButtplugServerJSONSerializer::default();
+    serializer.set_subprotocol("buttplug-json-v2").unwrap();
+    assert!(serializer
+      .deserialize(ButtplugSerializedMessage::Text(json.to_owned()))
+      .is_err());
+    serializer.set_subprotocol("buttplug-json-v3").unwrap();
+    assert!(serializer
+      .deserialize(ButtplugSerializedMessage::Text(json.to_owned()))
+      .is_ok());
+  }
+
+  #[test]
+  fn test_v3_messages_not_sent_to_v2() {
+    let json = r#"[{
+            "RequestServerInfo": {
+                "Id": 1,
+                "ClientName": "Test Client",
+                "MessageVersion": 2
+            }
+        }]"#;
+    let serializer = ButtplugServerJSONSerializer::default();
+    serializer
+      .deserialize(ButtplugSerializedMessage::Text(json.to_owned()))
+      .unwrap();
+    let reading: ButtplugServerMessage =
+      messages::SensorReading::new(0, 0, messages::SensorType::Pressure, vec![10]).into();
+    let log: ButtplugServerMessage = messages::Log::new(messages::LogLevel::Info, "Test").into();
+    for msg in vec![reading.clone(), log] {
+      match serializer.serialize(vec![msg]) {
+        ButtplugSerializedMessage::Text(text) => assert!(text.contains("\"Error\"")),
+        ButtplugSerializedMessage::Binary(_) => panic!("JSON serializer should output text"),
+      }
+    }
+    serializer.set_subprotocol("buttplug-json-v3").unwrap();
+    match serializer.serialize(vec![reading]) {
+      ButtplugSerializedMessage::Text(text) => assert!(text.contains("\"SensorReading\"")),
+      ButtplugSerializedMessage::Binary(_) => panic!("JSON serializer should output text"),
+    }
+  }
+
   #[test]
   fn test_client_incorrect_messages() {
     let incorrect_incoming_messages = vec![
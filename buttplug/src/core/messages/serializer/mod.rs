@@ -1,7 +1,19 @@
 #[cfg(feature = "serialize-json")]
 mod json_serializer;
 #[cfg(feature = "serialize-json")]
-pub use json_serializer::{ButtplugClientJSONSerializer, ButtplugServerJSONSerializer};
+pub use json_serializer::{
+  ButtplugClientJSONSerializer, ButtplugServerJSONSerializer, BUTTPLUG_JSON_SUBPROTOCOLS,
+};
+#[cfg(feature = "serialize-json")]
+mod server_serializer;
+#[cfg(feature = "serialize-json")]
+pub use server_serializer::{ButtplugServerSerializer, BUTTPLUG_SERVER_SUBPROTOCOLS};
+#[cfg(all(test, feature = "serialize-json"))]
+mod json_roundtrip_tests;
+#[cfg(feature = "json-schema")]
+mod json_schema;
+#[cfg(feature = "json-schema")]
+pub use json_schema::message_schema;
 
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
@@ -22,6 +34,8 @@ pub enum ButtplugSerializerError {
   TextDeserializationError,
   #[error("Message version not received, can't figure out which spec version to de/serialize to.")]
   MessageSpecVersionNotReceived,
+  #[error("Serializer does not support subprotocol {0}.")]
+  UnknownSubprotocol(String),
 }
 
 #[derive(Debug, Display, Clone, PartialEq)]
@@ -42,7 +56,7 @@ impl From<Vec<u8>> for ButtplugSerializedMessage {
   }
 }
 
-pub trait ButtplugMessageSerializer: Default + Sync + Send {
+pub trait ButtplugMessageSerializer: Sync + Send {
   type Inbound;
   type Outbound;
   fn deserialize(
@@ -50,4 +64,12 @@ pub trait ButtplugMessageSerializer: Default + Sync + Send {
     msg: ButtplugSerializedMessage,
   ) -> ButtplugSerializerResult<Vec<Self::Inbound>>;
   fn serialize(&self, msg: Vec<Self::Outbound>) -> ButtplugSerializedMessage;
+  /// Switches to the format named by a subprotocol the transport agreed on
+  /// while connecting (e.g. websocket `Sec-WebSocket-Protocol`). Called
+  /// before any messages come through. Serializers that only speak one
+  /// format don't support any subprotocols, see [ButtplugServerSerializer]
+  /// for one that speaks several.
+  fn set_subprotocol(&self, subprotocol: &str) -> ButtplugSerializerResult<()> {
+    Err(ButtplugSerializerError::UnknownSubprotocol(subprotocol.to_owned()))
+  }
 }
@@ -0,0 +1,118 @@
+use super::{
+  ButtplugMessageSerializer, ButtplugSerializedMessage, ButtplugSerializerError,
+  ButtplugSerializerResult, ButtplugServerJSONSerializer, BUTTPLUG_JSON_SUBPROTOCOLS,
+};
+use crate::core::messages::{ButtplugClientMessage, ButtplugServerMessage};
+use std::cell::RefCell;
+
+/// Subprotocols [ButtplugServerSerializer] supports, in order of preference.
+/// JSON is the only format for now, other formats add theirs here and in
+/// [format_for_subprotocol].
+pub const BUTTPLUG_SERVER_SUBPROTOCOLS: &[&str] = BUTTPLUG_JSON_SUBPROTOCOLS;
+
+type ServerFormat = Box<
+  dyn ButtplugMessageSerializer<Inbound = ButtplugClientMessage, Outbound = ButtplugServerMessage>,
+>;
+
+fn format_for_subprotocol(subprotocol: &str) -> Option<ServerFormat> {
+  if BUTTPLUG_JSON_SUBPROTOCOLS.contains(&subprotocol) {
+    Some(Box::new(ButtplugServerJSONSerializer::default()))
+  } else {
+    None
+  }
+}
+
+/// Server serializer that picks its format per connection, from the
+/// subprotocol the transport agreed on with the client. Connections that
+/// don't ask for a subprotocol get JSON, with the spec version taken from
+/// their RequestServerInfo.
+///
+/// Remote connectors create a serializer for every connection, so one server
+/// can talk to clients using different formats and spec versions.
+pub struct ButtplugServerSerializer {
+  format: RefCell<ServerFormat>,
+}
+
+impl Default for ButtplugServerSerializer {
+  fn default() -> Self {
+    Self {
+      format: RefCell::new(Box::new(ButtplugServerJSONSerializer::default())),
+    }
+  }
+}
+
+unsafe impl Sync for ButtplugServerSerializer {}
+unsafe impl Send for ButtplugServerSerializer {}
+
+impl ButtplugMessageSerializer for ButtplugServerSerializer {
+  type Inbound = ButtplugClientMessage;
+  type Outbound = ButtplugServerMessage;
+
+  fn deserialize(
+    &self,
+    msg: ButtplugSerializedMessage,
+  ) -> ButtplugSerializerResult<Vec<ButtplugClientMessage>> {
+    self.format.borrow().deserialize(msg)
+  }
+
+  fn serialize(&self, msgs: Vec<ButtplugServerMessage>) -> ButtplugSerializedMessage {
+    self.format.borrow().serialize(msgs)
+  }
+
+  fn set_subprotocol(&self, subprotocol: &str) -> ButtplugSerializerResult<()> {
+    let format = format_for_subprotocol(subprotocol)
+      .ok_or_else(|| ButtplugSerializerError::UnknownSubprotocol(subprotocol.to_owned()))?;
+    // Formats can cover more than one subprotocol, e.g. JSON spec versions.
+    format.set_subprotocol(subprotocol)?;
+    *self.format.borrow_mut() = format;
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::core::messages::{self, ButtplugMessageSpecVersion};
+
+  #[test]
+  fn test_server_serializer_per_connection() {
+    let stop = r#"[{
+            "StopAllDevices": {
+                "Id": 2
+            }
+        }]"#;
+    let handshake = r#"[{
+            "RequestServerInfo": {
+                "Id": 1,
+                "ClientName": "Test Client",
+                "MessageVersion": 2
+            }
+        }]"#;
+    // Two connections to the same server, one pinning spec v0 through its
+    // subprotocol, one working it out from the handshake.
+    let pinned = ButtplugServerSerializer::default();
+    pinned.set_subprotocol("buttplug-json-v0").unwrap();
+    assert!(pinned
+      .deserialize(ButtplugSerializedMessage::Text(stop.to_owned()))
+      .is_ok());
+    let negotiated = ButtplugServerSerializer::default();
+    assert!(matches!(
+      negotiated.deserialize(ButtplugSerializedMessage::Text(stop.to_owned())),
+      Err(ButtplugSerializerError::MessageSpecVersionNotReceived)
+    ));
+    assert!(negotiated
+      .deserialize(ButtplugSerializedMessage::Text(handshake.to_owned()))
+      .is_ok());
+    // Spec v0 has its own ServerInfo, with version fields later specs dropped.
+    let server_info: ButtplugServerMessage =
+      messages::ServerInfo::new("Test Server", ButtplugMessageSpecVersion::Version2, 0).into();
+    assert_ne!(
+      pinned.serialize(vec![server_info.clone()]),
+      negotiated.serialize(vec![server_info])
+    );
+    assert!(matches!(
+      negotiated.set_subprotocol("buttplug-cbor"),
+      Err(ButtplugSerializerError::UnknownSubprotocol(_))
+    ));
+  }
+}
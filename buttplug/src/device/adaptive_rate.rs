@@ -0,0 +1,490 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2022 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Slows down commands to devices that can't keep up with them.
+//!
+//! On a congested link (usually BLE), writes take longer and longer to go
+//! through, and every command sent while the device is behind just gets
+//! queued up behind the ones before it. Users see that as lag that keeps
+//! growing, up to seconds. So we time how long actuator commands take to
+//! complete, and while that's over [LATENCY_THRESHOLD] the device is treated
+//! as falling behind:
+//!
+//! - Only one actuator command is sent at a time, and no sooner than the
+//!   average latency after the last one.
+//! - Commands that come in before then are merged per feature, so only the
+//!   latest value for each feature is sent. Their futures resolve with the
+//!   result of the merged send.
+//!
+//! Once latency recovers, commands go straight through again. Stop commands
+//! are never held. They drop anything waiting to be sent, and aren't
+//! written until a flush of held commands that's already under way has been
+//! cancelled.
+//!
+//! Holding commands is only turned on for protocols that set the
+//! `adaptive-rate` option. Latency is timed for every device either way, as
+//! linear latency compensation uses it.
+
+use super::{protocol::ButtplugProtocol, ButtplugDeviceResultFuture, DeviceImpl};
+use crate::{
+  core::{
+    errors::ButtplugError,
+    messages::{
+      self, ButtplugDeviceCommandMessageUnion, ButtplugDeviceMessage, ButtplugMessage, LinearCmd,
+      RotateCmd, VibrateCmd,
+    },
+  },
+  util::clock::Clock,
+};
+use futures::{channel::oneshot, lock::Mutex as AsyncMutex, select, FutureExt};
+use std::{
+  collections::HashMap,
+  sync::{Arc, Mutex},
+  time::{Duration, Instant},
+};
+use tokio_util::sync::CancellationToken;
+
+/// Average command latency above which a device is falling behind.
+pub const LATENCY_THRESHOLD: Duration = Duration::from_millis(100);
+/// Weight of the newest sample in the latency average.
+const LATENCY_SMOOTHING: f64 = 0.25;
+
+/// A held command, and the callers of every command merged into it.
+struct HeldCommand {
+  message: ButtplugDeviceCommandMessageUnion,
+  waiters: Vec<oneshot::Sender<Result<(), ButtplugError>>>,
+}
+
+#[derive(Default)]
+struct RateState {
+  average_latency: Option<Duration>,
+  in_flight: usize,
+  last_sent_at: Option<Instant>,
+  /// Held commands, at most one per message type.
+  pending: HashMap<&'static str, HeldCommand>,
+  flush_scheduled: bool,
+  /// Cancelled by stop commands, to drop flushes that are waiting or under
+  /// way. Replaced after every cancel.
+  flush_token: CancellationToken,
+  falling_behind: bool,
+}
+
+impl RateState {
+  /// Time left before the next command can go out, or None if it can't
+  /// until the command in flight completes.
+  fn wait_time(&self, now: Instant) -> Option<Duration> {
+    if !self.falling_behind {
+      return Some(Duration::ZERO);
+    }
+    if self.in_flight > 0 {
+      return None;
+    }
+    let next_send_at = self.last_sent_at? + self.average_latency.unwrap_or_default();
+    Some(next_send_at.saturating_duration_since(now))
+  }
+
+  fn record_latency(&mut self, latency: Duration) {
+    let average = match self.average_latency {
+      Some(average) => {
+        average.mul_f64(1.0 - LATENCY_SMOOTHING) + latency.mul_f64(LATENCY_SMOOTHING)
+      }
+      None => latency,
+    };
+    self.average_latency = Some(average);
+    let falling_behind = average > LATENCY_THRESHOLD;
+    if falling_behind != self.falling_behind {
+      if falling_behind {
+        warn!(
+          "Device commands are taking {:?} to complete, slowing down commands to catch up.",
+          average
+        );
+      } else {
+        info!("Device caught up, no longer slowing down commands.");
+      }
+      self.falling_behind = falling_behind;
+    }
+  }
+}
+
+/// Paces actuator commands for a device to what its link can take.
+pub(crate) struct AdaptiveRateController {
+  /// Whether commands are held while the device is behind.
+  enabled: bool,
+  state: Arc<Mutex<RateState>>,
+  /// Held for as long as a flush runs, so stops can wait for a cancelled
+  /// flush to be gone.
+  flush_lock: Arc<AsyncMutex<()>>,
+  protocol: Arc<dyn ButtplugProtocol>,
+  device: Arc<DeviceImpl>,
+  clock: Arc<dyn Clock>,
+}
+
+impl AdaptiveRateController {
+  pub fn new(enabled: bool, protocol: Arc<dyn ButtplugProtocol>, device: Arc<DeviceImpl>) -> Self {
+    Self {
+      enabled,
+      state: Arc::new(Mutex::new(RateState::default())),
+      flush_lock: Arc::new(AsyncMutex::new(())),
+      clock: device.clock(),
+      protocol,
+      device,
+    }
+  }
+
+  pub fn send(&self, message: ButtplugDeviceCommandMessageUnion) -> ButtplugDeviceResultFuture {
+    if let ButtplugDeviceCommandMessageUnion::StopDeviceCmd(_) = message {
+      return self.send_stop(message);
+    }
+    if !is_actuator_command(&message) {
+      return self.protocol.handle_command(self.device.clone(), message);
+    }
+    let mut state = self.state.lock().unwrap();
+    if !self.enabled
+      || (state.pending.is_empty() && state.wait_time(self.clock.now()) == Some(Duration::ZERO))
+    {
+      return self.send_now(&mut state, message);
+    }
+    let id = message.id();
+    let name = message.message_name();
+    let (sender, receiver) = oneshot::channel();
+    let held = match state.pending.remove(name) {
+      Some(mut held) => {
+        held.message = merge_commands(held.message, message);
+        held.waiters.push(sender);
+        held
+      }
+      None => HeldCommand {
+        message,
+        waiters: vec![sender],
+      },
+    };
+    state.pending.insert(name, held);
+    self.schedule_flush(&mut state);
+    Box::pin(async move {
+      match receiver.await {
+        Ok(Err(e)) => Err(e),
+        // The sender is only dropped without a result if a stop dropped the
+        // command, which stands in for it.
+        Ok(Ok(())) | Err(_) => Ok(messages::Ok::new(id).into()),
+      }
+    })
+  }
+
+  /// Average time actuator commands have taken to complete, or None if
+  /// none have been timed yet.
+  pub fn average_latency(&self) -> Option<Duration> {
+    self.state.lock().unwrap().average_latency
+  }
+
+  fn send_stop(&self, message: ButtplugDeviceCommandMessageUnion) -> ButtplugDeviceResultFuture {
+    {
+      let mut state = self.state.lock().unwrap();
+      state.flush_token.cancel();
+      state.flush_token = CancellationToken::new();
+      state.flush_scheduled = false;
+      state.pending.clear();
+    }
+    let flush_lock = self.flush_lock.clone();
+    let protocol = self.protocol.clone();
+    let device = self.device.clone();
+    Box::pin(async move {
+      // A flush that was mid-send when it got cancelled has to be dropped
+      // before the stop goes out, or its write could land after the stop.
+      drop(flush_lock.lock().await);
+      protocol.handle_command(device, message).await
+    })
+  }
+
+  fn send_now(
+    &self,
+    state: &mut RateState,
+    message: ButtplugDeviceCommandMessageUnion,
+  ) -> ButtplugDeviceResultFuture {
+    let sent_at = self.clock.now();
+    state.in_flight += 1;
+    state.last_sent_at = Some(sent_at);
+    let fut = self.protocol.handle_command(self.device.clone(), message);
+    // Dropping the future counts as completing it, so a dropped command
+    // can't hold up everything after it.
+    let completion = InFlight {
+      controller: self.clone_handle(),
+      sent_at,
+    };
+    Box::pin(async move {
+      let result = fut.await;
+      drop(completion);
+      result
+    })
+  }
+
+  /// Sends held commands once the wait is over. If a command is in flight,
+  /// its completion schedules this instead.
+  fn schedule_flush(&self, state: &mut RateState) {
+    if state.pending.is_empty() || state.flush_scheduled {
+      return;
+    }
+    let wait = match state.wait_time(self.clock.now()) {
+      Some(wait) => wait,
+      None => return,
+    };
+    state.flush_scheduled = true;
+    let token = state.flush_token.clone();
+    let controller = self.clone_handle();
+    self.device.scheduler().schedule(wait, move || {
+      let token = token.clone();
+      let controller = controller.clone_handle();
+      async move {
+        let _flushing = controller.flush_lock.lock().await;
+        if !token.is_cancelled() {
+          select! {
+            _ = token.cancelled().fuse() => {}
+            _ = controller.flush().fuse() => {}
+          }
+        }
+        None
+      }
+    });
+  }
+
+  /// Sends held commands one at a time, handing each result to the callers
+  /// waiting on it.
+  async fn flush(&self) {
+    let held: Vec<HeldCommand> = {
+      let mut state = self.state.lock().unwrap();
+      state.flush_scheduled = false;
+      state.pending.drain().map(|(_, held)| held).collect()
+    };
+    for held in held {
+      let send = {
+        let mut state = self.state.lock().unwrap();
+        self.send_now(&mut state, held.message)
+      };
+      let result = send.await.map(|_| ());
+      for waiter in held.waiters {
+        let _ = waiter.send(result.clone());
+      }
+    }
+  }
+
+  fn clone_handle(&self) -> Self {
+    Self {
+      enabled: self.enabled,
+      state: self.state.clone(),
+      flush_lock: self.flush_lock.clone(),
+      protocol: self.protocol.clone(),
+      device: self.device.clone(),
+      clock: self.clock.clone(),
+    }
+  }
+}
+
+struct InFlight {
+  controller: AdaptiveRateController,
+  sent_at: Instant,
+}
+
+impl Drop for InFlight {
+  fn drop(&mut self) {
+    let latency = self.controller.clock.now() - self.sent_at;
+    let mut state = self.controller.state.lock().unwrap();
+    state.in_flight = state.in_flight.saturating_sub(1);
+    state.record_latency(latency);
+    self.controller.schedule_flush(&mut state);
+  }
+}
+
+fn is_actuator_command(message: &ButtplugDeviceCommandMessageUnion) -> bool {
+  matches!(
+    message,
+    ButtplugDeviceCommandMessageUnion::VibrateCmd(_)
+      | ButtplugDeviceCommandMessageUnion::RotateCmd(_)
+      | ButtplugDeviceCommandMessageUnion::LinearCmd(_)
+  )
+}
+
+/// Merges subcommands by feature index, with the newer command winning.
+fn merge_by_index<T: Clone>(older: &[T], newer: &[T], index: fn(&T) -> u32) -> Vec<T> {
+  let mut merged: Vec<T> = older
+    .iter()
+    .filter(|old| !newer.iter().any(|new| index(new) == index(old)))
+    .cloned()
+    .collect();
+  merged.extend(newer.iter().cloned());
+  merged.sort_by_key(index);
+  merged
+}
+
+fn merge_commands(
+  older: ButtplugDeviceCommandMessageUnion,
+  newer: ButtplugDeviceCommandMessageUnion,
+) -> ButtplugDeviceCommandMessageUnion {
+  match (older, newer) {
+    (
+      ButtplugDeviceCommandMessageUnion::VibrateCmd(older),
+      ButtplugDeviceCommandMessageUnion::VibrateCmd(newer),
+    ) => VibrateCmd::new(
+      newer.device_index(),
+      merge_by_index(older.speeds(), newer.speeds(), |cmd| cmd.index()),
+    )
+    .into(),
+    (
+      ButtplugDeviceCommandMessageUnion::RotateCmd(older),
+      ButtplugDeviceCommandMessageUnion::RotateCmd(newer),
+    ) => RotateCmd::new(
+      newer.device_index(),
+      merge_by_index(&older.rotations, &newer.rotations, |cmd| cmd.index()),
+    )
+    .into(),
+    (
+      ButtplugDeviceCommandMessageUnion::LinearCmd(older),
+      ButtplugDeviceCommandMessageUnion::LinearCmd(newer),
+    ) => LinearCmd::new(
+      newer.device_index(),
+      merge_by_index(older.vectors(), newer.vectors(), |cmd| cmd.index()),
+    )
+    .into(),
+    // Pending commands are keyed by message type, so this doesn't happen.
+    (_, newer) => newer,
+  }
+}
+
+#[cfg(all(test, feature = "server"))]
+mod test {
+  use crate::{
+    core::messages::{StopDeviceCmd, VibrateCmd, VibrateSubcommand},
+    device::{
+      configuration_manager::{
+        BluetoothLESpecifier, DeviceConfigurationManager, DeviceConfigurationOptions,
+        DeviceSpecifier,
+      },
+      ButtplugDevice, DeviceImplCommand, DeviceWriteCmd, Endpoint,
+    },
+    test::{
+      check_test_recv_empty, check_test_recv_value, TestDeviceImplCreator, TestDeviceInternal,
+      TestFaultConfig,
+    },
+    util::{async_manager, clock::ManualClock},
+  };
+  use futures::{channel::oneshot, future::BoxFuture};
+  use std::{sync::Arc, time::Duration};
+
+  const WRITE_LATENCY: Duration = Duration::from_millis(200);
+
+  fn vibrate(index: u32, speed: f64) -> VibrateCmd {
+    VibrateCmd::new(0, vec![VibrateSubcommand::new(index, speed)])
+  }
+
+  fn write(data: Vec<u8>) -> DeviceImplCommand {
+    DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, data, false))
+  }
+
+  // Commands to a slow device don't complete until the clock moves, so they
+  // run in the background, and this resolves once they're done.
+  fn send_in_background(
+    device: &Arc<ButtplugDevice>,
+    command: VibrateCmd,
+  ) -> BoxFuture<'static, ()> {
+    let (sender, receiver) = oneshot::channel();
+    let fut = device.parse_message(command.into());
+    async_manager::spawn(async move {
+      fut.await.unwrap();
+      let _ = sender.send(());
+    })
+    .unwrap();
+    Box::pin(async move {
+      receiver.await.unwrap();
+    })
+  }
+
+  #[test]
+  fn test_adaptive_rate_holds_commands_for_slow_devices() {
+    async_manager::block_on(async {
+      let clock = ManualClock::new();
+      let mut test_device = TestDeviceInternal::new_with_faults(
+        "Massage Demo",
+        "adaptive-rate-test",
+        TestFaultConfig {
+          write_latency: Some(WRITE_LATENCY),
+          ..Default::default()
+        },
+      );
+      test_device.set_clock(Arc::new(clock.clone()));
+      let test_device = Arc::new(test_device);
+      let creator = TestDeviceImplCreator::new(
+        DeviceSpecifier::BluetoothLE(BluetoothLESpecifier::new_from_device("Massage Demo")),
+        test_device.clone(),
+      );
+      let config = DeviceConfigurationManager::new_with_options(&DeviceConfigurationOptions {
+        user_device_configuration_json: Some(
+          r#"{ "protocols": { "aneros": { "options": { "adaptive-rate": true } } } }"#.to_owned(),
+        ),
+        ..Default::default()
+      })
+      .unwrap();
+      let device = Arc::new(
+        ButtplugDevice::try_create_device(Arc::new(config), Box::new(creator))
+          .await
+          .unwrap()
+          .unwrap(),
+      );
+      let command_receiver = test_device.get_endpoint_receiver(&Endpoint::Tx).unwrap();
+
+      // Nothing's been timed yet, so the first command goes straight out.
+      let done = send_in_background(&device, vibrate(0, 0.5));
+      clock.wait_for_sleeps(1).await;
+      check_test_recv_value(&command_receiver, write(vec![0xF1, 64]));
+      clock.advance(WRITE_LATENCY);
+      done.await;
+
+      // The device is now behind, but nothing's in flight and the average
+      // latency has passed, so this one goes out too.
+      let done = send_in_background(&device, vibrate(0, 0.1));
+      clock.wait_for_sleeps(2).await;
+      check_test_recv_value(&command_receiver, write(vec![0xF1, 13]));
+
+      // These are held while the last command is in flight.
+      let held_first = send_in_background(&device, vibrate(0, 1.0));
+      let held_second = send_in_background(&device, vibrate(1, 0.5));
+      assert!(check_test_recv_empty(&command_receiver));
+
+      // Once the command in flight completes, the held ones go out merged,
+      // and both resolve when the merged command does.
+      clock.advance(WRITE_LATENCY);
+      done.await;
+      clock.wait_for_sleeps(3).await;
+      check_test_recv_value(&command_receiver, write(vec![0xF1, 127]));
+      clock.advance(WRITE_LATENCY);
+      clock.wait_for_sleeps(4).await;
+      check_test_recv_value(&command_receiver, write(vec![0xF2, 64]));
+      clock.advance(WRITE_LATENCY);
+      held_first.await;
+      held_second.await;
+      assert!(check_test_recv_empty(&command_receiver));
+
+      // Held commands a stop comes in behind are dropped, never written.
+      let done = send_in_background(&device, vibrate(0, 0.5));
+      clock.wait_for_sleeps(5).await;
+      check_test_recv_value(&command_receiver, write(vec![0xF1, 64]));
+      let held = send_in_background(&device, vibrate(1, 1.0));
+
+      // Stops are never held, even with a command in flight.
+      let stop = device.parse_message(StopDeviceCmd::new(0).into());
+      async_manager::spawn(async move {
+        stop.await.unwrap();
+      })
+      .unwrap();
+      clock.wait_for_sleeps(6).await;
+      check_test_recv_value(&command_receiver, write(vec![0xF1, 0]));
+      held.await;
+      clock.advance(WRITE_LATENCY);
+      done.await;
+      clock.wait_for_sleeps(7).await;
+      check_test_recv_value(&command_receiver, write(vec![0xF2, 0]));
+      assert!(check_test_recv_empty(&command_receiver));
+    });
+  }
+}
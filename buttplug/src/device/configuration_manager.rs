@@ -12,18 +12,25 @@ use crate::{
     errors::{ButtplugDeviceError, ButtplugError},
     messages::{ButtplugDeviceMessageType, DeviceMessageAttributes, DeviceMessageAttributesMap},
   },
-  device::Endpoint,
+  device::{
+    custom_endpoint::{deserialize_declared_endpoints, deserialize_declared_service_endpoints},
+    identity::{normalize_entry, DeviceIdentity},
+    Endpoint, WriteType,
+  },
   util::json::JSONValidator,
 };
-use super::protocol::{ButtplugProtocol, TryCreateProtocolFunc, get_default_protocol_map, add_to_protocol_map};
-use serde::Deserialize;
+use super::protocol::{
+  add_to_protocol_map, get_default_protocol_map, ButtplugProtocol, ProtocolFactory,
+  TryCreateProtocolFunc,
+};
+use serde::{de::DeserializeOwned, Deserialize};
 use std::{
   collections::{HashMap, HashSet},
   mem,
   sync::Arc
 };
 use uuid::Uuid;
-use dashmap::DashMap;
+use dashmap::{DashMap, DashSet};
 
 static DEVICE_CONFIGURATION_JSON: &str =
   include_str!("../../buttplug-device-config/buttplug-device-config.json");
@@ -41,7 +48,25 @@ static USER_DEVICE_CONFIGURATION_JSON_SCHEMA: &str =
 #[derive(Deserialize, Debug, Clone)]
 pub struct BluetoothLESpecifier {
   pub names: HashSet<String>,
+  #[serde(deserialize_with = "deserialize_declared_service_endpoints")]
   pub services: HashMap<Uuid, HashMap<Endpoint, Uuid>>,
+  /// Write types for endpoints that need a specific one, used instead of
+  /// whatever the protocol asks for.
+  #[serde(
+    rename = "write-types",
+    default,
+    deserialize_with = "deserialize_declared_endpoints"
+  )]
+  pub write_types: HashMap<Endpoint, WriteType>,
+  /// Devices won't work until they're bonded (paired) with the OS.
+  #[serde(rename = "requires-bonding", default)]
+  pub requires_bonding: bool,
+  /// Devices known to be bonded, by address or device ID: the ones the user
+  /// configuration lists, plus any bonded since. Shared with the
+  /// configuration manager, so devices that bond get recorded there. Filled
+  /// in when a device is matched, never read from the main configuration.
+  #[serde(skip)]
+  pub bonded_devices: Arc<DashSet<String>>,
 }
 
 impl PartialEq for BluetoothLESpecifier {
@@ -80,24 +105,192 @@ impl BluetoothLESpecifier {
     BluetoothLESpecifier {
       names: set,
       services: HashMap::new(),
+      write_types: HashMap::new(),
+      requires_bonding: false,
+      bonded_devices: Arc::new(DashSet::new()),
     }
   }
 }
 
+fn default_btclassic_services() -> HashSet<Uuid> {
+  // Serial Port Profile, which is what every RFCOMM toy we know of uses.
+  let mut services = HashSet::new();
+  services.insert(Uuid::from_u128(0x0000_1101_0000_1000_8000_0080_5f9b_34fb));
+  services
+}
+
+fn default_btclassic_channel() -> u8 {
+  1
+}
+
+/// Matches paired Bluetooth Classic devices by name, and by the RFCOMM
+/// services they advertise. Devices are connected to on the given RFCOMM
+/// channel.
+#[derive(Deserialize, Debug, Clone)]
+pub struct BluetoothClassicSpecifier {
+  pub names: HashSet<String>,
+  #[serde(default = "default_btclassic_services")]
+  pub services: HashSet<Uuid>,
+  #[serde(default = "default_btclassic_channel")]
+  pub channel: u8,
+}
+
+impl BluetoothClassicSpecifier {
+  pub fn new_from_device(name: &str, services: HashSet<Uuid>) -> Self {
+    let mut names = HashSet::new();
+    names.insert(name.to_owned());
+    Self {
+      names,
+      services,
+      channel: default_btclassic_channel(),
+    }
+  }
+}
+
+impl PartialEq for BluetoothClassicSpecifier {
+  fn eq(&self, other: &Self) -> bool {
+    self.names.intersection(&other.names).count() > 0
+      && self.services.intersection(&other.services).count() > 0
+  }
+}
+
+/// Matches devices found through Lovense Connect apps. Without hosts or
+/// device types, every device matches. Otherwise, hosts are hostname/IP
+/// patterns for the app the device is connected through, and device types
+/// are the toy types the app announces, so different device families can be
+/// routed to different protocols. Patterns can use `*` for any run of
+/// characters, e.g. `192.168.1.*`.
 #[derive(Deserialize, Debug, Clone)]
 pub struct LovenseConnectServiceSpecifier {
-  exists: bool
+  #[serde(default = "default_exists")]
+  exists: bool,
+  #[serde(default)]
+  pub hosts: HashSet<String>,
+  #[serde(default, rename = "device-types")]
+  pub device_types: HashSet<String>,
+}
+
+fn default_exists() -> bool {
+  true
 }
 
 impl Default for LovenseConnectServiceSpecifier {
   fn default() -> Self {
-    Self { exists: true }
+    Self {
+      exists: true,
+      hosts: HashSet::new(),
+      device_types: HashSet::new(),
+    }
+  }
+}
+
+impl LovenseConnectServiceSpecifier {
+  pub fn new_from_device(host: &str, device_type: &str) -> Self {
+    let mut hosts = HashSet::new();
+    hosts.insert(host.to_owned());
+    let mut device_types = HashSet::new();
+    device_types.insert(device_type.to_owned());
+    Self {
+      exists: true,
+      hosts,
+      device_types,
+    }
+  }
+
+  /// True if this only matches some devices, i.e. it has hosts or device
+  /// types. Used to prefer these over catch all definitions.
+  pub fn is_restricted(&self) -> bool {
+    !self.hosts.is_empty() || !self.device_types.is_empty()
+  }
+}
+
+fn wildcard_match(pattern: &str, value: &str) -> bool {
+  let mut parts = pattern.split('*');
+  // split always returns at least one part.
+  let first = parts.next().unwrap();
+  if !value.starts_with(first) {
+    return false;
+  }
+  let mut rest = &value[first.len()..];
+  let mut parts: Vec<&str> = parts.collect();
+  let last = match parts.pop() {
+    Some(last) => last,
+    // No wildcard, so this has to be an exact match.
+    None => return rest.is_empty(),
+  };
+  for part in parts {
+    match rest.find(part) {
+      Some(index) => rest = &rest[index + part.len()..],
+      None => return false,
+    }
+  }
+  rest.len() >= last.len() && rest.ends_with(last)
+}
+
+fn patterns_match(a: &HashSet<String>, b: &HashSet<String>) -> bool {
+  if a.is_empty() || b.is_empty() {
+    return true;
   }
+  a.iter().any(|a_value| {
+    b.iter().any(|b_value| {
+      let (a_value, b_value) = (a_value.to_lowercase(), b_value.to_lowercase());
+      wildcard_match(&a_value, &b_value) || wildcard_match(&b_value, &a_value)
+    })
+  })
 }
 
 impl PartialEq for LovenseConnectServiceSpecifier {
-  fn eq(&self, _other: &Self) -> bool {
-    true
+  fn eq(&self, other: &Self) -> bool {
+    patterns_match(&self.hosts, &other.hosts)
+      && patterns_match(&self.device_types, &other.device_types)
+  }
+}
+
+/// Matches devices exposed by the MQTT comm manager, by the identifier given in
+/// their topic mapping.
+#[derive(Deserialize, Debug, Clone)]
+pub struct MQTTSpecifier {
+  pub names: HashSet<String>,
+}
+
+impl MQTTSpecifier {
+  pub fn new_from_identifier(identifier: &str) -> Self {
+    let mut names = HashSet::new();
+    names.insert(identifier.to_owned());
+    Self { names }
+  }
+}
+
+impl PartialEq for MQTTSpecifier {
+  fn eq(&self, other: &Self) -> bool {
+    self.names.intersection(&other.names).count() > 0
+  }
+}
+
+/// Matches devices reached over an HTTP API, by the name their comm manager
+/// gives them. Endpoints map to the URL path on the device host that
+/// endpoint's reads and writes go to.
+#[derive(Deserialize, Debug, Clone)]
+pub struct HTTPSpecifier {
+  pub names: HashSet<String>,
+  #[serde(default, deserialize_with = "deserialize_declared_endpoints")]
+  pub endpoints: HashMap<Endpoint, String>,
+}
+
+impl HTTPSpecifier {
+  pub fn new_from_name(name: &str) -> Self {
+    let mut names = HashSet::new();
+    names.insert(name.to_owned());
+    Self {
+      names,
+      endpoints: HashMap::new(),
+    }
+  }
+}
+
+impl PartialEq for HTTPSpecifier {
+  fn eq(&self, other: &Self) -> bool {
+    self.names.intersection(&other.names).count() > 0
   }
 }
 
@@ -161,14 +354,26 @@ pub struct USBSpecifier {
   product_id: u16,
 }
 
+impl USBSpecifier {
+  pub fn new(vendor_id: u16, product_id: u16) -> Self {
+    Self {
+      vendor_id,
+      product_id,
+    }
+  }
+}
+
 #[derive(Deserialize, Debug, PartialEq, Clone)]
 pub enum DeviceSpecifier {
   BluetoothLE(BluetoothLESpecifier),
+  BluetoothClassic(BluetoothClassicSpecifier),
   HID(HIDSpecifier),
   USB(USBSpecifier),
   Serial(SerialSpecifier),
   XInput(XInputSpecifier),
   LovenseConnectService(LovenseConnectServiceSpecifier),
+  MQTT(MQTTSpecifier),
+  HTTP(HTTPSpecifier),
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -185,21 +390,109 @@ pub struct ProtocolDefinition {
   // very clumsy, but we really don't do this a bunch during a session.
   pub usb: Option<Vec<USBSpecifier>>,
   pub btle: Option<BluetoothLESpecifier>,
+  pub btclassic: Option<BluetoothClassicSpecifier>,
   pub serial: Option<Vec<SerialSpecifier>>,
   pub hid: Option<Vec<HIDSpecifier>>,
   pub xinput: Option<XInputSpecifier>,
   #[serde(rename = "lovense-connect-service")]
   pub lovense_connect_service: Option<LovenseConnectServiceSpecifier>,
+  pub mqtt: Option<MQTTSpecifier>,
+  pub http: Option<HTTPSpecifier>,
+  /// Devices that can physically hurt someone if misused (fucking machines,
+  /// e-stim units, etc). These are only created if the server was set up to
+  /// allow them.
+  #[serde(rename = "power-device", default)]
+  pub power_device: bool,
+  /// Limits on how long devices of this protocol can run hard before they
+  /// risk overheating. Enforced unless the server was set up to ignore them.
+  #[serde(rename = "duty-cycle")]
+  pub duty_cycle: Option<DutyCycleLimit>,
+  #[serde(default)]
+  pub options: ProtocolOptions,
   pub defaults: Option<ProtocolAttributes>,
   #[serde(default)]
   pub configurations: Vec<ProtocolAttributes>,
 }
 
+/// Duty cycle limits for a protocol. Once a feature has run above `intensity`
+/// for `max_continuous_seconds` without a break, it's capped at `intensity`
+/// for `cooldown_seconds`.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct DutyCycleLimit {
+  /// Speed (0.0-1.0) above which a feature counts as running hard.
+  pub intensity: f64,
+  #[serde(rename = "max-continuous-seconds")]
+  pub max_continuous_seconds: f64,
+  #[serde(rename = "cooldown-seconds")]
+  pub cooldown_seconds: f64,
+}
+
+/// Protocol specific settings, for values a protocol would otherwise have to
+/// hardcode (update intervals, ramp rates, vendor mode flags, etc). Each
+/// protocol decides which keys it reads, and unknown keys are ignored.
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(transparent)]
+pub struct ProtocolOptions(HashMap<String, serde_json::Value>);
+
+impl ProtocolOptions {
+  /// Returns the value of an option, or None if it isn't set. A value that
+  /// can't be read as the type asked for is a configuration error.
+  pub fn get<T>(&self, key: &str) -> Result<Option<T>, ButtplugDeviceError>
+  where
+    T: DeserializeOwned,
+  {
+    self
+      .0
+      .get(key)
+      .map(|value| {
+        serde_json::from_value(value.clone()).map_err(|e| {
+          ButtplugDeviceError::DeviceConfigurationFileError(format!(
+            "Protocol option {} has an invalid value: {}",
+            key, e
+          ))
+        })
+      })
+      .transpose()
+  }
+
+  /// Returns the value of an option, or `default` if it isn't set.
+  pub fn get_or<T>(&self, key: &str, default: T) -> Result<T, ButtplugDeviceError>
+  where
+    T: DeserializeOwned,
+  {
+    Ok(self.get(key)?.unwrap_or(default))
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.0.is_empty()
+  }
+
+  // Options from other, usually user, configuration win over ours.
+  fn merge(&mut self, other: ProtocolOptions) {
+    self.0.extend(other.0);
+  }
+}
+
+/// Message attribute overrides for specific devices of a protocol. Only the
+/// fields given are overridden, everything else is inherited from the main
+/// device configuration.
+#[derive(Deserialize, Debug, Clone)]
+pub struct UserProtocolAttributes {
+  pub identifier: Vec<String>,
+  pub messages: DeviceMessageAttributesMap,
+}
+
 #[derive(Deserialize, Debug, Clone)]
 pub struct UserProtocolDefinition {
-  // Right now, we only allow users to specify serial ports through this
-  // interface. It will contain more additions in the future.
+  // Users can specify serial ports, and override message attributes of
+  // devices we already know about. New protocols still need to be
+  // implemented in source.
   pub serial: Option<Vec<SerialSpecifier>>,
+  /// Replaces the values of the same options in the main configuration.
+  #[serde(default)]
+  pub options: ProtocolOptions,
+  #[serde(default)]
+  pub configurations: Vec<UserProtocolAttributes>,
 }
 
 fn option_some_eq<T>(a: &Option<T>, b: &T) -> bool
@@ -223,9 +516,14 @@ impl PartialEq<DeviceSpecifier> for ProtocolDefinition {
       DeviceSpecifier::USB(other_usb) => option_some_eq_vec(&self.usb, other_usb),
       DeviceSpecifier::Serial(other_serial) => option_some_eq_vec(&self.serial, other_serial),
       DeviceSpecifier::BluetoothLE(other_btle) => option_some_eq(&self.btle, other_btle),
+      DeviceSpecifier::BluetoothClassic(other_btclassic) => {
+        option_some_eq(&self.btclassic, other_btclassic)
+      }
       DeviceSpecifier::HID(other_hid) => option_some_eq_vec(&self.hid, other_hid),
       DeviceSpecifier::XInput(other_xinput) => option_some_eq(&self.xinput, other_xinput),
       DeviceSpecifier::LovenseConnectService(other_lovense_service) => option_some_eq(&self.lovense_connect_service, other_lovense_service),
+      DeviceSpecifier::MQTT(other_mqtt) => option_some_eq(&self.mqtt, other_mqtt),
+      DeviceSpecifier::HTTP(other_http) => option_some_eq(&self.http, other_http),
     }
   }
 }
@@ -239,22 +537,199 @@ pub struct ProtocolConfiguration {
 #[derive(Deserialize, Debug)]
 pub struct UserProtocolConfiguration {
   pub protocols: HashMap<String, UserProtocolDefinition>,
+  /// Addresses of multi-feature devices that should be exposed as one
+  /// logical device per feature.
+  #[serde(rename = "split-devices", default)]
+  pub split_devices: Vec<String>,
+  /// Device indexes reserved for specific devices, keyed by address or
+  /// device ID. Other devices are never given a reserved index.
+  #[serde(rename = "reserved-indexes", default)]
+  pub reserved_indexes: HashMap<String, u32>,
+  /// Bluetooth devices known to be bonded with the OS, by address or device
+  /// ID. Bonding checks are skipped for these.
+  #[serde(rename = "bonded-devices", default)]
+  pub bonded_devices: Vec<String>,
+}
+
+/// Checks reserved index entries for conflicts, and returns them keyed by
+/// normalized entry. Two entries can't reserve the same index, and the same
+/// device can't reserve two indexes (e.g. by writing its address in two
+/// formats).
+fn validate_reserved_indexes(
+  reserved_indexes: &HashMap<String, u32>,
+) -> Result<HashMap<String, u32>, ButtplugDeviceError> {
+  // Sort so errors are reported the same way every time.
+  let mut entries: Vec<(&String, &u32)> = reserved_indexes.iter().collect();
+  entries.sort();
+  let mut validated: HashMap<String, u32> = HashMap::new();
+  let mut index_owners: HashMap<u32, &String> = HashMap::new();
+  for (entry, index) in entries {
+    let normalized = normalize_entry(entry);
+    if let Some(existing_index) = validated.get(&normalized) {
+      if existing_index != index {
+        return Err(ButtplugDeviceError::DeviceConfigurationFileError(format!(
+          "Device {} reserves both index {} and index {}",
+          entry, existing_index, index
+        )));
+      }
+      continue;
+    }
+    if let Some(owner) = index_owners.get(index) {
+      return Err(ButtplugDeviceError::DeviceConfigurationFileError(format!(
+        "Index {} is reserved for both {} and {}",
+        index, owner, entry
+      )));
+    }
+    index_owners.insert(*index, entry);
+    validated.insert(normalized, *index);
+  }
+  Ok(validated)
+}
+
+impl ProtocolDefinition {
+  /// Every set of message attributes the protocol declares, defaults first,
+  /// then one per configuration.
+  pub fn message_attribute_maps(&self) -> impl Iterator<Item = &DeviceMessageAttributesMap> {
+    self
+      .defaults
+      .iter()
+      .chain(self.configurations.iter())
+      .filter_map(|attrs| attrs.messages.as_ref())
+  }
+
+  /// Message attributes for an identifier as the main config file defines
+  /// them, before any raw message or user additions.
+  fn messages_for(&self, identifier: &str) -> Option<DeviceMessageAttributesMap> {
+    let mut messages = self
+      .defaults
+      .as_ref()
+      .and_then(|attrs| attrs.messages.clone())
+      .unwrap_or_default();
+    match self.configurations.iter().find(|attrs| {
+      option_some_eq_vec(&attrs.identifier, &identifier.to_owned())
+    }) {
+      Some(attrs) => messages.extend(attrs.messages.clone().unwrap_or_default()),
+      None if self.defaults.is_none() => return None,
+      None => {}
+    }
+    Some(messages)
+  }
+
+  /// Merges user message attribute overrides for a single identifier into this
+  /// protocol definition.
+  fn merge_user_messages(
+    &mut self,
+    protocol: &str,
+    identifier: &str,
+    overrides: &DeviceMessageAttributesMap,
+  ) -> Result<(), ButtplugDeviceError> {
+    let override_error = |message_type: Option<ButtplugDeviceMessageType>, reason: &str| {
+      ButtplugDeviceError::UserConfigurationOverrideError(
+        protocol.to_owned(),
+        identifier.to_owned(),
+        message_type.map_or_else(|| "*".to_owned(), |t| t.to_string()),
+        reason.to_owned(),
+      )
+    };
+    let base_messages = self
+      .messages_for(identifier)
+      .ok_or_else(|| override_error(None, "Identifier is not known for this protocol"))?;
+
+    let mut merged_messages = DeviceMessageAttributesMap::new();
+    for (message_type, message_overrides) in overrides {
+      let base_attrs = base_messages.get(message_type).ok_or_else(|| {
+        override_error(
+          Some(*message_type),
+          "Protocol does not handle this message for this device",
+        )
+      })?;
+      let merged = base_attrs.merge(message_overrides);
+      let base_feature_count = base_attrs.as_v2().feature_count;
+      if merged.feature_count != base_feature_count {
+        return Err(override_error(
+          Some(*message_type),
+          "Feature count cannot differ from what the protocol supports",
+        ));
+      }
+      let feature_count = merged.feature_count.unwrap_or(0) as usize;
+      if merged
+        .step_count
+        .as_ref()
+        .map_or(false, |steps| steps.len() != feature_count)
+      {
+        return Err(override_error(
+          Some(*message_type),
+          "StepCount needs one entry per feature",
+        ));
+      }
+      if merged
+        .features
+        .as_ref()
+        .map_or(false, |features| features.len() != feature_count)
+      {
+        return Err(override_error(
+          Some(*message_type),
+          "Features needs one entry per feature",
+        ));
+      }
+      merged_messages.insert(*message_type, merged);
+    }
+
+    // Store the merged attributes on the identifier's own configuration,
+    // creating one if the identifier was only covered by the defaults.
+    if let Some(attrs) = self.configurations.iter_mut().find(|attrs| {
+      option_some_eq_vec(&attrs.identifier, &identifier.to_owned())
+    }) {
+      attrs
+        .messages
+        .get_or_insert_with(DeviceMessageAttributesMap::new)
+        .extend(merged_messages);
+    } else {
+      // messages_for() only returns Some without a matching configuration if
+      // we have defaults, so this unwrap is safe.
+      let defaults = self.defaults.as_ref().unwrap();
+      self.configurations.push(ProtocolAttributes {
+        identifier: Some(vec![identifier.to_owned()]),
+        name: defaults.name.clone(),
+        messages: Some(merged_messages),
+      });
+    }
+    Ok(())
+  }
 }
 
 impl ProtocolConfiguration {
-  pub fn merge_user_config(&mut self, other: UserProtocolConfiguration) {
-    // For now, we're only merging serial info in.
+  pub fn merge_user_config(
+    &mut self,
+    other: UserProtocolConfiguration,
+  ) -> Result<(), ButtplugDeviceError> {
     for (protocol, conf) in other.protocols {
-      if self.protocols.contains_key(&protocol) {
-        let our_serial_conf_option = &mut self.protocols.get_mut(&protocol).unwrap().serial;
-        let mut other_serial_conf = conf.serial;
-        if let Some(ref mut our_serial_config) = our_serial_conf_option {
-          our_serial_config.extend(other_serial_conf.unwrap());
-        } else {
-          mem::swap(our_serial_conf_option, &mut other_serial_conf);
+      let our_protocol = match self.protocols.get_mut(&protocol) {
+        Some(our_protocol) => our_protocol,
+        None if conf.configurations.is_empty() && conf.options.is_empty() => continue,
+        None => {
+          return Err(ButtplugDeviceError::UserConfigurationOverrideError(
+            protocol,
+            "*".to_owned(),
+            "*".to_owned(),
+            "Protocol does not exist".to_owned(),
+          ))
+        }
+      };
+      let mut other_serial_conf = conf.serial;
+      if let Some(ref mut our_serial_config) = our_protocol.serial {
+        our_serial_config.extend(other_serial_conf.unwrap_or_default());
+      } else {
+        mem::swap(&mut our_protocol.serial, &mut other_serial_conf);
+      }
+      our_protocol.options.merge(conf.options);
+      for user_attrs in &conf.configurations {
+        for identifier in &user_attrs.identifier {
+          our_protocol.merge_user_messages(&protocol, identifier, &user_attrs.messages)?;
         }
       }
     }
+    Ok(())
   }
 }
 
@@ -263,6 +738,7 @@ pub struct DeviceProtocolConfiguration {
   allow_raw_messages: bool,
   defaults: Option<ProtocolAttributes>,
   configurations: Vec<ProtocolAttributes>,
+  options: ProtocolOptions,
 }
 
 impl DeviceProtocolConfiguration {
@@ -270,14 +746,20 @@ impl DeviceProtocolConfiguration {
     allow_raw_messages: bool,
     defaults: Option<ProtocolAttributes>,
     configurations: Vec<ProtocolAttributes>,
+    options: ProtocolOptions,
   ) -> Self {
     Self {
       allow_raw_messages,
       defaults,
       configurations,
+      options,
     }
   }
 
+  pub fn options(&self) -> &ProtocolOptions {
+    &self.options
+  }
+
   pub fn get_attributes(
     &self,
     identifier: &str,
@@ -347,33 +829,65 @@ impl DeviceProtocolConfiguration {
       .entry(ButtplugDeviceMessageType::StopDeviceCmd)
       .or_insert_with(DeviceMessageAttributes::default);
 
+    // Config files describe features individually, but command managers,
+    // device groups/splits and older spec clients still work off of
+    // FeatureCount/StepCount, so fill those in here.
+    for message_attributes in attributes.values_mut() {
+      message_attributes.fill_flat_attributes();
+    }
+
     // The device config JSON schema requires us to have a name map, so we can unwrap this.
-    Ok((device_attrs.name.as_ref().unwrap().clone(), attributes))
+    Ok((
+      device_attrs.name.as_ref().unwrap().clone(),
+      attributes.interned(),
+    ))
   }
 }
 
+/// Settings for loading a [DeviceConfigurationManager].
+#[derive(Debug, Clone, Default)]
+pub struct DeviceConfigurationOptions {
+  /// Adds raw message support to every device.
+  pub allow_raw_messages: bool,
+  /// Allows protocols marked as power devices (fucking machines, e-stim,
+  /// etc) to be matched. These can injure people if misused, so they're off
+  /// unless explicitly requested.
+  pub allow_power_devices: bool,
+  /// Device configuration JSON to use instead of the one built into the
+  /// library.
+  pub device_configuration_json: Option<String>,
+  /// User device configuration JSON, merged over the device configuration.
+  pub user_device_configuration_json: Option<String>,
+}
+
 pub struct DeviceConfigurationManager {
   allow_raw_messages: bool,
+  allow_power_devices: bool,
+  ignore_duty_cycle_limits: bool,
   pub(self) config: ProtocolConfiguration,
-  protocol_map: Arc<DashMap<String, TryCreateProtocolFunc>>
+  protocol_map: Arc<DashMap<String, ProtocolFactory>>,
+  split_devices: HashSet<String>,
+  bonded_devices: Arc<DashSet<String>>,
+  /// Reserved device indexes, keyed by normalized address or device ID.
+  reserved_indexes: HashMap<String, u32>,
+  /// Protocols the user configuration has settings for.
+  user_config_protocols: Vec<String>,
 }
 
 impl Default for DeviceConfigurationManager {
   fn default() -> Self {
     // Unwrap allowed here because we assume our built in device config will
     // always work. System won't pass tests or possibly even build otherwise.
-    Self::new_with_options(false, &None, &None).unwrap()
+    Self::new_with_options(&DeviceConfigurationOptions::default()).unwrap()
   }
 }
 
 impl DeviceConfigurationManager {
   pub fn new_with_options(
-    allow_raw_messages: bool,
-    external_config: &Option<String>,
-    user_config: &Option<String>,
+    options: &DeviceConfigurationOptions,
   ) -> Result<Self, ButtplugDeviceError> {
     // TODO Handling references incorrectly here.
-    let config_str = if let Some(cfg) = external_config {
+    let config_str = if let Some(cfg) = &options.device_configuration_json {
       cfg
     } else {
       DEVICE_CONFIGURATION_JSON
@@ -402,11 +916,24 @@ impl DeviceConfigurationManager {
       config.version
     );
 
-    if let Some(user_config_str) = user_config {
+    let mut split_devices = HashSet::new();
+    let bonded_devices = Arc::new(DashSet::new());
+    let mut reserved_indexes = HashMap::new();
+    let mut user_config_protocols = vec![];
+    if let Some(user_config_str) = &options.user_device_configuration_json {
       let user_validator = JSONValidator::new(USER_DEVICE_CONFIGURATION_JSON_SCHEMA);
       match user_validator.validate(&user_config_str) {
-        Ok(_) => match serde_json::from_str(&user_config_str) {
-          Ok(user_cfg) => config.merge_user_config(user_cfg),
+        Ok(_) => match serde_json::from_str::<UserProtocolConfiguration>(&user_config_str) {
+          Ok(user_cfg) => {
+            split_devices.extend(user_cfg.split_devices.iter().cloned());
+            for bonded_device in &user_cfg.bonded_devices {
+              bonded_devices.insert(bonded_device.clone());
+            }
+            reserved_indexes = validate_reserved_indexes(&user_cfg.reserved_indexes)?;
+            user_config_protocols.extend(user_cfg.protocols.keys().cloned());
+            user_config_protocols.sort();
+            config.merge_user_config(user_cfg)?
+          }
           Err(err) => {
             return Err(ButtplugDeviceError::DeviceConfigurationFileError(format!(
               "{}",
@@ -424,12 +951,24 @@ impl DeviceConfigurationManager {
     }
 
     Ok(DeviceConfigurationManager {
-      allow_raw_messages,
+      allow_raw_messages: options.allow_raw_messages,
+      allow_power_devices: options.allow_power_devices,
+      ignore_duty_cycle_limits: false,
       config,
-      protocol_map: Arc::new(get_default_protocol_map())
+      protocol_map: Arc::new(get_default_protocol_map()),
+      split_devices,
+      bonded_devices,
+      reserved_indexes,
+      user_config_protocols,
     })
   }
 
+  /// Drops the duty cycle limits from every protocol, for users who would
+  /// rather run their devices hard and accept the risk of overheating them.
+  pub fn set_ignore_duty_cycle_limits(&mut self, ignore: bool) {
+    self.ignore_duty_cycle_limits = ignore;
+  }
+
   pub fn add_protocol<T>(&self, protocol_name: &str) where T: ButtplugProtocol {
     add_to_protocol_map::<T>(&self.protocol_map, protocol_name);
   }
@@ -446,8 +985,77 @@ impl DeviceConfigurationManager {
     self.protocol_map.contains_key(protocol_name)
   }
 
+  pub fn allow_raw_messages(&self) -> bool {
+    self.allow_raw_messages
+  }
+
+  /// Protocols the user configuration has settings for that won't be used,
+  /// because the device configuration doesn't define them or no
+  /// implementation of them has been added.
+  pub fn unused_user_config_protocols(&self) -> Vec<String> {
+    self
+      .user_config_protocols
+      .iter()
+      .filter(|protocol| {
+        !self.config.protocols.contains_key(*protocol) || !self.has_protocol(protocol)
+      })
+      .cloned()
+      .collect()
+  }
+
+  /// True if the user configuration asked for this device to be split into
+  /// one logical device per feature.
+  pub fn is_split_device(&self, identity: &DeviceIdentity) -> bool {
+    self
+      .split_devices
+      .iter()
+      .any(|entry| identity.matches(entry))
+  }
+
+  /// Bluetooth devices known to be bonded, sorted: the user configuration's
+  /// `bonded-devices`, plus devices that bonded since. Applications can save
+  /// these back to the user configuration, so later sessions skip the
+  /// bonding check.
+  pub fn bonded_devices(&self) -> Vec<String> {
+    let mut bonded_devices: Vec<String> =
+      self.bonded_devices.iter().map(|entry| entry.key().clone()).collect();
+    bonded_devices.sort();
+    bonded_devices
+  }
+
+  /// Index the user configuration reserved for this device, if any. If a
+  /// device matches more than one entry (its address and its device ID), the
+  /// lowest index wins.
+  pub fn reserved_index(&self, identity: &DeviceIdentity) -> Option<u32> {
+    self
+      .reserved_indexes
+      .iter()
+      .filter(|(entry, _)| identity.matches(entry))
+      .map(|(_, index)| *index)
+      .min()
+  }
+
+  /// True if the index is reserved for some device, and shouldn't be handed
+  /// out to anything else.
+  pub fn is_reserved_index(&self, index: u32) -> bool {
+    self.reserved_indexes.values().any(|reserved| *reserved == index)
+  }
+
   pub fn get_protocol_creator(&self, protocol_name: &str) -> TryCreateProtocolFunc {
-    self.protocol_map.get(protocol_name).unwrap().clone()
+    self.protocol_map.get(protocol_name).unwrap().try_create
+  }
+
+  /// Device messages the named protocol's implementation handles itself, see
+  /// [ButtplugProtocol::handled_message_types]. None if no implementation of
+  /// it has been added.
+  pub fn handled_message_types(
+    &self,
+    protocol_name: &str,
+  ) -> Option<&'static [ButtplugDeviceMessageType]> {
+    self
+      .protocol_map
+      .get(protocol_name)
+      .map(|factory| (factory.handled_message_types)())
   }
 
   /// Provides read-only access to the internal protocol/identifier map. Mainly
@@ -465,11 +1073,40 @@ impl DeviceConfigurationManager {
       "Looking for protocol that matches specifier: {:?}",
       specifier
     );
-    for (name, def) in self.config.protocols.iter() {
-      if def == specifier {
-        info!("Found protocol {:?} for specifier {:?}.", name, specifier);
-        return Some((self.allow_raw_messages, name.clone(), def.clone()));
+    // Lovense Connect definitions restricted to some hosts or device types
+    // need to win over the catch all one, whatever order the map is in.
+    let mut matches: Vec<(&String, &ProtocolDefinition)> = self
+      .config
+      .protocols
+      .iter()
+      .filter(|(_, def)| *def == specifier)
+      .collect();
+    matches.sort_by_key(|(_, def)| {
+      !def
+        .lovense_connect_service
+        .as_ref()
+        .map_or(false, |spec| spec.is_restricted())
+    });
+    for (name, def) in matches {
+      if def.power_device && !self.allow_power_devices {
+        warn!(
+          "Protocol {:?} matches specifier {:?}, but it is a power device and power devices are not allowed by the server options. Skipping.",
+          name, specifier
+        );
+        continue;
+      }
+      info!("Found protocol {:?} for specifier {:?}.", name, specifier);
+      let mut def = def.clone();
+      if let Some(btle) = &mut def.btle {
+        btle.bonded_devices = self.bonded_devices.clone();
       }
+      if self.ignore_duty_cycle_limits && def.duty_cycle.take().is_some() {
+        warn!(
+          "Ignoring duty cycle limits for protocol {:?} as requested by the server options.",
+          name
+        );
+      }
+      return Some((self.allow_raw_messages, name.clone(), def));
     }
     debug!("No protocol found for specifier {:?}.", specifier);
     None
@@ -485,6 +1122,7 @@ impl DeviceConfigurationManager {
         self.allow_raw_messages,
         proto.defaults.clone(),
         proto.configurations.clone(),
+        proto.options.clone(),
       ))
     } else {
       debug!("No matching protocol definition found.");
@@ -496,9 +1134,16 @@ impl DeviceConfigurationManager {
 #[cfg(test)]
 mod test {
   use super::{
-    BluetoothLESpecifier, DeviceConfigurationManager, DeviceProtocolConfiguration, DeviceSpecifier,
+    BluetoothClassicSpecifier, BluetoothLESpecifier, DeviceConfigurationManager,
+    DeviceConfigurationOptions, DeviceProtocolConfiguration, DeviceSpecifier,
+    LovenseConnectServiceSpecifier, ProtocolDefinition, SerialSpecifier,
+  };
+  use crate::{
+    core::{errors::ButtplugDeviceError, messages::ButtplugDeviceMessageType},
+    device::{identity::DeviceIdentity, CustomEndpoint, Endpoint, WriteType},
   };
-  use crate::core::messages::ButtplugDeviceMessageType;
+  use std::collections::HashSet;
+  use uuid::Uuid;
 
   #[test]
   fn test_load_config() {
@@ -527,8 +1172,12 @@ mod test {
     let lovense =
       DeviceSpecifier::BluetoothLE(BluetoothLESpecifier::new_from_device("LVS-Whatever"));
     let proto = config.find_configuration(&lovense).unwrap();
-    let proto_config =
-      DeviceProtocolConfiguration::new(false, proto.2.defaults.clone(), proto.2.configurations);
+    let proto_config = DeviceProtocolConfiguration::new(
+      false,
+      proto.2.defaults.clone(),
+      proto.2.configurations,
+      proto.2.options,
+    );
     let (name_map, message_map) = proto_config.get_attributes("P", &vec![]).unwrap();
     // Make sure we got the right name
     assert_eq!(name_map.get("en-us").unwrap(), "Lovense Edge");
@@ -545,12 +1194,20 @@ mod test {
 
   #[test]
   fn test_raw_device_config_creation() {
-    let config = DeviceConfigurationManager::new_with_options(true, &None, &None).unwrap();
+    let config = DeviceConfigurationManager::new_with_options(&DeviceConfigurationOptions {
+      allow_raw_messages: true,
+      ..Default::default()
+    })
+    .unwrap();
     let lovense =
       DeviceSpecifier::BluetoothLE(BluetoothLESpecifier::new_from_device("LVS-Whatever"));
     let proto = config.find_configuration(&lovense).unwrap();
-    let proto_config =
-      DeviceProtocolConfiguration::new(true, proto.2.defaults.clone(), proto.2.configurations);
+    let proto_config = DeviceProtocolConfiguration::new(
+      true,
+      proto.2.defaults.clone(),
+      proto.2.configurations,
+      proto.2.options,
+    );
     let (name_map, message_map) = proto_config.get_attributes("P", &vec![]).unwrap();
     // Make sure we got the right name
     assert_eq!(name_map.get("en-us").unwrap(), "Lovense Edge");
@@ -567,8 +1224,12 @@ mod test {
     let lovense =
       DeviceSpecifier::BluetoothLE(BluetoothLESpecifier::new_from_device("LVS-Whatever"));
     let proto = config.find_configuration(&lovense).unwrap();
-    let proto_config =
-      DeviceProtocolConfiguration::new(false, proto.2.defaults.clone(), proto.2.configurations);
+    let proto_config = DeviceProtocolConfiguration::new(
+      false,
+      proto.2.defaults.clone(),
+      proto.2.configurations,
+      proto.2.options,
+    );
     let (name_map, message_map) = proto_config.get_attributes("P", &vec![]).unwrap();
     // Make sure we got the right name
     assert_eq!(name_map.get("en-us").unwrap(), "Lovense Edge");
@@ -579,6 +1240,22 @@ mod test {
     assert!(!message_map.contains_key(&ButtplugDeviceMessageType::RawUnsubscribeCmd));
   }
 
+  #[test]
+  fn test_feature_descriptor_config() {
+    let config = DeviceConfigurationManager::default();
+    let proto_config = config.get_protocol_config("libo-elle").unwrap();
+    let (_, message_map) = proto_config.get_attributes("PiPiJing", &vec![]).unwrap();
+    let vibrate_attrs = message_map
+      .get(&ButtplugDeviceMessageType::VibrateCmd)
+      .unwrap();
+    let features = vibrate_attrs.features.as_ref().unwrap();
+    assert_eq!(features[0].descriptor, "Estim");
+    assert_eq!(features[1].descriptor, "Vibe");
+    // Flat attributes should be filled in from the descriptors.
+    assert_eq!(vibrate_attrs.feature_count, Some(2));
+    assert_eq!(vibrate_attrs.step_count, Some(vec![14, 3]));
+  }
+
   #[test]
   fn test_user_config_loading() {
     let mut config = DeviceConfigurationManager::default();
@@ -603,10 +1280,8 @@ mod test {
         .len(),
       1
     );
-    config = DeviceConfigurationManager::new_with_options(
-      false,
-      &None,
-      &Some(
+    config = DeviceConfigurationManager::new_with_options(&DeviceConfigurationOptions {
+      user_device_configuration_json: Some(
         r#"
         { 
             "protocols": {
@@ -626,7 +1301,8 @@ mod test {
         "#
         .to_string(),
       ),
-    )
+      ..Default::default()
+    })
     .unwrap();
     assert!(config.config.protocols.contains_key("nobra"));
     assert!(config
@@ -661,6 +1337,374 @@ mod test {
       .any(|x| x.port == "COM1"));
   }
 
+  fn user_config_with_overrides(
+    overrides: &str,
+  ) -> Result<DeviceConfigurationManager, ButtplugDeviceError> {
+    DeviceConfigurationManager::new_with_options(&DeviceConfigurationOptions {
+      user_device_configuration_json: Some(format!(
+        r#"
+        {{
+            "protocols": {{
+                "lovense": {{
+                    "configurations": [
+                        {{
+                            "identifier": ["P"],
+                            "messages": {}
+                        }}
+                    ]
+                }}
+            }}
+        }}
+        "#,
+        overrides
+      )),
+      ..Default::default()
+    })
+  }
+
+  #[test]
+  fn test_user_config_message_override() {
+    let config =
+      user_config_with_overrides(r#"{ "VibrateCmd": { "StepCount": [10, 10] } }"#).unwrap();
+    let proto_config = config.get_protocol_config("lovense").unwrap();
+    let (name_map, message_map) = proto_config.get_attributes("P", &vec![]).unwrap();
+    assert_eq!(name_map.get("en-us").unwrap(), "Lovense Edge");
+    let vibrate_attrs = message_map
+      .get(&ButtplugDeviceMessageType::VibrateCmd)
+      .unwrap();
+    // StepCount is overridden, everything else is inherited.
+    assert_eq!(vibrate_attrs.step_count, Some(vec![10, 10]));
+    assert_eq!(vibrate_attrs.feature_count, Some(2));
+    for feature in vibrate_attrs.features.as_ref().unwrap() {
+      assert_eq!(feature.step_range, Some((0, 10)));
+    }
+    assert!(message_map.contains_key(&ButtplugDeviceMessageType::BatteryLevelCmd));
+  }
+
+  #[test]
+  fn test_user_config_invalid_message_override() {
+    let unsupported_message =
+      user_config_with_overrides(r#"{ "RotateCmd": { "StepCount": [10] } }"#);
+    assert!(matches!(
+      unsupported_message,
+      Err(ButtplugDeviceError::UserConfigurationOverrideError(protocol, identifier, message, _))
+        if protocol == "lovense" && identifier == "P" && message == "RotateCmd"
+    ));
+    let wrong_step_count =
+      user_config_with_overrides(r#"{ "VibrateCmd": { "StepCount": [10, 10, 10] } }"#);
+    assert!(matches!(
+      wrong_step_count,
+      Err(ButtplugDeviceError::UserConfigurationOverrideError(_, _, message, _))
+        if message == "VibrateCmd"
+    ));
+    let added_features = user_config_with_overrides(r#"{ "VibrateCmd": { "FeatureCount": 3 } }"#);
+    assert!(added_features.is_err());
+  }
+
+  #[test]
+  fn test_user_config_split_devices() {
+    let config = DeviceConfigurationManager::new_with_options(&DeviceConfigurationOptions {
+      user_device_configuration_json: Some(
+        r#"
+        {
+            "protocols": {},
+            "split-devices": ["AA:BB:CC:DD:EE:FF"]
+        }
+        "#
+        .to_string(),
+      ),
+      ..Default::default()
+    })
+    .unwrap();
+    assert!(config.is_split_device(&DeviceIdentity::new("AA:BB:CC:DD:EE:FF", None)));
+    assert!(config.is_split_device(&DeviceIdentity::new("aa-bb-cc-dd-ee-ff", None)));
+    // Same device seen through a platform that only gives us a UUID, but with
+    // the MAC reported by the protocol.
+    assert!(config.is_split_device(&DeviceIdentity::new(
+      "5a8e3f0c-2b1d-4c6e-9f7a-0123456789ab",
+      Some("AABBCCDDEEFF")
+    )));
+    assert!(!config.is_split_device(&DeviceIdentity::new("FF:EE:DD:CC:BB:AA", None)));
+  }
+
+  #[test]
+  fn test_user_config_bonded_devices() {
+    let config = DeviceConfigurationManager::new_with_options(&DeviceConfigurationOptions {
+      user_device_configuration_json: Some(
+        r#"
+        {
+            "protocols": {},
+            "bonded-devices": ["AA:BB:CC:DD:EE:FF"]
+        }
+        "#
+        .to_string(),
+      ),
+      ..Default::default()
+    })
+    .unwrap();
+    let (_, _, def) = config
+      .find_configuration(&DeviceSpecifier::BluetoothLE(
+        BluetoothLESpecifier::new_from_device("LVS-Z001"),
+      ))
+      .unwrap();
+    let btle = def.btle.unwrap();
+    assert!(!btle.requires_bonding);
+    assert!(btle.bonded_devices.contains("AA:BB:CC:DD:EE:FF"));
+    let (_, _, def) = config
+      .find_configuration(&DeviceSpecifier::BluetoothLE(
+        BluetoothLESpecifier::new_from_device("Aogu SCB"),
+      ))
+      .unwrap();
+    let btle = def.btle.unwrap();
+    assert!(btle.requires_bonding);
+    // Devices that bond are recorded with the configuration manager.
+    btle.bonded_devices.insert("11:22:33:44:55:66".to_owned());
+    assert_eq!(
+      config.bonded_devices(),
+      vec![
+        "11:22:33:44:55:66".to_owned(),
+        "AA:BB:CC:DD:EE:FF".to_owned()
+      ]
+    );
+  }
+
+  #[test]
+  fn test_unused_user_config_protocols() {
+    let config = DeviceConfigurationManager::new_with_options(&DeviceConfigurationOptions {
+      user_device_configuration_json: Some(
+        r#"
+        {
+            "protocols": {
+                "nobra": {
+                    "serial": [
+                        {
+                            "port": "COM1",
+                            "baud-rate": 19200,
+                            "data-bits": 8,
+                            "parity": "N",
+                            "stop-bits": 1
+                        }
+                    ]
+                },
+                "not-a-protocol": {
+                    "serial": [
+                        {
+                            "port": "COM2",
+                            "baud-rate": 19200,
+                            "data-bits": 8,
+                            "parity": "N",
+                            "stop-bits": 1
+                        }
+                    ]
+                }
+            }
+        }
+        "#
+        .to_string(),
+      ),
+      ..Default::default()
+    })
+    .unwrap();
+    assert_eq!(
+      config.unused_user_config_protocols(),
+      vec!["not-a-protocol"]
+    );
+    // Protocols without an implementation are just as unused.
+    config.remove_protocol("nobra");
+    assert_eq!(
+      config.unused_user_config_protocols(),
+      vec!["nobra", "not-a-protocol"]
+    );
+  }
+
+  #[test]
+  fn test_user_config_reserved_indexes() {
+    let load = |reserved_indexes: &str| {
+      DeviceConfigurationManager::new_with_options(&DeviceConfigurationOptions {
+        user_device_configuration_json: Some(format!(
+          r#"{{ "protocols": {{}}, "reserved-indexes": {} }}"#,
+          reserved_indexes
+        )),
+        ..Default::default()
+      })
+    };
+    let config = load(r#"{ "AA:BB:CC:DD:EE:FF": 3, "id:0082059ad3bd": 5 }"#).unwrap();
+    assert_eq!(
+      config.reserved_index(&DeviceIdentity::new("aa-bb-cc-dd-ee-ff", None)),
+      Some(3)
+    );
+    assert_eq!(
+      config.reserved_index(&DeviceIdentity::new(
+        "serial-port",
+        Some("00:82:05:9A:D3:BD")
+      )),
+      Some(5)
+    );
+    assert!(config.is_reserved_index(3));
+    assert!(!config.is_reserved_index(4));
+    // Same index for two devices
+    assert!(load(r#"{ "AA:BB:CC:DD:EE:FF": 3, "FF:EE:DD:CC:BB:AA": 3 }"#).is_err());
+    // Same device written two ways, with different indexes
+    assert!(load(r#"{ "AA:BB:CC:DD:EE:FF": 3, "aa-bb-cc-dd-ee-ff": 4 }"#).is_err());
+    // Same device written two ways, with the same index, is fine.
+    assert!(load(r#"{ "AA:BB:CC:DD:EE:FF": 3, "aa-bb-cc-dd-ee-ff": 3 }"#).is_ok());
+  }
+
+  #[test]
+  fn test_power_device_gate() {
+    let fmachine = DeviceSpecifier::Serial(SerialSpecifier::new_from_name("default-fmachine"));
+    let config = DeviceConfigurationManager::default();
+    assert!(config.find_configuration(&fmachine).is_none());
+    let config = DeviceConfigurationManager::new_with_options(&DeviceConfigurationOptions {
+      allow_power_devices: true,
+      ..Default::default()
+    })
+    .unwrap();
+    assert_eq!(config.find_configuration(&fmachine).unwrap().1, "fmachine");
+  }
+
+  #[test]
+  fn test_protocol_options() {
+    let protocol: ProtocolDefinition = serde_json::from_str(
+      r#"{"options": {"keepalive-ms": 500, "ramp": 0.25, "mode": "burst", "enabled": true}}"#,
+    )
+    .unwrap();
+    let options = &protocol.options;
+    assert_eq!(options.get::<u64>("keepalive-ms").unwrap(), Some(500));
+    assert_eq!(options.get::<f64>("ramp").unwrap(), Some(0.25));
+    assert_eq!(
+      options.get::<String>("mode").unwrap(),
+      Some("burst".to_owned())
+    );
+    assert!(options.get_or("enabled", false).unwrap());
+    assert_eq!(options.get_or("missing", 10u32).unwrap(), 10);
+    assert!(matches!(
+      options.get::<u64>("mode"),
+      Err(ButtplugDeviceError::DeviceConfigurationFileError(_))
+    ));
+    let protocol: ProtocolDefinition = serde_json::from_str("{}").unwrap();
+    assert!(protocol.options.is_empty());
+  }
+
+  #[test]
+  fn test_btle_write_types() {
+    let protocol: ProtocolDefinition = serde_json::from_str(
+      r#"{"btle": {"names": ["Toy"], "services": {}, "write-types": {"tx": "without-response", "txmode": "with-response"}}}"#,
+    )
+    .unwrap();
+    let write_types = protocol.btle.unwrap().write_types;
+    assert_eq!(write_types[&Endpoint::Tx], WriteType::WithoutResponse);
+    assert_eq!(write_types[&Endpoint::TxMode], WriteType::WithResponse);
+    assert!(!write_types.contains_key(&Endpoint::Rx));
+  }
+
+  #[test]
+  fn test_btle_custom_endpoints() {
+    let protocol: ProtocolDefinition = serde_json::from_str(
+      r#"{"btle": {"names": ["DIY Toy"], "services": {"0000fff0-0000-1000-8000-00805f9b34fb": {"tx": "0000fff1-0000-1000-8000-00805f9b34fb", "custom:heater": "0000fff2-0000-1000-8000-00805f9b34fb"}}}}"#,
+    )
+    .unwrap();
+    let heater = Endpoint::Custom(CustomEndpoint::new("heater").unwrap());
+    let services = protocol.btle.unwrap().services;
+    let characteristics = services.values().next().unwrap();
+    assert!(characteristics.contains_key(&Endpoint::Tx));
+    assert!(characteristics.contains_key(&heater));
+    // Unprefixed names that aren't built in are still rejected.
+    assert!(serde_json::from_str::<ProtocolDefinition>(
+      r#"{"btle": {"names": ["DIY Toy"], "services": {"0000fff0-0000-1000-8000-00805f9b34fb": {"heater": "0000fff2-0000-1000-8000-00805f9b34fb"}}}}"#,
+    )
+    .is_err());
+  }
+
+  #[test]
+  fn test_lovense_connect_service_matching() {
+    let mut config = DeviceConfigurationManager::default();
+    let nora = DeviceSpecifier::LovenseConnectService(
+      LovenseConnectServiceSpecifier::new_from_device("192-168-1-5.lovense.club", "nora"),
+    );
+    assert_eq!(
+      config.find_configuration(&nora).unwrap().1,
+      "lovense-connect-service"
+    );
+    let mut diy = config.config.protocols["lovense-connect-service"].clone();
+    diy.lovense_connect_service = Some(
+      serde_json::from_str(r#"{"hosts": ["192-168-1-*.lovense.club"], "device-types": ["diy-*"]}"#)
+        .unwrap(),
+    );
+    config
+      .config
+      .protocols
+      .insert("lovense-diy".to_owned(), diy);
+    // Devices the restricted definition doesn't match still fall through to
+    // the catch all one.
+    assert_eq!(
+      config.find_configuration(&nora).unwrap().1,
+      "lovense-connect-service"
+    );
+    let diy_device = DeviceSpecifier::LovenseConnectService(
+      LovenseConnectServiceSpecifier::new_from_device("192-168-1-5.lovense.club", "diy-stroker"),
+    );
+    assert_eq!(
+      config.find_configuration(&diy_device).unwrap().1,
+      "lovense-diy"
+    );
+    let other_host = DeviceSpecifier::LovenseConnectService(
+      LovenseConnectServiceSpecifier::new_from_device("10-0-0-2.lovense.club", "diy-stroker"),
+    );
+    assert_eq!(
+      config.find_configuration(&other_host).unwrap().1,
+      "lovense-connect-service"
+    );
+  }
+
+  #[test]
+  fn test_user_config_options_override() {
+    let config = DeviceConfigurationManager::new_with_options(&DeviceConfigurationOptions {
+      user_device_configuration_json: Some(
+        r#"
+        {
+            "protocols": {
+                "mysteryvibe": {
+                    "options": {
+                        "command-delay-ms": 50
+                    }
+                }
+            }
+        }
+        "#
+        .to_owned(),
+      ),
+      ..Default::default()
+    })
+    .unwrap();
+    let proto_config = config.get_protocol_config("mysteryvibe").unwrap();
+    assert_eq!(
+      proto_config
+        .options()
+        .get::<u64>("command-delay-ms")
+        .unwrap(),
+      Some(50)
+    );
+  }
+
+  #[test]
+  fn test_btclassic_service_matching() {
+    let protocol: ProtocolDefinition =
+      serde_json::from_str(r#"{"btclassic": {"names": ["Legacy Toy"]}}"#).unwrap();
+    assert_eq!(protocol.btclassic.as_ref().unwrap().channel, 1);
+    let spp = Uuid::parse_str("00001101-0000-1000-8000-00805f9b34fb").unwrap();
+    let other = Uuid::parse_str("0000110b-0000-1000-8000-00805f9b34fb").unwrap();
+    let specifier = |name: &str, services: Vec<Uuid>| {
+      DeviceSpecifier::BluetoothClassic(BluetoothClassicSpecifier::new_from_device(
+        name,
+        services.into_iter().collect::<HashSet<Uuid>>(),
+      ))
+    };
+    assert!(protocol == specifier("Legacy Toy", vec![other, spp]));
+    assert!(protocol != specifier("Legacy Toy", vec![other]));
+    assert!(protocol != specifier("Other Toy", vec![spp]));
+  }
+
   // TODO Test invalid config load (not json)
   // TODO Test invalid user config load (not json)
   // TODO Test device config with repeated ble service
@@ -0,0 +1,190 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2022 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Endpoints outside of the built in set.
+//!
+//! The built in [Endpoint][super::Endpoint] variants cover the hardware we
+//! ship protocols for, but DIY and websocket devices often want names of
+//! their own ("motor2", "heater", ...). Those are written as
+//! `custom:<name>` anywhere an endpoint name goes (device configuration
+//! files, raw messages), so a typo in a built in name is still an error
+//! instead of a new endpoint.
+//!
+//! Names are interned, so custom endpoints are as cheap to copy, hash and
+//! compare as the built in ones, and [Endpoint][super::Endpoint] stays
+//! [Copy]. Only loading device configuration adds names. Everywhere else
+//! (raw messages, [Endpoint::from_str][std::str::FromStr::from_str]) looks
+//! names up, so a client can't grow the set by sending made up endpoints.
+
+use super::Endpoint;
+use crate::core::errors::ButtplugDeviceError;
+use once_cell::sync::Lazy;
+use serde::{de, Deserialize, Deserializer};
+use std::{
+  collections::{HashMap, HashSet},
+  fmt,
+  sync::Mutex,
+};
+use uuid::Uuid;
+
+/// Prefix marking an endpoint name as custom.
+pub const CUSTOM_ENDPOINT_PREFIX: &str = "custom:";
+const MAX_CUSTOM_ENDPOINT_NAME_LENGTH: usize = 64;
+
+// Only ever grows, but only device configuration adds names, so there are
+// never many.
+static CUSTOM_ENDPOINT_NAMES: Lazy<Mutex<HashSet<&'static str>>> =
+  Lazy::new(|| Mutex::new(HashSet::new()));
+
+/// Name of a device specific endpoint. Lowercase ASCII letters, digits, `-`
+/// and `_` only, like the built in endpoint names.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct CustomEndpoint(&'static str);
+
+impl CustomEndpoint {
+  /// Looks up a custom endpoint that device configuration has declared.
+  /// `name` shouldn't include the `custom:` prefix.
+  pub fn new(name: &str) -> Result<Self, ButtplugDeviceError> {
+    let names = CUSTOM_ENDPOINT_NAMES.lock().unwrap();
+    names
+      .get(name)
+      .map(|interned| Self(*interned))
+      .ok_or_else(|| {
+        ButtplugDeviceError::InvalidEndpointName(
+          name.to_owned(),
+          "No device configuration declares this endpoint".to_owned(),
+        )
+      })
+  }
+
+  /// Declares a custom endpoint, adding its name if it's new. Only used
+  /// while loading device configuration.
+  pub(crate) fn declare(name: &str) -> Result<Self, ButtplugDeviceError> {
+    let invalid =
+      |reason: &str| ButtplugDeviceError::InvalidEndpointName(name.to_owned(), reason.to_owned());
+    if name.is_empty() {
+      return Err(invalid("Name is empty"));
+    }
+    if name.len() > MAX_CUSTOM_ENDPOINT_NAME_LENGTH {
+      return Err(invalid("Name is too long"));
+    }
+    if !name
+      .chars()
+      .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_')
+    {
+      return Err(invalid(
+        "Names can only contain lowercase letters, digits, '-' and '_'",
+      ));
+    }
+    let mut names = CUSTOM_ENDPOINT_NAMES.lock().unwrap();
+    if let Some(interned) = names.get(name) {
+      return Ok(Self(interned));
+    }
+    let interned: &'static str = Box::leak(name.to_owned().into_boxed_str());
+    names.insert(interned);
+    Ok(Self(interned))
+  }
+
+  /// Name of the endpoint, without the `custom:` prefix.
+  pub fn name(&self) -> &'static str {
+    self.0
+  }
+}
+
+impl fmt::Debug for CustomEndpoint {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "CustomEndpoint({})", self.0)
+  }
+}
+
+impl fmt::Display for CustomEndpoint {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}{}", CUSTOM_ENDPOINT_PREFIX, self.0)
+  }
+}
+
+fn declare_endpoints<V>(
+  named: HashMap<String, V>,
+) -> Result<HashMap<Endpoint, V>, ButtplugDeviceError> {
+  named
+    .into_iter()
+    .map(|(name, value)| Ok((Endpoint::declare(&name)?, value)))
+    .collect()
+}
+
+/// Deserializes a configuration map keyed by endpoint, declaring any custom
+/// endpoints in it. For use with `#[serde(deserialize_with)]`.
+pub(crate) fn deserialize_declared_endpoints<'de, D, V>(
+  deserializer: D,
+) -> Result<HashMap<Endpoint, V>, D::Error>
+where
+  D: Deserializer<'de>,
+  V: Deserialize<'de>,
+{
+  declare_endpoints(HashMap::<String, V>::deserialize(deserializer)?).map_err(de::Error::custom)
+}
+
+/// Same as [deserialize_declared_endpoints], for BLE service maps.
+pub(crate) fn deserialize_declared_service_endpoints<'de, D>(
+  deserializer: D,
+) -> Result<HashMap<Uuid, HashMap<Endpoint, Uuid>>, D::Error>
+where
+  D: Deserializer<'de>,
+{
+  HashMap::<Uuid, HashMap<String, Uuid>>::deserialize(deserializer)?
+    .into_iter()
+    .map(|(service, named)| Ok((service, declare_endpoints(named)?)))
+    .collect::<Result<_, ButtplugDeviceError>>()
+    .map_err(de::Error::custom)
+}
+
+#[cfg(test)]
+mod test {
+  use super::CustomEndpoint;
+  use crate::device::Endpoint;
+  use std::str::FromStr;
+
+  #[test]
+  fn test_custom_endpoint_names() {
+    let motor = CustomEndpoint::declare("motor2").unwrap();
+    assert_eq!(motor, CustomEndpoint::declare("motor2").unwrap());
+    assert_eq!(motor, CustomEndpoint::new("motor2").unwrap());
+    assert_ne!(motor, CustomEndpoint::declare("motor3").unwrap());
+    assert!(CustomEndpoint::declare("").is_err());
+    assert!(CustomEndpoint::declare("Motor").is_err());
+    assert!(CustomEndpoint::declare("motor 2").is_err());
+    assert!(CustomEndpoint::declare(&"a".repeat(65)).is_err());
+    // Looking a name up doesn't add it.
+    assert!(CustomEndpoint::new("never-declared").is_err());
+    assert!(CustomEndpoint::new("never-declared").is_err());
+  }
+
+  #[test]
+  fn test_custom_endpoint_parsing() {
+    // Names have to be declared before they parse.
+    assert!(Endpoint::from_str("custom:fan").is_err());
+    assert!(serde_json::from_str::<Endpoint>("\"custom:fan\"").is_err());
+    let endpoint = Endpoint::declare("custom:fan").unwrap();
+    assert_eq!(Endpoint::from_str("custom:fan").unwrap(), endpoint);
+    assert_eq!(
+      endpoint,
+      Endpoint::Custom(CustomEndpoint::new("fan").unwrap())
+    );
+    assert_eq!(endpoint.to_string(), "custom:fan");
+    // Built in names still parse to their variants, and unknown names
+    // without the prefix are still errors.
+    assert_eq!(Endpoint::from_str("tx").unwrap(), Endpoint::Tx);
+    assert_eq!(Endpoint::declare("tx").unwrap(), Endpoint::Tx);
+    assert_eq!(Endpoint::Tx.to_string(), "tx");
+    assert!(Endpoint::from_str("fan").is_err());
+    assert!(Endpoint::declare("fan").is_err());
+    assert!(Endpoint::declare("custom:").is_err());
+    let json = serde_json::to_string(&endpoint).unwrap();
+    assert_eq!(json, "\"custom:fan\"");
+    assert_eq!(serde_json::from_str::<Endpoint>(&json).unwrap(), endpoint);
+  }
+}
@@ -0,0 +1,201 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2022 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Enforcement of the duty cycle limits declared in the device configuration.
+//!
+//! Commands are tracked per feature. A feature that's been above the limit's
+//! intensity for too long is derated: it's set down to the limit's intensity
+//! right away, whether or not more commands come in, and commands for it are
+//! capped at that intensity until the cooldown is over. After the cooldown,
+//! the feature stays where it is until the next command for it.
+
+use super::{configuration_manager::DutyCycleLimit, protocol::ButtplugProtocol, DeviceImpl};
+use crate::core::messages::{
+  ButtplugDeviceCommandMessageUnion, ButtplugDeviceMessage, ButtplugDeviceMessageType,
+  ButtplugMessage, RotateCmd, RotationSubcommand, VibrateCmd, VibrateSubcommand,
+};
+use std::{
+  collections::HashMap,
+  sync::{Arc, Mutex},
+  time::{Duration, Instant},
+};
+
+type FeatureKey = (ButtplugDeviceMessageType, u32);
+
+#[derive(Default)]
+struct FeatureState {
+  /// Last speed asked for, before any capping.
+  requested: f64,
+  /// Last direction asked for, only used by rotation.
+  clockwise: bool,
+  above_since: Option<Instant>,
+  derated_until: Option<Instant>,
+  // Bumped every time the feature goes above the limit or comes back down,
+  // so timers for earlier runs know to bail out.
+  run: u64,
+}
+
+pub(crate) struct DutyCycleGuard {
+  limit: DutyCycleLimit,
+  features: Arc<Mutex<HashMap<FeatureKey, FeatureState>>>,
+  protocol: Arc<dyn ButtplugProtocol>,
+  device: Arc<DeviceImpl>,
+}
+
+impl DutyCycleGuard {
+  pub fn new(
+    limit: DutyCycleLimit,
+    protocol: Arc<dyn ButtplugProtocol>,
+    device: Arc<DeviceImpl>,
+  ) -> Self {
+    Self {
+      limit,
+      features: Arc::new(Mutex::new(HashMap::new())),
+      protocol,
+      device,
+    }
+  }
+
+  /// Returns the command with speeds capped for any derated features, and
+  /// starts timing features that just went above the limit.
+  pub fn limit_command(
+    &self,
+    message: ButtplugDeviceCommandMessageUnion,
+  ) -> ButtplugDeviceCommandMessageUnion {
+    match message {
+      ButtplugDeviceCommandMessageUnion::VibrateCmd(msg) => {
+        let speeds = msg
+          .speeds()
+          .iter()
+          .map(|cmd| {
+            let speed = self.update(
+              ButtplugDeviceMessageType::VibrateCmd,
+              cmd.index(),
+              cmd.speed(),
+              false,
+            );
+            VibrateSubcommand::new(cmd.index(), speed)
+          })
+          .collect();
+        let mut limited = VibrateCmd::new(msg.device_index(), speeds);
+        limited.set_id(msg.id());
+        limited.into()
+      }
+      ButtplugDeviceCommandMessageUnion::RotateCmd(msg) => {
+        let rotations = msg
+          .rotations
+          .iter()
+          .map(|cmd| {
+            let speed = self.update(
+              ButtplugDeviceMessageType::RotateCmd,
+              cmd.index(),
+              cmd.speed(),
+              cmd.clockwise(),
+            );
+            RotationSubcommand::new(cmd.index(), speed, cmd.clockwise())
+          })
+          .collect();
+        let mut limited = RotateCmd::new(msg.device_index, rotations);
+        limited.set_id(msg.id());
+        limited.into()
+      }
+      ButtplugDeviceCommandMessageUnion::StopDeviceCmd(msg) => {
+        // Derated features keep cooling down, everything else starts over.
+        for state in self.features.lock().unwrap().values_mut() {
+          state.requested = 0.0;
+          state.above_since = None;
+          state.run += 1;
+        }
+        msg.into()
+      }
+      message => message,
+    }
+  }
+
+  /// Records a requested speed for a feature and returns the speed that
+  /// should actually be sent.
+  fn update(
+    &self,
+    message_type: ButtplugDeviceMessageType,
+    index: u32,
+    requested: f64,
+    clockwise: bool,
+  ) -> f64 {
+    let mut features = self.features.lock().unwrap();
+    let state = features.entry((message_type, index)).or_default();
+    state.requested = requested;
+    state.clockwise = clockwise;
+    let now = Instant::now();
+    if state.derated_until.map_or(false, |until| until > now) {
+      return requested.min(self.limit.intensity);
+    }
+    state.derated_until = None;
+    if requested <= self.limit.intensity {
+      if state.above_since.take().is_some() {
+        state.run += 1;
+      }
+      return requested;
+    }
+    if state.above_since.is_none() {
+      state.above_since = Some(now);
+      state.run += 1;
+      self.start_timer((message_type, index), state.run);
+    }
+    requested
+  }
+
+  /// Derates the feature once it's been above the limit for the maximum
+  /// time, unless it came back down (or stopped) in the meantime.
+  fn start_timer(&self, key: FeatureKey, run: u64) {
+    let limit = self.limit;
+    let features = self.features.clone();
+    let protocol = self.protocol.clone();
+    let device = self.device.clone();
+    let delay = Duration::from_secs_f64(limit.max_continuous_seconds);
+    self.device.scheduler().schedule(delay, move || {
+      // Derated before the job's future is built, so the lock isn't held
+      // across an await.
+      let derated = {
+        let mut features = features.lock().unwrap();
+        match features.get_mut(&key) {
+          Some(state) if state.run == run => {
+            state.above_since = None;
+            state.derated_until =
+              Some(Instant::now() + Duration::from_secs_f64(limit.cooldown_seconds));
+            state.run += 1;
+            Some((state.requested.min(limit.intensity), state.clockwise))
+          }
+          _ => None,
+        }
+      };
+      let protocol = protocol.clone();
+      let device = device.clone();
+      async move {
+        let (speed, clockwise) = derated?;
+        let (message_type, index) = key;
+        warn!(
+          "Feature {} of {} has been running above {} for {}s, derating it to protect the device.",
+          index,
+          protocol.name(),
+          limit.intensity,
+          limit.max_continuous_seconds
+        );
+        // The device index isn't used below the device manager, so 0 is fine.
+        let command: ButtplugDeviceCommandMessageUnion = match message_type {
+          ButtplugDeviceMessageType::RotateCmd => {
+            RotateCmd::new(0, vec![RotationSubcommand::new(index, speed, clockwise)]).into()
+          }
+          _ => VibrateCmd::new(0, vec![VibrateSubcommand::new(index, speed)]).into(),
+        };
+        if let Err(e) = protocol.handle_command(device, command).await {
+          error!("Could not derate device feature: {:?}", e);
+        }
+        None
+      }
+    });
+  }
+}
@@ -0,0 +1,163 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2021 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Platform independent device identity.
+//!
+//! Transport addresses aren't stable across machines. The same Bluetooth toy
+//! shows up with its MAC address on Linux and Windows, but with a per-machine
+//! UUID on macOS and iOS, and either may come back from the OS in different
+//! cases or formats. User configuration (split devices, device groups, device
+//! filters) and device index reuse go through [DeviceIdentity] instead of raw
+//! addresses, so entries written on one machine keep working on another.
+//!
+//! An identity is made of the normalized transport address, plus a device ID
+//! if the transport or protocol could get one from the hardware (a serial
+//! number, or the MAC address some protocols report, like Lovense's
+//! DeviceType reply). When a device ID is available, it's preferred over the
+//! address.
+
+use uuid::Uuid;
+
+/// Prefix for configuration entries that refer to a device by device ID
+/// instead of by address, e.g. `id:0082059ad3bd`.
+pub const DEVICE_ID_PREFIX: &str = "id:";
+
+/// Normalizes a transport address, so the same address formatted differently
+/// compares equal.
+///
+/// UUIDs (with or without braces or hyphens) become lowercase hyphenated
+/// UUIDs, and MAC addresses (colon or hyphen separated) become lowercase colon
+/// separated MACs. Anything else (serial ports, MQTT topics, virtual device
+/// addresses, etc) is only trimmed, as case may matter there.
+pub fn normalize_address(address: &str) -> String {
+  let address = address.trim();
+  let unbraced = address
+    .strip_prefix('{')
+    .and_then(|addr| addr.strip_suffix('}'))
+    .unwrap_or(address);
+  if let Ok(uuid) = Uuid::parse_str(unbraced) {
+    return uuid.to_hyphenated().to_string();
+  }
+  let octets: Vec<&str> = address.split(|c| c == ':' || c == '-').collect();
+  if octets.len() == 6
+    && octets
+      .iter()
+      .all(|octet| octet.len() == 2 && octet.chars().all(|c| c.is_ascii_hexdigit()))
+  {
+    return octets.join(":").to_ascii_lowercase();
+  }
+  address.to_owned()
+}
+
+/// Normalizes a device ID. Separators are dropped and case is ignored, so a
+/// MAC address reported by a protocol matches however the OS formats it.
+pub fn normalize_device_id(device_id: &str) -> String {
+  device_id
+    .trim()
+    .chars()
+    .filter(|c| *c != ':' && *c != '-')
+    .collect::<String>()
+    .to_ascii_lowercase()
+}
+
+/// Normalizes a configuration entry (an address, or a device ID with
+/// [DEVICE_ID_PREFIX]), so entries that refer to the same device compare
+/// equal.
+pub fn normalize_entry(entry: &str) -> String {
+  let entry = entry.trim();
+  match entry.strip_prefix(DEVICE_ID_PREFIX) {
+    Some(device_id) => format!("{}{}", DEVICE_ID_PREFIX, normalize_device_id(device_id)),
+    None => normalize_address(entry),
+  }
+}
+
+/// Identifies a physical (or virtual) device across platforms and sessions.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct DeviceIdentity {
+  address: String,
+  device_id: Option<String>,
+}
+
+impl DeviceIdentity {
+  pub fn new(address: &str, device_id: Option<&str>) -> Self {
+    Self {
+      address: normalize_address(address),
+      device_id: device_id
+        .map(normalize_device_id)
+        .filter(|id| !id.is_empty()),
+    }
+  }
+
+  /// Normalized transport address.
+  pub fn address(&self) -> &str {
+    &self.address
+  }
+
+  /// Normalized device ID, if one was reported for the device.
+  pub fn device_id(&self) -> Option<&str> {
+    self.device_id.as_deref()
+  }
+
+  /// Key used to remember the device between connections. This is the device
+  /// ID if we have one, otherwise the normalized address.
+  pub fn key(&self) -> String {
+    match &self.device_id {
+      Some(device_id) => format!("{}{}", DEVICE_ID_PREFIX, device_id),
+      None => self.address.clone(),
+    }
+  }
+
+  /// Checks a configuration entry against this identity. Entries can be an
+  /// address, or a device ID with [DEVICE_ID_PREFIX]. Addresses are also
+  /// checked against the device ID, so that a MAC address written on a
+  /// platform that exposes MACs still matches on one that doesn't, as long as
+  /// the protocol reports the MAC as its device ID.
+  pub fn matches(&self, entry: &str) -> bool {
+    let entry = entry.trim();
+    if let Some(device_id) = entry.strip_prefix(DEVICE_ID_PREFIX) {
+      return self.device_id.as_deref() == Some(normalize_device_id(device_id).as_str());
+    }
+    normalize_address(entry) == self.address
+      || self
+        .device_id
+        .as_ref()
+        .map_or(false, |device_id| *device_id == normalize_device_id(entry))
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::{normalize_address, DeviceIdentity};
+
+  #[test]
+  fn test_normalize_address() {
+    assert_eq!(normalize_address("00:82:05:9A:D3:BD"), "00:82:05:9a:d3:bd");
+    assert_eq!(normalize_address("00-82-05-9a-d3-bd"), "00:82:05:9a:d3:bd");
+    assert_eq!(
+      normalize_address("{5A8E3F0C-2B1D-4C6E-9F7A-0123456789AB}"),
+      "5a8e3f0c-2b1d-4c6e-9f7a-0123456789ab"
+    );
+    assert_eq!(normalize_address(" COM3 "), "COM3");
+  }
+
+  #[test]
+  fn test_device_identity_matches() {
+    // macOS style identity, where the protocol reported the device's MAC.
+    let identity =
+      DeviceIdentity::new("5A8E3F0C-2B1D-4C6E-9F7A-0123456789AB", Some("0082059AD3BD"));
+    assert_eq!(identity.key(), "id:0082059ad3bd");
+    assert!(identity.matches("5a8e3f0c-2b1d-4c6e-9f7a-0123456789ab"));
+    assert!(identity.matches("00:82:05:9A:D3:BD"));
+    assert!(identity.matches("id:0082059ad3bd"));
+    assert!(!identity.matches("id:0082059ad3be"));
+
+    let identity = DeviceIdentity::new("00:82:05:9A:D3:BD", None);
+    assert_eq!(identity.key(), "00:82:05:9a:d3:bd");
+    assert!(identity.matches("00-82-05-9a-d3-bd"));
+    assert!(!identity.matches("id:0082059ad3bd"));
+  }
+}
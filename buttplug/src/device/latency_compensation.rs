@@ -0,0 +1,125 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2022 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Compensates LinearCmd timing for link and firmware delay.
+//!
+//! Scripts for strokers give moves as "be at this position in this long",
+//! timed against a video. By the time a command gets through BLE and the
+//! device firmware, part of that time is gone, so the stroke finishes late
+//! and drifts behind the video. Taking the delay off the move duration gets
+//! the device to the position when the script said it should be there.
+
+use super::configuration_manager::ProtocolOptions;
+use crate::core::{
+  errors::ButtplugDeviceError,
+  messages::{ButtplugDeviceMessage, ButtplugMessage, LinearCmd, VectorSubcommand},
+};
+use std::time::Duration;
+
+/// Shortest duration a compensated move is given. Moves the delay eats
+/// entirely are sent as fast as the device can go, rather than with a zero
+/// duration protocols may not handle.
+const MIN_COMPENSATED_DURATION_MS: u32 = 1;
+
+/// How much to take off LinearCmd durations for a device.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LinearLatencyCompensation {
+  /// A fixed delay, usually set by the user after lining things up by eye.
+  Fixed(Duration),
+  /// The device's measured command latency. Nothing is taken off until the
+  /// first command has been timed.
+  Measured,
+}
+
+impl LinearLatencyCompensation {
+  /// Reads the `linear-latency-ms` and `linear-latency-measured` protocol
+  /// options. A fixed delay wins if both are set.
+  pub fn from_options(options: &ProtocolOptions) -> Result<Option<Self>, ButtplugDeviceError> {
+    if let Some(latency_ms) = options.get::<u64>("linear-latency-ms")? {
+      return Ok(Some(Self::Fixed(Duration::from_millis(latency_ms))));
+    }
+    if options.get_or("linear-latency-measured", false)? {
+      return Ok(Some(Self::Measured));
+    }
+    Ok(None)
+  }
+
+  /// Delay to take off move durations, given the device's measured latency.
+  pub fn offset(&self, measured_latency: Option<Duration>) -> Duration {
+    match self {
+      Self::Fixed(offset) => *offset,
+      Self::Measured => measured_latency.unwrap_or_default(),
+    }
+  }
+}
+
+/// Takes `offset` off every move in the command.
+pub(crate) fn compensate_linear(msg: &LinearCmd, offset: Duration) -> LinearCmd {
+  let offset_ms = offset.as_millis().min(u32::MAX as u128) as u32;
+  let vectors = msg
+    .vectors()
+    .iter()
+    .map(|cmd| {
+      VectorSubcommand::new(
+        cmd.index(),
+        cmd
+          .duration()
+          .saturating_sub(offset_ms)
+          .max(MIN_COMPENSATED_DURATION_MS),
+        *cmd.position(),
+      )
+    })
+    .collect();
+  let mut compensated = LinearCmd::new(msg.device_index(), vectors);
+  compensated.set_id(msg.id());
+  compensated
+}
+
+#[cfg(test)]
+mod test {
+  use super::{compensate_linear, LinearLatencyCompensation};
+  use crate::core::messages::{ButtplugMessage, LinearCmd, VectorSubcommand};
+  use std::time::Duration;
+
+  #[test]
+  fn test_compensate_linear() {
+    let mut msg = LinearCmd::new(
+      2,
+      vec![
+        VectorSubcommand::new(0, 500, 0.9),
+        VectorSubcommand::new(1, 60, 0.1),
+      ],
+    );
+    msg.set_id(7);
+    let compensated = compensate_linear(&msg, Duration::from_millis(80));
+    assert_eq!(compensated.id(), 7);
+    assert_eq!(
+      compensated.vectors(),
+      &vec![
+        VectorSubcommand::new(0, 420, 0.9),
+        VectorSubcommand::new(1, 1, 0.1),
+      ]
+    );
+  }
+
+  #[test]
+  fn test_latency_offset() {
+    let measured = Some(Duration::from_millis(120));
+    assert_eq!(
+      LinearLatencyCompensation::Fixed(Duration::from_millis(50)).offset(measured),
+      Duration::from_millis(50)
+    );
+    assert_eq!(
+      LinearLatencyCompensation::Measured.offset(measured),
+      Duration::from_millis(120)
+    );
+    assert_eq!(
+      LinearLatencyCompensation::Measured.offset(None),
+      Duration::ZERO
+    );
+  }
+}
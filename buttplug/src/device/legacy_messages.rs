@@ -0,0 +1,245 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2022 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Translation of deprecated device messages into the generic messages that
+//! replaced them.
+//!
+//! Spec v0 and v1 clients can send SingleMotorVibrateCmd,
+//! FleshlightLaunchFW12Cmd and VorzeA10CycloneCmd. Instead of each protocol
+//! backporting these, they're turned into VibrateCmd, LinearCmd and RotateCmd
+//! before the protocol sees them, so any device taking the generic message
+//! takes the legacy one too. Those clients are told as much when their device
+//! info is built, see
+//! [DeviceMessageInfoV1][crate::core::messages::DeviceMessageInfoV1].
+//!
+//! A protocol that lists a legacy message in its device configuration gets it
+//! as is. That's only the Kiiroo protocols, where the Fleshlight position and
+//! speed are what goes over the wire.
+
+use super::protocol::fleshlight_launch_helper::get_duration;
+use crate::core::{
+  errors::ButtplugDeviceError,
+  messages::{
+    ButtplugDeviceCommandMessageUnion, ButtplugDeviceMessage, ButtplugDeviceMessageType,
+    ButtplugMessage, DeviceMessageAttributesMap, LinearCmd, RotateCmd, RotationSubcommand,
+    VectorSubcommand, VibrateCmd, VibrateSubcommand,
+  },
+};
+use std::sync::atomic::{AtomicU8, Ordering};
+
+// Fleshlight and Vorze commands take positions and speeds from 0 to 99.
+const LEGACY_MAX_VALUE: f64 = 99f64;
+
+#[derive(Default)]
+pub(crate) struct LegacyMessageTranslator {
+  // Fleshlight commands only give a target position and a speed, so we need
+  // the last target to work out how long a move should take.
+  previous_position: AtomicU8,
+}
+
+impl LegacyMessageTranslator {
+  /// Returns the message to hand to the protocol. Anything that isn't a
+  /// legacy message, or is one the protocol handles itself, comes back as is.
+  pub fn translate(
+    &self,
+    message: ButtplugDeviceCommandMessageUnion,
+    message_attributes: &DeviceMessageAttributesMap,
+  ) -> Result<ButtplugDeviceCommandMessageUnion, ButtplugDeviceError> {
+    let native =
+      |message_type: ButtplugDeviceMessageType| message_attributes.contains_key(&message_type);
+    let translated: ButtplugDeviceCommandMessageUnion = match message {
+      // SingleMotorVibrateCmd has always meant "every vibrator at this speed".
+      ButtplugDeviceCommandMessageUnion::SingleMotorVibrateCmd(msg)
+        if !native(ButtplugDeviceMessageType::SingleMotorVibrateCmd) =>
+      {
+        let feature_count = generic_feature_count(
+          message_attributes,
+          ButtplugDeviceMessageType::SingleMotorVibrateCmd,
+          ButtplugDeviceMessageType::VibrateCmd,
+        )?;
+        let mut vibrate = VibrateCmd::new(
+          msg.device_index(),
+          (0..feature_count)
+            .map(|index| VibrateSubcommand::new(index, msg.speed()))
+            .collect(),
+        );
+        vibrate.set_id(msg.id());
+        vibrate.into()
+      }
+      ButtplugDeviceCommandMessageUnion::FleshlightLaunchFW12Cmd(msg)
+        if !native(ButtplugDeviceMessageType::FleshlightLaunchFW12Cmd) =>
+      {
+        generic_feature_count(
+          message_attributes,
+          ButtplugDeviceMessageType::FleshlightLaunchFW12Cmd,
+          ButtplugDeviceMessageType::LinearCmd,
+        )?;
+        let previous_position = self
+          .previous_position
+          .swap(msg.position(), Ordering::SeqCst);
+        let position = msg.position() as f64 / LEGACY_MAX_VALUE;
+        let distance = (position - previous_position as f64 / LEGACY_MAX_VALUE).abs();
+        let duration = get_duration(distance, msg.speed() as f64 / LEGACY_MAX_VALUE);
+        let mut linear = LinearCmd::new(
+          msg.device_index(),
+          vec![VectorSubcommand::new(0, duration, position)],
+        );
+        linear.set_id(msg.id());
+        linear.into()
+      }
+      ButtplugDeviceCommandMessageUnion::VorzeA10CycloneCmd(msg)
+        if !native(ButtplugDeviceMessageType::VorzeA10CycloneCmd) =>
+      {
+        generic_feature_count(
+          message_attributes,
+          ButtplugDeviceMessageType::VorzeA10CycloneCmd,
+          ButtplugDeviceMessageType::RotateCmd,
+        )?;
+        let mut rotate = RotateCmd::new(
+          msg.device_index(),
+          vec![RotationSubcommand::new(
+            0,
+            msg.speed() as f64 / LEGACY_MAX_VALUE,
+            msg.clockwise(),
+          )],
+        );
+        rotate.set_id(msg.id());
+        rotate.into()
+      }
+      message => message,
+    };
+    Ok(translated)
+  }
+}
+
+/// Number of features the generic replacement for a legacy message has, or
+/// an error naming the legacy message if the device doesn't take it.
+fn generic_feature_count(
+  message_attributes: &DeviceMessageAttributesMap,
+  legacy_type: ButtplugDeviceMessageType,
+  generic_type: ButtplugDeviceMessageType,
+) -> Result<u32, ButtplugDeviceError> {
+  message_attributes
+    .get(&generic_type)
+    .and_then(|attributes| attributes.feature_count)
+    .ok_or(ButtplugDeviceError::MessageNotSupported(legacy_type))
+}
+
+#[cfg(test)]
+mod test {
+  use super::LegacyMessageTranslator;
+  use crate::core::{
+    errors::ButtplugDeviceError,
+    messages::{
+      ButtplugDeviceCommandMessageUnion, ButtplugDeviceMessage, ButtplugDeviceMessageType,
+      DeviceMessageAttributes, DeviceMessageAttributesMap, FleshlightLaunchFW12Cmd,
+      SingleMotorVibrateCmd, VorzeA10CycloneCmd,
+    },
+  };
+
+  fn attributes(types: &[(ButtplugDeviceMessageType, u32)]) -> DeviceMessageAttributesMap {
+    types
+      .iter()
+      .map(|(message_type, feature_count)| {
+        (
+          *message_type,
+          DeviceMessageAttributes {
+            feature_count: Some(*feature_count),
+            ..Default::default()
+          },
+        )
+      })
+      .collect()
+  }
+
+  #[test]
+  fn test_single_motor_vibrate_translation() {
+    let translator = LegacyMessageTranslator::default();
+    let attributes = attributes(&[(ButtplugDeviceMessageType::VibrateCmd, 2)]);
+    match translator
+      .translate(SingleMotorVibrateCmd::new(3, 0.5).into(), &attributes)
+      .unwrap()
+    {
+      ButtplugDeviceCommandMessageUnion::VibrateCmd(msg) => {
+        assert_eq!(msg.device_index(), 3);
+        assert_eq!(msg.speeds().len(), 2);
+        assert!(msg.speeds().iter().all(|cmd| cmd.speed() == 0.5));
+      }
+      msg => panic!("Expected a VibrateCmd, got {:?}", msg),
+    }
+  }
+
+  #[test]
+  fn test_vorze_translation() {
+    let translator = LegacyMessageTranslator::default();
+    let attributes = attributes(&[(ButtplugDeviceMessageType::RotateCmd, 1)]);
+    match translator
+      .translate(VorzeA10CycloneCmd::new(0, 99, false).into(), &attributes)
+      .unwrap()
+    {
+      ButtplugDeviceCommandMessageUnion::RotateCmd(msg) => {
+        assert_eq!(msg.rotations.len(), 1);
+        assert_eq!(msg.rotations[0].speed(), 1.0);
+        assert!(!msg.rotations[0].clockwise());
+      }
+      msg => panic!("Expected a RotateCmd, got {:?}", msg),
+    }
+  }
+
+  #[test]
+  fn test_fleshlight_translation_tracks_position() {
+    let translator = LegacyMessageTranslator::default();
+    let attributes = attributes(&[(ButtplugDeviceMessageType::LinearCmd, 1)]);
+    let mut durations = vec![];
+    for position in [99, 0, 0] {
+      match translator
+        .translate(
+          FleshlightLaunchFW12Cmd::new(0, position, 50).into(),
+          &attributes,
+        )
+        .unwrap()
+      {
+        ButtplugDeviceCommandMessageUnion::LinearCmd(msg) => {
+          assert_eq!(msg.vectors()[0].position, position as f64 / 99f64);
+          durations.push(msg.vectors()[0].duration);
+        }
+        msg => panic!("Expected a LinearCmd, got {:?}", msg),
+      }
+    }
+    // A full stroke takes time, staying put doesn't.
+    assert!(durations[0] > 0);
+    assert_eq!(durations[0], durations[1]);
+    assert_eq!(durations[2], 0);
+  }
+
+  #[test]
+  fn test_native_legacy_messages_pass_through() {
+    let translator = LegacyMessageTranslator::default();
+    let attributes = attributes(&[
+      (ButtplugDeviceMessageType::LinearCmd, 1),
+      (ButtplugDeviceMessageType::FleshlightLaunchFW12Cmd, 1),
+    ]);
+    assert!(matches!(
+      translator
+        .translate(FleshlightLaunchFW12Cmd::new(0, 50, 50).into(), &attributes)
+        .unwrap(),
+      ButtplugDeviceCommandMessageUnion::FleshlightLaunchFW12Cmd(_)
+    ));
+  }
+
+  #[test]
+  fn test_unsupported_legacy_message() {
+    let translator = LegacyMessageTranslator::default();
+    let attributes = attributes(&[(ButtplugDeviceMessageType::VibrateCmd, 1)]);
+    assert!(matches!(
+      translator
+        .translate(VorzeA10CycloneCmd::new(0, 50, true).into(), &attributes)
+        .unwrap_err(),
+      ButtplugDeviceError::MessageNotSupported(ButtplugDeviceMessageType::VorzeA10CycloneCmd)
+    ));
+  }
+}
@@ -1,19 +1,30 @@
+mod adaptive_rate;
 pub mod configuration_manager;
+mod custom_endpoint;
+mod duty_cycle;
+pub mod identity;
+mod latency_compensation;
+mod legacy_messages;
 pub mod protocol;
+mod soft_start;
+pub mod spans;
+mod write_batcher;
 use serde::{
   de::{self, Visitor},
   Deserialize, Deserializer, Serialize, Serializer,
 };
 use std::{
+  collections::HashMap,
   fmt::{self, Debug},
   str::FromStr,
   string::ToString,
-  sync::Arc,
+  sync::{Arc, Mutex},
+  time::Duration,
 };
 
 use crate::{
   core::{
-    errors::ButtplugError,
+    errors::{ButtplugDeviceError, ButtplugError},
     messages::{
       self, ButtplugDeviceCommandMessageUnion, ButtplugServerMessage, DeviceMessageAttributesMap,
       RawReadCmd, RawReading, RawSubscribeCmd, RawUnsubscribeCmd, RawWriteCmd,
@@ -24,18 +35,37 @@ use crate::{
     configuration_manager::{DeviceConfigurationManager, DeviceSpecifier, ProtocolDefinition},
     protocol::ButtplugProtocol,
   },
+  util::{
+    clock::Clock,
+    scheduler::Scheduler,
+  },
 };
+use adaptive_rate::AdaptiveRateController;
 use async_trait::async_trait;
 use configuration_manager::DeviceProtocolConfiguration;
 use core::hash::{Hash, Hasher};
-use futures::future::BoxFuture;
+pub use custom_endpoint::{CustomEndpoint, CUSTOM_ENDPOINT_PREFIX};
+use dashmap::DashMap;
+use duty_cycle::DutyCycleGuard;
+use futures::future::{self, BoxFuture};
+use identity::DeviceIdentity;
+use latency_compensation::compensate_linear;
+pub use latency_compensation::LinearLatencyCompensation;
+use legacy_messages::LegacyMessageTranslator;
+use once_cell::sync::{Lazy, OnceCell};
+pub use soft_start::{SoftStartConfig, DEFAULT_SOFT_START_THRESHOLD};
+use soft_start::SoftStartRamp;
+use strum::IntoEnumIterator;
 use tokio::sync::broadcast;
+use tracing_futures::{Instrument, Instrumented};
+pub use write_batcher::WriteBatchConfig;
+use write_batcher::WriteBatcher;
 
 // We need this array to be exposed in our WASM FFI, but the only way to do that
 // is to expose it at the declaration level. Therefore, we use the WASM feature
 // to assume we're building for WASM and attach our bindgen. The serde
 // de/serialization is taken care of at the FFI level.
-#[derive(EnumString, Clone, Debug, PartialEq, Eq, Hash, Display, Copy)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Copy, IntoStaticStr, EnumIter)]
 #[strum(serialize_all = "lowercase")]
 pub enum Endpoint {
   Command,
@@ -83,6 +113,59 @@ pub enum Endpoint {
   Generic29,
   Generic30,
   Generic31,
+  /// Device specific endpoint, written as `custom:<name>`. See
+  /// [CustomEndpoint].
+  #[strum(disabled)]
+  Custom(CustomEndpoint),
+}
+
+// Built in endpoints by name, for parsing.
+static BUILT_IN_ENDPOINTS: Lazy<HashMap<&'static str, Endpoint>> = Lazy::new(|| {
+  Endpoint::iter()
+    .map(|endpoint| (endpoint.into(), endpoint))
+    .collect()
+});
+
+impl FromStr for Endpoint {
+  type Err = ButtplugDeviceError;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    if let Some(name) = s.strip_prefix(CUSTOM_ENDPOINT_PREFIX) {
+      return CustomEndpoint::new(name).map(Endpoint::Custom);
+    }
+    BUILT_IN_ENDPOINTS.get(s).copied().ok_or_else(|| {
+      ButtplugDeviceError::InvalidEndpointName(
+        s.to_owned(),
+        format!(
+          "Not a built in endpoint, custom endpoints need a \"{}\" prefix",
+          CUSTOM_ENDPOINT_PREFIX
+        ),
+      )
+    })
+  }
+}
+
+impl Endpoint {
+  /// Parses an endpoint name like [from_str][Endpoint::from_str], declaring
+  /// it first if it's custom. Only used while loading device configuration.
+  pub(crate) fn declare(s: &str) -> Result<Self, ButtplugDeviceError> {
+    match s.strip_prefix(CUSTOM_ENDPOINT_PREFIX) {
+      Some(name) => CustomEndpoint::declare(name).map(Endpoint::Custom),
+      None => Endpoint::from_str(s),
+    }
+  }
+}
+
+impl fmt::Display for Endpoint {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Endpoint::Custom(custom) => fmt::Display::fmt(custom, f),
+      endpoint => {
+        let name: &'static str = endpoint.into();
+        f.write_str(name)
+      }
+    }
+  }
 }
 
 impl Serialize for Endpoint {
@@ -120,9 +203,67 @@ impl<'de> Deserialize<'de> for Endpoint {
   }
 }
 
+#[cfg(feature = "json-schema")]
+impl schemars::JsonSchema for Endpoint {
+  fn schema_name() -> String {
+    "Endpoint".to_owned()
+  }
+
+  fn json_schema(_: &mut schemars::gen::SchemaGenerator) -> schemars::schema::Schema {
+    use schemars::schema::{InstanceType, SchemaObject, StringValidation, SubschemaValidation};
+    let built_in = SchemaObject {
+      instance_type: Some(InstanceType::String.into()),
+      enum_values: Some(
+        Endpoint::iter()
+          .map(|endpoint| endpoint.to_string().into())
+          .collect(),
+      ),
+      ..Default::default()
+    };
+    let custom = SchemaObject {
+      instance_type: Some(InstanceType::String.into()),
+      string: Some(Box::new(StringValidation {
+        pattern: Some(format!("^{}[a-z0-9_-]{{1,64}}$", CUSTOM_ENDPOINT_PREFIX)),
+        ..Default::default()
+      })),
+      ..Default::default()
+    };
+    SchemaObject {
+      subschemas: Some(Box::new(SubschemaValidation {
+        any_of: Some(vec![built_in.into(), custom.into()]),
+        ..Default::default()
+      })),
+      ..Default::default()
+    }
+    .into()
+  }
+}
+
+/// How writes to an endpoint are sent, on transports that can do either
+/// (Bluetooth LE).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum WriteType {
+  /// Wait for the device to acknowledge each write.
+  WithResponse,
+  /// Send writes without waiting, for higher throughput.
+  WithoutResponse,
+}
+
+impl WriteType {
+  pub fn with_response(self) -> bool {
+    self == WriteType::WithResponse
+  }
+}
+
 pub type ButtplugDeviceResultFuture =
   BoxFuture<'static, Result<ButtplugServerMessage, ButtplugError>>;
 
+/// Future for a transport operation, running in its `device io` span (see
+/// [spans]). The transport's own boxed future is instrumented as is, so
+/// tracing the IO path doesn't cost an extra allocation per operation.
+pub type DeviceIoFuture<T = ()> = Instrumented<ButtplugResultFuture<T>>;
+
 #[derive(PartialEq, Debug)]
 pub struct DeviceReadCmd {
   pub endpoint: Endpoint,
@@ -295,13 +436,47 @@ pub enum ButtplugDeviceReturn {
 pub enum ButtplugDeviceEvent {
   Connected(Arc<ButtplugDevice>),
   Notification(String, Endpoint, Vec<u8>),
+  /// Sensor reading parsed by the device's protocol. The device index isn't
+  /// set, as devices don't know their index.
+  SensorReading(String, messages::SensorReading),
   Removed(String),
 }
+
+/// How far along creating a device is, reported through
+/// [ButtplugDevice::try_create_device_with_progress].
+#[derive(Debug, Clone, PartialEq)]
+pub enum DeviceCreationStage {
+  /// The device matched a protocol, and we're connecting to it.
+  Connecting { protocol: String },
+  /// The device is connected, and its protocol is setting it up. Protocols
+  /// with slow setups report what they're doing (see
+  /// [DeviceImpl::report_initialization_stage]), otherwise the stage is
+  /// empty.
+  Initializing { stage: String },
+}
+
+type InitializationProgress = Arc<dyn Fn(&str) + Send + Sync>;
+
 pub struct DeviceImpl {
   name: String,
   address: String,
   endpoints: Vec<Endpoint>,
-  internal_impl: Box<dyn DeviceImplInternal>,
+  /// Hardware reported ID (serial number, MAC, etc), set by the transport or
+  /// protocol if the device has one.
+  device_id: OnceCell<String>,
+  internal_impl: Arc<dyn DeviceImplInternal>,
+  // The internal impl event stream belongs to the comm manager, so protocols
+  // emit their sensor readings through a stream of our own.
+  sensor_sender: broadcast::Sender<ButtplugDeviceEvent>,
+  // Runs timed jobs (keepalives, output loops, ramps) for the protocol, and
+  // is its time source for pacing writes.
+  scheduler: Scheduler,
+  // Write types that replace whatever the protocol asks for, per endpoint.
+  write_types: DashMap<Endpoint, WriteType>,
+  // Endpoints in throughput mode.
+  write_batchers: DashMap<Endpoint, WriteBatcher>,
+  // Where initialization stages go while the device is being created.
+  initialization_progress: Mutex<Option<InitializationProgress>>,
 }
 
 impl DeviceImpl {
@@ -311,11 +486,53 @@ impl DeviceImpl {
     endpoints: &[Endpoint],
     internal_impl: Box<dyn DeviceImplInternal>,
   ) -> Self {
+    Self::new_with_scheduler(
+      name,
+      address,
+      endpoints,
+      internal_impl,
+      Scheduler::shared(),
+    )
+  }
+
+  /// Creates a device whose protocol keeps time with the given clock, instead
+  /// of real time.
+  pub fn new_with_clock(
+    name: &str,
+    address: &str,
+    endpoints: &[Endpoint],
+    internal_impl: Box<dyn DeviceImplInternal>,
+    clock: Arc<dyn Clock>,
+  ) -> Self {
+    // Jobs on a clock of their own can't share workers with anyone else.
+    Self::new_with_scheduler(
+      name,
+      address,
+      endpoints,
+      internal_impl,
+      Scheduler::new(1, clock),
+    )
+  }
+
+  fn new_with_scheduler(
+    name: &str,
+    address: &str,
+    endpoints: &[Endpoint],
+    internal_impl: Box<dyn DeviceImplInternal>,
+    scheduler: Scheduler,
+  ) -> Self {
+    let (sensor_sender, _) = broadcast::channel(256);
     Self {
       name: name.to_owned(),
       address: address.to_owned(),
       endpoints: endpoints.into(),
-      internal_impl,
+      device_id: OnceCell::new(),
+      internal_impl: Arc::from(internal_impl),
+      sensor_sender,
+      scheduler,
+      write_types: DashMap::new(),
+      write_batchers: DashMap::new(),
+      initialization_progress: Mutex::new(None),
     }
   }
 
@@ -327,6 +544,89 @@ impl DeviceImpl {
     &self.address
   }
 
+  pub fn device_id(&self) -> Option<&str> {
+    self.device_id.get().map(|id| id.as_str())
+  }
+
+  /// Records the hardware ID of the device. Only the first ID set is kept, as
+  /// the device's identity shouldn't change once it's been used.
+  pub fn set_device_id(&self, device_id: &str) {
+    if self.device_id.set(device_id.to_owned()).is_err() {
+      debug!(
+        "Device {} already has a device ID, ignoring {}",
+        self.address, device_id
+      );
+    }
+  }
+
+  /// Reports what the protocol is doing while it initializes the device,
+  /// e.g. "Setting up session", so UIs can show progress for protocols that
+  /// take a while. Does nothing once the device is created.
+  pub fn report_initialization_stage(&self, stage: &str) {
+    debug!("Device {} initializing: {}", self.address, stage);
+    let progress = self.initialization_progress.lock().unwrap().clone();
+    if let Some(progress) = progress {
+      progress(stage);
+    }
+  }
+
+  pub fn clock(&self) -> Arc<dyn Clock> {
+    self.scheduler.clock()
+  }
+
+  /// Scheduler for protocol jobs that run on a timer, shared with other
+  /// devices unless this device has a clock of its own.
+  pub fn scheduler(&self) -> &Scheduler {
+    &self.scheduler
+  }
+
+  /// Sets the write type used for every write to an endpoint, no matter
+  /// what the protocol (or a raw write) asks for. Transports set this from
+  /// the device configuration, and it can be changed for devices that need
+  /// something else. None goes back to what each write asks for.
+  pub fn set_write_type(&self, endpoint: Endpoint, write_type: Option<WriteType>) {
+    match write_type {
+      Some(write_type) => {
+        self.write_types.insert(endpoint, write_type);
+      }
+      None => {
+        self.write_types.remove(&endpoint);
+      }
+    }
+  }
+
+  /// Turns on throughput mode for an endpoint (see [WriteBatchConfig]),
+  /// replacing any earlier settings for it. Writes to the endpoint resolve
+  /// as soon as they're queued, are always sent without response, and are
+  /// dropped if a newer write replaces them before they're sent. Protocols
+  /// should only turn this on for endpoints where that's safe.
+  pub fn enable_write_batching(&self, config: WriteBatchConfig) {
+    info!(
+      "Batching writes to {} every {:?}",
+      config.endpoint, config.interval
+    );
+    let endpoint = config.endpoint;
+    let batcher = WriteBatcher::new(
+      config,
+      self.address.clone(),
+      self.internal_impl.clone(),
+      self.clock(),
+    );
+    self.write_batchers.insert(endpoint, batcher);
+  }
+
+  /// Turns off throughput mode for an endpoint. A write already queued is
+  /// still sent.
+  pub fn disable_write_batching(&self, endpoint: Endpoint) {
+    self.write_batchers.remove(&endpoint);
+  }
+
+  /// Write type set for an endpoint with
+  /// [set_write_type][DeviceImpl::set_write_type], if any.
+  pub fn write_type(&self, endpoint: Endpoint) -> Option<WriteType> {
+    self.write_types.get(&endpoint).map(|write_type| *write_type)
+  }
+
   pub fn connected(&self) -> bool {
     self.internal_impl.connected()
   }
@@ -335,6 +635,18 @@ impl DeviceImpl {
     self.internal_impl.event_stream()
   }
 
+  pub fn sensor_event_stream(&self) -> broadcast::Receiver<ButtplugDeviceEvent> {
+    self.sensor_sender.subscribe()
+  }
+
+  pub fn send_sensor_reading(&self, reading: messages::SensorReading) {
+    // Nobody listening just means the device isn't registered with a device
+    // manager, so there's nowhere to send the reading anyways.
+    let _ = self
+      .sensor_sender
+      .send(ButtplugDeviceEvent::SensorReading(self.address.clone(), reading));
+  }
+
   pub fn endpoints(&self) -> Vec<Endpoint> {
     self.endpoints.clone()
   }
@@ -343,23 +655,49 @@ impl DeviceImpl {
     self.internal_impl.disconnect()
   }
 
-  pub fn read_value(
-    &self,
-    msg: DeviceReadCmd,
-  ) -> BoxFuture<'static, Result<RawReading, ButtplugError>> {
-    self.internal_impl.read_value(msg)
+  pub fn read_value(&self, msg: DeviceReadCmd) -> DeviceIoFuture<RawReading> {
+    let span = spans::device_io_span(&self.address, "read", msg.endpoint);
+    let fut = span.in_scope(|| self.internal_impl.read_value(msg));
+    fut.instrument(span)
   }
 
-  pub fn write_value(&self, msg: DeviceWriteCmd) -> ButtplugResultFuture {
-    self.internal_impl.write_value(msg)
+  pub fn write_value(&self, mut msg: DeviceWriteCmd) -> DeviceIoFuture {
+    if let Some(write_type) = self.write_type(msg.endpoint) {
+      msg.write_with_response = write_type.with_response();
+    }
+    let endpoint = msg.endpoint;
+    let span = spans::device_io_span(&self.address, "write", endpoint);
+    let queued = self
+      .write_batchers
+      .get(&endpoint)
+      .map(|batcher| batcher.queue(msg.data.clone()));
+    let fut: ButtplugResultFuture = match queued {
+      Some(true) => Box::pin(future::ready(Ok(()))),
+      Some(false) => {
+        self.write_batchers.remove(&endpoint);
+        Box::pin(future::ready(Err(
+          ButtplugDeviceError::DeviceCommunicationError(format!(
+            "Batched writes to {} stopped after a write failed.",
+            endpoint
+          ))
+          .into(),
+        )))
+      }
+      None => span.in_scope(|| self.internal_impl.write_value(msg)),
+    };
+    fut.instrument(span)
   }
 
-  pub fn subscribe(&self, msg: DeviceSubscribeCmd) -> ButtplugResultFuture {
-    self.internal_impl.subscribe(msg)
+  pub fn subscribe(&self, msg: DeviceSubscribeCmd) -> DeviceIoFuture {
+    let span = spans::device_io_span(&self.address, "subscribe", msg.endpoint);
+    let fut = span.in_scope(|| self.internal_impl.subscribe(msg));
+    fut.instrument(span)
   }
 
-  pub fn unsubscribe(&self, msg: DeviceUnsubscribeCmd) -> ButtplugResultFuture {
-    self.internal_impl.unsubscribe(msg)
+  pub fn unsubscribe(&self, msg: DeviceUnsubscribeCmd) -> DeviceIoFuture {
+    let span = spans::device_io_span(&self.address, "unsubscribe", msg.endpoint);
+    let fut = span.in_scope(|| self.internal_impl.unsubscribe(msg));
+    fut.instrument(span)
   }
 }
 
@@ -386,8 +724,19 @@ pub trait ButtplugDeviceImplCreator: Sync + Send + Debug {
 }
 
 pub struct ButtplugDevice {
-  protocol: Box<dyn ButtplugProtocol>,
+  protocol: Arc<dyn ButtplugProtocol>,
   device: Arc<DeviceImpl>,
+  /// Name of the device configuration protocol entry this device was created
+  /// from. Devices that weren't created from the device configuration (test
+  /// devices, virtual devices, etc) won't have one.
+  protocol_identifier: Option<String>,
+  /// Only set if the device configuration gives the protocol duty cycle
+  /// limits.
+  duty_cycle: Option<DutyCycleGuard>,
+  legacy_messages: LegacyMessageTranslator,
+  rate_controller: AdaptiveRateController,
+  linear_latency: Mutex<Option<LinearLatencyCompensation>>,
+  soft_start: SoftStartRamp,
 }
 
 impl Debug for ButtplugDevice {
@@ -415,16 +764,56 @@ impl PartialEq for ButtplugDevice {
 
 impl ButtplugDevice {
   pub fn new(protocol: Box<dyn ButtplugProtocol>, device: Arc<DeviceImpl>) -> Self {
-    Self { protocol, device }
+    let protocol: Arc<dyn ButtplugProtocol> = Arc::from(protocol);
+    Self {
+      rate_controller: AdaptiveRateController::new(false, protocol.clone(), device.clone()),
+      soft_start: SoftStartRamp::new(None, protocol.clone(), device.clone()),
+      protocol,
+      device,
+      protocol_identifier: None,
+      duty_cycle: None,
+      legacy_messages: LegacyMessageTranslator::default(),
+      linear_latency: Mutex::new(None),
+    }
   }
 
   pub fn address(&self) -> &str {
     self.device.address()
   }
 
+  pub fn device_id(&self) -> Option<&str> {
+    self.device.device_id()
+  }
+
+  /// Platform independent identity of the device, used for matching user
+  /// configuration and reusing device indexes.
+  pub fn identity(&self) -> DeviceIdentity {
+    DeviceIdentity::new(self.address(), self.device_id())
+  }
+
+  pub fn protocol_identifier(&self) -> Option<&str> {
+    self.protocol_identifier.as_deref()
+  }
+
+  /// Endpoints the device's transport exposes.
+  pub fn endpoints(&self) -> Vec<Endpoint> {
+    self.device.endpoints()
+  }
+
   pub async fn try_create_device(
+    device_config_mgr: Arc<DeviceConfigurationManager>,
+    device_creator: Box<dyn ButtplugDeviceImplCreator>,
+  ) -> Result<Option<ButtplugDevice>, ButtplugError> {
+    Self::try_create_device_with_progress(device_config_mgr, device_creator, |_| {}).await
+  }
+
+  /// Same as [ButtplugDevice::try_create_device], but calls `progress` as
+  /// creation moves through each [DeviceCreationStage]. Nothing is reported
+  /// for devices that don't match a protocol.
+  pub async fn try_create_device_with_progress(
     device_config_mgr: Arc<DeviceConfigurationManager>,
     mut device_creator: Box<dyn ButtplugDeviceImplCreator>,
+    progress: impl Fn(DeviceCreationStage) + Send + Sync + 'static,
   ) -> Result<Option<ButtplugDevice>, ButtplugError> {
     // First off, we need to see if we even have a configuration available
     // for the device we're trying to create. If we don't, return Ok(None),
@@ -442,10 +831,18 @@ impl ButtplugDevice {
           allow_raw_messages,
           config.defaults.clone(),
           config.configurations.clone(),
+          config.options.clone(),
         );
+        let duty_cycle = config.duty_cycle;
+        let linear_latency = LinearLatencyCompensation::from_options(&config.options)?;
+        let soft_start = SoftStartConfig::from_options(&config.options)?;
+        let adaptive_rate = config.options.get_or("adaptive-rate", false)?;
         // TODO Should we even return a config from the device_config_mgr if the
         // protocol isn't there?
         if device_config_mgr.has_protocol(&*config_name) {
+          progress(DeviceCreationStage::Connecting {
+            protocol: config_name.clone(),
+          });
           match device_creator.try_create_device_impl(config).await {
             Ok(device_impl) => {
               info!(
@@ -460,13 +857,52 @@ impl ButtplugDevice {
               // whatever it needs. For most protocols, this is a no-op. However, for
               // devices like Lovense, some Kiiroo, etc, this can get fairly
               // complicated.
+              progress(DeviceCreationStage::Initializing {
+                stage: String::new(),
+              });
+              *device_impl.initialization_progress.lock().unwrap() =
+                Some(Arc::new(move |stage: &str| {
+                  progress(DeviceCreationStage::Initializing {
+                    stage: stage.to_owned(),
+                  })
+                }));
               let sharable_device_impl = Arc::new(device_impl);
-              match device_config_mgr.get_protocol_creator(&*config_name)(sharable_device_impl.clone(), device_protocol_config).await
-              {
-                Ok(protocol_impl) => Ok(Some(ButtplugDevice::new(
-                  protocol_impl,
-                  sharable_device_impl,
-                ))),
+              let protocol_impl = device_config_mgr.get_protocol_creator(&*config_name)(
+                sharable_device_impl.clone(),
+                device_protocol_config,
+              )
+              .await;
+              // Anything reported from here on is protocol jobs running on
+              // a created device, not initialization.
+              sharable_device_impl
+                .initialization_progress
+                .lock()
+                .unwrap()
+                .take();
+              match protocol_impl {
+                Ok(protocol_impl) => {
+                  let protocol: Arc<dyn ButtplugProtocol> = Arc::from(protocol_impl);
+                  let duty_cycle = duty_cycle.map(|limit| {
+                    DutyCycleGuard::new(limit, protocol.clone(), sharable_device_impl.clone())
+                  });
+                  let rate_controller = AdaptiveRateController::new(
+                    adaptive_rate,
+                    protocol.clone(),
+                    sharable_device_impl.clone(),
+                  );
+                  let soft_start =
+                    SoftStartRamp::new(soft_start, protocol.clone(), sharable_device_impl.clone());
+                  Ok(Some(ButtplugDevice {
+                    protocol,
+                    device: sharable_device_impl,
+                    protocol_identifier: Some(config_name),
+                    duty_cycle,
+                    legacy_messages: LegacyMessageTranslator::default(),
+                    rate_controller,
+                    linear_latency: Mutex::new(linear_latency),
+                    soft_start,
+                  }))
+                }
                 Err(e) => Err(e),
               }
             }
@@ -505,6 +941,18 @@ impl ButtplugDevice {
     self.device.disconnect()
   }
 
+  /// True if the device's protocol has a command to turn it off.
+  pub fn supports_power_off(&self) -> bool {
+    self.protocol.supports_power_off()
+  }
+
+  /// Turns the device off. Errors if the protocol has no command for it, see
+  /// [ButtplugDevice::supports_power_off].
+  pub fn power_off(&self) -> ButtplugDeviceResultFuture {
+    info!("Powering off device {}", self.address());
+    self.protocol.handle_power_off(self.device.clone())
+  }
+
   pub fn message_attributes(&self) -> DeviceMessageAttributesMap {
     self.protocol.message_attributes()
   }
@@ -513,12 +961,196 @@ impl ButtplugDevice {
     &self,
     message: ButtplugDeviceCommandMessageUnion,
   ) -> ButtplugDeviceResultFuture {
-    self.protocol.handle_command(self.device.clone(), message)
+    // Legacy messages are translated first, so neither the duty cycle guard
+    // nor the protocol has to deal with them.
+    let message = match self
+      .legacy_messages
+      .translate(message, &self.protocol.message_attributes())
+    {
+      Ok(message) => message,
+      Err(e) => return Box::pin(future::ready(Err(e.into()))),
+    };
+    let message = match &self.duty_cycle {
+      Some(duty_cycle) => duty_cycle.limit_command(message),
+      None => message,
+    };
+    let message = self.soft_start.ramp_command(message);
+    let message = match message {
+      ButtplugDeviceCommandMessageUnion::LinearCmd(msg) => match self.linear_latency_offset() {
+        Some(offset) => compensate_linear(&msg, offset).into(),
+        None => msg.into(),
+      },
+      message => message,
+    };
+    if let ButtplugDeviceCommandMessageUnion::StopDeviceCmd(_) = message {
+      let ramp_step = self.soft_start.ramp_step_finished();
+      let fut = self.rate_controller.send(message);
+      return Box::pin(async move {
+        ramp_step.await;
+        fut.await
+      });
+    }
+    self.rate_controller.send(message)
+  }
+
+  /// Sets how much to take off LinearCmd durations to make up for the delay
+  /// getting commands to the device. Replaces whatever the device
+  /// configuration set, and None turns compensation off.
+  pub fn set_linear_latency_compensation(&self, compensation: Option<LinearLatencyCompensation>) {
+    *self.linear_latency.lock().unwrap() = compensation;
+  }
+
+  pub fn linear_latency_compensation(&self) -> Option<LinearLatencyCompensation> {
+    *self.linear_latency.lock().unwrap()
+  }
+
+  /// Delay currently being taken off LinearCmd durations, or None if
+  /// compensation is off.
+  pub fn linear_latency_offset(&self) -> Option<Duration> {
+    self
+      .linear_latency_compensation()
+      .map(|compensation| compensation.offset(self.rate_controller.average_latency()))
+  }
+
+  /// Sets how features are ramped up when started from idle. Replaces
+  /// whatever the device configuration set, and None turns soft start off.
+  pub fn set_soft_start(&self, config: Option<SoftStartConfig>) {
+    self.soft_start.set_config(config);
+  }
+
+  pub fn soft_start(&self) -> Option<SoftStartConfig> {
+    self.soft_start.config()
+  }
+
+  /// Same as [parse_message][Self::parse_message], but runs the command in a
+  /// `device command` span. See [spans] for the fields recorded.
+  pub fn parse_message_instrumented(
+    &self,
+    device_index: u32,
+    message: ButtplugDeviceCommandMessageUnion,
+  ) -> ButtplugDeviceResultFuture {
+    let span = spans::device_command_span(device_index, self, &message);
+    // Protocols do some of their work before returning the future, so that
+    // needs to be in the span too.
+    let fut = span.in_scope(|| self.parse_message(message));
+    Box::pin(fut.instrument(span))
   }
 
   pub fn event_stream(&self) -> broadcast::Receiver<ButtplugDeviceEvent> {
     self.device.event_stream()
   }
 
+  pub fn sensor_event_stream(&self) -> broadcast::Receiver<ButtplugDeviceEvent> {
+    self.device.sensor_event_stream()
+  }
+
   // TODO Handle raw messages here.
 }
+
+#[cfg(all(test, feature = "server"))]
+mod test {
+  use super::{
+    configuration_manager::{BluetoothLESpecifier, DeviceConfigurationManager, DeviceSpecifier},
+    ButtplugDevice, DeviceImplCommand, DeviceWriteCmd, Endpoint, WriteBatchConfig, WriteType,
+  };
+  use crate::{
+    test::{
+      check_test_recv_empty, check_test_recv_value, new_bluetoothle_test_device,
+      TestDeviceImplCreator, TestDeviceInternal,
+    },
+    util::{async_manager, clock::ManualClock},
+  };
+  use std::{sync::Arc, time::Duration};
+
+  #[test]
+  fn test_write_type_override() {
+    async_manager::block_on(async {
+      let (device, test_device) = new_bluetoothle_test_device("Massage Demo").await.unwrap();
+      let command_receiver = test_device.get_endpoint_receiver(&Endpoint::Tx).unwrap();
+      device
+        .device
+        .set_write_type(Endpoint::Tx, Some(WriteType::WithResponse));
+      device
+        .device
+        .write_value(DeviceWriteCmd::new(Endpoint::Tx, vec![0x01], false))
+        .await
+        .unwrap();
+      check_test_recv_value(
+        &command_receiver,
+        DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![0x01], true)),
+      );
+      device.device.set_write_type(Endpoint::Tx, None);
+      device
+        .device
+        .write_value(DeviceWriteCmd::new(Endpoint::Tx, vec![0x02], false))
+        .await
+        .unwrap();
+      check_test_recv_value(
+        &command_receiver,
+        DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![0x02], false)),
+      );
+    });
+  }
+
+  #[test]
+  fn test_write_batching() {
+    async_manager::block_on(async {
+      let clock = ManualClock::new();
+      let mut test_device = TestDeviceInternal::new("Massage Demo", "massage-demo-test");
+      test_device.set_clock(Arc::new(clock.clone()));
+      let test_device = Arc::new(test_device);
+      let creator = TestDeviceImplCreator::new(
+        DeviceSpecifier::BluetoothLE(BluetoothLESpecifier::new_from_device("Massage Demo")),
+        test_device.clone(),
+      );
+      let device = ButtplugDevice::try_create_device(
+        Arc::new(DeviceConfigurationManager::default()),
+        Box::new(creator),
+      )
+      .await
+      .unwrap()
+      .unwrap();
+      let command_receiver = test_device.get_endpoint_receiver(&Endpoint::Tx).unwrap();
+      device
+        .device
+        .enable_write_batching(WriteBatchConfig::new(Endpoint::Tx, Duration::from_millis(10)));
+      // Batched writes never wait for a response, even if asked to.
+      device
+        .device
+        .write_value(DeviceWriteCmd::new(Endpoint::Tx, vec![0x01], true))
+        .await
+        .unwrap();
+      clock.wait_for_sleeps(1).await;
+      check_test_recv_value(
+        &command_receiver,
+        DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![0x01], false)),
+      );
+      for data in [0x02u8, 0x03, 0x04] {
+        device
+          .device
+          .write_value(DeviceWriteCmd::new(Endpoint::Tx, vec![data], false))
+          .await
+          .unwrap();
+      }
+      assert!(check_test_recv_empty(&command_receiver));
+      // Only the newest write goes out once the interval is up.
+      clock.advance(Duration::from_millis(10));
+      clock.wait_for_sleeps(2).await;
+      check_test_recv_value(
+        &command_receiver,
+        DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![0x04], false)),
+      );
+      assert!(check_test_recv_empty(&command_receiver));
+      device.device.disable_write_batching(Endpoint::Tx);
+      device
+        .device
+        .write_value(DeviceWriteCmd::new(Endpoint::Tx, vec![0x05], true))
+        .await
+        .unwrap();
+      check_test_recv_value(
+        &command_receiver,
+        DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![0x05], true)),
+      );
+    });
+  }
+}
@@ -1,6 +1,8 @@
 use super::{ButtplugDeviceResultFuture, ButtplugProtocol, ButtplugProtocolCommandHandler};
 use crate::{
-  core::messages::{self, ButtplugDeviceCommandMessageUnion, DeviceMessageAttributesMap},
+  core::messages::{
+    self, ButtplugDeviceCommandMessageUnion, ButtplugDeviceMessageType, DeviceMessageAttributesMap,
+  },
   device::{
     protocol::{generic_command_manager::GenericCommandManager, ButtplugProtocolProperties},
     DeviceImpl, DeviceWriteCmd, Endpoint,
@@ -34,6 +36,13 @@ impl ButtplugProtocol for Aneros {
       manager: Arc::new(Mutex::new(manager)),
     })
   }
+
+  fn handled_message_types() -> &'static [ButtplugDeviceMessageType]
+  where
+    Self: Sized,
+  {
+    &[ButtplugDeviceMessageType::VibrateCmd]
+  }
 }
 
 impl ButtplugProtocolCommandHandler for Aneros {
@@ -0,0 +1,326 @@
+use super::{ButtplugDeviceResultFuture, ButtplugProtocol, ButtplugProtocolCommandHandler};
+use crate::{
+  core::{
+    errors::ButtplugDeviceError,
+    messages::{
+      self, ButtplugDeviceCommandMessageUnion, ButtplugDeviceMessageType,
+      DeviceMessageAttributesMap,
+    },
+  },
+  device::{
+    protocol::{generic_command_manager::GenericCommandManager, ButtplugProtocolProperties},
+    DeviceImpl, DeviceWriteCmd, Endpoint,
+  },
+};
+use serde::Serialize;
+use std::{sync::Arc, time::Duration};
+use tokio::sync::Mutex;
+
+/// Roughly how long a full stroke takes at 100% speed. LinearCmd speeds are
+/// worked out from this, so moves take about, not exactly, their duration.
+const FULL_SPEED_STROKE_TIME: Duration = Duration::from_millis(200);
+
+/// Body for the oscillate endpoint. Speed and bounds are all percentages.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+struct OscillationState {
+  speed: u32,
+  #[serde(rename = "minValue")]
+  min_value: u32,
+  #[serde(rename = "maxValue")]
+  max_value: u32,
+}
+
+impl OscillationState {
+  fn write_cmd(&self) -> DeviceWriteCmd {
+    if self.speed == 0 {
+      stop_write_cmd()
+    } else {
+      DeviceWriteCmd::new(
+        Endpoint::Tx,
+        serde_json::to_vec(self).expect("Oscillation state always serializes."),
+        false,
+      )
+    }
+  }
+}
+
+fn stop_write_cmd() -> DeviceWriteCmd {
+  DeviceWriteCmd::new(Endpoint::TxMode, vec![], false)
+}
+
+#[derive(Default)]
+struct AutoblowState {
+  /// Where the last LinearCmd sent the device, in percent.
+  position: u32,
+  /// Bumped by every command, so the stop at the end of a LinearCmd move
+  /// knows whether something else has been sent since.
+  generation: u64,
+}
+
+/// Speed that covers `distance` percent of the stroke in `duration`.
+fn move_speed(distance: u32, duration: Duration) -> u32 {
+  if duration.is_zero() {
+    return 100;
+  }
+  let strokes = f64::from(distance) / 100f64;
+  let speed = strokes * FULL_SPEED_STROKE_TIME.as_secs_f64() / duration.as_secs_f64();
+  ((speed * 100f64).round() as u32).max(1).min(100)
+}
+
+/// Autoblow AI Ultra, driven through its HTTP API.
+///
+/// The device only knows how to oscillate between two positions at a speed,
+/// and has no command for moving to a single position. VibrateCmd
+/// oscillates over the whole stroke, at the given speed. LinearCmd has one
+/// axis, and moves are made by oscillating between where the last LinearCmd
+/// left the device and the new position, at a speed that covers the distance
+/// in the command's duration, then stopping once the duration is up. Any
+/// other command sent before then takes over.
+///
+/// The tx and txmode endpoints map to the oscillate and stop URLs, via the
+/// "http" specifier in the device configuration.
+#[derive(ButtplugProtocolProperties)]
+pub struct AutoblowAIUltra {
+  name: String,
+  message_attributes: DeviceMessageAttributesMap,
+  manager: Arc<Mutex<GenericCommandManager>>,
+  stop_commands: Vec<ButtplugDeviceCommandMessageUnion>,
+  state: Arc<Mutex<AutoblowState>>,
+}
+
+impl ButtplugProtocol for AutoblowAIUltra {
+  fn new_protocol(
+    name: &str,
+    message_attributes: DeviceMessageAttributesMap,
+  ) -> Box<dyn ButtplugProtocol>
+  where
+    Self: Sized,
+  {
+    let manager = GenericCommandManager::new(&message_attributes);
+
+    Box::new(Self {
+      name: name.to_owned(),
+      message_attributes,
+      stop_commands: manager.get_stop_commands(),
+      manager: Arc::new(Mutex::new(manager)),
+      state: Arc::new(Mutex::new(AutoblowState::default())),
+    })
+  }
+
+  fn handled_message_types() -> &'static [ButtplugDeviceMessageType]
+  where
+    Self: Sized,
+  {
+    &[
+      ButtplugDeviceMessageType::LinearCmd,
+      ButtplugDeviceMessageType::VibrateCmd,
+    ]
+  }
+}
+
+impl ButtplugProtocolCommandHandler for AutoblowAIUltra {
+  fn handle_vibrate_cmd(
+    &self,
+    device: Arc<DeviceImpl>,
+    message: messages::VibrateCmd,
+  ) -> ButtplugDeviceResultFuture {
+    let manager = self.manager.clone();
+    let state = self.state.clone();
+    Box::pin(async move {
+      // Linear moves change what the device is doing behind the manager's
+      // back, so every speed is sent, even if it's the same as the last one.
+      let result = manager.lock().await.update_vibration(&message, true)?;
+      if let Some(cmds) = result {
+        if let Some(speed) = cmds[0] {
+          let mut state = state.lock().await;
+          state.generation += 1;
+          let oscillation = OscillationState {
+            speed,
+            min_value: 0,
+            max_value: 100,
+          };
+          device.write_value(oscillation.write_cmd()).await?;
+        }
+      }
+      Ok(messages::Ok::default().into())
+    })
+  }
+
+  fn handle_linear_cmd(
+    &self,
+    device: Arc<DeviceImpl>,
+    message: messages::LinearCmd,
+  ) -> ButtplugDeviceResultFuture {
+    let state_handle = self.state.clone();
+    Box::pin(async move {
+      let vector = match message.vectors().as_slice() {
+        [vector] if vector.index == 0 => vector.clone(),
+        [vector] => {
+          return Err(ButtplugDeviceError::DeviceFeatureIndexError(1, vector.index).into())
+        }
+        _ => {
+          return Err(
+            ButtplugDeviceError::ProtocolRequirementError(
+              "Autoblow AI Ultra takes exactly one LinearCmd vector.".to_owned(),
+            )
+            .into(),
+          )
+        }
+      };
+      let target = (vector.position.max(0.0).min(1.0) * 100f64).round() as u32;
+      let duration = Duration::from_millis(u64::from(vector.duration));
+      let mut state = state_handle.lock().await;
+      state.generation += 1;
+      let from = state.position;
+      state.position = target;
+      if from == target {
+        return Ok(messages::Ok::default().into());
+      }
+      let oscillation = OscillationState {
+        speed: move_speed(from.max(target) - from.min(target), duration),
+        min_value: from.min(target),
+        max_value: from.max(target),
+      };
+      device.write_value(oscillation.write_cmd()).await?;
+      let generation = state.generation;
+      drop(state);
+      // The device would keep oscillating past the target, so stop it once
+      // the move's time is up, unless another command took over.
+      let job_state = state_handle;
+      let job_device = device.clone();
+      device.scheduler().schedule(duration, move || {
+        let state = job_state.clone();
+        let device = job_device.clone();
+        async move {
+          if state.lock().await.generation == generation {
+            if let Err(err) = device.write_value(stop_write_cmd()).await {
+              error!("Could not stop Autoblow AI Ultra after move: {:?}", err);
+            }
+          }
+          None
+        }
+      });
+      Ok(messages::Ok::default().into())
+    })
+  }
+}
+
+#[cfg(all(test, feature = "server"))]
+mod test {
+  use crate::{
+    core::messages::{LinearCmd, StopDeviceCmd, VectorSubcommand, VibrateCmd, VibrateSubcommand},
+    device::{
+      configuration_manager::{DeviceConfigurationManager, DeviceSpecifier, HTTPSpecifier},
+      ButtplugDevice, DeviceImplCommand, DeviceWriteCmd, Endpoint,
+    },
+    test::{
+      check_test_recv_empty, check_test_recv_value, TestDeviceImplCreator, TestDeviceInternal,
+    },
+    util::{async_manager, clock::ManualClock},
+  };
+  use std::{sync::Arc, time::Duration};
+
+  fn oscillate(body: &[u8]) -> DeviceImplCommand {
+    DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, body.to_vec(), false))
+  }
+
+  fn stop() -> DeviceImplCommand {
+    DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::TxMode, vec![], false))
+  }
+
+  fn linear(duration: u32, position: f64) -> LinearCmd {
+    LinearCmd::new(0, vec![VectorSubcommand::new(0, duration, position)])
+  }
+
+  // Stops at the end of moves are written from the scheduler, after the
+  // clock moves.
+  async fn wait_for_scheduler() {
+    async_manager::sleep(Duration::from_millis(50)).await;
+  }
+
+  #[test]
+  pub fn test_autoblow_ai_ultra_protocol() {
+    async_manager::block_on(async move {
+      let clock = ManualClock::new();
+      let mut test_device = TestDeviceInternal::new("Autoblow AI Ultra", "http://autoblow.local");
+      test_device.set_clock(Arc::new(clock.clone()));
+      let test_device = Arc::new(test_device);
+      test_device.add_endpoint(&Endpoint::Tx).await;
+      test_device.add_endpoint(&Endpoint::TxMode).await;
+      let creator = TestDeviceImplCreator::new(
+        DeviceSpecifier::HTTP(HTTPSpecifier::new_from_name("Autoblow AI Ultra")),
+        test_device.clone(),
+      );
+      let device = ButtplugDevice::try_create_device(
+        Arc::new(DeviceConfigurationManager::default()),
+        Box::new(creator),
+      )
+      .await
+      .unwrap()
+      .unwrap();
+      let command_receiver = test_device.get_endpoint_receiver(&Endpoint::Tx).unwrap();
+      let stop_receiver = test_device
+        .get_endpoint_receiver(&Endpoint::TxMode)
+        .unwrap();
+
+      // Moves oscillate between the last position and the new one, fast
+      // enough to cover the distance in the duration, then stop.
+      device.parse_message(linear(500, 0.8).into()).await.unwrap();
+      check_test_recv_value(
+        &command_receiver,
+        oscillate(br#"{"speed":32,"minValue":0,"maxValue":80}"#),
+      );
+      clock.wait_for_sleeps(1).await;
+      clock.advance(Duration::from_millis(500));
+      wait_for_scheduler().await;
+      check_test_recv_value(&stop_receiver, stop());
+
+      // Speeds are capped, and the next move starts where the last ended.
+      device.parse_message(linear(100, 0.2).into()).await.unwrap();
+      check_test_recv_value(
+        &command_receiver,
+        oscillate(br#"{"speed":100,"minValue":20,"maxValue":80}"#),
+      );
+      // Vibration takes over before the move is up, so the move's stop
+      // isn't sent.
+      clock.wait_for_sleeps(2).await;
+      device
+        .parse_message(VibrateCmd::new(0, vec![VibrateSubcommand::new(0, 0.5)]).into())
+        .await
+        .unwrap();
+      check_test_recv_value(
+        &command_receiver,
+        oscillate(br#"{"speed":50,"minValue":0,"maxValue":100}"#),
+      );
+      clock.advance(Duration::from_millis(100));
+      wait_for_scheduler().await;
+      assert!(check_test_recv_empty(&stop_receiver));
+
+      // There's only one axis.
+      assert!(device
+        .parse_message(LinearCmd::new(0, vec![VectorSubcommand::new(1, 500, 1.0)]).into())
+        .await
+        .is_err());
+      assert!(device
+        .parse_message(
+          LinearCmd::new(
+            0,
+            vec![
+              VectorSubcommand::new(0, 500, 0.0),
+              VectorSubcommand::new(0, 500, 1.0),
+            ],
+          )
+          .into()
+        )
+        .await
+        .is_err());
+
+      device
+        .parse_message(StopDeviceCmd::new(0).into())
+        .await
+        .unwrap();
+      check_test_recv_value(&stop_receiver, stop());
+      assert!(check_test_recv_empty(&command_receiver));
+    });
+  }
+}
@@ -0,0 +1,302 @@
+use super::{
+  waveform::{Waveform, WaveformFrame, WAVEFORM_FRAME_DURATION_MS},
+  ButtplugDeviceResultFuture, ButtplugProtocol, ButtplugProtocolCommandHandler,
+};
+use crate::{
+  core::{
+    errors::ButtplugError,
+    messages::{
+      self, ButtplugDeviceCommandMessageUnion, ButtplugDeviceMessageType,
+      DeviceMessageAttributesMap,
+    },
+  },
+  device::{
+    protocol::{generic_command_manager::GenericCommandManager, ButtplugProtocolProperties},
+    DeviceImpl, DeviceWriteCmd, Endpoint,
+  },
+};
+use std::{
+  sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+  },
+  time::Duration,
+};
+use tokio::sync::Mutex;
+
+// The Coyote 2 takes power in internal units of 0-2047, and the official app
+// moves 7 units per step. We expose 200 steps, which keeps the top end a good
+// way below the hardware maximum.
+const COYOTE_POWER_UNITS_PER_STEP: u32 = 7;
+const COYOTE_MAX_POWER_UNITS: u32 = 2047;
+
+/// Current output state of both channels of a Coyote.
+pub(super) struct CoyoteOutput {
+  pub power: [u32; 2],
+  pub waveforms: [Waveform; 2],
+}
+
+impl Default for CoyoteOutput {
+  fn default() -> Self {
+    Self {
+      power: [0, 0],
+      waveforms: [Waveform::default(), Waveform::default()],
+    }
+  }
+}
+
+/// Builds the writes for one output frame, advancing the waveforms.
+pub(super) type CoyoteFrameEncoder = fn(&mut CoyoteOutput) -> Vec<DeviceWriteCmd>;
+
+async fn write_frame(device: &DeviceImpl, cmds: Vec<DeviceWriteCmd>) -> Result<(), ButtplugError> {
+  for cmd in cmds {
+    device.write_value(cmd).await?;
+  }
+  Ok(())
+}
+
+// Coyotes stop output if they don't get a new frame every 100ms, so while any
+// channel has power, we keep streaming frames.
+fn start_output_updates(
+  device: Arc<DeviceImpl>,
+  output: Arc<Mutex<CoyoteOutput>>,
+  updater_running: Arc<AtomicBool>,
+  encoder: CoyoteFrameEncoder,
+) {
+  info!("Starting Coyote output job");
+  let frame_duration = Duration::from_millis(WAVEFORM_FRAME_DURATION_MS);
+  let scheduler = device.scheduler().clone();
+  scheduler.schedule(frame_duration, move || {
+    let device = device.clone();
+    let output = output.clone();
+    let updater_running = updater_running.clone();
+    async move {
+      let cmds = {
+        let mut output = output.lock().await;
+        // Checked under the lock, so a command coming in while we exit will
+        // see that it needs to start a new job.
+        if output.power == [0, 0] {
+          updater_running.store(false, Ordering::SeqCst);
+          info!("Coyote output stopped, exiting output job.");
+          return None;
+        }
+        encoder(&mut output)
+      };
+      if write_frame(&device, cmds).await.is_err() {
+        updater_running.store(false, Ordering::SeqCst);
+        info!("Coyote output job exiting, most likely due to device disconnection.");
+        return None;
+      }
+      Some(frame_duration)
+    }
+  });
+}
+
+/// Shared VibrateCmd handling for all Coyote versions. Each vibrator feature
+/// sets the power of one channel, and output frames are streamed until both
+/// channels are back at zero.
+pub(super) fn handle_coyote_vibrate_cmd(
+  device: Arc<DeviceImpl>,
+  message: messages::VibrateCmd,
+  manager: Arc<Mutex<GenericCommandManager>>,
+  output: Arc<Mutex<CoyoteOutput>>,
+  updater_running: Arc<AtomicBool>,
+  encoder: CoyoteFrameEncoder,
+  power_scale: u32,
+) -> ButtplugDeviceResultFuture {
+  Box::pin(async move {
+    let result = manager.lock().await.update_vibration(&message, false)?;
+    let speeds = if let Some(speeds) = result {
+      speeds
+    } else {
+      return Ok(messages::Ok::default().into());
+    };
+    let loop_output = output.clone();
+    let cmds = {
+      let mut output = output.lock().await;
+      for (channel, speed) in speeds.iter().enumerate() {
+        if let Some(speed) = speed {
+          output.power[channel] = speed * power_scale;
+        }
+      }
+      // Send a frame right away, so power changes don't wait on the output job.
+      let cmds = encoder(&mut output);
+      if output.power != [0, 0] && !updater_running.swap(true, Ordering::SeqCst) {
+        start_output_updates(device.clone(), loop_output, updater_running, encoder);
+      }
+      cmds
+    };
+    write_frame(&device, cmds).await?;
+    Ok(messages::Ok::default().into())
+  })
+}
+
+fn encode_power(power_a: u32, power_b: u32) -> Vec<u8> {
+  let value = (power_a.min(COYOTE_MAX_POWER_UNITS) << 11) | power_b.min(COYOTE_MAX_POWER_UNITS);
+  value.to_le_bytes()[0..3].to_vec()
+}
+
+// Coyote 2 waveforms are described by pulses per group (x, 1-31), gap between
+// groups (y, 0-1023) and pulse width (z, 0-31). x + y makes up the pulse
+// period, split using the formula from DG-Lab's protocol docs.
+fn encode_waveform(frame: WaveformFrame) -> Vec<u8> {
+  let period = frame.pulse_period_ms.max(10).min(1000) as u32;
+  let x = ((period as f64 / 1000.0).sqrt() * 15.0)
+    .round()
+    .max(1.0)
+    .min(31.0) as u32;
+  let y = (period - x).min(1023);
+  let z = frame.intensity as u32 * 31 / 100;
+  let value = (z << 15) | (y << 5) | x;
+  value.to_le_bytes()[0..3].to_vec()
+}
+
+fn encode_coyote_frame(output: &mut CoyoteOutput) -> Vec<DeviceWriteCmd> {
+  vec![
+    DeviceWriteCmd::new(
+      Endpoint::Tx,
+      encode_power(output.power[0], output.power[1]),
+      false,
+    ),
+    DeviceWriteCmd::new(
+      Endpoint::Generic0,
+      encode_waveform(output.waveforms[0].next_frame()),
+      false,
+    ),
+    DeviceWriteCmd::new(
+      Endpoint::Generic1,
+      encode_waveform(output.waveforms[1].next_frame()),
+      false,
+    ),
+  ]
+}
+
+/// DG-Lab Coyote 2.0 e-stim unit. Each vibrator feature controls the power of
+/// one channel (A, then B).
+#[derive(ButtplugProtocolProperties)]
+pub struct DGLabCoyote {
+  name: String,
+  message_attributes: DeviceMessageAttributesMap,
+  manager: Arc<Mutex<GenericCommandManager>>,
+  stop_commands: Vec<ButtplugDeviceCommandMessageUnion>,
+  output: Arc<Mutex<CoyoteOutput>>,
+  updater_running: Arc<AtomicBool>,
+}
+
+impl ButtplugProtocol for DGLabCoyote {
+  fn new_protocol(
+    name: &str,
+    message_attributes: DeviceMessageAttributesMap,
+  ) -> Box<dyn ButtplugProtocol> {
+    let manager = GenericCommandManager::new(&message_attributes);
+
+    Box::new(Self {
+      name: name.to_owned(),
+      message_attributes,
+      stop_commands: manager.get_stop_commands(),
+      manager: Arc::new(Mutex::new(manager)),
+      output: Arc::new(Mutex::new(CoyoteOutput::default())),
+      updater_running: Arc::new(AtomicBool::new(false)),
+    })
+  }
+
+  fn handled_message_types() -> &'static [ButtplugDeviceMessageType]
+  where
+    Self: Sized,
+  {
+    &[ButtplugDeviceMessageType::VibrateCmd]
+  }
+}
+
+impl ButtplugProtocolCommandHandler for DGLabCoyote {
+  fn handle_vibrate_cmd(
+    &self,
+    device: Arc<DeviceImpl>,
+    message: messages::VibrateCmd,
+  ) -> ButtplugDeviceResultFuture {
+    handle_coyote_vibrate_cmd(
+      device,
+      message,
+      self.manager.clone(),
+      self.output.clone(),
+      self.updater_running.clone(),
+      encode_coyote_frame,
+      COYOTE_POWER_UNITS_PER_STEP,
+    )
+  }
+}
+
+#[cfg(all(test, feature = "server"))]
+mod test {
+  use crate::{
+    core::messages::{StopDeviceCmd, VibrateCmd, VibrateSubcommand},
+    device::{
+      configuration_manager::{
+        BluetoothLESpecifier, DeviceConfigurationManager, DeviceConfigurationOptions,
+        DeviceSpecifier,
+      },
+      ButtplugDevice, DeviceImplCommand, DeviceWriteCmd, Endpoint,
+    },
+    test::{check_test_recv_value, TestDeviceImplCreator, TestDeviceInternal},
+    util::async_manager,
+  };
+  use std::sync::Arc;
+
+  #[test]
+  pub fn test_dg_lab_coyote_protocol() {
+    async_manager::block_on(async move {
+      let test_device = Arc::new(TestDeviceInternal::new("D-LAB ESTIM01", "coyote-test"));
+      let creator = TestDeviceImplCreator::new(
+        DeviceSpecifier::BluetoothLE(BluetoothLESpecifier::new_from_device("D-LAB ESTIM01")),
+        test_device.clone(),
+      );
+      let config = DeviceConfigurationManager::new_with_options(&DeviceConfigurationOptions {
+        allow_power_devices: true,
+        ..Default::default()
+      })
+      .unwrap();
+      let device = ButtplugDevice::try_create_device(Arc::new(config), Box::new(creator))
+        .await
+        .unwrap()
+        .unwrap();
+      let power_receiver = test_device.get_endpoint_receiver(&Endpoint::Tx).unwrap();
+      let waveform_receiver = test_device
+        .get_endpoint_receiver(&Endpoint::Generic0)
+        .unwrap();
+      device
+        .parse_message(VibrateCmd::new(0, vec![VibrateSubcommand::new(0, 0.5)]).into())
+        .await
+        .unwrap();
+      // 100 steps * 7 units = 700 on channel A, in bits 21-11.
+      check_test_recv_value(
+        &power_receiver,
+        DeviceImplCommand::Write(DeviceWriteCmd::new(
+          Endpoint::Tx,
+          vec![0x00, 0xE0, 0x15],
+          false,
+        )),
+      );
+      // Default waveform: 10ms period (x = 2, y = 8), full width (z = 31).
+      check_test_recv_value(
+        &waveform_receiver,
+        DeviceImplCommand::Write(DeviceWriteCmd::new(
+          Endpoint::Generic0,
+          vec![0x02, 0x81, 0x0F],
+          false,
+        )),
+      );
+      device
+        .parse_message(StopDeviceCmd::new(0).into())
+        .await
+        .unwrap();
+      check_test_recv_value(
+        &power_receiver,
+        DeviceImplCommand::Write(DeviceWriteCmd::new(
+          Endpoint::Tx,
+          vec![0x00, 0x00, 0x00],
+          false,
+        )),
+      );
+    });
+  }
+}
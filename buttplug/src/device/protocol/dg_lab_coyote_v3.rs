@@ -0,0 +1,175 @@
+use super::{
+  dg_lab_coyote::{handle_coyote_vibrate_cmd, CoyoteOutput},
+  waveform::WaveformFrame,
+  ButtplugDeviceResultFuture, ButtplugProtocol, ButtplugProtocolCommandHandler,
+};
+use crate::{
+  core::messages::{
+    self, ButtplugDeviceCommandMessageUnion, ButtplugDeviceMessageType, DeviceMessageAttributesMap,
+  },
+  device::{
+    protocol::{generic_command_manager::GenericCommandManager, ButtplugProtocolProperties},
+    DeviceImpl, DeviceWriteCmd, Endpoint,
+  },
+};
+use std::sync::{atomic::AtomicBool, Arc};
+use tokio::sync::Mutex;
+
+// Coyote 3 power is 0-200, which maps directly to our step count.
+const COYOTE_V3_POWER_UNITS_PER_STEP: u32 = 1;
+const COYOTE_V3_MAX_POWER: u32 = 200;
+// Sequence number 0 (no response requested), absolute power set on both
+// channels.
+const COYOTE_V3_ABSOLUTE_POWER: u8 = 0x0F;
+
+// Coyote 3 frequencies are compressed into a single byte, 10-240, covering
+// periods of 10-1000ms.
+fn compress_frequency(pulse_period_ms: u16) -> u8 {
+  let period = pulse_period_ms.max(10).min(1000);
+  let compressed = match period {
+    10..=100 => period,
+    101..=600 => (period - 100) / 5 + 100,
+    _ => (period - 600) / 10 + 200,
+  };
+  compressed as u8
+}
+
+fn push_channel_frame(data: &mut Vec<u8>, frame: WaveformFrame) {
+  // Each B0 command covers 100ms as 4 25ms subframes. We play the same frame
+  // for all 4.
+  data.extend_from_slice(&[compress_frequency(frame.pulse_period_ms); 4]);
+  data.extend_from_slice(&[frame.intensity; 4]);
+}
+
+fn encode_coyote_v3_frame(output: &mut CoyoteOutput) -> Vec<DeviceWriteCmd> {
+  let mut data = vec![
+    0xB0,
+    COYOTE_V3_ABSOLUTE_POWER,
+    output.power[0].min(COYOTE_V3_MAX_POWER) as u8,
+    output.power[1].min(COYOTE_V3_MAX_POWER) as u8,
+  ];
+  push_channel_frame(&mut data, output.waveforms[0].next_frame());
+  push_channel_frame(&mut data, output.waveforms[1].next_frame());
+  vec![DeviceWriteCmd::new(Endpoint::Tx, data, false)]
+}
+
+/// DG-Lab Coyote 3.0 e-stim unit. Same feature layout as the 2.0, but all
+/// output goes through a single B0 command.
+#[derive(ButtplugProtocolProperties)]
+pub struct DGLabCoyoteV3 {
+  name: String,
+  message_attributes: DeviceMessageAttributesMap,
+  manager: Arc<Mutex<GenericCommandManager>>,
+  stop_commands: Vec<ButtplugDeviceCommandMessageUnion>,
+  output: Arc<Mutex<CoyoteOutput>>,
+  updater_running: Arc<AtomicBool>,
+}
+
+impl ButtplugProtocol for DGLabCoyoteV3 {
+  fn new_protocol(
+    name: &str,
+    message_attributes: DeviceMessageAttributesMap,
+  ) -> Box<dyn ButtplugProtocol> {
+    let manager = GenericCommandManager::new(&message_attributes);
+
+    Box::new(Self {
+      name: name.to_owned(),
+      message_attributes,
+      stop_commands: manager.get_stop_commands(),
+      manager: Arc::new(Mutex::new(manager)),
+      output: Arc::new(Mutex::new(CoyoteOutput::default())),
+      updater_running: Arc::new(AtomicBool::new(false)),
+    })
+  }
+
+  fn handled_message_types() -> &'static [ButtplugDeviceMessageType]
+  where
+    Self: Sized,
+  {
+    &[ButtplugDeviceMessageType::VibrateCmd]
+  }
+}
+
+impl ButtplugProtocolCommandHandler for DGLabCoyoteV3 {
+  fn handle_vibrate_cmd(
+    &self,
+    device: Arc<DeviceImpl>,
+    message: messages::VibrateCmd,
+  ) -> ButtplugDeviceResultFuture {
+    handle_coyote_vibrate_cmd(
+      device,
+      message,
+      self.manager.clone(),
+      self.output.clone(),
+      self.updater_running.clone(),
+      encode_coyote_v3_frame,
+      COYOTE_V3_POWER_UNITS_PER_STEP,
+    )
+  }
+}
+
+#[cfg(all(test, feature = "server"))]
+mod test {
+  use super::compress_frequency;
+  use crate::{
+    core::messages::{VibrateCmd, VibrateSubcommand},
+    device::{
+      configuration_manager::{
+        BluetoothLESpecifier, DeviceConfigurationManager, DeviceConfigurationOptions,
+        DeviceSpecifier,
+      },
+      ButtplugDevice, DeviceImplCommand, DeviceWriteCmd, Endpoint,
+    },
+    test::{check_test_recv_value, TestDeviceImplCreator, TestDeviceInternal},
+    util::async_manager,
+  };
+  use std::sync::Arc;
+
+  #[test]
+  pub fn test_dg_lab_coyote_v3_protocol() {
+    assert_eq!(compress_frequency(50), 50);
+    assert_eq!(compress_frequency(600), 200);
+    assert_eq!(compress_frequency(1000), 240);
+    async_manager::block_on(async move {
+      let test_device = Arc::new(TestDeviceInternal::new("47L121000", "coyote-v3-test"));
+      let creator = TestDeviceImplCreator::new(
+        DeviceSpecifier::BluetoothLE(BluetoothLESpecifier::new_from_device("47L121000")),
+        test_device.clone(),
+      );
+      let config = DeviceConfigurationManager::new_with_options(&DeviceConfigurationOptions {
+        allow_power_devices: true,
+        ..Default::default()
+      })
+      .unwrap();
+      let device = ButtplugDevice::try_create_device(Arc::new(config), Box::new(creator))
+        .await
+        .unwrap()
+        .unwrap();
+      let command_receiver = test_device.get_endpoint_receiver(&Endpoint::Tx).unwrap();
+      device
+        .parse_message(
+          VibrateCmd::new(
+            0,
+            vec![
+              VibrateSubcommand::new(0, 0.5),
+              VibrateSubcommand::new(1, 0.25),
+            ],
+          )
+          .into(),
+        )
+        .await
+        .unwrap();
+      check_test_recv_value(
+        &command_receiver,
+        DeviceImplCommand::Write(DeviceWriteCmd::new(
+          Endpoint::Tx,
+          vec![
+            0xB0, 0x0F, 100, 50, 10, 10, 10, 10, 100, 100, 100, 100, 10, 10, 10, 10, 100, 100, 100,
+            100,
+          ],
+          false,
+        )),
+      );
+    });
+  }
+}
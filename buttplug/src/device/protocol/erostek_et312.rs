@@ -0,0 +1,487 @@
+use super::{ButtplugDeviceResultFuture, ButtplugProtocol, ButtplugProtocolCommandHandler};
+use crate::{
+  core::{
+    errors::{ButtplugDeviceError, ButtplugError},
+    messages::{
+      self, ButtplugDeviceCommandMessageUnion, ButtplugDeviceMessageType,
+      DeviceMessageAttributesMap,
+    },
+  },
+  device::{
+    configuration_manager::DeviceProtocolConfiguration,
+    protocol::{generic_command_manager::GenericCommandManager, ButtplugProtocolProperties},
+    ButtplugDeviceEvent, DeviceImpl, DeviceSubscribeCmd, DeviceWriteCmd, Endpoint,
+  },
+};
+use futures::{future::BoxFuture, select, FutureExt};
+use std::{sync::Arc, time::Duration};
+use tokio::sync::{broadcast, Mutex};
+
+// The box answers within a few ms at 19200 baud, anything past this means the
+// byte got lost or the box is out of sync.
+const ET312_RESPONSE_TIMEOUT_MS: u64 = 100;
+// The box resyncs after receiving up to 11 garbage bytes, so that's how many
+// sync attempts we make before giving up.
+const ET312_SYNC_RETRY: u32 = 11;
+const ET312_COMMAND_RETRY: u32 = 3;
+
+const ET312_SYNC_BYTE: u8 = 0x00;
+const ET312_SYNC_REPLY: u8 = 0x07;
+const ET312_KEY_EXCHANGE_CMD: u8 = 0x2f;
+const ET312_KEY_EXCHANGE_REPLY: u8 = 0x21;
+const ET312_READ_CMD: u8 = 0x3c;
+const ET312_READ_REPLY: u8 = 0x22;
+const ET312_WRITE_CMD: u8 = 0x0d;
+const ET312_WRITE_REPLY: u8 = 0x06;
+// The box key is xor'd with this and our own key (which we always send as 0)
+// to get the key used for everything we send afterwards.
+const ET312_KEY_MAGIC: u8 = 0x55;
+
+// Bit 0 of this register disables the front panel level pots, which lets us
+// set the channel levels ourselves.
+const ET312_ADDRESS_PANEL_CONTROL: u16 = 0x400f;
+const ET312_PANEL_CONTROL_DISABLE_POTS: u8 = 0x01;
+const ET312_ADDRESS_LEVEL: [u16; 2] = [0x4064, 0x4065];
+
+fn et312_error(msg: &str) -> ButtplugError {
+  ButtplugDeviceError::ProtocolSpecificError("ErosTek ET312".to_owned(), msg.to_owned()).into()
+}
+
+fn checksum(data: &[u8]) -> u8 {
+  data.iter().fold(0u8, |sum, b| sum.wrapping_add(*b))
+}
+
+/// Serial connection state for an ET312. The box is strictly request/response,
+/// so all traffic goes through this, behind a mutex.
+struct ET312Connection {
+  device: Arc<DeviceImpl>,
+  event_receiver: broadcast::Receiver<ButtplugDeviceEvent>,
+  buffer: Vec<u8>,
+  key: Option<u8>,
+}
+
+impl ET312Connection {
+  fn new(device: Arc<DeviceImpl>) -> Self {
+    let event_receiver = device.event_stream();
+    Self {
+      device,
+      event_receiver,
+      buffer: vec![],
+      key: None,
+    }
+  }
+
+  /// Sends a packet, appending the checksum and encrypting it if we've
+  /// exchanged keys, then waits for a reply of the given length. Sync bytes
+  /// are the only thing sent without a checksum.
+  async fn send(&mut self, packet: &[u8], reply_len: usize) -> Result<Vec<u8>, ButtplugError> {
+    let mut data = packet.to_vec();
+    if packet[..] != [ET312_SYNC_BYTE] {
+      data.push(checksum(packet));
+    }
+    if let Some(key) = self.key {
+      data.iter_mut().for_each(|b| *b ^= key);
+    }
+    // Anything still sitting around is from a previous command that timed
+    // out, and will just confuse us.
+    self.buffer.clear();
+    loop {
+      match self.event_receiver.try_recv() {
+        Ok(_) | Err(broadcast::error::TryRecvError::Lagged(_)) => continue,
+        Err(_) => break,
+      }
+    }
+    self
+      .device
+      .write_value(DeviceWriteCmd::new(Endpoint::Tx, data, false))
+      .await?;
+    self.read_reply(reply_len).await
+  }
+
+  async fn read_reply(&mut self, reply_len: usize) -> Result<Vec<u8>, ButtplugError> {
+    let mut timeout = self
+      .device
+      .clock()
+      .sleep(Duration::from_millis(ET312_RESPONSE_TIMEOUT_MS))
+      .fuse();
+    // Serial data can show up in arbitrary chunks, so keep reading until we
+    // have the whole reply.
+    while self.buffer.len() < reply_len {
+      select! {
+        event = self.event_receiver.recv().fuse() => match event {
+          Ok(ButtplugDeviceEvent::Notification(_, _, data)) => self.buffer.extend(data),
+          Ok(ButtplugDeviceEvent::Removed(_)) | Err(broadcast::error::RecvError::Closed) => {
+            return Err(et312_error("Device disconnected while waiting for reply."));
+          }
+          _ => {}
+        },
+        _ = timeout => return Err(et312_error("Timed out waiting for reply.")),
+      }
+    }
+    Ok(self.buffer.drain(..reply_len).collect())
+  }
+
+  /// Gets the box to a point where it's waiting for the start of a command.
+  async fn sync(&mut self) -> Result<(), ButtplugError> {
+    for attempt in 0..ET312_SYNC_RETRY {
+      match self.send(&[ET312_SYNC_BYTE], 1).await {
+        Ok(reply) if reply[0] == ET312_SYNC_REPLY => return Ok(()),
+        Ok(reply) => debug!(
+          "ET312 sync attempt {} got unexpected reply {:?}",
+          attempt, reply
+        ),
+        Err(err) => debug!("ET312 sync attempt {} failed: {:?}", attempt, err),
+      }
+    }
+    Err(et312_error(&format!(
+      "Box did not sync after {} attempts. If a previous session did not disconnect cleanly, the box may need to be power cycled.",
+      ET312_SYNC_RETRY
+    )))
+  }
+
+  async fn handshake(&mut self) -> Result<(), ButtplugError> {
+    self.sync().await?;
+    let reply = self.send(&[ET312_KEY_EXCHANGE_CMD, 0x00], 3).await?;
+    if reply[0] != ET312_KEY_EXCHANGE_REPLY || reply[2] != checksum(&reply[..2]) {
+      return Err(et312_error(&format!(
+        "Invalid key exchange reply {:?}",
+        reply
+      )));
+    }
+    self.key = Some(reply[1] ^ ET312_KEY_MAGIC);
+    Ok(())
+  }
+
+  /// Runs a command, resyncing and retrying if the box doesn't answer. Reads
+  /// and writes are both idempotent, so retrying is safe.
+  async fn command(&mut self, packet: &[u8], reply_len: usize) -> Result<Vec<u8>, ButtplugError> {
+    let mut last_err = None;
+    for _ in 0..ET312_COMMAND_RETRY {
+      match self.send(packet, reply_len).await {
+        Ok(reply) => return Ok(reply),
+        Err(err) => {
+          warn!("ET312 command failed, resyncing: {:?}", err);
+          last_err = Some(err);
+          self.sync().await?;
+        }
+      }
+    }
+    Err(last_err.unwrap())
+  }
+
+  async fn read_byte(&mut self, address: u16) -> Result<u8, ButtplugError> {
+    let [high, low] = address.to_be_bytes();
+    let reply = self.command(&[ET312_READ_CMD, high, low], 3).await?;
+    if reply[0] != ET312_READ_REPLY || reply[2] != checksum(&reply[..2]) {
+      return Err(et312_error(&format!("Invalid read reply {:?}", reply)));
+    }
+    Ok(reply[1])
+  }
+
+  async fn write_bytes(&mut self, address: u16, data: &[u8]) -> Result<(), ButtplugError> {
+    let [high, low] = address.to_be_bytes();
+    // Upper nibble of the command is the packet length, not counting the
+    // checksum.
+    let mut packet = vec![ET312_WRITE_CMD | (((data.len() + 3) as u8) << 4), high, low];
+    packet.extend_from_slice(data);
+    let reply = self.command(&packet, 1).await?;
+    if reply[0] != ET312_WRITE_REPLY {
+      return Err(et312_error(&format!("Invalid write reply {:?}", reply)));
+    }
+    Ok(())
+  }
+}
+
+#[derive(ButtplugProtocolProperties)]
+pub struct ErosTekET312 {
+  name: String,
+  message_attributes: DeviceMessageAttributesMap,
+  manager: Arc<Mutex<GenericCommandManager>>,
+  stop_commands: Vec<ButtplugDeviceCommandMessageUnion>,
+  connection: Option<Arc<Mutex<ET312Connection>>>,
+}
+
+impl ButtplugProtocol for ErosTekET312 {
+  fn new_protocol(
+    name: &str,
+    message_attributes: DeviceMessageAttributesMap,
+  ) -> Box<dyn ButtplugProtocol>
+  where
+    Self: Sized,
+  {
+    let manager = GenericCommandManager::new(&message_attributes);
+
+    Box::new(Self {
+      name: name.to_owned(),
+      message_attributes,
+      stop_commands: manager.get_stop_commands(),
+      manager: Arc::new(Mutex::new(manager)),
+      connection: None,
+    })
+  }
+
+  // The handshake sets up the encryption key used for everything afterward,
+  // so unlike most protocols, we need to hold on to state from
+  // initialization, which means building the protocol ourselves.
+  fn try_create(
+    device_impl: Arc<DeviceImpl>,
+    config: DeviceProtocolConfiguration,
+  ) -> BoxFuture<'static, Result<Box<dyn ButtplugProtocol>, ButtplugError>>
+  where
+    Self: Sized,
+  {
+    Box::pin(async move {
+      let endpoints = device_impl.endpoints();
+      let identifier = device_impl.name().to_owned();
+      device_impl
+        .subscribe(DeviceSubscribeCmd::new(Endpoint::Rx))
+        .await?;
+      let mut connection = ET312Connection::new(device_impl);
+      connection.handshake().await?;
+      let panel_control = connection.read_byte(ET312_ADDRESS_PANEL_CONTROL).await?;
+      connection
+        .write_bytes(
+          ET312_ADDRESS_PANEL_CONTROL,
+          &[panel_control | ET312_PANEL_CONTROL_DISABLE_POTS],
+        )
+        .await?;
+      for address in ET312_ADDRESS_LEVEL.iter() {
+        connection.write_bytes(*address, &[0]).await?;
+      }
+      let (names, attrs) = config.get_attributes(&identifier, &endpoints)?;
+      let name = names.get("en-us").unwrap().clone();
+      let manager = GenericCommandManager::new(&attrs);
+      let protocol: Box<dyn ButtplugProtocol> = Box::new(Self {
+        name,
+        message_attributes: attrs,
+        stop_commands: manager.get_stop_commands(),
+        manager: Arc::new(Mutex::new(manager)),
+        connection: Some(Arc::new(Mutex::new(connection))),
+      });
+      Ok(protocol)
+    })
+  }
+
+  fn handled_message_types() -> &'static [ButtplugDeviceMessageType]
+  where
+    Self: Sized,
+  {
+    &[ButtplugDeviceMessageType::VibrateCmd]
+  }
+}
+
+impl ButtplugProtocolCommandHandler for ErosTekET312 {
+  fn handle_vibrate_cmd(
+    &self,
+    _device: Arc<DeviceImpl>,
+    message: messages::VibrateCmd,
+  ) -> ButtplugDeviceResultFuture {
+    let manager = self.manager.clone();
+    let connection = self.connection.clone();
+    Box::pin(async move {
+      let connection = connection.ok_or_else(|| et312_error("Box has not been initialized."))?;
+      let result = manager.lock().await.update_vibration(&message, false)?;
+      if let Some(cmds) = result {
+        let mut connection = connection.lock().await;
+        for (index, cmd) in cmds.iter().enumerate() {
+          if let Some(level) = cmd {
+            connection
+              .write_bytes(ET312_ADDRESS_LEVEL[index], &[*level as u8])
+              .await?;
+          }
+        }
+      }
+      Ok(messages::Ok::default().into())
+    })
+  }
+}
+
+#[cfg(all(test, feature = "server"))]
+mod test {
+  use super::*;
+  use crate::{
+    core::messages::{StopDeviceCmd, VibrateCmd, VibrateSubcommand},
+    device::{
+      configuration_manager::{
+        DeviceConfigurationManager, DeviceConfigurationOptions, DeviceSpecifier, SerialSpecifier,
+      },
+      ButtplugDevice, DeviceImplCommand,
+    },
+    test::{TestDeviceImplCreator, TestDeviceInternal},
+    util::{async_manager, scheduler::Scheduler, stream::recv_now},
+  };
+  use futures::future;
+  use std::{
+    collections::HashMap,
+    sync::atomic::{AtomicU32, Ordering},
+  };
+
+  const MOCK_BOX_KEY: u8 = 0x10;
+
+  /// Emulates the serial side of an ET312, answering writes to Tx with
+  /// notifications, and keeping track of memory writes. Ignores the first
+  /// `dropped_syncs` sync bytes, to exercise the retry logic.
+  fn spawn_mock_et312(
+    test_device: Arc<TestDeviceInternal>,
+    memory: Arc<std::sync::Mutex<HashMap<u16, u8>>>,
+    dropped_syncs: Arc<AtomicU32>,
+  ) {
+    let receiver = test_device.get_endpoint_receiver(&Endpoint::Tx).unwrap();
+    let mut key = None;
+    // Checks for writes every millisecond, until the endpoint closes.
+    Scheduler::shared().schedule(Duration::ZERO, move || {
+      let next = loop {
+        let cmd = recv_now(&mut receiver.lock().unwrap());
+        let mut data = match cmd {
+          Some(Some(DeviceImplCommand::Write(write))) => write.data,
+          Some(_) => break None,
+          None => break Some(Duration::from_millis(1)),
+        };
+        if let Some(key) = key {
+          data.iter_mut().for_each(|b: &mut u8| *b ^= key);
+        }
+        let reply = match data[0] {
+          ET312_SYNC_BYTE => {
+            if dropped_syncs.load(Ordering::SeqCst) > 0 {
+              dropped_syncs.fetch_sub(1, Ordering::SeqCst);
+              continue;
+            }
+            vec![ET312_SYNC_REPLY]
+          }
+          ET312_KEY_EXCHANGE_CMD => {
+            key = Some(MOCK_BOX_KEY ^ ET312_KEY_MAGIC);
+            vec![
+              ET312_KEY_EXCHANGE_REPLY,
+              MOCK_BOX_KEY,
+              checksum(&[ET312_KEY_EXCHANGE_REPLY, MOCK_BOX_KEY]),
+            ]
+          }
+          ET312_READ_CMD => {
+            let address = u16::from_be_bytes([data[1], data[2]]);
+            let value = *memory.lock().unwrap().get(&address).unwrap_or(&0);
+            vec![
+              ET312_READ_REPLY,
+              value,
+              checksum(&[ET312_READ_REPLY, value]),
+            ]
+          }
+          header if header & 0x0f == ET312_WRITE_CMD => {
+            let len = (header >> 4) as usize;
+            assert_eq!(data[len], checksum(&data[..len]));
+            let address = u16::from_be_bytes([data[1], data[2]]);
+            let mut memory = memory.lock().unwrap();
+            for (offset, value) in data[3..len].iter().enumerate() {
+              memory.insert(address + offset as u16, *value);
+            }
+            vec![ET312_WRITE_REPLY]
+          }
+          _ => panic!("Unexpected ET312 packet {:?}", data),
+        };
+        // Send replies a byte at a time, like a slow serial port would.
+        for byte in reply {
+          test_device.send_event(ButtplugDeviceEvent::Notification(
+            test_device.address(),
+            Endpoint::Rx,
+            vec![byte],
+          ));
+        }
+      };
+      future::ready(next)
+    });
+  }
+
+  async fn create_et312(
+    dropped_syncs: u32,
+  ) -> (ButtplugDevice, Arc<std::sync::Mutex<HashMap<u16, u8>>>) {
+    let test_device = Arc::new(TestDeviceInternal::new("ET312", "/dev/ttyUSB0"));
+    test_device.add_endpoint(&Endpoint::Tx).await;
+    test_device.add_endpoint(&Endpoint::Rx).await;
+    let memory = Arc::new(std::sync::Mutex::new(HashMap::new()));
+    memory
+      .lock()
+      .unwrap()
+      .insert(ET312_ADDRESS_PANEL_CONTROL, 0x80);
+    spawn_mock_et312(
+      test_device.clone(),
+      memory.clone(),
+      Arc::new(AtomicU32::new(dropped_syncs)),
+    );
+    let creator = TestDeviceImplCreator::new(
+      DeviceSpecifier::Serial(SerialSpecifier::new_from_name("default-et312")),
+      test_device,
+    );
+    let device = ButtplugDevice::try_create_device(
+      Arc::new(
+        DeviceConfigurationManager::new_with_options(&DeviceConfigurationOptions {
+          allow_power_devices: true,
+          ..Default::default()
+        })
+        .unwrap(),
+      ),
+      Box::new(creator),
+    )
+    .await
+    .unwrap()
+    .unwrap();
+    (device, memory)
+  }
+
+  #[test]
+  pub fn test_erostek_et312_protocol() {
+    async_manager::block_on(async move {
+      let (device, memory) = create_et312(0).await;
+      assert_eq!(
+        memory.lock().unwrap().get(&ET312_ADDRESS_PANEL_CONTROL),
+        Some(&0x81)
+      );
+      device
+        .parse_message(
+          VibrateCmd::new(
+            0,
+            vec![
+              VibrateSubcommand::new(0, 0.5),
+              VibrateSubcommand::new(1, 1.0),
+            ],
+          )
+          .into(),
+        )
+        .await
+        .unwrap();
+      assert_eq!(
+        memory.lock().unwrap().get(&ET312_ADDRESS_LEVEL[0]),
+        Some(&128)
+      );
+      assert_eq!(
+        memory.lock().unwrap().get(&ET312_ADDRESS_LEVEL[1]),
+        Some(&255)
+      );
+      device
+        .parse_message(StopDeviceCmd::new(0).into())
+        .await
+        .unwrap();
+      assert_eq!(
+        memory.lock().unwrap().get(&ET312_ADDRESS_LEVEL[0]),
+        Some(&0)
+      );
+      assert_eq!(
+        memory.lock().unwrap().get(&ET312_ADDRESS_LEVEL[1]),
+        Some(&0)
+      );
+    });
+  }
+
+  #[test]
+  pub fn test_erostek_et312_sync_retry() {
+    async_manager::block_on(async move {
+      let (device, memory) = create_et312(3).await;
+      device
+        .parse_message(VibrateCmd::new(0, vec![VibrateSubcommand::new(1, 0.1)]).into())
+        .await
+        .unwrap();
+      assert_eq!(
+        memory.lock().unwrap().get(&ET312_ADDRESS_LEVEL[1]),
+        Some(&26)
+      );
+    });
+  }
+}
@@ -0,0 +1,197 @@
+use super::{ButtplugDeviceResultFuture, ButtplugProtocol, ButtplugProtocolCommandHandler};
+use crate::{
+  core::{
+    errors::ButtplugDeviceError,
+    messages::{
+      self, ButtplugDeviceCommandMessageUnion, ButtplugDeviceMessageType,
+      DeviceMessageAttributesMap,
+    },
+  },
+  device::{
+    protocol::{generic_command_manager::GenericCommandManager, ButtplugProtocolProperties},
+    DeviceImpl, DeviceWriteCmd, Endpoint,
+  },
+};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+// Machines can reach speeds well past what's comfortable (or safe), so a full
+// speed command only runs the motor at this percentage of its maximum.
+const MAX_SPEED_PERCENT: u32 = 60;
+
+/// Serial controlled fucking machines (F-Machine/Tempest style controllers).
+///
+/// Commands are ASCII lines, "S{percent}\n" for stroke speed and
+/// "D{percent}\n" for stroke depth. Speed is exposed as a VibrateCmd feature,
+/// depth as the position of a LinearCmd feature (duration is ignored, the
+/// controller moves at its own pace).
+///
+/// This is a power device, so it will only be created if the server allows
+/// power devices.
+#[derive(ButtplugProtocolProperties)]
+pub struct FMachine {
+  name: String,
+  message_attributes: DeviceMessageAttributesMap,
+  manager: Arc<Mutex<GenericCommandManager>>,
+  stop_commands: Vec<ButtplugDeviceCommandMessageUnion>,
+}
+
+impl ButtplugProtocol for FMachine {
+  fn new_protocol(
+    name: &str,
+    message_attributes: DeviceMessageAttributesMap,
+  ) -> Box<dyn ButtplugProtocol>
+  where
+    Self: Sized,
+  {
+    let manager = GenericCommandManager::new(&message_attributes);
+
+    Box::new(Self {
+      name: name.to_owned(),
+      message_attributes,
+      stop_commands: manager.get_stop_commands(),
+      manager: Arc::new(Mutex::new(manager)),
+    })
+  }
+
+  fn handled_message_types() -> &'static [ButtplugDeviceMessageType]
+  where
+    Self: Sized,
+  {
+    &[
+      ButtplugDeviceMessageType::VibrateCmd,
+      ButtplugDeviceMessageType::LinearCmd,
+    ]
+  }
+}
+
+impl ButtplugProtocolCommandHandler for FMachine {
+  fn handle_vibrate_cmd(
+    &self,
+    device: Arc<DeviceImpl>,
+    message: messages::VibrateCmd,
+  ) -> ButtplugDeviceResultFuture {
+    let manager = self.manager.clone();
+    Box::pin(async move {
+      let result = manager.lock().await.update_vibration(&message, false)?;
+      if let Some(cmds) = result {
+        if let Some(speed) = cmds[0] {
+          // Step count is 100, so speed is already a percentage.
+          let capped_speed = speed.min(100) * MAX_SPEED_PERCENT / 100;
+          device
+            .write_value(DeviceWriteCmd::new(
+              Endpoint::Tx,
+              format!("S{}\n", capped_speed).into_bytes(),
+              false,
+            ))
+            .await?;
+        }
+      }
+      Ok(messages::Ok::default().into())
+    })
+  }
+
+  fn handle_linear_cmd(
+    &self,
+    device: Arc<DeviceImpl>,
+    message: messages::LinearCmd,
+  ) -> ButtplugDeviceResultFuture {
+    let vector = match message.vectors().iter().find(|v| v.index == 0) {
+      Some(vector) => vector,
+      None => {
+        return ButtplugDeviceError::DeviceFeatureIndexError(1, message.vectors()[0].index).into()
+      }
+    };
+    let depth = (vector.position.max(0.0).min(1.0) * 100f64).round() as u32;
+    let fut = device.write_value(DeviceWriteCmd::new(
+      Endpoint::Tx,
+      format!("D{}\n", depth).into_bytes(),
+      false,
+    ));
+    Box::pin(async move {
+      fut.await?;
+      Ok(messages::Ok::default().into())
+    })
+  }
+}
+
+#[cfg(all(test, feature = "server"))]
+mod test {
+  use crate::{
+    core::messages::{LinearCmd, StopDeviceCmd, VectorSubcommand, VibrateCmd, VibrateSubcommand},
+    device::{
+      configuration_manager::{
+        DeviceConfigurationManager, DeviceConfigurationOptions, DeviceSpecifier, SerialSpecifier,
+      },
+      ButtplugDevice, DeviceImplCommand, DeviceWriteCmd, Endpoint,
+    },
+    test::{check_test_recv_value, TestDeviceImplCreator, TestDeviceInternal},
+    util::async_manager,
+  };
+  use std::sync::Arc;
+
+  #[test]
+  pub fn test_fmachine_protocol() {
+    async_manager::block_on(async move {
+      let test_device = Arc::new(TestDeviceInternal::new("F-Machine", "default-fmachine"));
+      test_device.add_endpoint(&Endpoint::Tx).await;
+      let creator = TestDeviceImplCreator::new(
+        DeviceSpecifier::Serial(SerialSpecifier::new_from_name("default-fmachine")),
+        test_device.clone(),
+      );
+      let config = DeviceConfigurationManager::new_with_options(&DeviceConfigurationOptions {
+        allow_power_devices: true,
+        ..Default::default()
+      })
+      .unwrap();
+      let device = ButtplugDevice::try_create_device(Arc::new(config), Box::new(creator))
+        .await
+        .unwrap()
+        .unwrap();
+      let command_receiver = test_device.get_endpoint_receiver(&Endpoint::Tx).unwrap();
+      // Full speed is capped.
+      device
+        .parse_message(VibrateCmd::new(0, vec![VibrateSubcommand::new(0, 1.0)]).into())
+        .await
+        .unwrap();
+      check_test_recv_value(
+        &command_receiver,
+        DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, b"S60\n".to_vec(), false)),
+      );
+      device
+        .parse_message(LinearCmd::new(0, vec![VectorSubcommand::new(0, 500, 0.25)]).into())
+        .await
+        .unwrap();
+      check_test_recv_value(
+        &command_receiver,
+        DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, b"D25\n".to_vec(), false)),
+      );
+      device
+        .parse_message(StopDeviceCmd::new(0).into())
+        .await
+        .unwrap();
+      check_test_recv_value(
+        &command_receiver,
+        DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, b"S0\n".to_vec(), false)),
+      );
+    });
+  }
+
+  #[test]
+  pub fn test_fmachine_requires_power_devices() {
+    async_manager::block_on(async move {
+      let test_device = Arc::new(TestDeviceInternal::new("F-Machine", "default-fmachine"));
+      let creator = TestDeviceImplCreator::new(
+        DeviceSpecifier::Serial(SerialSpecifier::new_from_name("default-fmachine")),
+        test_device,
+      );
+      assert!(ButtplugDevice::try_create_device(
+        Arc::new(DeviceConfigurationManager::default()),
+        Box::new(creator)
+      )
+      .await
+      .unwrap()
+      .is_none());
+    });
+  }
+}
@@ -1,10 +1,56 @@
-use crate::core::{
-  errors::{ButtplugDeviceError, ButtplugError},
-  messages::{
-    ButtplugDeviceCommandMessageUnion, ButtplugDeviceMessageType, DeviceMessageAttributesMap,
-    LinearCmd, RotateCmd, RotationSubcommand, VibrateCmd, VibrateSubcommand,
+use crate::{
+  core::{
+    errors::{ButtplugDeviceError, ButtplugError},
+    messages::{
+      ButtplugDeviceCommandMessageUnion, ButtplugDeviceMessageType, DeviceMessageAttributes,
+      DeviceMessageAttributesMap, LinearCmd, RotateCmd, RotationSubcommand, StopBehavior,
+      VectorSubcommand, VibrateCmd, VibrateSubcommand,
+    },
   },
+  util::clock::Clock,
 };
+use std::{sync::Arc, time::Instant};
+
+/// Estimates how far rotators have turned, for devices that can't report it,
+/// by adding up commanded speed over time. Only as good as the full speed
+/// figure it's given, and drifts as the device speeds up and slows down, so
+/// it's for things like counting turns, not exact positioning.
+struct RotationTracker {
+  clock: Arc<dyn Clock>,
+  full_speed_turns_per_second: f64,
+  /// Turns as of the last speed change (clockwise positive), speed in turns
+  /// per second, and when the speed changed, per rotator.
+  rotators: Vec<(f64, f64, Instant)>,
+}
+
+impl RotationTracker {
+  fn turns_at(rotator: &(f64, f64, Instant), now: Instant) -> f64 {
+    let (turns, speed, since) = rotator;
+    turns + speed * now.saturating_duration_since(*since).as_secs_f64()
+  }
+
+  /// `speed` is the fraction of full speed the rotator was actually set to.
+  fn set_speed(&mut self, index: usize, speed: f64, clockwise: bool) {
+    let now = self.clock.now();
+    let rotator = &mut self.rotators[index];
+    let turns = Self::turns_at(rotator, now);
+    let direction = if clockwise { 1.0 } else { -1.0 };
+    *rotator = (
+      turns,
+      speed * direction * self.full_speed_turns_per_second,
+      now,
+    );
+  }
+
+  fn positions(&self) -> Vec<f64> {
+    let now = self.clock.now();
+    self
+      .rotators
+      .iter()
+      .map(|rotator| Self::turns_at(rotator, now))
+      .collect()
+  }
+}
 
 pub struct GenericCommandManager {
   sent_vibration: bool,
@@ -17,6 +63,11 @@ pub struct GenericCommandManager {
   _linears: Vec<(u32, u32)>,
   _linear_step_counts: Vec<u32>,
   stop_commands: Vec<ButtplugDeviceCommandMessageUnion>,
+  // Per feature stop behaviors, for each message type the device supports.
+  vibration_stops: Option<Vec<StopBehavior>>,
+  rotation_stops: Option<Vec<StopBehavior>>,
+  linear_stops: Option<Vec<StopBehavior>>,
+  rotation_tracker: Option<RotationTracker>,
 }
 
 impl GenericCommandManager {
@@ -27,8 +78,9 @@ impl GenericCommandManager {
     let mut rotation_step_counts: Vec<u32> = vec![];
     let mut linears: Vec<(u32, u32)> = vec![];
     let mut linear_step_counts: Vec<u32> = vec![];
-
-    let mut stop_commands = vec![];
+    let mut vibration_stops = None;
+    let mut rotation_stops = None;
+    let mut linear_stops = None;
 
     // TODO We should probably panic here if we don't have feature and step counts?
     if let Some(attr) = attributes.get(&ButtplugDeviceMessageType::VibrateCmd) {
@@ -38,12 +90,7 @@ impl GenericCommandManager {
       if let Some(step_counts) = &attr.step_count {
         vibration_step_counts = step_counts.clone();
       }
-
-      let mut subcommands = vec![];
-      for i in 0..vibrations.len() {
-        subcommands.push(VibrateSubcommand::new(i as u32, 0.0));
-      }
-      stop_commands.push(VibrateCmd::new(0, subcommands).into());
+      vibration_stops = Some(Self::configured_stops(attr, vibrations.len()));
     }
     if let Some(attr) = attributes.get(&ButtplugDeviceMessageType::RotateCmd) {
       if let Some(count) = attr.feature_count {
@@ -52,16 +99,7 @@ impl GenericCommandManager {
       if let Some(step_counts) = &attr.step_count {
         rotation_step_counts = step_counts.clone();
       }
-
-      // TODO Can we assume clockwise is false here? We might send extra
-      // messages on Lovense since it'll require both a speed and change
-      // direction command, but is that really a big deal? We can just
-      // have it ignore the direction difference on a 0.0 speed?
-      let mut subcommands = vec![];
-      for i in 0..rotations.len() {
-        subcommands.push(RotationSubcommand::new(i as u32, 0.0, false));
-      }
-      stop_commands.push(RotateCmd::new(0, subcommands).into());
+      rotation_stops = Some(Self::configured_stops(attr, rotations.len()));
     }
     if let Some(attr) = attributes.get(&ButtplugDeviceMessageType::LinearCmd) {
       if let Some(count) = attr.feature_count {
@@ -70,9 +108,10 @@ impl GenericCommandManager {
       if let Some(step_counts) = &attr.step_count {
         linear_step_counts = step_counts.clone();
       }
+      linear_stops = Some(Self::configured_stops(attr, linears.len()));
     }
 
-    Self {
+    let mut manager = Self {
       sent_vibration: false,
       sent_rotation: false,
       _sent_linear: false,
@@ -82,8 +121,122 @@ impl GenericCommandManager {
       vibration_step_counts,
       rotation_step_counts,
       _linear_step_counts: linear_step_counts,
-      stop_commands,
+      stop_commands: vec![],
+      vibration_stops,
+      rotation_stops,
+      linear_stops,
+      rotation_tracker: None,
+    };
+    manager.stop_commands = manager.build_stop_commands();
+    manager
+  }
+
+  fn configured_stops(attr: &DeviceMessageAttributes, feature_count: usize) -> Vec<StopBehavior> {
+    (0..feature_count)
+      .map(|index| {
+        attr
+          .features
+          .as_ref()
+          .and_then(|features| features.get(index))
+          .and_then(|feature| feature.stop_behavior)
+          .unwrap_or_default()
+      })
+      .collect()
+  }
+
+  /// Changes what a feature does on stop, for protocols that know better
+  /// than the device configuration. Stop commands fetched before this was
+  /// called don't change.
+  pub fn set_stop_behavior(
+    &mut self,
+    message_type: ButtplugDeviceMessageType,
+    index: u32,
+    behavior: StopBehavior,
+  ) -> Result<(), ButtplugError> {
+    let stops = match message_type {
+      ButtplugDeviceMessageType::VibrateCmd => self.vibration_stops.as_mut(),
+      ButtplugDeviceMessageType::RotateCmd => self.rotation_stops.as_mut(),
+      ButtplugDeviceMessageType::LinearCmd => self.linear_stops.as_mut(),
+      _ => None,
+    }
+    .ok_or(ButtplugDeviceError::MessageNotSupported(message_type))?;
+    let feature_count = stops.len() as u32;
+    let stop = stops
+      .get_mut(index as usize)
+      .ok_or(ButtplugDeviceError::DeviceFeatureIndexError(feature_count, index))?;
+    *stop = behavior;
+    self.stop_commands = self.build_stop_commands();
+    Ok(())
+  }
+
+  fn build_stop_commands(&self) -> Vec<ButtplugDeviceCommandMessageUnion> {
+    // Vibrators and rotators always stop, whatever their stop behavior.
+    // Only position features have anything else to do.
+    let mut stop_commands = vec![];
+    if let Some(stops) = &self.vibration_stops {
+      let subcommands: Vec<VibrateSubcommand> = (0..stops.len() as u32)
+        .map(|index| VibrateSubcommand::new(index, 0.0))
+        .collect();
+      if !subcommands.is_empty() {
+        stop_commands.push(VibrateCmd::new(0, subcommands).into());
+      }
+    }
+    if let Some(stops) = &self.rotation_stops {
+      // TODO Can we assume clockwise is false here? We might send extra
+      // messages on Lovense since it'll require both a speed and change
+      // direction command, but is that really a big deal? We can just
+      // have it ignore the direction difference on a 0.0 speed?
+      let subcommands: Vec<RotationSubcommand> = (0..stops.len() as u32)
+        .map(|index| RotationSubcommand::new(index, 0.0, false))
+        .collect();
+      if !subcommands.is_empty() {
+        stop_commands.push(RotateCmd::new(0, subcommands).into());
+      }
     }
+    if let Some(stops) = &self.linear_stops {
+      let subcommands: Vec<VectorSubcommand> = stops
+        .iter()
+        .enumerate()
+        .filter_map(|(index, stop)| match stop {
+          StopBehavior::MoveTo { position, duration } => {
+            Some(VectorSubcommand::new(index as u32, *duration, *position))
+          }
+          _ => None,
+        })
+        .collect();
+      if !subcommands.is_empty() {
+        stop_commands.push(LinearCmd::new(0, subcommands).into());
+      }
+    }
+    stop_commands
+  }
+
+  /// Starts estimating rotator positions from here on, assuming rotators
+  /// make `full_speed_turns_per_second` turns a second at full speed. See
+  /// [rotation_positions][Self::rotation_positions].
+  pub fn track_rotation(&mut self, clock: Arc<dyn Clock>, full_speed_turns_per_second: f64) {
+    let now = clock.now();
+    let rotators = self
+      .rotations
+      .iter()
+      .enumerate()
+      .map(|(index, (speed, clockwise))| {
+        let direction = if *clockwise { 1.0 } else { -1.0 };
+        let fraction = *speed as f64 / self.rotation_step_counts[index] as f64;
+        (0.0, fraction * direction * full_speed_turns_per_second, now)
+      })
+      .collect();
+    self.rotation_tracker = Some(RotationTracker {
+      clock,
+      full_speed_turns_per_second,
+      rotators,
+    });
+  }
+
+  /// Estimated turns each rotator has made since tracking started, clockwise
+  /// positive. None if tracking is off.
+  pub fn rotation_positions(&self) -> Option<Vec<f64>> {
+    self.rotation_tracker.as_ref().map(|tracker| tracker.positions())
   }
 
   pub fn update_vibration(
@@ -217,6 +370,10 @@ impl GenericCommandManager {
       {
         self.rotations[index] = (speed, clockwise);
         result[index] = Some((speed, clockwise));
+        if let Some(tracker) = &mut self.rotation_tracker {
+          let fraction = speed as f64 / self.rotation_step_counts[index] as f64;
+          tracker.set_speed(index, fraction, clockwise);
+        }
       }
     }
 
@@ -257,10 +414,16 @@ impl GenericCommandManager {
 mod test {
 
   use super::GenericCommandManager;
-  use crate::core::messages::{
-    ButtplugDeviceMessageType, DeviceMessageAttributes, DeviceMessageAttributesMap, RotateCmd,
-    RotationSubcommand, VibrateCmd, VibrateSubcommand,
+  use crate::{
+    core::messages::{
+      ButtplugDeviceCommandMessageUnion, ButtplugDeviceMessageType, DeviceMessageAttributes,
+      DeviceMessageAttributesMap, LinearCmd, RotateCmd, RotationSubcommand, StopBehavior,
+      VectorSubcommand, VibrateCmd, VibrateSubcommand,
+    },
+    util::clock::ManualClock,
   };
+  use std::{sync::Arc, time::Duration};
+
   #[test]
   pub fn test_command_generator_vibration() {
     let mut attributes_map = DeviceMessageAttributesMap::new();
@@ -337,5 +500,98 @@ mod test {
     assert!(mgr.update_rotation(&rotate_msg_invalid).is_err());
   }
 
+  #[test]
+  pub fn test_rotation_tracking() {
+    let mut attributes_map = DeviceMessageAttributesMap::new();
+    let rotate_attributes = DeviceMessageAttributes {
+      feature_count: Some(1),
+      step_count: Some(vec![20]),
+      ..Default::default()
+    };
+    attributes_map.insert(ButtplugDeviceMessageType::RotateCmd, rotate_attributes);
+    let mut mgr = GenericCommandManager::new(&attributes_map);
+    assert_eq!(mgr.rotation_positions(), None);
+    let clock = ManualClock::new();
+    mgr.track_rotation(Arc::new(clock.clone()), 2.0);
+    let rotate =
+      |speed, clockwise| RotateCmd::new(0, vec![RotationSubcommand::new(0, speed, clockwise)]);
+    mgr.update_rotation(&rotate(0.5, true)).unwrap();
+    clock.advance(Duration::from_secs(2));
+    assert_eq!(mgr.rotation_positions(), Some(vec![2.0]));
+    mgr.update_rotation(&rotate(0.25, false)).unwrap();
+    clock.advance(Duration::from_secs(4));
+    assert_eq!(mgr.rotation_positions(), Some(vec![0.0]));
+    mgr.update_rotation(&rotate(0.0, false)).unwrap();
+    clock.advance(Duration::from_secs(4));
+    assert_eq!(mgr.rotation_positions(), Some(vec![0.0]));
+  }
+
+  #[test]
+  pub fn test_stop_behaviors() {
+    let attributes = |json: &str| {
+      let mut attributes: DeviceMessageAttributes = serde_json::from_str(json).unwrap();
+      attributes.fill_flat_attributes();
+      attributes
+    };
+    let mut attributes_map = DeviceMessageAttributesMap::new();
+    attributes_map.insert(
+      ButtplugDeviceMessageType::VibrateCmd,
+      attributes(
+        r#"{ "Features": [
+          { "ActuatorType": "Vibrate", "StepRange": [0, 20] },
+          { "ActuatorType": "Vibrate", "StepRange": [0, 20], "StopBehavior": "Stop" }
+        ] }"#,
+      ),
+    );
+    attributes_map.insert(
+      ButtplugDeviceMessageType::LinearCmd,
+      attributes(
+        r#"{ "Features": [
+          {
+            "ActuatorType": "Position",
+            "StepRange": [0, 99],
+            "StopBehavior": { "MoveTo": { "Position": 0.0, "Duration": 1500 } }
+          }
+        ] }"#,
+      ),
+    );
+    let mut mgr = GenericCommandManager::new(&attributes_map);
+    let vibrate_stop: ButtplugDeviceCommandMessageUnion = VibrateCmd::new(
+      0,
+      vec![
+        VibrateSubcommand::new(0, 0.0),
+        VibrateSubcommand::new(1, 0.0),
+      ],
+    )
+    .into();
+    let expected: Vec<ButtplugDeviceCommandMessageUnion> = vec![
+      vibrate_stop.clone(),
+      LinearCmd::new(0, vec![VectorSubcommand::new(0, 1500, 0.0)]).into(),
+    ];
+    assert_eq!(mgr.get_stop_commands(), expected);
+
+    mgr
+      .set_stop_behavior(ButtplugDeviceMessageType::LinearCmd, 0, StopBehavior::Stop)
+      .unwrap();
+    // Vibrators stop whatever they're told, so they can't be left running.
+    mgr
+      .set_stop_behavior(
+        ButtplugDeviceMessageType::VibrateCmd,
+        0,
+        StopBehavior::MoveTo {
+          position: 1.0,
+          duration: 0,
+        },
+      )
+      .unwrap();
+    assert_eq!(mgr.get_stop_commands(), vec![vibrate_stop]);
+    assert!(mgr
+      .set_stop_behavior(ButtplugDeviceMessageType::VibrateCmd, 2, StopBehavior::Stop)
+      .is_err());
+    assert!(mgr
+      .set_stop_behavior(ButtplugDeviceMessageType::RotateCmd, 0, StopBehavior::Stop)
+      .is_err());
+  }
+
   // TODO Write test for vibration stop generator
 }
@@ -0,0 +1,939 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2022 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Kiiroo's v2 and v2.1 protocols, and the devices that share them.
+//!
+//! All of these take Fleshlight style position/speed pairs for strokers and
+//! one byte per vibrator, and only differ in how the bytes are laid out and
+//! what gets sent on connect. Each protocol version is a [KiirooVariant] with
+//! its own [KiirooAttributes], and they all share the [Kiiroo] implementation.
+
+use super::{
+  fleshlight_launch_helper::get_speed, ButtplugDeviceResultFuture, ButtplugProtocol,
+  ButtplugProtocolCommandHandler, ButtplugProtocolProperties,
+};
+use crate::{
+  core::{
+    errors::ButtplugError,
+    messages::{
+      self, ButtplugDeviceCommandMessageUnion, ButtplugDeviceMessage, ButtplugDeviceMessageType,
+      DeviceMessageAttributesMap, FleshlightLaunchFW12Cmd, SensorType,
+    },
+  },
+  device::{
+    protocol::{
+      generic_command_manager::GenericCommandManager,
+      sensor_subscription_manager::SensorSubscriptionManager,
+    },
+    DeviceImpl, DeviceWriteCmd, Endpoint,
+  },
+};
+use futures::future::BoxFuture;
+use futures_timer::Delay;
+use std::{
+  marker::PhantomData,
+  sync::{
+    atomic::{AtomicU8, Ordering::SeqCst},
+    Arc,
+  },
+  time::Duration,
+};
+use tokio::sync::Mutex;
+
+/// How vibration speeds are packed into writes.
+#[derive(Clone, Copy, Debug)]
+pub enum KiirooVibratePacket {
+  /// One `[command, speed]` write per feature that changed. Command bytes
+  /// count up from the one given, one per feature.
+  PerFeature(u8),
+  /// One write with the speed of every feature, in feature order, padded
+  /// with zeros (or cut off) to the length given.
+  AllFeatures(usize),
+}
+
+/// Where the protocol versions differ.
+#[derive(Clone, Copy)]
+pub struct KiirooAttributes {
+  /// Written, with response, when the device connects. Each write is sent
+  /// 100ms after the one before it.
+  pub init_commands: &'static [(Endpoint, &'static [u8])],
+  /// Sent ahead of the position and speed bytes in linear commands.
+  pub linear_header: &'static [u8],
+  /// True if the position byte comes before the speed byte in linear
+  /// commands.
+  pub position_first: bool,
+  pub vibrate_packet: KiirooVibratePacket,
+  /// Endpoint each sensor type reports on, for devices that have sensors.
+  pub sensor_endpoint: fn(SensorType) -> Option<Endpoint>,
+  /// Endpoint that can be put in throughput mode, for strokers where every
+  /// write on it is a position update.
+  pub write_batch_endpoint: Option<Endpoint>,
+}
+
+fn no_sensors(_: SensorType) -> Option<Endpoint> {
+  None
+}
+
+// The Onyx 2 reports where its touch rings are being squeezed on rx. The
+// Launch doesn't configure any sensors, so this is never used for it.
+fn v2_sensor_endpoint(sensor_type: SensorType) -> Option<Endpoint> {
+  match sensor_type {
+    SensorType::Position => Some(Endpoint::Rx),
+    _ => None,
+  }
+}
+
+// The Pearl 2 reports its touch sensors and accelerometer on separate
+// characteristics.
+fn v2_vibrator_sensor_endpoint(sensor_type: SensorType) -> Option<Endpoint> {
+  match sensor_type {
+    SensorType::Pressure => Some(Endpoint::RxTouch),
+    SensorType::Accelerometer => Some(Endpoint::RxAccel),
+    _ => None,
+  }
+}
+
+/// A Kiiroo protocol version.
+pub trait KiirooVariant: Send + Sync + 'static {
+  const ATTRIBUTES: KiirooAttributes;
+}
+
+/// Launch and Onyx 2.
+pub struct V2;
+
+impl KiirooVariant for V2 {
+  const ATTRIBUTES: KiirooAttributes = KiirooAttributes {
+    init_commands: &[(Endpoint::Firmware, &[0x00])],
+    linear_header: &[],
+    position_first: true,
+    // Nothing on v2 vibrates, this is only here to fill the field.
+    vibrate_packet: KiirooVibratePacket::PerFeature(0x01),
+    sensor_endpoint: v2_sensor_endpoint,
+    write_batch_endpoint: Some(Endpoint::Tx),
+  };
+}
+
+/// Pearl 2, Titan, Fuse and the other v2 vibrators.
+pub struct V2Vibrator;
+
+impl KiirooVariant for V2Vibrator {
+  const ATTRIBUTES: KiirooAttributes = KiirooAttributes {
+    init_commands: &[],
+    linear_header: &[],
+    position_first: true,
+    vibrate_packet: KiirooVibratePacket::AllFeatures(3),
+    sensor_endpoint: v2_vibrator_sensor_endpoint,
+    write_batch_endpoint: None,
+  };
+}
+
+/// v2.1 devices that work as soon as they connect.
+pub struct V21;
+
+impl KiirooVariant for V21 {
+  const ATTRIBUTES: KiirooAttributes = KiirooAttributes {
+    init_commands: &[],
+    linear_header: &[0x03, 0x00],
+    position_first: false,
+    // Each control channel has its own command, starting at 0x01. Most
+    // devices only have the one, Esca style devices also have a pattern
+    // channel on 0x02.
+    vibrate_packet: KiirooVibratePacket::PerFeature(0x01),
+    sensor_endpoint: no_sensors,
+    // Vibrators here take a command per channel on the same endpoint, so a
+    // newer write doesn't always replace an older one.
+    write_batch_endpoint: None,
+  };
+}
+
+/// v2.1 strokers that need to be moved before they'll take commands.
+pub struct V21Initialized;
+
+impl KiirooVariant for V21Initialized {
+  const ATTRIBUTES: KiirooAttributes = KiirooAttributes {
+    init_commands: &[
+      (Endpoint::Tx, &[0x03, 0x00, 0x64, 0x19]),
+      (Endpoint::Tx, &[0x03, 0x00, 0x64, 0x00]),
+    ],
+    write_batch_endpoint: Some(Endpoint::Tx),
+    ..V21::ATTRIBUTES
+  };
+}
+
+pub type KiirooV2 = Kiiroo<V2>;
+pub type KiirooV2Vibrator = Kiiroo<V2Vibrator>;
+pub type KiirooV21 = Kiiroo<V21>;
+pub type KiirooV21Initialized = Kiiroo<V21Initialized>;
+
+pub struct Kiiroo<V: KiirooVariant> {
+  name: String,
+  message_attributes: DeviceMessageAttributesMap,
+  manager: Arc<Mutex<GenericCommandManager>>,
+  stop_commands: Vec<ButtplugDeviceCommandMessageUnion>,
+  previous_position: Arc<AtomicU8>,
+  sensors: SensorSubscriptionManager,
+  _variant: PhantomData<V>,
+}
+
+// The derive doesn't handle generics, so this is written out.
+impl<V: KiirooVariant> ButtplugProtocolProperties for Kiiroo<V> {
+  fn name(&self) -> &str {
+    &self.name
+  }
+
+  fn message_attributes(&self) -> DeviceMessageAttributesMap {
+    self.message_attributes.clone()
+  }
+
+  fn stop_commands(&self) -> Vec<ButtplugDeviceCommandMessageUnion> {
+    self.stop_commands.clone()
+  }
+}
+
+impl<V: KiirooVariant> ButtplugProtocol for Kiiroo<V> {
+  fn new_protocol(
+    name: &str,
+    message_attributes: DeviceMessageAttributesMap,
+  ) -> Box<dyn ButtplugProtocol> {
+    let manager = GenericCommandManager::new(&message_attributes);
+    let sensors =
+      SensorSubscriptionManager::new(&message_attributes, V::ATTRIBUTES.sensor_endpoint);
+
+    Box::new(Self {
+      name: name.to_owned(),
+      message_attributes,
+      stop_commands: manager.get_stop_commands(),
+      manager: Arc::new(Mutex::new(manager)),
+      previous_position: Arc::new(AtomicU8::new(0)),
+      sensors,
+      _variant: PhantomData,
+    })
+  }
+
+  fn write_batch_endpoint() -> Option<Endpoint> {
+    V::ATTRIBUTES.write_batch_endpoint
+  }
+
+  fn initialize(
+    device_impl: Arc<DeviceImpl>,
+  ) -> BoxFuture<'static, Result<Option<String>, ButtplugError>> {
+    let init_commands = V::ATTRIBUTES.init_commands;
+    Box::pin(async move {
+      if !init_commands.is_empty() {
+        device_impl.report_initialization_stage("Sending init commands");
+      }
+      for (index, (endpoint, data)) in init_commands.iter().enumerate() {
+        if index > 0 {
+          Delay::new(Duration::from_millis(100)).await;
+        }
+        device_impl
+          .write_value(DeviceWriteCmd::new(*endpoint, data.to_vec(), true))
+          .await?;
+      }
+      Ok(None)
+    })
+  }
+
+  fn handled_message_types() -> &'static [ButtplugDeviceMessageType]
+  where
+    Self: Sized,
+  {
+    &[
+      ButtplugDeviceMessageType::VibrateCmd,
+      ButtplugDeviceMessageType::LinearCmd,
+      ButtplugDeviceMessageType::FleshlightLaunchFW12Cmd,
+      ButtplugDeviceMessageType::SensorSubscribeCmd,
+      ButtplugDeviceMessageType::SensorUnsubscribeCmd,
+    ]
+  }
+}
+
+impl<V: KiirooVariant> ButtplugProtocolCommandHandler for Kiiroo<V> {
+  fn handle_vibrate_cmd(
+    &self,
+    device: Arc<DeviceImpl>,
+    message: messages::VibrateCmd,
+  ) -> ButtplugDeviceResultFuture {
+    // Store off result before the match, so we drop the lock ASAP.
+    let manager = self.manager.clone();
+    let vibrate_packet = V::ATTRIBUTES.vibrate_packet;
+    Box::pin(async move {
+      let match_all = matches!(vibrate_packet, KiirooVibratePacket::AllFeatures(_));
+      let result = manager.lock().await.update_vibration(&message, match_all)?;
+      if let Some(cmds) = result {
+        match vibrate_packet {
+          KiirooVibratePacket::PerFeature(first_command) => {
+            for (index, cmd) in cmds.iter().enumerate() {
+              if let Some(speed) = cmd {
+                device
+                  .write_value(DeviceWriteCmd::new(
+                    Endpoint::Tx,
+                    vec![first_command + index as u8, *speed as u8],
+                    false,
+                  ))
+                  .await?;
+              }
+            }
+          }
+          KiirooVibratePacket::AllFeatures(length) => {
+            let mut data: Vec<u8> = cmds.iter().map(|cmd| cmd.unwrap_or(0) as u8).collect();
+            data.resize(length, 0);
+            device
+              .write_value(DeviceWriteCmd::new(Endpoint::Tx, data, false))
+              .await?;
+          }
+        }
+      }
+      Ok(messages::Ok::default().into())
+    })
+  }
+
+  fn handle_linear_cmd(
+    &self,
+    device: Arc<DeviceImpl>,
+    message: messages::LinearCmd,
+  ) -> ButtplugDeviceResultFuture {
+    let v = message.vectors()[0].clone();
+    // In the protocol, we know max speed is 99, so convert here. We have to
+    // use AtomicU8 because there's no AtomicF64 yet.
+    let previous_position = self.previous_position.load(SeqCst);
+    let distance = (previous_position as f64 - (v.position * 99f64)).abs() / 99f64;
+    let fl_cmd = FleshlightLaunchFW12Cmd::new(
+      message.device_index(),
+      (v.position * 99f64) as u8,
+      (get_speed(distance, v.duration) * 99f64) as u8,
+    );
+    self.handle_fleshlight_launch_fw12_cmd(device, fl_cmd)
+  }
+
+  fn handle_fleshlight_launch_fw12_cmd(
+    &self,
+    device: Arc<DeviceImpl>,
+    message: messages::FleshlightLaunchFW12Cmd,
+  ) -> ButtplugDeviceResultFuture {
+    let previous_position = self.previous_position.clone();
+    let position = message.position();
+    let mut data = V::ATTRIBUTES.linear_header.to_vec();
+    if V::ATTRIBUTES.position_first {
+      data.extend_from_slice(&[message.position(), message.speed()]);
+    } else {
+      data.extend_from_slice(&[message.speed(), message.position()]);
+    }
+    let fut = device.write_value(DeviceWriteCmd::new(Endpoint::Tx, data, false));
+    Box::pin(async move {
+      previous_position.store(position, SeqCst);
+      fut.await?;
+      Ok(messages::Ok::default().into())
+    })
+  }
+
+  fn handle_sensor_subscribe_cmd(
+    &self,
+    device: Arc<DeviceImpl>,
+    message: messages::SensorSubscribeCmd,
+  ) -> ButtplugDeviceResultFuture {
+    self.sensors.subscribe(device, message)
+  }
+
+  fn handle_sensor_unsubscribe_cmd(
+    &self,
+    device: Arc<DeviceImpl>,
+    message: messages::SensorUnsubscribeCmd,
+  ) -> ButtplugDeviceResultFuture {
+    self.sensors.unsubscribe(device, message)
+  }
+}
+
+#[cfg(all(test, feature = "server"))]
+mod test {
+  use crate::{
+    core::messages::{
+      ButtplugDeviceMessageType, FleshlightLaunchFW12Cmd, LinearCmd, SensorReading,
+      SensorSubscribeCmd, SensorType, SensorUnsubscribeCmd, StopDeviceCmd, VectorSubcommand,
+      VibrateCmd, VibrateSubcommand,
+    },
+    device::{ButtplugDeviceEvent, DeviceImplCommand, DeviceWriteCmd, Endpoint},
+    test::{check_test_recv_empty, check_test_recv_value, new_bluetoothle_test_device},
+    util::async_manager,
+  };
+
+  const V21_INIT_WRITES: [[u8; 4]; 2] = [[0x03, 0x00, 0x64, 0x19], [0x03, 0x00, 0x64, 0x00]];
+
+  struct ExpectedBytes {
+    name: &'static str,
+    init: &'static [[u8; 4]],
+    // Writes for every vibrator being set to 0.5.
+    vibrate: &'static [&'static [u8]],
+    // Write for a 500ms move to 0.5 from the bottom.
+    linear: Option<&'static [u8]>,
+  }
+
+  // Every device name in the device config for each Kiiroo protocol, with
+  // the bytes the protocols sent before they were merged.
+  const EXPECTED_BYTES: &[ExpectedBytes] = &[
+    // kiiroo-v2
+    ExpectedBytes {
+      name: "Launch",
+      init: &[],
+      vibrate: &[],
+      linear: Some(&[49, 19]),
+    },
+    ExpectedBytes {
+      name: "Onyx2",
+      init: &[],
+      vibrate: &[],
+      linear: Some(&[49, 19]),
+    },
+    // kiiroo-v2-vibrator
+    ExpectedBytes {
+      name: "Pearl2",
+      init: &[],
+      vibrate: &[&[50, 0, 0]],
+      linear: None,
+    },
+    ExpectedBytes {
+      name: "Fuse",
+      init: &[],
+      vibrate: &[&[50, 50, 0]],
+      linear: None,
+    },
+    ExpectedBytes {
+      name: "Virtual Blowbot",
+      init: &[],
+      vibrate: &[&[50, 50, 0]],
+      linear: None,
+    },
+    ExpectedBytes {
+      name: "Titan",
+      init: &[],
+      vibrate: &[&[50, 50, 50]],
+      linear: None,
+    },
+    ExpectedBytes {
+      name: "Virtual Rabbit",
+      init: &[],
+      vibrate: &[&[50, 50, 0]],
+      linear: None,
+    },
+    // kiiroo-v21
+    ExpectedBytes {
+      name: "Titan1.1",
+      init: &[],
+      vibrate: &[&[0x01, 50]],
+      linear: Some(&[0x03, 0x00, 19, 49]),
+    },
+    ExpectedBytes {
+      name: "Cliona",
+      init: &[],
+      vibrate: &[&[0x01, 50]],
+      linear: None,
+    },
+    ExpectedBytes {
+      name: "Pearl2.1",
+      init: &[],
+      vibrate: &[&[0x01, 50]],
+      linear: None,
+    },
+    ExpectedBytes {
+      name: "OhMiBod 4.0",
+      init: &[],
+      vibrate: &[&[0x01, 50], &[0x02, 50]],
+      linear: None,
+    },
+    ExpectedBytes {
+      name: "OhMiBod LUMEN",
+      init: &[],
+      vibrate: &[&[0x01, 50]],
+      linear: None,
+    },
+    // kiiroo-v21-initialized
+    ExpectedBytes {
+      name: "Rey",
+      init: &V21_INIT_WRITES,
+      vibrate: &[],
+      linear: Some(&[0x03, 0x00, 19, 49]),
+    },
+    ExpectedBytes {
+      name: "We-Vibe Rocketman",
+      init: &V21_INIT_WRITES,
+      vibrate: &[],
+      linear: Some(&[0x03, 0x00, 19, 49]),
+    },
+    ExpectedBytes {
+      name: "Onyx2.1",
+      init: &V21_INIT_WRITES,
+      vibrate: &[],
+      linear: Some(&[0x03, 0x00, 19, 49]),
+    },
+    ExpectedBytes {
+      name: "Onyx+",
+      init: &V21_INIT_WRITES,
+      vibrate: &[],
+      linear: Some(&[0x03, 0x00, 19, 49]),
+    },
+    ExpectedBytes {
+      name: "KEON",
+      init: &V21_INIT_WRITES,
+      vibrate: &[],
+      linear: Some(&[0x03, 0x00, 19, 49]),
+    },
+  ];
+
+  #[test]
+  pub fn test_kiiroo_bytes_for_every_device() {
+    async_manager::block_on(async move {
+      for expected in EXPECTED_BYTES {
+        let (device, test_device) = new_bluetoothle_test_device(expected.name).await.unwrap();
+        let command_receiver = test_device.get_endpoint_receiver(&Endpoint::Tx).unwrap();
+        for init in expected.init {
+          check_test_recv_value(
+            &command_receiver,
+            DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, init.to_vec(), true)),
+          );
+        }
+        assert!(
+          check_test_recv_empty(&command_receiver),
+          "{}",
+          expected.name
+        );
+        let vibrator_count = device
+          .message_attributes()
+          .get(&ButtplugDeviceMessageType::VibrateCmd)
+          .and_then(|attributes| attributes.feature_count)
+          .unwrap_or(0);
+        if vibrator_count > 0 {
+          device
+            .parse_message(
+              VibrateCmd::new(
+                0,
+                (0..vibrator_count)
+                  .map(|index| VibrateSubcommand::new(index, 0.5))
+                  .collect(),
+              )
+              .into(),
+            )
+            .await
+            .unwrap();
+        }
+        for write in expected.vibrate {
+          check_test_recv_value(
+            &command_receiver,
+            DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, write.to_vec(), false)),
+          );
+        }
+        assert!(
+          check_test_recv_empty(&command_receiver),
+          "{}",
+          expected.name
+        );
+        if let Some(linear) = expected.linear {
+          device
+            .parse_message(LinearCmd::new(0, vec![VectorSubcommand::new(0, 500, 0.5)]).into())
+            .await
+            .unwrap();
+          check_test_recv_value(
+            &command_receiver,
+            DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, linear.to_vec(), false)),
+          );
+        } else {
+          assert!(device
+            .parse_message(LinearCmd::new(0, vec![VectorSubcommand::new(0, 500, 0.5)]).into())
+            .await
+            .is_err());
+        }
+        assert!(
+          check_test_recv_empty(&command_receiver),
+          "{}",
+          expected.name
+        );
+      }
+    });
+  }
+
+  #[test]
+  pub fn test_kiiroov2_fleshlight_fw12cmd() {
+    async_manager::block_on(async move {
+      let (device, test_device) = new_bluetoothle_test_device("Launch").await.unwrap();
+      let command_receiver = test_device.get_endpoint_receiver(&Endpoint::Tx).unwrap();
+      device
+        .parse_message(FleshlightLaunchFW12Cmd::new(0, 50, 50).into())
+        .await
+        .unwrap();
+      check_test_recv_value(
+        &command_receiver,
+        DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![50, 50], false)),
+      );
+    });
+  }
+
+  #[test]
+  pub fn test_kiiroov2_stop_moves_home() {
+    async_manager::block_on(async move {
+      let (device, test_device) = new_bluetoothle_test_device("Launch").await.unwrap();
+      let command_receiver = test_device.get_endpoint_receiver(&Endpoint::Tx).unwrap();
+      device
+        .parse_message(LinearCmd::new(0, vec![VectorSubcommand::new(0, 500, 0.5)]).into())
+        .await
+        .unwrap();
+      check_test_recv_value(
+        &command_receiver,
+        DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![49, 19], false)),
+      );
+      // The device config has the Launch slide back to the bottom over 1.5s.
+      device
+        .parse_message(StopDeviceCmd::new(0).into())
+        .await
+        .unwrap();
+      check_test_recv_value(
+        &command_receiver,
+        DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![0, 6], false)),
+      );
+      assert!(check_test_recv_empty(&command_receiver));
+    });
+  }
+
+  #[test]
+  pub fn test_kiiroov2_onyx2_position_sensor() {
+    async_manager::block_on(async move {
+      let (device, test_device) = new_bluetoothle_test_device("Onyx2").await.unwrap();
+      let mut sensor_receiver = device.sensor_event_stream();
+      device
+        .parse_message(SensorSubscribeCmd::new(0, 0).into())
+        .await
+        .unwrap();
+      test_device.send_event(ButtplugDeviceEvent::Notification(
+        test_device.address(),
+        Endpoint::Rx,
+        vec![0, 1, 1, 0],
+      ));
+      match sensor_receiver.recv().await.unwrap() {
+        ButtplugDeviceEvent::SensorReading(_, reading) => assert_eq!(
+          reading,
+          SensorReading::new(0, 0, SensorType::Position, vec![0, 1, 1, 0])
+        ),
+        event => panic!("Unexpected event {:?}", event),
+      }
+      // The Launch shares the protocol, but has no sensors.
+      let (launch, _) = new_bluetoothle_test_device("Launch").await.unwrap();
+      assert!(launch
+        .parse_message(SensorSubscribeCmd::new(0, 0).into())
+        .await
+        .is_err());
+    });
+  }
+
+  #[test]
+  pub fn test_kiiroov2vibrator_protocol_3_features() {
+    async_manager::block_on(async move {
+      let (device, test_device) = new_bluetoothle_test_device("Titan").await.unwrap();
+      let command_receiver = test_device.get_endpoint_receiver(&Endpoint::Tx).unwrap();
+      device
+        .parse_message(
+          VibrateCmd::new(
+            0,
+            vec![
+              VibrateSubcommand::new(0, 0.25),
+              VibrateSubcommand::new(1, 0.5),
+              VibrateSubcommand::new(2, 0.75),
+            ],
+          )
+          .into(),
+        )
+        .await
+        .unwrap();
+      check_test_recv_value(
+        &command_receiver,
+        DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![25, 50, 75], false)),
+      );
+      // Since we only created one subcommand, we should only receive one command.
+      device
+        .parse_message(
+          VibrateCmd::new(
+            0,
+            vec![
+              VibrateSubcommand::new(0, 0.25),
+              VibrateSubcommand::new(1, 0.5),
+              VibrateSubcommand::new(2, 0.75),
+            ],
+          )
+          .into(),
+        )
+        .await
+        .unwrap();
+      assert!(check_test_recv_empty(&command_receiver));
+      device
+        .parse_message(StopDeviceCmd::new(0).into())
+        .await
+        .unwrap();
+      check_test_recv_value(
+        &command_receiver,
+        DeviceImplCommand::Write(DeviceWriteCmd::new(
+          Endpoint::Tx,
+          vec![0x0, 0x0, 0x0],
+          false,
+        )),
+      );
+    });
+  }
+
+  #[test]
+  pub fn test_kiiroov2vibrator_protocol_2_features() {
+    async_manager::block_on(async move {
+      let (device, test_device) = new_bluetoothle_test_device("Fuse").await.unwrap();
+      let command_receiver = test_device.get_endpoint_receiver(&Endpoint::Tx).unwrap();
+      device
+        .parse_message(
+          VibrateCmd::new(
+            0,
+            vec![
+              VibrateSubcommand::new(0, 0.25),
+              VibrateSubcommand::new(1, 0.5),
+            ],
+          )
+          .into(),
+        )
+        .await
+        .unwrap();
+      check_test_recv_value(
+        &command_receiver,
+        DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![25, 50, 0], false)),
+      );
+      // Since we only created one subcommand, we should only receive one command.
+      device
+        .parse_message(
+          VibrateCmd::new(
+            0,
+            vec![
+              VibrateSubcommand::new(0, 0.25),
+              VibrateSubcommand::new(1, 0.5),
+            ],
+          )
+          .into(),
+        )
+        .await
+        .unwrap();
+      assert!(check_test_recv_empty(&command_receiver));
+      device
+        .parse_message(StopDeviceCmd::new(0).into())
+        .await
+        .unwrap();
+      check_test_recv_value(
+        &command_receiver,
+        DeviceImplCommand::Write(DeviceWriteCmd::new(
+          Endpoint::Tx,
+          vec![0x0, 0x0, 0x0],
+          false,
+        )),
+      );
+    });
+  }
+
+  #[test]
+  pub fn test_kiiroov2vibrator_protocol_1_features() {
+    async_manager::block_on(async move {
+      let (device, test_device) = new_bluetoothle_test_device("Pearl2").await.unwrap();
+      let command_receiver = test_device.get_endpoint_receiver(&Endpoint::Tx).unwrap();
+      device
+        .parse_message(VibrateCmd::new(0, vec![VibrateSubcommand::new(0, 0.25)]).into())
+        .await
+        .unwrap();
+      check_test_recv_value(
+        &command_receiver,
+        DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![25, 0, 0], false)),
+      );
+      // Since we only created one subcommand, we should only receive one command.
+      device
+        .parse_message(VibrateCmd::new(0, vec![VibrateSubcommand::new(0, 0.25)]).into())
+        .await
+        .unwrap();
+      assert!(check_test_recv_empty(&command_receiver));
+      device
+        .parse_message(StopDeviceCmd::new(0).into())
+        .await
+        .unwrap();
+      check_test_recv_value(
+        &command_receiver,
+        DeviceImplCommand::Write(DeviceWriteCmd::new(
+          Endpoint::Tx,
+          vec![0x0, 0x0, 0x0],
+          false,
+        )),
+      );
+    });
+  }
+
+  #[test]
+  pub fn test_kiiroov2vibrator_sensors() {
+    async_manager::block_on(async move {
+      let (device, test_device) = new_bluetoothle_test_device("Pearl2").await.unwrap();
+      let mut sensor_receiver = device.sensor_event_stream();
+      device
+        .parse_message(SensorSubscribeCmd::new(0, 1).into())
+        .await
+        .unwrap();
+      // Touch isn't subscribed, so only the accelerometer data should come
+      // through.
+      test_device.send_event(ButtplugDeviceEvent::Notification(
+        test_device.address(),
+        Endpoint::RxTouch,
+        vec![5],
+      ));
+      test_device.send_event(ButtplugDeviceEvent::Notification(
+        test_device.address(),
+        Endpoint::RxAccel,
+        vec![1, 2, 3],
+      ));
+      match sensor_receiver.recv().await.unwrap() {
+        ButtplugDeviceEvent::SensorReading(address, reading) => {
+          assert_eq!(address, test_device.address());
+          assert_eq!(
+            reading,
+            SensorReading::new(0, 1, SensorType::Accelerometer, vec![1, 2, 3])
+          );
+        }
+        event => panic!("Unexpected event {:?}", event),
+      }
+      device
+        .parse_message(SensorUnsubscribeCmd::new(0, 1).into())
+        .await
+        .unwrap();
+      device
+        .parse_message(SensorSubscribeCmd::new(0, 0).into())
+        .await
+        .unwrap();
+      test_device.send_event(ButtplugDeviceEvent::Notification(
+        test_device.address(),
+        Endpoint::RxAccel,
+        vec![4],
+      ));
+      test_device.send_event(ButtplugDeviceEvent::Notification(
+        test_device.address(),
+        Endpoint::RxTouch,
+        vec![6],
+      ));
+      match sensor_receiver.recv().await.unwrap() {
+        ButtplugDeviceEvent::SensorReading(_, reading) => assert_eq!(
+          reading,
+          SensorReading::new(0, 0, SensorType::Pressure, vec![6])
+        ),
+        event => panic!("Unexpected event {:?}", event),
+      }
+      assert!(device
+        .parse_message(SensorSubscribeCmd::new(0, 2).into())
+        .await
+        .is_err());
+    });
+  }
+
+  #[test]
+  pub fn test_kiiroov2vibrator_no_sensors() {
+    async_manager::block_on(async move {
+      let (device, _) = new_bluetoothle_test_device("Fuse").await.unwrap();
+      assert!(device
+        .parse_message(SensorSubscribeCmd::new(0, 0).into())
+        .await
+        .is_err());
+    });
+  }
+
+  #[test]
+  pub fn test_kiiroov21_vibratecmd() {
+    async_manager::block_on(async move {
+      let (device, test_device) = new_bluetoothle_test_device("Cliona").await.unwrap();
+      let command_receiver = test_device.get_endpoint_receiver(&Endpoint::Tx).unwrap();
+      assert!(check_test_recv_empty(&command_receiver));
+      device
+        .parse_message(VibrateCmd::new(0, vec![VibrateSubcommand::new(0, 0.5)]).into())
+        .await
+        .unwrap();
+      check_test_recv_value(
+        &command_receiver,
+        DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![0x01, 50], false)),
+      );
+      // Since we only created one subcommand, we should only receive one command.
+      device
+        .parse_message(VibrateCmd::new(0, vec![VibrateSubcommand::new(0, 0.5)]).into())
+        .await
+        .unwrap();
+      assert!(check_test_recv_empty(&command_receiver));
+      device
+        .parse_message(StopDeviceCmd::new(0).into())
+        .await
+        .unwrap();
+      check_test_recv_value(
+        &command_receiver,
+        DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![0x01, 0], false)),
+      );
+    });
+  }
+
+  #[test]
+  pub fn test_kiiroov21_vibratecmd_pattern_channel() {
+    async_manager::block_on(async move {
+      let (device, test_device) = new_bluetoothle_test_device("OhMiBod 4.0").await.unwrap();
+      let command_receiver = test_device.get_endpoint_receiver(&Endpoint::Tx).unwrap();
+      assert!(check_test_recv_empty(&command_receiver));
+      device
+        .parse_message(VibrateCmd::new(0, vec![VibrateSubcommand::new(1, 0.5)]).into())
+        .await
+        .unwrap();
+      // Only the pattern channel was set, so the vibrator shouldn't be touched.
+      check_test_recv_value(
+        &command_receiver,
+        DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![0x02, 50], false)),
+      );
+      assert!(check_test_recv_empty(&command_receiver));
+      device
+        .parse_message(VibrateCmd::new(0, vec![VibrateSubcommand::new(0, 0.2)]).into())
+        .await
+        .unwrap();
+      check_test_recv_value(
+        &command_receiver,
+        DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![0x01, 20], false)),
+      );
+      assert!(check_test_recv_empty(&command_receiver));
+      device
+        .parse_message(StopDeviceCmd::new(0).into())
+        .await
+        .unwrap();
+      check_test_recv_value(
+        &command_receiver,
+        DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![0x01, 0], false)),
+      );
+      check_test_recv_value(
+        &command_receiver,
+        DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![0x02, 0], false)),
+      );
+    });
+  }
+
+  #[test]
+  pub fn test_kiiroov21initialized_fleshlight_fw12cmd() {
+    async_manager::block_on(async move {
+      let (device, test_device) = new_bluetoothle_test_device("Onyx2.1").await.unwrap();
+      let command_receiver = test_device.get_endpoint_receiver(&Endpoint::Tx).unwrap();
+      for init in &V21_INIT_WRITES {
+        check_test_recv_value(
+          &command_receiver,
+          DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, init.to_vec(), true)),
+        );
+      }
+      device
+        .parse_message(FleshlightLaunchFW12Cmd::new(0, 50, 50).into())
+        .await
+        .unwrap();
+      check_test_recv_value(
+        &command_receiver,
+        DeviceImplCommand::Write(DeviceWriteCmd::new(
+          Endpoint::Tx,
+          vec![0x03, 0x00, 50, 50],
+          false,
+        )),
+      );
+    });
+  }
+}
@@ -7,7 +7,8 @@ use crate::{
   core::{
     errors::ButtplugError,
     messages::{
-      self, ButtplugDeviceCommandMessageUnion, ButtplugDeviceMessage, DeviceMessageAttributesMap,
+      self, ButtplugDeviceCommandMessageUnion, ButtplugDeviceMessage, ButtplugDeviceMessageType,
+      DeviceMessageAttributesMap,
     },
   },
   device::{
@@ -34,6 +35,75 @@ use tokio::sync::Mutex;
 const LOVENSE_COMMAND_TIMEOUT_MS: u64 = 500;
 const LOVENSE_COMMAND_RETRY: u64 = 5;
 
+/// Parsed reply to DeviceType, e.g. "P:11:0082059AD3BD;".
+#[derive(Debug, PartialEq)]
+struct LovenseDeviceType {
+  identifier: String,
+  firmware: Option<u32>,
+  /// The device's MAC address, which makes for a device ID that doesn't
+  /// change across platforms.
+  device_id: Option<String>,
+}
+
+impl LovenseDeviceType {
+  fn parse(response: &str) -> Self {
+    let fields: Vec<&str> = response.trim_end_matches(';').split(':').collect();
+    Self {
+      identifier: fields[0].to_owned(),
+      firmware: fields.get(1).and_then(|version| version.parse().ok()),
+      device_id: fields
+        .get(2)
+        .filter(|id| !id.is_empty())
+        .map(|id| (*id).to_owned()),
+    }
+  }
+}
+
+async fn query_device_type(
+  device_impl: &Arc<DeviceImpl>,
+) -> Result<LovenseDeviceType, ButtplugError> {
+  let mut event_receiver = device_impl.event_stream();
+  let mut count = 0;
+  device_impl
+    .subscribe(DeviceSubscribeCmd::new(Endpoint::Rx))
+    .await?;
+
+  loop {
+    let msg = DeviceWriteCmd::new(Endpoint::Tx, b"DeviceType;".to_vec(), false);
+    device_impl.write_value(msg).await?;
+
+    select! {
+      event = event_receiver.recv().fuse() => {
+        if let Ok(ButtplugDeviceEvent::Notification(_, _, n)) = event {
+          let type_response = std::str::from_utf8(&n).unwrap().to_owned();
+          info!("Lovense Device Type Response: {}", type_response);
+          return Ok(LovenseDeviceType::parse(&type_response));
+        } else {
+          return Err(
+            ButtplugDeviceError::ProtocolSpecificError(
+              "Lovense".to_owned(),
+              "Lovense Device disconnected while getting DeviceType info.".to_owned(),
+            )
+            .into(),
+          );
+        }
+      }
+      _ = Delay::new(Duration::from_millis(LOVENSE_COMMAND_TIMEOUT_MS)).fuse() => {
+        count += 1;
+        if count > LOVENSE_COMMAND_RETRY {
+          return Err(
+            ButtplugDeviceError::ProtocolSpecificError(
+              "Lovense".to_owned(),
+              format!("Lovense Device timed out while getting DeviceType info. ({} retries)", LOVENSE_COMMAND_RETRY).to_owned(),
+            )
+            .into()
+          );
+        }
+      }
+    }
+  }
+}
+
 #[derive(ButtplugProtocolProperties)]
 pub struct Lovense {
   name: String,
@@ -64,53 +134,48 @@ impl ButtplugProtocol for Lovense {
     device_impl: Arc<DeviceImpl>,
   ) -> BoxFuture<'static, Result<Option<String>, ButtplugError>> {
     Box::pin(async move {
-      let mut event_receiver = device_impl.event_stream();
-      let identifier;
-      let mut count = 0;
-      device_impl
-        .subscribe(DeviceSubscribeCmd::new(Endpoint::Rx))
-        .await?;
-
-      loop {
-        let msg = DeviceWriteCmd::new(Endpoint::Tx, b"DeviceType;".to_vec(), false);
-        device_impl.write_value(msg).await?;
-
-        select! {
-          event = event_receiver.recv().fuse() => {
-            if let Ok(ButtplugDeviceEvent::Notification(_, _, n)) = event {
-              let type_response = std::str::from_utf8(&n).unwrap().to_owned();
-              info!("Lovense Device Type Response: {}", type_response);
-              identifier = type_response.split(':').collect::<Vec<&str>>()[0].to_owned();
-              return Ok(Some(identifier));
-            } else {
-              return Err(
-                ButtplugDeviceError::ProtocolSpecificError(
-                  "Lovense".to_owned(),
-                  "Lovense Device disconnected while getting DeviceType info.".to_owned(),
-                )
-                .into(),
-              );
-            }
-          }
-          _ = Delay::new(Duration::from_millis(LOVENSE_COMMAND_TIMEOUT_MS)).fuse() => {
-            count += 1;
-            if count > LOVENSE_COMMAND_RETRY {
-              return Err(
-                ButtplugDeviceError::ProtocolSpecificError(
-                  "Lovense".to_owned(),
-                  format!("Lovense Device timed out while getting DeviceType info. ({} retries)", LOVENSE_COMMAND_RETRY).to_owned(),
-                )
-                .into()
-              );
-            }
-          }
-        }
+      device_impl.report_initialization_stage("Reading device type");
+      let device_type = query_device_type(&device_impl).await?;
+      debug!(
+        "Lovense device type {} firmware {:?}",
+        device_type.identifier, device_type.firmware
+      );
+      if let Some(device_id) = &device_type.device_id {
+        device_impl.set_device_id(device_id);
       }
+      Ok(Some(device_type.identifier))
     })
   }
+
+  fn handled_message_types() -> &'static [ButtplugDeviceMessageType]
+  where
+    Self: Sized,
+  {
+    &[
+      ButtplugDeviceMessageType::VibrateCmd,
+      ButtplugDeviceMessageType::RotateCmd,
+      ButtplugDeviceMessageType::BatteryLevelCmd,
+    ]
+  }
 }
 
 impl ButtplugProtocolCommandHandler for Lovense {
+  fn supports_power_off(&self) -> bool {
+    true
+  }
+
+  fn handle_power_off(&self, device: Arc<DeviceImpl>) -> ButtplugDeviceResultFuture {
+    let fut = device.write_value(DeviceWriteCmd::new(
+      Endpoint::Tx,
+      b"PowerOff;".to_vec(),
+      false,
+    ));
+    Box::pin(async move {
+      fut.await?;
+      Ok(messages::Ok::default().into())
+    })
+  }
+
   fn handle_vibrate_cmd(
     &self,
     device: Arc<DeviceImpl>,
@@ -233,6 +298,8 @@ impl ButtplugProtocolCommandHandler for Lovense {
           ButtplugDeviceEvent::Connected(_) => {
             unimplemented!("Shouldn't get here as device will always be connected.");
           }
+          // Only comes through the sensor stream, which we aren't listening to.
+          ButtplugDeviceEvent::SensorReading(..) => {}
         }
       }
       Err(
@@ -248,3 +315,27 @@ impl ButtplugProtocolCommandHandler for Lovense {
 
 // TODO Gonna need to add the ability to set subscribe data in tests before
 // writing Lovense tests. Oops.
+#[cfg(test)]
+mod test {
+  use super::LovenseDeviceType;
+
+  #[test]
+  fn test_lovense_device_type_parse() {
+    assert_eq!(
+      LovenseDeviceType::parse("P:11:0082059AD3BD;"),
+      LovenseDeviceType {
+        identifier: "P".to_owned(),
+        firmware: Some(11),
+        device_id: Some("0082059AD3BD".to_owned()),
+      }
+    );
+    assert_eq!(
+      LovenseDeviceType::parse("W:x:;"),
+      LovenseDeviceType {
+        identifier: "W".to_owned(),
+        firmware: None,
+        device_id: None,
+      }
+    );
+  }
+}
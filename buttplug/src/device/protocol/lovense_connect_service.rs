@@ -1,7 +1,8 @@
 use super::{ButtplugDeviceResultFuture, ButtplugProtocol, ButtplugProtocolCommandHandler};
 use crate::{
   core::messages::{
-    self, ButtplugDeviceCommandMessageUnion, ButtplugDeviceMessage, DeviceMessageAttributesMap,
+    self, ButtplugDeviceCommandMessageUnion, ButtplugDeviceMessage, ButtplugDeviceMessageType,
+      DeviceMessageAttributesMap,
   },
   device::{
     protocol::{generic_command_manager::GenericCommandManager, ButtplugProtocolProperties},
@@ -39,6 +40,17 @@ impl ButtplugProtocol for LovenseConnectService {
       rotation_direction: Arc::new(AtomicBool::new(false)),
     })
   }
+
+  fn handled_message_types() -> &'static [ButtplugDeviceMessageType]
+  where
+    Self: Sized,
+  {
+    &[
+      ButtplugDeviceMessageType::VibrateCmd,
+      ButtplugDeviceMessageType::RotateCmd,
+      ButtplugDeviceMessageType::BatteryLevelCmd,
+    ]
+  }
 }
 
 impl ButtplugProtocolCommandHandler for LovenseConnectService {
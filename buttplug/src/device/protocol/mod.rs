@@ -1,12 +1,14 @@
 // Since users can pick and choose protocols, we need all of these to be public.
 pub mod aneros;
+pub mod autoblow_ai_ultra;
 pub mod cachito;
+pub mod dg_lab_coyote;
+pub mod dg_lab_coyote_v3;
+pub mod erostek_et312;
+pub mod fmachine;
 pub mod fleshlight_launch_helper;
 pub mod generic_command_manager;
-pub mod kiiroo_v2;
-pub mod kiiroo_v21;
-pub mod kiiroo_v21_initialized;
-pub mod kiiroo_v2_vibrator;
+pub mod kiiroo;
 pub mod lelof1s;
 pub mod libo_elle;
 pub mod libo_shark;
@@ -19,16 +21,20 @@ pub mod magic_motion_v2;
 pub mod magic_motion_v3;
 pub mod maxpro;
 pub mod motorbunny;
+pub mod mqtt_generic;
 pub mod mysteryvibe;
 pub mod nobra;
 pub mod picobong;
 pub mod prettylove;
 pub mod raw_protocol;
 pub mod realov;
+pub mod rez_trancevibrator;
+pub mod sensor_subscription_manager;
 pub mod svakom;
 pub mod thehandy;
 pub mod vibratissimo;
 pub mod vorze_sa;
+pub mod waveform;
 pub mod wevibe;
 pub mod wevibe8bit;
 pub mod xinput;
@@ -41,32 +47,56 @@ use crate::{
     errors::{ButtplugDeviceError, ButtplugError},
     messages::{
       self, ButtplugDeviceCommandMessageUnion, ButtplugDeviceMessage, ButtplugDeviceMessageType,
-      ButtplugMessage, DeviceMessageAttributesMap, RawReading, VibrateCmd, VibrateSubcommand,
+      ButtplugMessage, DeviceMessageAttributesMap, RawReading,
     },
   },
   device::{
     configuration_manager::DeviceProtocolConfiguration, ButtplugDeviceResultFuture, DeviceReadCmd,
-    Endpoint,
+    Endpoint, WriteBatchConfig,
   },
 };
 use futures::future::{self, BoxFuture};
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 use dashmap::DashMap;
 
 pub type TryCreateProtocolFunc = fn(Arc<DeviceImpl>, DeviceProtocolConfiguration) -> BoxFuture<'static, Result<Box<dyn ButtplugProtocol>, ButtplugError>>;
 
-pub fn add_to_protocol_map<T>(map: &DashMap<String, TryCreateProtocolFunc>, protocol_name: &str) where T: ButtplugProtocol {
-  map.insert(protocol_name.to_owned(), T::try_create as TryCreateProtocolFunc);
+pub type HandledMessageTypesFunc = fn() -> &'static [ButtplugDeviceMessageType];
+
+/// What's kept for each protocol implementation that's been added: how to
+/// create it, and what it can handle.
+#[derive(Clone, Copy)]
+pub struct ProtocolFactory {
+  pub try_create: TryCreateProtocolFunc,
+  pub handled_message_types: HandledMessageTypesFunc,
+}
+
+pub fn add_to_protocol_map<T>(map: &DashMap<String, ProtocolFactory>, protocol_name: &str)
+where
+  T: ButtplugProtocol,
+{
+  map.insert(
+    protocol_name.to_owned(),
+    ProtocolFactory {
+      try_create: T::try_create as TryCreateProtocolFunc,
+      handled_message_types: T::handled_message_types as HandledMessageTypesFunc,
+    },
+  );
 }
 
-pub fn get_default_protocol_map() -> DashMap<String, TryCreateProtocolFunc> {
+pub fn get_default_protocol_map() -> DashMap<String, ProtocolFactory> {
   let map = DashMap::new();
   add_to_protocol_map::<aneros::Aneros>(&map, "aneros");
+  add_to_protocol_map::<autoblow_ai_ultra::AutoblowAIUltra>(&map, "autoblow-ai-ultra");
   add_to_protocol_map::<cachito::Cachito>(&map, "cachito");
-  add_to_protocol_map::<kiiroo_v2::KiirooV2>(&map, "kiiroo-v2");
-  add_to_protocol_map::<kiiroo_v2_vibrator::KiirooV2Vibrator>(&map, "kiiroo-v2-vibrator");
-  add_to_protocol_map::<kiiroo_v21::KiirooV21>(&map, "kiiroo-v21");
-  add_to_protocol_map::<kiiroo_v21_initialized::KiirooV21Initialized>(&map, "kiiroo-v21-initialized");
+  add_to_protocol_map::<dg_lab_coyote::DGLabCoyote>(&map, "dg-lab-coyote");
+  add_to_protocol_map::<dg_lab_coyote_v3::DGLabCoyoteV3>(&map, "dg-lab-coyote-v3");
+  add_to_protocol_map::<erostek_et312::ErosTekET312>(&map, "erostek-et312");
+  add_to_protocol_map::<fmachine::FMachine>(&map, "fmachine");
+  add_to_protocol_map::<kiiroo::KiirooV2>(&map, "kiiroo-v2");
+  add_to_protocol_map::<kiiroo::KiirooV2Vibrator>(&map, "kiiroo-v2-vibrator");
+  add_to_protocol_map::<kiiroo::KiirooV21>(&map, "kiiroo-v21");
+  add_to_protocol_map::<kiiroo::KiirooV21Initialized>(&map, "kiiroo-v21-initialized");
   add_to_protocol_map::<lelof1s::LeloF1s>(&map, "lelo-f1s");
   add_to_protocol_map::<libo_elle::LiboElle>(&map, "libo-elle");
   add_to_protocol_map::<libo_shark::LiboShark>(&map, "libo-shark");
@@ -79,12 +109,14 @@ pub fn get_default_protocol_map() -> DashMap<String, TryCreateProtocolFunc> {
   add_to_protocol_map::<magic_motion_v3::MagicMotionV3>(&map, "magic-motion-3");
   add_to_protocol_map::<maxpro::Maxpro>(&map, "maxpro");
   add_to_protocol_map::<motorbunny::Motorbunny>(&map, "motorbunny");
+  add_to_protocol_map::<mqtt_generic::MqttGeneric>(&map, "mqtt-generic");
   add_to_protocol_map::<mysteryvibe::MysteryVibe>(&map, "mysteryvibe");
   add_to_protocol_map::<nobra::Nobra>(&map, "nobra");
   add_to_protocol_map::<picobong::Picobong>(&map, "picobong");
   add_to_protocol_map::<prettylove::PrettyLove>(&map, "prettylove");
   add_to_protocol_map::<raw_protocol::RawProtocol>(&map, "raw");
   add_to_protocol_map::<realov::Realov>(&map, "realov");
+  add_to_protocol_map::<rez_trancevibrator::RezTranceVibrator>(&map, "rez-trancevibrator");
   add_to_protocol_map::<svakom::Svakom>(&map, "svakom");
   add_to_protocol_map::<thehandy::TheHandy>(&map, "thehandy");
   add_to_protocol_map::<vibratissimo::Vibratissimo>(&map, "vibratissimo");
@@ -97,6 +129,27 @@ pub fn get_default_protocol_map() -> DashMap<String, TryCreateProtocolFunc> {
   map
 }
 
+/// Turns on throughput mode (see [WriteBatchConfig]) for an endpoint if the
+/// protocol options set `stream-interval-ms`, the time between writes.
+///
+/// The default [ButtplugProtocol::try_create] calls this for the endpoint
+/// from [ButtplugProtocol::write_batch_endpoint], protocols that override it
+/// can call it to take the same option.
+pub fn enable_configured_write_batching(
+  device_impl: &DeviceImpl,
+  config: &DeviceProtocolConfiguration,
+  endpoint: Endpoint,
+) -> Result<(), ButtplugDeviceError> {
+  let interval_ms: Option<u64> = config.options().get("stream-interval-ms")?;
+  if let Some(interval_ms) = interval_ms {
+    device_impl.enable_write_batching(WriteBatchConfig::new(
+      endpoint,
+      Duration::from_millis(interval_ms),
+    ));
+  }
+  Ok(())
+}
+
 pub trait ButtplugProtocol: ButtplugProtocolCommandHandler + Sync {
   fn try_create(
     device_impl: Arc<DeviceImpl>,
@@ -107,18 +160,43 @@ pub trait ButtplugProtocol: ButtplugProtocolCommandHandler + Sync {
   {
     let endpoints = device_impl.endpoints();
     let name = device_impl.name().to_owned();
-    let init_fut = Self::initialize(device_impl);
+    let batch_endpoint = Self::write_batch_endpoint();
+    let init_fut = Self::initialize(device_impl.clone());
     Box::pin(async move {
       let device_identifier = match init_fut.await {
         Ok(maybe_ident) => maybe_ident.unwrap_or(name),
         Err(err) => return Err(err),
       };
+      // Only once initialization is done, so init writes aren't dropped.
+      if let Some(endpoint) = batch_endpoint {
+        enable_configured_write_batching(&device_impl, &config, endpoint)?;
+      }
       let (names, attrs) = config.get_attributes(&device_identifier, &endpoints)?;
       let name = names.get("en-us").unwrap().clone();
       Ok(Self::new_protocol(&name, attrs))
     })
   }
 
+  /// Endpoint that takes a stream of updates where each write replaces the
+  /// one before it, like stroker positions, if the protocol's devices can
+  /// use throughput mode on it. Protocols that don't return one never batch
+  /// writes, whatever their options say.
+  fn write_batch_endpoint() -> Option<Endpoint>
+  where
+    Self: Sized,
+  {
+    None
+  }
+
+  /// Device messages the protocol's command handler implements itself.
+  /// Stopping, raw messages, and battery reads from the standard BLE battery
+  /// endpoint work for every protocol and don't need to be listed. The
+  /// protocol support matrix goes by this, so it only claims messages a
+  /// protocol can actually do something with.
+  fn handled_message_types() -> &'static [ButtplugDeviceMessageType]
+  where
+    Self: Sized;
+
   fn initialize(
     _device_impl: Arc<DeviceImpl>,
   ) -> BoxFuture<'static, Result<Option<String>, ButtplugError>>
@@ -195,9 +273,18 @@ pub trait ButtplugProtocolProperties {
         &ButtplugDeviceMessageType::RSSILevelCmd,
         &self.message_attributes(),
       ),
-      // We translate SingleMotorVibrateCmd into Vibrate, so this one is special.
+      ButtplugDeviceCommandMessageUnion::SensorSubscribeCmd(_) => check_message_support(
+        &ButtplugDeviceMessageType::SensorSubscribeCmd,
+        &self.message_attributes(),
+      ),
+      // Unsubscribing is allowed anywhere subscribing is, so it doesn't get
+      // its own attributes.
+      ButtplugDeviceCommandMessageUnion::SensorUnsubscribeCmd(_) => check_message_support(
+        &ButtplugDeviceMessageType::SensorSubscribeCmd,
+        &self.message_attributes(),
+      ),
       ButtplugDeviceCommandMessageUnion::SingleMotorVibrateCmd(_) => check_message_support(
-        &ButtplugDeviceMessageType::VibrateCmd,
+        &ButtplugDeviceMessageType::SingleMotorVibrateCmd,
         &self.message_attributes(),
       ),
       ButtplugDeviceCommandMessageUnion::StopDeviceCmd(_) => check_message_support(
@@ -263,6 +350,12 @@ pub trait ButtplugProtocolCommandHandler: Send + ButtplugProtocolProperties {
       ButtplugDeviceCommandMessageUnion::RSSILevelCmd(msg) => {
         self.handle_rssi_level_cmd(device, msg)
       }
+      ButtplugDeviceCommandMessageUnion::SensorSubscribeCmd(msg) => {
+        self.handle_sensor_subscribe_cmd(device, msg)
+      }
+      ButtplugDeviceCommandMessageUnion::SensorUnsubscribeCmd(msg) => {
+        self.handle_sensor_unsubscribe_cmd(device, msg)
+      }
     }
   }
 
@@ -288,48 +381,16 @@ pub trait ButtplugProtocolCommandHandler: Send + ButtplugProtocolProperties {
     })
   }
 
+  // SingleMotorVibrateCmd, FleshlightLaunchFW12Cmd and VorzeA10CycloneCmd are
+  // translated to generic messages before they get to the protocol, unless
+  // the protocol lists them in its device configuration. Only protocols that
+  // do need to override these.
   fn handle_single_motor_vibrate_cmd(
     &self,
-    device: Arc<DeviceImpl>,
+    _device: Arc<DeviceImpl>,
     message: messages::SingleMotorVibrateCmd,
   ) -> ButtplugDeviceResultFuture {
-    // Time for sadness! In order to handle conversion of
-    // SingleMotorVibrateCmd, we need to know how many vibrators a device
-    // has. We don't actually know that until we get to the protocol level,
-    // so we're stuck parsing this here. Since we can assume
-    // SingleMotorVibrateCmd will ALWAYS map to vibration, we can convert to
-    // VibrateCmd here and save ourselves having to handle it in every
-    // protocol, meaning spec v0 and v1 programs will still be forward
-    // compatible with vibrators.
-    let vibrator_count;
-    if let Some(attr) = self
-      .message_attributes()
-      .get(&ButtplugDeviceMessageType::VibrateCmd)
-    {
-      if let Some(count) = attr.feature_count {
-        vibrator_count = count as usize;
-      } else {
-        return ButtplugDeviceError::ProtocolRequirementError(format!(
-          "{} needs to support VibrateCmd with a feature count to use SingleMotorVibrateCmd.",
-          self.name()
-        ))
-        .into();
-      }
-    } else {
-      return ButtplugDeviceError::ProtocolRequirementError(format!(
-        "{} needs to support VibrateCmd to use SingleMotorVibrateCmd.",
-        self.name()
-      ))
-      .into();
-    }
-    let speed = message.speed();
-    let mut cmds = vec![];
-    for i in 0..vibrator_count {
-      cmds.push(VibrateSubcommand::new(i as u32, speed));
-    }
-    let mut vibrate_cmd = VibrateCmd::new(message.device_index(), cmds);
-    vibrate_cmd.set_id(message.id());
-    self.handle_command(device, vibrate_cmd.into())
+    self.command_unimplemented(print_type_of(&message))
   }
 
   fn handle_raw_write_cmd(
@@ -387,6 +448,19 @@ pub trait ButtplugProtocolCommandHandler: Send + ButtplugProtocolProperties {
     )))
   }
 
+  /// True if the protocol can turn the device off, see
+  /// [ButtplugProtocolCommandHandler::handle_power_off].
+  fn supports_power_off(&self) -> bool {
+    false
+  }
+
+  /// Turns the device off entirely, for devices that have a command for it,
+  /// so they don't sit idle draining their battery once a session is over.
+  /// Most devices disconnect right after.
+  fn handle_power_off(&self, _device: Arc<DeviceImpl>) -> ButtplugDeviceResultFuture {
+    self.command_unimplemented("PowerOff")
+  }
+
   fn handle_vorze_a10_cyclone_cmd(
     &self,
     _device: Arc<DeviceImpl>,
@@ -466,4 +540,20 @@ pub trait ButtplugProtocolCommandHandler: Send + ButtplugProtocolProperties {
   ) -> ButtplugDeviceResultFuture {
     self.command_unimplemented(print_type_of(&message))
   }
+
+  fn handle_sensor_subscribe_cmd(
+    &self,
+    _device: Arc<DeviceImpl>,
+    message: messages::SensorSubscribeCmd,
+  ) -> ButtplugDeviceResultFuture {
+    self.command_unimplemented(print_type_of(&message))
+  }
+
+  fn handle_sensor_unsubscribe_cmd(
+    &self,
+    _device: Arc<DeviceImpl>,
+    message: messages::SensorUnsubscribeCmd,
+  ) -> ButtplugDeviceResultFuture {
+    self.command_unimplemented(print_type_of(&message))
+  }
 }
@@ -0,0 +1,139 @@
+use super::{ButtplugDeviceResultFuture, ButtplugProtocol, ButtplugProtocolCommandHandler};
+use crate::{
+  core::messages::{
+    self, ButtplugDeviceCommandMessageUnion, ButtplugDeviceMessageType, DeviceMessageAttributesMap,
+  },
+  device::{
+    protocol::{generic_command_manager::GenericCommandManager, ButtplugProtocolProperties},
+    DeviceImpl, DeviceWriteCmd, Endpoint,
+  },
+};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+/// Plain text protocol for DIY devices on an MQTT broker. Speeds are published
+/// as decimal step values, which is what ESPHome number entities take. Devices
+/// with more than one motor get "index:value" instead.
+#[derive(ButtplugProtocolProperties)]
+pub struct MqttGeneric {
+  name: String,
+  message_attributes: DeviceMessageAttributesMap,
+  manager: Arc<Mutex<GenericCommandManager>>,
+  stop_commands: Vec<ButtplugDeviceCommandMessageUnion>,
+}
+
+impl ButtplugProtocol for MqttGeneric {
+  fn new_protocol(
+    name: &str,
+    message_attributes: DeviceMessageAttributesMap,
+  ) -> Box<dyn ButtplugProtocol>
+  where
+    Self: Sized,
+  {
+    let manager = GenericCommandManager::new(&message_attributes);
+
+    Box::new(Self {
+      name: name.to_owned(),
+      message_attributes,
+      stop_commands: manager.get_stop_commands(),
+      manager: Arc::new(Mutex::new(manager)),
+    })
+  }
+
+  fn handled_message_types() -> &'static [ButtplugDeviceMessageType]
+  where
+    Self: Sized,
+  {
+    &[ButtplugDeviceMessageType::VibrateCmd]
+  }
+}
+
+impl ButtplugProtocolCommandHandler for MqttGeneric {
+  fn handle_vibrate_cmd(
+    &self,
+    device: Arc<DeviceImpl>,
+    message: messages::VibrateCmd,
+  ) -> ButtplugDeviceResultFuture {
+    let manager = self.manager.clone();
+    Box::pin(async move {
+      let result = manager.lock().await.update_vibration(&message, false)?;
+      if let Some(cmds) = result {
+        let single_motor = cmds.len() == 1;
+        for (index, cmd) in cmds.iter().enumerate() {
+          if let Some(speed) = cmd {
+            let payload = if single_motor {
+              format!("{}", speed)
+            } else {
+              format!("{}:{}", index, speed)
+            };
+            device
+              .write_value(DeviceWriteCmd::new(
+                Endpoint::Tx,
+                payload.into_bytes(),
+                false,
+              ))
+              .await?;
+          }
+        }
+      }
+      Ok(messages::Ok::default().into())
+    })
+  }
+}
+
+#[cfg(all(test, feature = "server"))]
+mod test {
+  use crate::{
+    core::messages::{StopDeviceCmd, VibrateCmd, VibrateSubcommand},
+    device::{
+      configuration_manager::{DeviceConfigurationManager, DeviceSpecifier, MQTTSpecifier},
+      ButtplugDevice, DeviceImplCommand, DeviceWriteCmd, Endpoint,
+    },
+    test::{
+      check_test_recv_empty, check_test_recv_value, TestDeviceImplCreator, TestDeviceInternal,
+    },
+    util::async_manager,
+  };
+  use std::sync::Arc;
+
+  #[test]
+  pub fn test_mqtt_generic_protocol() {
+    async_manager::block_on(async move {
+      let test_device = Arc::new(TestDeviceInternal::new(
+        "generic-dual-vibrator",
+        "mqtt-test-device",
+      ));
+      test_device.add_endpoint(&Endpoint::Tx).await;
+      let creator = TestDeviceImplCreator::new(
+        DeviceSpecifier::MQTT(MQTTSpecifier::new_from_identifier("generic-dual-vibrator")),
+        test_device.clone(),
+      );
+      let device = ButtplugDevice::try_create_device(
+        Arc::new(DeviceConfigurationManager::default()),
+        Box::new(creator),
+      )
+      .await
+      .unwrap()
+      .unwrap();
+      let command_receiver = test_device.get_endpoint_receiver(&Endpoint::Tx).unwrap();
+      device
+        .parse_message(VibrateCmd::new(0, vec![VibrateSubcommand::new(1, 0.5)]).into())
+        .await
+        .unwrap();
+      check_test_recv_value(
+        &command_receiver,
+        DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, b"1:50".to_vec(), false)),
+      );
+      assert!(check_test_recv_empty(&command_receiver));
+      device
+        .parse_message(StopDeviceCmd::new(0).into())
+        .await
+        .unwrap();
+      check_test_recv_value(
+        &command_receiver,
+        DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, b"1:0".to_vec(), false)),
+      );
+      assert!(check_test_recv_empty(&command_receiver));
+    });
+  }
+}
@@ -2,16 +2,18 @@ use super::{ButtplugDeviceResultFuture, ButtplugProtocol, ButtplugProtocolComman
 use crate::{
   core::{
     errors::ButtplugError,
-    messages::{self, ButtplugDeviceCommandMessageUnion, DeviceMessageAttributesMap},
+    messages::{
+      self, ButtplugDeviceCommandMessageUnion, ButtplugDeviceMessageType,
+      DeviceMessageAttributesMap,
+    },
   },
   device::{
+    configuration_manager::DeviceProtocolConfiguration,
     protocol::{generic_command_manager::GenericCommandManager, ButtplugProtocolProperties},
     DeviceImpl, DeviceWriteCmd, Endpoint,
   },
-  util::async_manager,
 };
 use futures::future::BoxFuture;
-use futures_timer::Delay;
 use std::{
   sync::{
     atomic::{AtomicBool, Ordering},
@@ -21,11 +23,18 @@ use std::{
 };
 use tokio::sync::{Mutex, RwLock};
 
+// Mysteryvibe devices don't take commands for single motors. Instead, we send
+// a frame with a speed byte for every motor (6 on the Crescendo), over and
+// over, and the device runs whatever the last frame said. Commands that come in
+// between frames are merged into the next one, so however many motors change,
+// it only takes one write.
+//
 // Time between Mysteryvibe update commands, in milliseconds. This is basically
 // a best guess derived from watching packet timing a few years ago.
 //
 // Thelemic vibrator. Neat.
 //
+// Can be changed with the command-delay-ms protocol option.
 const MYSTERYVIBE_COMMAND_DELAY_MS: u64 = 93;
 
 #[derive(ButtplugProtocolProperties)]
@@ -36,22 +45,65 @@ pub struct MysteryVibe {
   stop_commands: Vec<ButtplugDeviceCommandMessageUnion>,
   current_command: Arc<RwLock<Vec<u8>>>,
   updater_running: Arc<AtomicBool>,
+  command_delay: Duration,
 }
 
-impl ButtplugProtocol for MysteryVibe {
-  fn new_protocol(
+impl MysteryVibe {
+  fn new(
     name: &str,
     message_attributes: DeviceMessageAttributesMap,
-  ) -> Box<dyn ButtplugProtocol> {
+    command_delay: Duration,
+  ) -> Self {
     let manager = GenericCommandManager::new(&message_attributes);
 
-    Box::new(Self {
+    Self {
       name: name.to_owned(),
       message_attributes,
       stop_commands: manager.get_stop_commands(),
       manager: Arc::new(Mutex::new(manager)),
       updater_running: Arc::new(AtomicBool::new(false)),
       current_command: Arc::new(RwLock::new(vec![0u8, 0, 0, 0, 0, 0])),
+      command_delay,
+    }
+  }
+}
+
+impl ButtplugProtocol for MysteryVibe {
+  fn new_protocol(
+    name: &str,
+    message_attributes: DeviceMessageAttributesMap,
+  ) -> Box<dyn ButtplugProtocol> {
+    Box::new(Self::new(
+      name,
+      message_attributes,
+      Duration::from_millis(MYSTERYVIBE_COMMAND_DELAY_MS),
+    ))
+  }
+
+  // Same as the default, except we need the update loop timing from the
+  // protocol options.
+  fn try_create(
+    device_impl: Arc<DeviceImpl>,
+    config: DeviceProtocolConfiguration,
+  ) -> BoxFuture<'static, Result<Box<dyn ButtplugProtocol>, ButtplugError>>
+  where
+    Self: Sized,
+  {
+    Box::pin(async move {
+      let command_delay_ms = config
+        .options()
+        .get_or("command-delay-ms", MYSTERYVIBE_COMMAND_DELAY_MS)?;
+      let endpoints = device_impl.endpoints();
+      let name = device_impl.name().to_owned();
+      Self::initialize(device_impl).await?;
+      let (names, attrs) = config.get_attributes(&name, &endpoints)?;
+      let name = names.get("en-us").unwrap().clone();
+      let protocol: Box<dyn ButtplugProtocol> = Box::new(Self::new(
+        &name,
+        attrs,
+        Duration::from_millis(command_delay_ms),
+      ));
+      Ok(protocol)
     })
   }
 
@@ -65,25 +117,45 @@ impl ButtplugProtocol for MysteryVibe {
       Ok(None)
     })
   }
-}
 
-async fn vibration_update_handler(device: Arc<DeviceImpl>, command_holder: Arc<RwLock<Vec<u8>>>) {
-  info!("Entering Mysteryvibe Control Loop");
-  let mut current_command = command_holder.read().await.clone();
-  while device
-    .write_value(DeviceWriteCmd::new(
-      Endpoint::TxVibrate,
-      current_command,
-      false,
-    ))
-    .await
-    .is_ok()
+  fn handled_message_types() -> &'static [ButtplugDeviceMessageType]
+  where
+    Self: Sized,
   {
-    Delay::new(Duration::from_millis(MYSTERYVIBE_COMMAND_DELAY_MS)).await;
-    current_command = command_holder.read().await.clone();
-    info!("MV Command: {:?}", current_command);
+    &[ButtplugDeviceMessageType::VibrateCmd]
   }
-  info!("Mysteryvibe control loop exiting, most likely due to device disconnection.");
+}
+
+// Writes the latest frame every command_delay, on the device's scheduler, until
+// a write fails.
+fn start_vibration_updates(
+  device: Arc<DeviceImpl>,
+  command_holder: Arc<RwLock<Vec<u8>>>,
+  command_delay: Duration,
+) {
+  info!("Starting Mysteryvibe update job");
+  let scheduler = device.scheduler().clone();
+  scheduler.schedule(Duration::ZERO, move || {
+    let device = device.clone();
+    let command_holder = command_holder.clone();
+    async move {
+      let current_command = command_holder.read().await.clone();
+      info!("MV Command: {:?}", current_command);
+      if device
+        .write_value(DeviceWriteCmd::new(
+          Endpoint::TxVibrate,
+          current_command,
+          false,
+        ))
+        .await
+        .is_err()
+      {
+        info!("Mysteryvibe update job exiting, most likely due to device disconnection.");
+        return None;
+      }
+      Some(command_delay)
+    }
+  });
 }
 
 impl ButtplugProtocolCommandHandler for MysteryVibe {
@@ -95,33 +167,105 @@ impl ButtplugProtocolCommandHandler for MysteryVibe {
     let manager = self.manager.clone();
     let current_command = self.current_command.clone();
     let update_running = self.updater_running.clone();
+    let command_delay = self.command_delay;
     Box::pin(async move {
       let result = manager.lock().await.update_vibration(&message, true)?;
       info!("MV Result: {:?}", result);
       if result.is_none() {
         return Ok(messages::Ok::default().into());
       }
-      let write_mutex = current_command.clone();
-      let mut command_writer = write_mutex.write().await;
+      // Since we matched all motors, every entry is set, and this is the whole
+      // frame.
       let command: Vec<u8> = result
         .unwrap()
         .into_iter()
         .map(|x| x.unwrap() as u8)
         .collect();
-      *command_writer = command;
-      if !update_running.load(Ordering::SeqCst) {
-        async_manager::spawn(
-          async move { vibration_update_handler(device, current_command).await },
-        )
-        .unwrap();
-        update_running.store(true, Ordering::SeqCst);
+      *current_command.write().await = command;
+      // Swap so that commands racing each other can't start two loops.
+      if !update_running.swap(true, Ordering::SeqCst) {
+        start_vibration_updates(device, current_command, command_delay);
       }
       Ok(messages::Ok::default().into())
     })
   }
 }
 
-// TODO Write some tests!
-//
-// At least, once I figure out how to do that with the weird timing on this
-// thing.
+#[cfg(all(test, feature = "server"))]
+mod test {
+  use super::MYSTERYVIBE_COMMAND_DELAY_MS;
+  use crate::{
+    core::messages::{VibrateCmd, VibrateSubcommand},
+    device::{
+      configuration_manager::{BluetoothLESpecifier, DeviceConfigurationManager, DeviceSpecifier},
+      ButtplugDevice, DeviceImplCommand, DeviceWriteCmd, Endpoint,
+    },
+    test::{
+      check_test_recv_empty, check_test_recv_value, TestDeviceImplCreator, TestDeviceInternal,
+    },
+    util::{async_manager, clock::ManualClock},
+  };
+  use std::{sync::Arc, time::Duration};
+
+  // The update job keeps writing frames on its own schedule, so this test
+  // steps the device's clock from one frame to the next.
+  #[test]
+  pub fn test_mysteryvibe_crescendo_frames() {
+    async_manager::block_on(async move {
+      let clock = ManualClock::new();
+      let mut test_device = TestDeviceInternal::new("MV Crescendo", "mysteryvibe-test");
+      test_device.set_clock(Arc::new(clock.clone()));
+      let test_device = Arc::new(test_device);
+      let creator = TestDeviceImplCreator::new(
+        DeviceSpecifier::BluetoothLE(BluetoothLESpecifier::new_from_device("MV Crescendo")),
+        test_device.clone(),
+      );
+      let device = ButtplugDevice::try_create_device(
+        Arc::new(DeviceConfigurationManager::default()),
+        Box::new(creator),
+      )
+      .await
+      .unwrap()
+      .unwrap();
+      let command_receiver = test_device
+        .get_endpoint_receiver(&Endpoint::TxVibrate)
+        .unwrap();
+      device
+        .parse_message(VibrateCmd::new(0, vec![VibrateSubcommand::new(2, 0.5)]).into())
+        .await
+        .unwrap();
+      // The job writes a frame, then waits for the next one.
+      clock.wait_for_sleeps(1).await;
+      check_test_recv_value(
+        &command_receiver,
+        DeviceImplCommand::Write(DeviceWriteCmd::new(
+          Endpoint::TxVibrate,
+          vec![0, 0, 28, 0, 0, 0],
+          false,
+        )),
+      );
+      assert!(check_test_recv_empty(&command_receiver));
+      // Both of these land before the next frame, so they go out together.
+      device
+        .parse_message(VibrateCmd::new(0, vec![VibrateSubcommand::new(0, 1.0)]).into())
+        .await
+        .unwrap();
+      device
+        .parse_message(VibrateCmd::new(0, vec![VibrateSubcommand::new(5, 0.25)]).into())
+        .await
+        .unwrap();
+      assert!(check_test_recv_empty(&command_receiver));
+      clock.advance(Duration::from_millis(MYSTERYVIBE_COMMAND_DELAY_MS));
+      clock.wait_for_sleeps(2).await;
+      check_test_recv_value(
+        &command_receiver,
+        DeviceImplCommand::Write(DeviceWriteCmd::new(
+          Endpoint::TxVibrate,
+          vec![56, 0, 28, 0, 0, 14],
+          false,
+        )),
+      );
+      assert!(check_test_recv_empty(&command_receiver));
+    });
+  }
+}
@@ -1,6 +1,8 @@
 use super::{ButtplugProtocol, ButtplugProtocolCommandHandler};
 use crate::{
-  core::messages::{ButtplugDeviceCommandMessageUnion, DeviceMessageAttributesMap},
+  core::messages::{
+    ButtplugDeviceCommandMessageUnion, ButtplugDeviceMessageType, DeviceMessageAttributesMap,
+  },
   device::protocol::ButtplugProtocolProperties,
 };
 
@@ -22,6 +24,13 @@ impl ButtplugProtocol for RawProtocol {
       stop_commands: vec![],
     })
   }
+
+  fn handled_message_types() -> &'static [ButtplugDeviceMessageType]
+  where
+    Self: Sized,
+  {
+    &[]
+  }
 }
 
 impl ButtplugProtocolCommandHandler for RawProtocol {}
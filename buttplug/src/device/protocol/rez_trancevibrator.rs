@@ -0,0 +1,133 @@
+use super::{ButtplugDeviceResultFuture, ButtplugProtocol, ButtplugProtocolCommandHandler};
+use crate::{
+  core::messages::{
+    self, ButtplugDeviceCommandMessageUnion, ButtplugDeviceMessageType, DeviceMessageAttributesMap,
+  },
+  device::{
+    protocol::{generic_command_manager::GenericCommandManager, ButtplugProtocolProperties},
+    DeviceImpl, DeviceWriteCmd, Endpoint,
+  },
+};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+#[derive(ButtplugProtocolProperties)]
+pub struct RezTranceVibrator {
+  name: String,
+  message_attributes: DeviceMessageAttributesMap,
+  manager: Arc<Mutex<GenericCommandManager>>,
+  stop_commands: Vec<ButtplugDeviceCommandMessageUnion>,
+}
+
+impl ButtplugProtocol for RezTranceVibrator {
+  fn new_protocol(
+    name: &str,
+    message_attributes: DeviceMessageAttributesMap,
+  ) -> Box<dyn ButtplugProtocol>
+  where
+    Self: Sized,
+  {
+    let manager = GenericCommandManager::new(&message_attributes);
+
+    Box::new(Self {
+      name: name.to_owned(),
+      message_attributes,
+      stop_commands: manager.get_stop_commands(),
+      manager: Arc::new(Mutex::new(manager)),
+    })
+  }
+
+  fn handled_message_types() -> &'static [ButtplugDeviceMessageType]
+  where
+    Self: Sized,
+  {
+    &[ButtplugDeviceMessageType::VibrateCmd]
+  }
+}
+
+impl ButtplugProtocolCommandHandler for RezTranceVibrator {
+  fn handle_vibrate_cmd(
+    &self,
+    device: Arc<DeviceImpl>,
+    message: messages::VibrateCmd,
+  ) -> ButtplugDeviceResultFuture {
+    let manager = self.manager.clone();
+    Box::pin(async move {
+      let result = manager.lock().await.update_vibration(&message, false)?;
+      if let Some(cmds) = result {
+        if let Some(speed) = cmds[0] {
+          // Speed is sent as the value of vendor control request 1, with no
+          // data stage.
+          device
+            .write_value(DeviceWriteCmd::new(
+              Endpoint::TxVendorControl,
+              vec![0x01, speed as u8, 0x00, 0x00, 0x00],
+              false,
+            ))
+            .await?;
+        }
+      }
+      Ok(messages::Ok::default().into())
+    })
+  }
+}
+
+#[cfg(all(test, feature = "server"))]
+mod test {
+  use crate::{
+    core::messages::{StopDeviceCmd, VibrateCmd, VibrateSubcommand},
+    device::{
+      configuration_manager::{DeviceConfigurationManager, DeviceSpecifier, USBSpecifier},
+      ButtplugDevice, DeviceImplCommand, DeviceWriteCmd, Endpoint,
+    },
+    test::{check_test_recv_value, TestDeviceImplCreator, TestDeviceInternal},
+    util::async_manager,
+  };
+  use std::sync::Arc;
+
+  #[test]
+  pub fn test_rez_trancevibrator_protocol() {
+    async_manager::block_on(async move {
+      let test_device = Arc::new(TestDeviceInternal::new("Rez TranceVibrator", "usb-001-002"));
+      test_device.add_endpoint(&Endpoint::TxVendorControl).await;
+      let creator = TestDeviceImplCreator::new(
+        DeviceSpecifier::USB(USBSpecifier::new(2889, 1615)),
+        test_device.clone(),
+      );
+      let device = ButtplugDevice::try_create_device(
+        Arc::new(DeviceConfigurationManager::default()),
+        Box::new(creator),
+      )
+      .await
+      .unwrap()
+      .unwrap();
+      let command_receiver = test_device
+        .get_endpoint_receiver(&Endpoint::TxVendorControl)
+        .unwrap();
+      device
+        .parse_message(VibrateCmd::new(0, vec![VibrateSubcommand::new(0, 0.5)]).into())
+        .await
+        .unwrap();
+      check_test_recv_value(
+        &command_receiver,
+        DeviceImplCommand::Write(DeviceWriteCmd::new(
+          Endpoint::TxVendorControl,
+          vec![0x01, 128, 0x00, 0x00, 0x00],
+          false,
+        )),
+      );
+      device
+        .parse_message(StopDeviceCmd::new(0).into())
+        .await
+        .unwrap();
+      check_test_recv_value(
+        &command_receiver,
+        DeviceImplCommand::Write(DeviceWriteCmd::new(
+          Endpoint::TxVendorControl,
+          vec![0x01, 0x00, 0x00, 0x00, 0x00],
+          false,
+        )),
+      );
+    });
+  }
+}
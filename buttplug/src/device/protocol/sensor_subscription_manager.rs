@@ -0,0 +1,160 @@
+use crate::{
+  core::{
+    errors::{ButtplugDeviceError, ButtplugError},
+    messages::{
+      self, ButtplugDeviceMessageType, ButtplugMessage, DeviceMessageAttributesMap, SensorReading,
+      SensorSubscribeCmd, SensorType, SensorUnsubscribeCmd,
+    },
+  },
+  device::{
+    ButtplugDeviceEvent, ButtplugDeviceResultFuture, DeviceImpl, DeviceSubscribeCmd,
+    DeviceUnsubscribeCmd, Endpoint,
+  },
+  util::async_manager,
+};
+use futures::{future, select, FutureExt};
+use std::{
+  collections::HashMap,
+  sync::{Arc, Mutex},
+};
+use tokio::sync::broadcast;
+use tokio_util::sync::CancellationToken;
+
+/// Turns endpoint notifications into SensorReading messages for protocols
+/// whose sensors each live on their own endpoint.
+///
+/// Sensors are listed, in index order, by the SensorType attribute of
+/// SensorSubscribeCmd. Notification payloads are passed through with one
+/// value per byte.
+pub struct SensorSubscriptionManager {
+  sensors: Vec<SensorType>,
+  endpoint_for_type: fn(SensorType) -> Option<Endpoint>,
+  /// Cancels the forwarding task of each subscribed sensor.
+  subscriptions: Arc<Mutex<HashMap<u32, CancellationToken>>>,
+}
+
+impl SensorSubscriptionManager {
+  pub fn new(
+    attributes: &DeviceMessageAttributesMap,
+    endpoint_for_type: fn(SensorType) -> Option<Endpoint>,
+  ) -> Self {
+    let sensors = attributes
+      .get(&ButtplugDeviceMessageType::SensorSubscribeCmd)
+      .and_then(|attrs| attrs.sensor_type.clone())
+      .unwrap_or_default();
+    Self {
+      sensors,
+      endpoint_for_type,
+      subscriptions: Arc::new(Mutex::new(HashMap::new())),
+    }
+  }
+
+  fn sensor(&self, sensor_index: u32) -> Result<(SensorType, Endpoint), ButtplugError> {
+    let sensor_type = *self.sensors.get(sensor_index as usize).ok_or_else(|| {
+      ButtplugError::from(ButtplugDeviceError::DeviceFeatureIndexError(
+        self.sensors.len() as u32,
+        sensor_index,
+      ))
+    })?;
+    let endpoint = (self.endpoint_for_type)(sensor_type).ok_or_else(|| {
+      ButtplugError::from(ButtplugDeviceError::ProtocolRequirementError(format!(
+        "Protocol has no endpoint for {:?} sensors.",
+        sensor_type
+      )))
+    })?;
+    Ok((sensor_type, endpoint))
+  }
+
+  pub fn subscribe(
+    &self,
+    device: Arc<DeviceImpl>,
+    message: SensorSubscribeCmd,
+  ) -> ButtplugDeviceResultFuture {
+    let id = message.id();
+    let sensor_index = message.sensor_index();
+    let (sensor_type, endpoint) = match self.sensor(sensor_index) {
+      Ok(sensor) => sensor,
+      Err(err) => return Box::pin(future::ready(Err(err))),
+    };
+    let subscriptions = self.subscriptions.clone();
+    Box::pin(async move {
+      if subscriptions.lock().unwrap().contains_key(&sensor_index) {
+        return Ok(messages::Ok::new(id).into());
+      }
+      // Start listening before subscribing, so we can't miss the first
+      // notification.
+      let mut event_receiver = device.event_stream();
+      device.subscribe(DeviceSubscribeCmd::new(endpoint)).await?;
+      let token = CancellationToken::new();
+      if let Some(previous) = subscriptions
+        .lock()
+        .unwrap()
+        .insert(sensor_index, token.clone())
+      {
+        // Lost a race with another subscribe, only keep one task around.
+        previous.cancel();
+      }
+      // Don't keep the device alive just because a sensor is subscribed.
+      let device = Arc::downgrade(&device);
+      async_manager::spawn_named("sensor subscription forwarder", async move {
+        loop {
+          let event = select! {
+            _ = token.cancelled().fuse() => break,
+            event = event_receiver.recv().fuse() => event,
+          };
+          match event {
+            Ok(ButtplugDeviceEvent::Notification(_, notification_endpoint, data))
+              if notification_endpoint == endpoint =>
+            {
+              let device = match device.upgrade() {
+                Some(device) => device,
+                None => break,
+              };
+              device.send_sensor_reading(SensorReading::new(
+                0,
+                sensor_index,
+                sensor_type,
+                data.iter().map(|b| *b as i32).collect(),
+              ));
+            }
+            Ok(ButtplugDeviceEvent::Removed(_)) | Err(broadcast::error::RecvError::Closed) => break,
+            _ => {}
+          }
+        }
+      })
+      .unwrap();
+      Ok(messages::Ok::new(id).into())
+    })
+  }
+
+  /// Ends a sensor subscription: the task forwarding its notifications is
+  /// stopped, and the endpoint is unsubscribed. Unsubscribing a sensor that
+  /// isn't subscribed does nothing.
+  pub fn unsubscribe(
+    &self,
+    device: Arc<DeviceImpl>,
+    message: SensorUnsubscribeCmd,
+  ) -> ButtplugDeviceResultFuture {
+    let id = message.id();
+    let endpoint = match self.sensor(message.sensor_index()) {
+      Ok((_, endpoint)) => endpoint,
+      Err(err) => return Box::pin(future::ready(Err(err))),
+    };
+    let token = self
+      .subscriptions
+      .lock()
+      .unwrap()
+      .remove(&message.sensor_index());
+    Box::pin(async move {
+      if let Some(token) = token {
+        // Stops the forwarding task right away, instead of whenever the
+        // next notification shows up.
+        token.cancel();
+        device
+          .unsubscribe(DeviceUnsubscribeCmd::new(endpoint))
+          .await?;
+      }
+      Ok(messages::Ok::new(id).into())
+    })
+  }
+}
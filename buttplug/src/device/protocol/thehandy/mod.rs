@@ -1,12 +1,10 @@
-use super::{
-  fleshlight_launch_helper, ButtplugDeviceResultFuture, ButtplugProtocol,
-  ButtplugProtocolCommandHandler,
-};
+use super::{ButtplugDeviceResultFuture, ButtplugProtocol, ButtplugProtocolCommandHandler};
 use crate::{
   core::{
     errors::{ButtplugDeviceError, ButtplugError},
     messages::{
-      self, ButtplugDeviceCommandMessageUnion, ButtplugDeviceMessage, DeviceMessageAttributesMap,
+      self, ButtplugDeviceCommandMessageUnion, ButtplugDeviceMessageType,
+      DeviceMessageAttributesMap,
     },
   },
   device::{
@@ -15,10 +13,7 @@ use crate::{
   },
 };
 use futures::future::{self, BoxFuture};
-use std::sync::{
-  atomic::{AtomicU8, Ordering},
-  Arc,
-};
+use std::sync::Arc;
 // use tokio::sync::Mutex;
 use prost::Message;
 
@@ -36,11 +31,6 @@ pub struct TheHandy {
   message_attributes: DeviceMessageAttributesMap,
   //_manager: Arc<Mutex<GenericCommandManager>>,
   stop_commands: Vec<ButtplugDeviceCommandMessageUnion>,
-  // The generic command manager would normally handle this storage, but the
-  // only reason we're retaining tracking information is to build our fucking
-  // timing calculation for the fleshlight command backport. I am so mad right
-  // now.
-  previous_position: Arc<AtomicU8>,
 }
 
 impl ButtplugProtocol for TheHandy {
@@ -58,7 +48,6 @@ impl ButtplugProtocol for TheHandy {
       message_attributes,
       stop_commands: manager.get_stop_commands(),
       //_manager: Arc::new(Mutex::new(manager)),
-      previous_position: Arc::new(AtomicU8::new(0)),
     })
   }
 
@@ -108,6 +97,7 @@ impl ButtplugProtocol for TheHandy {
       //
       // If they ever change this, I quit (or will just update the device config).
 
+      device_impl.report_initialization_stage("Setting up session");
       let mut sec_buf = vec![];
       session_req.encode(&mut sec_buf).unwrap();
       device_impl.write_value(DeviceWriteCmd::new(Endpoint::Firmware, sec_buf, false));
@@ -123,35 +113,16 @@ impl ButtplugProtocol for TheHandy {
       Ok(None)
     })
   }
-}
 
-impl ButtplugProtocolCommandHandler for TheHandy {
-  fn handle_fleshlight_launch_fw12_cmd(
-    &self,
-    device: Arc<DeviceImpl>,
-    message: messages::FleshlightLaunchFW12Cmd,
-  ) -> ButtplugDeviceResultFuture {
-    // Oh good. ScriptPlayer hasn't updated to LinearCmd yet so now I have to
-    // work backward from fleshlight to my own Linear format that Handy uses.
-    //
-    // Building this library was a mistake.
-    let goal_position = message.position() as f64 / 100f64;
-    let previous_position = self.previous_position.load(Ordering::SeqCst) as f64 / 100f64;
-    self
-      .previous_position
-      .store(message.position(), Ordering::SeqCst);
-    let distance = (goal_position - previous_position).abs();
-    let duration =
-      fleshlight_launch_helper::get_duration(distance, message.speed() as f64 / 99f64) as u32;
-    self.handle_linear_cmd(
-      device,
-      messages::LinearCmd::new(
-        message.device_index(),
-        vec![messages::VectorSubcommand::new(0, duration, goal_position)],
-      ),
-    )
+  fn handled_message_types() -> &'static [ButtplugDeviceMessageType]
+  where
+    Self: Sized,
+  {
+    &[ButtplugDeviceMessageType::LinearCmd]
   }
+}
 
+impl ButtplugProtocolCommandHandler for TheHandy {
   fn handle_linear_cmd(
     &self,
     device: Arc<DeviceImpl>,
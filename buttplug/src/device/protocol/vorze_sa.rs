@@ -1,15 +1,33 @@
 use super::{ButtplugDeviceResultFuture, ButtplugProtocol, ButtplugProtocolCommandHandler};
 use crate::{
-  core::messages::{
-    self, ButtplugDeviceCommandMessageUnion, ButtplugDeviceMessage, DeviceMessageAttributesMap,
+  core::{
+    errors::{ButtplugDeviceError, ButtplugError},
+    messages::{
+      self, ButtplugDeviceCommandMessageUnion, ButtplugDeviceMessageType, ButtplugMessage,
+      DeviceMessageAttributesMap, SensorReading, SensorType,
+    },
   },
   device::{
+    configuration_manager::DeviceProtocolConfiguration,
     protocol::{generic_command_manager::GenericCommandManager, ButtplugProtocolProperties},
     DeviceImpl, DeviceWriteCmd, Endpoint,
   },
+  util::clock::{Clock, SystemClock},
+};
+use futures::future::{self, BoxFuture};
+use std::{
+  sync::{Arc, Mutex as StdMutex},
+  time::Duration,
 };
-use std::sync::Arc;
 use tokio::sync::Mutex;
+use tokio_util::sync::CancellationToken;
+
+/// Rough full speed of the rotating Vorze toys, used to estimate rotation
+/// position. Devices vary, so the `rotation-turns-per-second` protocol option
+/// can replace it.
+const VORZE_FULL_SPEED_TURNS_PER_SECOND: f64 = 1.0;
+/// How often estimated rotation is reported to sensor subscribers.
+const ROTATION_REPORT_INTERVAL: Duration = Duration::from_millis(100);
 
 #[derive(ButtplugProtocolProperties)]
 pub struct VorzeSA {
@@ -17,22 +35,119 @@ pub struct VorzeSA {
   message_attributes: DeviceMessageAttributesMap,
   manager: Arc<Mutex<GenericCommandManager>>,
   stop_commands: Vec<ButtplugDeviceCommandMessageUnion>,
+  // The hardware has no position feedback, so the "position sensor" on
+  // rotating devices is the command manager's estimate, reported while
+  // subscribed.
+  rotation_reporter: StdMutex<Option<CancellationToken>>,
 }
 
-impl ButtplugProtocol for VorzeSA {
-  fn new_protocol(
+impl VorzeSA {
+  fn new(
     name: &str,
     message_attributes: DeviceMessageAttributesMap,
-  ) -> Box<dyn ButtplugProtocol> {
-    let manager = GenericCommandManager::new(&message_attributes);
-
-    Box::new(Self {
+    clock: Arc<dyn Clock>,
+    full_speed_turns_per_second: f64,
+  ) -> Self {
+    let mut manager = GenericCommandManager::new(&message_attributes);
+    if message_attributes.contains_key(&ButtplugDeviceMessageType::RotateCmd) {
+      manager.track_rotation(clock, full_speed_turns_per_second);
+    }
+    Self {
       name: name.to_owned(),
       message_attributes,
       stop_commands: manager.get_stop_commands(),
       manager: Arc::new(Mutex::new(manager)),
+      rotation_reporter: StdMutex::new(None),
+    }
+  }
+}
+
+impl ButtplugProtocol for VorzeSA {
+  fn new_protocol(
+    name: &str,
+    message_attributes: DeviceMessageAttributesMap,
+  ) -> Box<dyn ButtplugProtocol> {
+    Box::new(Self::new(
+      name,
+      message_attributes,
+      Arc::new(SystemClock),
+      VORZE_FULL_SPEED_TURNS_PER_SECOND,
+    ))
+  }
+
+  // Same as the default, except rotation estimates need the device's clock
+  // and the full speed option.
+  fn try_create(
+    device_impl: Arc<DeviceImpl>,
+    config: DeviceProtocolConfiguration,
+  ) -> BoxFuture<'static, Result<Box<dyn ButtplugProtocol>, ButtplugError>>
+  where
+    Self: Sized,
+  {
+    Box::pin(async move {
+      let full_speed_turns_per_second = config
+        .options()
+        .get_or("rotation-turns-per-second", VORZE_FULL_SPEED_TURNS_PER_SECOND)?;
+      let endpoints = device_impl.endpoints();
+      let (names, attrs) = config.get_attributes(device_impl.name(), &endpoints)?;
+      let name = names.get("en-us").unwrap().clone();
+      let protocol: Box<dyn ButtplugProtocol> = Box::new(Self::new(
+        &name,
+        attrs,
+        device_impl.clock(),
+        full_speed_turns_per_second,
+      ));
+      Ok(protocol)
     })
   }
+
+  fn handled_message_types() -> &'static [ButtplugDeviceMessageType]
+  where
+    Self: Sized,
+  {
+    &[
+      ButtplugDeviceMessageType::VibrateCmd,
+      ButtplugDeviceMessageType::RotateCmd,
+      ButtplugDeviceMessageType::SensorSubscribeCmd,
+      ButtplugDeviceMessageType::SensorUnsubscribeCmd,
+    ]
+  }
+}
+
+/// Sends estimated rotation, in degrees turned since the device connected
+/// (clockwise positive), whenever it changes, until `token` is cancelled.
+fn report_rotation(
+  device: &Arc<DeviceImpl>,
+  manager: Arc<Mutex<GenericCommandManager>>,
+  token: CancellationToken,
+) {
+  let weak_device = Arc::downgrade(device);
+  let last_degrees = Arc::new(StdMutex::new(None));
+  device.scheduler().schedule(Duration::ZERO, move || {
+    let device = weak_device.clone();
+    let manager = manager.clone();
+    let last_degrees = last_degrees.clone();
+    let token = token.clone();
+    async move {
+      if token.is_cancelled() {
+        return None;
+      }
+      let device = device.upgrade()?;
+      let turns = manager
+        .lock()
+        .await
+        .rotation_positions()
+        .and_then(|positions| positions.first().copied())
+        .unwrap_or_default();
+      let degrees = (turns * 360.0).round() as i32;
+      let mut last_degrees = last_degrees.lock().unwrap();
+      if *last_degrees != Some(degrees) {
+        device.send_sensor_reading(SensorReading::new(0, 0, SensorType::Position, vec![degrees]));
+        *last_degrees = Some(degrees);
+      }
+      Some(ROTATION_REPORT_INTERVAL)
+    }
+  });
 }
 
 #[repr(u8)]
@@ -108,33 +223,61 @@ impl ButtplugProtocolCommandHandler for VorzeSA {
     })
   }
 
-  fn handle_vorze_a10_cyclone_cmd(
+  fn handle_sensor_subscribe_cmd(
     &self,
     device: Arc<DeviceImpl>,
-    msg: messages::VorzeA10CycloneCmd,
+    message: messages::SensorSubscribeCmd,
   ) -> ButtplugDeviceResultFuture {
-    self.handle_rotate_cmd(
-      device,
-      messages::RotateCmd::new(
-        msg.device_index(),
-        vec![messages::RotationSubcommand::new(
-          0,
-          msg.speed() as f64 / 99f64,
-          msg.clockwise(),
-        )],
-      ),
-    )
+    if message.sensor_index() != 0 {
+      return ButtplugDeviceError::DeviceFeatureIndexError(1, message.sensor_index()).into();
+    }
+    let mut reporter = self.rotation_reporter.lock().unwrap();
+    if reporter.is_none() {
+      let token = CancellationToken::new();
+      report_rotation(&device, self.manager.clone(), token.clone());
+      *reporter = Some(token);
+    }
+    Box::pin(future::ready(Ok(messages::Ok::new(message.id()).into())))
+  }
+
+  fn handle_sensor_unsubscribe_cmd(
+    &self,
+    _device: Arc<DeviceImpl>,
+    message: messages::SensorUnsubscribeCmd,
+  ) -> ButtplugDeviceResultFuture {
+    if message.sensor_index() != 0 {
+      return ButtplugDeviceError::DeviceFeatureIndexError(1, message.sensor_index()).into();
+    }
+    if let Some(token) = self.rotation_reporter.lock().unwrap().take() {
+      token.cancel();
+    }
+    Box::pin(future::ready(Ok(messages::Ok::new(message.id()).into())))
   }
 }
 
 #[cfg(all(test, feature = "server"))]
 mod test {
   use crate::{
-    core::messages::{RotateCmd, RotationSubcommand, StopDeviceCmd, VibrateCmd, VibrateSubcommand},
-    device::{DeviceImplCommand, DeviceWriteCmd, Endpoint},
-    test::{check_test_recv_empty, check_test_recv_value, new_bluetoothle_test_device},
-    util::async_manager,
+    core::messages::{
+      RotateCmd, RotationSubcommand, SensorReading, SensorSubscribeCmd, SensorType,
+      SensorUnsubscribeCmd, StopDeviceCmd, VibrateCmd, VibrateSubcommand, VorzeA10CycloneCmd,
+    },
+    device::{ButtplugDeviceEvent, DeviceImplCommand, DeviceWriteCmd, Endpoint},
+    test::{
+      check_test_recv_empty, check_test_recv_value, new_bluetoothle_test_device,
+      new_bluetoothle_test_device_with_clock,
+    },
+    util::{async_manager, clock::ManualClock},
   };
+  use std::{sync::Arc, time::Duration};
+  use tokio::sync::broadcast;
+
+  async fn next_reading(receiver: &mut broadcast::Receiver<ButtplugDeviceEvent>) -> SensorReading {
+    match receiver.recv().await.unwrap() {
+      ButtplugDeviceEvent::SensorReading(_, reading) => reading,
+      event => panic!("Unexpected event {:?}", event),
+    }
+  }
 
   #[test]
   pub fn test_vorze_sa_vibration_protocol() {
@@ -219,4 +362,74 @@ mod test {
       assert!(check_test_recv_empty(&command_receiver));
     });
   }
+
+  #[test]
+  pub fn test_vorze_sa_vorze_a10_cyclone_cmd() {
+    async_manager::block_on(async move {
+      let (device, test_device) = new_bluetoothle_test_device("CycSA").await.unwrap();
+      let command_receiver = test_device.get_endpoint_receiver(&Endpoint::Tx).unwrap();
+      // Translated to a RotateCmd before it gets to the protocol.
+      device
+        .parse_message(VorzeA10CycloneCmd::new(0, 50, true).into())
+        .await
+        .unwrap();
+      check_test_recv_value(
+        &command_receiver,
+        DeviceImplCommand::Write(DeviceWriteCmd::new(
+          Endpoint::Tx,
+          vec![0x01, 0x01, 178],
+          false,
+        )),
+      );
+      assert!(check_test_recv_empty(&command_receiver));
+    });
+  }
+
+  #[test]
+  pub fn test_vorze_sa_rotation_position() {
+    async_manager::block_on(async move {
+      let clock = ManualClock::new();
+      let (device, _) = new_bluetoothle_test_device_with_clock("CycSA", Arc::new(clock.clone()))
+        .await
+        .unwrap();
+      let mut sensor_receiver = device.sensor_event_stream();
+      device
+        .parse_message(SensorSubscribeCmd::new(0, 0).into())
+        .await
+        .unwrap();
+      assert_eq!(
+        next_reading(&mut sensor_receiver).await,
+        SensorReading::new(0, 0, SensorType::Position, vec![0])
+      );
+      // 0.5 rounds up to step 50 of 99, at a turn a second at full speed.
+      device
+        .parse_message(RotateCmd::new(0, vec![RotationSubcommand::new(0, 0.5, true)]).into())
+        .await
+        .unwrap();
+      clock.wait_for_sleeps(1).await;
+      clock.advance(Duration::from_secs(1));
+      assert_eq!(
+        next_reading(&mut sensor_receiver).await,
+        SensorReading::new(0, 0, SensorType::Position, vec![182])
+      );
+      device
+        .parse_message(RotateCmd::new(0, vec![RotationSubcommand::new(0, 0.5, false)]).into())
+        .await
+        .unwrap();
+      clock.wait_for_sleeps(2).await;
+      clock.advance(Duration::from_secs(2));
+      assert_eq!(
+        next_reading(&mut sensor_receiver).await,
+        SensorReading::new(0, 0, SensorType::Position, vec![-182])
+      );
+      device
+        .parse_message(SensorUnsubscribeCmd::new(0, 0).into())
+        .await
+        .unwrap();
+      assert!(device
+        .parse_message(SensorSubscribeCmd::new(0, 1).into())
+        .await
+        .is_err());
+    });
+  }
 }
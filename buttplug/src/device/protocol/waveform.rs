@@ -0,0 +1,104 @@
+//! Waveform patterns for devices that need a continuous stream of output
+//! frames, rather than a single speed setting (mostly e-stim units).
+//!
+//! Protocols keep a [Waveform] per output channel and pull a new
+//! [WaveformFrame] from it every [WAVEFORM_FRAME_DURATION_MS]. How a frame is
+//! encoded is up to the protocol.
+
+/// Length of time each frame is played for, in milliseconds.
+pub const WAVEFORM_FRAME_DURATION_MS: u64 = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WaveformFrame {
+  /// Time between pulses, in milliseconds. Devices will clamp this to whatever
+  /// range they support.
+  pub pulse_period_ms: u16,
+  /// Intensity of the pulses, as a percentage (0-100) of the channel's
+  /// current power level.
+  pub intensity: u8,
+}
+
+impl WaveformFrame {
+  pub fn new(pulse_period_ms: u16, intensity: u8) -> Self {
+    Self {
+      pulse_period_ms,
+      intensity: intensity.min(100),
+    }
+  }
+}
+
+/// Looping sequence of waveform frames.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Waveform {
+  frames: Vec<WaveformFrame>,
+  position: usize,
+}
+
+impl Default for Waveform {
+  fn default() -> Self {
+    Self::constant(10, 100)
+  }
+}
+
+impl Waveform {
+  /// Creates a waveform from a list of frames. Panics if the list is empty.
+  pub fn new(frames: Vec<WaveformFrame>) -> Self {
+    assert!(!frames.is_empty(), "Waveforms require at least one frame.");
+    Self {
+      frames,
+      position: 0,
+    }
+  }
+
+  /// Same frame, forever.
+  pub fn constant(pulse_period_ms: u16, intensity: u8) -> Self {
+    Self::new(vec![WaveformFrame::new(pulse_period_ms, intensity)])
+  }
+
+  /// Linearly ramps intensity from `from` to `to` over `frame_count` frames,
+  /// then starts over.
+  pub fn ramp(pulse_period_ms: u16, from: u8, to: u8, frame_count: usize) -> Self {
+    let frame_count = frame_count.max(1);
+    let frames = (0..frame_count)
+      .map(|i| {
+        let progress = if frame_count == 1 {
+          1.0
+        } else {
+          i as f64 / (frame_count - 1) as f64
+        };
+        let intensity = from as f64 + (to as f64 - from as f64) * progress;
+        WaveformFrame::new(pulse_period_ms, intensity.round() as u8)
+      })
+      .collect();
+    Self::new(frames)
+  }
+
+  /// Returns the current frame and advances to the next, looping at the end.
+  pub fn next_frame(&mut self) -> WaveformFrame {
+    let frame = self.frames[self.position];
+    self.position = (self.position + 1) % self.frames.len();
+    frame
+  }
+
+  /// Starts the waveform over from its first frame.
+  pub fn reset(&mut self) {
+    self.position = 0;
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::{Waveform, WaveformFrame};
+
+  #[test]
+  fn test_waveform_ramp_loops() {
+    let mut waveform = Waveform::ramp(20, 0, 100, 3);
+    assert_eq!(waveform.next_frame(), WaveformFrame::new(20, 0));
+    assert_eq!(waveform.next_frame(), WaveformFrame::new(20, 50));
+    assert_eq!(waveform.next_frame(), WaveformFrame::new(20, 100));
+    assert_eq!(waveform.next_frame(), WaveformFrame::new(20, 0));
+    waveform.next_frame();
+    waveform.reset();
+    assert_eq!(waveform.next_frame(), WaveformFrame::new(20, 0));
+  }
+}
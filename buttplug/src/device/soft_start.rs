@@ -0,0 +1,396 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2022 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Ramps features up when they're started from idle.
+//!
+//! Pattern apps tend to go straight from nothing to full speed, which is a
+//! jolt nobody asked for. With a soft start set, a feature that's stopped
+//! and gets asked for more than the threshold speed is started at a fraction
+//! of it, then stepped up to it over the ramp duration. Commands that come in
+//! during the ramp move its target, unless they ask for less than the feature
+//! is already at, in which case they're sent as is and the ramp is over.
+//! Stops end every ramp, and wait for a ramp step that's already being
+//! written, so no step lands after the stop.
+
+use super::{configuration_manager::ProtocolOptions, protocol::ButtplugProtocol, DeviceImpl};
+use crate::core::{
+  errors::ButtplugDeviceError,
+  messages::{
+    ButtplugDeviceCommandMessageUnion, ButtplugDeviceMessage, ButtplugDeviceMessageType,
+    ButtplugMessage, RotateCmd, RotationSubcommand, VibrateCmd, VibrateSubcommand,
+  },
+};
+use futures::Future;
+use std::{
+  collections::HashMap,
+  sync::{Arc, Mutex},
+  time::Duration,
+};
+use tokio::sync::Mutex as AsyncMutex;
+
+/// Speed at or below which features are started straight away.
+pub const DEFAULT_SOFT_START_THRESHOLD: f64 = 0.25;
+/// Number of steps a ramp is split into, including the first one.
+const RAMP_STEPS: u32 = 10;
+
+/// Soft start settings for a device.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SoftStartConfig {
+  /// How long it takes to get from idle to the requested speed.
+  pub duration: Duration,
+  /// Speed (0.0-1.0) a feature has to be asked for from idle to be ramped.
+  pub threshold: f64,
+}
+
+impl SoftStartConfig {
+  pub fn new(duration: Duration) -> Self {
+    Self {
+      duration,
+      threshold: DEFAULT_SOFT_START_THRESHOLD,
+    }
+  }
+
+  /// Reads the `soft-start-ms` and `soft-start-threshold` protocol options.
+  /// There's no soft start unless `soft-start-ms` is set.
+  pub fn from_options(options: &ProtocolOptions) -> Result<Option<Self>, ButtplugDeviceError> {
+    let duration_ms = match options.get::<u64>("soft-start-ms")? {
+      Some(duration_ms) => duration_ms,
+      None => return Ok(None),
+    };
+    let mut config = Self::new(Duration::from_millis(duration_ms));
+    config.threshold = options.get_or("soft-start-threshold", DEFAULT_SOFT_START_THRESHOLD)?;
+    Ok(Some(config))
+  }
+}
+
+type FeatureKey = (ButtplugDeviceMessageType, u32);
+
+#[derive(Default)]
+struct FeatureState {
+  /// Last speed asked for.
+  requested: f64,
+  /// Last direction asked for, only used by rotation.
+  clockwise: bool,
+  /// Last speed sent to the device.
+  sent: f64,
+  /// Step the ramp is on, if the feature is ramping.
+  ramp_step: Option<u32>,
+  // Bumped every time a ramp starts or is cut short, so ramp tasks for
+  // earlier ramps know to bail out.
+  run: u64,
+}
+
+pub(crate) struct SoftStartRamp {
+  config: Mutex<Option<SoftStartConfig>>,
+  features: Arc<Mutex<HashMap<FeatureKey, FeatureState>>>,
+  /// Held while a ramp step is checked and written, so stops can wait for
+  /// a step that's already going out.
+  step_lock: Arc<AsyncMutex<()>>,
+  protocol: Arc<dyn ButtplugProtocol>,
+  device: Arc<DeviceImpl>,
+}
+
+impl SoftStartRamp {
+  pub fn new(
+    config: Option<SoftStartConfig>,
+    protocol: Arc<dyn ButtplugProtocol>,
+    device: Arc<DeviceImpl>,
+  ) -> Self {
+    Self {
+      config: Mutex::new(config),
+      features: Arc::new(Mutex::new(HashMap::new())),
+      step_lock: Arc::new(AsyncMutex::new(())),
+      protocol,
+      device,
+    }
+  }
+
+  pub fn config(&self) -> Option<SoftStartConfig> {
+    *self.config.lock().unwrap()
+  }
+
+  /// Changes the soft start settings. Ramps already running finish with the
+  /// settings they started with.
+  pub fn set_config(&self, config: Option<SoftStartConfig>) {
+    *self.config.lock().unwrap() = config;
+  }
+
+  /// Resolves once no ramp step is being written. Stops have to wait on
+  /// this after going through [ramp_command][SoftStartRamp::ramp_command],
+  /// which ends the ramps, so a step that was mid-write can't land after
+  /// them.
+  pub fn ramp_step_finished(&self) -> impl Future<Output = ()> + Send + 'static {
+    let step_lock = self.step_lock.clone();
+    async move {
+      drop(step_lock.lock().await);
+    }
+  }
+
+  /// Returns the command with speeds lowered for features that are starting
+  /// a ramp or are in the middle of one.
+  pub fn ramp_command(
+    &self,
+    message: ButtplugDeviceCommandMessageUnion,
+  ) -> ButtplugDeviceCommandMessageUnion {
+    let config = match self.config() {
+      Some(config) => config,
+      None => return message,
+    };
+    match message {
+      ButtplugDeviceCommandMessageUnion::VibrateCmd(msg) => {
+        let speeds = msg
+          .speeds()
+          .iter()
+          .map(|cmd| {
+            let speed = self.update(
+              &config,
+              (ButtplugDeviceMessageType::VibrateCmd, cmd.index()),
+              cmd.speed(),
+              false,
+            );
+            VibrateSubcommand::new(cmd.index(), speed)
+          })
+          .collect();
+        let mut ramped = VibrateCmd::new(msg.device_index(), speeds);
+        ramped.set_id(msg.id());
+        ramped.into()
+      }
+      ButtplugDeviceCommandMessageUnion::RotateCmd(msg) => {
+        let rotations = msg
+          .rotations
+          .iter()
+          .map(|cmd| {
+            let speed = self.update(
+              &config,
+              (ButtplugDeviceMessageType::RotateCmd, cmd.index()),
+              cmd.speed(),
+              cmd.clockwise(),
+            );
+            RotationSubcommand::new(cmd.index(), speed, cmd.clockwise())
+          })
+          .collect();
+        let mut ramped = RotateCmd::new(msg.device_index, rotations);
+        ramped.set_id(msg.id());
+        ramped.into()
+      }
+      ButtplugDeviceCommandMessageUnion::StopDeviceCmd(msg) => {
+        for state in self.features.lock().unwrap().values_mut() {
+          state.requested = 0.0;
+          state.sent = 0.0;
+          state.ramp_step = None;
+          state.run += 1;
+        }
+        msg.into()
+      }
+      message => message,
+    }
+  }
+
+  /// Records a requested speed for a feature and returns the speed that
+  /// should actually be sent.
+  fn update(
+    &self,
+    config: &SoftStartConfig,
+    key: FeatureKey,
+    requested: f64,
+    clockwise: bool,
+  ) -> f64 {
+    let mut features = self.features.lock().unwrap();
+    let state = features.entry(key).or_default();
+    state.requested = requested;
+    state.clockwise = clockwise;
+    if let Some(step) = state.ramp_step {
+      if requested > state.sent {
+        state.sent = ramp_speed(state, step);
+        return state.sent;
+      }
+      state.ramp_step = None;
+      state.run += 1;
+    } else if state.sent == 0.0 && requested > config.threshold {
+      state.ramp_step = Some(1);
+      state.run += 1;
+      state.sent = ramp_speed(state, 1);
+      self.start_ramp(key, state.run, config.duration);
+      return state.sent;
+    }
+    state.sent = requested;
+    requested
+  }
+
+  /// Steps the feature up to its requested speed, unless the ramp is cut
+  /// short (or the device stopped) in the meantime.
+  fn start_ramp(&self, key: FeatureKey, run: u64, duration: Duration) {
+    let features = self.features.clone();
+    let step_lock = self.step_lock.clone();
+    let protocol = self.protocol.clone();
+    let device = self.device.clone();
+    let interval = duration / RAMP_STEPS;
+    self.device.scheduler().schedule(interval, move || {
+      let features = features.clone();
+      let step_lock = step_lock.clone();
+      let protocol = protocol.clone();
+      let device = device.clone();
+      async move {
+        // Held until the step is written. A stop that comes in before this
+        // is taken changes the run, and one that comes in after waits for
+        // the write.
+        let _step_lock = step_lock.lock().await;
+        let step = {
+          let mut features = features.lock().unwrap();
+          match features.get_mut(&key) {
+            Some(state) if state.run == run => {
+              // Only None if the run changed, which was checked above.
+              let step = state.ramp_step.unwrap() + 1;
+              state.sent = ramp_speed(state, step);
+              let done = step >= RAMP_STEPS;
+              state.ramp_step = if done { None } else { Some(step) };
+              Some((state.sent, state.clockwise, done))
+            }
+            _ => None,
+          }
+        };
+        let (speed, clockwise, done) = step?;
+        let (message_type, index) = key;
+        // The device index isn't used below the device manager, so 0 is fine.
+        let command: ButtplugDeviceCommandMessageUnion = match message_type {
+          ButtplugDeviceMessageType::RotateCmd => {
+            RotateCmd::new(0, vec![RotationSubcommand::new(index, speed, clockwise)]).into()
+          }
+          _ => VibrateCmd::new(0, vec![VibrateSubcommand::new(index, speed)]).into(),
+        };
+        if let Err(e) = protocol.handle_command(device, command).await {
+          error!("Could not ramp device feature: {:?}", e);
+          return None;
+        }
+        if done {
+          None
+        } else {
+          Some(interval)
+        }
+      }
+    });
+  }
+}
+
+/// Speed for a ramp step. Never lower than what's already been sent, in case
+/// the target was lowered partway through.
+fn ramp_speed(state: &FeatureState, step: u32) -> f64 {
+  (state.requested * step as f64 / RAMP_STEPS as f64)
+    .max(state.sent)
+    .min(state.requested)
+}
+
+#[cfg(all(test, feature = "server"))]
+mod test {
+  use super::SoftStartConfig;
+  use crate::{
+    core::messages::{
+      ButtplugDeviceCommandMessageUnion, StopDeviceCmd, VibrateCmd, VibrateSubcommand,
+    },
+    device::{configuration_manager::ProtocolOptions, DeviceImplCommand, DeviceWriteCmd, Endpoint},
+    test::{check_test_recv_empty, check_test_recv_value, new_bluetoothle_test_device_with_clock},
+    util::{async_manager, clock::ManualClock, stream::recv_now},
+  };
+  use std::{sync::Arc, time::Duration};
+
+  fn write(speed: u8) -> DeviceImplCommand {
+    DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![0xF1, speed], false))
+  }
+
+  #[test]
+  fn test_soft_start_options() {
+    let options: ProtocolOptions =
+      serde_json::from_str(r#"{ "soft-start-ms": 500, "soft-start-threshold": 0.5 }"#).unwrap();
+    assert_eq!(
+      SoftStartConfig::from_options(&options).unwrap(),
+      Some(SoftStartConfig {
+        duration: Duration::from_millis(500),
+        threshold: 0.5,
+      })
+    );
+    let options: ProtocolOptions =
+      serde_json::from_str(r#"{ "soft-start-threshold": 0.5 }"#).unwrap();
+    assert_eq!(SoftStartConfig::from_options(&options).unwrap(), None);
+  }
+
+  #[test]
+  fn test_soft_start_ramp() {
+    async_manager::block_on(async {
+      let clock = ManualClock::new();
+      let (device, test_device) =
+        new_bluetoothle_test_device_with_clock("Massage Demo", Arc::new(clock.clone()))
+          .await
+          .unwrap();
+      let command_receiver = test_device.get_endpoint_receiver(&Endpoint::Tx).unwrap();
+      device.set_soft_start(Some(SoftStartConfig::new(Duration::from_millis(100))));
+      let vibrate = |speed| -> ButtplugDeviceCommandMessageUnion {
+        VibrateCmd::new(0, vec![VibrateSubcommand::new(0, speed)]).into()
+      };
+
+      // Low speeds start straight away.
+      device.parse_message(vibrate(0.2)).await.unwrap();
+      check_test_recv_value(&command_receiver, write(26));
+      device
+        .parse_message(StopDeviceCmd::new(0).into())
+        .await
+        .unwrap();
+      check_test_recv_value(&command_receiver, write(0));
+
+      // High speeds from idle start at a tenth, and step up from there.
+      device.parse_message(vibrate(1.0)).await.unwrap();
+      check_test_recv_value(&command_receiver, write(13));
+      clock.wait_for_sleeps(1).await;
+      clock.advance(Duration::from_millis(10));
+      clock.wait_for_sleeps(2).await;
+      check_test_recv_value(&command_receiver, write(26));
+
+      // Asking for less than the ramp is at ends it.
+      device.parse_message(vibrate(0.1)).await.unwrap();
+      check_test_recv_value(&command_receiver, write(13));
+      clock.advance(Duration::from_millis(10));
+      assert!(check_test_recv_empty(&command_receiver));
+
+      // Features that are already running aren't ramped.
+      device.parse_message(vibrate(1.0)).await.unwrap();
+      check_test_recv_value(&command_receiver, write(127));
+    });
+  }
+
+  #[test]
+  fn test_soft_start_stop_during_ramp_step() {
+    async_manager::block_on(async {
+      let clock = ManualClock::new();
+      let (device, test_device) =
+        new_bluetoothle_test_device_with_clock("Massage Demo", Arc::new(clock.clone()))
+          .await
+          .unwrap();
+      let command_receiver = test_device.get_endpoint_receiver(&Endpoint::Tx).unwrap();
+      device.set_soft_start(Some(SoftStartConfig::new(Duration::from_millis(100))));
+      device
+        .parse_message(VibrateCmd::new(0, vec![VibrateSubcommand::new(0, 1.0)]).into())
+        .await
+        .unwrap();
+      check_test_recv_value(&command_receiver, write(13));
+
+      // Let a step come due, and stop before it's had a chance to run. The
+      // step may or may not make it out first, but never after the stop.
+      clock.wait_for_sleeps(1).await;
+      clock.advance(Duration::from_millis(10));
+      device
+        .parse_message(StopDeviceCmd::new(0).into())
+        .await
+        .unwrap();
+      let mut writes = vec![];
+      while let Some(Some(command)) = recv_now(&mut command_receiver.lock().unwrap()) {
+        writes.push(command);
+      }
+      assert!(writes == vec![write(0)] || writes == vec![write(26), write(0)]);
+      clock.advance(Duration::from_millis(100));
+      assert!(check_test_recv_empty(&command_receiver));
+    });
+  }
+}
@@ -0,0 +1,83 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2021 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Tracing spans for the device command path.
+//!
+//! Every command the server sends to a device runs inside a `device command`
+//! span, and every transport operation the command causes runs inside a
+//! `device io` span nested under it. Anything logged by protocols or
+//! transports while handling the command picks up these fields, so logs from
+//! multi-device sessions can be split up per device without parsing messages.
+//!
+//! Commands coming from a client are also nested under the server's
+//! `Buttplug Server Message` span, which has the message `id`.
+//!
+//! # Field schema
+//!
+//! These field names are treated as stable, so log processing built on them
+//! keeps working across releases. New fields may be added.
+//!
+//! `device command` (INFO)
+//!
+//! | Field          | Type   | Description                                                  |
+//! |----------------|--------|--------------------------------------------------------------|
+//! | `device_index` | u32    | Index of the device, as the client sees it.                  |
+//! | `protocol`     | string | Device configuration protocol name, or `virtual` for groups and split devices. |
+//! | `device_name`  | string | Device name, as sent in DeviceAdded.                         |
+//! | `address`      | string | Transport address of the device.                             |
+//! | `message_type` | string | Name of the device command message, e.g. `VibrateCmd`.       |
+//!
+//! `device io` (TRACE)
+//!
+//! | Field       | Type   | Description                                                  |
+//! |-------------|--------|--------------------------------------------------------------|
+//! | `address`   | string | Transport address of the hardware being talked to.           |
+//! | `operation` | string | One of `write`, `read`, `subscribe`, `unsubscribe`.          |
+//! | `endpoint`  | string | Endpoint name, e.g. `tx`.                                    |
+//!
+//! Commands to group devices fan out to their members, so a `device command`
+//! span for a group will have `device io` spans with the members' addresses
+//! under it. Stops sent by the server itself (StopAllDevices, ping timeouts)
+//! get a `device command` span per device, same as client commands.
+//!
+//! Transports that hand the actual write off to their own task (btleplug,
+//! for instance) log from there under their own spans, which carry the
+//! device address.
+
+use super::{ButtplugDevice, Endpoint};
+use crate::core::messages::ButtplugDeviceCommandMessageUnion;
+use tracing::Span;
+
+/// Value of the `protocol` field for devices that don't come from the device
+/// configuration.
+pub const VIRTUAL_PROTOCOL: &str = "virtual";
+
+/// Span for a single command sent to a device.
+pub fn device_command_span(
+  device_index: u32,
+  device: &ButtplugDevice,
+  message: &ButtplugDeviceCommandMessageUnion,
+) -> Span {
+  info_span!(
+    "device command",
+    device_index = device_index,
+    protocol = device.protocol_identifier().unwrap_or(VIRTUAL_PROTOCOL),
+    device_name = tracing::field::display(device.name()),
+    address = tracing::field::display(device.address()),
+    message_type = message.message_name()
+  )
+}
+
+/// Span for a single transport operation on a device.
+pub fn device_io_span(address: &str, operation: &'static str, endpoint: Endpoint) -> Span {
+  trace_span!(
+    "device io",
+    address = tracing::field::display(address),
+    operation = operation,
+    endpoint = tracing::field::display(endpoint)
+  )
+}
@@ -0,0 +1,82 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2022 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Throughput mode for devices that take a stream of small updates, like
+//! strokers taking position updates over a BLE bridge.
+//!
+//! Waiting for a response to every write caps updates at one per round trip,
+//! usually well under 60Hz, and updates queue up behind each other whenever
+//! the client sends faster than that. In throughput mode, writes to an
+//! endpoint are sent without response, at most once per interval, and only
+//! the newest write queued since the last one is sent. With the interval
+//! matched to the BLE connection interval, every connection event carries
+//! the latest update and nothing stale. Dropping writes is only safe when
+//! each write replaces the one before it, so protocols opt in per endpoint.
+
+use super::{spans, DeviceImplInternal, DeviceWriteCmd, Endpoint};
+use crate::util::{async_manager, clock::Clock};
+use std::{sync::Arc, time::Duration};
+use tokio::sync::watch;
+use tracing_futures::Instrument;
+
+/// Settings for throughput mode on an endpoint.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WriteBatchConfig {
+  pub endpoint: Endpoint,
+  /// Time between writes, usually the connection interval.
+  pub interval: Duration,
+}
+
+impl WriteBatchConfig {
+  pub fn new(endpoint: Endpoint, interval: Duration) -> Self {
+    Self { endpoint, interval }
+  }
+}
+
+/// Holds the newest write for an endpoint and sends it once the interval
+/// since the last write is up. The batching task stops once the batcher is
+/// dropped, or a write fails.
+pub(super) struct WriteBatcher {
+  sender: watch::Sender<Vec<u8>>,
+}
+
+impl WriteBatcher {
+  pub fn new(
+    config: WriteBatchConfig,
+    address: String,
+    internal_impl: Arc<dyn DeviceImplInternal>,
+    clock: Arc<dyn Clock>,
+  ) -> Self {
+    let (sender, mut receiver) = watch::channel(vec![]);
+    async_manager::spawn_named("device write batcher", async move {
+      while receiver.changed().await.is_ok() {
+        // Anything queued since the last write replaced whatever was queued
+        // before it.
+        let data = receiver.borrow().clone();
+        let write = DeviceWriteCmd::new(config.endpoint, data, false);
+        let span = spans::device_io_span(&address, "write", config.endpoint);
+        let fut = span.in_scope(|| internal_impl.write_value(write));
+        if let Err(err) = fut.instrument(span).await {
+          error!(
+            "Batched write to {} failed, stopping batching: {}",
+            config.endpoint, err
+          );
+          break;
+        }
+        clock.sleep(config.interval).await;
+      }
+    })
+    .unwrap();
+    Self { sender }
+  }
+
+  /// Queues data for the next write, replacing anything already queued.
+  /// Returns false if the batching task has stopped.
+  pub fn queue(&self, data: Vec<u8>) -> bool {
+    self.sender.send(data).is_ok()
+  }
+}
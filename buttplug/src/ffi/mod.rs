@@ -0,0 +1,271 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2021 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! C ABI for embedding a Buttplug server in other languages.
+//!
+//! Language bindings load a cdylib that re-exports these functions, create a
+//! server with [buttplug_ffi_server_create], push Buttplug JSON protocol
+//! messages in with [buttplug_ffi_server_send], and get replies and events
+//! back through the message callback, also as JSON protocol messages. This is
+//! the same wire format the websocket connectors use, so bindings can reuse
+//! whatever client code they already have.
+//!
+//! Each server owns its own runtime. Callbacks happen on that runtime's
+//! threads, and the buffer passed to a callback is only valid for the
+//! duration of the call.
+//!
+//! Panics never unwind into the caller. Functions report them as
+//! [ButtplugFFIResult::Panic], or null from [buttplug_ffi_server_create].
+//!
+//! Anything added here needs to keep working for bindings built against older
+//! versions. New functions are fine, changes to existing signatures or structs
+//! require bumping [BUTTPLUG_FFI_ABI_VERSION].
+
+mod transport;
+
+use crate::{
+  connector::ButtplugRemoteServerConnector,
+  core::messages::serializer::ButtplugSerializedMessage,
+  server::{ButtplugRemoteServer, ButtplugServerOptions},
+};
+use std::{
+  ffi::{c_void, CStr},
+  os::raw::c_char,
+  panic::{self, AssertUnwindSafe},
+  slice, str,
+};
+use tokio::{runtime::Runtime, sync::mpsc};
+use transport::{ButtplugFFITransport, FFICallback};
+
+/// Bumped whenever an existing function or struct in this module changes in
+/// an incompatible way.
+pub const BUTTPLUG_FFI_ABI_VERSION: u32 = 1;
+
+/// Called with a serialized (JSON) array of server messages. The buffer is
+/// not null terminated, and is only valid until the callback returns.
+pub type ButtplugFFIMessageCallback =
+  Option<extern "C" fn(ctx: *mut c_void, buf: *const u8, len: usize)>;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ButtplugFFIResult {
+  Ok = 0,
+  /// A required pointer argument was null.
+  NullPointer = 1,
+  /// A string or message buffer wasn't valid UTF-8.
+  InvalidUtf8 = 2,
+  /// The server couldn't be created or has shut down.
+  ServerError = 3,
+  /// Too many messages are waiting to be processed, try again later.
+  Busy = 4,
+  /// The library panicked. The server may be in a bad state and should be
+  /// freed.
+  Panic = 5,
+}
+
+/// Server creation options. String pointers may be null, in which case the
+/// same defaults as [ButtplugServerOptions] are used.
+#[repr(C)]
+pub struct ButtplugFFIServerOptions {
+  pub name: *const c_char,
+  pub max_ping_time: u64,
+  pub allow_raw_messages: bool,
+  pub allow_power_devices: bool,
+  pub device_configuration_json: *const c_char,
+  pub user_device_configuration_json: *const c_char,
+  /// If true, adds all device communication managers compiled into the
+  /// library.
+  pub add_default_comm_managers: bool,
+}
+
+/// Opaque server handle.
+pub struct ButtplugFFIServer {
+  runtime: Runtime,
+  server: ButtplugRemoteServer,
+  message_sender: mpsc::Sender<ButtplugSerializedMessage>,
+}
+
+/// Runs the body of an FFI function, returning `on_panic` if it panics, as
+/// unwinding into the caller's frames is undefined behavior.
+fn catch_panic<T>(on_panic: T, body: impl FnOnce() -> T) -> T {
+  // Nothing the body touches is looked at again after a panic, other than
+  // the server handle, which the caller is told to free.
+  panic::catch_unwind(AssertUnwindSafe(body)).unwrap_or_else(|_| {
+    error!("Panic in FFI call, returning an error to the caller instead.");
+    on_panic
+  })
+}
+
+unsafe fn optional_string(ptr: *const c_char) -> Result<Option<String>, ButtplugFFIResult> {
+  if ptr.is_null() {
+    return Ok(None);
+  }
+  CStr::from_ptr(ptr)
+    .to_str()
+    .map(|s| Some(s.to_owned()))
+    .map_err(|_| ButtplugFFIResult::InvalidUtf8)
+}
+
+unsafe fn server_options(
+  options: *const ButtplugFFIServerOptions,
+) -> Result<(ButtplugServerOptions, bool), ButtplugFFIResult> {
+  let mut server_options = ButtplugServerOptions::default();
+  if options.is_null() {
+    return Ok((server_options, true));
+  }
+  let options = &*options;
+  if let Some(name) = optional_string(options.name)? {
+    server_options.name = name;
+  }
+  server_options.max_ping_time = options.max_ping_time;
+  server_options.allow_raw_messages = options.allow_raw_messages;
+  server_options.allow_power_devices = options.allow_power_devices;
+  server_options.device_configuration_json = optional_string(options.device_configuration_json)?;
+  server_options.user_device_configuration_json =
+    optional_string(options.user_device_configuration_json)?;
+  Ok((server_options, options.add_default_comm_managers))
+}
+
+/// Returns [BUTTPLUG_FFI_ABI_VERSION], so bindings can check they were built
+/// against a compatible library.
+#[no_mangle]
+pub extern "C" fn buttplug_ffi_abi_version() -> u32 {
+  catch_panic(0, || BUTTPLUG_FFI_ABI_VERSION)
+}
+
+/// Creates a server and starts waiting for a client handshake.
+///
+/// `options` may be null to use defaults (with all compiled in comm
+/// managers). `callback` receives every reply and event, and is called from
+/// library owned threads with `ctx` passed through untouched.
+///
+/// Returns null on failure. The returned handle must be freed with
+/// [buttplug_ffi_server_free].
+///
+/// # Safety
+///
+/// `options`, if not null, must point to a valid [ButtplugFFIServerOptions]
+/// whose string pointers are null or valid null terminated strings. `ctx` must
+/// be safe to use from other threads for as long as the server exists.
+#[no_mangle]
+pub unsafe extern "C" fn buttplug_ffi_server_create(
+  options: *const ButtplugFFIServerOptions,
+  callback: ButtplugFFIMessageCallback,
+  ctx: *mut c_void,
+) -> *mut ButtplugFFIServer {
+  catch_panic(std::ptr::null_mut(), || {
+    let (options, add_default_comm_managers) = match server_options(options) {
+      Ok(options) => options,
+      Err(err) => {
+        error!("Invalid FFI server options: {:?}", err);
+        return std::ptr::null_mut();
+      }
+    };
+    let runtime = match Runtime::new() {
+      Ok(runtime) => runtime,
+      Err(err) => {
+        error!("Cannot create runtime for FFI server: {:?}", err);
+        return std::ptr::null_mut();
+      }
+    };
+    // Server creation spawns the device manager loop, so it needs to happen
+    // inside the runtime.
+    let runtime_guard = runtime.enter();
+    let server = match ButtplugRemoteServer::new_with_options(&options) {
+      Ok(server) => server,
+      Err(err) => {
+        error!("Cannot create FFI server: {:?}", err);
+        return std::ptr::null_mut();
+      }
+    };
+    if add_default_comm_managers {
+      if let Err(err) = server.add_default_comm_managers() {
+        error!("Cannot add comm managers to FFI server: {:?}", err);
+        return std::ptr::null_mut();
+      }
+    }
+    let (message_sender, message_receiver) = mpsc::channel(256);
+    let transport = ButtplugFFITransport::new(message_receiver, FFICallback::new(callback, ctx));
+    let connector = ButtplugRemoteServerConnector::new(transport);
+    let server_fut = server.start(connector);
+    runtime.spawn(async move {
+      if let Err(err) = server_fut.await {
+        error!("FFI server connector failed: {:?}", err);
+      }
+    });
+    drop(runtime_guard);
+    Box::into_raw(Box::new(ButtplugFFIServer {
+      runtime,
+      server,
+      message_sender,
+    }))
+  })
+}
+
+/// Queues a serialized (JSON) array of client messages for the server. The
+/// reply comes back through the message callback.
+///
+/// # Safety
+///
+/// `server` must be a handle returned by [buttplug_ffi_server_create] that
+/// hasn't been freed, and `buf` must point to `len` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn buttplug_ffi_server_send(
+  server: *mut ButtplugFFIServer,
+  buf: *const u8,
+  len: usize,
+) -> ButtplugFFIResult {
+  catch_panic(ButtplugFFIResult::Panic, || {
+    if server.is_null() || buf.is_null() {
+      return ButtplugFFIResult::NullPointer;
+    }
+    let server = &*server;
+    let msg = match str::from_utf8(slice::from_raw_parts(buf, len)) {
+      Ok(msg) => msg.to_owned(),
+      Err(_) => return ButtplugFFIResult::InvalidUtf8,
+    };
+    // Never block here, the host may be calling us from a UI or render thread.
+    match server
+      .message_sender
+      .try_send(ButtplugSerializedMessage::Text(msg))
+    {
+      Ok(()) => ButtplugFFIResult::Ok,
+      Err(mpsc::error::TrySendError::Full(_)) => ButtplugFFIResult::Busy,
+      Err(mpsc::error::TrySendError::Closed(_)) => ButtplugFFIResult::ServerError,
+    }
+  })
+}
+
+/// Disconnects the client, stops all devices, and frees the server.
+///
+/// # Safety
+///
+/// `server` must be null or a handle returned by [buttplug_ffi_server_create]
+/// that hasn't been freed. This blocks until the server shuts down, so it must
+/// not be called from inside the message callback.
+#[no_mangle]
+pub unsafe extern "C" fn buttplug_ffi_server_free(server: *mut ButtplugFFIServer) {
+  catch_panic((), || {
+    if server.is_null() {
+      return;
+    }
+    let server = Box::from_raw(server);
+    let ButtplugFFIServer {
+      runtime,
+      server,
+      message_sender,
+    } = *server;
+    drop(message_sender);
+    if let Err(err) = runtime.block_on(server.disconnect()) {
+      error!("Error disconnecting FFI server: {:?}", err);
+    }
+    // Dropping the server has to happen inside the runtime, as it may spawn
+    // cleanup tasks.
+    let _guard = runtime.enter();
+    drop(server);
+  })
+}
@@ -0,0 +1,154 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2021 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+use super::ButtplugFFIMessageCallback;
+use crate::{
+  connector::{
+    transport::{ButtplugConnectorTransport, ButtplugTransportIncomingMessage},
+    ButtplugConnectorError, ButtplugConnectorResultFuture,
+  },
+  core::messages::serializer::ButtplugSerializedMessage,
+  util::async_manager,
+};
+use futures::{future::BoxFuture, FutureExt};
+use std::{
+  ffi::c_void,
+  sync::{Arc, Mutex},
+};
+use tokio::sync::{
+  mpsc::{Receiver, Sender},
+  Notify,
+};
+
+/// Host callback plus the context pointer it was registered with.
+pub(super) struct FFICallback {
+  callback: ButtplugFFIMessageCallback,
+  ctx: *mut c_void,
+}
+
+// The context pointer is opaque to us. Hosts are told in the
+// buttplug_ffi_server_create docs that the callback will be called from
+// library threads, so it's on them to make whatever it points to safe for
+// that.
+unsafe impl Send for FFICallback {}
+unsafe impl Sync for FFICallback {}
+
+impl FFICallback {
+  pub(super) fn new(callback: ButtplugFFIMessageCallback, ctx: *mut c_void) -> Self {
+    Self { callback, ctx }
+  }
+
+  fn call(&self, buf: &[u8]) {
+    if let Some(callback) = self.callback {
+      callback(self.ctx, buf.as_ptr(), buf.len());
+    }
+  }
+}
+
+/// Transport that hands serialized server messages to a host callback, and
+/// takes serialized client messages from [super::buttplug_ffi_server_send].
+pub(super) struct ButtplugFFITransport {
+  host_receiver: Mutex<Option<Receiver<ButtplugSerializedMessage>>>,
+  callback: Arc<FFICallback>,
+  disconnect_notifier: Arc<Notify>,
+}
+
+impl ButtplugFFITransport {
+  pub(super) fn new(
+    host_receiver: Receiver<ButtplugSerializedMessage>,
+    callback: FFICallback,
+  ) -> Self {
+    Self {
+      host_receiver: Mutex::new(Some(host_receiver)),
+      callback: Arc::new(callback),
+      disconnect_notifier: Arc::new(Notify::new()),
+    }
+  }
+}
+
+async fn run_ffi_transport_loop(
+  mut host_receiver: Receiver<ButtplugSerializedMessage>,
+  mut outgoing_receiver: Receiver<ButtplugSerializedMessage>,
+  incoming_sender: Sender<ButtplugTransportIncomingMessage>,
+  callback: Arc<FFICallback>,
+  disconnect_notifier: Arc<Notify>,
+) {
+  info!("Starting FFI transport loop.");
+  loop {
+    select! {
+      host_msg = host_receiver.recv().fuse() => match host_msg {
+        Some(msg) => {
+          if incoming_sender
+            .send(ButtplugTransportIncomingMessage::Message(msg))
+            .await
+            .is_err()
+          {
+            debug!("Connector dropped, exiting FFI transport loop.");
+            return;
+          }
+        }
+        None => {
+          debug!("FFI server handle dropped, exiting FFI transport loop.");
+          break;
+        }
+      },
+      outgoing_msg = outgoing_receiver.recv().fuse() => match outgoing_msg {
+        Some(ButtplugSerializedMessage::Text(text)) => callback.call(text.as_bytes()),
+        Some(ButtplugSerializedMessage::Binary(bytes)) => callback.call(&bytes),
+        None => {
+          debug!("Connector dropped, exiting FFI transport loop.");
+          return;
+        }
+      },
+      _ = disconnect_notifier.notified().fuse() => {
+        debug!("FFI transport disconnected.");
+        break;
+      }
+    }
+  }
+  let _ = incoming_sender
+    .send(ButtplugTransportIncomingMessage::Close(
+      "FFI transport closed".to_owned(),
+    ))
+    .await;
+}
+
+impl ButtplugConnectorTransport for ButtplugFFITransport {
+  fn connect(
+    &self,
+    outgoing_receiver: Receiver<ButtplugSerializedMessage>,
+    incoming_sender: Sender<ButtplugTransportIncomingMessage>,
+  ) -> BoxFuture<'static, Result<(), ButtplugConnectorError>> {
+    let host_receiver = match self.host_receiver.lock().unwrap().take() {
+      Some(receiver) => receiver,
+      None => return ButtplugConnectorError::ConnectorAlreadyConnected.into(),
+    };
+    let callback = self.callback.clone();
+    let disconnect_notifier = self.disconnect_notifier.clone();
+    Box::pin(async move {
+      async_manager::spawn_named(
+        "ffi transport loop",
+        run_ffi_transport_loop(
+          host_receiver,
+          outgoing_receiver,
+          incoming_sender,
+          callback,
+          disconnect_notifier,
+        ),
+      )
+      .map_err(|err| ButtplugConnectorError::ConnectorGenericError(format!("{:?}", err)))
+    })
+  }
+
+  fn disconnect(self) -> ButtplugConnectorResultFuture {
+    let disconnect_notifier = self.disconnect_notifier;
+    Box::pin(async move {
+      disconnect_notifier.notify_waiters();
+      Ok(())
+    })
+  }
+}
@@ -31,6 +31,8 @@ pub mod client;
 pub mod connector;
 pub mod core;
 pub mod device;
+#[cfg(feature = "ffi")]
+pub mod ffi;
 #[cfg(feature = "server")]
 pub mod server;
 pub mod util;
@@ -0,0 +1,135 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2021 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+use crate::{
+  core::errors::ButtplugError,
+  device::configuration_manager::{DeviceSpecifier, HTTPSpecifier},
+  server::comm_managers::{
+    http::{HttpClient, HttpDeviceCommunicationManagerBuilder, HttpDeviceInfo, HttpDeviceSource},
+    DeviceCommunicationEvent, DeviceCommunicationManager, DeviceCommunicationManagerBuilder,
+  },
+};
+use async_trait::async_trait;
+use serde::Deserialize;
+use std::time::Duration;
+use tokio::sync::mpsc::Sender;
+
+const AUTOBLOW_DEVICE_NAME: &str = "Autoblow AI Ultra";
+const AUTOBLOW_TOKEN_HEADER: &str = "x-device-token";
+const AUTOBLOW_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// An Autoblow AI Ultra, reached through an API host.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AutoblowDeviceInfo {
+  /// Base URL of the API host, i.e. "http://192.168.1.20:8080". Also used as
+  /// the device address.
+  pub host: String,
+  /// Token identifying the device to the API host.
+  pub device_token: String,
+}
+
+impl AutoblowDeviceInfo {
+  pub fn new(host: &str, device_token: &str) -> Self {
+    Self {
+      host: host.trim_end_matches('/').to_owned(),
+      device_token: device_token.to_owned(),
+    }
+  }
+
+  fn http_device_info(&self) -> HttpDeviceInfo {
+    HttpDeviceInfo::new(
+      AUTOBLOW_DEVICE_NAME,
+      &self.host,
+      DeviceSpecifier::HTTP(HTTPSpecifier::new_from_name(AUTOBLOW_DEVICE_NAME)),
+      &self.host,
+    )
+    .header(AUTOBLOW_TOKEN_HEADER, &self.device_token)
+  }
+}
+
+#[derive(Deserialize, Debug)]
+struct AutoblowConnectedReply {
+  #[serde(rename = "isConnected")]
+  is_connected: bool,
+}
+
+/// Reports devices the API host says are connected. Endpoint paths come from
+/// the device configuration, so writes use the default JSON PUT.
+struct AutoblowDeviceSource {
+  devices: Vec<AutoblowDeviceInfo>,
+}
+
+#[async_trait]
+impl HttpDeviceSource for AutoblowDeviceSource {
+  fn name(&self) -> &'static str {
+    "AutoblowCommunicationManager"
+  }
+
+  async fn poll(
+    &self,
+    client: &HttpClient,
+    _is_scanning: bool,
+  ) -> Result<Vec<HttpDeviceInfo>, ButtplugError> {
+    let mut found = vec![];
+    for device in &self.devices {
+      let info = device.http_device_info();
+      let request = info.request(reqwest::Method::GET, "/autoblow/connected");
+      match client.send_json::<AutoblowConnectedReply>(request).await {
+        Ok(reply) if reply.is_connected => found.push(info),
+        Ok(_) => debug!("Autoblow at {} is not connected to its host.", device.host),
+        Err(err) => debug!("Cannot reach Autoblow host {}: {}", device.host, err),
+      }
+    }
+    Ok(found)
+  }
+}
+
+pub struct AutoblowCommunicationManagerBuilder {
+  sender: Option<Sender<DeviceCommunicationEvent>>,
+  devices: Vec<AutoblowDeviceInfo>,
+  poll_interval: Duration,
+}
+
+impl Default for AutoblowCommunicationManagerBuilder {
+  fn default() -> Self {
+    Self {
+      sender: None,
+      devices: vec![],
+      poll_interval: AUTOBLOW_POLL_INTERVAL,
+    }
+  }
+}
+
+impl AutoblowCommunicationManagerBuilder {
+  pub fn device(mut self, host: &str, device_token: &str) -> Self {
+    self
+      .devices
+      .push(AutoblowDeviceInfo::new(host, device_token));
+    self
+  }
+
+  /// How often to check whether devices are still connected.
+  pub fn poll_interval(mut self, interval: Duration) -> Self {
+    self.poll_interval = interval;
+    self
+  }
+}
+
+impl DeviceCommunicationManagerBuilder for AutoblowCommunicationManagerBuilder {
+  fn set_event_sender(&mut self, sender: Sender<DeviceCommunicationEvent>) {
+    self.sender = Some(sender)
+  }
+
+  fn finish(mut self) -> Box<dyn DeviceCommunicationManager> {
+    let mut builder = HttpDeviceCommunicationManagerBuilder::new(AutoblowDeviceSource {
+      devices: self.devices,
+    })
+    .poll_interval(self.poll_interval);
+    builder.set_event_sender(self.sender.take().unwrap());
+    builder.finish()
+  }
+}
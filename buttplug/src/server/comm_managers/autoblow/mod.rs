@@ -0,0 +1,19 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2021 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Autoblow AI Ultra support, through the HTTP API the device exposes to its
+//! cluster.
+//!
+//! Devices are added in the comm manager builder, as the base URL of the API
+//! host plus the device token shown in the Autoblow app. Pointing the host at
+//! a cluster on the local network keeps everything offline. Scanning only
+//! announces devices the host reports as connected, and connected devices are
+//! polled so they're removed when they go away.
+
+mod autoblow_comm_manager;
+
+pub use autoblow_comm_manager::{AutoblowCommunicationManagerBuilder, AutoblowDeviceInfo};
@@ -0,0 +1,158 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2021 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+use super::btclassic_device_impl::BluetoothClassicDeviceImplCreator;
+use crate::{
+  core::ButtplugResultFuture,
+  server::comm_managers::{
+    DeviceCommunicationEvent, DeviceCommunicationManager, DeviceCommunicationManagerBuilder,
+    DeviceCommunicationManagerCapabilities, DeviceCommunicationTransport,
+  },
+};
+use bluer::{AddressType, Device, Session};
+use futures::future;
+use std::collections::HashSet;
+use tokio::sync::mpsc::Sender;
+use tracing_futures::Instrument;
+use uuid::Uuid;
+
+#[derive(Default)]
+pub struct BluetoothClassicCommunicationManagerBuilder {
+  sender: Option<Sender<DeviceCommunicationEvent>>,
+}
+
+impl DeviceCommunicationManagerBuilder for BluetoothClassicCommunicationManagerBuilder {
+  fn set_event_sender(&mut self, sender: Sender<DeviceCommunicationEvent>) {
+    self.sender = Some(sender)
+  }
+
+  fn finish(mut self) -> Box<dyn DeviceCommunicationManager> {
+    Box::new(BluetoothClassicCommunicationManager::new(
+      self.sender.take().unwrap(),
+    ))
+  }
+}
+
+pub struct BluetoothClassicCommunicationManager {
+  sender: Sender<DeviceCommunicationEvent>,
+}
+
+impl BluetoothClassicCommunicationManager {
+  fn new(sender: Sender<DeviceCommunicationEvent>) -> Self {
+    Self { sender }
+  }
+}
+
+/// A paired device, along with its name and advertised services.
+struct PairedDevice {
+  device: Device,
+  name: String,
+  services: HashSet<Uuid>,
+}
+
+async fn paired_devices() -> bluer::Result<Vec<PairedDevice>> {
+  let session = Session::new().await?;
+  let adapter = session.default_adapter().await?;
+  let mut devices = vec![];
+  for address in adapter.device_addresses().await? {
+    let device = adapter.device(address)?;
+    // LE devices are the btleplug manager's problem.
+    if device.address_type().await? != AddressType::BrEdr || !device.is_paired().await? {
+      continue;
+    }
+    // Without a name, there's nothing to match against.
+    let name = match device.name().await? {
+      Some(name) => name,
+      None => continue,
+    };
+    // BlueZ uses a newer uuid crate than we do, so convert through u128.
+    let services = device
+      .uuids()
+      .await?
+      .unwrap_or_default()
+      .iter()
+      .map(|uuid| Uuid::from_u128(uuid.as_u128()))
+      .collect();
+    devices.push(PairedDevice {
+      device,
+      name,
+      services,
+    });
+  }
+  Ok(devices)
+}
+
+impl DeviceCommunicationManager for BluetoothClassicCommunicationManager {
+  fn name(&self) -> &'static str {
+    "BluetoothClassicCommunicationManager"
+  }
+
+  fn capabilities(&self) -> DeviceCommunicationManagerCapabilities {
+    DeviceCommunicationManagerCapabilities {
+      transport: DeviceCommunicationTransport::Bluetooth,
+      // Only paired devices are listed, once per scan.
+      supports_hotplug: false,
+      requires_permissions: false,
+      unavailable_reason: None,
+    }
+  }
+
+  fn start_scanning(&self) -> ButtplugResultFuture {
+    debug!("Bluetooth Classic manager scanning for paired devices.");
+    let sender = self.sender.clone();
+    Box::pin(
+      async move {
+        match paired_devices().await {
+          Ok(devices) => {
+            for paired in devices {
+              let address = paired.device.address().to_string();
+              trace!(
+                "Found paired Bluetooth Classic device {} ({}) with services {:?}",
+                paired.name,
+                address,
+                paired.services
+              );
+              let creator = Box::new(BluetoothClassicDeviceImplCreator::new(
+                paired.device,
+                &paired.name,
+                paired.services,
+              ));
+              if sender
+                .send(DeviceCommunicationEvent::DeviceFound {
+                  name: paired.name,
+                  address,
+                  creator,
+                })
+                .await
+                .is_err()
+              {
+                debug!("Device manager disappeared, exiting.");
+                break;
+              }
+            }
+          }
+          Err(err) => error!("Cannot list Bluetooth Classic devices: {}", err),
+        }
+        if sender
+          .send(DeviceCommunicationEvent::ScanningFinished)
+          .await
+          .is_err()
+        {
+          error!("Error sending scanning finished.");
+        }
+        Ok(())
+      }
+      .instrument(tracing::info_span!(
+        "Bluetooth Classic Device Comm Manager Scanning."
+      )),
+    )
+  }
+
+  fn stop_scanning(&self) -> ButtplugResultFuture {
+    Box::pin(future::ready(Ok(())))
+  }
+}
@@ -0,0 +1,287 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2021 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+use crate::{
+  core::{
+    errors::{ButtplugDeviceError, ButtplugError},
+    messages::RawReading,
+    ButtplugResultFuture,
+  },
+  device::{
+    configuration_manager::{BluetoothClassicSpecifier, DeviceSpecifier, ProtocolDefinition},
+    ButtplugDeviceEvent, ButtplugDeviceImplCreator, DeviceImpl, DeviceImplInternal, DeviceReadCmd,
+    DeviceSubscribeCmd, DeviceUnsubscribeCmd, DeviceWriteCmd, Endpoint,
+  },
+  server::comm_managers::ButtplugDeviceSpecificError,
+  util::async_manager,
+};
+use async_trait::async_trait;
+use bluer::{
+  rfcomm::{
+    stream::{OwnedReadHalf, OwnedWriteHalf},
+    SocketAddr, Stream,
+  },
+  Device,
+};
+use futures::{
+  future::{self, BoxFuture},
+  select, FutureExt,
+};
+use std::{
+  collections::HashSet,
+  fmt::{self, Debug},
+  sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+  },
+};
+use tokio::{
+  io::{AsyncReadExt, AsyncWriteExt},
+  sync::{broadcast, mpsc, Mutex},
+};
+use tokio_util::sync::CancellationToken;
+use uuid::Uuid;
+
+fn btclassic_error(err: impl ToString) -> ButtplugError {
+  ButtplugDeviceError::DeviceSpecificError(ButtplugDeviceSpecificError::BluetoothClassicError(
+    err.to_string(),
+  ))
+  .into()
+}
+
+pub struct BluetoothClassicDeviceImplCreator {
+  device: Device,
+  name: String,
+  specifier: DeviceSpecifier,
+}
+
+impl BluetoothClassicDeviceImplCreator {
+  pub(super) fn new(device: Device, name: &str, services: HashSet<Uuid>) -> Self {
+    Self {
+      device,
+      name: name.to_owned(),
+      specifier: DeviceSpecifier::BluetoothClassic(BluetoothClassicSpecifier::new_from_device(
+        name, services,
+      )),
+    }
+  }
+}
+
+impl Debug for BluetoothClassicDeviceImplCreator {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("BluetoothClassicDeviceImplCreator")
+      .field("name", &self.name)
+      .field("address", &self.device.address())
+      .finish()
+  }
+}
+
+#[async_trait]
+impl ButtplugDeviceImplCreator for BluetoothClassicDeviceImplCreator {
+  fn get_specifier(&self) -> DeviceSpecifier {
+    self.specifier.clone()
+  }
+
+  async fn try_create_device_impl(
+    &mut self,
+    protocol: ProtocolDefinition,
+  ) -> Result<DeviceImpl, ButtplugError> {
+    // We only get here if the protocol matched our specifier, so it has a
+    // btclassic definition.
+    let channel = protocol
+      .btclassic
+      .map(|btclassic| btclassic.channel)
+      .unwrap_or(1);
+    let device_impl_internal =
+      BluetoothClassicDeviceImpl::try_create(&self.device, channel).await?;
+    Ok(DeviceImpl::new(
+      &self.name,
+      &self.device.address().to_string(),
+      &[Endpoint::Rx, Endpoint::Tx],
+      Box::new(device_impl_internal),
+    ))
+  }
+}
+
+struct RfcommReadState {
+  address: String,
+  read_sender: mpsc::Sender<Vec<u8>>,
+  subscribed: Arc<AtomicBool>,
+  connected: Arc<AtomicBool>,
+  event_sender: broadcast::Sender<ButtplugDeviceEvent>,
+}
+
+async fn rfcomm_read_loop(
+  mut reader: OwnedReadHalf,
+  state: RfcommReadState,
+  token: CancellationToken,
+) {
+  let mut buf = [0u8; 1024];
+  loop {
+    let result = select! {
+      result = reader.read(&mut buf).fuse() => result,
+      _ = token.cancelled().fuse() => break,
+    };
+    let data = match result {
+      Ok(len) if len > 0 => buf[0..len].to_vec(),
+      Ok(_) => {
+        info!("RFCOMM connection to {} closed.", state.address);
+        break;
+      }
+      Err(err) => {
+        error!("RFCOMM read error from {}: {:?}", state.address, err);
+        break;
+      }
+    };
+    if state.subscribed.load(Ordering::SeqCst) {
+      let _ = state.event_sender.send(ButtplugDeviceEvent::Notification(
+        state.address.clone(),
+        Endpoint::Rx,
+        data,
+      ));
+    } else if state.read_sender.try_send(data).is_err() {
+      // Nobody is reading, so older data is as good as newer data here.
+      trace!(
+        "RFCOMM read buffer for {} full, dropping data.",
+        state.address
+      );
+    }
+  }
+  // If we didn't shut down from our side, the device went away.
+  if state.connected.swap(false, Ordering::SeqCst) {
+    let _ = state
+      .event_sender
+      .send(ButtplugDeviceEvent::Removed(state.address.clone()));
+  }
+}
+
+pub struct BluetoothClassicDeviceImpl {
+  address: String,
+  writer: Arc<Mutex<OwnedWriteHalf>>,
+  read_receiver: Arc<Mutex<mpsc::Receiver<Vec<u8>>>>,
+  subscribed: Arc<AtomicBool>,
+  connected: Arc<AtomicBool>,
+  event_sender: broadcast::Sender<ButtplugDeviceEvent>,
+  cancellation_token: CancellationToken,
+}
+
+impl BluetoothClassicDeviceImpl {
+  pub async fn try_create(device: &Device, channel: u8) -> Result<Self, ButtplugError> {
+    let address = device.address();
+    debug!("Connecting to {} on RFCOMM channel {}", address, channel);
+    let stream = Stream::connect(SocketAddr::new(address, channel))
+      .await
+      .map_err(btclassic_error)?;
+    let (reader, writer) = stream.into_split();
+    let (event_sender, _) = broadcast::channel(256);
+    let (read_sender, read_receiver) = mpsc::channel(256);
+    let subscribed = Arc::new(AtomicBool::new(false));
+    let connected = Arc::new(AtomicBool::new(true));
+    let cancellation_token = CancellationToken::new();
+    let read_state = RfcommReadState {
+      address: address.to_string(),
+      read_sender,
+      subscribed: subscribed.clone(),
+      connected: connected.clone(),
+      event_sender: event_sender.clone(),
+    };
+    async_manager::spawn_named(
+      "btclassic rfcomm read loop",
+      rfcomm_read_loop(reader, read_state, cancellation_token.child_token()),
+    )
+    .unwrap();
+    Ok(Self {
+      address: address.to_string(),
+      writer: Arc::new(Mutex::new(writer)),
+      read_receiver: Arc::new(Mutex::new(read_receiver)),
+      subscribed,
+      connected,
+      event_sender,
+      cancellation_token,
+    })
+  }
+}
+
+impl DeviceImplInternal for BluetoothClassicDeviceImpl {
+  fn event_stream(&self) -> broadcast::Receiver<ButtplugDeviceEvent> {
+    self.event_sender.subscribe()
+  }
+
+  fn connected(&self) -> bool {
+    self.connected.load(Ordering::SeqCst)
+  }
+
+  fn disconnect(&self) -> ButtplugResultFuture {
+    self.cancellation_token.cancel();
+    if self.connected.swap(false, Ordering::SeqCst) {
+      let _ = self
+        .event_sender
+        .send(ButtplugDeviceEvent::Removed(self.address.clone()));
+    }
+    Box::pin(future::ready(Ok(())))
+  }
+
+  /// Returns the oldest unread data from the device, or nothing if there
+  /// isn't any. Never waits.
+  fn read_value(
+    &self,
+    msg: DeviceReadCmd,
+  ) -> BoxFuture<'static, Result<RawReading, ButtplugError>> {
+    if msg.endpoint != Endpoint::Rx {
+      return ButtplugDeviceError::InvalidEndpoint(msg.endpoint).into();
+    }
+    let receiver = self.read_receiver.clone();
+    Box::pin(async move {
+      let data = receiver
+        .lock()
+        .await
+        .recv()
+        .now_or_never()
+        .flatten()
+        .unwrap_or_default();
+      Ok(RawReading::new(0, Endpoint::Rx, data))
+    })
+  }
+
+  fn write_value(&self, msg: DeviceWriteCmd) -> ButtplugResultFuture {
+    if msg.endpoint != Endpoint::Tx {
+      return ButtplugDeviceError::InvalidEndpoint(msg.endpoint).into();
+    }
+    let writer = self.writer.clone();
+    Box::pin(async move {
+      writer
+        .lock()
+        .await
+        .write_all(&msg.data)
+        .await
+        .map_err(btclassic_error)
+    })
+  }
+
+  fn subscribe(&self, msg: DeviceSubscribeCmd) -> ButtplugResultFuture {
+    if msg.endpoint != Endpoint::Rx {
+      return ButtplugDeviceError::InvalidEndpoint(msg.endpoint).into();
+    }
+    self.subscribed.store(true, Ordering::SeqCst);
+    Box::pin(future::ready(Ok(())))
+  }
+
+  fn unsubscribe(&self, msg: DeviceUnsubscribeCmd) -> ButtplugResultFuture {
+    if msg.endpoint != Endpoint::Rx {
+      return ButtplugDeviceError::InvalidEndpoint(msg.endpoint).into();
+    }
+    self.subscribed.store(false, Ordering::SeqCst);
+    Box::pin(future::ready(Ok(())))
+  }
+}
+
+impl Drop for BluetoothClassicDeviceImpl {
+  fn drop(&mut self) {
+    self.connected.store(false, Ordering::SeqCst);
+    self.cancellation_token.cancel();
+  }
+}
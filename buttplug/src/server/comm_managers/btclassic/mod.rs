@@ -0,0 +1,23 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2021 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Bluetooth Classic (RFCOMM) support, for older devices that predate LE.
+//!
+//! Only available on Linux, through BlueZ. Devices have to be paired with the
+//! system first (most of these use a fixed PIN like 0000 or 1234), after which
+//! scanning announces every paired BR/EDR device along with the services it
+//! advertises. Protocols pick devices up through the "btclassic" specifier in
+//! the device configuration, which matches on name and on the device having
+//! one of the listed services (the Serial Port Profile, by default).
+
+mod btclassic_comm_manager;
+mod btclassic_device_impl;
+
+pub use btclassic_comm_manager::{
+  BluetoothClassicCommunicationManager, BluetoothClassicCommunicationManagerBuilder,
+};
+pub use btclassic_device_impl::{BluetoothClassicDeviceImpl, BluetoothClassicDeviceImplCreator};
@@ -0,0 +1,98 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2022 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Bonding for devices that won't talk to us until they're bonded (paired)
+//! with the OS.
+//!
+//! btleplug doesn't expose pairing, and on every platform we support the OS
+//! owns it anyways: using a characteristic that needs an encrypted link makes
+//! the OS start pairing, possibly asking the user to confirm in a system
+//! dialog (Windows, macOS, iOS, Android) or through a pairing agent (BlueZ).
+//! So to bond, we read a characteristic once connected. If the OS refuses the
+//! read because the link isn't encrypted, pairing needs the user, and we tell
+//! them how to do it on their platform.
+
+use crate::{core::errors::ButtplugDeviceError, device::identity::DeviceIdentity};
+use btleplug::Error;
+use dashmap::DashSet;
+
+/// Checks the devices known to be bonded for a device.
+pub fn bonded_hint(bonded_devices: &DashSet<String>, address: &str) -> bool {
+  let identity = DeviceIdentity::new(address, None);
+  bonded_devices
+    .iter()
+    .any(|entry| identity.matches(entry.key()))
+}
+
+/// True if an error means the OS wouldn't let us use a characteristic
+/// because the device isn't bonded.
+pub fn is_bonding_error(err: &Error) -> bool {
+  if matches!(err, Error::PermissionDenied) {
+    return true;
+  }
+  // Each backend reports this differently, and mostly as strings.
+  let description = format!("{:?}", err).to_ascii_lowercase();
+  [
+    "insufficient authentication",
+    "insufficient encryption",
+    "authentication is insufficient",
+    "encryption is insufficient",
+    "notpermitted",
+    "protectionlevel",
+  ]
+  .iter()
+  .any(|needle| description.contains(needle))
+}
+
+fn bonding_instructions(address: &str) -> String {
+  if cfg!(target_os = "linux") {
+    format!(
+      "pair the device in your desktop's Bluetooth settings, or run 'bluetoothctl pair {}', then scan again.",
+      address
+    )
+  } else if cfg!(target_os = "windows") {
+    "pair the device in Settings > Bluetooth & devices, then scan again.".to_owned()
+  } else if cfg!(any(target_os = "macos", target_os = "ios")) {
+    "accept the pairing request the system shows when connecting, then scan again. If no request showed up, forget the device in Bluetooth settings and try again.".to_owned()
+  } else {
+    "pair the device in the system Bluetooth settings, then scan again.".to_owned()
+  }
+}
+
+/// Error for a device that needs the user to finish pairing.
+pub fn bonding_required_error(address: &str) -> ButtplugDeviceError {
+  ButtplugDeviceError::DeviceBondingRequired(address.to_owned(), bonding_instructions(address))
+}
+
+#[cfg(test)]
+mod test {
+  use super::{bonded_hint, bonding_required_error, is_bonding_error};
+  use crate::core::errors::ButtplugDeviceError;
+  use btleplug::Error;
+  use dashmap::DashSet;
+
+  #[test]
+  fn test_bonding_errors() {
+    assert!(is_bonding_error(&Error::PermissionDenied));
+    assert!(is_bonding_error(&Error::Other(
+      "org.bluez.Error.Failed: Insufficient Authentication".to_owned()
+    )));
+    assert!(!is_bonding_error(&Error::NotConnected));
+    assert!(matches!(
+      bonding_required_error("aa:bb:cc:dd:ee:ff"),
+      ButtplugDeviceError::DeviceBondingRequired(address, _) if address == "aa:bb:cc:dd:ee:ff"
+    ));
+  }
+
+  #[test]
+  fn test_bonded_hint() {
+    let bonded_devices = DashSet::new();
+    bonded_devices.insert("AA-BB-CC-DD-EE-FF".to_owned());
+    assert!(bonded_hint(&bonded_devices, "aa:bb:cc:dd:ee:ff"));
+    assert!(!bonded_hint(&bonded_devices, "ff:ee:dd:cc:bb:aa"));
+  }
+}
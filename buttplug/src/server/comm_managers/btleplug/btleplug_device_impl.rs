@@ -82,7 +82,8 @@ impl<T: Peripheral> ButtplugDeviceImplCreator for BtlePlugDeviceImplCreator<T> {
       let name = device.properties().local_name.unwrap();
       let address = device.properties().address.to_string();
       let (device_event_sender, _) = broadcast::channel(256);
-      // rumble calls, so this will block whatever thread it's spawned to.
+      // The event loop runs the peripheral's blocking calls on blocking
+      // threads, so it's fine to spawn it as a normal task.
       let mut event_loop = BtlePlugInternalEventLoop::new(
         self.broadcaster.subscribe(),
         device,
@@ -90,7 +91,8 @@ impl<T: Peripheral> ButtplugDeviceImplCreator for BtlePlugDeviceImplCreator<T> {
         device_receiver,
         device_event_sender.clone(),
       );
-      async_manager::spawn(
+      async_manager::spawn_named(
+        "btleplug device event loop",
         async move { event_loop.run().await }.instrument(tracing::info_span!(
           "btleplug Event Loop",
           device = tracing::field::display(&name),
@@ -122,8 +124,18 @@ impl<T: Peripheral> ButtplugDeviceImplCreator for BtlePlugDeviceImplCreator<T> {
             &info.endpoints,
             Box::new(device_internal_impl),
           );
+          if let Some(serial_number) = &info.serial_number {
+            device_impl.set_device_id(serial_number);
+          }
+          for (endpoint, write_type) in &proto.write_types {
+            device_impl.set_write_type(*endpoint, Some(*write_type));
+          }
           Ok(device_impl)
         }
+        // The user has to do something about this one, so pass it on as is.
+        ButtplugDeviceReturn::Error(ButtplugError::ButtplugDeviceError(
+          err @ ButtplugDeviceError::DeviceBondingRequired(..),
+        )) => Err(err.into()),
         // TODO It'd be nice to carry this error through as a source.
         ButtplugDeviceReturn::Error(err) => Err(
           ButtplugDeviceError::DeviceConnectionError(format!(
@@ -1,3 +1,4 @@
+use super::btleplug_bonding;
 use crate::{
   core::{errors::ButtplugDeviceError, messages, ButtplugResult},
   device::{
@@ -11,7 +12,9 @@ use crate::{
     future::{ButtplugFuture, ButtplugFutureStateShared},
   },
 };
-use btleplug::api::{CentralEvent, Characteristic, Peripheral, ValueNotification, WriteType};
+use btleplug::api::{
+  CentralEvent, CharPropFlags, Characteristic, Peripheral, ValueNotification, WriteType,
+};
 use futures::FutureExt;
 use std::collections::HashMap;
 use tokio::{
@@ -20,6 +23,10 @@ use tokio::{
 };
 use uuid::Uuid;
 
+/// Serial Number String characteristic, from the standard Device Information
+/// service.
+const SERIAL_NUMBER_STRING_UUID: Uuid = Uuid::from_u128(0x0000_2a25_0000_1000_8000_0080_5f9b_34fb);
+
 pub type DeviceReturnStateShared = ButtplugFutureStateShared<ButtplugDeviceReturn>;
 pub type DeviceReturnFuture = ButtplugFuture<ButtplugDeviceReturn>;
 
@@ -29,6 +36,33 @@ enum BtlePlugCommLoopChannelValue {
   ChannelClosed,
 }
 
+/// Picks the write type for a characteristic. If the characteristic only
+/// supports one kind of write, that's what it gets, as the OS would refuse the
+/// other one.
+fn btle_write_type(chr: &Characteristic, with_response: bool) -> WriteType {
+  let supports_with_response = chr.properties.contains(CharPropFlags::WRITE);
+  let supports_without_response = chr
+    .properties
+    .contains(CharPropFlags::WRITE_WITHOUT_RESPONSE);
+  if with_response && !supports_with_response && supports_without_response {
+    debug!(
+      "Characteristic {} only supports writes without response, ignoring requested write type.",
+      chr.uuid
+    );
+    WriteType::WithoutResponse
+  } else if !with_response && !supports_without_response && supports_with_response {
+    debug!(
+      "Characteristic {} only supports writes with response, ignoring requested write type.",
+      chr.uuid
+    );
+    WriteType::WithResponse
+  } else if with_response {
+    WriteType::WithResponse
+  } else {
+    WriteType::WithoutResponse
+  }
+}
+
 pub struct BtlePlugInternalEventLoop<T: Peripheral> {
   device: T,
   protocol: BluetoothLESpecifier,
@@ -38,7 +72,7 @@ pub struct BtlePlugInternalEventLoop<T: Peripheral> {
   endpoints: HashMap<Endpoint, Characteristic>,
 }
 
-impl<T: Peripheral> BtlePlugInternalEventLoop<T> {
+impl<T: Peripheral + 'static> BtlePlugInternalEventLoop<T> {
   pub fn new(
     mut btleplug_event_broadcaster: broadcast::Receiver<CentralEvent>,
     device: T,
@@ -48,7 +82,7 @@ impl<T: Peripheral> BtlePlugInternalEventLoop<T> {
   ) -> Self {
     let (event_sender, event_receiver) = mpsc::channel(256);
     let device_address = device.address();
-    async_manager::spawn(async move {
+    async_manager::spawn_named("btleplug device event forwarder", async move {
       while let Ok(event) = btleplug_event_broadcaster.recv().await {
         match event {
           CentralEvent::DeviceConnected(ev) => {
@@ -95,10 +129,28 @@ impl<T: Peripheral> BtlePlugInternalEventLoop<T> {
     }
   }
 
+  /// Runs a call on the peripheral on a blocking thread. btleplug's
+  /// peripheral calls all block until the OS gets back to them, which can take
+  /// seconds for connections.
+  async fn blocking<F, R>(&self, func: F) -> btleplug::Result<R>
+  where
+    F: FnOnce(&T) -> btleplug::Result<R> + Send + 'static,
+    R: Send + 'static,
+  {
+    let device = self.device.clone();
+    async_manager::spawn_blocking(move || func(&device))
+      .await
+      .unwrap_or_else(|_| {
+        Err(btleplug::Error::Other(
+          "Runtime is shutting down, cannot run bluetooth call.".to_owned(),
+        ))
+      })
+  }
+
   // TODO this should probably return Result and we should handle state filling in parent.
   async fn handle_connection(&mut self, state: &mut DeviceReturnStateShared) -> ButtplugResult {
     info!("Connecting to BTLEPlug device");
-    if let Err(err) = self.device.connect() {
+    if let Err(err) = self.blocking(|device| device.connect()).await {
       let return_err = ButtplugDeviceError::DeviceSpecificError(
         ButtplugDeviceSpecificError::BtleplugError(format!("{:?}", err)),
       );
@@ -135,7 +187,10 @@ impl<T: Peripheral> BtlePlugInternalEventLoop<T> {
     }
     // Map UUIDs to endpoints
     let mut uuid_map = HashMap::<Uuid, Endpoint>::new();
-    let chars = match self.device.discover_characteristics() {
+    let chars = match self
+      .blocking(|device| device.discover_characteristics())
+      .await
+    {
       Ok(chars) => chars,
       Err(err) => {
         error!("BTLEPlug error discovering characteristics: {:?}", err);
@@ -157,6 +212,14 @@ impl<T: Peripheral> BtlePlugInternalEventLoop<T> {
         }
       }
     }
+    if let Err(err) = self.check_bonding().await {
+      if let Err(disconnect_err) = self.blocking(|device| device.disconnect()).await {
+        error!("Error disconnecting unbonded device: {:?}", disconnect_err);
+      }
+      state.set_reply(ButtplugDeviceReturn::Error(err.clone().into()));
+      return Err(err.into());
+    }
+    let serial_number = self.read_serial_number(&chars).await;
     let os = self.output_sender.clone();
     let mut error_notification = false;
     let address = self.device.properties().address.to_string();
@@ -196,22 +259,103 @@ impl<T: Peripheral> BtlePlugInternalEventLoop<T> {
       endpoints: self.endpoints.keys().cloned().collect(),
       manufacturer_name: None,
       product_name: None,
-      serial_number: None,
+      serial_number,
     };
     state.set_reply(ButtplugDeviceReturn::Connected(device_info));
     Ok(())
   }
 
-  fn handle_write(&mut self, write_msg: &DeviceWriteCmd, state: &mut DeviceReturnStateShared) {
-    match self.endpoints.get(&write_msg.endpoint) {
-      Some(chr) => {
-        let write_type = if write_msg.write_with_response {
-          WriteType::WithResponse
+  /// Reads the serial number from the Device Information service, for devices
+  /// that have one, so identical devices can be told apart across
+  /// reconnects.
+  async fn read_serial_number(&self, chars: &[Characteristic]) -> Option<String> {
+    let chr = chars
+      .iter()
+      .find(|chr| {
+        chr.uuid == SERIAL_NUMBER_STRING_UUID && chr.properties.contains(CharPropFlags::READ)
+      })?
+      .clone();
+    match self.blocking(move |device| device.read(&chr)).await {
+      Ok(data) => {
+        // Some firmware pads the string out with nulls.
+        let serial_number = String::from_utf8_lossy(&data)
+          .trim_matches(|c: char| c == '\0' || c.is_whitespace())
+          .to_owned();
+        if serial_number.is_empty() {
+          None
         } else {
-          WriteType::WithoutResponse
-        };
-        if let Err(err) = self.device.write(&chr, &write_msg.data, write_type) {
+          Some(serial_number)
+        }
+      }
+      Err(err) => {
+        debug!("Cannot read device serial number: {:?}", err);
+        None
+      }
+    }
+  }
+
+  /// Makes sure devices that need bonding are bonded, see
+  /// [btleplug_bonding].
+  async fn check_bonding(&self) -> Result<(), ButtplugDeviceError> {
+    if !self.protocol.requires_bonding {
+      return Ok(());
+    }
+    let address = self.device.properties().address.to_string();
+    if btleplug_bonding::bonded_hint(&self.protocol.bonded_devices, &address) {
+      debug!("Device is known to be bonded, skipping bonding check.");
+      return Ok(());
+    }
+    let chr = match self
+      .endpoints
+      .values()
+      .find(|chr| chr.properties.contains(CharPropFlags::READ))
+    {
+      Some(chr) => chr.clone(),
+      None => {
+        warn!("Device needs bonding, but has no readable endpoints to bond through.");
+        return Ok(());
+      }
+    };
+    info!("Device needs bonding, checking bond.");
+    match self.blocking(move |device| device.read(&chr)).await {
+      Ok(_) => {
+        // Recorded so applications can save it, see
+        // DeviceConfigurationManager::bonded_devices.
+        info!("Device is bonded.");
+        self.protocol.bonded_devices.insert(address);
+        Ok(())
+      }
+      Err(err) if btleplug_bonding::is_bonding_error(&err) => {
+        warn!("Device is not bonded, and the OS needs the user to pair it: {:?}", err);
+        Err(btleplug_bonding::bonding_required_error(&address))
+      }
+      Err(err) => Err(ButtplugDeviceError::DeviceConnectionError(format!(
+        "BTLEPlug error checking device bond: {:?}",
+        err
+      ))),
+    }
+  }
+
+  async fn handle_write(
+    &mut self,
+    write_msg: &DeviceWriteCmd,
+    state: &mut DeviceReturnStateShared,
+  ) {
+    match self.endpoints.get(&write_msg.endpoint).cloned() {
+      Some(chr) => {
+        let write_type = btle_write_type(&chr, write_msg.write_with_response);
+        let data = write_msg.data.clone();
+        if let Err(err) = self
+          .blocking(move |device| device.write(&chr, &data, write_type))
+          .await
+        {
           error!("BTLEPlug device write error: {:?}", err);
+          state.set_reply(ButtplugDeviceReturn::Error(
+            ButtplugDeviceError::DeviceSpecificError(ButtplugDeviceSpecificError::BtleplugError(
+              format!("{:?}", err),
+            ))
+            .into(),
+          ));
         } else {
           state.set_reply(ButtplugDeviceReturn::Ok(messages::Ok::default()));
         }
@@ -222,9 +366,9 @@ impl<T: Peripheral> BtlePlugInternalEventLoop<T> {
     }
   }
 
-  fn handle_read(&mut self, read_msg: &DeviceReadCmd, state: &mut DeviceReturnStateShared) {
-    match self.endpoints.get(&read_msg.endpoint) {
-      Some(chr) => match self.device.read(&chr) {
+  async fn handle_read(&mut self, read_msg: &DeviceReadCmd, state: &mut DeviceReturnStateShared) {
+    match self.endpoints.get(&read_msg.endpoint).cloned() {
+      Some(chr) => match self.blocking(move |device| device.read(&chr)).await {
         Ok(data) => {
           trace!("Got reading: {:?}", data);
           state.set_reply(ButtplugDeviceReturn::RawReading(messages::RawReading::new(
@@ -249,14 +393,14 @@ impl<T: Peripheral> BtlePlugInternalEventLoop<T> {
     }
   }
 
-  fn handle_subscribe(
+  async fn handle_subscribe(
     &mut self,
     sub_msg: &DeviceSubscribeCmd,
     state: &mut DeviceReturnStateShared,
   ) {
-    match self.endpoints.get(&sub_msg.endpoint) {
+    match self.endpoints.get(&sub_msg.endpoint).cloned() {
       Some(chr) => {
-        if let Err(err) = self.device.subscribe(&chr) {
+        if let Err(err) = self.blocking(move |device| device.subscribe(&chr)).await {
           error!("BTLEPlug device subscribe error: {:?}", err);
         } else {
           state.set_reply(ButtplugDeviceReturn::Ok(messages::Ok::default()));
@@ -268,14 +412,14 @@ impl<T: Peripheral> BtlePlugInternalEventLoop<T> {
     }
   }
 
-  fn handle_unsubscribe(
+  async fn handle_unsubscribe(
     &mut self,
     sub_msg: &DeviceUnsubscribeCmd,
     state: &mut DeviceReturnStateShared,
   ) {
-    match self.endpoints.get(&sub_msg.endpoint) {
+    match self.endpoints.get(&sub_msg.endpoint).cloned() {
       Some(chr) => {
-        if let Err(err) = self.device.subscribe(&chr) {
+        if let Err(err) = self.blocking(move |device| device.subscribe(&chr)).await {
           error!("BTLEPlug device unsubscribe error: {:?}", err);
         } else {
           state.set_reply(ButtplugDeviceReturn::Ok(messages::Ok::default()));
@@ -298,20 +442,20 @@ impl<T: Peripheral> BtlePlugInternalEventLoop<T> {
       }
       ButtplugDeviceCommand::Message(raw_msg) => match raw_msg {
         DeviceImplCommand::Write(write_msg) => {
-          self.handle_write(write_msg, state);
+          self.handle_write(write_msg, state).await;
         }
         DeviceImplCommand::Read(read_msg) => {
-          self.handle_read(read_msg, state);
+          self.handle_read(read_msg, state).await;
         }
         DeviceImplCommand::Subscribe(sub_msg) => {
-          self.handle_subscribe(sub_msg, state);
+          self.handle_subscribe(sub_msg, state).await;
         }
         DeviceImplCommand::Unsubscribe(sub_msg) => {
-          self.handle_unsubscribe(sub_msg, state);
+          self.handle_unsubscribe(sub_msg, state).await;
         }
       },
       ButtplugDeviceCommand::Disconnect => {
-        if let Err(e) = self.device.disconnect() {
+        if let Err(e) = self.blocking(|device| device.disconnect()).await {
           error!(
             "Error disconnecting device {:?}: {:?}",
             self.device.properties().local_name,
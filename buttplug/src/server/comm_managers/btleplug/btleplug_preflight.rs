@@ -0,0 +1,104 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2021 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Checks for common permission problems before trying to scan on linux.
+//!
+//! The bluez backend of btleplug talks to the adapter over raw HCI sockets,
+//! which requires either running as root or having the CAP_NET_RAW and
+//! CAP_NET_ADMIN capabilities set on the executable. Without them, scanning
+//! fails with an opaque IO error, so we look for the problem up front and
+//! tell the user how to fix it.
+
+use crate::core::errors::ButtplugDeviceError;
+use std::{env, fs};
+
+const CAP_NET_ADMIN: u32 = 12;
+const CAP_NET_RAW: u32 = 13;
+
+/// Parses the effective capability set out of the contents of
+/// /proc/self/status.
+fn effective_capabilities(status: &str) -> Option<u64> {
+  status
+    .lines()
+    .find(|line| line.starts_with("CapEff:"))
+    .and_then(|line| u64::from_str_radix(line["CapEff:".len()..].trim(), 16).ok())
+}
+
+fn has_capability(capabilities: u64, capability: u32) -> bool {
+  capabilities & (1 << capability) != 0
+}
+
+fn check_capabilities(status: &str) -> Result<(), ButtplugDeviceError> {
+  let capabilities = match effective_capabilities(status) {
+    Some(caps) => caps,
+    None => {
+      // If we can't figure out our capabilities, let btleplug try anyways.
+      warn!("Cannot read process capabilities, skipping bluetooth permission check.");
+      return Ok(());
+    }
+  };
+  let missing: Vec<&str> = [
+    (CAP_NET_RAW, "cap_net_raw"),
+    (CAP_NET_ADMIN, "cap_net_admin"),
+  ]
+  .iter()
+  .filter(|(cap, _)| !has_capability(capabilities, *cap))
+  .map(|(_, name)| *name)
+  .collect();
+  if missing.is_empty() {
+    return Ok(());
+  }
+  let executable = env::current_exe()
+    .map(|path| path.display().to_string())
+    .unwrap_or_else(|_| "<path to executable>".to_owned());
+  Err(ButtplugDeviceError::BluetoothPermissionError(
+    format!(
+      "Process is missing the {} capabilities needed to access bluetooth adapters.",
+      missing.join(" and ")
+    ),
+    format!(
+      "run 'sudo setcap cap_net_raw,cap_net_admin+eip {}', then restart the application.",
+      executable
+    ),
+  ))
+}
+
+/// Returns an error describing what the user needs to do if the process
+/// isn't allowed to use bluetooth.
+pub fn check_bluetooth_permissions() -> Result<(), ButtplugDeviceError> {
+  match fs::read_to_string("/proc/self/status") {
+    Ok(status) => check_capabilities(&status),
+    Err(err) => {
+      warn!(
+        "Cannot read /proc/self/status ({}), skipping bluetooth permission check.",
+        err
+      );
+      Ok(())
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::check_capabilities;
+  use crate::core::errors::ButtplugDeviceError;
+
+  #[test]
+  fn test_capability_check() {
+    let root = "Name:\ttest\nCapEff:\t000001ffffffffff\n";
+    assert!(check_capabilities(root).is_ok());
+    let setcap = "Name:\ttest\nCapEff:\t0000000000003000\n";
+    assert!(check_capabilities(setcap).is_ok());
+    let user = "Name:\ttest\nCapEff:\t0000000000000000\n";
+    assert!(matches!(
+      check_capabilities(user),
+      Err(ButtplugDeviceError::BluetoothPermissionError(_, _))
+    ));
+    // Missing line means we can't tell, so let scanning go ahead.
+    assert!(check_capabilities("Name:\ttest\n").is_ok());
+  }
+}
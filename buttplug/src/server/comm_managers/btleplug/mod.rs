@@ -1,13 +1,18 @@
+mod btleplug_bonding;
 mod btleplug_device_impl;
 mod btleplug_internal;
+#[cfg(target_os = "linux")]
+mod btleplug_preflight;
 
 use crate::{
   core::{errors::ButtplugDeviceError, ButtplugResultFuture},
   server::comm_managers::{
     DeviceCommunicationEvent, DeviceCommunicationManager, DeviceCommunicationManagerBuilder,
+    DeviceCommunicationManagerCapabilities, DeviceCommunicationTransport,
   },
-  util::async_manager,
+  util::{async_manager, blocking::Blocking, logging},
 };
+use futures::future::{BoxFuture, FutureExt, Shared};
 use std::{
   sync::{
     atomic::{AtomicBool, Ordering},
@@ -26,7 +31,6 @@ use btleplug::corebluetooth::{adapter::Adapter, manager::Manager};
 use btleplug::winrtble::{adapter::Adapter, manager::Manager};
 use btleplug_device_impl::BtlePlugDeviceImplCreator;
 use dashmap::DashMap;
-use tokio::runtime::Handle;
 
 #[derive(Default)]
 pub struct BtlePlugCommunicationManagerBuilder {
@@ -43,11 +47,15 @@ impl DeviceCommunicationManagerBuilder for BtlePlugCommunicationManagerBuilder {
   }
 }
 
+/// The first bluetooth adapter, once btleplug has found it, or None if there
+/// isn't one.
+type AdapterFuture = Shared<BoxFuture<'static, Option<Adapter>>>;
+
 pub struct BtlePlugCommunicationManager {
   // BtlePlug says to only have one manager at a time, so we'll have the comm
   // manager hold it.
-  manager: Manager,
-  adapter: Option<Adapter>,
+  _manager: Blocking<Option<Manager>>,
+  adapter: AdapterFuture,
   adapter_event_sender: broadcast::Sender<CentralEvent>,
   tried_addresses: Arc<DashMap<BDAddr, ()>>,
   connected_addresses: Arc<DashMap<BDAddr, ()>>,
@@ -60,7 +68,6 @@ impl BtlePlugCommunicationManager {
   fn new(device_sender: Sender<DeviceCommunicationEvent>) -> Self {
     // At this point, no one will be subscribed, so just drop the receiver.
     let (adapter_event_sender, _) = broadcast::channel(256);
-    let manager = Manager::new().unwrap();
     let tried_addresses = Arc::new(DashMap::new());
     let tried_addresses_clone = tried_addresses.clone();
     let mut adapter_event_handler = adapter_event_sender.subscribe();
@@ -69,25 +76,31 @@ impl BtlePlugCommunicationManager {
     let connected_addresses_clone = connected_addresses.clone();
     let scanning_notifier = Arc::new(Notify::new());
     let scanning_notifier_clone = scanning_notifier.clone();
-    async_manager::spawn(async move {
+    async_manager::spawn_named("btleplug adapter event loop", async move {
       while let Ok(event) = adapter_event_handler.recv().await {
         match event {
-          CentralEvent::DeviceDiscovered(_) => {
+          CentralEvent::DeviceDiscovered(addr) => {
+            // Addresses need to be known to the redactor before they show
+            // up in any log output.
+            logging::register_identifier(&addr.to_string());
             debug!("BTLEPlug Device discovered: {:?}", event);
             scanning_notifier_clone.notify_waiters();
           }
-          CentralEvent::DeviceUpdated(_) => {
+          CentralEvent::DeviceUpdated(addr) => {
             // We will get a LOT of these messages due to RSSI updates, but
             // they'll also happen if we got RSSI first then got an
             // advertisement packet with a name update.
+            logging::register_identifier(&addr.to_string());
             trace!("BTLEPlug Device updated: {:?}", event);
             scanning_notifier_clone.notify_waiters();
           }
           CentralEvent::DeviceConnected(addr) => {
+            logging::register_identifier(&addr.to_string());
             info!("BTLEPlug Device connected: {:?}", addr);
             connected_addresses_clone.insert(addr, ());
           }
           CentralEvent::DeviceDisconnected(addr) => {
+            logging::register_identifier(&addr.to_string());
             debug!("BTLEPlug Device disconnected: {:?}", event);
             connected_addresses_clone.remove(&addr);
             tried_addresses_clone.remove(&addr);
@@ -98,54 +111,67 @@ impl BtlePlugCommunicationManager {
     })
     .unwrap();
 
-    let mut comm_mgr = Self {
-      manager,
-      adapter: None,
+    let manager = Blocking::new(None);
+    let adapter = Self::setup_adapter(manager.clone(), adapter_event_sender.clone());
+    Self {
+      _manager: manager,
+      adapter,
       adapter_event_sender,
       connected_addresses,
       tried_addresses,
       device_sender,
       scanning_notifier,
       is_scanning: Arc::new(AtomicBool::new(false)),
-    };
-    comm_mgr.setup_adapter();
-    comm_mgr
-  }
-
-  fn get_central(&self) -> Option<Adapter> {
-    let adapters = self.manager.adapters().unwrap();
-    if adapters.is_empty() {
-      return None;
     }
+  }
 
-    let adapter = adapters.into_iter().next().unwrap();
-
-    return Some(adapter);
+  /// Brings up the manager and the first adapter, and starts forwarding its
+  /// events. btleplug's calls all block, so this runs on a blocking thread,
+  /// and anything needing the adapter waits on the returned future.
+  fn setup_adapter(
+    manager: Blocking<Option<Manager>>,
+    event_sender: broadcast::Sender<CentralEvent>,
+  ) -> AdapterFuture {
+    let setup = async move {
+      let adapter = manager
+        .run(|manager| {
+          let manager = manager.get_or_insert_with(|| Manager::new().unwrap());
+          manager.adapters().unwrap().into_iter().next()
+        })
+        .await
+        .ok()
+        .flatten()?;
+      let receiver = adapter.event_receiver().unwrap();
+      // Since this is an std channel receiver, every recv blocks, and it's
+      // waited on for as long as the adapter's around, so it gets its own
+      // thread instead of tying up one of the runtime's blocking threads.
+      thread::spawn(move || {
+        while let Ok(event) = receiver.recv() {
+          if event_sender.receiver_count() > 0 {
+            let _ = event_sender.send(event);
+          }
+        }
+      });
+      Some(adapter)
+    };
+    async_manager::spawn_with_handle(setup)
+      .unwrap()
+      .boxed()
+      .shared()
   }
+}
 
-  fn setup_adapter(&mut self) {
-    let maybe_adapter = self.get_central();
-    if maybe_adapter.is_none() {
-      return;
+impl BtlePlugCommunicationManager {
+  fn unavailable_reason(&self) -> Option<String> {
+    // Until setup's done, assume there'll be an adapter.
+    if let Some(None) = self.adapter.peek() {
+      return Some("No Bluetooth adapter found. Make sure Bluetooth is turned on, or plug in a Bluetooth dongle.".to_owned());
     }
-    let adapter = maybe_adapter.unwrap();
-    let receiver = adapter.event_receiver().unwrap();
-    self.adapter = Some(adapter);
-    let event_sender = self.adapter_event_sender.clone();
-    let handle = Handle::current();
-    thread::spawn(move || {
-      // Since this is an std channel receiver, it's mpsc. That means we don't
-      // have clone or sync. Therefore we have to wrap it in its own thread for
-      // now and block the async calls instead.
-      while let Ok(event) = receiver.recv() {
-        let event_broadcaster_clone = event_sender.clone();
-        if event_broadcaster_clone.receiver_count() > 0 {
-          handle.spawn(async move {
-            let _ = event_broadcaster_clone.send(event);
-          });
-        }
-      }
-    });
+    #[cfg(target_os = "linux")]
+    if let Err(err) = btleplug_preflight::check_bluetooth_permissions() {
+      return Some(err.to_string());
+    }
+    None
   }
 }
 
@@ -154,40 +180,71 @@ impl DeviceCommunicationManager for BtlePlugCommunicationManager {
     "BtlePlugCommunicationManager"
   }
 
+  fn capabilities(&self) -> DeviceCommunicationManagerCapabilities {
+    DeviceCommunicationManagerCapabilities {
+      transport: DeviceCommunicationTransport::Bluetooth,
+      supports_hotplug: true,
+      // Linux needs BlueZ/DBus access, macOS needs the app to be granted
+      // Bluetooth access.
+      requires_permissions: cfg!(any(target_os = "linux", target_os = "macos")),
+      unavailable_reason: self.unavailable_reason(),
+    }
+  }
+
   fn start_scanning(&self) -> ButtplugResultFuture {
     // get the first bluetooth adapter
     debug!("Bringing up adapter.");
-    // TODO What happens if we don't have a radio?
-    if self.adapter.is_none() {
-      warn!("No adapter, can't scan.");
-      return ButtplugDeviceError::UnhandledCommand(
-        "Cannot scan, no bluetooth adapters found".to_owned(),
-      )
-      .into();
-    }
+    let adapter = self.adapter.clone();
     let device_sender = self.device_sender.clone();
     let scanning_notifier = self.scanning_notifier.clone();
     let is_scanning = self.is_scanning.clone();
 
-    let central = self.adapter.clone().unwrap();
     let adapter_event_sender_clone = self.adapter_event_sender.clone();
     let tried_addresses_handler = self.tried_addresses.clone();
     let connected_addresses_handler = self.connected_addresses.clone();
     Box::pin(async move {
+      let central = match adapter.await {
+        Some(central) => central,
+        None => {
+          warn!("No adapter, can't scan.");
+          return Err(
+            ButtplugDeviceError::UnhandledCommand(
+              "Cannot scan, no bluetooth adapters found".to_owned(),
+            )
+            .into(),
+          );
+        }
+      };
+      #[cfg(target_os = "linux")]
+      if let Err(err) = btleplug_preflight::check_bluetooth_permissions() {
+        error!("Bluetooth permission check failed: {}", err);
+        return Err(err.into());
+      }
       info!("Starting scan.");
-      if let Err(err) = central.start_scan() {
-        // TODO Explain the setcap issue on linux here.
-        return Err(ButtplugDeviceError::DevicePermissionError(format!("BTLEPlug cannot start scanning. This may be a permissions error (on linux) or an issue with finding the radio. Reason: {}", err)).into());
+      let scan_central = central.clone();
+      let scan_result = async_manager::spawn_blocking(move || scan_central.start_scan())
+        .await
+        .map_err(|_| {
+          ButtplugDeviceError::DeviceConnectionError(
+            "Cannot start scanning, runtime is shutting down.".to_owned(),
+          )
+        })?;
+      if let Err(err) = scan_result {
+        // Permission problems on linux should have been caught by the
+        // preflight check, so this is most likely an issue with the radio.
+        return Err(ButtplugDeviceError::DevicePermissionError(format!("BTLEPlug cannot start scanning. This may be a permissions error or an issue with finding the radio. Reason: {}", err)).into());
       }
       is_scanning.store(true, Ordering::SeqCst);
-      async_manager::spawn(async move {
+      async_manager::spawn_named("btleplug scanning", async move {
         // When stop_scanning is called, this will get false and stop the
         // task.
         while is_scanning.load(Ordering::SeqCst) {
           for p in central.peripherals() {
+            logging::register_identifier(&p.properties().address.to_string());
             // If a device has no discernable name, we can't do anything
             // with it, just ignore it.
             if let Some(name) = p.properties().local_name {
+              logging::register_identifier(&name);
               let span = info_span!(
                 "btleplug enumeration",
                 address = tracing::field::display(p.properties().address),
@@ -237,7 +294,11 @@ impl DeviceCommunicationManager for BtlePlugCommunicationManager {
           }
           scanning_notifier.notified().await;
         }
-        central.stop_scan().unwrap();
+        match async_manager::spawn_blocking(move || central.stop_scan()).await {
+          Ok(Ok(())) => {}
+          Ok(Err(err)) => error!("BTLEPlug cannot stop scanning: {:?}", err),
+          Err(_) => error!("BTLEPlug cannot stop scanning, runtime is shutting down."),
+        }
         debug!("BTLEPlug scanning finished.");
         if device_sender
           .send(DeviceCommunicationEvent::ScanningFinished)
@@ -283,56 +344,3 @@ impl Drop for BtlePlugCommunicationManager {
     }
   }
 }
-
-#[cfg(test)]
-mod test {
-  use super::BtlePlugCommunicationManager;
-  use crate::{
-    server::comm_managers::{
-      DeviceCommunicationEvent, DeviceCommunicationManager,
-    },
-    util::async_manager,
-  };
-  use tokio::sync::mpsc::channel;
-
-  // Ignored because it requires a device. Should probably just be a manual integration test.
-  #[test]
-  #[ignore]
-  pub fn test_btleplug() {
-    async_manager::block_on(async move {
-      let (sender, mut receiver) = channel(256);
-      let mgr = BtlePlugCommunicationManager::new(sender);
-      mgr.start_scanning().await.unwrap();
-      loop {
-        match receiver.recv().await.unwrap() {
-          DeviceCommunicationEvent::DeviceFound {
-            name: _,
-            address: _,
-            creator: _device,
-          } => {
-            info!("Got device!");
-            info!("Sending message!");
-            // TODO since we don't return full devices as this point
-            // anymore, we need to find some other way to test this.
-            //
-            // match device
-            //     .parse_message(
-            //         &VibrateCmd::new(1, vec![VibrateSubcommand::new(0, 0.5)]).into(),
-            //     )
-            //     .await
-            // {
-            //     Ok(msg) => match msg {
-            //         ButtplugMessageUnion::Ok(_) => info!("Returned Ok"),
-            //         _ => info!("Returned something other than ok"),
-            //     },
-            //     Err(_) => {
-            //         assert!(false, "Error returned from parse message");
-            //     }
-            // }
-          }
-          _ => unreachable!("Shouldn't get other message types!"),
-        }
-      }
-    });
-  }
-}
@@ -0,0 +1,96 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2021 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+use crate::{
+  core::errors::{ButtplugDeviceError, ButtplugError},
+  server::comm_managers::ButtplugDeviceSpecificError,
+};
+use serde::de::DeserializeOwned;
+
+fn http_error(message: String) -> ButtplugError {
+  ButtplugDeviceError::DeviceSpecificError(ButtplugDeviceSpecificError::HttpError(message)).into()
+}
+
+/// A request to an HTTP device API.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HttpRequest {
+  pub method: reqwest::Method,
+  pub url: String,
+  pub headers: Vec<(String, String)>,
+  pub body: Option<Vec<u8>>,
+}
+
+impl HttpRequest {
+  pub fn new(method: reqwest::Method, url: &str) -> Self {
+    Self {
+      method,
+      url: url.to_owned(),
+      headers: vec![],
+      body: None,
+    }
+  }
+
+  pub fn get(url: &str) -> Self {
+    Self::new(reqwest::Method::GET, url)
+  }
+
+  pub fn put(url: &str) -> Self {
+    Self::new(reqwest::Method::PUT, url)
+  }
+
+  pub fn header(mut self, name: &str, value: &str) -> Self {
+    self.headers.push((name.to_owned(), value.to_owned()));
+    self
+  }
+
+  pub fn json_body(mut self, body: Vec<u8>) -> Self {
+    self.body = Some(body);
+    self.header("content-type", "application/json")
+  }
+}
+
+/// Sends requests for HTTP devices, turning transport, status and parse
+/// failures into ButtplugErrors.
+#[derive(Debug, Clone, Default)]
+pub struct HttpClient {
+  client: reqwest::Client,
+}
+
+impl HttpClient {
+  /// Sends the request, returning the reply body. Non-success statuses are
+  /// errors.
+  pub async fn send(&self, request: HttpRequest) -> Result<Vec<u8>, ButtplugError> {
+    let mut builder = self.client.request(request.method, &request.url);
+    for (name, value) in &request.headers {
+      builder = builder.header(name.as_str(), value.as_str());
+    }
+    if let Some(body) = request.body {
+      builder = builder.body(body);
+    }
+    let reply = builder
+      .send()
+      .await
+      .and_then(|res| res.error_for_status())
+      .map_err(|err| http_error(err.to_string()))?;
+    let body = reply
+      .bytes()
+      .await
+      .map_err(|err| http_error(err.to_string()))?;
+    Ok(body.to_vec())
+  }
+
+  /// Sends the request and parses the reply body as JSON.
+  pub async fn send_json<T>(&self, request: HttpRequest) -> Result<T, ButtplugError>
+  where
+    T: DeserializeOwned,
+  {
+    let url = request.url.clone();
+    let body = self.send(request).await?;
+    serde_json::from_slice(&body)
+      .map_err(|err| http_error(format!("Cannot parse reply from {}: {}", url, err)))
+  }
+}
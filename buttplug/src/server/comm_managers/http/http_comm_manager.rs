@@ -0,0 +1,461 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2021 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+use super::{
+  http_client::{HttpClient, HttpRequest},
+  http_device_impl::HttpDeviceImplCreator,
+};
+use crate::{
+  core::{
+    errors::{ButtplugDeviceError, ButtplugError},
+    ButtplugResultFuture,
+  },
+  device::{configuration_manager::DeviceSpecifier, ButtplugDeviceEvent, DeviceWriteCmd, Endpoint},
+  server::comm_managers::{
+    DeviceCommunicationEvent, DeviceCommunicationManager, DeviceCommunicationManagerBuilder,
+    DeviceCommunicationManagerCapabilities, DeviceCommunicationTransport,
+  },
+  util::async_manager,
+};
+use async_trait::async_trait;
+use dashmap::DashMap;
+use futures::{future, select, FutureExt};
+use std::{
+  collections::{HashMap, HashSet},
+  sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+  },
+  time::Duration,
+};
+use tokio::sync::{broadcast, mpsc::Sender, Notify};
+use tracing_futures::Instrument;
+
+const HTTP_DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A device reachable through an HTTP API, as reported by an
+/// [HttpDeviceSource] poll.
+#[derive(Debug, Clone)]
+pub struct HttpDeviceInfo {
+  /// Name sent along with DeviceFound.
+  pub name: String,
+  /// Unique address for the device. Devices are tracked across polls by
+  /// address, so it should stay stable.
+  pub address: String,
+  /// Used to pick the protocol for the device.
+  pub specifier: DeviceSpecifier,
+  /// Base URL for requests to the device. Endpoint paths are appended to it.
+  pub base_url: String,
+  /// Headers sent with every request to the device (tokens, etc).
+  pub headers: Vec<(String, String)>,
+  /// URL paths for endpoints. Paths from the "http" specifier in the device
+  /// configuration are added to these when the device is created.
+  pub endpoints: HashMap<Endpoint, String>,
+  /// Values from the last poll. Reads of these endpoints return them instead
+  /// of making a request.
+  pub readings: HashMap<Endpoint, Vec<u8>>,
+}
+
+impl HttpDeviceInfo {
+  pub fn new(name: &str, address: &str, specifier: DeviceSpecifier, base_url: &str) -> Self {
+    Self {
+      name: name.to_owned(),
+      address: address.to_owned(),
+      specifier,
+      base_url: base_url.trim_end_matches('/').to_owned(),
+      headers: vec![],
+      endpoints: HashMap::new(),
+      readings: HashMap::new(),
+    }
+  }
+
+  pub fn header(mut self, name: &str, value: &str) -> Self {
+    self.headers.push((name.to_owned(), value.to_owned()));
+    self
+  }
+
+  pub fn endpoint(mut self, endpoint: Endpoint, path: &str) -> Self {
+    self.endpoints.insert(endpoint, path.to_owned());
+    self
+  }
+
+  pub fn reading(mut self, endpoint: Endpoint, data: Vec<u8>) -> Self {
+    self.readings.insert(endpoint, data);
+    self
+  }
+
+  /// Builds a request for a path on the device, with the device's headers.
+  pub fn request(&self, method: reqwest::Method, path: &str) -> HttpRequest {
+    let mut request = HttpRequest::new(method, &format!("{}{}", self.base_url, path));
+    request.headers = self.headers.clone();
+    request
+  }
+
+  pub(super) fn endpoint_path(&self, endpoint: Endpoint) -> Result<&String, ButtplugError> {
+    self
+      .endpoints
+      .get(&endpoint)
+      .ok_or_else(|| ButtplugDeviceError::InvalidEndpoint(endpoint).into())
+  }
+}
+
+/// Finds devices behind an HTTP API, and maps device writes to requests.
+#[async_trait]
+pub trait HttpDeviceSource: Send + Sync {
+  /// Name of the comm manager built around this source.
+  fn name(&self) -> &'static str;
+
+  /// Returns every device currently available. Called every poll interval
+  /// while scanning, or while any device from this source is connected.
+  /// Connected devices missing from the list are removed. Errors leave all
+  /// devices as they are, so sources should only return one if they know
+  /// nothing about their devices.
+  async fn poll(
+    &self,
+    client: &HttpClient,
+    is_scanning: bool,
+  ) -> Result<Vec<HttpDeviceInfo>, ButtplugError>;
+
+  /// Builds the request for a write to a device. By default, the data is PUT
+  /// as a JSON body to the endpoint's path.
+  fn write_request(
+    &self,
+    device: &HttpDeviceInfo,
+    msg: &DeviceWriteCmd,
+  ) -> Result<HttpRequest, ButtplugError> {
+    let path = device.endpoint_path(msg.endpoint)?;
+    let request = device.request(reqwest::Method::PUT, path);
+    if msg.data.is_empty() {
+      Ok(request)
+    } else {
+      Ok(request.json_body(msg.data.clone()))
+    }
+  }
+}
+
+/// Set on a tracked device once a device impl has been created for it.
+pub(super) struct HttpDeviceConnection {
+  pub connected: Arc<AtomicBool>,
+  pub event_sender: broadcast::Sender<ButtplugDeviceEvent>,
+}
+
+pub(super) struct HttpTrackedDevice {
+  pub info: HttpDeviceInfo,
+  /// True once DeviceFound has been sent, until the device is disconnected.
+  pub announced: bool,
+  pub connection: Option<HttpDeviceConnection>,
+}
+
+/// Devices seen in the last poll, keyed by address.
+pub(super) type HttpDeviceMap = Arc<DashMap<String, HttpTrackedDevice>>;
+
+pub struct HttpDeviceCommunicationManagerBuilder {
+  sender: Option<Sender<DeviceCommunicationEvent>>,
+  source: Arc<dyn HttpDeviceSource>,
+  poll_interval: Duration,
+}
+
+impl HttpDeviceCommunicationManagerBuilder {
+  pub fn new<T>(source: T) -> Self
+  where
+    T: HttpDeviceSource + 'static,
+  {
+    Self {
+      sender: None,
+      source: Arc::new(source),
+      poll_interval: HTTP_DEFAULT_POLL_INTERVAL,
+    }
+  }
+
+  pub fn poll_interval(mut self, interval: Duration) -> Self {
+    self.poll_interval = interval;
+    self
+  }
+}
+
+impl DeviceCommunicationManagerBuilder for HttpDeviceCommunicationManagerBuilder {
+  fn set_event_sender(&mut self, sender: Sender<DeviceCommunicationEvent>) {
+    self.sender = Some(sender)
+  }
+
+  fn finish(mut self) -> Box<dyn DeviceCommunicationManager> {
+    Box::new(HttpDeviceCommunicationManager::new(
+      self.sender.take().unwrap(),
+      self.source,
+      self.poll_interval,
+    ))
+  }
+}
+
+pub struct HttpDeviceCommunicationManager {
+  name: &'static str,
+  sender: Sender<DeviceCommunicationEvent>,
+  is_scanning: Arc<AtomicBool>,
+  scan_notifier: Arc<Notify>,
+  shutdown_notifier: Arc<Notify>,
+}
+
+impl HttpDeviceCommunicationManager {
+  fn new(
+    sender: Sender<DeviceCommunicationEvent>,
+    source: Arc<dyn HttpDeviceSource>,
+    poll_interval: Duration,
+  ) -> Self {
+    let name = source.name();
+    let is_scanning = Arc::new(AtomicBool::new(false));
+    let scan_notifier = Arc::new(Notify::new());
+    let shutdown_notifier = Arc::new(Notify::new());
+    let poll_loop = http_poll_loop(
+      source,
+      sender.clone(),
+      poll_interval,
+      is_scanning.clone(),
+      scan_notifier.clone(),
+      shutdown_notifier.clone(),
+    );
+    async_manager::spawn_named(
+      "http device poll loop",
+      poll_loop.instrument(tracing::info_span!("HTTP Device Poll Loop", name)),
+    )
+    .unwrap();
+    Self {
+      name,
+      sender,
+      is_scanning,
+      scan_notifier,
+      shutdown_notifier,
+    }
+  }
+}
+
+/// Applies a poll result: removes devices that went away, and announces new
+/// ones if we're scanning.
+async fn update_devices(
+  source: &Arc<dyn HttpDeviceSource>,
+  client: &HttpClient,
+  sender: &Sender<DeviceCommunicationEvent>,
+  devices: &HttpDeviceMap,
+  found: Vec<HttpDeviceInfo>,
+  is_scanning: bool,
+) {
+  let found_addresses: HashSet<String> = found.iter().map(|info| info.address.clone()).collect();
+  devices.retain(|address, tracked| {
+    if found_addresses.contains(address) {
+      return true;
+    }
+    if let Some(connection) = &tracked.connection {
+      info!("HTTP device {} went away, removing.", address);
+      connection.connected.store(false, Ordering::SeqCst);
+      let _ = connection
+        .event_sender
+        .send(ButtplugDeviceEvent::Removed(address.clone()));
+    }
+    false
+  });
+  let mut announcements = vec![];
+  for info in found {
+    let mut tracked = devices
+      .entry(info.address.clone())
+      .or_insert_with(|| HttpTrackedDevice {
+        info: info.clone(),
+        announced: false,
+        connection: None,
+      });
+    if is_scanning && !tracked.announced {
+      tracked.announced = true;
+      announcements.push(info.clone());
+    }
+    tracked.info = info;
+  }
+  for info in announcements {
+    let creator = Box::new(HttpDeviceImplCreator::new(
+      source.clone(),
+      client.clone(),
+      devices.clone(),
+      &info,
+    ));
+    if sender
+      .send(DeviceCommunicationEvent::DeviceFound {
+        name: info.name.clone(),
+        address: info.address.clone(),
+        creator,
+      })
+      .await
+      .is_err()
+    {
+      error!("Device manager disappeared, cannot send HTTP device found.");
+    }
+  }
+}
+
+async fn http_poll_loop(
+  source: Arc<dyn HttpDeviceSource>,
+  sender: Sender<DeviceCommunicationEvent>,
+  poll_interval: Duration,
+  is_scanning: Arc<AtomicBool>,
+  scan_notifier: Arc<Notify>,
+  shutdown_notifier: Arc<Notify>,
+) {
+  let client = HttpClient::default();
+  let devices: HttpDeviceMap = Arc::new(DashMap::new());
+  loop {
+    select! {
+      _ = async_manager::sleep(poll_interval).fuse() => {},
+      _ = scan_notifier.notified().fuse() => {},
+      _ = shutdown_notifier.notified().fuse() => break,
+    }
+    let scanning = is_scanning.load(Ordering::SeqCst);
+    // Only bother the API if someone is going to use the answer.
+    if !scanning && !devices.iter().any(|tracked| tracked.connection.is_some()) {
+      continue;
+    }
+    match source.poll(&client, scanning).await {
+      Ok(found) => update_devices(&source, &client, &sender, &devices, found, scanning).await,
+      Err(err) => error!("Error polling {}: {}", source.name(), err),
+    }
+  }
+  info!("Exiting HTTP device poll loop.");
+}
+
+impl DeviceCommunicationManager for HttpDeviceCommunicationManager {
+  fn name(&self) -> &'static str {
+    self.name
+  }
+
+  fn capabilities(&self) -> DeviceCommunicationManagerCapabilities {
+    DeviceCommunicationManagerCapabilities {
+      transport: DeviceCommunicationTransport::Network,
+      // Devices are picked up whenever a poll sees them.
+      supports_hotplug: true,
+      requires_permissions: false,
+      unavailable_reason: None,
+    }
+  }
+
+  fn start_scanning(&self) -> ButtplugResultFuture {
+    self.is_scanning.store(true, Ordering::SeqCst);
+    // Poll now, instead of waiting out the interval.
+    self.scan_notifier.notify_one();
+    Box::pin(future::ready(Ok(())))
+  }
+
+  fn stop_scanning(&self) -> ButtplugResultFuture {
+    if self.is_scanning.swap(false, Ordering::SeqCst) {
+      let sender = self.sender.clone();
+      Box::pin(async move {
+        if sender
+          .send(DeviceCommunicationEvent::ScanningFinished)
+          .await
+          .is_err()
+        {
+          error!("Error sending scanning finished.");
+        }
+        Ok(())
+      })
+    } else {
+      Box::pin(future::ready(Ok(())))
+    }
+  }
+
+  fn scanning_status(&self) -> Arc<AtomicBool> {
+    self.is_scanning.clone()
+  }
+}
+
+impl Drop for HttpDeviceCommunicationManager {
+  fn drop(&mut self) {
+    self.is_scanning.store(false, Ordering::SeqCst);
+    // The poll loop may be in the middle of a poll rather than waiting on the
+    // notifier, so store a permit instead of only waking current waiters.
+    self.shutdown_notifier.notify_one();
+  }
+}
+
+#[cfg(all(test, feature = "server"))]
+mod test {
+  use super::*;
+  use crate::device::{
+    configuration_manager::{HTTPSpecifier, ProtocolDefinition},
+    ButtplugDeviceImplCreator, DeviceReadCmd,
+  };
+  use std::sync::Mutex;
+  use tokio::sync::mpsc;
+
+  struct TestSource {
+    devices: Arc<Mutex<Vec<HttpDeviceInfo>>>,
+  }
+
+  #[async_trait]
+  impl HttpDeviceSource for TestSource {
+    fn name(&self) -> &'static str {
+      "TestHttpSource"
+    }
+
+    async fn poll(
+      &self,
+      _client: &HttpClient,
+      _is_scanning: bool,
+    ) -> Result<Vec<HttpDeviceInfo>, ButtplugError> {
+      Ok(self.devices.lock().unwrap().clone())
+    }
+  }
+
+  #[test]
+  pub fn test_http_device_lifetime() {
+    async_manager::block_on(async move {
+      let info = HttpDeviceInfo::new(
+        "Test HTTP Device",
+        "http-test-device",
+        DeviceSpecifier::HTTP(HTTPSpecifier::new_from_name("Test HTTP Device")),
+        "http://127.0.0.1:1",
+      )
+      .reading(Endpoint::Rx, vec![42]);
+      let devices = Arc::new(Mutex::new(vec![info]));
+      let (sender, mut receiver) = mpsc::channel(256);
+      let mut builder = HttpDeviceCommunicationManagerBuilder::new(TestSource {
+        devices: devices.clone(),
+      })
+      .poll_interval(Duration::from_millis(10));
+      builder.set_event_sender(sender);
+      let manager = builder.finish();
+      manager.start_scanning().await.unwrap();
+      let mut creator = match receiver.recv().await.unwrap() {
+        DeviceCommunicationEvent::DeviceFound {
+          address, creator, ..
+        } => {
+          assert_eq!(address, "http-test-device");
+          creator
+        }
+        event => panic!("Unexpected event {:?}", event),
+      };
+      let protocol: ProtocolDefinition = serde_json::from_str(
+        r#"{"http": {"names": ["Test HTTP Device"], "endpoints": {"tx": "/tx"}}}"#,
+      )
+      .unwrap();
+      let device = creator.try_create_device_impl(protocol).await.unwrap();
+      assert!(device.endpoints().contains(&Endpoint::Tx));
+      let reading = device
+        .read_value(DeviceReadCmd::new(Endpoint::Rx, 0, 0))
+        .await
+        .unwrap();
+      assert_eq!(reading.data(), &vec![42]);
+      // Already connected devices aren't announced again.
+      manager.stop_scanning().await.unwrap();
+      assert!(matches!(
+        receiver.recv().await.unwrap(),
+        DeviceCommunicationEvent::ScanningFinished
+      ));
+      let mut events = device.event_stream();
+      devices.lock().unwrap().clear();
+      assert!(matches!(
+        events.recv().await.unwrap(),
+        ButtplugDeviceEvent::Removed(address) if address == "http-test-device"
+      ));
+      assert!(!device.connected());
+    });
+  }
+}
@@ -0,0 +1,245 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2021 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+use super::{
+  http_client::HttpClient,
+  http_comm_manager::{HttpDeviceConnection, HttpDeviceInfo, HttpDeviceMap, HttpDeviceSource},
+};
+use crate::{
+  core::{
+    errors::{ButtplugDeviceError, ButtplugError},
+    messages::RawReading,
+    ButtplugResultFuture,
+  },
+  device::{
+    configuration_manager::{DeviceSpecifier, ProtocolDefinition},
+    ButtplugDeviceEvent, ButtplugDeviceImplCreator, DeviceImpl, DeviceImplInternal, DeviceReadCmd,
+    DeviceSubscribeCmd, DeviceUnsubscribeCmd, DeviceWriteCmd, Endpoint,
+  },
+};
+use async_trait::async_trait;
+use futures::future::{self, BoxFuture};
+use std::{
+  collections::{HashMap, HashSet},
+  fmt::{self, Debug},
+  sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+  },
+};
+use tokio::sync::broadcast;
+
+pub struct HttpDeviceImplCreator {
+  source: Arc<dyn HttpDeviceSource>,
+  client: HttpClient,
+  devices: HttpDeviceMap,
+  address: String,
+  specifier: DeviceSpecifier,
+}
+
+impl HttpDeviceImplCreator {
+  pub(super) fn new(
+    source: Arc<dyn HttpDeviceSource>,
+    client: HttpClient,
+    devices: HttpDeviceMap,
+    info: &HttpDeviceInfo,
+  ) -> Self {
+    Self {
+      source,
+      client,
+      devices,
+      address: info.address.clone(),
+      specifier: info.specifier.clone(),
+    }
+  }
+}
+
+impl Debug for HttpDeviceImplCreator {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("HttpDeviceImplCreator")
+      .field("source", &self.source.name())
+      .field("address", &self.address)
+      .finish()
+  }
+}
+
+#[async_trait]
+impl ButtplugDeviceImplCreator for HttpDeviceImplCreator {
+  fn get_specifier(&self) -> DeviceSpecifier {
+    self.specifier.clone()
+  }
+
+  async fn try_create_device_impl(
+    &mut self,
+    protocol: ProtocolDefinition,
+  ) -> Result<DeviceImpl, ButtplugError> {
+    let config_endpoints = protocol.http.map(|http| http.endpoints).unwrap_or_default();
+    let (event_sender, _) = broadcast::channel(256);
+    let connected = Arc::new(AtomicBool::new(true));
+    let info = match self.devices.get_mut(&self.address) {
+      Some(mut tracked) if tracked.connection.is_none() => {
+        tracked.connection = Some(HttpDeviceConnection {
+          connected: connected.clone(),
+          event_sender: event_sender.clone(),
+        });
+        tracked.info.clone()
+      }
+      _ => {
+        return Err(
+          ButtplugDeviceError::DeviceConnectionError(format!(
+            "HTTP device {} is already connected or no longer available.",
+            self.address
+          ))
+          .into(),
+        )
+      }
+    };
+    let endpoints: HashSet<Endpoint> = info
+      .endpoints
+      .keys()
+      .chain(config_endpoints.keys())
+      .chain(info.readings.keys())
+      .cloned()
+      .collect();
+    let endpoints: Vec<Endpoint> = endpoints.into_iter().collect();
+    let device_impl_internal = HttpDeviceImpl {
+      address: self.address.clone(),
+      source: self.source.clone(),
+      client: self.client.clone(),
+      devices: self.devices.clone(),
+      config_endpoints,
+      connected,
+      event_sender,
+    };
+    Ok(DeviceImpl::new(
+      &info.name,
+      &self.address,
+      &endpoints,
+      Box::new(device_impl_internal),
+    ))
+  }
+}
+
+pub struct HttpDeviceImpl {
+  address: String,
+  source: Arc<dyn HttpDeviceSource>,
+  client: HttpClient,
+  devices: HttpDeviceMap,
+  config_endpoints: HashMap<Endpoint, String>,
+  connected: Arc<AtomicBool>,
+  event_sender: broadcast::Sender<ButtplugDeviceEvent>,
+}
+
+impl HttpDeviceImpl {
+  /// Device info from the last poll, plus endpoints from the device config.
+  fn current_info(&self) -> Result<HttpDeviceInfo, ButtplugError> {
+    let mut info = self
+      .devices
+      .get(&self.address)
+      .map(|tracked| tracked.info.clone())
+      .ok_or_else(|| {
+        ButtplugError::from(ButtplugDeviceError::DeviceConnectionError(format!(
+          "HTTP device {} is no longer available.",
+          self.address
+        )))
+      })?;
+    for (endpoint, path) in &self.config_endpoints {
+      info
+        .endpoints
+        .entry(*endpoint)
+        .or_insert_with(|| path.clone());
+    }
+    Ok(info)
+  }
+
+  fn release(&self) {
+    if let Some(mut tracked) = self.devices.get_mut(&self.address) {
+      tracked.connection = None;
+      // Let the device be announced again on the next scan.
+      tracked.announced = false;
+    }
+  }
+}
+
+impl DeviceImplInternal for HttpDeviceImpl {
+  fn event_stream(&self) -> broadcast::Receiver<ButtplugDeviceEvent> {
+    self.event_sender.subscribe()
+  }
+
+  fn connected(&self) -> bool {
+    self.connected.load(Ordering::SeqCst)
+  }
+
+  fn disconnect(&self) -> ButtplugResultFuture {
+    if self.connected.swap(false, Ordering::SeqCst) {
+      self.release();
+      let _ = self
+        .event_sender
+        .send(ButtplugDeviceEvent::Removed(self.address.clone()));
+    }
+    Box::pin(future::ready(Ok(())))
+  }
+
+  fn read_value(
+    &self,
+    msg: DeviceReadCmd,
+  ) -> BoxFuture<'static, Result<RawReading, ButtplugError>> {
+    let info = match self.current_info() {
+      Ok(info) => info,
+      Err(err) => return Box::pin(future::ready(Err(err))),
+    };
+    if let Some(data) = info.readings.get(&msg.endpoint) {
+      return Box::pin(future::ready(Ok(RawReading::new(
+        0,
+        msg.endpoint,
+        data.clone(),
+      ))));
+    }
+    let request = match info.endpoint_path(msg.endpoint) {
+      Ok(path) => info.request(reqwest::Method::GET, path),
+      Err(err) => return Box::pin(future::ready(Err(err))),
+    };
+    let client = self.client.clone();
+    Box::pin(async move {
+      let data = client.send(request).await?;
+      Ok(RawReading::new(0, msg.endpoint, data))
+    })
+  }
+
+  fn write_value(&self, msg: DeviceWriteCmd) -> ButtplugResultFuture {
+    let request = match self
+      .current_info()
+      .and_then(|info| self.source.write_request(&info, &msg))
+    {
+      Ok(request) => request,
+      Err(err) => return Box::pin(future::ready(Err(err))),
+    };
+    let client = self.client.clone();
+    Box::pin(async move {
+      client.send(request).await?;
+      Ok(())
+    })
+  }
+
+  fn subscribe(&self, msg: DeviceSubscribeCmd) -> ButtplugResultFuture {
+    ButtplugDeviceError::InvalidEndpoint(msg.endpoint).into()
+  }
+
+  fn unsubscribe(&self, msg: DeviceUnsubscribeCmd) -> ButtplugResultFuture {
+    ButtplugDeviceError::InvalidEndpoint(msg.endpoint).into()
+  }
+}
+
+impl Drop for HttpDeviceImpl {
+  fn drop(&mut self) {
+    // If we're dropped without being disconnected (protocol initialization
+    // failed, for instance), still let the device be found again.
+    if self.connected.swap(false, Ordering::SeqCst) {
+      self.release();
+    }
+  }
+}
@@ -0,0 +1,25 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2021 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Shared plumbing for devices that are controlled through an HTTP API.
+//!
+//! Each kind of device implements [HttpDeviceSource], which polls its API and
+//! maps the replies into [HttpDeviceInfo] descriptions. The comm manager takes
+//! care of the rest: polling on an interval while scanning or while any device
+//! is connected, announcing new devices, removing devices that stop showing up
+//! in polls, and turning device reads and writes into HTTP requests.
+
+mod http_client;
+mod http_comm_manager;
+mod http_device_impl;
+
+pub use http_client::{HttpClient, HttpRequest};
+pub use http_comm_manager::{
+  HttpDeviceCommunicationManager, HttpDeviceCommunicationManagerBuilder, HttpDeviceInfo,
+  HttpDeviceSource,
+};
+pub use http_device_impl::{HttpDeviceImpl, HttpDeviceImplCreator};
@@ -1,38 +1,35 @@
-use super::lovense_connect_service_device_impl::LovenseServiceDeviceImplCreator;
 use crate::{
-  core::ButtplugResultFuture,
+  core::errors::ButtplugError,
+  device::{
+    configuration_manager::{DeviceSpecifier, LovenseConnectServiceSpecifier},
+    DeviceWriteCmd, Endpoint,
+  },
   server::comm_managers::{
+    http::{
+      HttpClient, HttpDeviceCommunicationManagerBuilder, HttpDeviceInfo, HttpDeviceSource,
+      HttpRequest,
+    },
     DeviceCommunicationEvent, DeviceCommunicationManager, DeviceCommunicationManagerBuilder,
   },
-  util::async_manager
 };
-use dashmap::DashMap;
-use futures::future;
-use futures_timer::Delay;
+use async_trait::async_trait;
 use serde::{Deserialize, Deserializer};
-use std::{
-  collections::HashMap,
-  sync::{
-    atomic::{AtomicBool, Ordering},
-    Arc,
-  },
-  time::Duration,
-};
-use tracing_futures::Instrument;
-use tokio::sync::{mpsc, Mutex, Notify, RwLock};
+use std::{collections::HashMap, time::Duration};
+use tokio::sync::{mpsc, Mutex};
+use url::Url;
 
-const LOVENSE_LOCAL_SERVICE_CHECK_INTERVAL: u64 = 1;
-const LOVENSE_REMOTE_SERVICE_CHECK_INTERVAL: u64 = 1;
+const LOVENSE_SERVICE_POLL_INTERVAL: Duration = Duration::from_secs(1);
+const LOVENSE_REMOTE_SERVICE_URL: &str = "https://api.lovense.com/api/lan/getToys";
 
 fn connected_deserializer<'de, D>(deserializer: D) -> Result<bool, D::Error>
 where
-    D: Deserializer<'de>,
+  D: Deserializer<'de>,
 {
   Ok(String::deserialize(deserializer)? == "1")
 }
 
 #[derive(Deserialize, Debug, Clone)]
-pub(super) struct LovenseServiceToyInfo {
+struct LovenseServiceToyInfo {
   pub id: String,
   pub name: String,
   #[serde(rename = "nickName")]
@@ -67,177 +64,129 @@ struct LovenseServiceLocalInfo {
 
 type LovenseServiceInfo = HashMap<String, LovenseServiceHostInfo>;
 
-async fn lovense_local_service_check(
-  event_sender: mpsc::Sender<DeviceCommunicationEvent>,
-  has_known_hosts: Arc<AtomicBool>,
-  is_scanning: Arc<AtomicBool>,
-  known_hosts: Arc<Mutex<Vec<String>>>,
-) {
-  let connected_device_info: Arc<DashMap<String, Arc<RwLock<LovenseServiceToyInfo>>>> = Arc::new(DashMap::new());
-  loop {
-    let hosts = known_hosts.lock().await.clone();
-    if hosts.len() == 0 {
-      has_known_hosts.store(false, Ordering::SeqCst);
-      break;
-    }
-    for host in hosts {
-      match reqwest::get(format!("{}/GetToys", host)).await {
-        Ok(res) => {
-          let text = res.text().await.unwrap();
-          let info: LovenseServiceLocalInfo = serde_json::from_str(&text).unwrap();
-
-          // First off, remove all devices that are no longer in the list
-          // (devices turned off or removed from the Lovense Connect app)
+/// Finds Lovense Connect apps on the local network through the Lovense API
+/// while scanning, then reports the toys connected to them.
+#[derive(Default)]
+struct LovenseConnectServiceSource {
+  known_hosts: Mutex<Vec<String>>,
+}
 
-          for disconnected_device in connected_device_info.iter().filter(|p| !info.data.contains_key(p.key())) {
-            disconnected_device.value().write().await.connected = false;
-          }
-          connected_device_info.retain(|k, _| info.data.contains_key(k));
+#[async_trait]
+impl HttpDeviceSource for LovenseConnectServiceSource {
+  fn name(&self) -> &'static str {
+    "LovenseServiceDeviceCommManager"
+  }
 
-          for (_, toy) in info.data.iter() {
-            if connected_device_info.contains_key(&toy.id) {
-              // For some reason, this requires its own scoping block, otherwise
-              // the write lock will hold forever, which blocks the server? I'm
-              // guessing this has to do with loop hoisting but it still seems
-              // odd.
-              {
-                let info_ref = connected_device_info.get(&toy.id).unwrap();
-                let mut info_lock = info_ref.write().await;
-                *info_lock = toy.clone();
-              }
-              // If the toy is no longer connected, remove it from our tracking.
-              if !toy.connected {
-                info!("Removing toy from main info map");
-                connected_device_info.retain(|k, _| *k != toy.id);
-              }
-              continue;
-            }
-            if !is_scanning.load(Ordering::SeqCst) {
-              continue;
-            }
-            if !toy.connected {
-              continue;
-            }
-            connected_device_info.insert(toy.id.clone(), Arc::new(RwLock::new((*toy).clone())));
-            let device_creator = Box::new(LovenseServiceDeviceImplCreator::new(
-              &host,
-              connected_device_info.get(&toy.id).unwrap().clone()
-            ));
-            if event_sender
-              .send(DeviceCommunicationEvent::DeviceFound {
-                name: toy.name.clone(),
-                address: toy.id.clone(),
-                creator: device_creator,
-              })
-              .await
-              .is_err()
-            {
-              error!("Error sending device found message from HTTP Endpoint Manager.");
-            }
+  async fn poll(
+    &self,
+    client: &HttpClient,
+    is_scanning: bool,
+  ) -> Result<Vec<HttpDeviceInfo>, ButtplugError> {
+    if is_scanning {
+      match client
+        .send_json::<LovenseServiceInfo>(HttpRequest::get(LOVENSE_REMOTE_SERVICE_URL))
+        .await
+      {
+        // We set the protocol type here so it'll just filter down, in case we
+        // want to move to secure.
+        Ok(info) => {
+          *self.known_hosts.lock().await = info
+            .iter()
+            .map(|(domain, host)| format!("http://{}:{}", domain, host.http_port))
+            .collect()
+        }
+        Err(err) => error!("Got http error: {}", err),
+      }
+    }
+    let hosts = self.known_hosts.lock().await.clone();
+    let mut found = vec![];
+    for host in hosts {
+      match client
+        .send_json::<LovenseServiceLocalInfo>(HttpRequest::get(&format!("{}/GetToys", host)))
+        .await
+      {
+        Ok(info) => {
+          // Hosts are the URLs we built above, so they'll always parse.
+          let hostname = Url::parse(&host)
+            .ok()
+            .and_then(|url| url.host_str().map(|hostname| hostname.to_owned()))
+            .unwrap_or_default();
+          for toy in info.data.values().filter(|toy| toy.connected) {
+            // The app only knows the battery level from its last check, so
+            // battery reads just return what the last poll saw.
+            found.push(
+              HttpDeviceInfo::new(
+                &toy.name,
+                &toy.id,
+                DeviceSpecifier::LovenseConnectService(
+                  LovenseConnectServiceSpecifier::new_from_device(&hostname, &toy.name),
+                ),
+                &host,
+              )
+              .endpoint(Endpoint::Tx, "/")
+              .reading(Endpoint::Rx, vec![toy.battery]),
+            );
           }
-          
-          //connected_devices = new_connected_devices;
         }
         Err(err) => {
           error!(
             "Got http error from lovense service, assuming Lovense connect app shutdown: {}",
             err
           );
-          (*known_hosts.lock().await).retain(|x| *x != host);
+          self.known_hosts.lock().await.retain(|x| *x != host);
         }
       }
     }
-    Delay::new(Duration::from_secs(LOVENSE_LOCAL_SERVICE_CHECK_INTERVAL)).await;
-  }
-}
-
-
-#[derive(Default)]
-pub struct LovenseConnectServiceCommunicationManagerBuilder {
-  sender: Option<tokio::sync::mpsc::Sender<DeviceCommunicationEvent>>
-}
-
-impl DeviceCommunicationManagerBuilder for LovenseConnectServiceCommunicationManagerBuilder {
-  fn set_event_sender(&mut self, sender: mpsc::Sender<DeviceCommunicationEvent>) {
-    self.sender = Some(sender)
+    Ok(found)
   }
 
-  fn finish(mut self) -> Box<dyn DeviceCommunicationManager> {
-    Box::new(LovenseConnectServiceCommunicationManager::new(self.sender.take().unwrap()))
+  /// Commands are written as the path and query string of a GET, i.e.
+  /// "Vibrate?v=10".
+  fn write_request(
+    &self,
+    device: &HttpDeviceInfo,
+    msg: &DeviceWriteCmd,
+  ) -> Result<HttpRequest, ButtplugError> {
+    Ok(HttpRequest::get(&format!(
+      "{}/{}",
+      device.base_url,
+      String::from_utf8_lossy(&msg.data)
+    )))
   }
 }
 
-pub struct LovenseConnectServiceCommunicationManager {
-  sender: mpsc::Sender<DeviceCommunicationEvent>,
-  scanning_notifier: Arc<Notify>,
-  known_hosts: Arc<Mutex<Vec<String>>>,
-  is_scanning: Arc<AtomicBool>,
-  has_known_hosts: Arc<AtomicBool>,
+pub struct LovenseConnectServiceCommunicationManagerBuilder {
+  sender: Option<mpsc::Sender<DeviceCommunicationEvent>>,
+  poll_interval: Duration,
 }
 
-impl LovenseConnectServiceCommunicationManager {
-  fn new(sender: mpsc::Sender<DeviceCommunicationEvent>) -> Self {
+impl Default for LovenseConnectServiceCommunicationManagerBuilder {
+  fn default() -> Self {
     Self {
-      sender,
-      scanning_notifier: Arc::new(Notify::new()),
-      known_hosts: Arc::new(Mutex::new(vec![])),
-      is_scanning: Arc::new(AtomicBool::new(false)),
-      has_known_hosts: Arc::new(AtomicBool::new(false))
+      sender: None,
+      poll_interval: LOVENSE_SERVICE_POLL_INTERVAL,
     }
   }
 }
 
-impl DeviceCommunicationManager for LovenseConnectServiceCommunicationManager {
-  fn name(&self) -> &'static str {
-    "LovenseServiceDeviceCommManager"
+impl LovenseConnectServiceCommunicationManagerBuilder {
+  /// How often to ask the Lovense API and apps for toys.
+  pub fn poll_interval(mut self, interval: Duration) -> Self {
+    self.poll_interval = interval;
+    self
   }
+}
 
-  fn start_scanning(&self) -> ButtplugResultFuture {
-    self.is_scanning.store(true, Ordering::SeqCst);
-    let sender = self.sender.clone();
-    let is_scanning = self.is_scanning.clone();
-    let known_hosts = self.known_hosts.clone();
-    let has_known_hosts = self.has_known_hosts.clone();
-    async_manager::spawn(async move {
-      debug!("Starting scanning");
-      while is_scanning.load(Ordering::SeqCst) {
-        match reqwest::get("https://api.lovense.com/api/lan/getToys").await {
-          Ok(res) => {
-            let text = res.text().await.unwrap();
-            let info: LovenseServiceInfo = serde_json::from_str(&text).unwrap();
-            let mut current_known_hosts = known_hosts.lock().await;
-            // We set the protocol type here so it'll just filter down, in case we want to move to secure.
-            let new_known_hosts: Vec<String> = info.iter().map(|x| format!("http://{}:{}", x.0, x.1.http_port)).collect();
-            // check for both different numbers of elements as well as elements not being the same
-            if current_known_hosts.len() != new_known_hosts.len() || !current_known_hosts.iter().all(|item| new_known_hosts.contains(&item)) {
-              *current_known_hosts = new_known_hosts.iter().map(|x| (*x).clone()).collect();
-            }
-            if current_known_hosts.len() > 0 && !has_known_hosts.load(Ordering::SeqCst) {
-              has_known_hosts.store(true, Ordering::SeqCst);
-              let service_fut = lovense_local_service_check(sender.clone(), has_known_hosts.clone(), is_scanning.clone(), known_hosts.clone());
-              async_manager::spawn(async move {
-                service_fut.await;
-              }).unwrap();
-            }
-          }
-          Err(err) => error!("Got http error: {}", err),
-        };
-        Delay::new(Duration::from_secs(LOVENSE_REMOTE_SERVICE_CHECK_INTERVAL)).await;
-      }
-      debug!("Stopping scanning");
-    }.instrument(info_span!("Lovense Connect Service Scanner"))).unwrap();
-    Box::pin(async move { Ok(()) })
+impl DeviceCommunicationManagerBuilder for LovenseConnectServiceCommunicationManagerBuilder {
+  fn set_event_sender(&mut self, sender: mpsc::Sender<DeviceCommunicationEvent>) {
+    self.sender = Some(sender)
   }
 
-  fn stop_scanning(&self) -> ButtplugResultFuture {
-    self.is_scanning.store(false, Ordering::SeqCst);
-    Box::pin(future::ready(Ok(())))
+  fn finish(mut self) -> Box<dyn DeviceCommunicationManager> {
+    let mut builder =
+      HttpDeviceCommunicationManagerBuilder::new(LovenseConnectServiceSource::default())
+        .poll_interval(self.poll_interval);
+    builder.set_event_sender(self.sender.take().unwrap());
+    builder.finish()
   }
 }
-
-impl Drop for LovenseConnectServiceCommunicationManager {
-  fn drop(&mut self) {
-    self.is_scanning.store(false, Ordering::SeqCst);
-    self.has_known_hosts.store(false, Ordering::SeqCst);
-  }
-}
\ No newline at end of file
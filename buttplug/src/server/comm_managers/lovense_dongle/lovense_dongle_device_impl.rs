@@ -106,7 +106,7 @@ impl LovenseDongleDeviceImpl {
     let address_clone = address.to_owned();
     let (device_event_sender, _) = broadcast::channel(256);
     let device_event_sender_clone = device_event_sender.clone();
-    async_manager::spawn(async move {
+    async_manager::spawn_named("lovense dongle device event loop", async move {
       while let Some(msg) = device_incoming.recv().await {
         if msg.func != LovenseDongleMessageFunc::ToyData {
           continue;
@@ -299,7 +299,7 @@ impl LovenseDongleState for LovenseCheckForAlreadyConnectedDevice {
             _ => error!("Cannot handle incoming message {:?}", incoming_msg),
         }
       },
-      _ = futures_timer::Delay::new(std::time::Duration::from_millis(250)).fuse() => {
+      _ = crate::util::async_manager::sleep(std::time::Duration::from_millis(250)).fuse() => {
         // noop, just fall thru.
       }
     }
@@ -8,6 +8,7 @@ use crate::{
   core::{errors::ButtplugDeviceError, ButtplugResultFuture},
   server::comm_managers::{
     DeviceCommunicationEvent, DeviceCommunicationManager, DeviceCommunicationManagerBuilder,
+    DeviceCommunicationManagerCapabilities, DeviceCommunicationTransport,
   },
   util::async_manager,
 };
@@ -163,7 +164,8 @@ impl LovenseHIDDongleCommunicationManager {
       thread_cancellation_token: CancellationToken::new(),
     };
     let dongle_fut = mgr.find_dongle();
-    async_manager::spawn(
+    async_manager::spawn_named(
+      "lovense hid dongle search",
       async move {
         let _ = dongle_fut.await;
       }
@@ -172,7 +174,8 @@ impl LovenseHIDDongleCommunicationManager {
     .unwrap();
     let mut machine =
       create_lovense_dongle_machine(event_sender, machine_receiver, mgr.is_scanning.clone());
-    async_manager::spawn(
+    async_manager::spawn_named(
+      "lovense hid dongle state machine",
       async move {
         while let Some(next) = machine.transition().await {
           machine = next;
@@ -197,19 +200,30 @@ impl LovenseHIDDongleCommunicationManager {
     Box::pin(async move {
       let (writer_sender, writer_receiver) = channel(256);
       let (reader_sender, reader_receiver) = channel(256);
-      let api = HidApi::new().map_err(|_| {
-        // This may happen if we create a new server in the same process?
-        error!("Failed to create HIDAPI instance. Was one already created?");
-        ButtplugDeviceError::DeviceConnectionError("Cannot create HIDAPI.".to_owned())
-      })?;
-      let dongle1 = api.open(0x1915, 0x520a).map_err(|_| {
-        warn!("Cannot find lovense HID dongle.");
-        ButtplugDeviceError::DeviceConnectionError("Cannot find lovense HID Dongle.".to_owned())
-      })?;
-      let dongle2 = api.open(0x1915, 0x520a).map_err(|_| {
-        warn!("Cannot find lovense HID dongle.");
-        ButtplugDeviceError::DeviceConnectionError("Cannot find lovense HID Dongle.".to_owned())
-      })?;
+      // HIDAPI enumerates devices when it's created, and opening them blocks
+      // too.
+      let (dongle1, dongle2) = async_manager::spawn_blocking(|| {
+        let api = HidApi::new().map_err(|_| {
+          // This may happen if we create a new server in the same process?
+          error!("Failed to create HIDAPI instance. Was one already created?");
+          ButtplugDeviceError::DeviceConnectionError("Cannot create HIDAPI.".to_owned())
+        })?;
+        let open_dongle = || {
+          api.open(0x1915, 0x520a).map_err(|_| {
+            warn!("Cannot find lovense HID dongle.");
+            ButtplugDeviceError::DeviceConnectionError(
+              "Cannot find lovense HID Dongle.".to_owned(),
+            )
+          })
+        };
+        Ok::<_, ButtplugDeviceError>((open_dongle()?, open_dongle()?))
+      })
+      .await
+      .map_err(|_| {
+        ButtplugDeviceError::DeviceConnectionError(
+          "Cannot open lovense HID dongle, runtime is shutting down.".to_owned(),
+        )
+      })??;
 
       let read_thread = thread::Builder::new()
         .name("Lovense Dongle HID Reader Thread".to_string())
@@ -249,6 +263,16 @@ impl DeviceCommunicationManager for LovenseHIDDongleCommunicationManager {
     "LovenseHIDDongleCommunicationManager"
   }
 
+  fn capabilities(&self) -> DeviceCommunicationManagerCapabilities {
+    DeviceCommunicationManagerCapabilities {
+      transport: DeviceCommunicationTransport::HID,
+      supports_hotplug: true,
+      // Raw HID access needs a udev rule on linux.
+      requires_permissions: cfg!(target_os = "linux"),
+      unavailable_reason: None,
+    }
+  }
+
   fn start_scanning(&self) -> ButtplugResultFuture {
     debug!("Lovense Dongle Manager scanning for devices");
     let sender = self.machine_sender.clone();
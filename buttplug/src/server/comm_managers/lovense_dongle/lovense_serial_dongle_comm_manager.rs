@@ -5,9 +5,10 @@ use super::{
   lovense_dongle_state_machine::create_lovense_dongle_machine,
 };
 use crate::{
-  core::ButtplugResultFuture,
+  core::{errors::ButtplugDeviceError, ButtplugResultFuture},
   server::comm_managers::{
     DeviceCommunicationEvent, DeviceCommunicationManager, DeviceCommunicationManagerBuilder,
+    DeviceCommunicationManagerCapabilities, DeviceCommunicationTransport,
   },
   util::async_manager,
 };
@@ -81,7 +82,7 @@ fn serial_read_thread(
             match msg {
               Ok(m) => {
                 debug!("Read message: {:?}", m);
-                async_manager::block_on(async { sender_clone.send(m).await.unwrap() });
+                sender_clone.blocking_send(m).unwrap();
               }
               Err(e) => {
                 error!("Error reading: {:?}", e);
@@ -148,7 +149,7 @@ impl LovenseSerialDongleCommunicationManager {
     };
     let dongle_fut = mgr.find_dongle();
     // TODO If we don't find a dongle before scanning, what happens?
-    async_manager::spawn(async move {
+    async_manager::spawn_named("lovense serial dongle search", async move {
       if let Err(err) = dongle_fut.await {
         error!("Error finding serial dongle: {:?}", err);
       }
@@ -156,7 +157,8 @@ impl LovenseSerialDongleCommunicationManager {
     .unwrap();
     let mut machine =
       create_lovense_dongle_machine(event_sender, machine_receiver, mgr.is_scanning.clone());
-    async_manager::spawn(
+    async_manager::spawn_named(
+      "lovense serial dongle state machine",
       async move {
         while let Some(next) = machine.transition().await {
           machine = next;
@@ -182,9 +184,17 @@ impl LovenseSerialDongleCommunicationManager {
     let token = self.thread_cancellation_token.child_token();
     Box::pin(
       async move {
-        // TODO Does this block? Should it run in one of our threads?
         let found_dongle = false;
-        match available_ports() {
+        // Both listing and opening ports block, so they're done on blocking
+        // threads.
+        let ports = async_manager::spawn_blocking(available_ports)
+          .await
+          .map_err(|_| {
+            ButtplugDeviceError::DeviceConnectionError(
+              "Cannot list serial ports, runtime is shutting down.".to_owned(),
+            )
+          })?;
+        match ports {
           Ok(ports) => {
             debug!("Got {} serial ports back", ports.len());
             for p in ports {
@@ -196,7 +206,14 @@ impl LovenseSerialDongleCommunicationManager {
                   info!("Found lovense dongle, connecting");
                   let serial_port =
                     serialport::new(&p.port_name, 115200).timeout(Duration::from_millis(500));
-                  match serial_port.open() {
+                  let open_result = async_manager::spawn_blocking(move || serial_port.open())
+                    .await
+                    .map_err(|_| {
+                      ButtplugDeviceError::DeviceConnectionError(
+                        "Cannot open serial port, runtime is shutting down.".to_owned(),
+                      )
+                    })?;
+                  match open_result {
                     Ok(dongle_port) => {
                       let read_token = token.child_token();
                       let write_token = token.child_token();
@@ -254,6 +271,15 @@ impl DeviceCommunicationManager for LovenseSerialDongleCommunicationManager {
     "LovenseSerialDongleCommunicationManager"
   }
 
+  fn capabilities(&self) -> DeviceCommunicationManagerCapabilities {
+    DeviceCommunicationManagerCapabilities {
+      transport: DeviceCommunicationTransport::Serial,
+      supports_hotplug: true,
+      requires_permissions: cfg!(target_os = "linux"),
+      unavailable_reason: None,
+    }
+  }
+
   fn start_scanning(&self) -> ButtplugResultFuture {
     debug!("Lovense Dongle Manager scanning for devices.");
     let sender = self.machine_sender.clone();
@@ -1,13 +1,23 @@
 #[cfg(feature = "btleplug-manager")]
 pub mod btleplug;
+#[cfg(all(feature = "btclassic-manager", target_os = "linux"))]
+pub mod btclassic;
 #[cfg(feature = "lovense-dongle-manager")]
 pub mod lovense_dongle;
 #[cfg(feature = "serial-manager")]
 pub mod serialport;
+#[cfg(feature = "usb-manager")]
+pub mod usb;
 #[cfg(all(feature = "xinput-manager", target_os = "windows"))]
 pub mod xinput;
 #[cfg(feature = "lovense-connect-service-manager")]
 pub mod lovense_connect_service;
+#[cfg(feature = "mqtt-manager")]
+pub mod mqtt;
+#[cfg(feature = "http-manager")]
+pub mod http;
+#[cfg(feature = "autoblow-manager")]
+pub mod autoblow;
 
 use crate::{core::ButtplugResultFuture, device::ButtplugDeviceImplCreator};
 use serde::{Deserialize, Serialize};
@@ -24,9 +34,12 @@ pub enum DeviceCommunicationEvent {
     address: String,
     creator: Box<dyn ButtplugDeviceImplCreator>,
   },
-  DeviceManagerAdded(Arc<AtomicBool>),
-  ScanningStarted,
+  /// Sent once a manager is done with the current scan, whether it ran to
+  /// completion or was stopped.
   ScanningFinished,
+  /// Sent instead of ScanningFinished if the scan ended because of an error.
+  /// The message is passed on to the client with the ScanningFinished event.
+  ScanningFailed(String),
 }
 
 pub trait DeviceCommunicationManagerBuilder: Send {
@@ -34,6 +47,46 @@ pub trait DeviceCommunicationManagerBuilder: Send {
   fn finish(self) -> Box<dyn DeviceCommunicationManager>;
 }
 
+/// Type of transport a comm manager uses to talk to devices.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DeviceCommunicationTransport {
+  Bluetooth,
+  Serial,
+  HID,
+  USB,
+  XInput,
+  Network,
+  Other,
+}
+
+/// Describes what a comm manager can do on the current system, so UIs can
+/// explain why devices may not show up, instead of just relaying scan
+/// errors.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DeviceCommunicationManagerCapabilities {
+  pub transport: DeviceCommunicationTransport,
+  /// True if devices can show up (or go away) while scanning, versus only
+  /// being enumerated once per scan.
+  pub supports_hotplug: bool,
+  /// True if the platform may require the user to grant extra permissions
+  /// for this transport to work.
+  pub requires_permissions: bool,
+  /// If the manager knows it cannot work on this system, a description of why
+  /// and what the user can do about it.
+  pub unavailable_reason: Option<String>,
+}
+
+impl Default for DeviceCommunicationManagerCapabilities {
+  fn default() -> Self {
+    Self {
+      transport: DeviceCommunicationTransport::Other,
+      supports_hotplug: false,
+      requires_permissions: false,
+      unavailable_reason: None,
+    }
+  }
+}
+
 pub trait DeviceCommunicationManager: Send + Sync {
   fn name(&self) -> &'static str;
   fn start_scanning(&self) -> ButtplugResultFuture;
@@ -41,6 +94,9 @@ pub trait DeviceCommunicationManager: Send + Sync {
   fn scanning_status(&self) -> Arc<AtomicBool> {
     Arc::new(AtomicBool::new(false))
   }
+  fn capabilities(&self) -> DeviceCommunicationManagerCapabilities {
+    DeviceCommunicationManagerCapabilities::default()
+  }
   // Events happen via channel senders passed to the comm manager.
 }
 
@@ -54,7 +110,19 @@ pub enum ButtplugDeviceSpecificError {
   #[cfg(feature = "btleplug-manager")]
   #[error("Btleplug error: {0}")]
   BtleplugError(String),
+  #[cfg(all(feature = "btclassic-manager", target_os = "linux"))]
+  #[error("Bluetooth Classic error: {0}")]
+  BluetoothClassicError(String),
   #[cfg(feature = "serial-manager")]
   #[error("Serial error: {0}")]
   SerialError(String),
+  #[cfg(feature = "usb-manager")]
+  #[error("USB error: {0}")]
+  UsbError(String),
+  #[cfg(feature = "mqtt-manager")]
+  #[error("MQTT error: {0}")]
+  MqttError(String),
+  #[cfg(feature = "http-manager")]
+  #[error("HTTP error: {0}")]
+  HttpError(String),
 }
@@ -0,0 +1,23 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2021 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! MQTT device support, for DIY hardware (ESPHome, Tasmota, etc) that already
+//! talks to a broker.
+//!
+//! Devices are defined by a mapping in the comm manager builder. Each mapping
+//! has a command topic, which device writes are published to, and an optional
+//! state topic, which is exposed as the device's Rx endpoint. Mappings with a
+//! state topic are only announced once the device has published something
+//! there, so devices that are offline don't show up.
+
+mod mqtt_comm_manager;
+mod mqtt_device_impl;
+
+pub use mqtt_comm_manager::{
+  MqttCommunicationManager, MqttCommunicationManagerBuilder, MqttDeviceMapping,
+};
+pub use mqtt_device_impl::{MqttDeviceImpl, MqttDeviceImplCreator};
@@ -0,0 +1,354 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2021 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+use super::mqtt_device_impl::MqttDeviceImplCreator;
+use crate::{
+  core::ButtplugResultFuture,
+  device::{ButtplugDeviceEvent, Endpoint},
+  server::comm_managers::{
+    DeviceCommunicationEvent, DeviceCommunicationManager, DeviceCommunicationManagerBuilder,
+    DeviceCommunicationManagerCapabilities, DeviceCommunicationTransport,
+  },
+  util::async_manager,
+};
+use dashmap::DashMap;
+use futures::{future, select, FutureExt};
+use rumqttc::{AsyncClient, Event, EventLoop, MqttOptions, Packet, QoS};
+use std::{
+  sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+  },
+  time::Duration,
+};
+use tokio::sync::{broadcast, mpsc::Sender, Notify};
+use tracing_futures::Instrument;
+
+const MQTT_KEEP_ALIVE: Duration = Duration::from_secs(30);
+// rumqttc reconnects on the next poll after an error, so just keep it from
+// spinning while the broker is unreachable.
+const MQTT_RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// Describes a device that lives on an MQTT broker.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MqttDeviceMapping {
+  /// Matched against the "mqtt" names in the device configuration, to pick
+  /// the protocol for the device.
+  pub identifier: String,
+  /// Unique address for the device. Used for device index reuse and device
+  /// filters, so it should stay stable across restarts.
+  pub address: String,
+  /// Topic that writes to the Tx endpoint are published to.
+  pub command_topic: String,
+  /// Topic the device publishes its state to, exposed as the Rx endpoint.
+  pub state_topic: Option<String>,
+}
+
+impl MqttDeviceMapping {
+  pub fn new(identifier: &str, address: &str, command_topic: &str) -> Self {
+    Self {
+      identifier: identifier.to_owned(),
+      address: address.to_owned(),
+      command_topic: command_topic.to_owned(),
+      state_topic: None,
+    }
+  }
+
+  pub fn state_topic(mut self, topic: &str) -> Self {
+    self.state_topic = Some(topic.to_owned());
+    self
+  }
+}
+
+/// Set on a device state once a device impl has been created for it.
+pub(super) struct MqttDeviceConnection {
+  pub event_sender: broadcast::Sender<ButtplugDeviceEvent>,
+  pub subscribed: Arc<AtomicBool>,
+}
+
+pub(super) struct MqttDeviceState {
+  pub mapping: MqttDeviceMapping,
+  pub last_state: Option<Vec<u8>>,
+  pub connection: Option<MqttDeviceConnection>,
+}
+
+/// Device states, keyed by device address.
+pub(super) type MqttDeviceStateMap = Arc<DashMap<String, MqttDeviceState>>;
+
+pub struct MqttCommunicationManagerBuilder {
+  sender: Option<Sender<DeviceCommunicationEvent>>,
+  broker_host: String,
+  broker_port: u16,
+  client_id: String,
+  credentials: Option<(String, String)>,
+  devices: Vec<MqttDeviceMapping>,
+}
+
+impl MqttCommunicationManagerBuilder {
+  pub fn new(broker_host: &str, broker_port: u16) -> Self {
+    Self {
+      sender: None,
+      broker_host: broker_host.to_owned(),
+      broker_port,
+      client_id: "buttplug".to_owned(),
+      credentials: None,
+      devices: vec![],
+    }
+  }
+
+  /// Client id to connect to the broker with. Needs to be unique per broker,
+  /// so set this if running more than one server against the same broker.
+  pub fn client_id(mut self, client_id: &str) -> Self {
+    self.client_id = client_id.to_owned();
+    self
+  }
+
+  pub fn credentials(mut self, username: &str, password: &str) -> Self {
+    self.credentials = Some((username.to_owned(), password.to_owned()));
+    self
+  }
+
+  pub fn device(mut self, mapping: MqttDeviceMapping) -> Self {
+    self.devices.push(mapping);
+    self
+  }
+}
+
+impl DeviceCommunicationManagerBuilder for MqttCommunicationManagerBuilder {
+  fn set_event_sender(&mut self, sender: Sender<DeviceCommunicationEvent>) {
+    self.sender = Some(sender)
+  }
+
+  fn finish(mut self) -> Box<dyn DeviceCommunicationManager> {
+    let mut options = MqttOptions::new(&self.client_id, &self.broker_host, self.broker_port);
+    options.set_keep_alive(MQTT_KEEP_ALIVE);
+    if let Some((username, password)) = &self.credentials {
+      options.set_credentials(username, password);
+    }
+    Box::new(MqttCommunicationManager::new(
+      self.sender.take().unwrap(),
+      options,
+      self.devices,
+    ))
+  }
+}
+
+pub struct MqttCommunicationManager {
+  sender: Sender<DeviceCommunicationEvent>,
+  client: AsyncClient,
+  devices: MqttDeviceStateMap,
+  is_scanning: Arc<AtomicBool>,
+  shutdown_notifier: Arc<Notify>,
+}
+
+impl MqttCommunicationManager {
+  fn new(
+    sender: Sender<DeviceCommunicationEvent>,
+    options: MqttOptions,
+    mappings: Vec<MqttDeviceMapping>,
+  ) -> Self {
+    let (client, event_loop) = AsyncClient::new(options, 256);
+    let devices: MqttDeviceStateMap = Arc::new(DashMap::new());
+    for mapping in mappings {
+      devices.insert(
+        mapping.address.clone(),
+        MqttDeviceState {
+          mapping,
+          last_state: None,
+          connection: None,
+        },
+      );
+    }
+    let is_scanning = Arc::new(AtomicBool::new(false));
+    let shutdown_notifier = Arc::new(Notify::new());
+    let mqtt_loop = mqtt_event_loop(
+      event_loop,
+      client.clone(),
+      sender.clone(),
+      devices.clone(),
+      is_scanning.clone(),
+      shutdown_notifier.clone(),
+    );
+    async_manager::spawn_named(
+      "mqtt event loop",
+      mqtt_loop.instrument(tracing::info_span!("MQTT Event Loop")),
+    )
+    .unwrap();
+    Self {
+      sender,
+      client,
+      devices,
+      is_scanning,
+      shutdown_notifier,
+    }
+  }
+}
+
+/// Sends DeviceFound for a device if it can be connected: it isn't already
+/// connected, and if it has a state topic, it's been heard from.
+async fn announce_device(
+  sender: &Sender<DeviceCommunicationEvent>,
+  client: &AsyncClient,
+  devices: &MqttDeviceStateMap,
+  address: &str,
+) {
+  let mapping = match devices.get(address) {
+    Some(state)
+      if state.connection.is_none()
+        && (state.mapping.state_topic.is_none() || state.last_state.is_some()) =>
+    {
+      state.mapping.clone()
+    }
+    _ => return,
+  };
+  let creator = Box::new(MqttDeviceImplCreator::new(
+    &mapping,
+    client.clone(),
+    devices.clone(),
+  ));
+  if sender
+    .send(DeviceCommunicationEvent::DeviceFound {
+      name: mapping.identifier.clone(),
+      address: mapping.address.clone(),
+      creator,
+    })
+    .await
+    .is_err()
+  {
+    error!("Device manager disappeared, cannot send MQTT device found.");
+  }
+}
+
+async fn mqtt_event_loop(
+  mut event_loop: EventLoop,
+  client: AsyncClient,
+  sender: Sender<DeviceCommunicationEvent>,
+  devices: MqttDeviceStateMap,
+  is_scanning: Arc<AtomicBool>,
+  shutdown_notifier: Arc<Notify>,
+) {
+  loop {
+    let event = select! {
+      event = event_loop.poll().fuse() => event,
+      _ = shutdown_notifier.notified().fuse() => break,
+    };
+    match event {
+      Ok(Event::Incoming(Packet::ConnAck(_))) => {
+        info!("Connected to MQTT broker.");
+        // Subscriptions don't survive reconnects with clean sessions, so
+        // redo them on every connection. These can't wait on the request
+        // queue, since we're the ones that drain it.
+        let topics: Vec<String> = devices
+          .iter()
+          .filter_map(|state| state.mapping.state_topic.clone())
+          .collect();
+        for topic in topics {
+          if let Err(err) = client.try_subscribe(&topic, QoS::AtMostOnce) {
+            error!("Cannot subscribe to MQTT topic {}: {:?}", topic, err);
+          }
+        }
+      }
+      Ok(Event::Incoming(Packet::Publish(publish))) => {
+        let mut addresses = vec![];
+        for mut state in devices.iter_mut() {
+          if state.mapping.state_topic.as_ref() != Some(&publish.topic) {
+            continue;
+          }
+          let data = publish.payload.to_vec();
+          if let Some(connection) = &state.connection {
+            if connection.subscribed.load(Ordering::SeqCst) {
+              let _ = connection
+                .event_sender
+                .send(ButtplugDeviceEvent::Notification(
+                  state.mapping.address.clone(),
+                  Endpoint::Rx,
+                  data.clone(),
+                ));
+            }
+          }
+          state.last_state = Some(data);
+          addresses.push(state.mapping.address.clone());
+        }
+        if is_scanning.load(Ordering::SeqCst) {
+          for address in addresses {
+            announce_device(&sender, &client, &devices, &address).await;
+          }
+        }
+      }
+      Ok(_) => {}
+      Err(err) => {
+        error!("MQTT connection error: {:?}", err);
+        select! {
+          _ = async_manager::sleep(MQTT_RECONNECT_DELAY).fuse() => {},
+          _ = shutdown_notifier.notified().fuse() => break,
+        }
+      }
+    }
+  }
+  info!("Exiting MQTT event loop.");
+}
+
+impl DeviceCommunicationManager for MqttCommunicationManager {
+  fn name(&self) -> &'static str {
+    "MqttCommunicationManager"
+  }
+
+  fn capabilities(&self) -> DeviceCommunicationManagerCapabilities {
+    DeviceCommunicationManagerCapabilities {
+      transport: DeviceCommunicationTransport::Network,
+      // Devices with state topics show up whenever they come online.
+      supports_hotplug: true,
+      requires_permissions: false,
+      unavailable_reason: None,
+    }
+  }
+
+  fn start_scanning(&self) -> ButtplugResultFuture {
+    self.is_scanning.store(true, Ordering::SeqCst);
+    let sender = self.sender.clone();
+    let client = self.client.clone();
+    let devices = self.devices.clone();
+    Box::pin(async move {
+      let addresses: Vec<String> = devices.iter().map(|state| state.key().clone()).collect();
+      for address in addresses {
+        announce_device(&sender, &client, &devices, &address).await;
+      }
+      Ok(())
+    })
+  }
+
+  fn stop_scanning(&self) -> ButtplugResultFuture {
+    if self.is_scanning.swap(false, Ordering::SeqCst) {
+      let sender = self.sender.clone();
+      Box::pin(async move {
+        if sender
+          .send(DeviceCommunicationEvent::ScanningFinished)
+          .await
+          .is_err()
+        {
+          error!("Error sending scanning finished.");
+        }
+        Ok(())
+      })
+    } else {
+      Box::pin(future::ready(Ok(())))
+    }
+  }
+
+  fn scanning_status(&self) -> Arc<AtomicBool> {
+    self.is_scanning.clone()
+  }
+}
+
+impl Drop for MqttCommunicationManager {
+  fn drop(&mut self) {
+    self.is_scanning.store(false, Ordering::SeqCst);
+    // The event loop may be in the middle of a poll rather than waiting on
+    // the notifier, so store a permit instead of only waking current waiters.
+    self.shutdown_notifier.notify_one();
+  }
+}
@@ -0,0 +1,208 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2021 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+use super::mqtt_comm_manager::{MqttDeviceConnection, MqttDeviceMapping, MqttDeviceStateMap};
+use crate::{
+  core::{
+    errors::{ButtplugDeviceError, ButtplugError},
+    messages::RawReading,
+    ButtplugResultFuture,
+  },
+  device::{
+    configuration_manager::{DeviceSpecifier, MQTTSpecifier, ProtocolDefinition},
+    ButtplugDeviceEvent, ButtplugDeviceImplCreator, DeviceImpl, DeviceImplInternal, DeviceReadCmd,
+    DeviceSubscribeCmd, DeviceUnsubscribeCmd, DeviceWriteCmd, Endpoint,
+  },
+  server::comm_managers::ButtplugDeviceSpecificError,
+};
+use async_trait::async_trait;
+use futures::future::{self, BoxFuture};
+use rumqttc::{AsyncClient, QoS};
+use std::{
+  fmt::{self, Debug},
+  sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+  },
+};
+use tokio::sync::broadcast;
+
+pub struct MqttDeviceImplCreator {
+  mapping: MqttDeviceMapping,
+  client: AsyncClient,
+  devices: MqttDeviceStateMap,
+}
+
+impl MqttDeviceImplCreator {
+  pub(super) fn new(
+    mapping: &MqttDeviceMapping,
+    client: AsyncClient,
+    devices: MqttDeviceStateMap,
+  ) -> Self {
+    Self {
+      mapping: mapping.clone(),
+      client,
+      devices,
+    }
+  }
+}
+
+impl Debug for MqttDeviceImplCreator {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("MqttDeviceImplCreator")
+      .field("mapping", &self.mapping)
+      .finish()
+  }
+}
+
+#[async_trait]
+impl ButtplugDeviceImplCreator for MqttDeviceImplCreator {
+  fn get_specifier(&self) -> DeviceSpecifier {
+    DeviceSpecifier::MQTT(MQTTSpecifier::new_from_identifier(&self.mapping.identifier))
+  }
+
+  async fn try_create_device_impl(
+    &mut self,
+    _protocol: ProtocolDefinition,
+  ) -> Result<DeviceImpl, ButtplugError> {
+    let (event_sender, _) = broadcast::channel(256);
+    let subscribed = Arc::new(AtomicBool::new(false));
+    match self.devices.get_mut(&self.mapping.address) {
+      Some(mut state) if state.connection.is_none() => {
+        state.connection = Some(MqttDeviceConnection {
+          event_sender: event_sender.clone(),
+          subscribed: subscribed.clone(),
+        });
+      }
+      _ => {
+        return Err(
+          ButtplugDeviceError::DeviceConnectionError(format!(
+            "MQTT device {} is already connected.",
+            self.mapping.address
+          ))
+          .into(),
+        )
+      }
+    }
+    let mut endpoints = vec![Endpoint::Tx];
+    if self.mapping.state_topic.is_some() {
+      endpoints.push(Endpoint::Rx);
+    }
+    let device_impl_internal = MqttDeviceImpl {
+      mapping: self.mapping.clone(),
+      client: self.client.clone(),
+      devices: self.devices.clone(),
+      connected: Arc::new(AtomicBool::new(true)),
+      subscribed,
+      event_sender,
+    };
+    Ok(DeviceImpl::new(
+      &self.mapping.identifier,
+      &self.mapping.address,
+      &endpoints,
+      Box::new(device_impl_internal),
+    ))
+  }
+}
+
+pub struct MqttDeviceImpl {
+  mapping: MqttDeviceMapping,
+  client: AsyncClient,
+  devices: MqttDeviceStateMap,
+  connected: Arc<AtomicBool>,
+  subscribed: Arc<AtomicBool>,
+  event_sender: broadcast::Sender<ButtplugDeviceEvent>,
+}
+
+impl DeviceImplInternal for MqttDeviceImpl {
+  fn event_stream(&self) -> broadcast::Receiver<ButtplugDeviceEvent> {
+    self.event_sender.subscribe()
+  }
+
+  fn connected(&self) -> bool {
+    self.connected.load(Ordering::SeqCst)
+  }
+
+  fn disconnect(&self) -> ButtplugResultFuture {
+    self.connected.store(false, Ordering::SeqCst);
+    self.subscribed.store(false, Ordering::SeqCst);
+    // Clear our connection so the comm manager can announce the device again.
+    if let Some(mut state) = self.devices.get_mut(&self.mapping.address) {
+      state.connection = None;
+    }
+    let _ = self
+      .event_sender
+      .send(ButtplugDeviceEvent::Removed(self.mapping.address.clone()));
+    Box::pin(future::ready(Ok(())))
+  }
+
+  /// Returns the last payload published to the state topic. MQTT has no way
+  /// to ask for a value, so this never waits.
+  fn read_value(
+    &self,
+    msg: DeviceReadCmd,
+  ) -> BoxFuture<'static, Result<RawReading, ButtplugError>> {
+    if msg.endpoint != Endpoint::Rx || self.mapping.state_topic.is_none() {
+      return ButtplugDeviceError::InvalidEndpoint(msg.endpoint).into();
+    }
+    let data = self
+      .devices
+      .get(&self.mapping.address)
+      .and_then(|state| state.last_state.clone())
+      .unwrap_or_default();
+    Box::pin(future::ready(Ok(RawReading::new(0, Endpoint::Rx, data))))
+  }
+
+  fn write_value(&self, msg: DeviceWriteCmd) -> ButtplugResultFuture {
+    if msg.endpoint != Endpoint::Tx {
+      return ButtplugDeviceError::InvalidEndpoint(msg.endpoint).into();
+    }
+    let client = self.client.clone();
+    let topic = self.mapping.command_topic.clone();
+    Box::pin(async move {
+      client
+        .publish(topic, QoS::AtLeastOnce, false, msg.data)
+        .await
+        .map_err(|err| {
+          ButtplugDeviceError::DeviceSpecificError(ButtplugDeviceSpecificError::MqttError(
+            err.to_string(),
+          ))
+          .into()
+        })
+    })
+  }
+
+  fn subscribe(&self, msg: DeviceSubscribeCmd) -> ButtplugResultFuture {
+    if msg.endpoint != Endpoint::Rx || self.mapping.state_topic.is_none() {
+      return ButtplugDeviceError::InvalidEndpoint(msg.endpoint).into();
+    }
+    // We're always subscribed to the state topic on the broker, this just
+    // controls whether state updates are forwarded as notifications.
+    self.subscribed.store(true, Ordering::SeqCst);
+    Box::pin(future::ready(Ok(())))
+  }
+
+  fn unsubscribe(&self, msg: DeviceUnsubscribeCmd) -> ButtplugResultFuture {
+    if msg.endpoint != Endpoint::Rx || self.mapping.state_topic.is_none() {
+      return ButtplugDeviceError::InvalidEndpoint(msg.endpoint).into();
+    }
+    self.subscribed.store(false, Ordering::SeqCst);
+    Box::pin(future::ready(Ok(())))
+  }
+}
+
+impl Drop for MqttDeviceImpl {
+  fn drop(&mut self) {
+    // If we're dropped without being disconnected (protocol initialization
+    // failed, for instance), still let the device be found again.
+    if self.connected.load(Ordering::SeqCst) {
+      if let Some(mut state) = self.devices.get_mut(&self.mapping.address) {
+        state.connection = None;
+      }
+    }
+  }
+}
@@ -3,6 +3,7 @@ use crate::{
   core::ButtplugResultFuture,
   server::comm_managers::{
     DeviceCommunicationEvent, DeviceCommunicationManager, DeviceCommunicationManagerBuilder,
+    DeviceCommunicationManagerCapabilities, DeviceCommunicationTransport,
   },
 };
 use futures::future;
@@ -41,6 +42,17 @@ impl DeviceCommunicationManager for SerialPortCommunicationManager {
     "SerialPortCommunicationManager"
   }
 
+  fn capabilities(&self) -> DeviceCommunicationManagerCapabilities {
+    DeviceCommunicationManagerCapabilities {
+      transport: DeviceCommunicationTransport::Serial,
+      // Ports are enumerated once per scan.
+      supports_hotplug: false,
+      // Usually requires being in the dialout/uucp group on linux.
+      requires_permissions: cfg!(target_os = "linux"),
+      unavailable_reason: None,
+    }
+  }
+
   fn start_scanning(&self) -> ButtplugResultFuture {
     debug!("Serial port manager scanning for devices.");
     // TODO Does this block? Should it run in one of our threads?
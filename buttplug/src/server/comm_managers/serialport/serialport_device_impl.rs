@@ -135,9 +135,6 @@ impl SerialPortDeviceImpl {
       .find(|port| port_info.port_name == port.port)
       .unwrap();
 
-    // This seems like it should be a oneshot, but there's no way to await a
-    // value on those?
-    let (port_sender, mut port_receiver) = mpsc::channel(1);
     // Mostly just feeling lazy here and don't wanna do the enum conversions.
     /*
     settings.stop_bits = port_def.stop_bits;
@@ -146,27 +143,24 @@ impl SerialPortDeviceImpl {
     */
     // TODO for now, assume 8/N/1. Not really sure when/if this would ever change.
     let port_name = port_info.port_name.clone();
-    thread::Builder::new()
-      .name("Serial Port Connection Thread".to_string())
-      .spawn(move || {
-        debug!("Starting serial port connection thread for {}", port_name);
-        let port_result = serialport::new(&port_name, port_def.baud_rate)
-          .timeout(Duration::from_millis(100))
-          .open();
-        if port_sender.blocking_send(port_result)
-          .is_err() {
-            warn!("Serial port open thread did not return before serial device was dropped. Dropping port.");
-          }
-        debug!("Exiting serial port connection thread for {}", port_name);
-      })
-      .unwrap();
-
-    let port = port_receiver.recv().await.unwrap().map_err(|e| {
+    // Opening the port blocks, sometimes for a while on windows.
+    let port = async_manager::spawn_blocking(move || {
+      debug!("Opening serial port {}", port_name);
+      serialport::new(&port_name, port_def.baud_rate)
+        .timeout(Duration::from_millis(100))
+        .open()
+    })
+    .await
+    .map_err(|_| {
+      ButtplugDeviceError::DeviceConnectionError(
+        "Cannot open serial port, runtime is shutting down.".to_owned(),
+      )
+    })?
+    .map_err(|e| {
       ButtplugError::from(ButtplugDeviceError::DeviceSpecificError(
         ButtplugDeviceSpecificError::SerialError(e.to_string()),
       ))
     })?;
-    debug!("Serial port received from thread.");
     let (writer_sender, writer_receiver) = mpsc::channel(256);
     let (reader_sender, reader_receiver) = mpsc::channel(256);
 
@@ -254,7 +248,7 @@ impl DeviceImplInternal for SerialPortDeviceImpl {
     let event_sender = self.device_event_sender.clone();
     let address = self.address.clone();
     Box::pin(async move {
-      async_manager::spawn(async move {
+      async_manager::spawn_named("serial port subscription", async move {
         // TODO There's only one subscribable endpoint on a serial port, so we
         // should check to make sure we don't have multiple subscriptions so we
         // don't deadlock.
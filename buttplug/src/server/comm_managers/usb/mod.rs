@@ -0,0 +1,5 @@
+mod usb_comm_manager;
+mod usb_device_impl;
+
+pub use usb_comm_manager::{UsbCommunicationManager, UsbCommunicationManagerBuilder};
+pub use usb_device_impl::{UsbDeviceImpl, UsbDeviceImplCreator};
@@ -0,0 +1,272 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2021 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Comm manager for devices we talk to via raw USB transfers (libusb on
+//! linux/macOS, WinUSB on windows), like the Rez TranceVibrator.
+
+use super::UsbDeviceImplCreator;
+use crate::{
+  core::{errors::ButtplugDeviceError, ButtplugResultFuture},
+  device::ButtplugDeviceEvent,
+  server::comm_managers::{
+    DeviceCommunicationEvent, DeviceCommunicationManager, DeviceCommunicationManagerBuilder,
+    DeviceCommunicationManagerCapabilities, DeviceCommunicationTransport,
+  },
+  util::async_manager,
+};
+use dashmap::DashMap;
+use futures::future;
+use rusb::{Device, GlobalContext, Hotplug, HotplugBuilder, UsbContext};
+use std::{
+  sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+  },
+  thread,
+  time::Duration,
+};
+use tokio::sync::{broadcast, mpsc::Sender};
+use tracing_futures::Instrument;
+
+/// Map of addresses of devices we've created, to the event senders for those
+/// devices, so we can tell them when they've been unplugged.
+pub(super) type UsbConnectedDeviceMap =
+  Arc<DashMap<String, broadcast::Sender<ButtplugDeviceEvent>>>;
+
+pub(super) fn usb_device_address(device: &Device<GlobalContext>) -> String {
+  format!("usb-{:03}-{:03}", device.bus_number(), device.address())
+}
+
+/// Creates a DeviceFound event for a USB device. Returns None if we can't read
+/// the device descriptor, in which case we'll never be able to match it
+/// against the device configuration anyways.
+fn device_found_event(
+  device: Device<GlobalContext>,
+  connected_devices: &UsbConnectedDeviceMap,
+) -> Option<DeviceCommunicationEvent> {
+  let descriptor = match device.device_descriptor() {
+    Ok(desc) => desc,
+    Err(err) => {
+      debug!("Cannot read descriptor for USB device, ignoring: {:?}", err);
+      return None;
+    }
+  };
+  let address = usb_device_address(&device);
+  if connected_devices.contains_key(&address) {
+    return None;
+  }
+  Some(DeviceCommunicationEvent::DeviceFound {
+    name: format!(
+      "USB Device {:04x}:{:04x}",
+      descriptor.vendor_id(),
+      descriptor.product_id()
+    ),
+    address,
+    creator: Box::new(UsbDeviceImplCreator::new(
+      device,
+      descriptor.vendor_id(),
+      descriptor.product_id(),
+      connected_devices.clone(),
+    )),
+  })
+}
+
+struct UsbHotplugHandler {
+  sender: Sender<DeviceCommunicationEvent>,
+  connected_devices: UsbConnectedDeviceMap,
+}
+
+impl Hotplug<GlobalContext> for UsbHotplugHandler {
+  fn device_arrived(&mut self, device: Device<GlobalContext>) {
+    if let Some(event) = device_found_event(device, &self.connected_devices) {
+      // We're on our own event thread here, so blocking is fine.
+      if self.sender.blocking_send(event).is_err() {
+        debug!("Device manager disappeared, cannot send USB device found event.");
+      }
+    }
+  }
+
+  fn device_left(&mut self, device: Device<GlobalContext>) {
+    let address = usb_device_address(&device);
+    if let Some((_, event_sender)) = self.connected_devices.remove(&address) {
+      info!("USB device {} unplugged.", address);
+      let _ = event_sender.send(ButtplugDeviceEvent::Removed(address));
+    }
+  }
+}
+
+#[derive(Default)]
+pub struct UsbCommunicationManagerBuilder {
+  sender: Option<tokio::sync::mpsc::Sender<DeviceCommunicationEvent>>,
+}
+
+impl DeviceCommunicationManagerBuilder for UsbCommunicationManagerBuilder {
+  fn set_event_sender(&mut self, sender: Sender<DeviceCommunicationEvent>) {
+    self.sender = Some(sender)
+  }
+
+  fn finish(mut self) -> Box<dyn DeviceCommunicationManager> {
+    Box::new(UsbCommunicationManager::new(self.sender.take().unwrap()))
+  }
+}
+
+pub struct UsbCommunicationManager {
+  sender: Sender<DeviceCommunicationEvent>,
+  connected_devices: UsbConnectedDeviceMap,
+  is_scanning: Arc<AtomicBool>,
+}
+
+impl UsbCommunicationManager {
+  fn new(sender: Sender<DeviceCommunicationEvent>) -> Self {
+    trace!("USB comm manager created.");
+    Self {
+      sender,
+      connected_devices: Arc::new(DashMap::new()),
+      is_scanning: Arc::new(AtomicBool::new(false)),
+    }
+  }
+
+  /// On platforms with hotplug support, we register for hotplug events (with
+  /// enumeration of already plugged devices) and keep handling them until
+  /// scanning is stopped.
+  fn start_hotplug_scanning(&self) -> ButtplugResultFuture {
+    if self.is_scanning.swap(true, Ordering::SeqCst) {
+      return ButtplugDeviceError::DeviceScanningAlreadyStarted.into();
+    }
+    let sender = self.sender.clone();
+    let connected_devices = self.connected_devices.clone();
+    let is_scanning = self.is_scanning.clone();
+    thread::Builder::new()
+      .name("USB Hotplug Thread".to_owned())
+      .spawn(move || {
+        let context = GlobalContext::default();
+        let handler = UsbHotplugHandler {
+          sender: sender.clone(),
+          connected_devices,
+        };
+        let mut builder = HotplugBuilder::new();
+        builder.enumerate(true);
+        // The callback stays registered until the registration is dropped at
+        // the end of this thread.
+        let _registration = match builder.register(context, Box::new(handler)) {
+          Ok(reg) => reg,
+          Err(err) => {
+            error!("Cannot register USB hotplug callback: {:?}", err);
+            is_scanning.store(false, Ordering::SeqCst);
+            let _ = sender.blocking_send(DeviceCommunicationEvent::ScanningFinished);
+            return;
+          }
+        };
+        while is_scanning.load(Ordering::SeqCst) {
+          if let Err(err) = context.handle_events(Some(Duration::from_millis(100))) {
+            error!("Error handling USB events, stopping scan: {:?}", err);
+            is_scanning.store(false, Ordering::SeqCst);
+          }
+        }
+        debug!("USB hotplug scanning finished.");
+        if sender
+          .blocking_send(DeviceCommunicationEvent::ScanningFinished)
+          .is_err()
+        {
+          error!("Error sending scanning finished from USB manager.");
+        }
+      })
+      .unwrap();
+    Box::pin(future::ready(Ok(())))
+  }
+
+  /// Without hotplug support, do a single pass over the device list, same as
+  /// the serial port manager.
+  fn start_enumeration_scanning(&self) -> ButtplugResultFuture {
+    let sender = self.sender.clone();
+    let connected_devices = self.connected_devices.clone();
+    Box::pin(
+      async move {
+        // Enumeration reads descriptors from every device on the bus, so
+        // it's done on a blocking thread.
+        let events = async_manager::spawn_blocking(move || {
+          rusb::devices().map(|devices| {
+            debug!("Got {} USB devices back", devices.len());
+            devices
+              .iter()
+              .filter_map(|device| device_found_event(device, &connected_devices))
+              .collect::<Vec<_>>()
+          })
+        })
+        .await;
+        match events {
+          Ok(Ok(events)) => {
+            for event in events {
+              if sender.send(event).await.is_err() {
+                debug!("Device manager disappeared, exiting.");
+                break;
+              }
+            }
+          }
+          Ok(Err(err)) => {
+            error!("Cannot enumerate USB devices: {:?}", err);
+          }
+          Err(_) => {
+            error!("Cannot enumerate USB devices, runtime is shutting down.");
+          }
+        }
+        if sender
+          .send(DeviceCommunicationEvent::ScanningFinished)
+          .await
+          .is_err()
+        {
+          error!("Error sending scanning finished.");
+        }
+        Ok(())
+      }
+      .instrument(tracing::info_span!("USB Device Comm Manager Scanning.")),
+    )
+  }
+}
+
+impl DeviceCommunicationManager for UsbCommunicationManager {
+  fn name(&self) -> &'static str {
+    "UsbCommunicationManager"
+  }
+
+  fn capabilities(&self) -> DeviceCommunicationManagerCapabilities {
+    DeviceCommunicationManagerCapabilities {
+      transport: DeviceCommunicationTransport::USB,
+      supports_hotplug: rusb::has_hotplug(),
+      // Linux needs udev rules for raw device access, windows needs the
+      // WinUSB driver installed for the device (via Zadig or similar).
+      requires_permissions: cfg!(any(target_os = "linux", target_os = "windows")),
+      unavailable_reason: None,
+    }
+  }
+
+  fn start_scanning(&self) -> ButtplugResultFuture {
+    debug!("USB manager scanning for devices.");
+    if rusb::has_hotplug() {
+      self.start_hotplug_scanning()
+    } else {
+      self.start_enumeration_scanning()
+    }
+  }
+
+  fn stop_scanning(&self) -> ButtplugResultFuture {
+    // Only matters for hotplug scanning, enumeration finishes on its own.
+    self.is_scanning.store(false, Ordering::SeqCst);
+    Box::pin(future::ready(Ok(())))
+  }
+
+  fn scanning_status(&self) -> Arc<AtomicBool> {
+    self.is_scanning.clone()
+  }
+}
+
+impl Drop for UsbCommunicationManager {
+  fn drop(&mut self) {
+    // Make sure the hotplug thread exits.
+    self.is_scanning.store(false, Ordering::SeqCst);
+  }
+}
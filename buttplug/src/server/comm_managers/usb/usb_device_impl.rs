@@ -0,0 +1,414 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2021 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+use super::usb_comm_manager::{usb_device_address, UsbConnectedDeviceMap};
+use crate::{
+  core::{
+    errors::{ButtplugDeviceError, ButtplugError},
+    messages::RawReading,
+    ButtplugResultFuture,
+  },
+  device::{
+    configuration_manager::{DeviceSpecifier, ProtocolDefinition, USBSpecifier},
+    ButtplugDeviceEvent, ButtplugDeviceImplCreator, DeviceImpl, DeviceImplInternal, DeviceReadCmd,
+    DeviceSubscribeCmd, DeviceUnsubscribeCmd, DeviceWriteCmd, Endpoint,
+  },
+  server::comm_managers::ButtplugDeviceSpecificError,
+  util::async_manager,
+};
+use async_trait::async_trait;
+use futures::{
+  future::{self, BoxFuture},
+  task::SpawnError,
+};
+use rusb::{Device, DeviceHandle, Direction, GlobalContext, Recipient, RequestType, TransferType};
+use std::{
+  fmt::{self, Debug},
+  ops::Deref,
+  sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+  },
+  thread,
+  time::Duration,
+};
+use tokio::sync::broadcast;
+
+// Transfers to toys are tiny, so if they take longer than this something has
+// gone wrong.
+const USB_WRITE_TIMEOUT: Duration = Duration::from_millis(500);
+const USB_SUBSCRIBE_READ_TIMEOUT: Duration = Duration::from_millis(100);
+// Vendor control writes carry a 5 byte header: request (1 byte), value (u16
+// LE), index (u16 LE). Anything after that goes in the data stage.
+const VENDOR_CONTROL_HEADER_LENGTH: usize = 5;
+
+fn usb_error(err: rusb::Error) -> ButtplugError {
+  ButtplugDeviceError::DeviceSpecificError(ButtplugDeviceSpecificError::UsbError(err.to_string()))
+    .into()
+}
+
+fn transfer_spawn_error(_: SpawnError) -> ButtplugError {
+  ButtplugDeviceError::DeviceCommunicationError(
+    "Cannot run USB transfer, runtime is shutting down.".to_owned(),
+  )
+  .into()
+}
+
+#[derive(Clone, Copy, Debug)]
+struct UsbEndpoint {
+  address: u8,
+  transfer_type: TransferType,
+}
+
+/// Finds the first bulk or interrupt endpoints for each direction, along with
+/// the interface they belong to, which we'll need to claim.
+fn find_transfer_endpoints(
+  device: &Device<GlobalContext>,
+) -> (Option<u8>, Option<UsbEndpoint>, Option<UsbEndpoint>) {
+  let config = match device.active_config_descriptor() {
+    Ok(config) => config,
+    Err(err) => {
+      debug!(
+        "Cannot read USB config descriptor, assuming control transfers only: {:?}",
+        err
+      );
+      return (None, None, None);
+    }
+  };
+  let mut interface_number = None;
+  let mut out_endpoint = None;
+  let mut in_endpoint = None;
+  for interface in config.interfaces() {
+    for descriptor in interface.descriptors() {
+      for endpoint in descriptor.endpoint_descriptors() {
+        if endpoint.transfer_type() != TransferType::Bulk
+          && endpoint.transfer_type() != TransferType::Interrupt
+        {
+          continue;
+        }
+        let usb_endpoint = UsbEndpoint {
+          address: endpoint.address(),
+          transfer_type: endpoint.transfer_type(),
+        };
+        let slot = match endpoint.direction() {
+          Direction::Out => &mut out_endpoint,
+          Direction::In => &mut in_endpoint,
+        };
+        if slot.is_none() {
+          *slot = Some(usb_endpoint);
+          interface_number.get_or_insert(descriptor.interface_number());
+        }
+      }
+    }
+  }
+  (interface_number, out_endpoint, in_endpoint)
+}
+
+pub struct UsbDeviceImplCreator {
+  specifier: DeviceSpecifier,
+  device: Option<Device<GlobalContext>>,
+  address: String,
+  connected_devices: UsbConnectedDeviceMap,
+}
+
+impl UsbDeviceImplCreator {
+  pub(super) fn new(
+    device: Device<GlobalContext>,
+    vendor_id: u16,
+    product_id: u16,
+    connected_devices: UsbConnectedDeviceMap,
+  ) -> Self {
+    Self {
+      specifier: DeviceSpecifier::USB(USBSpecifier::new(vendor_id, product_id)),
+      address: usb_device_address(&device),
+      device: Some(device),
+      connected_devices,
+    }
+  }
+}
+
+impl Debug for UsbDeviceImplCreator {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("UsbDeviceImplCreator")
+      .field("specifier", &self.specifier)
+      .field("address", &self.address)
+      .finish()
+  }
+}
+
+#[async_trait]
+impl ButtplugDeviceImplCreator for UsbDeviceImplCreator {
+  fn get_specifier(&self) -> DeviceSpecifier {
+    self.specifier.clone()
+  }
+
+  async fn try_create_device_impl(
+    &mut self,
+    _protocol: ProtocolDefinition,
+  ) -> Result<DeviceImpl, ButtplugError> {
+    let device = self.device.take().unwrap();
+    let address = self.address.clone();
+    let connected_devices = self.connected_devices.clone();
+    // Opening the device and claiming its interface both block.
+    let device_impl_internal = async_manager::spawn_blocking(move || {
+      UsbDeviceImpl::try_create(&address, &device, connected_devices)
+    })
+    .await
+    .map_err(|_| {
+      ButtplugDeviceError::DeviceConnectionError(
+        "Cannot open USB device, runtime is shutting down.".to_owned(),
+      )
+    })??;
+    let mut endpoints = vec![Endpoint::TxVendorControl];
+    if device_impl_internal.out_endpoint.is_some() {
+      endpoints.push(Endpoint::Tx);
+    }
+    if device_impl_internal.in_endpoint.is_some() {
+      endpoints.push(Endpoint::Rx);
+    }
+    self.connected_devices.insert(
+      self.address.clone(),
+      device_impl_internal.device_event_sender.clone(),
+    );
+    let device_impl = DeviceImpl::new(
+      &self.address,
+      &self.address,
+      &endpoints,
+      Box::new(device_impl_internal),
+    );
+    Ok(device_impl)
+  }
+}
+
+/// Device handle that's closed on a blocking thread. Closing releases the
+/// claimed interface, which is a blocking transfer, and the last reference
+/// usually goes away on an async thread.
+struct UsbHandle(Option<DeviceHandle<GlobalContext>>);
+
+impl Deref for UsbHandle {
+  type Target = DeviceHandle<GlobalContext>;
+
+  fn deref(&self) -> &Self::Target {
+    self.0.as_ref().expect("Handle is only taken on drop")
+  }
+}
+
+impl Drop for UsbHandle {
+  fn drop(&mut self) {
+    if let Some(handle) = self.0.take() {
+      // If we can't spawn, the runtime's going away, so just close it here.
+      let _ = async_manager::spawn(async move {
+        let _ = async_manager::spawn_blocking(move || drop(handle)).await;
+      });
+    }
+  }
+}
+
+pub struct UsbDeviceImpl {
+  address: String,
+  handle: Arc<UsbHandle>,
+  out_endpoint: Option<UsbEndpoint>,
+  in_endpoint: Option<UsbEndpoint>,
+  connected: Arc<AtomicBool>,
+  subscribed: Arc<AtomicBool>,
+  device_event_sender: broadcast::Sender<ButtplugDeviceEvent>,
+  connected_devices: UsbConnectedDeviceMap,
+}
+
+impl UsbDeviceImpl {
+  fn try_create(
+    address: &str,
+    device: &Device<GlobalContext>,
+    connected_devices: UsbConnectedDeviceMap,
+  ) -> Result<Self, ButtplugError> {
+    let (device_event_sender, _) = broadcast::channel(256);
+    let handle = device.open().map_err(usb_error)?;
+    let (interface_number, out_endpoint, in_endpoint) = find_transfer_endpoints(device);
+    if let Some(interface_number) = interface_number {
+      // Not supported on all platforms (WinUSB devices won't have a kernel
+      // driver attached anyways), so just log failures.
+      if let Err(err) = handle.set_auto_detach_kernel_driver(true) {
+        debug!("Cannot set USB kernel driver auto detach: {:?}", err);
+      }
+      handle
+        .claim_interface(interface_number)
+        .map_err(usb_error)?;
+    }
+    Ok(Self {
+      address: address.to_owned(),
+      handle: Arc::new(UsbHandle(Some(handle))),
+      out_endpoint,
+      in_endpoint,
+      connected: Arc::new(AtomicBool::new(true)),
+      subscribed: Arc::new(AtomicBool::new(false)),
+      device_event_sender,
+      connected_devices,
+    })
+  }
+}
+
+fn read_endpoint(
+  handle: &DeviceHandle<GlobalContext>,
+  endpoint: UsbEndpoint,
+  length: usize,
+  timeout: Duration,
+) -> rusb::Result<Vec<u8>> {
+  let mut buf = vec![0; length];
+  let read_len = match endpoint.transfer_type {
+    TransferType::Interrupt => handle.read_interrupt(endpoint.address, &mut buf, timeout)?,
+    _ => handle.read_bulk(endpoint.address, &mut buf, timeout)?,
+  };
+  buf.truncate(read_len);
+  Ok(buf)
+}
+
+impl DeviceImplInternal for UsbDeviceImpl {
+  fn event_stream(&self) -> broadcast::Receiver<ButtplugDeviceEvent> {
+    self.device_event_sender.subscribe()
+  }
+
+  fn connected(&self) -> bool {
+    self.connected.load(Ordering::SeqCst)
+  }
+
+  fn disconnect(&self) -> ButtplugResultFuture {
+    self.connected.store(false, Ordering::SeqCst);
+    self.subscribed.store(false, Ordering::SeqCst);
+    // Let the comm manager find the device again on the next scan.
+    if self.connected_devices.remove(&self.address).is_some() {
+      let _ = self
+        .device_event_sender
+        .send(ButtplugDeviceEvent::Removed(self.address.clone()));
+    }
+    Box::pin(future::ready(Ok(())))
+  }
+
+  fn read_value(
+    &self,
+    msg: DeviceReadCmd,
+  ) -> BoxFuture<'static, Result<RawReading, ButtplugError>> {
+    let endpoint = match (msg.endpoint, self.in_endpoint) {
+      (Endpoint::Rx, Some(endpoint)) => endpoint,
+      _ => return ButtplugDeviceError::InvalidEndpoint(msg.endpoint).into(),
+    };
+    let handle = self.handle.clone();
+    let length = msg.length as usize;
+    let timeout = Duration::from_millis(msg.timeout_ms as u64);
+    // Reads wait up to the caller's timeout, so they can't run on an async
+    // thread.
+    Box::pin(async move {
+      async_manager::spawn_blocking(move || read_endpoint(&handle, endpoint, length, timeout))
+        .await
+        .map_err(transfer_spawn_error)?
+        .map(|data| RawReading::new(0, Endpoint::Rx, data))
+        .map_err(usb_error)
+    })
+  }
+
+  fn write_value(&self, msg: DeviceWriteCmd) -> ButtplugResultFuture {
+    // None for vendor control writes, which go to the device itself instead
+    // of an endpoint.
+    let out_endpoint = match (msg.endpoint, self.out_endpoint) {
+      (Endpoint::TxVendorControl, _) => {
+        if msg.data.len() < VENDOR_CONTROL_HEADER_LENGTH {
+          return ButtplugDeviceError::DeviceCommunicationError(format!(
+            "Vendor control writes require a {} byte header, got {} bytes.",
+            VENDOR_CONTROL_HEADER_LENGTH,
+            msg.data.len()
+          ))
+          .into();
+        }
+        None
+      }
+      (Endpoint::Tx, Some(endpoint)) => Some(endpoint),
+      _ => return ButtplugDeviceError::InvalidEndpoint(msg.endpoint).into(),
+    };
+    let handle = self.handle.clone();
+    // Even small transfers can block for the whole timeout if the device
+    // stops answering, so they run on a blocking thread.
+    Box::pin(async move {
+      async_manager::spawn_blocking(move || match out_endpoint {
+        None => handle.write_control(
+          rusb::request_type(Direction::Out, RequestType::Vendor, Recipient::Device),
+          msg.data[0],
+          u16::from_le_bytes([msg.data[1], msg.data[2]]),
+          u16::from_le_bytes([msg.data[3], msg.data[4]]),
+          &msg.data[VENDOR_CONTROL_HEADER_LENGTH..],
+          USB_WRITE_TIMEOUT,
+        ),
+        Some(UsbEndpoint {
+          address,
+          transfer_type: TransferType::Interrupt,
+        }) => handle.write_interrupt(address, &msg.data, USB_WRITE_TIMEOUT),
+        Some(UsbEndpoint { address, .. }) => {
+          handle.write_bulk(address, &msg.data, USB_WRITE_TIMEOUT)
+        }
+      })
+      .await
+      .map_err(transfer_spawn_error)?
+      .map(|_| ())
+      .map_err(usb_error)
+    })
+  }
+
+  fn subscribe(&self, msg: DeviceSubscribeCmd) -> ButtplugResultFuture {
+    let endpoint = match (msg.endpoint, self.in_endpoint) {
+      (Endpoint::Rx, Some(endpoint)) => endpoint,
+      _ => return ButtplugDeviceError::InvalidEndpoint(msg.endpoint).into(),
+    };
+    if self.subscribed.swap(true, Ordering::SeqCst) {
+      // Already reading, nothing else to do.
+      return Box::pin(future::ready(Ok(())));
+    }
+    let handle = self.handle.clone();
+    let subscribed = self.subscribed.clone();
+    let event_sender = self.device_event_sender.clone();
+    let address = self.address.clone();
+    thread::Builder::new()
+      .name("USB Reader Thread".to_owned())
+      .spawn(move || {
+        while subscribed.load(Ordering::SeqCst) {
+          match read_endpoint(&handle, endpoint, 64, USB_SUBSCRIBE_READ_TIMEOUT) {
+            Ok(data) => {
+              if event_sender
+                .send(ButtplugDeviceEvent::Notification(
+                  address.clone(),
+                  Endpoint::Rx,
+                  data,
+                ))
+                .is_err()
+              {
+                debug!("No USB event receivers, dropping notification.");
+              }
+            }
+            Err(rusb::Error::Timeout) => continue,
+            Err(err) => {
+              error!("USB read error, ending subscription: {:?}", err);
+              subscribed.store(false, Ordering::SeqCst);
+            }
+          }
+        }
+      })
+      .unwrap();
+    Box::pin(future::ready(Ok(())))
+  }
+
+  fn unsubscribe(&self, msg: DeviceUnsubscribeCmd) -> ButtplugResultFuture {
+    if msg.endpoint != Endpoint::Rx || self.in_endpoint.is_none() {
+      return ButtplugDeviceError::InvalidEndpoint(msg.endpoint).into();
+    }
+    self.subscribed.store(false, Ordering::SeqCst);
+    Box::pin(future::ready(Ok(())))
+  }
+}
+
+impl Drop for UsbDeviceImpl {
+  fn drop(&mut self) {
+    self.subscribed.store(false, Ordering::SeqCst);
+    self.connected_devices.remove(&self.address);
+  }
+}
@@ -4,11 +4,11 @@ use crate::{
   device::ButtplugDeviceEvent,
   server::comm_managers::{
     DeviceCommunicationEvent, DeviceCommunicationManager, DeviceCommunicationManagerBuilder,
+    DeviceCommunicationManagerCapabilities, DeviceCommunicationTransport,
   },
   util::async_manager,
 };
 use futures::{future, FutureExt};
-use futures_timer::Delay;
 use std::{
   string::ToString,
   sync::{
@@ -83,7 +83,7 @@ async fn check_gamepad_connectivity(
         }
       }
     }
-    Delay::new(Duration::from_millis(500)).await;
+    async_manager::sleep(Duration::from_millis(500)).await;
   }
 }
 
@@ -97,7 +97,7 @@ impl XInputConnectionTracker {
     if should_start {
       let connected_gamepads = self.connected_gamepads.clone();
       let check_running = self.check_running.clone();
-      async_manager::spawn(async move {
+      async_manager::spawn_named("xinput connectivity check", async move {
         check_gamepad_connectivity(connected_gamepads, check_running, None).await;
       })
       .unwrap();
@@ -116,7 +116,7 @@ impl XInputConnectionTracker {
     if should_start {
       let connected_gamepads = self.connected_gamepads.clone();
       let check_running = self.check_running.clone();
-      async_manager::spawn(async move {
+      async_manager::spawn_named("xinput connectivity check", async move {
         check_gamepad_connectivity(connected_gamepads, check_running, Some(sender)).await;
       })
       .unwrap();
@@ -165,12 +165,21 @@ impl DeviceCommunicationManager for XInputDeviceCommunicationManager {
     "XInputDeviceCommunicationManager"
   }
 
+  fn capabilities(&self) -> DeviceCommunicationManagerCapabilities {
+    DeviceCommunicationManagerCapabilities {
+      transport: DeviceCommunicationTransport::XInput,
+      supports_hotplug: true,
+      requires_permissions: false,
+      unavailable_reason: None,
+    }
+  }
+
   fn start_scanning(&self) -> ButtplugResultFuture {
     debug!("XInput manager scanning for devices");
     let sender = self.sender.clone();
     let scanning_notifier = self.scanning_notifier.clone();
     let connected_gamepads = self.connected_gamepads.clone();
-    async_manager::spawn(async move {
+    async_manager::spawn_named("xinput scanning", async move {
       let handle = rusty_xinput::XInputHandle::load_default().unwrap();
       let mut stop = false;
       while !stop {
@@ -210,7 +219,7 @@ impl DeviceCommunicationManager for XInputDeviceCommunicationManager {
         }
         // Wait for either one second, or until our notifier has been notified.
         select! {
-          _ = Delay::new(Duration::from_secs(1)).fuse() => {},
+          _ = async_manager::sleep(Duration::from_secs(1)).fuse() => {},
           _ = scanning_notifier.notified().fuse() => {
             debug!("XInput stop scanning notifier notified, ending scanning loop");
             stop = true;
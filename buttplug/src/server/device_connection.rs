@@ -0,0 +1,47 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2022 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Progress of found devices that aren't connected yet.
+//!
+//! Some devices take seconds between being found and showing up in a
+//! DeviceAdded message (session setup, reading device info, etc). The device
+//! manager sends a [DeviceConnectionEvent] for each step, so front-ends can
+//! show that something is happening instead of nothing at all. Only devices
+//! that match a protocol get these events, and every
+//! [DeviceConnectionEvent::DeviceConnecting] is followed by either
+//! [DeviceConnectionEvent::DeviceConnected] or
+//! [DeviceConnectionEvent::DeviceConnectionFailed].
+
+/// Events about devices being connected. `name` and `address` are what the
+/// comm manager found the device as, which is all we know about it until it
+/// connects.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DeviceConnectionEvent {
+  /// The device matched a protocol, and we're connecting to it.
+  DeviceConnecting {
+    name: String,
+    address: String,
+    protocol: String,
+  },
+  /// The device is connected, and its protocol is setting it up. Sent once
+  /// with an empty stage when initialization starts, then again for each
+  /// stage protocols with slow setups report, e.g. "Setting up session".
+  DeviceInitializing {
+    name: String,
+    address: String,
+    stage: String,
+  },
+  /// The device is ready. The client hears about it as usual, unless the
+  /// device is filtered, grouped, etc.
+  DeviceConnected { name: String, address: String },
+  /// Connecting or initializing the device failed.
+  DeviceConnectionFailed {
+    name: String,
+    address: String,
+    error: String,
+  },
+}
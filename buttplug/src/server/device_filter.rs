@@ -0,0 +1,64 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2021 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Limits which devices a server exposes to its client.
+
+use crate::device::ButtplugDevice;
+use std::collections::HashSet;
+
+/// Filter for the devices a client is allowed to see and control.
+///
+/// An empty filter allows every device. Once any address or protocol has been
+/// added, only devices matching at least one of the entries are visible.
+/// Devices that don't pass the filter are still connected and managed by the
+/// server, they just won't show up in DeviceAdded/DeviceList messages, and
+/// commands addressed to them will fail as if they weren't connected.
+///
+/// Addresses are matched against the device's
+/// [DeviceIdentity][crate::device::identity::DeviceIdentity], so they can be
+/// given in any format the platform reports, or as a device ID. Virtual
+/// devices (groups and splits) don't have a protocol, and are matched against
+/// their own address.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DeviceFilter {
+  addresses: HashSet<String>,
+  protocols: HashSet<String>,
+}
+
+impl DeviceFilter {
+  pub fn allow_address(mut self, address: &str) -> Self {
+    self.addresses.insert(address.to_owned());
+    self
+  }
+
+  /// Allows all devices using a protocol, by its name in the device
+  /// configuration file (for instance, "lovense").
+  pub fn allow_protocol(mut self, protocol: &str) -> Self {
+    self.protocols.insert(protocol.to_owned());
+    self
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.addresses.is_empty() && self.protocols.is_empty()
+  }
+
+  pub fn allows(&self, device: &ButtplugDevice) -> bool {
+    self.is_empty()
+      || self.allows_identity(device)
+      || device
+        .protocol_identifier()
+        .map_or(false, |protocol| self.protocols.contains(protocol))
+  }
+
+  fn allows_identity(&self, device: &ButtplugDevice) -> bool {
+    let identity = device.identity();
+    self
+      .addresses
+      .iter()
+      .any(|address| identity.matches(address))
+  }
+}
@@ -0,0 +1,438 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2021 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Virtual devices made up of multiple physical devices.
+//!
+//! A device group is registered with the server by name, along with the
+//! addresses of its member devices. When members connect, they are not
+//! exposed to clients individually. Instead, the server creates a composite
+//! device whose feature indexes are the concatenation of its members' feature
+//! indexes, in the order the addresses were given. Commands sent to the
+//! composite are split up and fanned out to the members.
+
+use crate::{
+  core::{
+    errors::{ButtplugDeviceError, ButtplugError},
+    messages::{
+      self, ButtplugDeviceCommandMessageUnion, ButtplugDeviceMessageType, DeviceMessageAttributes,
+      DeviceMessageAttributesMap, LinearCmd, RawReading, RotateCmd, RotationSubcommand,
+      StopDeviceCmd, VectorSubcommand, VibrateCmd, VibrateSubcommand,
+    },
+    ButtplugResultFuture,
+  },
+  device::{
+    identity::{normalize_address, DeviceIdentity},
+    protocol::{ButtplugProtocol, ButtplugProtocolCommandHandler, ButtplugProtocolProperties},
+    ButtplugDevice, ButtplugDeviceEvent, ButtplugDeviceResultFuture, DeviceImpl,
+    DeviceImplInternal, DeviceReadCmd, DeviceSubscribeCmd, DeviceUnsubscribeCmd, DeviceWriteCmd,
+  },
+};
+use futures::future::{self, BoxFuture};
+use std::{collections::HashMap, sync::Arc};
+use tokio::sync::broadcast;
+
+/// Prefix used to build addresses for composite devices, so that they can
+/// take part in device index reuse like any other device.
+pub const DEVICE_GROUP_ADDRESS_PREFIX: &str = "group:";
+
+/// Configuration for a device group: the name clients will see, and the
+/// addresses of the physical devices that make it up, in feature order.
+/// Member addresses can be anything a
+/// [DeviceIdentity][crate::device::identity::DeviceIdentity] matches,
+/// including device IDs.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeviceGroup {
+  name: String,
+  member_addresses: Vec<String>,
+}
+
+impl DeviceGroup {
+  pub fn new(name: &str, member_addresses: &[String]) -> Self {
+    Self {
+      name: name.to_owned(),
+      member_addresses: member_addresses.to_vec(),
+    }
+  }
+
+  pub fn name(&self) -> &str {
+    &self.name
+  }
+
+  pub fn member_addresses(&self) -> &Vec<String> {
+    &self.member_addresses
+  }
+
+  pub fn address(&self) -> String {
+    format!("{}{}", DEVICE_GROUP_ADDRESS_PREFIX, self.name)
+  }
+
+  /// True if the address is one of the group's members. Addresses are
+  /// compared after normalization, so formatting differences don't matter.
+  pub fn contains(&self, address: &str) -> bool {
+    let address = normalize_address(address);
+    self
+      .member_addresses
+      .iter()
+      .any(|member| normalize_address(member) == address)
+  }
+
+  /// True if the device is one of the group's members.
+  pub fn contains_device(&self, identity: &DeviceIdentity) -> bool {
+    self
+      .member_addresses
+      .iter()
+      .any(|member| identity.matches(member))
+  }
+
+  /// Builds the composite device out of whichever members are currently
+  /// connected. Members will be ordered as they were in the group
+  /// configuration, regardless of connection order.
+  pub fn create_device(
+    &self,
+    connected_members: &HashMap<String, Arc<ButtplugDevice>>,
+  ) -> Option<ButtplugDevice> {
+    let members: Vec<Arc<ButtplugDevice>> = self
+      .member_addresses
+      .iter()
+      .filter_map(|address| {
+        connected_members
+          .values()
+          .find(|member| member.identity().matches(address))
+          .cloned()
+      })
+      .collect();
+    if members.is_empty() {
+      return None;
+    }
+    let device_impl = DeviceImpl::new(
+      &self.name,
+      &self.address(),
+      &[],
+      Box::new(VirtualDeviceImpl::default()),
+    );
+    Some(ButtplugDevice::new(
+      Box::new(DeviceGroupProtocol::new_with_members(&self.name, members)),
+      Arc::new(device_impl),
+    ))
+  }
+}
+
+/// Virtual devices (groups and splits) don't talk to hardware themselves,
+/// everything goes through the physical devices backing them. This just fills
+/// in the DeviceImpl slot.
+pub(super) struct VirtualDeviceImpl {
+  event_sender: broadcast::Sender<ButtplugDeviceEvent>,
+}
+
+impl Default for VirtualDeviceImpl {
+  fn default() -> Self {
+    let (event_sender, _) = broadcast::channel(256);
+    Self { event_sender }
+  }
+}
+
+impl DeviceImplInternal for VirtualDeviceImpl {
+  fn connected(&self) -> bool {
+    true
+  }
+
+  fn disconnect(&self) -> ButtplugResultFuture {
+    // Backing devices are owned by the device manager, which will disconnect
+    // them itself.
+    Box::pin(future::ready(Ok(())))
+  }
+
+  fn event_stream(&self) -> broadcast::Receiver<ButtplugDeviceEvent> {
+    self.event_sender.subscribe()
+  }
+
+  fn read_value(
+    &self,
+    msg: DeviceReadCmd,
+  ) -> BoxFuture<'static, Result<RawReading, ButtplugError>> {
+    ButtplugDeviceError::InvalidEndpoint(msg.endpoint).into()
+  }
+
+  fn write_value(&self, msg: DeviceWriteCmd) -> ButtplugResultFuture {
+    ButtplugDeviceError::InvalidEndpoint(msg.endpoint).into()
+  }
+
+  fn subscribe(&self, msg: DeviceSubscribeCmd) -> ButtplugResultFuture {
+    ButtplugDeviceError::InvalidEndpoint(msg.endpoint).into()
+  }
+
+  fn unsubscribe(&self, msg: DeviceUnsubscribeCmd) -> ButtplugResultFuture {
+    ButtplugDeviceError::InvalidEndpoint(msg.endpoint).into()
+  }
+}
+
+#[derive(ButtplugProtocolProperties)]
+pub struct DeviceGroupProtocol {
+  name: String,
+  message_attributes: DeviceMessageAttributesMap,
+  stop_commands: Vec<ButtplugDeviceCommandMessageUnion>,
+  members: Vec<Arc<ButtplugDevice>>,
+  /// Maps composite feature indexes to (member index, member feature index),
+  /// per message type.
+  feature_map: HashMap<ButtplugDeviceMessageType, Vec<(usize, u32)>>,
+}
+
+impl DeviceGroupProtocol {
+  fn new_with_members(name: &str, members: Vec<Arc<ButtplugDevice>>) -> Self {
+    let mut message_attributes = DeviceMessageAttributesMap::new();
+    let mut feature_map = HashMap::new();
+    for message_type in [
+      ButtplugDeviceMessageType::VibrateCmd,
+      ButtplugDeviceMessageType::RotateCmd,
+      ButtplugDeviceMessageType::LinearCmd,
+    ]
+    .iter()
+    {
+      let mut features = vec![];
+      let mut step_count = vec![];
+      let mut max_duration = vec![];
+      let mut descriptors = vec![];
+      for (member_index, member) in members.iter().enumerate() {
+        if let Some(attrs) = member.message_attributes().get(message_type) {
+          for feature_index in 0..attrs.feature_count.unwrap_or(0) {
+            features.push((member_index, feature_index));
+          }
+          if let Some(member_descriptors) = attrs.features_for(*message_type) {
+            descriptors.extend(member_descriptors);
+          }
+          if let Some(steps) = &attrs.step_count {
+            step_count.extend(steps);
+          }
+          if let Some(durations) = &attrs.max_duration {
+            max_duration.extend(durations);
+          }
+        }
+      }
+      if features.is_empty() {
+        continue;
+      }
+      message_attributes.insert(
+        *message_type,
+        DeviceMessageAttributes {
+          feature_count: Some(features.len() as u32),
+          step_count: if step_count.is_empty() {
+            None
+          } else {
+            Some(step_count)
+          },
+          max_duration: if max_duration.is_empty() {
+            None
+          } else {
+            Some(max_duration)
+          },
+          features: if descriptors.len() == features.len() {
+            Some(descriptors)
+          } else {
+            None
+          },
+          ..Default::default()
+        },
+      );
+      feature_map.insert(*message_type, features);
+    }
+    message_attributes.insert(
+      ButtplugDeviceMessageType::StopDeviceCmd,
+      DeviceMessageAttributes::default(),
+    );
+    Self {
+      name: name.to_owned(),
+      message_attributes,
+      stop_commands: vec![],
+      members,
+      feature_map,
+    }
+  }
+
+  fn map_feature(
+    &self,
+    message_type: ButtplugDeviceMessageType,
+    index: u32,
+  ) -> Result<(usize, u32), ButtplugDeviceError> {
+    let features = self
+      .feature_map
+      .get(&message_type)
+      .ok_or(ButtplugDeviceError::MessageNotSupported(message_type))?;
+    features
+      .get(index as usize)
+      .cloned()
+      .ok_or(ButtplugDeviceError::DeviceFeatureIndexError(
+        features.len() as u32,
+        index,
+      ))
+  }
+
+  fn join_member_commands(fut_vec: Vec<ButtplugDeviceResultFuture>) -> ButtplugDeviceResultFuture {
+    Box::pin(async move {
+      // Run everything before checking results, so one failing member doesn't
+      // stop the others from getting their commands.
+      for result in future::join_all(fut_vec).await {
+        result?;
+      }
+      Ok(messages::Ok::default().into())
+    })
+  }
+}
+
+impl ButtplugProtocol for DeviceGroupProtocol {
+  fn new_protocol(
+    name: &str,
+    _message_attributes: DeviceMessageAttributesMap,
+  ) -> Box<dyn ButtplugProtocol> {
+    // Groups are only ever built from connected members, never from the device
+    // configuration, so all we can make here is an empty group.
+    Box::new(Self::new_with_members(name, vec![]))
+  }
+
+  fn handled_message_types() -> &'static [ButtplugDeviceMessageType] {
+    &[
+      ButtplugDeviceMessageType::VibrateCmd,
+      ButtplugDeviceMessageType::RotateCmd,
+      ButtplugDeviceMessageType::LinearCmd,
+    ]
+  }
+}
+
+impl ButtplugProtocolCommandHandler for DeviceGroupProtocol {
+  fn handle_stop_device_cmd(
+    &self,
+    _device: Arc<DeviceImpl>,
+    _message: messages::StopDeviceCmd,
+  ) -> ButtplugDeviceResultFuture {
+    let fut_vec = self
+      .members
+      .iter()
+      .map(|member| member.parse_message(StopDeviceCmd::new(0).into()))
+      .collect();
+    Self::join_member_commands(fut_vec)
+  }
+
+  fn handle_vibrate_cmd(
+    &self,
+    _device: Arc<DeviceImpl>,
+    message: messages::VibrateCmd,
+  ) -> ButtplugDeviceResultFuture {
+    let mut member_cmds: HashMap<usize, Vec<VibrateSubcommand>> = HashMap::new();
+    for cmd in message.speeds() {
+      match self.map_feature(ButtplugDeviceMessageType::VibrateCmd, cmd.index()) {
+        Ok((member, index)) => member_cmds
+          .entry(member)
+          .or_default()
+          .push(VibrateSubcommand::new(index, cmd.speed())),
+        Err(err) => return err.into(),
+      }
+    }
+    let fut_vec = member_cmds
+      .into_iter()
+      .map(|(member, cmds)| self.members[member].parse_message(VibrateCmd::new(0, cmds).into()))
+      .collect();
+    Self::join_member_commands(fut_vec)
+  }
+
+  fn handle_rotate_cmd(
+    &self,
+    _device: Arc<DeviceImpl>,
+    message: messages::RotateCmd,
+  ) -> ButtplugDeviceResultFuture {
+    let mut member_cmds: HashMap<usize, Vec<RotationSubcommand>> = HashMap::new();
+    for cmd in &message.rotations {
+      match self.map_feature(ButtplugDeviceMessageType::RotateCmd, cmd.index()) {
+        Ok((member, index)) => member_cmds
+          .entry(member)
+          .or_default()
+          .push(RotationSubcommand::new(index, cmd.speed(), cmd.clockwise())),
+        Err(err) => return err.into(),
+      }
+    }
+    let fut_vec = member_cmds
+      .into_iter()
+      .map(|(member, cmds)| self.members[member].parse_message(RotateCmd::new(0, cmds).into()))
+      .collect();
+    Self::join_member_commands(fut_vec)
+  }
+
+  fn handle_linear_cmd(
+    &self,
+    _device: Arc<DeviceImpl>,
+    message: messages::LinearCmd,
+  ) -> ButtplugDeviceResultFuture {
+    let mut member_cmds: HashMap<usize, Vec<VectorSubcommand>> = HashMap::new();
+    for cmd in message.vectors() {
+      match self.map_feature(ButtplugDeviceMessageType::LinearCmd, cmd.index) {
+        Ok((member, index)) => member_cmds
+          .entry(member)
+          .or_default()
+          .push(VectorSubcommand::new(index, cmd.duration, cmd.position)),
+        Err(err) => return err.into(),
+      }
+    }
+    let fut_vec = member_cmds
+      .into_iter()
+      .map(|(member, cmds)| self.members[member].parse_message(LinearCmd::new(0, cmds).into()))
+      .collect();
+    Self::join_member_commands(fut_vec)
+  }
+}
+
+#[cfg(all(test, feature = "server"))]
+mod test {
+  use super::DeviceGroup;
+  use crate::{
+    core::messages::{ButtplugDeviceMessageType, VibrateCmd, VibrateSubcommand},
+    device::{DeviceImplCommand, DeviceWriteCmd, Endpoint},
+    test::{check_test_recv_empty, check_test_recv_value, new_bluetoothle_test_device},
+    util::async_manager,
+  };
+  use std::{collections::HashMap, sync::Arc};
+
+  #[test]
+  pub fn test_device_group_feature_concatenation() {
+    async_manager::block_on(async move {
+      let (first, first_test_device) = new_bluetoothle_test_device("Massage Demo").await.unwrap();
+      let (second, second_test_device) = new_bluetoothle_test_device("Massage Demo").await.unwrap();
+      let first_address = first.address().to_owned();
+      let second_address = second.address().to_owned();
+      let mut members = HashMap::new();
+      members.insert(first_address.clone(), Arc::new(first));
+      members.insert(second_address.clone(), Arc::new(second));
+      let group = DeviceGroup::new("Group", &[first_address, second_address]);
+      let device = group.create_device(&members).unwrap();
+      assert_eq!(
+        device
+          .message_attributes()
+          .get(&ButtplugDeviceMessageType::VibrateCmd)
+          .unwrap()
+          .feature_count,
+        Some(4)
+      );
+      let command_receiver_first = first_test_device
+        .get_endpoint_receiver(&Endpoint::Tx)
+        .unwrap();
+      let command_receiver_second = second_test_device
+        .get_endpoint_receiver(&Endpoint::Tx)
+        .unwrap();
+      // Index 3 is the second motor of the second member.
+      device
+        .parse_message(VibrateCmd::new(0, vec![VibrateSubcommand::new(3, 0.5)]).into())
+        .await
+        .unwrap();
+      assert!(check_test_recv_empty(&command_receiver_first));
+      check_test_recv_value(
+        &command_receiver_second,
+        DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![0xF2, 64], false)),
+      );
+      assert!(device
+        .parse_message(VibrateCmd::new(0, vec![VibrateSubcommand::new(4, 0.5)]).into())
+        .await
+        .is_err());
+    });
+  }
+}
@@ -0,0 +1,42 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2022 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! What connected devices are, and how the server talks to them.
+//!
+//! Clients only see device names and the messages a device takes. When a
+//! device doesn't behave, diagnostics UIs usually want to show more than
+//! that, e.g. "this is the lovense protocol over Bluetooth, using tx and rx".
+//! [ButtplugServer::device_info][super::ButtplugServer::device_info] returns
+//! that as a [ServerDeviceInfo], which is a snapshot, and has no way of
+//! changing the device.
+
+use super::comm_managers::DeviceCommunicationTransport;
+use crate::device::Endpoint;
+#[cfg(feature = "serialize-json")]
+use serde::{Deserialize, Serialize};
+
+/// Read only details of a connected device.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serialize-json", derive(Serialize, Deserialize))]
+pub struct ServerDeviceInfo {
+  pub index: u32,
+  /// Device name, as sent to clients.
+  pub name: String,
+  pub address: String,
+  /// Device configuration protocol the device was matched to, e.g.
+  /// "lovense". None for devices that weren't created from device
+  /// configuration, like device groups.
+  pub protocol: Option<String>,
+  /// Name of the comm manager that found the device, e.g.
+  /// "BtlePlugCommunicationManager". None for devices no comm manager owns.
+  pub comm_manager: Option<String>,
+  /// Transport the comm manager talks to the device over. Only set along
+  /// with `comm_manager`.
+  pub transport: Option<DeviceCommunicationTransport>,
+  /// Endpoints the transport exposes for the device.
+  pub endpoints: Vec<Endpoint>,
+}
@@ -8,43 +8,138 @@
 //! Buttplug Device Manager, manages Device Subtype (Platform/Communication bus
 //! specific) Managers
 
+#[cfg(feature = "device-scripting")]
+use super::device_script::{DeviceScript, DeviceScriptError, DeviceScripts};
+#[cfg(feature = "server-emulator")]
+use super::emulator::{create_emulated_devices, EmulatorConfig};
 use super::{
   comm_managers::{
     DeviceCommunicationEvent, DeviceCommunicationManager, DeviceCommunicationManagerBuilder,
+    DeviceCommunicationManagerCapabilities,
   },
-  device_manager_event_loop::DeviceManagerEventLoop,
+  device_connection::DeviceConnectionEvent,
+  device_filter::DeviceFilter,
+  device_group::DeviceGroup,
+  device_info::ServerDeviceInfo,
+  device_manager_event_loop::{DeviceManagerEvent, DeviceManagerEventLoop},
+  diagnostics::ServerDiagnostic,
+  emergency_stop::EmergencyStop,
+  known_devices::{KnownDevice, KnownDeviceEvent, KnownDevices},
   ping_timer::PingTimer,
+  pressure_loop::{PressureLoopConfig, PressureLoops},
+  scheduled_stop::{ScheduledStop, ScheduledStopEvent},
   ButtplugServerError,
 };
 use crate::{
   core::{
-    errors::{ButtplugDeviceError, ButtplugMessageError, ButtplugUnknownError},
+    errors::{ButtplugDeviceError, ButtplugError, ButtplugMessageError, ButtplugUnknownError},
     messages::{
       self, ButtplugClientMessage, ButtplugDeviceCommandMessageUnion,
       ButtplugDeviceManagerMessageUnion, ButtplugDeviceMessage, ButtplugMessage,
-      ButtplugServerMessage, DeviceList, DeviceMessageInfo,
+      ButtplugServerMessage, DeviceAdded, DeviceList, DeviceMessageInfo, DeviceRemoved,
     },
   },
-  device::{configuration_manager::DeviceConfigurationManager, ButtplugDevice, protocol::ButtplugProtocol},
-  server::ButtplugServerResultFuture,
+  device::{
+    configuration_manager::{DeviceConfigurationManager, DeviceConfigurationOptions},
+    protocol::ButtplugProtocol,
+    ButtplugDevice,
+  },
+  server::{ButtplugServerResult, ButtplugServerResultFuture},
   test::{TestDeviceCommunicationManager, TestDeviceCommunicationManagerHelper},
-  util::async_manager,
+  util::{
+    async_manager, clock::Clock, future::ButtplugReadyOrBoxedFuture,
+    stream::convert_broadcast_receiver_to_stream,
+  },
+};
+use dashmap::{DashMap, DashSet};
+#[cfg(feature = "server-emulator")]
+use futures::{
+  channel::oneshot,
+  future::{FutureExt, Shared},
+};
+use futures::{
+  future::{self, BoxFuture},
+  Stream,
 };
-use dashmap::DashMap;
-use futures::future;
 use std::{
-  convert::TryFrom,
-  sync::{atomic::Ordering, Arc},
+  collections::{HashMap, HashSet},
+  sync::{atomic::Ordering, Arc, RwLock},
+  time::Duration,
 };
-use tokio::sync::{broadcast, mpsc};
+use tokio::sync::{broadcast, mpsc, Barrier};
+
+/// How indexes are picked for devices the server hasn't seen before in this
+/// session. Either way, a device that reconnects gets its previous index back
+/// as long as no other device holds it, since clients cache indexes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceIndexPolicy {
+  /// Indexes keep increasing for the whole session, so an index never refers
+  /// to more than one device.
+  Monotonic,
+  /// New devices get the lowest index not held by a connected device. Indexes
+  /// of disconnected devices may be handed out again, at which point the old
+  /// device loses its claim on them.
+  Recycle,
+}
+
+impl Default for DeviceIndexPolicy {
+  fn default() -> Self {
+    DeviceIndexPolicy::Monotonic
+  }
+}
+
+/// Which devices a StopAllDevices message from the client stops.
+///
+/// Devices can also be driven from outside the client protocol, e.g. by the
+/// OSC bridge. Scoping lets a client stop what it started without cutting off
+/// those. Engine control's StopAllDevices always stops everything.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopAllDevicesScope {
+  /// Stop every device the client can see.
+  AllDevices,
+  /// Only stop devices the client has sent commands to. Pressure loops and
+  /// script patterns driving other devices keep running.
+  CommandedDevices,
+}
+
+impl Default for StopAllDevicesScope {
+  fn default() -> Self {
+    StopAllDevicesScope::AllDevices
+  }
+}
 
 pub struct DeviceManager {
   // This uses a map to make sure we don't have 2 comm managers of the same type
   // register. Also means we can do lockless access since it's a Dashmap.
   comm_managers: Arc<DashMap<String, Box<dyn DeviceCommunicationManager>>>,
   devices: Arc<DashMap<u32, Arc<ButtplugDevice>>>,
-  device_event_sender: mpsc::Sender<DeviceCommunicationEvent>,
-  config: Arc<DeviceConfigurationManager>
+  device_event_sender: mpsc::Sender<DeviceManagerEvent>,
+  config: Arc<DeviceConfigurationManager>,
+  device_groups: Arc<DashMap<String, DeviceGroup>>,
+  device_filter: Arc<RwLock<DeviceFilter>>,
+  /// Indexes of devices the client hasn't been sent DeviceAdded for yet,
+  /// because they're still waiting out the debounce time. Left out of
+  /// device lists.
+  unannounced_devices: Arc<DashSet<u32>>,
+  output_sender: broadcast::Sender<ButtplugServerMessage>,
+  /// Maps device addresses to the name of the comm manager that found them,
+  /// so we know what to disconnect when a comm manager is removed.
+  device_owners: Arc<DashMap<String, String>>,
+  stop_all_devices_scope: StopAllDevicesScope,
+  /// Addresses of devices the client has sent commands to. Tracked by address
+  /// rather than index, as indexes can be handed to other devices.
+  client_commanded_devices: Arc<DashSet<String>>,
+  scheduled_stop: ScheduledStop,
+  emergency_stop: EmergencyStop,
+  pressure_loops: PressureLoops,
+  #[cfg(feature = "device-scripting")]
+  device_scripts: DeviceScripts,
+  known_devices: KnownDevices,
+  connection_event_sender: broadcast::Sender<DeviceConnectionEvent>,
+  /// Resolves once emulated devices are registered. Device lists wait on it,
+  /// so they always include every emulated device.
+  #[cfg(feature = "server-emulator")]
+  emulation_ready: Option<Shared<BoxFuture<'static, ()>>>,
 }
 
 unsafe impl Send for DeviceManager {}
@@ -55,37 +150,85 @@ impl DeviceManager {
   pub fn try_new(
     output_sender: broadcast::Sender<ButtplugServerMessage>,
     ping_timer: Arc<PingTimer>,
-    allow_raw_messages: bool,
-    device_config_json: &Option<String>,
-    user_device_config_json: &Option<String>,
+    config_options: &DeviceConfigurationOptions,
+    ignore_duty_cycle_limits: bool,
+    device_filter: DeviceFilter,
+    max_scanning_time: u64,
+    device_index_policy: DeviceIndexPolicy,
+    stop_all_devices_scope: StopAllDevicesScope,
+    device_debounce_time: u64,
+    max_concurrent_device_initializations: usize,
+    known_devices: Vec<KnownDevice>,
+    clock: Arc<dyn Clock>,
   ) -> Result<Self, ButtplugDeviceError> {
-    let config = Arc::new(DeviceConfigurationManager::new_with_options(
-      allow_raw_messages,
-      device_config_json,
-      user_device_config_json,
-    )?);
+    let mut config = DeviceConfigurationManager::new_with_options(config_options)?;
+    config.set_ignore_duty_cycle_limits(ignore_duty_cycle_limits);
+    let config = Arc::new(config);
     let devices = Arc::new(DashMap::new());
+    let comm_managers = Arc::new(DashMap::new());
+    let device_groups = Arc::new(DashMap::new());
+    let device_filter = Arc::new(RwLock::new(device_filter));
+    let device_owners = Arc::new(DashMap::new());
+    let unannounced_devices = Arc::new(DashSet::new());
+    let known_devices = KnownDevices::new(known_devices);
+    let (connection_event_sender, _) = broadcast::channel(256);
     let (device_event_sender, device_event_receiver) = mpsc::channel(256);
     let mut event_loop = DeviceManagerEventLoop::new(
       config.clone(),
-      output_sender,
+      output_sender.clone(),
       devices.clone(),
       ping_timer,
       device_event_receiver,
+      device_groups.clone(),
+      device_filter.clone(),
+      device_owners.clone(),
+      comm_managers.clone(),
+      max_scanning_time,
+      device_index_policy,
+      device_debounce_time,
+      unannounced_devices.clone(),
+      max_concurrent_device_initializations,
+      known_devices.clone(),
+      connection_event_sender.clone(),
+      clock,
     );
-    async_manager::spawn(async move {
+    async_manager::spawn_named("device manager event loop", async move {
       event_loop.run().await;
     })
     .unwrap();
+    let pressure_loops = PressureLoops::new(devices.clone());
+    #[cfg(feature = "device-scripting")]
+    let device_scripts = DeviceScripts::new(devices.clone());
+    #[allow(unused_mut)]
+    let mut runners = vec![pressure_loops.runners()];
+    #[cfg(feature = "device-scripting")]
+    runners.push(device_scripts.runners());
+    let emergency_stop = EmergencyStop::new(&devices, &runners);
     Ok(Self {
       device_event_sender,
+      comm_managers,
+      config,
+      device_groups,
+      device_filter,
+      unannounced_devices,
+      output_sender,
+      device_owners,
+      stop_all_devices_scope,
+      client_commanded_devices: Arc::new(DashSet::new()),
+      scheduled_stop: ScheduledStop::new(devices.clone()),
+      emergency_stop,
+      pressure_loops,
+      #[cfg(feature = "device-scripting")]
+      device_scripts,
+      known_devices,
+      connection_event_sender,
+      #[cfg(feature = "server-emulator")]
+      emulation_ready: None,
       devices,
-      comm_managers: Arc::new(DashMap::new()),
-      config
     })
   }
 
-  fn start_scanning(&self) -> ButtplugServerResultFuture {
+  pub fn start_scanning(&self) -> ButtplugServerResultFuture {
     if self.comm_managers.is_empty() {
       ButtplugUnknownError::NoDeviceCommManagers.into()
     } else {
@@ -97,46 +240,51 @@ impl DeviceManager {
             return Err(ButtplugDeviceError::DeviceScanningAlreadyStarted.into());
           }
         }
-        let fut_vec: Vec<_> = mgrs
-          .iter()
-          .map(|guard| guard.value().start_scanning())
-          .collect();
-        // TODO If start_scanning fails anywhere, this will ignore it. We should maybe at least log?
-        future::join_all(fut_vec).await;
-        debug!("All managers started, sending ScanningStarted (and invoking ScanningFinished hack) signal to event loop.");
-        // HACK: In case everything somehow exited between the time all of our
-        // futures resolved and when we updated the event loop, act like we're a
-        // device comm manager and send a ScanningFinished message. This will
-        // cause the finish check to run just in case, so we don't get stuck.
-        //
-        // Ideally, this should be some sort of state machine, but for now, we
-        // can deal with this.
-        //
-        // At this point, it doesn't really matter what we return, only way that
-        // event loop could shut down is if the whole system is shutting down.
-        // So complain if our sends error out, but don't worry about returning
-        // an error.
+        // Tell the event loop which managers to wait on before starting any of
+        // them, so a manager that finishes immediately can't report back
+        // before the loop knows a scan is running.
+        let names: Vec<String> = mgrs.iter().map(|mgr| mgr.key().clone()).collect();
         if sender
-          .send(DeviceCommunicationEvent::ScanningStarted)
+          .send(DeviceManagerEvent::ScanningStarted(names))
           .await
           .is_err()
-          || sender
-            .send(DeviceCommunicationEvent::ScanningFinished)
-            .await
-            .is_err()
         {
           debug!("Device manager event loop shut down, cannot send ScanningStarted");
+          return Ok(messages::Ok::default().into());
+        }
+        let fut_vec: Vec<_> = mgrs
+          .iter()
+          .map(|guard| {
+            let name = guard.key().clone();
+            let fut = guard.value().start_scanning();
+            async move { (name, fut.await) }
+          })
+          .collect();
+        // A manager that fails to start won't ever send ScanningFinished, so
+        // report the failure for it.
+        for (name, result) in future::join_all(fut_vec).await {
+          if let Err(err) = result {
+            error!("Comm manager {} failed to start scanning: {}", name, err);
+            let event = DeviceManagerEvent::CommManagerEvent {
+              name,
+              event: DeviceCommunicationEvent::ScanningFailed(err.to_string()),
+            };
+            if sender.send(event).await.is_err() {
+              debug!("Device manager event loop shut down, cannot send ScanningFailed");
+            }
+          }
         }
         Ok(messages::Ok::default().into())
       })
     }
   }
 
-  fn stop_scanning(&self) -> ButtplugServerResultFuture {
+  pub fn stop_scanning(&self) -> ButtplugServerResultFuture {
     if self.comm_managers.is_empty() {
       ButtplugUnknownError::NoDeviceCommManagers.into()
     } else {
       let mgrs = self.comm_managers.clone();
+      let sender = self.device_event_sender.clone();
       Box::pin(async move {
         let mut scanning_stopped = true;
         for mgr in mgrs.iter() {
@@ -149,27 +297,88 @@ impl DeviceManager {
         if scanning_stopped {
           return Err(ButtplugDeviceError::DeviceScanningAlreadyStopped.into());
         }
-
+        // Starts the clock on managers that never report back after being
+        // stopped.
+        if sender
+          .send(DeviceManagerEvent::ScanningStopRequested)
+          .await
+          .is_err()
+        {
+          debug!("Device manager event loop shut down, cannot send ScanningStopRequested");
+        }
         let fut_vec: Vec<_> = mgrs
           .iter()
-          .map(|guard| guard.value().stop_scanning())
+          .map(|guard| {
+            let name = guard.key().clone();
+            let fut = guard.value().stop_scanning();
+            async move { (name, fut.await) }
+          })
           .collect();
-        // TODO If stop_scanning fails anywhere, this will ignore it. We should maybe at least log?
-        future::join_all(fut_vec).await;
+        for (name, result) in future::join_all(fut_vec).await {
+          if let Err(err) = result {
+            error!("Comm manager {} failed to stop scanning: {}", name, err);
+            let event = DeviceManagerEvent::CommManagerEvent {
+              name,
+              event: DeviceCommunicationEvent::ScanningFailed(err.to_string()),
+            };
+            if sender.send(event).await.is_err() {
+              debug!("Device manager event loop shut down, cannot send ScanningFailed");
+            }
+          }
+        }
         Ok(messages::Ok::default().into())
       })
     }
   }
 
-  fn stop_all_devices(&self) -> ButtplugServerResultFuture {
+  pub fn stop_all_devices(&self) -> ButtplugServerResultFuture {
+    self.stop_devices(None)
+  }
+
+  /// Handles StopAllDevices from the client, following the
+  /// [StopAllDevicesScope] the server was created with.
+  pub fn stop_client_devices(&self) -> ButtplugServerResultFuture {
+    match self.stop_all_devices_scope {
+      StopAllDevicesScope::AllDevices => self.stop_devices(None),
+      StopAllDevicesScope::CommandedDevices => {
+        self.stop_devices(Some(self.client_commanded_devices.clone()))
+      }
+    }
+  }
+
+  /// Stops all devices visible through the device filter, or only those with
+  /// addresses in `only`, if given.
+  fn stop_devices(&self, only: Option<Arc<DashSet<String>>>) -> ButtplugServerResultFuture {
+    // Pressure loops and script patterns would otherwise turn devices right
+    // back on. Ones driving devices outside the scope keep running.
+    match &only {
+      None => {
+        self.pressure_loops.stop_all();
+        #[cfg(feature = "device-scripting")]
+        self.device_scripts.stop_all_patterns();
+      }
+      Some(only) => {
+        self.pressure_loops.stop_for_devices(only);
+        #[cfg(feature = "device-scripting")]
+        self.device_scripts.stop_patterns_for_devices(only);
+      }
+    }
     let device_map = self.devices.clone();
+    let device_filter = self.device_filter.clone();
     // TODO This could use some error reporting.
     Box::pin(async move {
       let fut_vec: Vec<_> = device_map
         .iter()
+        .filter(|dev| device_filter.read().unwrap().allows(dev.value()))
+        .filter(|dev| {
+          only
+            .as_ref()
+            .map_or(true, |only| only.contains(dev.value().address()))
+        })
         .map(|dev| {
-          let device = dev.value();
-          device.parse_message(messages::StopDeviceCmd::new(1).into())
+          dev
+            .value()
+            .parse_message_instrumented(*dev.key(), messages::StopDeviceCmd::new(1).into())
         })
         .collect();
       future::join_all(fut_vec).await;
@@ -177,112 +386,660 @@ impl DeviceManager {
     })
   }
 
+  /// Arms a hard stop of all devices `delay` from now, replacing any stop
+  /// already armed. A warning event is sent `warning` before the stop, unless
+  /// `warning` is zero.
+  pub fn schedule_stop(&self, delay: Duration, warning: Duration) {
+    self.scheduled_stop.schedule(delay, warning);
+  }
+
+  /// Disarms a pending scheduled stop, or lets devices take commands again
+  /// after one has fired.
+  pub fn cancel_scheduled_stop(&self) {
+    self.scheduled_stop.cancel();
+  }
+
+  /// Time left until the scheduled stop, if one is armed.
+  pub fn scheduled_stop_remaining(&self) -> Option<Duration> {
+    self.scheduled_stop.remaining()
+  }
+
+  /// True if a scheduled stop is armed or has fired and not been cancelled.
+  pub fn scheduled_stop_active(&self) -> bool {
+    self.scheduled_stop.active()
+  }
+
+  pub fn scheduled_stop_event_stream(&self) -> impl Stream<Item = ScheduledStopEvent> {
+    self.scheduled_stop.event_stream()
+  }
+
+  /// Handle for stopping all devices outside of the client message path.
+  pub fn emergency_stop(&self) -> EmergencyStop {
+    self.emergency_stop.clone()
+  }
+
+  pub fn start_pressure_loop(
+    &self,
+    config: PressureLoopConfig,
+  ) -> BoxFuture<'static, Result<u32, ButtplugError>> {
+    self.pressure_loops.start(config)
+  }
+
+  pub fn stop_pressure_loop(&self, id: u32) -> bool {
+    self.pressure_loops.stop(id)
+  }
+
+  #[cfg(feature = "device-scripting")]
+  pub fn add_device_script(&self, script: DeviceScript) -> Result<(), DeviceScriptError> {
+    self.device_scripts.add(script)
+  }
+
+  #[cfg(feature = "device-scripting")]
+  pub fn remove_device_script(&self, name: &str) -> bool {
+    self.device_scripts.remove(name)
+  }
+
+  #[cfg(feature = "device-scripting")]
+  pub fn start_script_pattern(
+    &self,
+    device_index: u32,
+    script_name: &str,
+  ) -> Result<u32, DeviceScriptError> {
+    self.device_scripts.start_pattern(device_index, script_name)
+  }
+
+  #[cfg(feature = "device-scripting")]
+  pub fn stop_script_pattern(&self, id: u32) -> bool {
+    self.device_scripts.stop_pattern(id)
+  }
+
+  /// Devices that have connected before, most recently connected first.
+  pub fn known_devices(&self) -> Vec<KnownDevice> {
+    self.known_devices.list()
+  }
+
+  /// Bluetooth devices known to be bonded, see
+  /// [DeviceConfigurationManager::bonded_devices].
+  pub fn bonded_devices(&self) -> Vec<String> {
+    self.config.bonded_devices()
+  }
+
+  /// Forgets a known device, so it no longer gets in range events. Returns
+  /// false if no known device had the identity key.
+  pub fn forget_known_device(&self, identity_key: &str) -> bool {
+    self.known_devices.forget(identity_key)
+  }
+
+  pub fn known_device_event_stream(&self) -> impl Stream<Item = KnownDeviceEvent> {
+    self.known_devices.event_stream()
+  }
+
+  pub fn device_connection_event_stream(&self) -> impl Stream<Item = DeviceConnectionEvent> {
+    convert_broadcast_receiver_to_stream(self.connection_event_sender.subscribe())
+  }
+
+  /// Disconnects a device, as if it had gone away on its own. The device will
+  /// be removed via the usual device event path once the disconnect is
+  /// processed.
+  pub fn disconnect_device(&self, device_index: u32) -> ButtplugServerResultFuture {
+    match self
+      .devices
+      .get(&device_index)
+      .map(|device| device.value().clone())
+    {
+      Some(device) => {
+        let fut = device.disconnect();
+        Box::pin(async move {
+          fut.await?;
+          Ok(messages::Ok::default().into())
+        })
+      }
+      None => ButtplugDeviceError::DeviceNotAvailable(device_index).into(),
+    }
+  }
+
+  /// Turns a device off, if its protocol has a command for it.
+  pub fn power_off_device(&self, device_index: u32) -> ButtplugServerResultFuture {
+    match self.device(device_index) {
+      Some(device) => {
+        let fut = device.power_off();
+        Box::pin(async move {
+          fut.await?;
+          Ok(messages::Ok::default().into())
+        })
+      }
+      None => ButtplugDeviceError::DeviceNotAvailable(device_index).into(),
+    }
+  }
+
+  /// Turns off every device visible through the device filter that can be
+  /// turned off, e.g. at the end of a session. Devices that can't be are
+  /// stopped instead.
+  pub fn power_off_all_devices(&self) -> ButtplugServerResultFuture {
+    let device_map = self.devices.clone();
+    let device_filter = self.device_filter.clone();
+    Box::pin(async move {
+      let fut_vec: Vec<_> = device_map
+        .iter()
+        .filter(|dev| device_filter.read().unwrap().allows(dev.value()))
+        .map(|dev| {
+          let device = dev.value();
+          if device.supports_power_off() {
+            device.power_off()
+          } else {
+            device.parse_message_instrumented(*dev.key(), messages::StopDeviceCmd::new(1).into())
+          }
+        })
+        .collect();
+      for result in future::join_all(fut_vec).await {
+        if let Err(err) = result {
+          error!("Error powering off device: {}", err);
+        }
+      }
+      Ok(messages::Ok::default().into())
+    })
+  }
+
+  /// Sends a set of commands, each to a different device, so they go out as
+  /// close together as we can manage.
+  ///
+  /// Every command is checked (device lookup, device scripts) before any of
+  /// them is sent, so a set either fails as a whole or goes out as a whole.
+  /// Each command then runs on its own task, and all the tasks wait on a
+  /// barrier before handing their command to the device, so no device's
+  /// write is held up behind another's. This is best effort: the barrier only
+  /// lines up when the tasks start. Each command still goes through its
+  /// device's rate limiting, soft start and command manager after that, and
+  /// the transports decide when writes actually reach the devices.
+  ///
+  /// This is for embedders, not clients, so the devices aren't marked as
+  /// client commanded.
+  ///
+  /// Resolves once every command is done, with the first error in command
+  /// order if any failed.
+  pub fn send_synchronized(
+    &self,
+    commands: Vec<ButtplugDeviceCommandMessageUnion>,
+  ) -> ButtplugServerResultFuture {
+    if let Err(err) = self.scheduled_stop.check_expired() {
+      return Box::pin(future::ready(Err(err.into())));
+    }
+    if commands.is_empty() {
+      return ButtplugMessageError::InvalidMessageContents(
+        "Synchronized command set is empty".to_owned(),
+      )
+      .into();
+    }
+    let mut sends = Vec::with_capacity(commands.len());
+    let mut device_indexes = HashSet::new();
+    for command in commands {
+      let device_index = command.device_index();
+      // Commands to the same device would race each other, so there's no
+      // order they could be sent in that means anything.
+      if !device_indexes.insert(device_index) {
+        return ButtplugMessageError::InvalidMessageContents(format!(
+          "Synchronized command set has more than one command for device {}",
+          device_index
+        ))
+        .into();
+      }
+      let device = match self.device(device_index) {
+        Some(device) => device,
+        None => return ButtplugDeviceError::DeviceNotAvailable(device_index).into(),
+      };
+      #[cfg(feature = "device-scripting")]
+      let command = self.device_scripts.transform(&device, command);
+      #[cfg(not(feature = "device-scripting"))]
+      let command = future::ready(Ok(command));
+      sends.push((device_index, device, command));
+    }
+    Box::pin(async move {
+      // Every transform has to succeed before anything is sent.
+      let mut transformed = Vec::with_capacity(sends.len());
+      for (device_index, device, command) in sends {
+        transformed.push((device_index, device, command.await?));
+      }
+      let barrier = Arc::new(Barrier::new(transformed.len()));
+      let handles: Vec<_> = transformed
+        .into_iter()
+        .map(|(device_index, device, command)| {
+          let barrier = barrier.clone();
+          async_manager::spawn_with_handle(async move {
+            barrier.wait().await;
+            device
+              .parse_message_instrumented(device_index, command)
+              .await
+          })
+          .unwrap()
+        })
+        .collect();
+      let mut first_error = None;
+      for result in future::join_all(handles).await {
+        if let Err(err) = result {
+          error!("Error sending synchronized command: {}", err);
+          first_error.get_or_insert(err);
+        }
+      }
+      match first_error {
+        Some(err) => Err(err),
+        None => Ok(messages::Ok::default().into()),
+      }
+    })
+  }
+
+  /// Returns the device at an index, if it exists and is visible through the
+  /// device filter.
+  ///
+  /// The device map is sharded, and this only holds a shard's read guard long
+  /// enough to clone the device handle, so commands to different devices
+  /// never wait on each other here.
+  pub fn device(&self, device_index: u32) -> Option<Arc<ButtplugDevice>> {
+    let device = self
+      .devices
+      .get(&device_index)
+      .map(|device| device.value().clone())?;
+    if self.device_filter.read().unwrap().allows(&device) {
+      Some(device)
+    } else {
+      None
+    }
+  }
+
+  /// Indexes of all devices visible through the device filter.
+  pub fn device_indexes(&self) -> Vec<u32> {
+    let device_filter = self.device_filter.read().unwrap();
+    self
+      .devices
+      .iter()
+      .filter(|device| device_filter.allows(device.value()))
+      .map(|device| *device.key())
+      .collect()
+  }
+
+  /// Details of a device visible through the device filter, if it's
+  /// connected.
+  pub fn device_info(&self, device_index: u32) -> Option<ServerDeviceInfo> {
+    let device = self.device(device_index)?;
+    let comm_manager = self
+      .device_owners
+      .get(device.address())
+      .map(|owner| owner.value().clone());
+    // Capabilities are worked out when asked for, but the transport never
+    // changes, so this is cheap.
+    let transport = comm_manager
+      .as_ref()
+      .and_then(|name| self.comm_managers.get(name))
+      .map(|mgr| mgr.value().capabilities().transport);
+    Some(ServerDeviceInfo {
+      index: device_index,
+      name: device.name(),
+      address: device.address().to_owned(),
+      protocol: device.protocol_identifier().map(str::to_owned),
+      comm_manager,
+      transport,
+      endpoints: device.endpoints(),
+    })
+  }
+
+  /// Details of every device visible through the device filter, in index
+  /// order.
+  pub fn device_info_list(&self) -> Vec<ServerDeviceInfo> {
+    let mut indexes = self.device_indexes();
+    indexes.sort_unstable();
+    indexes
+      .into_iter()
+      .filter_map(|index| self.device_info(index))
+      .collect()
+  }
+
   fn parse_device_message(
     &self,
     device_msg: ButtplugDeviceCommandMessageUnion,
-  ) -> ButtplugServerResultFuture {
-    match self.devices.get(&device_msg.device_index()) {
+  ) -> ButtplugReadyOrBoxedFuture<ButtplugServerResult> {
+    let device_index = device_msg.device_index();
+    if let Err(err) = self.scheduled_stop.check_expired() {
+      return ButtplugReadyOrBoxedFuture::ready(Err(err.into()));
+    }
+    match self.device(device_index) {
       Some(device) => {
-        let fut = device.parse_message(device_msg);
-        // Create a future to run the message through the device, then handle adding the id to the result.
-        Box::pin(async move { fut.await })
+        self
+          .client_commanded_devices
+          .insert(device.address().to_owned());
+        #[cfg(feature = "device-scripting")]
+        let send = self.device_scripts.send(device_index, device, device_msg);
+        #[cfg(not(feature = "device-scripting"))]
+        let send = device.parse_message_instrumented(device_index, device_msg);
+        send.into()
       }
-      None => ButtplugDeviceError::DeviceNotAvailable(device_msg.device_index()).into(),
+      None => ButtplugReadyOrBoxedFuture::ready(Err(
+        ButtplugDeviceError::DeviceNotAvailable(device_index).into(),
+      )),
     }
   }
 
   fn parse_device_manager_message(
     &self,
     manager_msg: ButtplugDeviceManagerMessageUnion,
-  ) -> ButtplugServerResultFuture {
+  ) -> ButtplugReadyOrBoxedFuture<ButtplugServerResult> {
     match manager_msg {
       ButtplugDeviceManagerMessageUnion::RequestDeviceList(msg) => {
-        let devices = self
-          .devices
-          .iter()
-          .map(|device| {
-            let dev = device.value();
-            DeviceMessageInfo::new(*device.key(), &dev.name(), dev.message_attributes())
-          })
-          .collect();
-        let mut device_list = DeviceList::new(devices);
-        device_list.set_id(msg.id());
-        Box::pin(future::ready(Ok(device_list.into())))
+        #[cfg(feature = "server-emulator")]
+        {
+          if let Some(ready) = self.emulation_ready.clone() {
+            let devices = self.devices.clone();
+            let device_filter = self.device_filter.clone();
+            let unannounced_devices = self.unannounced_devices.clone();
+            return ButtplugReadyOrBoxedFuture::boxed(async move {
+              ready.await;
+              let device_list = device_list(
+                &devices,
+                &device_filter.read().unwrap(),
+                &unannounced_devices,
+                msg.id(),
+              );
+              Ok(device_list.into())
+            });
+          }
+        }
+        let device_list = device_list(
+          &self.devices,
+          &self.device_filter.read().unwrap(),
+          &self.unannounced_devices,
+          msg.id(),
+        );
+        ButtplugReadyOrBoxedFuture::ready(Ok(device_list.into()))
       }
-      ButtplugDeviceManagerMessageUnion::StopAllDevices(_) => self.stop_all_devices(),
-      ButtplugDeviceManagerMessageUnion::StartScanning(_) => self.start_scanning(),
-      ButtplugDeviceManagerMessageUnion::StopScanning(_) => self.stop_scanning(),
+      ButtplugDeviceManagerMessageUnion::StopAllDevices(_) => self.stop_client_devices().into(),
+      ButtplugDeviceManagerMessageUnion::StartScanning(_) => self.start_scanning().into(),
+      ButtplugDeviceManagerMessageUnion::StopScanning(_) => self.stop_scanning().into(),
     }
   }
 
-  pub fn parse_message(&self, msg: ButtplugClientMessage) -> ButtplugServerResultFuture {
-    // If this is a device command message, just route it directly to the
-    // device.
-    match ButtplugDeviceCommandMessageUnion::try_from(msg.clone()) {
-      Ok(device_msg) => self.parse_device_message(device_msg),
-      Err(_) => match ButtplugDeviceManagerMessageUnion::try_from(msg.clone()) {
-        Ok(manager_msg) => self.parse_device_manager_message(manager_msg),
-        Err(_) => ButtplugMessageError::UnexpectedMessageType(format!("{:?}", msg)).into(),
-      },
-    }
+  /// Routes a device or device manager message. Lookup failures and device
+  /// lists are answered without allocating a future.
+  ///
+  /// Messages are moved all the way through to the device, so large payloads
+  /// (like RawWriteCmd data) are never copied.
+  pub fn parse_message(
+    &self,
+    msg: ButtplugClientMessage,
+  ) -> ButtplugReadyOrBoxedFuture<ButtplugServerResult> {
+    // Each message is moved straight into the union it belongs to, so large
+    // payloads aren't copied on the way.
+    let device_msg: ButtplugDeviceCommandMessageUnion = match msg {
+      ButtplugClientMessage::RequestDeviceList(msg) => {
+        return self.parse_device_manager_message(msg.into())
+      }
+      ButtplugClientMessage::StopAllDevices(msg) => {
+        return self.parse_device_manager_message(msg.into())
+      }
+      ButtplugClientMessage::StartScanning(msg) => {
+        return self.parse_device_manager_message(msg.into())
+      }
+      ButtplugClientMessage::StopScanning(msg) => {
+        return self.parse_device_manager_message(msg.into())
+      }
+      ButtplugClientMessage::VibrateCmd(msg) => msg.into(),
+      ButtplugClientMessage::LinearCmd(msg) => msg.into(),
+      ButtplugClientMessage::RotateCmd(msg) => msg.into(),
+      ButtplugClientMessage::RawWriteCmd(msg) => msg.into(),
+      ButtplugClientMessage::RawReadCmd(msg) => msg.into(),
+      ButtplugClientMessage::StopDeviceCmd(msg) => msg.into(),
+      ButtplugClientMessage::RawSubscribeCmd(msg) => msg.into(),
+      ButtplugClientMessage::RawUnsubscribeCmd(msg) => msg.into(),
+      ButtplugClientMessage::BatteryLevelCmd(msg) => msg.into(),
+      ButtplugClientMessage::RSSILevelCmd(msg) => msg.into(),
+      ButtplugClientMessage::SensorSubscribeCmd(msg) => msg.into(),
+      ButtplugClientMessage::SensorUnsubscribeCmd(msg) => msg.into(),
+      ButtplugClientMessage::SingleMotorVibrateCmd(msg) => msg.into(),
+      ButtplugClientMessage::FleshlightLaunchFW12Cmd(msg) => msg.into(),
+      ButtplugClientMessage::KiirooCmd(msg) => msg.into(),
+      ButtplugClientMessage::VorzeA10CycloneCmd(msg) => msg.into(),
+      ButtplugClientMessage::Ping(_)
+      | ButtplugClientMessage::RequestLog(_)
+      | ButtplugClientMessage::RequestServerInfo(_)
+      | ButtplugClientMessage::LovenseCmd(_) => {
+        return ButtplugReadyOrBoxedFuture::ready(Err(
+          ButtplugMessageError::UnexpectedMessageType(format!("{:?}", msg)).into(),
+        ))
+      }
+    };
+    self.parse_device_message(device_msg)
+  }
+
+  /// Creates the emulated devices in the background, and registers them in
+  /// order once they're all connected. See [emulator][super::emulator].
+  #[cfg(feature = "server-emulator")]
+  pub fn start_emulation(&mut self, config: EmulatorConfig) {
+    let (ready_sender, ready_receiver) = oneshot::channel();
+    let config_manager = self.config.clone();
+    let event_sender = self.device_event_sender.clone();
+    async_manager::spawn_named("device emulation", async move {
+      let devices = create_emulated_devices(config, config_manager).await;
+      info!("Registering {} emulated devices.", devices.len());
+      if event_sender
+        .send(DeviceManagerEvent::EmulatedDevicesConnected(
+          devices,
+          ready_sender,
+        ))
+        .await
+        .is_err()
+      {
+        error!("Device manager event loop shut down, dropping emulated devices.");
+      }
+    })
+    .unwrap();
+    // If the event loop is gone, there's nothing to wait for.
+    let ready: BoxFuture<'static, ()> = Box::pin(ready_receiver.map(|_| ()));
+    self.emulation_ready = Some(ready.shared());
   }
 
-  pub fn add_comm_manager<T>(&self, mut builder: T) -> Result<(), ButtplugServerError> where T: DeviceCommunicationManagerBuilder {
-    builder.set_event_sender(self.device_event_sender.clone());
+  pub fn add_comm_manager<T>(&self, mut builder: T) -> Result<(), ButtplugServerError>
+  where
+    T: DeviceCommunicationManagerBuilder,
+  {
+    let (sender, receiver) = mpsc::channel(256);
+    builder.set_event_sender(sender);
     let mgr = builder.finish();
+    self.insert_comm_manager(mgr, receiver)
+  }
+
+  fn insert_comm_manager(
+    &self,
+    mgr: Box<dyn DeviceCommunicationManager>,
+    receiver: mpsc::Receiver<DeviceCommunicationEvent>,
+  ) -> Result<(), ButtplugServerError> {
     if self.comm_managers.contains_key(mgr.name()) {
       return Err(ButtplugServerError::DeviceManagerTypeAlreadyAdded(
         mgr.name().to_owned(),
       ));
     }
-    let status = mgr.scanning_status();
+    let name = mgr.name().to_owned();
+    self.comm_managers.insert(name.clone(), mgr);
+    self.forward_comm_manager_events(name, receiver);
+    Ok(())
+  }
+
+  /// Each comm manager gets its own event channel, which we relay into the
+  /// event loop. This lets us track which manager found which device, and
+  /// ignore anything a manager finds after it has been removed.
+  fn forward_comm_manager_events(
+    &self,
+    name: String,
+    mut receiver: mpsc::Receiver<DeviceCommunicationEvent>,
+  ) {
+    let sender = self.device_event_sender.clone();
+    let comm_managers = self.comm_managers.clone();
+    let device_owners = self.device_owners.clone();
+    async_manager::spawn_named("comm manager event forwarder", async move {
+      while let Some(event) = receiver.recv().await {
+        if let DeviceCommunicationEvent::DeviceFound { address, .. } = &event {
+          if !comm_managers.contains_key(&name) {
+            debug!(
+              "Comm manager {} has been removed, ignoring found device {}.",
+              name, address
+            );
+            continue;
+          }
+          device_owners.insert(address.clone(), name.clone());
+        }
+        let event = DeviceManagerEvent::CommManagerEvent {
+          name: name.clone(),
+          event,
+        };
+        if sender.send(event).await.is_err() {
+          break;
+        }
+      }
+    })
+    .unwrap();
+  }
+
+  /// Removes a comm manager. If the manager is scanning, scanning will be
+  /// stopped, and all devices the manager found will be disconnected. Devices
+  /// that were found before removal but are still in the process of
+  /// connecting may still show up.
+  pub fn remove_comm_manager(&self, name: &str) -> Result<(), ButtplugServerError> {
+    let (name, mgr) = self
+      .comm_managers
+      .remove(name)
+      .ok_or_else(|| ButtplugServerError::DeviceManagerTypeNotFound(name.to_owned()))?;
     let sender = self.device_event_sender.clone();
-    // TODO This could run out of order and possibly cause weird scanning finished bugs?
     async_manager::spawn(async move {
-      sender
-        .send(DeviceCommunicationEvent::DeviceManagerAdded(status))
+      let status = mgr.scanning_status();
+      if status.load(Ordering::SeqCst) {
+        if let Err(err) = mgr.stop_scanning().await {
+          error!(
+            "Error stopping scanning on removed comm manager {}: {}",
+            name, err
+          );
+        }
+      }
+      if sender
+        .send(DeviceManagerEvent::CommManagerRemoved(name))
         .await
-        .unwrap();
+        .is_err()
+      {
+        debug!("Device manager event loop shut down, cannot send CommManagerRemoved");
+      }
     })
     .unwrap();
+    Ok(())
+  }
+
+  /// Warnings about the device configuration and comm managers, see
+  /// [ServerDiagnostic].
+  pub fn diagnostics(&self) -> Vec<ServerDiagnostic> {
+    let mut diagnostics: Vec<ServerDiagnostic> = self
+      .config
+      .unused_user_config_protocols()
+      .into_iter()
+      .map(ServerDiagnostic::UnknownUserProtocol)
+      .collect();
+    if self.comm_managers.is_empty() {
+      if self.config.allow_raw_messages() {
+        diagnostics.push(ServerDiagnostic::RawMessagesWithoutCommManagers);
+      }
+      diagnostics.push(ServerDiagnostic::NoCommManagers);
+    }
+    diagnostics
+  }
+
+  /// Capabilities of all currently added comm managers, keyed by manager
+  /// name.
+  pub fn comm_manager_capabilities(
+    &self,
+  ) -> HashMap<String, DeviceCommunicationManagerCapabilities> {
     self
       .comm_managers
-      .insert(mgr.name().to_owned(), mgr);
+      .iter()
+      .map(|mgr| (mgr.key().clone(), mgr.value().capabilities()))
+      .collect()
+  }
+
+  /// Registers a device group. Devices connecting with any of the member
+  /// addresses will be exposed as part of the group device instead of on
+  /// their own. Devices that are already connected are not affected until
+  /// they reconnect.
+  pub fn add_device_group(
+    &self,
+    group_name: &str,
+    member_addresses: &[String],
+  ) -> Result<(), ButtplugServerError> {
+    if self.device_groups.contains_key(group_name) {
+      return Err(ButtplugServerError::DeviceGroupAlreadyExists(
+        group_name.to_owned(),
+      ));
+    }
+    for group in self.device_groups.iter() {
+      if let Some(address) = member_addresses
+        .iter()
+        .find(|address| group.value().contains(address))
+      {
+        return Err(ButtplugServerError::DeviceGroupMemberConflict(
+          address.clone(),
+          group.key().clone(),
+        ));
+      }
+    }
+    self.device_groups.insert(
+      group_name.to_owned(),
+      DeviceGroup::new(group_name, member_addresses),
+    );
     Ok(())
   }
 
+  pub fn set_device_filter(&self, filter: DeviceFilter) {
+    // Hold the write lock while we diff, so the event loop can't sneak a
+    // DeviceAdded/DeviceRemoved out under the old filter.
+    let mut device_filter = self.device_filter.write().unwrap();
+    for device in self.devices.iter() {
+      let was_visible = device_filter.allows(device.value());
+      let is_visible = filter.allows(device.value());
+      let msg: Option<ButtplugServerMessage> = if is_visible && !was_visible {
+        Some(
+          DeviceAdded::new(
+            *device.key(),
+            &device.value().name(),
+            &device.value().message_attributes(),
+          )
+          .into(),
+        )
+      } else if was_visible && !is_visible {
+        Some(DeviceRemoved::new(*device.key()).into())
+      } else {
+        None
+      };
+      if let Some(msg) = msg {
+        if self.output_sender.send(msg).is_err() {
+          debug!("Server not currently available, dropping device filter update event.");
+        }
+      }
+    }
+    *device_filter = filter;
+  }
+
   pub fn add_test_comm_manager(
     &self,
   ) -> Result<TestDeviceCommunicationManagerHelper, ButtplugServerError> {
-    let mgr = TestDeviceCommunicationManager::new(self.device_event_sender.clone());
-    if self.comm_managers.contains_key(mgr.name()) {
-      return Err(ButtplugServerError::DeviceManagerTypeAlreadyAdded(
-        mgr.name().to_owned(),
-      ));
-    }
-    let status = mgr.scanning_status();
-    let sender = self.device_event_sender.clone();
-    // TODO This could run out of order and possibly cause weird scanning finished bugs?
-    async_manager::spawn(async move {
-      sender
-        .send(DeviceCommunicationEvent::DeviceManagerAdded(status))
-        .await
-        .unwrap();
-    })
-    .unwrap();
+    let (sender, receiver) = mpsc::channel(256);
+    let mgr = TestDeviceCommunicationManager::new(sender);
     let helper = mgr.helper();
-    self
-      .comm_managers
-      .insert(mgr.name().to_owned(), Box::new(mgr));
+    self.insert_comm_manager(Box::new(mgr), receiver)?;
     Ok(helper)
   }
 
-  pub fn add_protocol<T>(&self, protocol_name: &str) -> Result<(), ButtplugServerError> where T: ButtplugProtocol {
+  pub fn add_protocol<T>(&self, protocol_name: &str) -> Result<(), ButtplugServerError>
+  where
+    T: ButtplugProtocol,
+  {
     if !self.config.has_protocol(protocol_name) {
       self.config.add_protocol::<T>(protocol_name);
       Ok(())
     } else {
-      Err(ButtplugServerError::ProtocolAlreadyAdded(protocol_name.to_owned()))
+      Err(ButtplugServerError::ProtocolAlreadyAdded(
+        protocol_name.to_owned(),
+      ))
     }
   }
 
@@ -291,13 +1048,45 @@ impl DeviceManager {
       self.config.remove_protocol(protocol_name);
       Ok(())
     } else {
-      Err(ButtplugServerError::ProtocolDoesNotExist(protocol_name.to_owned()))
+      Err(ButtplugServerError::ProtocolDoesNotExist(
+        protocol_name.to_owned(),
+      ))
     }
   }
 
   pub fn remove_all_protocols(&self) {
     self.config.remove_all_protocols();
   }
+
+  /// Used by server extensions that need to talk to devices directly, outside
+  /// of the client protocol.
+  #[cfg(feature = "osc-bridge")]
+  pub(super) fn devices(&self) -> &Arc<DashMap<u32, Arc<ButtplugDevice>>> {
+    &self.devices
+  }
+}
+
+/// Devices visible through the device filter, that the client has been told
+/// about.
+fn device_list(
+  devices: &DashMap<u32, Arc<ButtplugDevice>>,
+  device_filter: &DeviceFilter,
+  unannounced_devices: &DashSet<u32>,
+  id: u32,
+) -> DeviceList {
+  let devices = devices
+    .iter()
+    .filter(|device| {
+      device_filter.allows(device.value()) && !unannounced_devices.contains(device.key())
+    })
+    .map(|device| {
+      let dev = device.value();
+      DeviceMessageInfo::new(*device.key(), &dev.name(), dev.message_attributes())
+    })
+    .collect();
+  let mut device_list = DeviceList::new(devices);
+  device_list.set_id(id);
+  device_list
 }
 
 impl Drop for DeviceManager {
@@ -1,46 +1,164 @@
-use super::{comm_managers::DeviceCommunicationEvent, ping_timer::PingTimer};
+use super::{
+  comm_managers::{DeviceCommunicationEvent, DeviceCommunicationManager},
+  device_connection::DeviceConnectionEvent,
+  device_filter::DeviceFilter,
+  device_group::DeviceGroup,
+  device_manager::DeviceIndexPolicy,
+  device_split::split_device,
+  known_devices::KnownDevices,
+  ping_timer::PingTimer,
+};
 use crate::{
-  core::messages::{
-    ButtplugServerMessage, DeviceAdded, DeviceRemoved, ScanningFinished, StopDeviceCmd,
+  core::{
+    errors::{ButtplugDeviceError, ButtplugError},
+    messages::{
+      ButtplugDeviceMessage, ButtplugServerMessage, DeviceAdded, DeviceRemoved, ScanningFinished,
+      SensorReading, StopDeviceCmd,
+    },
   },
   device::{
-    configuration_manager::DeviceConfigurationManager, ButtplugDevice, ButtplugDeviceEvent,
-    ButtplugDeviceImplCreator,
+    configuration_manager::DeviceConfigurationManager, identity::DeviceIdentity, ButtplugDevice,
+    ButtplugDeviceEvent, ButtplugDeviceImplCreator, DeviceCreationStage,
   },
-  util::async_manager,
+  util::{async_manager, clock::Clock, logging},
+};
+use dashmap::{DashMap, DashSet};
+use futures::{
+  future::{self, BoxFuture},
+  stream::FuturesUnordered,
+  FutureExt, StreamExt,
 };
-use dashmap::DashMap;
-use futures::{stream::FuturesUnordered, FutureExt, StreamExt};
-use std::sync::{
-  atomic::{AtomicBool, Ordering},
-  Arc,
+use std::{
+  collections::{HashMap, HashSet},
+  sync::{Arc, RwLock},
+  time::{Duration, Instant},
 };
-use tokio::sync::{broadcast, mpsc};
+#[cfg(feature = "server-emulator")]
+use futures::channel::oneshot;
+use tokio::sync::{broadcast, mpsc, Semaphore};
 use tracing;
 use tracing_futures::Instrument;
 
+/// How long comm managers get to report back once they've been told to stop
+/// scanning, before we give up on them and finish the scan anyways.
+const SCANNING_STOP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Events the device manager sends to its event loop.
+pub enum DeviceManagerEvent {
+  /// An event from a comm manager, tagged with the name of the manager.
+  CommManagerEvent {
+    name: String,
+    event: DeviceCommunicationEvent,
+  },
+  /// Sent when a comm manager is removed at runtime. Devices found by the
+  /// manager will be disconnected.
+  CommManagerRemoved(String),
+  /// Sent before the named comm managers are told to start scanning.
+  ScanningStarted(Vec<String>),
+  /// Sent before comm managers are told to stop scanning.
+  ScanningStopRequested,
+  /// Emulated devices, already connected, to register in order. The sender
+  /// is signalled once they're all registered.
+  #[cfg(feature = "server-emulator")]
+  EmulatedDevicesConnected(Vec<Arc<ButtplugDevice>>, oneshot::Sender<()>),
+}
+
+/// Bookkeeping for a scan that hasn't emitted ScanningFinished yet.
+struct ScanningState {
+  /// Comm managers that haven't reported the end of their scan.
+  pending_managers: HashSet<String>,
+  /// Errors reported by comm managers, passed on with ScanningFinished.
+  errors: Vec<String>,
+  /// True once managers have been asked to stop.
+  stopping: bool,
+  /// When we stop waiting on the current phase of the scan.
+  deadline: Option<Instant>,
+}
+
+/// A DeviceAdded/DeviceRemoved change that's waiting for the device to settle
+/// before the client hears about it.
+struct PendingDeviceNotification {
+  /// What the client should end up seeing at this index, None if the device
+  /// should be gone.
+  device_added: Option<DeviceAdded>,
+  /// When the device has been stable for long enough to tell the client.
+  deadline: Instant,
+}
+
 pub struct DeviceManagerEventLoop {
   device_config_manager: Arc<DeviceConfigurationManager>,
   device_index_generator: u32,
   device_map: Arc<DashMap<u32, Arc<ButtplugDevice>>>,
   ping_timer: Arc<PingTimer>,
-  /// Maps device addresses to indexes, so they can be reused on reconnect.
+  /// Maps device identity keys to indexes, so they can be reused on
+  /// reconnect.
   device_index_map: Arc<DashMap<String, u32>>,
   /// Broadcaster that relays device events in the form of Buttplug Messages to
   /// whoever owns the Buttplug Server.
   server_sender: broadcast::Sender<ButtplugServerMessage>,
   /// As the device manager owns the Device Communication Managers, it will have
   /// a receiver that the comm managers all send thru.
-  device_comm_receiver: mpsc::Receiver<DeviceCommunicationEvent>,
+  device_comm_receiver: mpsc::Receiver<DeviceManagerEvent>,
   /// Sender for device events, passed to new devices when they are created.
   device_event_sender: mpsc::Sender<ButtplugDeviceEvent>,
   /// Receiver for device events, which the event loops to handle events.
   device_event_receiver: mpsc::Receiver<ButtplugDeviceEvent>,
-  /// True if StartScanning has been called but no ScanningFinished has been
-  /// emitted yet.
-  scanning_in_progress: bool,
-  /// Holds the status of comm manager scanning states (scanning/not scanning).
-  comm_manager_scanning_statuses: Vec<Arc<AtomicBool>>,
+  /// Set from StartScanning until ScanningFinished is emitted.
+  scanning: Option<ScanningState>,
+  /// Maximum scan time before comm managers are stopped, 0 for no limit.
+  max_scanning_time: u64,
+  /// Whether indexes of disconnected devices are given to new devices.
+  device_index_policy: DeviceIndexPolicy,
+  /// Comm managers, shared with the device manager. Only used here to stop
+  /// scans that have run too long.
+  comm_managers: Arc<DashMap<String, Box<dyn DeviceCommunicationManager>>>,
+  /// Device creation results, sent from the tasks spawned for each found
+  /// device. Connected devices are handled like any other device event, and
+  /// a failed creation still gets counted so scanning can finish. Failures
+  /// the user needs to fix are passed on with ScanningFinished.
+  device_creation_sender: mpsc::Sender<Result<Option<Arc<ButtplugDevice>>, ButtplugError>>,
+  device_creation_receiver: mpsc::Receiver<Result<Option<Arc<ButtplugDevice>>, ButtplugError>>,
+  /// Found devices that are still being created. ScanningFinished isn't sent
+  /// until these settle, so DeviceAdded events for the scan come first.
+  pending_device_creations: usize,
+  /// Limits how many devices are created at once, None for no limit.
+  device_creation_limit: Option<Arc<Semaphore>>,
+  /// Device groups, keyed by group name. Shared with the device manager, which
+  /// handles group registration.
+  device_groups: Arc<DashMap<String, DeviceGroup>>,
+  /// Connected devices that belong to a device group, keyed by address. These
+  /// are only reachable through their group device, so they never go into the
+  /// device map.
+  group_members: HashMap<String, Arc<ButtplugDevice>>,
+  /// Identity keys of the logical devices created for each split device,
+  /// keyed by the physical device address.
+  split_devices: HashMap<String, Vec<String>>,
+  /// Devices that don't pass the filter are still managed, but we don't tell
+  /// the client about them.
+  device_filter: Arc<RwLock<DeviceFilter>>,
+  /// Maps device addresses to the comm manager that found them. Filled in by
+  /// the device manager.
+  device_owners: Arc<DashMap<String, String>>,
+  /// All connected physical devices, keyed by address, regardless of whether
+  /// they're exposed directly or through a virtual device.
+  connected_devices: HashMap<String, Arc<ButtplugDevice>>,
+  /// Time a device has to stay connected (or disconnected) before the client
+  /// is told about it, in milliseconds. 0 sends events right away.
+  device_debounce_time: u64,
+  /// Last DeviceAdded sent for each index still shown to the client. Only
+  /// tracked when debouncing.
+  announced_devices: HashMap<u32, DeviceAdded>,
+  /// Device changes waiting out the debounce time, keyed by device index.
+  pending_device_notifications: HashMap<u32, PendingDeviceNotification>,
+  /// Indexes in pending_device_notifications, shared with the device manager
+  /// so device lists leave out devices the client hasn't been told about.
+  unannounced_devices: Arc<DashSet<u32>>,
+  /// Time source for scan and debounce deadlines.
+  clock: Arc<dyn Clock>,
+  /// Devices that have connected before, shared with the device manager.
+  known_devices: KnownDevices,
+  /// Progress of found devices while they're created.
+  connection_event_sender: broadcast::Sender<DeviceConnectionEvent>,
 }
 
 impl DeviceManagerEventLoop {
@@ -49,9 +167,22 @@ impl DeviceManagerEventLoop {
     server_sender: broadcast::Sender<ButtplugServerMessage>,
     device_map: Arc<DashMap<u32, Arc<ButtplugDevice>>>,
     ping_timer: Arc<PingTimer>,
-    device_comm_receiver: mpsc::Receiver<DeviceCommunicationEvent>,
+    device_comm_receiver: mpsc::Receiver<DeviceManagerEvent>,
+    device_groups: Arc<DashMap<String, DeviceGroup>>,
+    device_filter: Arc<RwLock<DeviceFilter>>,
+    device_owners: Arc<DashMap<String, String>>,
+    comm_managers: Arc<DashMap<String, Box<dyn DeviceCommunicationManager>>>,
+    max_scanning_time: u64,
+    device_index_policy: DeviceIndexPolicy,
+    device_debounce_time: u64,
+    unannounced_devices: Arc<DashSet<u32>>,
+    max_concurrent_device_initializations: usize,
+    known_devices: KnownDevices,
+    connection_event_sender: broadcast::Sender<DeviceConnectionEvent>,
+    clock: Arc<dyn Clock>,
   ) -> Self {
     let (device_event_sender, device_event_receiver) = mpsc::channel(256);
+    let (device_creation_sender, device_creation_receiver) = mpsc::channel(256);
     Self {
       device_config_manager,
       server_sender,
@@ -62,83 +193,334 @@ impl DeviceManagerEventLoop {
       device_index_map: Arc::new(DashMap::new()),
       device_event_sender,
       device_event_receiver,
-      scanning_in_progress: false,
-      comm_manager_scanning_statuses: vec![],
+      scanning: None,
+      max_scanning_time,
+      device_index_policy,
+      comm_managers,
+      device_creation_sender,
+      device_creation_receiver,
+      pending_device_creations: 0,
+      device_creation_limit: if max_concurrent_device_initializations > 0 {
+        Some(Arc::new(Semaphore::new(max_concurrent_device_initializations)))
+      } else {
+        None
+      },
+      device_groups,
+      group_members: HashMap::new(),
+      split_devices: HashMap::new(),
+      device_filter,
+      device_owners,
+      connected_devices: HashMap::new(),
+      device_debounce_time,
+      announced_devices: HashMap::new(),
+      pending_device_notifications: HashMap::new(),
+      unannounced_devices,
+      clock,
+      known_devices,
+      connection_event_sender,
     }
   }
 
-  fn try_create_new_device(&mut self, device_creator: Box<dyn ButtplugDeviceImplCreator>) {
-    let device_event_sender_clone = self.device_event_sender.clone();
-    let create_device_future =
-      ButtplugDevice::try_create_device(self.device_config_manager.clone(), device_creator);
-    async_manager::spawn(async move {
-      match create_device_future.await {
-        Ok(option_dev) => match option_dev {
-          Some(device) => {
-            if device_event_sender_clone
-              .send(ButtplugDeviceEvent::Connected(Arc::new(device)))
-              .await
-              .is_err() {
-              error!("Device manager disappeared before connection established, device will be dropped.");
+  fn try_create_new_device(
+    &mut self,
+    name: String,
+    address: String,
+    device_creator: Box<dyn ButtplugDeviceImplCreator>,
+  ) {
+    let device_creation_sender = self.device_creation_sender.clone();
+    let connection_event_sender = self.connection_event_sender.clone();
+    let (progress_name, progress_address) = (name.clone(), address.clone());
+    let progress_sender = connection_event_sender.clone();
+    let create_device_future = ButtplugDevice::try_create_device_with_progress(
+      self.device_config_manager.clone(),
+      device_creator,
+      move |stage| {
+        let (name, address) = (progress_name.clone(), progress_address.clone());
+        let event = match stage {
+          DeviceCreationStage::Connecting { protocol } => {
+            DeviceConnectionEvent::DeviceConnecting {
+              name,
+              address,
+              protocol,
             }
           }
-          None => debug!("Device could not be matched to a protocol."),
-        },
-        Err(e) => error!("Device errored while trying to connect: {}", e),
+          DeviceCreationStage::Initializing { stage } => {
+            DeviceConnectionEvent::DeviceInitializing {
+              name,
+              address,
+              stage,
+            }
+          }
+        };
+        // No one listening is fine.
+        let _ = progress_sender.send(event);
+      },
+    );
+    let device_creation_limit = self.device_creation_limit.clone();
+    self.pending_device_creations += 1;
+    async_manager::spawn(async move {
+      // Held until the device is created or fails. The semaphore is never
+      // closed, so acquiring can't fail.
+      let _permit = match device_creation_limit {
+        Some(limit) => Some(limit.acquire_owned().await.unwrap()),
+        None => None,
+      };
+      let device = match create_device_future.await {
+        Ok(Some(device)) => {
+          let _ =
+            connection_event_sender.send(DeviceConnectionEvent::DeviceConnected { name, address });
+          Ok(Some(Arc::new(device)))
+        }
+        Ok(None) => {
+          debug!("Device could not be matched to a protocol.");
+          Ok(None)
+        }
+        Err(e) => {
+          error!("Device errored while trying to connect: {}", e);
+          let _ = connection_event_sender.send(DeviceConnectionEvent::DeviceConnectionFailed {
+            name,
+            address,
+            error: e.to_string(),
+          });
+          Err(e)
+        }
+      };
+      if device_creation_sender.send(device).await.is_err() {
+        error!("Device manager disappeared before connection established, device will be dropped.");
       }
     }.instrument(tracing::Span::current()))
     .unwrap();
   }
 
-  async fn handle_device_communication(&mut self, event: DeviceCommunicationEvent) {
+  async fn handle_device_manager_event(&mut self, event: DeviceManagerEvent) {
     match event {
-      DeviceCommunicationEvent::ScanningStarted => {
-        self.scanning_in_progress = true;
+      DeviceManagerEvent::ScanningStarted(managers) => {
+        if self.scanning.is_some() {
+          debug!("Scan already in progress, adding managers to it.");
+        }
+        let scanning = self.scanning.get_or_insert_with(|| ScanningState {
+          pending_managers: HashSet::new(),
+          errors: vec![],
+          stopping: false,
+          deadline: None,
+        });
+        scanning.pending_managers.extend(managers);
+        if self.max_scanning_time > 0 && !scanning.stopping {
+          scanning.deadline =
+            Some(self.clock.now() + Duration::from_millis(self.max_scanning_time));
+        }
+        self.check_scanning_finished();
       }
-      DeviceCommunicationEvent::ScanningFinished => {
-        debug!(
-          "System signaled that scanning was finished, check to see if all managers are finished."
-        );
-        if !self.scanning_in_progress {
-          debug!("Manager finished before scanning was fully started, continuing event loop.");
-          return;
+      DeviceManagerEvent::ScanningStopRequested => {
+        if let Some(scanning) = &mut self.scanning {
+          scanning.stopping = true;
+          scanning.deadline = Some(self.clock.now() + SCANNING_STOP_TIMEOUT);
         }
-        if self
-          .comm_manager_scanning_statuses
+      }
+      DeviceManagerEvent::CommManagerEvent { name, event } => {
+        self.handle_device_communication(name, event);
+      }
+      DeviceManagerEvent::CommManagerRemoved(name) => {
+        info!("Comm manager {} removed, disconnecting its devices.", name);
+        let owned_addresses: Vec<String> = self
+          .device_owners
           .iter()
-          .any(|x| x.load(Ordering::SeqCst))
-        {
-          debug!("At least one manager still scanning, continuing event loop.");
-          return;
+          .filter(|owner| *owner.value() == name)
+          .map(|owner| owner.key().clone())
+          .collect();
+        for address in owned_addresses {
+          self.device_owners.remove(&address);
+          if let Some(device) = self.connected_devices.get(&address) {
+            let disconnect_fut = device.disconnect();
+            async_manager::spawn(async move {
+              if let Err(err) = disconnect_fut.await {
+                error!("Error disconnecting device for removed comm manager: {:?}", err);
+              }
+            })
+            .unwrap();
+          }
         }
-        debug!("All managers finished, emitting ScanningFinished");
-        self.scanning_in_progress = false;
-        if self
-          .server_sender
-          .send(ScanningFinished::default().into())
-          .is_err()
-        {
-          info!("Server disappeared, exiting loop.");
-          return;
+        // The removed manager may have been the last one we were waiting on.
+        self.manager_scanning_finished(&name, None);
+      }
+      #[cfg(feature = "server-emulator")]
+      DeviceManagerEvent::EmulatedDevicesConnected(devices, ready_sender) => {
+        for device in devices {
+          self
+            .handle_device_event(ButtplugDeviceEvent::Connected(device))
+            .await;
         }
+        let _ = ready_sender.send(());
+      }
+    }
+  }
+
+  fn handle_device_communication(&mut self, name: String, event: DeviceCommunicationEvent) {
+    match event {
+      DeviceCommunicationEvent::ScanningFinished => {
+        debug!("Comm manager {} finished scanning.", name);
+        self.manager_scanning_finished(&name, None);
+      }
+      DeviceCommunicationEvent::ScanningFailed(err) => {
+        error!("Comm manager {} scanning failed: {}", name, err);
+        self.manager_scanning_finished(&name, Some(err));
       }
       DeviceCommunicationEvent::DeviceFound {
-        name,
+        name: device_name,
         address,
         creator,
       } => {
+        logging::register_identifier(&address);
+        logging::register_identifier(&device_name);
+        if !self.connected_devices.contains_key(&address) {
+          if let Some(known_device) = self.known_devices.find_by_address(&address) {
+            debug!("Found known device {}.", known_device.identity_key);
+            self.known_devices.send_in_range(known_device);
+          }
+        }
         let span = info_span!(
           "device creation",
-          name = tracing::field::display(name),
-          address = tracing::field::display(address)
+          name = tracing::field::display(&device_name),
+          address = tracing::field::display(&address)
         );
         let _enter = span.enter();
-        self.try_create_new_device(creator);
+        self.try_create_new_device(device_name, address, creator);
       }
-      DeviceCommunicationEvent::DeviceManagerAdded(status) => {
-        self.comm_manager_scanning_statuses.push(status);
+    }
+  }
+
+  fn manager_scanning_finished(&mut self, name: &str, error: Option<String>) {
+    let scanning = match &mut self.scanning {
+      Some(scanning) => scanning,
+      None => {
+        debug!("Comm manager {} finished outside of a scan, ignoring.", name);
+        return;
       }
+    };
+    if !scanning.pending_managers.remove(name) {
+      debug!("Comm manager {} was not part of the current scan, ignoring.", name);
+      return;
     }
+    if let Some(error) = error {
+      scanning.errors.push(format!("{}: {}", name, error));
+    }
+    self.check_scanning_finished();
+  }
+
+  /// Called when the current scan deadline passes. The first time through,
+  /// the scan has run for its maximum time and managers are told to stop. If
+  /// they still haven't finished by the second time, we stop waiting on them.
+  fn handle_scanning_timeout(&mut self) {
+    let scanning = match &mut self.scanning {
+      Some(scanning) => scanning,
+      None => return,
+    };
+    if !scanning.stopping {
+      info!("Maximum scanning time reached, stopping comm managers.");
+      scanning.stopping = true;
+      scanning.deadline = Some(self.clock.now() + SCANNING_STOP_TIMEOUT);
+      let fut_vec: Vec<_> = self
+        .comm_managers
+        .iter()
+        .filter(|mgr| scanning.pending_managers.contains(mgr.key()))
+        .map(|mgr| mgr.value().stop_scanning())
+        .collect();
+      async_manager::spawn(async move {
+        for result in future::join_all(fut_vec).await {
+          if let Err(err) = result {
+            error!("Error stopping scanning after scanning timeout: {}", err);
+          }
+        }
+      })
+      .unwrap();
+      return;
+    }
+    for name in scanning.pending_managers.drain() {
+      warn!("Comm manager {} did not finish scanning in time.", name);
+      scanning
+        .errors
+        .push(format!("{}: did not finish scanning in time", name));
+    }
+    // Don't hold the scan open for devices that are still connecting either,
+    // they'll show up on their own once they're ready.
+    self.finish_scanning();
+  }
+
+  fn scanning_timeout(&self) -> BoxFuture<'static, ()> {
+    match self.scanning.as_ref().and_then(|scanning| scanning.deadline) {
+      Some(deadline) => self
+        .clock
+        .sleep(deadline.saturating_duration_since(self.clock.now())),
+      None => future::pending().boxed(),
+    }
+  }
+
+  fn check_scanning_finished(&mut self) {
+    let scanning = match &self.scanning {
+      Some(scanning) => scanning,
+      None => return,
+    };
+    if !scanning.pending_managers.is_empty() {
+      debug!("At least one manager still scanning, continuing event loop.");
+      return;
+    }
+    if self.pending_device_creations > 0 {
+      debug!(
+        "Waiting on {} found devices before finishing scan.",
+        self.pending_device_creations
+      );
+      return;
+    }
+    if !self.pending_device_notifications.is_empty() {
+      debug!(
+        "Waiting on {} devices to settle before finishing scan.",
+        self.pending_device_notifications.len()
+      );
+      return;
+    }
+    self.finish_scanning();
+  }
+
+  fn finish_scanning(&mut self) {
+    let scanning = match self.scanning.take() {
+      Some(scanning) => scanning,
+      None => return,
+    };
+    debug!("All managers finished, emitting ScanningFinished");
+    if self
+      .server_sender
+      .send(ScanningFinished::new_with_errors(scanning.errors).into())
+      .is_err()
+    {
+      info!("Server disappeared, cannot send ScanningFinished.");
+    }
+  }
+
+  async fn handle_device_creation(
+    &mut self,
+    device: Result<Option<Arc<ButtplugDevice>>, ButtplugError>,
+  ) {
+    self.pending_device_creations -= 1;
+    match device {
+      Ok(Some(device)) => {
+        self
+          .handle_device_event(ButtplugDeviceEvent::Connected(device))
+          .await;
+      }
+      Ok(None) => {}
+      // Pairing has to happen outside of the library, so make sure the
+      // client hears about it.
+      Err(ButtplugError::ButtplugDeviceError(
+        err @ ButtplugDeviceError::DeviceBondingRequired(..),
+      )) => {
+        if let Some(scanning) = &mut self.scanning {
+          scanning.errors.push(err.to_string());
+        }
+      }
+      // Already logged by the creation task.
+      Err(_) => {}
+    }
+    self.check_scanning_finished();
   }
 
   async fn handle_device_event(&mut self, device_event: ButtplugDeviceEvent) {
@@ -151,88 +533,354 @@ impl DeviceManagerEventLoop {
           address = tracing::field::display(device.address())
         );
         let _enter = span.enter();
-        let generated_device_index = self.device_index_generator;
-        self.device_index_generator += 1;
-        // See if we have a reusable device index here.
-        let device_index = if let Some(id) = self.device_index_map.get(device.address()) {
-          *id.value()
-        } else {
+        self.forward_device_events(&device);
+        self.known_devices.record(&device);
+        self
+          .connected_devices
+          .insert(device.address().to_owned(), device.clone());
+        if let Some(group) = self.device_group_for(&device.identity()) {
+          info!(
+            "Device is a member of device group {}, updating group device.",
+            group.name()
+          );
           self
-            .device_index_map
-            .insert(device.address().to_owned(), generated_device_index);
-          generated_device_index
-        };
-        // Since we can now reuse device indexes, this means we might possibly
-        // stomp on devices already in the map if they don't register a
-        // disconnect before we try to insert the new device. If we have a
-        // device already in the map with the same index (and therefore same
-        // address), consider it disconnected and eject it from the map. This
-        // should also trigger a disconnect event before our new DeviceAdded
-        // message goes out, so timing matters here.
-        if self.device_map.contains_key(&device_index) {
-          info!("Device map contains key {}.", device_index);
-          // We just checked that the key exists, so we can unwrap
-          // here.
-          let (_, old_device) = self.device_map.remove(&device_index).unwrap();
-          // After removing the device from the array, manually disconnect it to
-          // make sure the event is thrown.
-          if let Err(err) = old_device.disconnect().await {
-            // If we throw an error during the disconnect, we can't really do
-            // anything with it, but should at least log it.
-            error!("Error during index collision disconnect: {:?}", err);
+            .group_members
+            .insert(device.address().to_owned(), device);
+          self.update_device_group(&group).await;
+        } else if self
+          .device_config_manager
+          .is_split_device(&device.identity())
+        {
+          info!("Splitting device into one device per feature.");
+          let mut split_keys = vec![];
+          for split in split_device(device.clone()) {
+            split_keys.push(split.identity().key());
+            self.register_device(Arc::new(split)).await;
           }
+          self
+            .split_devices
+            .insert(device.address().to_owned(), split_keys);
         } else {
-          info!("Device map does not contain key {}.", device_index);
+          self.register_device(device).await;
         }
-
-        // Create event loop for forwarding device events into our selector.
-        let mut event_listener = device.event_stream();
-        let event_sender = self.device_event_sender.clone();
-        async_manager::spawn(async move {
-          while let Ok(event) = event_listener.recv().await {
-            event_sender.send(event).await.unwrap();
+      }
+      ButtplugDeviceEvent::Removed(address) => {
+        let device = match self.connected_devices.remove(&address) {
+          Some(device) => device,
+          None => {
+            debug!("Got removal for unknown device {}, ignoring.", address);
+            return;
           }
-        })
-        .unwrap();
+        };
+        if self.group_members.remove(&address).is_some() {
+          if let Some(group) = self.device_group_for(&device.identity()) {
+            self.update_device_group(&group).await;
+          }
+          return;
+        }
+        if let Some(split_keys) = self.split_devices.remove(&address) {
+          for split_key in split_keys {
+            self.remove_device(&split_key);
+          }
+          return;
+        }
+        self.remove_device(&device.identity().key());
+      }
+      ButtplugDeviceEvent::Notification(_address, _endpoint, _data) => {
+        // TODO At some point here we need to fill this in for RawSubscribe.
+      }
+      ButtplugDeviceEvent::SensorReading(address, reading) => {
+        self.forward_sensor_reading(&address, reading);
+      }
+    }
+  }
 
-        info!("Assigning index {} to {}", device_index, device.name());
-        let device_added_message =
-          DeviceAdded::new(device_index, &device.name(), &device.message_attributes());
-        self.device_map.insert(device_index, device);
-        // After that, we can send out to the server's event listeners to let
-        // them know a device has been added.
-        if self
-          .server_sender
-          .send(device_added_message.into())
-          .is_err()
-        {
-          debug!("Server not currently available, dropping Device Added event.");
+  fn forward_device_events(&self, device: &ButtplugDevice) {
+    // Create event loops for forwarding device events and protocol sensor
+    // readings into our selector.
+    self.forward_event_stream(device.event_stream());
+    self.forward_event_stream(device.sensor_event_stream());
+  }
+
+  fn forward_event_stream(&self, mut event_listener: broadcast::Receiver<ButtplugDeviceEvent>) {
+    let event_sender = self.device_event_sender.clone();
+    async_manager::spawn_named("device event forwarder", async move {
+      loop {
+        match event_listener.recv().await {
+          Ok(event) => {
+            if event_sender.send(event).await.is_err() {
+              break;
+            }
+          }
+          // Sensors can be chatty, dropping a few readings is fine.
+          Err(broadcast::error::RecvError::Lagged(_)) => continue,
+          Err(broadcast::error::RecvError::Closed) => break,
         }
       }
-      ButtplugDeviceEvent::Removed(address) => {
-        let device_index = *self.device_index_map.get(&address).unwrap().value();
-        self.device_map.remove(&device_index).unwrap();
-        if self
-          .server_sender
-          .send(DeviceRemoved::new(device_index).into())
-          .is_err()
-        {
-          debug!("Server not currently available, dropping Device Removed event.");
+    })
+    .unwrap();
+  }
+
+  fn forward_sensor_reading(&self, address: &str, mut reading: SensorReading) {
+    // Readings only go out for devices the client can see. Devices that are
+    // grouped or split aren't in the device map under their own address, so
+    // their readings are dropped here.
+    let identity_key = match self.connected_devices.get(address) {
+      Some(device) => device.identity().key(),
+      None => return,
+    };
+    let device_index = match self.device_index_map.get(&identity_key) {
+      Some(index) => *index.value(),
+      None => return,
+    };
+    let visible = match self.device_map.get(&device_index) {
+      Some(device) if device.address() == address => {
+        self.device_filter.read().unwrap().allows(device.value())
+      }
+      _ => false,
+    };
+    if !visible {
+      trace!("Dropping sensor reading for unexposed device {}", address);
+      return;
+    }
+    reading.set_device_index(device_index);
+    if self.server_sender.send(reading.into()).is_err() {
+      debug!("Server not currently available, dropping sensor reading.");
+    }
+  }
+
+  /// Picks the index for a device. Devices we've seen before get their old
+  /// index back, and devices with a reserved index get that. Everything else
+  /// gets an index according to the index policy, never a reserved one, so
+  /// an unreserved device never takes an index a reserved device may show up
+  /// for later.
+  fn device_index_for(&mut self, identity: &DeviceIdentity) -> u32 {
+    let identity_key = identity.key();
+    // If the device wasn't identified by device ID last time it connected
+    // (e.g. the protocol only reports it during initialization), it'll be
+    // remembered by address.
+    let address_key = DeviceIdentity::new(identity.address(), None).key();
+    let previous_index = self
+      .device_index_map
+      .get(&identity_key)
+      .or_else(|| self.device_index_map.get(&address_key))
+      .map(|index| *index.value());
+    let device_index = match previous_index {
+      Some(index) => index,
+      None => match self.device_config_manager.reserved_index(identity) {
+        Some(reserved_index) => reserved_index,
+        None => match self.device_index_policy {
+          DeviceIndexPolicy::Monotonic => self.next_generated_index(),
+          DeviceIndexPolicy::Recycle => self.lowest_free_index(),
+        },
+      },
+    };
+    self.device_index_map.insert(identity_key, device_index);
+    device_index
+  }
+
+  fn next_generated_index(&mut self) -> u32 {
+    while self
+      .device_config_manager
+      .is_reserved_index(self.device_index_generator)
+    {
+      self.device_index_generator += 1;
+    }
+    let generated_index = self.device_index_generator;
+    self.device_index_generator += 1;
+    generated_index
+  }
+
+  fn lowest_free_index(&mut self) -> u32 {
+    let free_index = (0..)
+      .find(|index| {
+        !self.device_map.contains_key(index)
+          && !self.device_config_manager.is_reserved_index(*index)
+      })
+      .unwrap();
+    // Whatever disconnected device last had this index won't get it back.
+    self
+      .device_index_map
+      .retain(|_, index| *index != free_index);
+    free_index
+  }
+
+  async fn register_device(&mut self, device: Arc<ButtplugDevice>) {
+    let device_index = self.device_index_for(&device.identity());
+    // Since we can now reuse device indexes, this means we might possibly
+    // stomp on devices already in the map if they don't register a
+    // disconnect before we try to insert the new device. If we have a
+    // device already in the map with the same index (and therefore same
+    // address), consider it disconnected and eject it from the map. This
+    // should also trigger a disconnect event before our new DeviceAdded
+    // message goes out, so timing matters here.
+    if self.device_map.contains_key(&device_index) {
+      info!("Device map contains key {}.", device_index);
+      // We just checked that the key exists, so we can unwrap
+      // here.
+      let (_, old_device) = self.device_map.remove(&device_index).unwrap();
+      // After removing the device from the array, manually disconnect it to
+      // make sure the event is thrown.
+      if let Err(err) = old_device.disconnect().await {
+        // If we throw an error during the disconnect, we can't really do
+        // anything with it, but should at least log it.
+        error!("Error during index collision disconnect: {:?}", err);
+      }
+    } else {
+      info!("Device map does not contain key {}.", device_index);
+    }
+
+    info!("Assigning index {} to {}", device_index, device.name());
+    let device_added_message =
+      DeviceAdded::new(device_index, &device.name(), &device.message_attributes());
+    let visible = self.device_filter.read().unwrap().allows(&device);
+    self.device_map.insert(device_index, device);
+    // After that, we can send out to the server's event listeners to let
+    // them know a device has been added.
+    if !visible {
+      debug!("Device does not pass device filter, not sending Device Added event.");
+    } else {
+      self.notify_device_state(device_index, Some(device_added_message));
+    }
+  }
+
+  fn remove_device(&mut self, identity_key: &str) {
+    let device_index = *self.device_index_map.get(identity_key).unwrap().value();
+    let (_, device) = self.device_map.remove(&device_index).unwrap();
+    self.send_device_removed(device_index, &device);
+  }
+
+  fn send_device_removed(&mut self, device_index: u32, device: &ButtplugDevice) {
+    if !self.device_filter.read().unwrap().allows(device) {
+      return;
+    }
+    self.notify_device_state(device_index, None);
+  }
+
+  /// Tells the client a device is now at `device_index` (or that nothing is,
+  /// if `device_added` is None). When debouncing, the change is held until
+  /// the device has stayed that way for the debounce time, so a device that
+  /// drops out and comes right back never shows up as removed, and one that
+  /// appears and vanishes again never shows up at all.
+  fn notify_device_state(&mut self, device_index: u32, device_added: Option<DeviceAdded>) {
+    if self.device_debounce_time == 0 {
+      let message: ButtplugServerMessage = match device_added {
+        Some(device_added) => device_added.into(),
+        None => DeviceRemoved::new(device_index).into(),
+      };
+      if self.server_sender.send(message).is_err() {
+        debug!("Server not currently available, dropping device event.");
+      }
+      return;
+    }
+    if self.announced_devices.get(&device_index) == device_added.as_ref() {
+      // Back to what the client already has, nothing to tell it.
+      if self
+        .pending_device_notifications
+        .remove(&device_index)
+        .is_some()
+      {
+        self.unannounced_devices.remove(&device_index);
+        debug!("Device {} settled back to its previous state.", device_index);
+        self.check_scanning_finished();
+      }
+      return;
+    }
+    self.pending_device_notifications.insert(
+      device_index,
+      PendingDeviceNotification {
+        device_added,
+        deadline: self.clock.now() + Duration::from_millis(self.device_debounce_time),
+      },
+    );
+    self.unannounced_devices.insert(device_index);
+  }
+
+  /// Sends out device changes that have waited out the debounce time.
+  fn flush_device_notifications(&mut self) {
+    let now = self.clock.now();
+    let settled: Vec<u32> = self
+      .pending_device_notifications
+      .iter()
+      .filter(|(_, pending)| pending.deadline <= now)
+      .map(|(index, _)| *index)
+      .collect();
+    for device_index in settled {
+      let pending = self
+        .pending_device_notifications
+        .remove(&device_index)
+        .unwrap();
+      self.unannounced_devices.remove(&device_index);
+      let mut messages: Vec<ButtplugServerMessage> = vec![];
+      // A different device in the same slot has to replace the old one, so
+      // the client gets a removal first.
+      if self.announced_devices.remove(&device_index).is_some() {
+        messages.push(DeviceRemoved::new(device_index).into());
+      }
+      if let Some(device_added) = pending.device_added {
+        self
+          .announced_devices
+          .insert(device_index, device_added.clone());
+        messages.push(device_added.into());
+      }
+      for message in messages {
+        if self.server_sender.send(message).is_err() {
+          debug!("Server not currently available, dropping device event.");
         }
       }
-      ButtplugDeviceEvent::Notification(_address, _endpoint, _data) => {
-        // TODO At some point here we need to fill this in for RawSubscribe and
-        // other sensor subscriptions.
+    }
+    self.check_scanning_finished();
+  }
+
+  fn device_notification_timeout(&self) -> BoxFuture<'static, ()> {
+    match self
+      .pending_device_notifications
+      .values()
+      .map(|pending| pending.deadline)
+      .min()
+    {
+      Some(deadline) => self
+        .clock
+        .sleep(deadline.saturating_duration_since(self.clock.now())),
+      None => future::pending().boxed(),
+    }
+  }
+
+  fn device_group_for(&self, identity: &DeviceIdentity) -> Option<DeviceGroup> {
+    self
+      .device_groups
+      .iter()
+      .find(|group| group.value().contains_device(identity))
+      .map(|group| group.value().clone())
+  }
+
+  /// Rebuilds a group device after its membership has changed. Clients will
+  /// see the old group device removed, and a new one (with the same index)
+  /// added if any members are still connected.
+  async fn update_device_group(&mut self, group: &DeviceGroup) {
+    let group_index = self
+      .device_index_map
+      .get(&DeviceIdentity::new(&group.address(), None).key())
+      .map(|index| *index.value());
+    if let Some(device_index) = group_index {
+      if let Some((_, group_device)) = self.device_map.remove(&device_index) {
+        self.send_device_removed(device_index, &group_device);
       }
     }
+    if let Some(group_device) = group.create_device(&self.group_members) {
+      self.register_device(Arc::new(group_device)).await;
+    }
   }
 
   async fn handle_ping_timeout(&self) {
     error!("Pinged out, stopping devices");
     let mut fut_vec = FuturesUnordered::new();
     self.device_map.iter().for_each(|dev| {
-      let device = dev.value();
-      fut_vec.push(device.parse_message(StopDeviceCmd::new(1).into()))
+      fut_vec.push(
+        dev
+          .value()
+          .parse_message_instrumented(*dev.key(), StopDeviceCmd::new(1).into()),
+      )
     });
     async_manager::spawn(async move {
       while let Some(val) = fut_vec.next().await {
@@ -248,6 +896,8 @@ impl DeviceManagerEventLoop {
 
   pub async fn run(&mut self) {
     loop {
+      let scanning_timeout = self.scanning_timeout();
+      let device_notification_timeout = self.device_notification_timeout();
       select! {
         // If we have a ping timeout, stop all devices
         _ = self.ping_timer.ping_timeout_waiter().fuse() => {
@@ -255,11 +905,22 @@ impl DeviceManagerEventLoop {
         },
         device_comm_msg = self.device_comm_receiver.recv().fuse() => {
           if let Some(msg) = device_comm_msg {
-            self.handle_device_communication(msg).await;
+            self.handle_device_manager_event(msg).await;
           } else {
             break;
           }
         }
+        device_creation = self.device_creation_receiver.recv().fuse() => {
+          if let Some(device) = device_creation {
+            self.handle_device_creation(device).await;
+          }
+        }
+        _ = scanning_timeout.fuse() => {
+          self.handle_scanning_timeout();
+        }
+        _ = device_notification_timeout.fuse() => {
+          self.flush_device_notifications();
+        }
         device_event_msg = self.device_event_receiver.recv().fuse() => {
           if let Some(msg) = device_event_msg {
             self.handle_device_event(msg).await;
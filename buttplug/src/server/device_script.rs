@@ -0,0 +1,824 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2022 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Small [rhai](https://rhai.rs) scripts for device behavior the library
+//! doesn't have built in.
+//!
+//! Scripts are added with
+//! [ButtplugServerBuilder::device_script][super::ButtplugServerBuilder::device_script]
+//! (or [ButtplugServer::add_device_script][super::ButtplugServer::add_device_script]),
+//! and can define any of these functions:
+//!
+//! - `transform_vibrate(index, speed)`: Called for every feature in every
+//!   VibrateCmd sent to a device the script applies to. Returns the speed to
+//!   send instead.
+//! - `transform_rotate(index, speed)`: Same, for RotateCmd. Direction is left
+//!   as is.
+//! - `pattern(elapsed_ms)`: Called every pattern interval while the script's
+//!   pattern is running on a device, see
+//!   [ButtplugServer::start_script_pattern][super::ButtplugServer::start_script_pattern].
+//!   Returns a speed for every vibrator, an array of speeds (one per
+//!   vibrator), or nothing to leave the device as it is.
+//!
+//! ```rhai
+//! // Never go above half speed, and pulse when asked to run a pattern.
+//! fn transform_vibrate(index, speed) {
+//!   speed / 2.0
+//! }
+//!
+//! fn pattern(elapsed_ms) {
+//!   if (elapsed_ms / 500) % 2 == 0 { 1.0 } else { 0.0 }
+//! }
+//! ```
+//!
+//! Scripts can't touch anything outside of the values they're given and
+//! return: there are no file, network, or module imports, `eval` is turned
+//! off, and `print` goes to the log. Each call is limited in how many
+//! operations it can run and how long it can take (see [ScriptLimits]), so a
+//! runaway script fails instead of holding up commands. A transform that
+//! fails refuses the command, rather than letting it through unchanged.
+//!
+//! Scripts run on blocking threads, never on the async runtime. Commands
+//! to devices with scripts are still sent in the order they came in. Stop
+//! commands and zero speeds aren't passed to scripts, so a script can't
+//! keep a device running that was told to stop.
+
+use super::emergency_stop::{cancel_runners, Runner, Runners};
+use crate::{
+  core::{
+    errors::{ButtplugDeviceError, ButtplugError},
+    messages::{
+      ButtplugDeviceCommandMessageUnion, ButtplugDeviceMessage, ButtplugDeviceMessageType,
+      ButtplugMessage, RotateCmd, RotationSubcommand, VibrateCmd, VibrateSubcommand,
+    },
+  },
+  device::{ButtplugDevice, ButtplugDeviceResultFuture},
+  util::async_manager,
+};
+use dashmap::{DashMap, DashSet};
+use displaydoc::Display;
+use futures::{
+  channel::oneshot,
+  future::{self, BoxFuture},
+  select, FutureExt,
+};
+use rhai::{module_resolvers::DummyModuleResolver, Array, Dynamic, Engine, FuncArgs, Scope, AST};
+use std::{
+  sync::{
+    atomic::{AtomicU32, Ordering},
+    Arc, Mutex, RwLock, Weak,
+  },
+  time::{Duration, Instant},
+};
+use thiserror::Error;
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+
+/// Time between `pattern` calls, unless the script says otherwise.
+pub const DEFAULT_PATTERN_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Error, Display, Clone, PartialEq)]
+pub enum DeviceScriptError {
+  /// Device script {0} could not be compiled: {1}
+  CompileError(String, String),
+  /// A device script named {0} has already been added.
+  DuplicateScript(String),
+  /// No device script named {0} has been added.
+  UnknownScript(String),
+  /// Device script {0} doesn't have a pattern function.
+  NoPatternFunction(String),
+  /// No device available at index {0}
+  DeviceNotAvailable(u32),
+  /// Device {0} has no vibrators for a pattern to drive.
+  NoVibrators(u32),
+  /// Device script {0} failed: {1}
+  RuntimeError(String, String),
+}
+
+/// How much a single call into a script can do.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ScriptLimits {
+  /// Operations (roughly, expressions evaluated) a call can run.
+  pub max_operations: u64,
+  /// Wall clock time a call can take.
+  pub max_run_time: Duration,
+}
+
+impl Default for ScriptLimits {
+  fn default() -> Self {
+    Self {
+      max_operations: 100_000,
+      max_run_time: Duration::from_millis(20),
+    }
+  }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeviceScript {
+  /// Name of the script, used for starting its pattern and in errors.
+  pub name: String,
+  pub source: String,
+  /// Device names the script's transforms apply to. Empty means every
+  /// device.
+  pub device_names: Vec<String>,
+  pub pattern_interval: Duration,
+  pub limits: ScriptLimits,
+}
+
+impl DeviceScript {
+  pub fn new(name: &str, source: &str) -> Self {
+    Self {
+      name: name.to_owned(),
+      source: source.to_owned(),
+      device_names: vec![],
+      pattern_interval: DEFAULT_PATTERN_INTERVAL,
+      limits: ScriptLimits::default(),
+    }
+  }
+
+  /// Only applies transforms to devices with the given name. Can be called
+  /// more than once to add more names.
+  pub fn device_name(mut self, name: &str) -> Self {
+    self.device_names.push(name.to_owned());
+    self
+  }
+
+  pub fn pattern_interval(mut self, interval: Duration) -> Self {
+    self.pattern_interval = interval;
+    self
+  }
+
+  pub fn limits(mut self, limits: ScriptLimits) -> Self {
+    self.limits = limits;
+    self
+  }
+}
+
+struct CompiledScript {
+  script: DeviceScript,
+  engine: Engine,
+  ast: AST,
+  deadline: Arc<Mutex<Instant>>,
+  // Calls share the deadline, so only one can run at a time.
+  call_lock: Mutex<()>,
+}
+
+impl CompiledScript {
+  fn compile(script: DeviceScript) -> Result<Self, DeviceScriptError> {
+    let deadline = Arc::new(Mutex::new(Instant::now()));
+    let mut engine = Engine::new();
+    engine
+      .set_max_operations(script.limits.max_operations)
+      .set_max_call_levels(16)
+      .set_max_expr_depths(64, 32)
+      .set_max_string_size(1024)
+      .set_max_array_size(256)
+      .set_max_map_size(256)
+      .set_module_resolver(DummyModuleResolver::new())
+      .disable_symbol("eval");
+    let name = script.name.clone();
+    engine.on_print(move |text| info!("Device script {}: {}", name, text));
+    let progress_deadline = deadline.clone();
+    engine.on_progress(move |_| {
+      if Instant::now() > *progress_deadline.lock().unwrap() {
+        Some(Dynamic::UNIT)
+      } else {
+        None
+      }
+    });
+    let ast = engine
+      .compile(&script.source)
+      .map_err(|err| DeviceScriptError::CompileError(script.name.clone(), err.to_string()))?;
+    Ok(Self {
+      script,
+      engine,
+      ast,
+      deadline,
+      call_lock: Mutex::new(()),
+    })
+  }
+
+  fn has_function(&self, name: &str, param_count: usize) -> bool {
+    self
+      .ast
+      .iter_functions()
+      .any(|function| function.name == name && function.params.len() == param_count)
+  }
+
+  fn applies_to(&self, device_name: &str) -> bool {
+    self.script.device_names.is_empty()
+      || self
+        .script
+        .device_names
+        .iter()
+        .any(|name| name == device_name)
+  }
+
+  fn call(&self, function: &str, args: impl FuncArgs) -> Result<Dynamic, DeviceScriptError> {
+    let _call = self.call_lock.lock().unwrap();
+    *self.deadline.lock().unwrap() = Instant::now() + self.script.limits.max_run_time;
+    self
+      .engine
+      .call_fn(&mut Scope::new(), &self.ast, function, args)
+      .map_err(|err| self.runtime_error(err.to_string()))
+  }
+
+  fn runtime_error(&self, message: String) -> DeviceScriptError {
+    DeviceScriptError::RuntimeError(self.script.name.clone(), message)
+  }
+
+  /// Runs a transform function, if the script has it, on a speed.
+  fn transform_speed(
+    &self,
+    function: &str,
+    index: u32,
+    speed: f64,
+  ) -> Result<f64, DeviceScriptError> {
+    // Zero is a stop, which scripts don't get to change.
+    if speed == 0.0 || !self.has_function(function, 2) {
+      return Ok(speed);
+    }
+    let result = self.call(function, (index as i64, speed))?;
+    to_speed(&result)
+      .ok_or_else(|| self.runtime_error(format!("{} has to return a number.", function)))
+  }
+}
+
+/// Reads a number returned by a script as a speed.
+fn to_speed(value: &Dynamic) -> Option<f64> {
+  value
+    .as_float()
+    .ok()
+    .or_else(|| value.as_int().ok().map(|value| value as f64))
+    .map(|speed| speed.clamp(0.0, 1.0))
+}
+
+type TransformResult = Result<ButtplugDeviceCommandMessageUnion, ButtplugError>;
+
+/// A command waiting on its transforms. `done` gets the result, in the same
+/// order jobs were queued.
+struct TransformJob {
+  scripts: Vec<Arc<CompiledScript>>,
+  message: ButtplugDeviceCommandMessageUnion,
+  done: Box<dyn FnOnce(TransformResult) + Send>,
+}
+
+fn script_names(scripts: &[Arc<CompiledScript>]) -> String {
+  scripts
+    .iter()
+    .map(|compiled| compiled.script.name.as_str())
+    .collect::<Vec<_>>()
+    .join(", ")
+}
+
+fn transform_with(
+  scripts: &[Arc<CompiledScript>],
+  message: ButtplugDeviceCommandMessageUnion,
+) -> TransformResult {
+  scripts.iter().try_fold(message, |message, compiled| {
+    transform_command(compiled, message).map_err(|err| {
+      ButtplugError::from(ButtplugDeviceError::DeviceScriptError(
+        compiled.script.name.clone(),
+        err.to_string(),
+      ))
+    })
+  })
+}
+
+fn runner_shut_down(names: String) -> ButtplugError {
+  ButtplugDeviceError::DeviceScriptError(names, "script runner has shut down".to_owned()).into()
+}
+
+/// True if a command has a speed a script could change. Everything else
+/// skips the blocking thread.
+fn has_speeds_to_transform(message: &ButtplugDeviceCommandMessageUnion) -> bool {
+  match message {
+    ButtplugDeviceCommandMessageUnion::VibrateCmd(msg) => {
+      msg.speeds().iter().any(|cmd| cmd.speed() != 0.0)
+    }
+    ButtplugDeviceCommandMessageUnion::RotateCmd(msg) => {
+      msg.rotations.iter().any(|cmd| cmd.speed() != 0.0)
+    }
+    _ => false,
+  }
+}
+
+/// Runs queued transforms one at a time, so results come out in the order
+/// commands went in.
+async fn run_transforms(mut jobs: mpsc::UnboundedReceiver<TransformJob>) {
+  while let Some(job) = jobs.recv().await {
+    let TransformJob {
+      scripts,
+      message,
+      done,
+    } = job;
+    let result = if has_speeds_to_transform(&message) {
+      let names = script_names(&scripts);
+      async_manager::spawn_blocking(move || transform_with(&scripts, message))
+        .await
+        .unwrap_or_else(|err| {
+          Err(
+            ButtplugDeviceError::DeviceScriptError(names, format!("could not run: {}", err)).into(),
+          )
+        })
+    } else {
+      Ok(message)
+    };
+    done(result);
+  }
+}
+
+pub(crate) struct DeviceScripts {
+  devices: Arc<DashMap<u32, Arc<ButtplugDevice>>>,
+  scripts: RwLock<Vec<Arc<CompiledScript>>>,
+  patterns: Arc<Runners>,
+  next_id: AtomicU32,
+  transform_sender: mpsc::UnboundedSender<TransformJob>,
+}
+
+impl DeviceScripts {
+  pub fn new(devices: Arc<DashMap<u32, Arc<ButtplugDevice>>>) -> Self {
+    let (transform_sender, transform_receiver) = mpsc::unbounded_channel();
+    async_manager::spawn_named(
+      "device script transforms",
+      run_transforms(transform_receiver),
+    )
+    .unwrap();
+    Self {
+      devices,
+      scripts: RwLock::new(vec![]),
+      patterns: Arc::new(DashMap::new()),
+      next_id: AtomicU32::new(0),
+      transform_sender,
+    }
+  }
+
+  /// Compiles and adds a script. Transforms run in the order their scripts
+  /// were added.
+  pub fn add(&self, script: DeviceScript) -> Result<(), DeviceScriptError> {
+    let mut scripts = self.scripts.write().unwrap();
+    if scripts
+      .iter()
+      .any(|compiled| compiled.script.name == script.name)
+    {
+      return Err(DeviceScriptError::DuplicateScript(script.name));
+    }
+    info!("Adding device script {}", script.name);
+    scripts.push(Arc::new(CompiledScript::compile(script)?));
+    Ok(())
+  }
+
+  /// Removes a script. Patterns already running from it keep running until
+  /// they're stopped.
+  pub fn remove(&self, name: &str) -> bool {
+    let mut scripts = self.scripts.write().unwrap();
+    let count = scripts.len();
+    scripts.retain(|compiled| compiled.script.name != name);
+    scripts.len() != count
+  }
+
+  fn scripts_for(&self, device: &ButtplugDevice) -> Vec<Arc<CompiledScript>> {
+    let device_name = device.name();
+    self
+      .scripts
+      .read()
+      .unwrap()
+      .iter()
+      .filter(|compiled| compiled.applies_to(&device_name))
+      .cloned()
+      .collect()
+  }
+
+  fn queue_transform(
+    &self,
+    scripts: Vec<Arc<CompiledScript>>,
+    message: ButtplugDeviceCommandMessageUnion,
+    done: Box<dyn FnOnce(TransformResult) + Send>,
+  ) {
+    let names = script_names(&scripts);
+    let job = TransformJob {
+      scripts,
+      message,
+      done,
+    };
+    if let Err(mpsc::error::SendError(job)) = self.transform_sender.send(job) {
+      (job.done)(Err(runner_shut_down(names)));
+    }
+  }
+
+  /// Runs the transforms for the device over a command.
+  pub fn transform(
+    &self,
+    device: &ButtplugDevice,
+    message: ButtplugDeviceCommandMessageUnion,
+  ) -> BoxFuture<'static, TransformResult> {
+    let scripts = self.scripts_for(device);
+    if scripts.is_empty() {
+      return Box::pin(future::ready(Ok(message)));
+    }
+    let names = script_names(&scripts);
+    let (sender, receiver) = oneshot::channel();
+    self.queue_transform(
+      scripts,
+      message,
+      Box::new(move |result| {
+        let _ = sender.send(result);
+      }),
+    );
+    Box::pin(async move {
+      receiver
+        .await
+        .unwrap_or_else(|_| Err(runner_shut_down(names)))
+    })
+  }
+
+  /// Runs the transforms for the device over a command, then sends it.
+  /// Commands are sent in the order this is called in, however long their
+  /// transforms take.
+  pub fn send(
+    &self,
+    device_index: u32,
+    device: Arc<ButtplugDevice>,
+    message: ButtplugDeviceCommandMessageUnion,
+  ) -> ButtplugDeviceResultFuture {
+    let scripts = self.scripts_for(&device);
+    if scripts.is_empty() {
+      return device.parse_message_instrumented(device_index, message);
+    }
+    let names = script_names(&scripts);
+    let (sender, receiver) = oneshot::channel();
+    self.queue_transform(
+      scripts,
+      message,
+      Box::new(move |result| {
+        let send: ButtplugDeviceResultFuture = match result {
+          Ok(message) => device.parse_message_instrumented(device_index, message),
+          Err(err) => Box::pin(future::ready(Err(err))),
+        };
+        let _ = sender.send(send);
+      }),
+    );
+    Box::pin(async move {
+      match receiver.await {
+        Ok(send) => send.await,
+        Err(_) => Err(runner_shut_down(names)),
+      }
+    })
+  }
+
+  /// Starts calling the script's `pattern` function for a device, and sends
+  /// what it returns as VibrateCmds. Returns the pattern's id, for stopping
+  /// it later.
+  pub fn start_pattern(
+    &self,
+    device_index: u32,
+    script_name: &str,
+  ) -> Result<u32, DeviceScriptError> {
+    let compiled = self
+      .scripts
+      .read()
+      .unwrap()
+      .iter()
+      .find(|compiled| compiled.script.name == script_name)
+      .cloned()
+      .ok_or_else(|| DeviceScriptError::UnknownScript(script_name.to_owned()))?;
+    if !compiled.has_function("pattern", 1) {
+      return Err(DeviceScriptError::NoPatternFunction(script_name.to_owned()));
+    }
+    let device = self
+      .devices
+      .get(&device_index)
+      .map(|device| device.value().clone())
+      .ok_or(DeviceScriptError::DeviceNotAvailable(device_index))?;
+    let feature_count = device
+      .message_attributes()
+      .get(&ButtplugDeviceMessageType::VibrateCmd)
+      .and_then(|attrs| attrs.feature_count)
+      .ok_or(DeviceScriptError::NoVibrators(device_index))?;
+    let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+    let token = CancellationToken::new();
+    self.patterns.insert(
+      id,
+      Runner {
+        token: token.clone(),
+        device_address: device.address().to_owned(),
+      },
+    );
+    info!(
+      "Starting device script {} pattern {} on device {}",
+      script_name, id, device_index
+    );
+    let pattern = ScriptPattern {
+      compiled,
+      device: Arc::downgrade(&device),
+      device_index,
+      feature_count,
+    };
+    let patterns = self.patterns.clone();
+    async_manager::spawn_named("device script pattern", async move {
+      pattern.run(token).await;
+      patterns.remove(&id);
+    })
+    .unwrap();
+    Ok(id)
+  }
+
+  /// Stops a pattern and its vibration. Returns false if no pattern with the
+  /// id is running.
+  pub fn stop_pattern(&self, id: u32) -> bool {
+    match self.patterns.remove(&id) {
+      Some((_, runner)) => {
+        runner.token.cancel();
+        true
+      }
+      None => false,
+    }
+  }
+
+  /// The running patterns, for stopping them from elsewhere.
+  pub fn runners(&self) -> &Arc<Runners> {
+    &self.patterns
+  }
+
+  /// Stops every running pattern.
+  pub fn stop_all_patterns(&self) {
+    cancel_runners(&self.patterns, None);
+  }
+
+  /// Stops the patterns running on devices with addresses in `addresses`,
+  /// leaving the rest running.
+  pub fn stop_patterns_for_devices(&self, addresses: &DashSet<String>) {
+    cancel_runners(&self.patterns, Some(addresses));
+  }
+}
+
+fn transform_command(
+  compiled: &CompiledScript,
+  message: ButtplugDeviceCommandMessageUnion,
+) -> Result<ButtplugDeviceCommandMessageUnion, DeviceScriptError> {
+  match message {
+    ButtplugDeviceCommandMessageUnion::VibrateCmd(msg) => {
+      let speeds = msg
+        .speeds()
+        .iter()
+        .map(|cmd| {
+          let speed = compiled.transform_speed("transform_vibrate", cmd.index(), cmd.speed())?;
+          Ok(VibrateSubcommand::new(cmd.index(), speed))
+        })
+        .collect::<Result<Vec<_>, DeviceScriptError>>()?;
+      let mut transformed = VibrateCmd::new(msg.device_index(), speeds);
+      transformed.set_id(msg.id());
+      Ok(transformed.into())
+    }
+    ButtplugDeviceCommandMessageUnion::RotateCmd(msg) => {
+      let rotations = msg
+        .rotations
+        .iter()
+        .map(|cmd| {
+          let speed = compiled.transform_speed("transform_rotate", cmd.index(), cmd.speed())?;
+          Ok(RotationSubcommand::new(cmd.index(), speed, cmd.clockwise()))
+        })
+        .collect::<Result<Vec<_>, DeviceScriptError>>()?;
+      let mut transformed = RotateCmd::new(msg.device_index, rotations);
+      transformed.set_id(msg.id());
+      Ok(transformed.into())
+    }
+    message => Ok(message),
+  }
+}
+
+struct ScriptPattern {
+  compiled: Arc<CompiledScript>,
+  // Patterns don't keep devices around after they disconnect.
+  device: Weak<ButtplugDevice>,
+  device_index: u32,
+  feature_count: u32,
+}
+
+impl ScriptPattern {
+  fn vibrate_cmd(&self, speeds: Vec<f64>) -> ButtplugDeviceCommandMessageUnion {
+    VibrateCmd::new(
+      self.device_index,
+      speeds
+        .into_iter()
+        .enumerate()
+        .map(|(index, speed)| VibrateSubcommand::new(index as u32, speed))
+        .collect(),
+    )
+    .into()
+  }
+
+  /// Speeds for each vibrator from what `pattern` returned, or None if it
+  /// returned nothing.
+  fn speeds(&self, result: Dynamic) -> Result<Option<Vec<f64>>, DeviceScriptError> {
+    if result.is_unit() {
+      return Ok(None);
+    }
+    if let Some(speed) = to_speed(&result) {
+      return Ok(Some(vec![speed; self.feature_count as usize]));
+    }
+    let invalid = || {
+      self
+        .compiled
+        .runtime_error("pattern has to return a number, or an array of numbers.".to_owned())
+    };
+    let speeds = result.try_cast::<Array>().ok_or_else(invalid)?;
+    speeds
+      .iter()
+      .take(self.feature_count as usize)
+      .map(|speed| to_speed(speed).ok_or_else(invalid))
+      .collect::<Result<Vec<f64>, DeviceScriptError>>()
+      .map(Some)
+  }
+
+  async fn run(self, token: CancellationToken) {
+    let start = Instant::now();
+    loop {
+      let elapsed_ms = start.elapsed().as_millis() as i64;
+      let compiled = self.compiled.clone();
+      let speeds = async_manager::spawn_blocking(move || compiled.call("pattern", (elapsed_ms,)))
+        .await
+        .unwrap_or_else(|err| {
+          Err(
+            self
+              .compiled
+              .runtime_error(format!("could not run: {}", err)),
+          )
+        })
+        .and_then(|result| self.speeds(result));
+      // The pattern may have been stopped while the script ran, and the stop
+      // mustn't be followed by one more step.
+      if token.is_cancelled() {
+        break;
+      }
+      match speeds {
+        Ok(Some(speeds)) => {
+          let device = match self.device.upgrade() {
+            Some(device) => device,
+            None => break,
+          };
+          if let Err(err) = device.parse_message(self.vibrate_cmd(speeds)).await {
+            error!("Device script pattern could not set vibration: {:?}", err);
+          }
+        }
+        Ok(None) => {}
+        Err(err) => {
+          error!("Stopping device script pattern: {}", err);
+          break;
+        }
+      }
+      select! {
+        _ = async_manager::sleep(self.compiled.script.pattern_interval).fuse() => {},
+        _ = token.cancelled().fuse() => break,
+      }
+    }
+    info!("Device script pattern stopped.");
+    if let Some(device) = self.device.upgrade() {
+      let stop = self.vibrate_cmd(vec![0.0; self.feature_count as usize]);
+      if let Err(err) = device.parse_message(stop).await {
+        error!("Device script pattern could not stop vibration: {:?}", err);
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::{DeviceScript, DeviceScriptError, DeviceScripts, ScriptLimits};
+  use crate::{
+    core::messages::{ButtplugDeviceCommandMessageUnion, VibrateCmd, VibrateSubcommand},
+    device::{DeviceImplCommand, DeviceWriteCmd, Endpoint},
+    test::{check_test_recv_empty, check_test_recv_value, new_bluetoothle_test_device},
+    util::async_manager,
+  };
+  use dashmap::DashMap;
+  use std::{sync::Arc, time::Duration};
+
+  fn vibrate(speed: f64) -> ButtplugDeviceCommandMessageUnion {
+    VibrateCmd::new(0, vec![VibrateSubcommand::new(0, speed)]).into()
+  }
+
+  #[test]
+  fn test_script_transforms() {
+    async_manager::block_on(async {
+      let (device, _) = new_bluetoothle_test_device("Massage Demo").await.unwrap();
+      let scripts = DeviceScripts::new(Arc::new(DashMap::new()));
+      assert!(matches!(
+        scripts.add(DeviceScript::new("broken", "fn transform_vibrate(")),
+        Err(DeviceScriptError::CompileError(..))
+      ));
+      scripts
+        .add(DeviceScript::new(
+          "half",
+          "fn transform_vibrate(index, speed) { speed / 2.0 }",
+        ))
+        .unwrap();
+      scripts
+        .add(
+          DeviceScript::new("other device", "fn transform_vibrate(index, speed) { 0 }")
+            .device_name("Not The Massage Demo"),
+        )
+        .unwrap();
+      assert_eq!(
+        scripts.add(DeviceScript::new("half", "")),
+        Err(DeviceScriptError::DuplicateScript("half".to_owned()))
+      );
+      assert_eq!(
+        scripts.transform(&device, vibrate(0.8)).await.unwrap(),
+        vibrate(0.4)
+      );
+
+      // Scripts that run away are stopped, and the command is refused.
+      scripts
+        .add(
+          DeviceScript::new("spin", "fn transform_vibrate(index, speed) { loop {} }").limits(
+            ScriptLimits {
+              max_operations: 1000,
+              ..Default::default()
+            },
+          ),
+        )
+        .unwrap();
+      assert!(scripts.transform(&device, vibrate(0.8)).await.is_err());
+      assert!(scripts.remove("spin"));
+      assert!(!scripts.remove("spin"));
+      assert_eq!(
+        scripts.transform(&device, vibrate(0.8)).await.unwrap(),
+        vibrate(0.4)
+      );
+
+      // Zero speeds are stops, and never reach scripts.
+      scripts
+        .add(DeviceScript::new(
+          "always on",
+          "fn transform_vibrate(index, speed) { 0.5 }",
+        ))
+        .unwrap();
+      assert_eq!(
+        scripts.transform(&device, vibrate(0.0)).await.unwrap(),
+        vibrate(0.0)
+      );
+    });
+  }
+
+  #[test]
+  fn test_script_pattern() {
+    async_manager::block_on(async {
+      let (device, test_device) = new_bluetoothle_test_device("Massage Demo").await.unwrap();
+      let command_receiver = test_device.get_endpoint_receiver(&Endpoint::Tx).unwrap();
+      let devices = Arc::new(DashMap::new());
+      devices.insert(0, Arc::new(device));
+      let scripts = DeviceScripts::new(devices);
+      scripts
+        .add(
+          DeviceScript::new("steady", "fn pattern(elapsed_ms) { [0.5] }")
+            .pattern_interval(Duration::from_millis(10)),
+        )
+        .unwrap();
+      scripts
+        .add(DeviceScript::new("no pattern", "fn other() {}"))
+        .unwrap();
+      assert_eq!(
+        scripts.start_pattern(0, "no pattern"),
+        Err(DeviceScriptError::NoPatternFunction(
+          "no pattern".to_owned()
+        ))
+      );
+      assert_eq!(
+        scripts.start_pattern(1, "steady"),
+        Err(DeviceScriptError::DeviceNotAvailable(1))
+      );
+      let id = scripts.start_pattern(0, "steady").unwrap();
+      async_manager::sleep(Duration::from_millis(50)).await;
+      check_test_recv_value(
+        &command_receiver,
+        DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![0xF1, 64], false)),
+      );
+      assert!(scripts.stop_pattern(id));
+      assert!(!scripts.stop_pattern(id));
+      async_manager::sleep(Duration::from_millis(50)).await;
+      check_test_recv_value(
+        &command_receiver,
+        DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![0xF1, 0], false)),
+      );
+
+      scripts.start_pattern(0, "steady").unwrap();
+      async_manager::sleep(Duration::from_millis(50)).await;
+      check_test_recv_value(
+        &command_receiver,
+        DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![0xF1, 64], false)),
+      );
+      scripts.stop_all_patterns();
+      async_manager::sleep(Duration::from_millis(50)).await;
+      check_test_recv_value(
+        &command_receiver,
+        DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![0xF1, 0], false)),
+      );
+      assert!(check_test_recv_empty(&command_receiver));
+    });
+  }
+}
@@ -0,0 +1,283 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2021 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Splitting one physical device into multiple logical devices.
+//!
+//! Devices listed in the "split-devices" section of the user device
+//! configuration are exposed to clients as one device per feature, each with a
+//! single vibrator, rotator, or linear actuator. This lets clients that only
+//! know SingleMotorVibrateCmd control each motor of a multi-motor toy on its
+//! own.
+
+use super::device_group::{DeviceGroupProtocol, VirtualDeviceImpl};
+use crate::{
+  core::{
+    errors::{ButtplugDeviceError, ButtplugError},
+    messages::{
+      self, ButtplugDeviceCommandMessageUnion, ButtplugDeviceMessageType, DeviceMessageAttributes,
+      DeviceMessageAttributesMap, LinearCmd, RotateCmd, RotationSubcommand, VectorSubcommand,
+      VibrateCmd, VibrateSubcommand,
+    },
+  },
+  device::{
+    configuration_manager::DeviceProtocolConfiguration,
+    protocol::{ButtplugProtocol, ButtplugProtocolCommandHandler, ButtplugProtocolProperties},
+    ButtplugDevice, ButtplugDeviceResultFuture, DeviceImpl,
+  },
+};
+use futures::future::{self, BoxFuture};
+use std::sync::Arc;
+
+/// Splits a device into one logical device per feature. Each logical device
+/// gets an address (and device ID, if the physical device has one) derived
+/// from the physical device's, so indexes can be reused across reconnects.
+pub fn split_device(device: Arc<ButtplugDevice>) -> Vec<ButtplugDevice> {
+  let attributes = device.message_attributes();
+  let mut split_devices = vec![];
+  for (message_type, feature_name) in [
+    (ButtplugDeviceMessageType::VibrateCmd, "Vibrator"),
+    (ButtplugDeviceMessageType::RotateCmd, "Rotator"),
+    (ButtplugDeviceMessageType::LinearCmd, "Linear"),
+  ]
+  .iter()
+  {
+    let attrs = if let Some(attrs) = attributes.get(message_type) {
+      attrs
+    } else {
+      continue;
+    };
+    for feature_index in 0..attrs.feature_count.unwrap_or(0) {
+      let name = format!("{} ({} {})", device.name(), feature_name, feature_index + 1);
+      let address = format!(
+        "{}/{}/{}",
+        device.address(),
+        feature_name.to_lowercase(),
+        feature_index
+      );
+      let mut message_attributes = DeviceMessageAttributesMap::new();
+      message_attributes.insert(
+        *message_type,
+        DeviceMessageAttributes {
+          feature_count: Some(1),
+          step_count: attrs
+            .step_count
+            .as_ref()
+            .and_then(|steps| steps.get(feature_index as usize))
+            .map(|step| vec![*step]),
+          max_duration: attrs
+            .max_duration
+            .as_ref()
+            .and_then(|durations| durations.get(feature_index as usize))
+            .map(|duration| vec![*duration]),
+          features: attrs
+            .features_for(*message_type)
+            .and_then(|descriptors| descriptors.into_iter().nth(feature_index as usize))
+            .map(|descriptor| vec![descriptor]),
+          ..Default::default()
+        },
+      );
+      message_attributes.insert(
+        ButtplugDeviceMessageType::StopDeviceCmd,
+        DeviceMessageAttributes::default(),
+      );
+      let protocol = SplitDeviceProtocol {
+        name: name.clone(),
+        message_attributes,
+        stop_commands: vec![],
+        device: device.clone(),
+        message_type: *message_type,
+        feature_index,
+      };
+      let device_impl =
+        DeviceImpl::new(&name, &address, &[], Box::new(VirtualDeviceImpl::default()));
+      // Derive the logical device's ID from the physical one too, so split
+      // devices keep their indexes on platforms with unstable addresses.
+      if let Some(device_id) = device.device_id() {
+        device_impl.set_device_id(&format!(
+          "{}/{}/{}",
+          device_id,
+          feature_name.to_lowercase(),
+          feature_index
+        ));
+      }
+      split_devices.push(ButtplugDevice::new(
+        Box::new(protocol),
+        Arc::new(device_impl),
+      ));
+    }
+  }
+  split_devices
+}
+
+#[derive(ButtplugProtocolProperties)]
+pub struct SplitDeviceProtocol {
+  name: String,
+  message_attributes: DeviceMessageAttributesMap,
+  stop_commands: Vec<ButtplugDeviceCommandMessageUnion>,
+  device: Arc<ButtplugDevice>,
+  message_type: ButtplugDeviceMessageType,
+  feature_index: u32,
+}
+
+impl SplitDeviceProtocol {
+  fn check_index(&self, index: u32) -> Result<(), ButtplugDeviceError> {
+    if index != 0 {
+      Err(ButtplugDeviceError::DeviceFeatureIndexError(1, index))
+    } else {
+      Ok(())
+    }
+  }
+}
+
+impl ButtplugProtocol for SplitDeviceProtocol {
+  fn try_create(
+    _device_impl: Arc<DeviceImpl>,
+    _config: DeviceProtocolConfiguration,
+  ) -> BoxFuture<'static, Result<Box<dyn ButtplugProtocol>, ButtplugError>> {
+    // Split devices can only be made from an already connected device, via
+    // split_device().
+    Box::pin(future::ready(Err(
+      ButtplugDeviceError::ProtocolNotImplemented("split device".to_owned()).into(),
+    )))
+  }
+
+  fn new_protocol(
+    name: &str,
+    message_attributes: DeviceMessageAttributesMap,
+  ) -> Box<dyn ButtplugProtocol> {
+    // With no device to split, all we can make is an empty group, which
+    // doesn't support any messages.
+    DeviceGroupProtocol::new_protocol(name, message_attributes)
+  }
+
+  fn handled_message_types() -> &'static [ButtplugDeviceMessageType] {
+    &[
+      ButtplugDeviceMessageType::VibrateCmd,
+      ButtplugDeviceMessageType::RotateCmd,
+      ButtplugDeviceMessageType::LinearCmd,
+    ]
+  }
+}
+
+impl ButtplugProtocolCommandHandler for SplitDeviceProtocol {
+  fn handle_stop_device_cmd(
+    &self,
+    _device: Arc<DeviceImpl>,
+    _message: messages::StopDeviceCmd,
+  ) -> ButtplugDeviceResultFuture {
+    // Only stop our own feature, since the other features of the physical
+    // device belong to other logical devices.
+    match self.message_type {
+      ButtplugDeviceMessageType::VibrateCmd => self.device.parse_message(
+        VibrateCmd::new(0, vec![VibrateSubcommand::new(self.feature_index, 0.0)]).into(),
+      ),
+      ButtplugDeviceMessageType::RotateCmd => self.device.parse_message(
+        RotateCmd::new(
+          0,
+          vec![RotationSubcommand::new(self.feature_index, 0.0, false)],
+        )
+        .into(),
+      ),
+      // Linear movements end on their own, there's nothing to stop.
+      _ => Box::pin(future::ready(Ok(messages::Ok::default().into()))),
+    }
+  }
+
+  fn handle_vibrate_cmd(
+    &self,
+    _device: Arc<DeviceImpl>,
+    message: messages::VibrateCmd,
+  ) -> ButtplugDeviceResultFuture {
+    let mut cmds = vec![];
+    for cmd in message.speeds() {
+      if let Err(err) = self.check_index(cmd.index()) {
+        return err.into();
+      }
+      cmds.push(VibrateSubcommand::new(self.feature_index, cmd.speed()));
+    }
+    self.device.parse_message(VibrateCmd::new(0, cmds).into())
+  }
+
+  fn handle_rotate_cmd(
+    &self,
+    _device: Arc<DeviceImpl>,
+    message: messages::RotateCmd,
+  ) -> ButtplugDeviceResultFuture {
+    let mut cmds = vec![];
+    for cmd in &message.rotations {
+      if let Err(err) = self.check_index(cmd.index()) {
+        return err.into();
+      }
+      cmds.push(RotationSubcommand::new(
+        self.feature_index,
+        cmd.speed(),
+        cmd.clockwise(),
+      ));
+    }
+    self.device.parse_message(RotateCmd::new(0, cmds).into())
+  }
+
+  fn handle_linear_cmd(
+    &self,
+    _device: Arc<DeviceImpl>,
+    message: messages::LinearCmd,
+  ) -> ButtplugDeviceResultFuture {
+    let mut cmds = vec![];
+    for cmd in message.vectors() {
+      if let Err(err) = self.check_index(cmd.index) {
+        return err.into();
+      }
+      cmds.push(VectorSubcommand::new(
+        self.feature_index,
+        cmd.duration,
+        cmd.position,
+      ));
+    }
+    self.device.parse_message(LinearCmd::new(0, cmds).into())
+  }
+}
+
+#[cfg(all(test, feature = "server"))]
+mod test {
+  use super::split_device;
+  use crate::{
+    core::messages::{SingleMotorVibrateCmd, StopDeviceCmd},
+    device::{DeviceImplCommand, DeviceWriteCmd, Endpoint},
+    test::{check_test_recv_empty, check_test_recv_value, new_bluetoothle_test_device},
+    util::async_manager,
+  };
+  use std::sync::Arc;
+
+  #[test]
+  pub fn test_split_device() {
+    async_manager::block_on(async move {
+      let (device, test_device) = new_bluetoothle_test_device("Massage Demo").await.unwrap();
+      let command_receiver = test_device.get_endpoint_receiver(&Endpoint::Tx).unwrap();
+      let split = split_device(Arc::new(device));
+      assert_eq!(split.len(), 2);
+      let second_motor = &split[1];
+      assert_eq!(second_motor.name(), "Aneros Vivi (Vibrator 2)");
+      second_motor
+        .parse_message(SingleMotorVibrateCmd::new(0, 0.5).into())
+        .await
+        .unwrap();
+      check_test_recv_value(
+        &command_receiver,
+        DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![0xF2, 64], false)),
+      );
+      assert!(check_test_recv_empty(&command_receiver));
+      second_motor
+        .parse_message(StopDeviceCmd::new(0).into())
+        .await
+        .unwrap();
+      check_test_recv_value(
+        &command_receiver,
+        DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![0xF2, 0], false)),
+      );
+    });
+  }
+}
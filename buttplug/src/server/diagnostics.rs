@@ -0,0 +1,83 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2022 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Checks for server setups that work, but probably aren't what was meant.
+//!
+//! None of these stop the server from being created. Call
+//! [ButtplugServer::validate][super::ButtplugServer::validate] once comm
+//! managers have been added to get the full report, e.g. to show in a
+//! settings UI.
+
+use super::ButtplugServerOptions;
+use displaydoc::Display;
+
+/// Shortest ping time that gives clients a fair chance, in milliseconds.
+/// Anything lower and clients on a busy network will ping out.
+pub const MIN_REASONABLE_PING_TIME: u64 = 100;
+
+/// A warning about the server setup.
+#[derive(Debug, Display, Clone, PartialEq, Eq)]
+pub enum ServerDiagnostic {
+  /// Max ping time of {0}ms is too short for clients to keep up with, and they'll likely be disconnected.
+  PingTimeTooLow(u64),
+  /// Session stop warning time ({0}ms) isn't shorter than the max session time ({1}ms), so the warning is sent as soon as the session starts.
+  SessionStopWarningTooLong(u64, u64),
+  /// User device configuration has settings for protocol {0}, which this server doesn't support. They're ignored.
+  UnknownUserProtocol(String),
+  /// Raw messages are allowed, but no comm managers have been added, so there are no devices to send them to.
+  RawMessagesWithoutCommManagers,
+  /// No comm managers have been added, so scanning won't find any devices.
+  NoCommManagers,
+}
+
+impl ButtplugServerOptions {
+  /// Checks the options on their own. Checks that need the device
+  /// configuration or comm managers are only done by
+  /// [ButtplugServer::validate][super::ButtplugServer::validate].
+  pub fn validate(&self) -> Vec<ServerDiagnostic> {
+    let mut diagnostics = vec![];
+    if self.max_ping_time > 0 && self.max_ping_time < MIN_REASONABLE_PING_TIME {
+      diagnostics.push(ServerDiagnostic::PingTimeTooLow(self.max_ping_time));
+    }
+    if self.max_session_time > 0 && self.session_stop_warning_time >= self.max_session_time {
+      diagnostics.push(ServerDiagnostic::SessionStopWarningTooLong(
+        self.session_stop_warning_time,
+        self.max_session_time,
+      ));
+    }
+    diagnostics
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::{ServerDiagnostic, MIN_REASONABLE_PING_TIME};
+  use crate::server::ButtplugServerOptions;
+
+  #[test]
+  fn test_option_diagnostics() {
+    assert!(ButtplugServerOptions::default().validate().is_empty());
+    let options = ButtplugServerOptions {
+      max_ping_time: 5,
+      max_session_time: 30000,
+      session_stop_warning_time: 60000,
+      ..Default::default()
+    };
+    assert_eq!(
+      options.validate(),
+      vec![
+        ServerDiagnostic::PingTimeTooLow(5),
+        ServerDiagnostic::SessionStopWarningTooLong(60000, 30000)
+      ]
+    );
+    let options = ButtplugServerOptions {
+      max_ping_time: MIN_REASONABLE_PING_TIME,
+      ..Default::default()
+    };
+    assert!(options.validate().is_empty());
+  }
+}
@@ -0,0 +1,128 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2022 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Stopping every device from outside the client connection, e.g. from a
+//! hardware panic button.
+//!
+//! Client messages go through middleware, ping checks and whatever queueing
+//! the connector does, and a busy or misbehaving client can keep all of that
+//! saturated. [EmergencyStop] skips it: a trigger goes straight to a task of
+//! its own, which stops pressure loops and device script patterns first, so
+//! they can't turn devices back on, then sends StopDeviceCmd to every
+//! device, whatever the device filter, groups or scheduled stops say.
+//!
+//! The stops don't jump any queue. A command that's already being written to
+//! a device is written first, and the stop follows it.
+//!
+//! This only stops devices. The client stays connected, and can start them
+//! again; disconnect it if that's not what you want.
+
+use crate::{core::messages, device::ButtplugDevice, util::async_manager};
+use dashmap::{DashMap, DashSet};
+use futures::future;
+use std::sync::{Arc, Weak};
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+
+type DeviceMap = DashMap<u32, Arc<ButtplugDevice>>;
+
+/// A task that drives a device on its own, like a pressure loop.
+pub(crate) struct Runner {
+  pub token: CancellationToken,
+  /// Address of the device the task sends commands to.
+  pub device_address: String,
+}
+
+/// Running tasks that drive devices on their own, by id.
+pub(crate) type Runners = DashMap<u32, Runner>;
+
+/// Cancels runners and forgets them. If `only` is given, runners driving
+/// devices with other addresses are left alone.
+pub(crate) fn cancel_runners(runners: &Runners, only: Option<&DashSet<String>>) {
+  runners.retain(|_, runner| {
+    if only.map_or(false, |only| !only.contains(&runner.device_address)) {
+      return true;
+    }
+    runner.token.cancel();
+    false
+  });
+}
+
+/// Handle for stopping all devices of a server. Cheap to clone, and doesn't
+/// keep the server alive, so it can be handed to whatever watches the panic
+/// button.
+#[derive(Clone)]
+pub struct EmergencyStop {
+  trigger_sender: mpsc::UnboundedSender<()>,
+}
+
+impl EmergencyStop {
+  /// Starts the task that stops devices. Has to be called from within the
+  /// async runtime, unlike [EmergencyStop::trigger].
+  pub(crate) fn new(devices: &Arc<DeviceMap>, runners: &[&Arc<Runners>]) -> Self {
+    let (trigger_sender, trigger_receiver) = mpsc::unbounded_channel();
+    let runners = runners
+      .iter()
+      .map(|runners| Arc::downgrade(*runners))
+      .collect();
+    async_manager::spawn_named(
+      "emergency stop",
+      run_emergency_stop(Arc::downgrade(devices), runners, trigger_receiver),
+    )
+    .unwrap();
+    Self { trigger_sender }
+  }
+
+  /// Stops all devices. Never blocks and doesn't need an async runtime, so
+  /// it's safe to call from input callbacks, signal handling threads and the
+  /// like. Presses that come in while a stop is being sent are folded into
+  /// one more stop once it's done.
+  pub fn trigger(&self) {
+    warn!("Emergency stop triggered, stopping all devices.");
+    if self.trigger_sender.send(()).is_err() {
+      error!("Emergency stop triggered after the server shut down.");
+    }
+  }
+}
+
+async fn run_emergency_stop(
+  devices: Weak<DeviceMap>,
+  runners: Vec<Weak<Runners>>,
+  mut trigger_receiver: mpsc::UnboundedReceiver<()>,
+) {
+  while trigger_receiver.recv().await.is_some() {
+    // Anything queued up since is covered by this stop.
+    while trigger_receiver.try_recv().is_ok() {}
+    for runners in runners.iter().filter_map(Weak::upgrade) {
+      cancel_runners(&runners, None);
+    }
+    let devices = match devices.upgrade() {
+      Some(devices) => devices,
+      None => {
+        error!("Emergency stop triggered after the server shut down.");
+        return;
+      }
+    };
+    let fut_vec: Vec<_> = devices
+      .iter()
+      .map(|dev| {
+        dev
+          .value()
+          .parse_message_instrumented(*dev.key(), messages::StopDeviceCmd::new(1).into())
+      })
+      .collect();
+    // Don't hold the device map past the stop, so it can go away with the
+    // server.
+    drop(devices);
+    for result in future::join_all(fut_vec).await {
+      if let Err(err) = result {
+        error!("Error stopping device for emergency stop: {}", err);
+      }
+    }
+    info!("Emergency stop sent to all devices.");
+  }
+}
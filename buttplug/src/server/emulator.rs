@@ -0,0 +1,160 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2022 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Virtual devices for language binding test suites.
+//!
+//! With [ButtplugServerOptions::emulated_devices_json][super::ButtplugServerOptions::emulated_devices_json]
+//! set, the server creates a fixed set of virtual devices when it starts, no
+//! hardware or comm managers needed. Devices are registered in the order
+//! they're listed, so on a fresh server they get indexes 0, 1, 2, etc, and
+//! device lists wait until they're all registered, so every run sees the
+//! same devices. Scanning doesn't find them again, and they never disconnect.
+//!
+//! Devices are described by the Bluetooth LE name they'd advertise, which is
+//! matched against the device configuration like a real device would be:
+//!
+//! ```json
+//! {
+//!   "devices": [
+//!     { "name": "Massage Demo" },
+//!     { "name": "MV Crescendo", "address": "emulated-crescendo" }
+//!   ]
+//! }
+//! ```
+//!
+//! Commands sent to the devices are accepted and thrown away. Protocols that
+//! wait on replies from the device while connecting can't be emulated.
+
+use crate::{
+  core::errors::ButtplugDeviceError,
+  device::{
+    configuration_manager::{BluetoothLESpecifier, DeviceConfigurationManager, DeviceSpecifier},
+    ButtplugDevice,
+  },
+  test::{TestDeviceImplCreator, TestDeviceInternal},
+  util::async_manager,
+};
+use futures::future::poll_fn;
+use serde::Deserialize;
+use std::sync::Arc;
+
+/// A virtual device.
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct EmulatedDevice {
+  /// Bluetooth LE name of the device, used to find its protocol.
+  pub name: String,
+  /// Address of the device. Defaults to `emulated-device-<position in the
+  /// device list>`.
+  #[serde(default)]
+  pub address: Option<String>,
+}
+
+/// Virtual devices to create, in order.
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct EmulatorConfig {
+  pub devices: Vec<EmulatedDevice>,
+}
+
+impl EmulatorConfig {
+  pub fn from_json(json: &str) -> Result<Self, ButtplugDeviceError> {
+    serde_json::from_str(json).map_err(|err| {
+      ButtplugDeviceError::DeviceConfigurationFileError(format!(
+        "Invalid emulated device description: {}",
+        err
+      ))
+    })
+  }
+}
+
+/// Connects the emulated devices one at a time, in order. Devices that don't
+/// match a protocol, or fail to connect, are logged and skipped.
+pub(crate) async fn create_emulated_devices(
+  config: EmulatorConfig,
+  device_config_manager: Arc<DeviceConfigurationManager>,
+) -> Vec<Arc<ButtplugDevice>> {
+  let mut devices = vec![];
+  for (position, emulated) in config.devices.into_iter().enumerate() {
+    let address = emulated
+      .address
+      .unwrap_or_else(|| format!("emulated-device-{}", position));
+    let device_impl = Arc::new(TestDeviceInternal::new(&emulated.name, &address));
+    let creator = TestDeviceImplCreator::new(
+      DeviceSpecifier::BluetoothLE(BluetoothLESpecifier::new_from_device(&emulated.name)),
+      device_impl.clone(),
+    );
+    match ButtplugDevice::try_create_device(device_config_manager.clone(), Box::new(creator)).await
+    {
+      Ok(Some(device)) => {
+        discard_commands(&device_impl);
+        devices.push(Arc::new(device));
+      }
+      Ok(None) => error!(
+        "Emulated device {} doesn't match any protocol, skipping it.",
+        emulated.name
+      ),
+      Err(err) => error!(
+        "Emulated device {} could not connect, skipping it: {}",
+        emulated.name, err
+      ),
+    }
+  }
+  devices
+}
+
+/// Empties the device's endpoint channels as commands come in, so they never
+/// fill up.
+fn discard_commands(device_impl: &TestDeviceInternal) {
+  for endpoint in device_impl.endpoints() {
+    // Only None if the endpoint doesn't exist, and we just got it from the
+    // device.
+    let receiver = device_impl.get_endpoint_receiver(&endpoint).unwrap();
+    async_manager::spawn(async move {
+      while poll_fn(|cx| receiver.lock().unwrap().poll_recv(cx))
+        .await
+        .is_some()
+      {}
+    })
+    .unwrap();
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::{create_emulated_devices, EmulatedDevice, EmulatorConfig};
+  use crate::{device::configuration_manager::DeviceConfigurationManager, util::async_manager};
+  use std::sync::Arc;
+
+  #[test]
+  fn test_create_emulated_devices() {
+    let config = EmulatorConfig::from_json(
+      r#"
+      {
+        "devices": [
+          { "name": "Massage Demo", "address": "emulated-vivi" },
+          { "name": "Not A Real Device" },
+          { "name": "Massage Demo" }
+        ]
+      }
+      "#,
+    )
+    .unwrap();
+    assert_eq!(
+      config.devices[0],
+      EmulatedDevice {
+        name: "Massage Demo".to_owned(),
+        address: Some("emulated-vivi".to_owned()),
+      }
+    );
+    async_manager::block_on(async {
+      let devices =
+        create_emulated_devices(config, Arc::new(DeviceConfigurationManager::default())).await;
+      let addresses: Vec<&str> = devices.iter().map(|device| device.address()).collect();
+      assert_eq!(addresses, vec!["emulated-vivi", "emulated-device-2"]);
+    });
+    assert!(EmulatorConfig::from_json(r#"{ "devices": [{}] }"#).is_err());
+  }
+}
@@ -0,0 +1,257 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2021 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Engine control, for applications that wrap a server.
+//!
+//! GUIs that run a server on behalf of the user (Intiface Desktop being the
+//! main example) need to be able to start/stop scanning, drop devices, and
+//! shut the engine down, regardless of what the connected client is doing.
+//! Rather than having those commands interleaved with the Buttplug client
+//! protocol, this module provides a small management protocol that runs over
+//! its own channel.
+
+use super::ButtplugServer;
+use crate::{
+  core::messages::ButtplugServerMessage,
+  util::{async_manager, build_info::BuildInfo},
+};
+use futures::{
+  future::{self, BoxFuture},
+  select, FutureExt, Stream, StreamExt,
+};
+#[cfg(feature = "serialize-json")]
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tokio::sync::{mpsc, Notify};
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serialize-json", derive(Serialize, Deserialize))]
+pub enum EngineControlCommand {
+  StartScanning,
+  StopScanning,
+  StopAllDevices,
+  DisconnectDevice(u32),
+  /// Asks which build of the library the engine is running, answered with a
+  /// BuildInfo event.
+  RequestBuildInfo,
+  /// Stops scanning and all devices, then shuts down the engine.
+  Shutdown,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serialize-json", derive(Serialize, Deserialize))]
+pub struct EngineControlRequest {
+  pub id: u32,
+  pub command: EngineControlCommand,
+}
+
+impl EngineControlRequest {
+  pub fn new(id: u32, command: EngineControlCommand) -> Self {
+    Self { id, command }
+  }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serialize-json", derive(Serialize, Deserialize))]
+pub enum EngineControlEvent {
+  /// Request with the given id succeeded.
+  Ok(u32),
+  /// Request with the given id failed, with an error description.
+  Error(u32, String),
+  /// Reply to a RequestBuildInfo request with the given id.
+  BuildInfo(u32, BuildInfo),
+  DeviceAdded(u32, String),
+  DeviceRemoved(u32),
+  ScanningFinished,
+  /// Sent once a Shutdown request has been processed. No further events will
+  /// be sent after this.
+  ShuttingDown,
+}
+
+pub struct EngineControl {
+  server: Arc<ButtplugServer>,
+  shutdown_notifier: Option<Arc<Notify>>,
+}
+
+impl EngineControl {
+  pub fn new(server: Arc<ButtplugServer>) -> Self {
+    Self {
+      server,
+      shutdown_notifier: None,
+    }
+  }
+
+  /// Used by the remote server, so that shutdown also ends its connector
+  /// loop.
+  pub(super) fn new_with_shutdown_notifier(
+    server: Arc<ButtplugServer>,
+    shutdown_notifier: Arc<Notify>,
+  ) -> Self {
+    Self {
+      server,
+      shutdown_notifier: Some(shutdown_notifier),
+    }
+  }
+
+  pub fn handle_request(
+    &self,
+    request: EngineControlRequest,
+  ) -> BoxFuture<'static, EngineControlEvent> {
+    let id = request.id;
+    let fut = match request.command {
+      EngineControlCommand::StartScanning => self.server.start_scanning(),
+      EngineControlCommand::StopScanning => self.server.stop_scanning(),
+      EngineControlCommand::StopAllDevices => self.server.stop_all_devices(),
+      EngineControlCommand::DisconnectDevice(device_index) => {
+        self.server.disconnect_device(device_index)
+      }
+      EngineControlCommand::RequestBuildInfo => {
+        return Box::pin(future::ready(EngineControlEvent::BuildInfo(
+          id,
+          BuildInfo::current(),
+        )));
+      }
+      EngineControlCommand::Shutdown => {
+        let server = self.server.clone();
+        let shutdown_notifier = self.shutdown_notifier.clone();
+        return Box::pin(async move {
+          if let Err(err) = server.disconnect().await {
+            error!("Error while shutting down engine: {:?}", err);
+          }
+          if let Some(notifier) = shutdown_notifier {
+            notifier.notify_waiters();
+          }
+          EngineControlEvent::ShuttingDown
+        });
+      }
+    };
+    Box::pin(async move {
+      match fut.await {
+        Ok(_) => EngineControlEvent::Ok(id),
+        Err(err) => EngineControlEvent::Error(id, err.to_string()),
+      }
+    })
+  }
+
+  /// Runs the management protocol until either the request stream ends, the
+  /// event receiver is dropped, or a Shutdown request is processed. Server
+  /// events relevant to engine management are relayed along with request
+  /// results.
+  pub async fn run<S>(self, mut requests: S, event_sender: mpsc::Sender<EngineControlEvent>)
+  where
+    S: Stream<Item = EngineControlRequest> + Unpin,
+  {
+    let server_events = self.server.event_stream();
+    pin_mut!(server_events);
+    loop {
+      select! {
+        request = requests.next().fuse() => match request {
+          Some(request) => {
+            let is_shutdown = request.command == EngineControlCommand::Shutdown;
+            let fut = self.handle_request(request);
+            if is_shutdown {
+              // Run shutdown inline, we don't want to pick up anything else
+              // once it's been requested.
+              let _ = event_sender.send(fut.await).await;
+              break;
+            }
+            let sender = event_sender.clone();
+            async_manager::spawn(async move {
+              if sender.send(fut.await).await.is_err() {
+                debug!("Engine control receiver dropped, cannot send response.");
+              }
+            })
+            .unwrap();
+          }
+          None => {
+            info!("Engine control request stream closed, exiting loop.");
+            break;
+          }
+        },
+        server_msg = server_events.next().fuse() => {
+          let event = match server_msg {
+            Some(ButtplugServerMessage::DeviceAdded(da)) => {
+              EngineControlEvent::DeviceAdded(da.device_index(), da.device_name().clone())
+            }
+            Some(ButtplugServerMessage::DeviceRemoved(dr)) => {
+              EngineControlEvent::DeviceRemoved(dr.device_index())
+            }
+            Some(ButtplugServerMessage::ScanningFinished(_)) => EngineControlEvent::ScanningFinished,
+            Some(_) => continue,
+            None => {
+              info!("Server disappeared, exiting engine control loop.");
+              break;
+            }
+          };
+          if event_sender.send(event).await.is_err() {
+            info!("Engine control receiver dropped, exiting loop.");
+            break;
+          }
+        }
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use futures::stream;
+
+  #[test]
+  fn test_engine_control_requests() {
+    async_manager::block_on(async {
+      let server = Arc::new(ButtplugServer::default());
+      let helper = server.add_test_comm_manager().unwrap();
+      helper.add_ble_device("Massage Demo").await;
+      let (sender, mut receiver) = mpsc::channel(256);
+      // Keep the request stream open after our request, otherwise the control
+      // loop will exit before the device shows up.
+      let requests = stream::iter(vec![EngineControlRequest::new(
+        1,
+        EngineControlCommand::StartScanning,
+      )])
+      .chain(stream::pending());
+      let control = EngineControl::new(server.clone());
+      async_manager::spawn_named("engine control", async move {
+        control.run(requests, sender).await;
+      })
+      .unwrap();
+      let mut got_ok = false;
+      let mut got_device = false;
+      while !(got_ok && got_device) {
+        match receiver.recv().await.unwrap() {
+          EngineControlEvent::Ok(1) => got_ok = true,
+          EngineControlEvent::DeviceAdded(_, name) => {
+            assert_eq!(name, "Aneros Vivi");
+            got_device = true;
+          }
+          _ => {}
+        }
+      }
+      let control = EngineControl::new(server);
+      assert!(matches!(
+        control
+          .handle_request(EngineControlRequest::new(
+            2,
+            EngineControlCommand::DisconnectDevice(100)
+          ))
+          .await,
+        EngineControlEvent::Error(2, _)
+      ));
+      assert_eq!(
+        control
+          .handle_request(EngineControlRequest::new(
+            3,
+            EngineControlCommand::RequestBuildInfo
+          ))
+          .await,
+        EngineControlEvent::BuildInfo(3, BuildInfo::current())
+      );
+    });
+  }
+}
@@ -0,0 +1,207 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2021 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Filtered subscriptions to server events.
+
+use crate::{
+  core::messages::{ButtplugDeviceMessage, ButtplugServerMessage},
+  util::async_manager,
+};
+use std::{
+  collections::HashSet,
+  sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, Mutex, Weak,
+  },
+};
+use tokio::sync::{broadcast, mpsc};
+
+/// Kinds of messages a server can emit as events.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ServerEventType {
+  Ok,
+  Error,
+  Test,
+  Log,
+  ServerInfo,
+  DeviceList,
+  DeviceAdded,
+  DeviceRemoved,
+  ScanningFinished,
+  RawReading,
+  BatteryLevelReading,
+  RSSILevelReading,
+  SensorReading,
+}
+
+impl From<&ButtplugServerMessage> for ServerEventType {
+  fn from(msg: &ButtplugServerMessage) -> Self {
+    match msg {
+      ButtplugServerMessage::Ok(_) => ServerEventType::Ok,
+      ButtplugServerMessage::Error(_) => ServerEventType::Error,
+      ButtplugServerMessage::Test(_) => ServerEventType::Test,
+      ButtplugServerMessage::Log(_) => ServerEventType::Log,
+      ButtplugServerMessage::ServerInfo(_) => ServerEventType::ServerInfo,
+      ButtplugServerMessage::DeviceList(_) => ServerEventType::DeviceList,
+      ButtplugServerMessage::DeviceAdded(_) => ServerEventType::DeviceAdded,
+      ButtplugServerMessage::DeviceRemoved(_) => ServerEventType::DeviceRemoved,
+      ButtplugServerMessage::ScanningFinished(_) => ServerEventType::ScanningFinished,
+      ButtplugServerMessage::RawReading(_) => ServerEventType::RawReading,
+      ButtplugServerMessage::BatteryLevelReading(_) => ServerEventType::BatteryLevelReading,
+      ButtplugServerMessage::RSSILevelReading(_) => ServerEventType::RSSILevelReading,
+      ButtplugServerMessage::SensorReading(_) => ServerEventType::SensorReading,
+    }
+  }
+}
+
+fn event_device_index(msg: &ButtplugServerMessage) -> Option<u32> {
+  match msg {
+    ButtplugServerMessage::DeviceAdded(msg) => Some(msg.device_index()),
+    ButtplugServerMessage::DeviceRemoved(msg) => Some(msg.device_index()),
+    ButtplugServerMessage::RawReading(msg) => Some(msg.device_index()),
+    ButtplugServerMessage::BatteryLevelReading(msg) => Some(msg.device_index()),
+    ButtplugServerMessage::RSSILevelReading(msg) => Some(msg.device_index()),
+    ButtplugServerMessage::SensorReading(msg) => Some(msg.device_index()),
+    _ => None,
+  }
+}
+
+/// Selects which server events a subscriber receives.
+///
+/// Works like [DeviceFilter][super::device_filter::DeviceFilter]: an empty
+/// filter passes everything, and each set that has entries narrows it down.
+/// Events that aren't about a specific device (ScanningFinished, Log, etc) are
+/// only checked against the message types.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct EventFilter {
+  message_types: HashSet<ServerEventType>,
+  device_indexes: HashSet<u32>,
+}
+
+impl EventFilter {
+  pub fn allow_message_type(mut self, message_type: ServerEventType) -> Self {
+    self.message_types.insert(message_type);
+    self
+  }
+
+  pub fn allow_device_index(mut self, device_index: u32) -> Self {
+    self.device_indexes.insert(device_index);
+    self
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.message_types.is_empty() && self.device_indexes.is_empty()
+  }
+
+  pub fn allows(&self, msg: &ButtplugServerMessage) -> bool {
+    let type_allowed =
+      self.message_types.is_empty() || self.message_types.contains(&ServerEventType::from(msg));
+    let device_allowed = self.device_indexes.is_empty()
+      || event_device_index(msg).map_or(true, |index| self.device_indexes.contains(&index));
+    type_allowed && device_allowed
+  }
+}
+
+type FilteredSubscribers = Mutex<Vec<(EventFilter, mpsc::Sender<ButtplugServerMessage>)>>;
+
+/// Fans server events out to filtered subscribers.
+///
+/// Only the dispatcher reads from the server's broadcast channel, so
+/// subscribers just get copies of what they asked for, and a busy event type
+/// can't make them lag out of the messages they care about.
+pub(super) struct FilteredEventDispatcher {
+  output_sender: broadcast::Sender<ButtplugServerMessage>,
+  subscribers: Arc<FilteredSubscribers>,
+  started: AtomicBool,
+}
+
+impl FilteredEventDispatcher {
+  pub(super) fn new(output_sender: broadcast::Sender<ButtplugServerMessage>) -> Self {
+    Self {
+      output_sender,
+      subscribers: Arc::new(Mutex::new(vec![])),
+      started: AtomicBool::new(false),
+    }
+  }
+
+  pub(super) fn subscribe(&self, filter: EventFilter) -> mpsc::Receiver<ButtplugServerMessage> {
+    let (sender, receiver) = mpsc::channel(256);
+    self.subscribers.lock().unwrap().push((filter, sender));
+    // Don't run the dispatch loop until someone actually wants filtered
+    // events.
+    if !self.started.swap(true, Ordering::SeqCst) {
+      let event_receiver = self.output_sender.subscribe();
+      let subscribers = Arc::downgrade(&self.subscribers);
+      async_manager::spawn_named("event filter dispatch", async move {
+        run_dispatch_loop(event_receiver, subscribers).await;
+      })
+      .unwrap();
+    }
+    receiver
+  }
+}
+
+async fn run_dispatch_loop(
+  mut event_receiver: broadcast::Receiver<ButtplugServerMessage>,
+  subscribers: Weak<FilteredSubscribers>,
+) {
+  loop {
+    let msg = match event_receiver.recv().await {
+      Ok(msg) => msg,
+      Err(broadcast::error::RecvError::Lagged(count)) => {
+        warn!(
+          "Filtered event dispatcher lagged, dropped {} events.",
+          count
+        );
+        continue;
+      }
+      Err(broadcast::error::RecvError::Closed) => break,
+    };
+    let subscribers = match subscribers.upgrade() {
+      Some(subscribers) => subscribers,
+      None => break,
+    };
+    let mut subscribers = subscribers.lock().unwrap();
+    subscribers.retain(|(filter, sender)| {
+      if !filter.allows(&msg) {
+        return true;
+      }
+      match sender.try_send(msg.clone()) {
+        Ok(()) => true,
+        Err(mpsc::error::TrySendError::Full(_)) => {
+          warn!("Filtered event subscriber is full, dropping event.");
+          true
+        }
+        Err(mpsc::error::TrySendError::Closed(_)) => false,
+      }
+    });
+  }
+  debug!("Exiting filtered event dispatch loop.");
+}
+
+#[cfg(test)]
+mod test {
+  use super::{EventFilter, ServerEventType};
+  use crate::core::messages::{ButtplugServerMessage, DeviceRemoved, ScanningFinished};
+
+  #[test]
+  fn test_event_filter() {
+    let removed_0: ButtplugServerMessage = DeviceRemoved::new(0).into();
+    let removed_1: ButtplugServerMessage = DeviceRemoved::new(1).into();
+    let scanning_finished: ButtplugServerMessage = ScanningFinished::default().into();
+    assert!(EventFilter::default().allows(&removed_0));
+
+    let filter = EventFilter::default().allow_device_index(1);
+    assert!(!filter.allows(&removed_0));
+    assert!(filter.allows(&removed_1));
+    assert!(filter.allows(&scanning_finished));
+
+    let filter = EventFilter::default().allow_message_type(ServerEventType::DeviceRemoved);
+    assert!(filter.allows(&removed_0));
+    assert!(!filter.allows(&scanning_finished));
+  }
+}
@@ -0,0 +1,172 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2022 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Devices that have connected to the server before.
+//!
+//! Every device that connects is remembered, and when a comm manager finds a
+//! remembered device again, a [KnownDeviceEvent::InRange] event is sent, so
+//! front-ends can offer to reconnect to it. The library doesn't touch the
+//! filesystem, so applications that want the list to survive restarts save
+//! what [ButtplugServer::known_devices][super::ButtplugServer::known_devices]
+//! returns, and hand it back through
+//! [ButtplugServerOptions::known_devices][super::ButtplugServerOptions::known_devices].
+
+use crate::{
+  device::{identity::normalize_address, ButtplugDevice},
+  util::stream::convert_broadcast_receiver_to_stream,
+};
+use dashmap::DashMap;
+use futures::Stream;
+#[cfg(feature = "serialize-json")]
+use serde::{Deserialize, Serialize};
+use std::{
+  sync::Arc,
+  time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::broadcast;
+
+/// A device that has connected to the server before.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serialize-json", derive(Serialize, Deserialize))]
+pub struct KnownDevice {
+  /// Identity key of the device, see
+  /// [DeviceIdentity::key][crate::device::identity::DeviceIdentity::key].
+  pub identity_key: String,
+  /// Normalized transport address the device last connected from.
+  pub address: String,
+  /// Device name, as it was last sent to clients.
+  pub name: String,
+  /// Protocol the device was matched to.
+  pub protocol: Option<String>,
+  /// When the device last connected, in seconds since the Unix epoch.
+  pub last_connected: u64,
+}
+
+/// Events about known devices.
+#[derive(Debug, Clone, PartialEq)]
+pub enum KnownDeviceEvent {
+  /// A comm manager found a known device that isn't connected. This is sent
+  /// as soon as the device is found, before any connection attempt.
+  InRange(KnownDevice),
+}
+
+/// Known devices, shared between the device manager and its event loop.
+#[derive(Clone)]
+pub(crate) struct KnownDevices {
+  devices: Arc<DashMap<String, KnownDevice>>,
+  event_sender: broadcast::Sender<KnownDeviceEvent>,
+}
+
+impl KnownDevices {
+  pub fn new(known_devices: Vec<KnownDevice>) -> Self {
+    let (event_sender, _) = broadcast::channel(256);
+    let devices = DashMap::new();
+    for device in known_devices {
+      devices.insert(device.identity_key.clone(), device);
+    }
+    Self {
+      devices: Arc::new(devices),
+      event_sender,
+    }
+  }
+
+  /// Remembers a connected device, or updates what we know about it.
+  pub fn record(&self, device: &ButtplugDevice) {
+    let identity = device.identity();
+    let last_connected = SystemTime::now()
+      .duration_since(UNIX_EPOCH)
+      .map(|since_epoch| since_epoch.as_secs())
+      .unwrap_or(0);
+    self.devices.insert(
+      identity.key(),
+      KnownDevice {
+        identity_key: identity.key(),
+        address: identity.address().to_owned(),
+        name: device.name(),
+        protocol: device
+          .protocol_identifier()
+          .map(|protocol| protocol.to_owned()),
+        last_connected,
+      },
+    );
+  }
+
+  /// Looks up a known device by transport address. Found devices don't have
+  /// a device ID yet, so this is the only way to match them.
+  pub fn find_by_address(&self, address: &str) -> Option<KnownDevice> {
+    let address = normalize_address(address);
+    self
+      .devices
+      .iter()
+      .find(|device| device.value().address == address)
+      .map(|device| device.value().clone())
+  }
+
+  /// Tells listeners a known device is in range.
+  pub fn send_in_range(&self, device: KnownDevice) {
+    // No one listening is fine.
+    let _ = self.event_sender.send(KnownDeviceEvent::InRange(device));
+  }
+
+  /// Every known device, most recently connected first.
+  pub fn list(&self) -> Vec<KnownDevice> {
+    let mut devices: Vec<KnownDevice> = self
+      .devices
+      .iter()
+      .map(|device| device.value().clone())
+      .collect();
+    devices.sort_by(|a, b| b.last_connected.cmp(&a.last_connected));
+    devices
+  }
+
+  /// Forgets a known device. Returns false if it wasn't known.
+  pub fn forget(&self, identity_key: &str) -> bool {
+    self.devices.remove(identity_key).is_some()
+  }
+
+  pub fn event_stream(&self) -> impl Stream<Item = KnownDeviceEvent> {
+    convert_broadcast_receiver_to_stream(self.event_sender.subscribe())
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::{KnownDevice, KnownDevices};
+
+  fn known_device(identity_key: &str, address: &str, last_connected: u64) -> KnownDevice {
+    KnownDevice {
+      identity_key: identity_key.to_owned(),
+      address: address.to_owned(),
+      name: "Lovense Hush".to_owned(),
+      protocol: Some("lovense".to_owned()),
+      last_connected,
+    }
+  }
+
+  #[test]
+  fn test_known_devices() {
+    let known_devices = KnownDevices::new(vec![
+      known_device("id:0082059ad3bd", "00:82:05:9a:d3:bd", 10),
+      known_device("COM3", "COM3", 20),
+    ]);
+    // Found devices are matched however the OS formats their address.
+    assert_eq!(
+      known_devices
+        .find_by_address("00-82-05-9A-D3-BD")
+        .unwrap()
+        .identity_key,
+      "id:0082059ad3bd"
+    );
+    assert!(known_devices.find_by_address("COM4").is_none());
+    let list = known_devices.list();
+    assert_eq!(list[0].identity_key, "COM3");
+    assert_eq!(list[1].identity_key, "id:0082059ad3bd");
+    assert!(known_devices.forget("COM3"));
+    assert!(!known_devices.forget("COM3"));
+    assert_eq!(known_devices.list().len(), 1);
+  }
+}
@@ -0,0 +1,212 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2021 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Forwarding of library log records to clients, via RequestLog/Log.
+//!
+//! Applications that want clients to be able to request logs add
+//! [ButtplugLogLayer] to their tracing subscriber:
+//!
+//! ```no_run
+//! use buttplug::server::log_forwarding::ButtplugLogLayer;
+//! use tracing_subscriber::{layer::SubscriberExt, Registry};
+//!
+//! let subscriber = Registry::default().with(ButtplugLogLayer::default());
+//! tracing::subscriber::set_global_default(subscriber).unwrap();
+//! ```
+//!
+//! After that, a client can send RequestLog with a level, and the server will
+//! send Log messages for every record at or above that level until the client
+//! sends RequestLog with a level of Off, or disconnects. Nothing is formatted
+//! while no client has asked for logs.
+
+use crate::{
+  core::messages::{ButtplugServerMessage, Log, LogLevel},
+  util::{async_manager, logging::redact_identifiers},
+};
+use futures::FutureExt;
+use once_cell::sync::Lazy;
+use std::{
+  collections::HashMap,
+  fmt::{self, Write},
+  sync::{
+    atomic::{AtomicU64, AtomicU8, Ordering},
+    Arc, Mutex,
+  },
+};
+use tokio::sync::{broadcast, oneshot};
+use tracing::{
+  field::{Field, Visit},
+  Event, Subscriber,
+};
+use tracing_subscriber::layer::{Context, Layer};
+
+/// Modules that log about every message they pass along, including the Log
+/// messages we generate. Forwarding their records would mean every forwarded
+/// record causes more records, so they're never forwarded.
+const EXCLUDED_TARGETS: &[&str] = &[
+  "buttplug::client",
+  "buttplug::connector",
+  "buttplug::core::messages::serializer",
+  "buttplug::ffi",
+  "buttplug::server::log_forwarding",
+  "buttplug::server::remote_server",
+];
+
+static LOG_RECORD_SENDER: Lazy<broadcast::Sender<Log>> = Lazy::new(|| broadcast::channel(1024).0);
+/// Most verbose level any server is currently forwarding, so the layer can
+/// skip formatting records nobody wants.
+static MAX_LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Off as u8);
+static SUBSCRIPTION_LEVELS: Lazy<Mutex<HashMap<u64, u8>>> =
+  Lazy::new(|| Mutex::new(HashMap::new()));
+static NEXT_SUBSCRIPTION_ID: AtomicU64 = AtomicU64::new(0);
+
+fn update_max_level(levels: &HashMap<u64, u8>) {
+  let max_level = levels
+    .values()
+    .copied()
+    .max()
+    .unwrap_or(LogLevel::Off as u8);
+  MAX_LEVEL.store(max_level, Ordering::SeqCst);
+}
+
+/// Tracing layer that hands log records to any server with a client that has
+/// requested logs.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ButtplugLogLayer {}
+
+impl<S: Subscriber> Layer<S> for ButtplugLogLayer {
+  fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+    let metadata = event.metadata();
+    let level = LogLevel::from(*metadata.level());
+    if level.clone() as u8 > MAX_LEVEL.load(Ordering::Relaxed) {
+      return;
+    }
+    let target = metadata.target();
+    if EXCLUDED_TARGETS
+      .iter()
+      .any(|excluded| target.starts_with(excluded))
+    {
+      return;
+    }
+    let mut visitor = LogMessageVisitor::default();
+    event.record(&mut visitor);
+    // No receivers just means every server stopped forwarding since we
+    // checked the level.
+    let record = format!("{}: {}{}", target, visitor.message, visitor.fields);
+    let _ = LOG_RECORD_SENDER.send(Log::new(level, &redact_identifiers(&record)));
+  }
+}
+
+#[derive(Default)]
+struct LogMessageVisitor {
+  message: String,
+  fields: String,
+}
+
+impl Visit for LogMessageVisitor {
+  fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+    if field.name() == "message" {
+      let _ = write!(self.message, "{:?}", value);
+    } else {
+      let _ = write!(self.fields, " {}={:?}", field.name(), value);
+    }
+  }
+}
+
+/// A running forward of log records to one server's event stream.
+struct LogSubscription {
+  id: u64,
+  level: Arc<AtomicU8>,
+  // Dropping this stops the forwarding task.
+  _stop_sender: oneshot::Sender<()>,
+}
+
+impl LogSubscription {
+  fn new(level: LogLevel, output_sender: broadcast::Sender<ButtplugServerMessage>) -> Self {
+    let id = NEXT_SUBSCRIPTION_ID.fetch_add(1, Ordering::SeqCst);
+    let level = Arc::new(AtomicU8::new(level as u8));
+    let mut record_receiver = LOG_RECORD_SENDER.subscribe();
+    let (stop_sender, stop_receiver) = oneshot::channel();
+    let task_level = level.clone();
+    async_manager::spawn_named("log forwarding", async move {
+      let mut stop_receiver = stop_receiver.fuse();
+      loop {
+        select! {
+          record = record_receiver.recv().fuse() => match record {
+            Ok(record) => {
+              if record.log_level().clone() as u8 <= task_level.load(Ordering::Relaxed) {
+                // Only fails if nobody's listening to the server, in which
+                // case there's nobody to tell.
+                let _ = output_sender.send(record.into());
+              }
+            }
+            // Dropping records under load is fine, this is best effort.
+            Err(broadcast::error::RecvError::Lagged(_)) => continue,
+            Err(broadcast::error::RecvError::Closed) => break,
+          },
+          _ = stop_receiver => break,
+        }
+      }
+    })
+    .unwrap();
+    let subscription = Self {
+      id,
+      level,
+      _stop_sender: stop_sender,
+    };
+    subscription.register();
+    subscription
+  }
+
+  fn register(&self) {
+    let mut levels = SUBSCRIPTION_LEVELS.lock().unwrap();
+    levels.insert(self.id, self.level.load(Ordering::SeqCst));
+    update_max_level(&levels);
+  }
+
+  fn set_level(&self, level: LogLevel) {
+    self.level.store(level as u8, Ordering::SeqCst);
+    self.register();
+  }
+}
+
+impl Drop for LogSubscription {
+  fn drop(&mut self) {
+    let mut levels = SUBSCRIPTION_LEVELS.lock().unwrap();
+    levels.remove(&self.id);
+    update_max_level(&levels);
+  }
+}
+
+/// Per server log forwarding state, changed by RequestLog.
+pub(super) struct LogForwarder {
+  output_sender: broadcast::Sender<ButtplugServerMessage>,
+  subscription: Mutex<Option<LogSubscription>>,
+}
+
+impl LogForwarder {
+  pub(super) fn new(output_sender: broadcast::Sender<ButtplugServerMessage>) -> Self {
+    Self {
+      output_sender,
+      subscription: Mutex::new(None),
+    }
+  }
+
+  /// Starts, changes the level of, or (with [LogLevel::Off]) stops log
+  /// forwarding for this server.
+  pub(super) fn set_level(&self, level: LogLevel) {
+    let mut subscription = self.subscription.lock().unwrap();
+    if level == LogLevel::Off {
+      *subscription = None;
+      return;
+    }
+    match subscription.as_ref() {
+      Some(subscription) => subscription.set_level(level),
+      None => *subscription = Some(LogSubscription::new(level, self.output_sender.clone())),
+    }
+  }
+}
@@ -0,0 +1,267 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2022 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Hooks for watching or rewriting the messages a server handles.
+//!
+//! Middleware added with
+//! [ButtplugServerBuilder::middleware][super::ButtplugServerBuilder::middleware]
+//! or [ButtplugServer::add_middleware][super::ButtplugServer::add_middleware]
+//! sees every client message before the server handles it, and every
+//! message the server sends back, both replies and events. That's enough to
+//! log traffic, clamp intensities, or refuse messages the application
+//! doesn't want clients sending.
+//!
+//! Middleware runs in a fixed order. Client messages go through it in the
+//! order it was added, and server messages go through it in the reverse
+//! order, so the first middleware added is the first to see a request and the
+//! last to see what comes back, like layers around the server.
+//!
+//! Client messages still reach the server in the order they came in. A
+//! message doesn't go through middleware until the one before it has been
+//! handed to the server, so slow middleware holds up every message behind
+//! it.
+//!
+//! An error from middleware stops the message there. For client messages,
+//! the error is the reply to the client, and the server never sees the
+//! message. For replies, the error replaces the reply. Events that fail are
+//! dropped, and the error is logged. Errors the server replies with don't go
+//! through middleware.
+
+use crate::core::{
+  errors::ButtplugError,
+  messages::{ButtplugClientMessage, ButtplugServerMessage},
+};
+use futures::{
+  future::{self, BoxFuture},
+  Stream, StreamExt,
+};
+use std::sync::{Arc, RwLock};
+
+pub type ButtplugMiddlewareResult<T> = BoxFuture<'static, Result<T, ButtplugError>>;
+
+/// Watches or rewrites messages going through a server. Both methods pass
+/// messages through as is by default, so middleware only has to implement
+/// the side it cares about.
+pub trait ButtplugServerMiddleware: Send + Sync {
+  /// Called with every client message, before the server handles it.
+  /// Returns the message for the server (or the next middleware) to handle.
+  fn on_client_message(
+    &self,
+    msg: ButtplugClientMessage,
+  ) -> ButtplugMiddlewareResult<ButtplugClientMessage> {
+    Box::pin(future::ready(Ok(msg)))
+  }
+
+  /// Called with every successful reply and every event, before it goes out
+  /// to the client. Returns the message to send on.
+  fn on_server_message(
+    &self,
+    msg: ButtplugServerMessage,
+  ) -> ButtplugMiddlewareResult<ButtplugServerMessage> {
+    Box::pin(future::ready(Ok(msg)))
+  }
+}
+
+/// Middleware for a server, in the order it was added.
+#[derive(Clone, Default)]
+pub(super) struct MiddlewareChain {
+  middleware: Arc<RwLock<Vec<Arc<dyn ButtplugServerMiddleware>>>>,
+}
+
+impl MiddlewareChain {
+  pub fn add(&self, middleware: Arc<dyn ButtplugServerMiddleware>) {
+    self.middleware.write().unwrap().push(middleware);
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.middleware.read().unwrap().is_empty()
+  }
+
+  /// Copy of the chain as it is now, which middleware added later won't
+  /// show up in. Messages are run through a snapshot taken when they come
+  /// in, so a request and its reply always see the same middleware.
+  pub fn snapshot(&self) -> Self {
+    Self {
+      middleware: Arc::new(RwLock::new(self.layers())),
+    }
+  }
+
+  fn layers(&self) -> Vec<Arc<dyn ButtplugServerMiddleware>> {
+    self.middleware.read().unwrap().clone()
+  }
+
+  pub fn client_message(
+    &self,
+    msg: ButtplugClientMessage,
+  ) -> ButtplugMiddlewareResult<ButtplugClientMessage> {
+    let middleware = self.layers();
+    Box::pin(async move {
+      let mut msg = msg;
+      for layer in middleware {
+        msg = layer.on_client_message(msg).await?;
+      }
+      Ok(msg)
+    })
+  }
+
+  pub fn server_message(
+    &self,
+    msg: ButtplugServerMessage,
+  ) -> ButtplugMiddlewareResult<ButtplugServerMessage> {
+    let middleware = self.layers();
+    Box::pin(async move {
+      let mut msg = msg;
+      for layer in middleware.into_iter().rev() {
+        msg = layer.on_server_message(msg).await?;
+      }
+      Ok(msg)
+    })
+  }
+
+  /// Runs events from the stream through the middleware, one at a time so
+  /// they stay in order, and drops any that fail.
+  pub fn event_stream(
+    &self,
+    events: impl Stream<Item = ButtplugServerMessage> + Send + 'static,
+  ) -> impl Stream<Item = ButtplugServerMessage> + Send + 'static {
+    let chain = self.clone();
+    events
+      .then(move |msg| chain.server_message(msg))
+      .filter_map(|result| {
+        future::ready(match result {
+          Ok(msg) => Some(msg),
+          Err(err) => {
+            debug!("Middleware dropped server event: {}", err);
+            None
+          }
+        })
+      })
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::{ButtplugMiddlewareResult, ButtplugServerMiddleware};
+  use crate::{
+    core::{
+      errors::{ButtplugDeviceError, ButtplugError},
+      messages::{
+        self, ButtplugClientMessage, ButtplugMessage, ButtplugServerMessage, VibrateSubcommand,
+        BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION,
+      },
+    },
+    server::{ButtplugServer, ButtplugServerOptions},
+    util::async_manager,
+  };
+  use futures::future;
+  use std::sync::{Arc, Mutex};
+
+  /// Records the order middleware sees messages in.
+  struct Recorder {
+    name: &'static str,
+    seen: Arc<Mutex<Vec<String>>>,
+  }
+
+  impl ButtplugServerMiddleware for Recorder {
+    fn on_client_message(
+      &self,
+      msg: ButtplugClientMessage,
+    ) -> ButtplugMiddlewareResult<ButtplugClientMessage> {
+      self
+        .seen
+        .lock()
+        .unwrap()
+        .push(format!("{} client", self.name));
+      Box::pin(future::ready(Ok(msg)))
+    }
+
+    fn on_server_message(
+      &self,
+      msg: ButtplugServerMessage,
+    ) -> ButtplugMiddlewareResult<ButtplugServerMessage> {
+      self
+        .seen
+        .lock()
+        .unwrap()
+        .push(format!("{} server", self.name));
+      Box::pin(future::ready(Ok(msg)))
+    }
+  }
+
+  /// Refuses vibration commands.
+  struct NoVibration;
+
+  impl ButtplugServerMiddleware for NoVibration {
+    fn on_client_message(
+      &self,
+      msg: ButtplugClientMessage,
+    ) -> ButtplugMiddlewareResult<ButtplugClientMessage> {
+      Box::pin(future::ready(match msg {
+        ButtplugClientMessage::VibrateCmd(_) => Err(
+          ButtplugDeviceError::ProtocolRequirementError("Vibration is turned off.".to_owned())
+            .into(),
+        ),
+        msg => Ok(msg),
+      }))
+    }
+  }
+
+  #[test]
+  fn test_middleware_order() {
+    async_manager::block_on(async {
+      let server = ButtplugServer::new_with_options(&ButtplugServerOptions::default()).unwrap();
+      let seen = Arc::new(Mutex::new(vec![]));
+      for name in ["outer", "inner"] {
+        server.add_middleware(Arc::new(Recorder {
+          name,
+          seen: seen.clone(),
+        }));
+      }
+      let reply = server
+        .parse_message(
+          messages::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION)
+            .into(),
+        )
+        .await;
+      assert!(reply.is_ok());
+      assert_eq!(
+        *seen.lock().unwrap(),
+        vec![
+          "outer client",
+          "inner client",
+          "inner server",
+          "outer server"
+        ]
+      );
+    });
+  }
+
+  #[test]
+  fn test_middleware_errors() {
+    async_manager::block_on(async {
+      let server = ButtplugServer::new_with_options(&ButtplugServerOptions::default()).unwrap();
+      server.add_middleware(Arc::new(NoVibration));
+      server
+        .parse_message(
+          messages::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION)
+            .into(),
+        )
+        .await
+        .unwrap();
+      let mut msg = messages::VibrateCmd::new(0, vec![VibrateSubcommand::new(0, 1.0)]);
+      msg.set_id(3);
+      // The server never sees the command, so it doesn't get to complain
+      // about there being no device 0.
+      let error = server.parse_message(msg.into()).await.unwrap_err();
+      assert_eq!(error.id(), 3);
+      assert!(matches!(
+        error.original_error(),
+        ButtplugError::ButtplugDeviceError(ButtplugDeviceError::ProtocolRequirementError(_))
+      ));
+    });
+  }
+}
@@ -8,39 +8,89 @@
 //! Handles client sessions, as well as discovery and communication with hardware.
 
 pub mod comm_managers;
+pub mod device_connection;
+pub mod device_filter;
+pub mod device_group;
+pub mod device_info;
 pub mod device_manager;
 mod device_manager_event_loop;
+#[cfg(feature = "device-scripting")]
+pub mod device_script;
+pub mod device_split;
+pub mod diagnostics;
+pub mod emergency_stop;
+#[cfg(feature = "server-emulator")]
+pub mod emulator;
+#[cfg(feature = "engine-control")]
+pub mod engine_control;
+pub mod event_filter;
+pub mod known_devices;
+pub mod log_forwarding;
+pub mod middleware;
+#[cfg(feature = "osc-bridge")]
+pub mod osc_bridge;
 mod ping_timer;
+pub mod pressure_loop;
+pub mod protocol_support;
 pub mod remote_server;
+pub mod scheduled_stop;
+#[cfg(feature = "server-runner")]
+pub mod server_runner;
 
+pub use protocol_support::protocol_support_matrix;
 pub use remote_server::ButtplugRemoteServer;
 
 use crate::{
   core::{
     errors::*,
     messages::{
-      self, ButtplugClientMessage, ButtplugDeviceCommandMessageUnion,
-      ButtplugDeviceManagerMessageUnion, ButtplugMessage, ButtplugServerMessage, StopAllDevices,
-      StopScanning, BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION,
+      self, ButtplugClientMessage, ButtplugDeviceCommandMessageUnion, ButtplugMessage,
+      ButtplugMessageSpecVersion, ButtplugServerMessage, LogLevel,
+      BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION,
     },
   },
-  device::protocol::ButtplugProtocol,
+  device::{configuration_manager::DeviceConfigurationOptions, protocol::ButtplugProtocol},
   test::TestDeviceCommunicationManagerHelper,
-  util::{async_manager, stream::convert_broadcast_receiver_to_stream},
+  util::{
+    async_manager,
+    clock::{Clock, SystemClock},
+    future::ButtplugReadyOrBoxedFuture,
+    logging,
+    stream::{
+      convert_broadcast_receiver_to_filtered_stream, convert_broadcast_receiver_to_stream,
+      convert_mpsc_receiver_to_stream,
+    },
+  },
 };
-use comm_managers::DeviceCommunicationManagerBuilder;
-use device_manager::DeviceManager;
+use comm_managers::{DeviceCommunicationManagerBuilder, DeviceCommunicationManagerCapabilities};
+use device_connection::DeviceConnectionEvent;
+use device_filter::DeviceFilter;
+use device_info::ServerDeviceInfo;
+use device_manager::{DeviceIndexPolicy, DeviceManager, StopAllDevicesScope};
+use diagnostics::ServerDiagnostic;
+use emergency_stop::EmergencyStop;
+use event_filter::{EventFilter, FilteredEventDispatcher};
 use futures::{
-  future::{self, BoxFuture},
+  channel::oneshot,
+  future::{BoxFuture, Future},
+  task::{Context, Poll},
   Stream,
 };
+use known_devices::{KnownDevice, KnownDeviceEvent};
+use log_forwarding::LogForwarder;
+use middleware::{ButtplugServerMiddleware, MiddlewareChain};
 use ping_timer::PingTimer;
+use pressure_loop::PressureLoopConfig;
+use scheduled_stop::ScheduledStopEvent;
 use std::{
-  convert::{TryFrom, TryInto},
+  collections::HashMap,
+  convert::TryInto,
+  pin::Pin,
   sync::{
-    atomic::{AtomicBool, Ordering},
-    Arc,
+    atomic::{AtomicBool, AtomicU64, Ordering},
+    Arc, Mutex, RwLock,
   },
+  time::{Duration, SystemTime},
 };
 use thiserror::Error;
 use tokio::sync::broadcast;
@@ -53,19 +103,114 @@ pub type ButtplugServerResultFuture = BoxFuture<'static, ButtplugServerResult>;
 pub enum ButtplugServerError {
   #[error("DeviceManager of type {0} has already been added.")]
   DeviceManagerTypeAlreadyAdded(String),
+  #[error("DeviceManager of type {0} does not exist in the system and cannot be removed.")]
+  DeviceManagerTypeNotFound(String),
   #[error("Buttplug Protocol of type {0} has already been added to the system.")]
   ProtocolAlreadyAdded(String),
   #[error("Buttplug Protocol of type {0} does not exist in the system and cannot be removed.")]
   ProtocolDoesNotExist(String),
+  #[error("Device group {0} has already been added.")]
+  DeviceGroupAlreadyExists(String),
+  #[error("Device address {0} is already a member of device group {1}.")]
+  DeviceGroupMemberConflict(String, String),
+  #[error("Server could not be created: {0}")]
+  ServerCreationError(#[from] ButtplugError),
+  #[cfg(feature = "device-scripting")]
+  #[error(transparent)]
+  DeviceScriptError(#[from] device_script::DeviceScriptError),
+}
+
+/// What happens to devices when the client disconnects.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisconnectStopPolicy {
+  /// Stop devices as soon as the client disconnects.
+  StopImmediately,
+  /// Stop devices once the client has been gone this many milliseconds,
+  /// unless a client connects in the meantime. Gives clients that drop out
+  /// a chance to come back without their devices stopping.
+  StopAfterGracePeriod(u64),
+  /// Leave devices running, e.g. when handing them over from one client to
+  /// another. Whatever they were last told to do, they'll keep doing until
+  /// the next client or the application stops them.
+  KeepRunning,
+}
+
+impl Default for DisconnectStopPolicy {
+  fn default() -> Self {
+    DisconnectStopPolicy::StopImmediately
+  }
 }
 
 #[derive(Debug, Clone)]
 pub struct ButtplugServerOptions {
   pub name: String,
   pub max_ping_time: u64,
+  /// Maximum time a scan can run, in milliseconds, before scanning is stopped
+  /// on all comm managers. 0 means scans run until StopScanning is received,
+  /// or every comm manager finishes on its own.
+  pub max_scanning_time: u64,
+  /// Time a device has to stay connected, or disconnected, before clients
+  /// are sent DeviceAdded or DeviceRemoved for it, in milliseconds. Keeps
+  /// devices that flicker in and out of range (weak batteries, bad signal)
+  /// from flooding clients with events. Devices still waiting out the
+  /// debounce time are left out of device lists. 0 sends events right away.
+  pub device_debounce_time: u64,
+  /// How many found devices can be connecting and initializing at once.
+  /// Devices found past this wait their turn, so a scan that finds a lot of
+  /// devices doesn't swamp the BLE stack, while one slow device still can't
+  /// hold up the rest. 0 means no limit.
+  pub max_concurrent_device_initializations: usize,
   pub allow_raw_messages: bool,
+  /// Allows devices marked as power devices in the device configuration
+  /// (fucking machines, e-stim, etc) to be connected. These can injure
+  /// people if misused, so they're off unless explicitly requested.
+  pub allow_power_devices: bool,
+  /// Turns off the duty cycle limits the device configuration sets for
+  /// devices that can overheat. Devices may be damaged if run hard for too
+  /// long with this on.
+  pub ignore_duty_cycle_limits: bool,
   pub device_configuration_json: Option<String>,
   pub user_device_configuration_json: Option<String>,
+  /// Limits the devices the client connected to this server can see.
+  pub device_filter: DeviceFilter,
+  /// Whether indexes of disconnected devices can be given to new devices.
+  pub device_index_policy: DeviceIndexPolicy,
+  /// Which devices StopAllDevices messages from the client stop.
+  pub stop_all_devices_scope: StopAllDevicesScope,
+  /// Whether, and when, devices are stopped after the client disconnects.
+  /// Stops follow `stop_all_devices_scope`.
+  pub disconnect_stop_policy: DisconnectStopPolicy,
+  /// How long devices are left running after a client with an identity token
+  /// (see [ButtplugServer::set_client_identity_token]) disconnects, in
+  /// milliseconds, waiting for a client with the same token to take them
+  /// over. Any other client connecting stops them. Takes the place of
+  /// `disconnect_stop_policy` for those clients. 0 turns handoffs off.
+  pub handoff_timeout: u64,
+  /// Maximum session length, in milliseconds. If set, a scheduled stop is
+  /// armed when the first client finishes its handshake. Reconnecting doesn't
+  /// reset it, only [ButtplugServer::cancel_scheduled_stop] does. 0 means no
+  /// limit.
+  pub max_session_time: u64,
+  /// How long before a scheduled stop the warning event is sent, in
+  /// milliseconds, for stops armed via `max_session_time`.
+  pub session_stop_warning_time: u64,
+  /// If set, runs an OSC bridge alongside the server, using the given
+  /// address mappings.
+  #[cfg(feature = "osc-bridge")]
+  pub osc_bridge: Option<osc_bridge::OscBridgeConfig>,
+  /// Replaces device addresses and names with hashes in logs forwarded to
+  /// clients, and in output formatted with
+  /// [RedactingFields][crate::util::logging::RedactingFields], so logs can be
+  /// shared without identifying devices. Logging is global, so once any
+  /// server turns this on, it's on for the rest of the process.
+  pub redact_device_identifiers: bool,
+  /// Devices remembered from earlier sessions, usually whatever
+  /// [ButtplugServer::known_devices] returned last time the application ran.
+  pub known_devices: Vec<KnownDevice>,
+  /// If set, creates the virtual devices described when the server starts.
+  /// See [emulator] for the format.
+  #[cfg(feature = "server-emulator")]
+  pub emulated_devices_json: Option<String>,
 }
 
 impl Default for ButtplugServerOptions {
@@ -73,21 +218,192 @@ impl Default for ButtplugServerOptions {
     Self {
       name: "Buttplug Server".to_owned(),
       max_ping_time: 0,
+      max_scanning_time: 0,
+      device_debounce_time: 0,
+      max_concurrent_device_initializations: 3,
       allow_raw_messages: false,
+      allow_power_devices: false,
+      ignore_duty_cycle_limits: false,
       device_configuration_json: None,
       user_device_configuration_json: None,
+      device_filter: DeviceFilter::default(),
+      device_index_policy: DeviceIndexPolicy::default(),
+      stop_all_devices_scope: StopAllDevicesScope::default(),
+      disconnect_stop_policy: DisconnectStopPolicy::default(),
+      handoff_timeout: 0,
+      max_session_time: 0,
+      session_stop_warning_time: 60000,
+      #[cfg(feature = "osc-bridge")]
+      osc_bridge: None,
+      redact_device_identifiers: false,
+      known_devices: vec![],
+      #[cfg(feature = "server-emulator")]
+      emulated_devices_json: None,
     }
   }
 }
 
+type CommManagerAdder = Box<dyn FnOnce(&ButtplugServer) -> Result<(), ButtplugServerError> + Send>;
+
+/// Creates a [ButtplugServer] along with its comm managers, so the server
+/// never exists without the comm managers it was meant to have.
+///
+/// ```no_run
+/// # use buttplug::server::{ButtplugServerBuilder, ButtplugServerOptions};
+/// let server = ButtplugServerBuilder::new(ButtplugServerOptions {
+///   name: "My Server".to_owned(),
+///   ..Default::default()
+/// })
+/// .with_default_transports()
+/// .finish()
+/// .unwrap();
+/// ```
+#[derive(Default)]
+pub struct ButtplugServerBuilder {
+  options: ButtplugServerOptions,
+  comm_managers: Vec<CommManagerAdder>,
+  #[cfg(feature = "device-scripting")]
+  device_scripts: Vec<device_script::DeviceScript>,
+  middleware: Vec<Arc<dyn ButtplugServerMiddleware>>,
+}
+
+impl ButtplugServerBuilder {
+  pub fn new(options: ButtplugServerOptions) -> Self {
+    Self {
+      options,
+      comm_managers: vec![],
+      #[cfg(feature = "device-scripting")]
+      device_scripts: vec![],
+      middleware: vec![],
+    }
+  }
+
+  /// Adds a comm manager. Comm managers are added in the order given, and
+  /// adding two of the same type fails [finish][Self::finish].
+  pub fn comm_manager<T>(mut self, builder: T) -> Self
+  where
+    T: DeviceCommunicationManagerBuilder + 'static,
+  {
+    self
+      .comm_managers
+      .push(Box::new(move |server: &ButtplugServer| {
+        server.add_comm_manager(builder)
+      }));
+    self
+  }
+
+  /// Adds every comm manager compiled in via features, same as
+  /// [ButtplugServer::add_default_comm_managers].
+  pub fn with_default_transports(mut self) -> Self {
+    self.comm_managers.push(Box::new(|server: &ButtplugServer| {
+      server.add_default_comm_managers()
+    }));
+    self
+  }
+
+  /// Adds the Bluetooth LE comm manager.
+  #[cfg(feature = "btleplug-manager")]
+  pub fn with_btleplug(self) -> Self {
+    self.comm_manager(comm_managers::btleplug::BtlePlugCommunicationManagerBuilder::default())
+  }
+
+  /// Adds the serial port comm manager.
+  #[cfg(feature = "serial-manager")]
+  pub fn with_serial_port(self) -> Self {
+    self.comm_manager(comm_managers::serialport::SerialPortCommunicationManagerBuilder::default())
+  }
+
+  /// Adds the USB comm manager.
+  #[cfg(feature = "usb-manager")]
+  pub fn with_usb(self) -> Self {
+    self.comm_manager(comm_managers::usb::UsbCommunicationManagerBuilder::default())
+  }
+
+  /// Adds the XInput (Xbox gamepad) comm manager.
+  #[cfg(all(feature = "xinput-manager", target_os = "windows"))]
+  pub fn with_xinput(self) -> Self {
+    self.comm_manager(comm_managers::xinput::XInputDeviceCommunicationManagerBuilder::default())
+  }
+
+  /// Adds a device script (see [device_script]). Scripts that don't compile
+  /// fail [finish][Self::finish].
+  #[cfg(feature = "device-scripting")]
+  pub fn device_script(mut self, script: device_script::DeviceScript) -> Self {
+    self.device_scripts.push(script);
+    self
+  }
+
+  /// Adds middleware (see [middleware]). Middleware runs in the order it's
+  /// added here, before any added later with
+  /// [ButtplugServer::add_middleware].
+  pub fn middleware(mut self, middleware: Arc<dyn ButtplugServerMiddleware>) -> Self {
+    self.middleware.push(middleware);
+    self
+  }
+
+  pub fn finish(self) -> Result<ButtplugServer, ButtplugServerError> {
+    let server = ButtplugServer::new_with_options(&self.options)?;
+    for middleware in self.middleware {
+      server.add_middleware(middleware);
+    }
+    for add_comm_manager in self.comm_managers {
+      add_comm_manager(&server)?;
+    }
+    #[cfg(feature = "device-scripting")]
+    for script in self.device_scripts {
+      server.add_device_script(script)?;
+    }
+    Ok(server)
+  }
+}
+
+/// The client a server is connected to, as of its handshake.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ButtplugClientInfo {
+  /// Name the client sent in RequestServerInfo.
+  pub name: String,
+  /// Message spec version the client asked for. Messages it sends are
+  /// translated up from this version, and it only sees what it supports.
+  pub message_version: ButtplugMessageSpecVersion,
+  /// Identity token the client connected with, if any.
+  pub identity_token: Option<String>,
+}
+
 /// Represents a ButtplugServer.
 pub struct ButtplugServer {
+  handler: Arc<MessageHandler>,
+  device_manager: Arc<DeviceManager>,
+  output_sender: broadcast::Sender<ButtplugServerMessage>,
+  filtered_events: FilteredEventDispatcher,
+  middleware: MiddlewareChain,
+  // Resolves once the last message sent through middleware has been handed to
+  // the server, so messages reach it in the order they came in.
+  middleware_dispatched: Mutex<Option<oneshot::Receiver<()>>>,
+  option_diagnostics: Vec<ServerDiagnostic>,
+}
+
+/// The parts of the server that answer client messages. Shared, so replies
+/// can still be worked out once middleware is done with a message.
+struct MessageHandler {
   server_name: String,
   max_ping_time: u64,
-  device_manager: DeviceManager,
+  max_session_time: u64,
+  session_stop_warning_time: u64,
+  device_manager: Arc<DeviceManager>,
   ping_timer: Arc<PingTimer>,
   connected: Arc<AtomicBool>,
-  output_sender: broadcast::Sender<ButtplugServerMessage>,
+  client_info: Arc<RwLock<Option<ButtplugClientInfo>>>,
+  log_forwarder: LogForwarder,
+  disconnect_stop_policy: DisconnectStopPolicy,
+  handoff_timeout: u64,
+  /// Identity token of the client devices were left running for.
+  pending_handoff: Mutex<Option<String>>,
+  /// Identity token for the next handshake, from the connector.
+  next_identity_token: Mutex<Option<String>>,
+  // Bumped on every handshake and disconnect, so delayed stops know whether
+  // a client has come along since they were set up.
+  disconnect_stop_generation: AtomicU64,
+  clock: Arc<dyn Clock>,
 }
 
 impl Default for ButtplugServer {
@@ -99,14 +415,35 @@ impl Default for ButtplugServer {
 
 impl ButtplugServer {
   pub fn new_with_options(options: &ButtplugServerOptions) -> Result<Self, ButtplugError> {
+    Self::new_with_clock(options, Arc::new(SystemClock))
+  }
+
+  /// Creates a server that times pings and device debouncing with the given
+  /// clock, instead of the system clock. Mostly useful for tests, with a
+  /// [ManualClock][crate::util::clock::ManualClock].
+  pub fn new_with_clock(
+    options: &ButtplugServerOptions,
+    clock: Arc<dyn Clock>,
+  ) -> Result<Self, ButtplugError> {
     debug!("Creating server '{}'", options.name);
+    let option_diagnostics = options.validate();
+    for diagnostic in &option_diagnostics {
+      warn!("{}", diagnostic);
+    }
+    if options.redact_device_identifiers {
+      logging::enable_identifier_redaction();
+    }
     let (send, _) = broadcast::channel(256);
     let output_sender_clone = send.clone();
     let connected = Arc::new(AtomicBool::new(false));
-    let ping_timer = Arc::new(PingTimer::new(options.max_ping_time));
+    let ping_timer = Arc::new(PingTimer::new_with_clock(
+      options.max_ping_time,
+      clock.clone(),
+    ));
     let ping_timeout_notifier = ping_timer.ping_timeout_waiter();
     let connected_clone = connected.clone();
-    async_manager::spawn(
+    async_manager::spawn_named(
+      "ping timeout watcher",
       async move {
         // This will only exit if we've pinged out.
         ping_timeout_notifier.await;
@@ -123,41 +460,192 @@ impl ButtplugServer {
       .instrument(tracing::info_span!("Buttplug Server Ping Timeout Task")),
     )
     .unwrap();
-    let device_manager = DeviceManager::try_new(
+    #[allow(unused_mut)]
+    let mut device_manager = DeviceManager::try_new(
       send.clone(),
       ping_timer.clone(),
-      options.allow_raw_messages,
-      &options.device_configuration_json,
-      &options.user_device_configuration_json,
+      &DeviceConfigurationOptions {
+        allow_raw_messages: options.allow_raw_messages,
+        allow_power_devices: options.allow_power_devices,
+        device_configuration_json: options.device_configuration_json.clone(),
+        user_device_configuration_json: options.user_device_configuration_json.clone(),
+      },
+      options.ignore_duty_cycle_limits,
+      options.device_filter.clone(),
+      options.max_scanning_time,
+      options.device_index_policy,
+      options.stop_all_devices_scope,
+      options.device_debounce_time,
+      options.max_concurrent_device_initializations,
+      options.known_devices.clone(),
+      clock.clone(),
     )?;
+    #[cfg(feature = "server-emulator")]
+    {
+      if let Some(json) = &options.emulated_devices_json {
+        device_manager.start_emulation(emulator::EmulatorConfig::from_json(json)?);
+      }
+    }
+    #[cfg(feature = "osc-bridge")]
+    {
+      if let Some(config) = &options.osc_bridge {
+        osc_bridge::start_osc_bridge(config.clone(), device_manager.devices());
+      }
+    }
+    let device_manager = Arc::new(device_manager);
     Ok(Self {
-      server_name: options.name.clone(),
-      max_ping_time: options.max_ping_time,
+      handler: Arc::new(MessageHandler {
+        server_name: options.name.clone(),
+        max_ping_time: options.max_ping_time,
+        max_session_time: options.max_session_time,
+        session_stop_warning_time: options.session_stop_warning_time,
+        device_manager: device_manager.clone(),
+        ping_timer,
+        connected,
+        client_info: Arc::new(RwLock::new(None)),
+        log_forwarder: LogForwarder::new(send.clone()),
+        disconnect_stop_policy: options.disconnect_stop_policy,
+        handoff_timeout: options.handoff_timeout,
+        pending_handoff: Mutex::new(None),
+        next_identity_token: Mutex::new(None),
+        disconnect_stop_generation: AtomicU64::new(0),
+        clock,
+      }),
       device_manager,
-      ping_timer,
-      connected,
+      filtered_events: FilteredEventDispatcher::new(send.clone()),
       output_sender: send,
+      middleware: MiddlewareChain::default(),
+      middleware_dispatched: Mutex::new(None),
+      option_diagnostics,
     })
   }
 
   pub fn event_stream(&self) -> impl Stream<Item = ButtplugServerMessage> {
     // Unlike the client API, we can expect anyone using the server to pin this
     // themselves.
-    convert_broadcast_receiver_to_stream(self.output_sender.subscribe())
+    self
+      .middleware
+      .event_stream(convert_broadcast_receiver_to_stream(
+        self.output_sender.subscribe(),
+      ))
+  }
+
+  /// Stream of unsolicited messages (message `id` 0) for connectors relaying
+  /// them to a client. Replies to client requests never come through here,
+  /// they're returned by [ButtplugServer::parse_message]. Falling behind
+  /// skips events instead of ending the stream, so a burst of sensor readings
+  /// can't take the connection down.
+  pub(super) fn system_event_stream(&self) -> impl Stream<Item = ButtplugServerMessage> {
+    self
+      .middleware
+      .event_stream(convert_broadcast_receiver_to_filtered_stream(
+        self.output_sender.subscribe(),
+        |msg| {
+          if msg.id() == 0 {
+            Some(msg)
+          } else {
+            error!("Server event has a non-system id, dropping: {:?}", msg);
+            None
+          }
+        },
+      ))
+  }
+
+  /// Like [ButtplugServer::event_stream], but only yields events that pass
+  /// the filter. Filtering happens before events are queued for the stream,
+  /// so unwanted events don't build up if the stream is polled slowly.
+  pub fn event_stream_filtered(
+    &self,
+    filter: EventFilter,
+  ) -> impl Stream<Item = ButtplugServerMessage> {
+    self
+      .middleware
+      .event_stream(convert_mpsc_receiver_to_stream(
+        self.filtered_events.subscribe(filter),
+      ))
+  }
+
+  /// Adds middleware (see [middleware]) after any already added. Messages
+  /// already being handled aren't run through it.
+  pub fn add_middleware(&self, middleware: Arc<dyn ButtplugServerMiddleware>) {
+    self.middleware.add(middleware);
   }
 
-  pub fn add_comm_manager<T>(&self, builder: T) -> Result<(), ButtplugServerError> where T: DeviceCommunicationManagerBuilder
+  pub fn add_comm_manager<T>(&self, builder: T) -> Result<(), ButtplugServerError>
+  where
+    T: DeviceCommunicationManagerBuilder,
   {
     self.device_manager.add_comm_manager(builder)
   }
 
+  /// Checks the server setup for things that work, but probably aren't what
+  /// was meant, like settings for protocols the server doesn't support. Run
+  /// this after adding comm managers.
+  pub fn validate(&self) -> Vec<ServerDiagnostic> {
+    let mut diagnostics = self.option_diagnostics.clone();
+    diagnostics.extend(self.device_manager.diagnostics());
+    diagnostics
+  }
+
+  /// Adds every device communication manager that was compiled in via
+  /// features.
+  pub fn add_default_comm_managers(&self) -> Result<(), ButtplugServerError> {
+    #[cfg(feature = "btleplug-manager")]
+    {
+      use self::comm_managers::btleplug::BtlePlugCommunicationManagerBuilder;
+      self.add_comm_manager(BtlePlugCommunicationManagerBuilder::default())?;
+    }
+    #[cfg(feature = "serial-manager")]
+    {
+      use self::comm_managers::serialport::SerialPortCommunicationManagerBuilder;
+      self.add_comm_manager(SerialPortCommunicationManagerBuilder::default())?;
+    }
+    #[cfg(feature = "usb-manager")]
+    {
+      use self::comm_managers::usb::UsbCommunicationManagerBuilder;
+      self.add_comm_manager(UsbCommunicationManagerBuilder::default())?;
+    }
+    #[cfg(feature = "lovense-connect-service-manager")]
+    {
+      use self::comm_managers::lovense_connect_service::LovenseConnectServiceCommunicationManagerBuilder;
+      self.add_comm_manager(LovenseConnectServiceCommunicationManagerBuilder::default())?;
+    }
+    #[cfg(feature = "lovense-dongle-manager")]
+    {
+      use self::comm_managers::lovense_dongle::{
+        LovenseHIDDongleCommunicationManagerBuilder, LovenseSerialDongleCommunicationManagerBuilder,
+      };
+      self.add_comm_manager(LovenseHIDDongleCommunicationManagerBuilder::default())?;
+      self.add_comm_manager(LovenseSerialDongleCommunicationManagerBuilder::default())?;
+    }
+    #[cfg(all(feature = "xinput-manager", target_os = "windows"))]
+    {
+      use self::comm_managers::xinput::XInputDeviceCommunicationManagerBuilder;
+      self.add_comm_manager(XInputDeviceCommunicationManagerBuilder::default())?;
+    }
+    Ok(())
+  }
+
   pub fn add_test_comm_manager(
     &self,
   ) -> Result<TestDeviceCommunicationManagerHelper, ButtplugServerError> {
     self.device_manager.add_test_comm_manager()
   }
 
-  pub fn add_protocol<T>(&self, protocol_name: &str) -> Result<(), ButtplugServerError> where T: ButtplugProtocol {
+  pub fn remove_comm_manager(&self, name: &str) -> Result<(), ButtplugServerError> {
+    self.device_manager.remove_comm_manager(name)
+  }
+
+  pub fn comm_manager_capabilities(
+    &self,
+  ) -> HashMap<String, DeviceCommunicationManagerCapabilities> {
+    self.device_manager.comm_manager_capabilities()
+  }
+
+  pub fn add_protocol<T>(&self, protocol_name: &str) -> Result<(), ButtplugServerError>
+  where
+    T: ButtplugProtocol,
+  {
     self.device_manager.add_protocol::<T>(protocol_name)
   }
 
@@ -169,103 +657,407 @@ impl ButtplugServer {
     self.device_manager.remove_all_protocols();
   }
 
+  pub fn add_device_group(
+    &self,
+    group_name: &str,
+    member_addresses: &[String],
+  ) -> Result<(), ButtplugServerError> {
+    self
+      .device_manager
+      .add_device_group(group_name, member_addresses)
+  }
+
+  /// Changes which devices are visible to the client. Devices that become
+  /// visible or hidden will have DeviceAdded/DeviceRemoved events sent for
+  /// them.
+  pub fn set_device_filter(&self, filter: DeviceFilter) {
+    self.device_manager.set_device_filter(filter);
+  }
+
+  /// Protocol, transport and endpoints of a connected device, for
+  /// diagnostics (see [device_info]). None if there's no device at the index,
+  /// or the device filter hides it.
+  pub fn device_info(&self, device_index: u32) -> Option<ServerDeviceInfo> {
+    self.device_manager.device_info(device_index)
+  }
+
+  /// Same as [ButtplugServer::device_info], for every connected device.
+  pub fn device_info_list(&self) -> Vec<ServerDeviceInfo> {
+    self.device_manager.device_info_list()
+  }
+
+  /// Starts scanning on all comm managers, without requiring a connected
+  /// client. Meant for applications embedding the server.
+  pub fn start_scanning(&self) -> ButtplugServerResultFuture {
+    self.device_manager.start_scanning()
+  }
+
+  pub fn stop_scanning(&self) -> ButtplugServerResultFuture {
+    self.device_manager.stop_scanning()
+  }
+
+  pub fn stop_all_devices(&self) -> ButtplugServerResultFuture {
+    self.device_manager.stop_all_devices()
+  }
+
+  pub fn disconnect_device(&self, device_index: u32) -> ButtplugServerResultFuture {
+    self.device_manager.disconnect_device(device_index)
+  }
+
+  /// Turns a device off, for devices with a command for it (e.g. Lovense).
+  /// Errors for devices that can't be turned off.
+  pub fn power_off_device(&self, device_index: u32) -> ButtplugServerResultFuture {
+    self.device_manager.power_off_device(device_index)
+  }
+
+  /// Turns off every device that can be, and stops the rest, so toys aren't
+  /// left idling on their batteries once a session is over.
+  pub fn power_off_all_devices(&self) -> ButtplugServerResultFuture {
+    self.device_manager.power_off_all_devices()
+  }
+
+  /// Sends commands to several devices at once, for choreography where
+  /// devices drifting apart would show (e.g. two strokers moving together).
+  /// Nothing is sent unless every command can be, and no device's write
+  /// waits on another's, though when writes land is still up to the
+  /// transports. Takes at most one command per device.
+  pub fn send_synchronized(
+    &self,
+    commands: Vec<ButtplugDeviceCommandMessageUnion>,
+  ) -> ButtplugServerResultFuture {
+    self.device_manager.send_synchronized(commands)
+  }
+
+  /// Arms a hard stop of all devices `delay` from now, replacing any stop
+  /// already armed. The stop is enforced by the server whatever the client
+  /// does: once it fires, device commands are refused until
+  /// [ButtplugServer::cancel_scheduled_stop] is called. A
+  /// [ScheduledStopEvent::Warning] is sent `warning` before the stop, unless
+  /// `warning` is zero.
+  pub fn schedule_stop_after(&self, delay: Duration, warning: Duration) {
+    self.device_manager.schedule_stop(delay, warning);
+  }
+
+  /// Like [ButtplugServer::schedule_stop_after], but stops at a wall clock
+  /// time. Times in the past stop right away.
+  pub fn schedule_stop_at(&self, time: SystemTime, warning: Duration) {
+    let delay = time
+      .duration_since(SystemTime::now())
+      .unwrap_or(Duration::ZERO);
+    self.device_manager.schedule_stop(delay, warning);
+  }
+
+  /// Disarms a pending scheduled stop. If one already fired, devices accept
+  /// commands again.
+  pub fn cancel_scheduled_stop(&self) {
+    self.device_manager.cancel_scheduled_stop();
+  }
+
+  /// Time left until the scheduled stop, if one is armed.
+  pub fn scheduled_stop_remaining(&self) -> Option<Duration> {
+    self.device_manager.scheduled_stop_remaining()
+  }
+
+  pub fn scheduled_stop_event_stream(&self) -> impl Stream<Item = ScheduledStopEvent> {
+    self.device_manager.scheduled_stop_event_stream()
+  }
+
+  /// Handle for stopping all devices from outside the client connection, for
+  /// wiring up a hardware panic button (see [emergency_stop]). Triggering it
+  /// doesn't go through middleware or the client message path, and works from
+  /// any thread.
+  pub fn emergency_stop_handle(&self) -> EmergencyStop {
+    self.device_manager.emergency_stop()
+  }
+
+  /// Stops all devices, as if the [ButtplugServer::emergency_stop_handle]
+  /// handle was triggered.
+  pub fn emergency_stop(&self) {
+    self.device_manager.emergency_stop().trigger();
+  }
+
+  /// Starts driving a device's vibration from a pressure sensor (see
+  /// [pressure_loop]). Resolves to an id for
+  /// [ButtplugServer::stop_pressure_loop] once the sensor is subscribed.
+  /// Fails if either device isn't connected, the sensor isn't a pressure
+  /// sensor, or the device to vibrate can't.
+  pub fn start_pressure_loop(
+    &self,
+    config: PressureLoopConfig,
+  ) -> BoxFuture<'static, Result<u32, ButtplugError>> {
+    self.device_manager.start_pressure_loop(config)
+  }
+
+  /// Stops a pressure loop, along with the vibration it was driving. Loops
+  /// also stop by themselves when either device disconnects. Returns false
+  /// if the loop isn't running.
+  pub fn stop_pressure_loop(&self, id: u32) -> bool {
+    self.device_manager.stop_pressure_loop(id)
+  }
+
+  /// Compiles and adds a device script (see [device_script]). Its
+  /// transforms apply to commands sent after this returns.
+  #[cfg(feature = "device-scripting")]
+  pub fn add_device_script(
+    &self,
+    script: device_script::DeviceScript,
+  ) -> Result<(), device_script::DeviceScriptError> {
+    self.device_manager.add_device_script(script)
+  }
+
+  /// Removes a device script. Returns false if there's no script with the
+  /// name.
+  #[cfg(feature = "device-scripting")]
+  pub fn remove_device_script(&self, name: &str) -> bool {
+    self.device_manager.remove_device_script(name)
+  }
+
+  /// Starts running a device script's `pattern` function on a device.
+  /// Returns an id for [ButtplugServer::stop_script_pattern]. Patterns also
+  /// stop by themselves when the device disconnects, or the script fails.
+  #[cfg(feature = "device-scripting")]
+  pub fn start_script_pattern(
+    &self,
+    device_index: u32,
+    script_name: &str,
+  ) -> Result<u32, device_script::DeviceScriptError> {
+    self
+      .device_manager
+      .start_script_pattern(device_index, script_name)
+  }
+
+  /// Stops a script pattern, along with the vibration it was driving.
+  /// Returns false if the pattern isn't running.
+  #[cfg(feature = "device-scripting")]
+  pub fn stop_script_pattern(&self, id: u32) -> bool {
+    self.device_manager.stop_script_pattern(id)
+  }
+
+  /// Devices that have connected to this server, or were passed in through
+  /// [ButtplugServerOptions::known_devices], most recently connected first.
+  /// Applications can save these to remember devices across restarts.
+  pub fn known_devices(&self) -> Vec<KnownDevice> {
+    self.device_manager.known_devices()
+  }
+
+  /// Bluetooth devices known to be bonded with the OS: the user
+  /// configuration's `bonded-devices`, plus devices that bonded while this
+  /// server ran. Applications can save these to the user configuration's
+  /// `bonded-devices` list, so bonded devices skip the bonding check next
+  /// time.
+  pub fn bonded_devices(&self) -> Vec<String> {
+    self.device_manager.bonded_devices()
+  }
+
+  /// Forgets a known device. Returns false if no known device had the
+  /// identity key.
+  pub fn forget_known_device(&self, identity_key: &str) -> bool {
+    self.device_manager.forget_known_device(identity_key)
+  }
+
+  /// Stream of [KnownDeviceEvent]s, sent when scanning finds a device that
+  /// has connected before.
+  pub fn known_device_event_stream(&self) -> impl Stream<Item = KnownDeviceEvent> {
+    self.device_manager.known_device_event_stream()
+  }
+
+  /// Stream of [DeviceConnectionEvent]s, sent while found devices are being
+  /// connected and initialized.
+  pub fn device_connection_event_stream(&self) -> impl Stream<Item = DeviceConnectionEvent> {
+    self.device_manager.device_connection_event_stream()
+  }
+
   pub fn connected(&self) -> bool {
-    self.connected.load(Ordering::SeqCst)
+    self.handler.connected()
+  }
+
+  /// Name and message spec version of the connected client, if there is one.
+  /// Lets applications warn people still using clients on old spec versions.
+  pub fn client_info(&self) -> Option<ButtplugClientInfo> {
+    if !self.connected() {
+      return None;
+    }
+    self.handler.client_info.read().unwrap().clone()
+  }
+
+  /// Sets the identity token the next client to handshake connected with.
+  /// Tokens aren't part of the message spec, so they come from the
+  /// connector, e.g. a websocket handshake header, and whoever runs the
+  /// connector passes them on here before the handshake is parsed.
+  pub fn set_client_identity_token(&self, identity_token: Option<String>) {
+    *self.handler.next_identity_token.lock().unwrap() = identity_token;
   }
 
   pub fn disconnect(&self) -> BoxFuture<Result<(), messages::Error>> {
-    debug!("Buttplug Server {} disconnect requested", self.server_name);
-    let ping_timer = self.ping_timer.clone();
-    let stop_scanning_fut =
-      self.parse_message(ButtplugClientMessage::StopScanning(StopScanning::default()));
-    let stop_fut = self.parse_message(ButtplugClientMessage::StopAllDevices(
-      StopAllDevices::default(),
-    ));
-    let connected = self.connected.clone();
+    debug!(
+      "Buttplug Server {} disconnect requested",
+      self.handler.server_name
+    );
+    let ping_timer = self.handler.ping_timer.clone();
+    // These go straight to the device manager, so middleware can't refuse
+    // them.
+    let stop_scanning_fut = self.device_manager.stop_scanning();
+    let handler = self.handler.clone();
+    // Whoever connects next will have to ask for logs again.
+    self.handler.log_forwarder.set_level(LogLevel::Off);
+    let handoff_token = self
+      .handler
+      .client_info
+      .write()
+      .unwrap()
+      .take()
+      .and_then(|info| info.identity_token)
+      .filter(|_| self.handler.handoff_timeout > 0);
     Box::pin(async move {
-      connected.store(false, Ordering::SeqCst);
+      handler.connected.store(false, Ordering::SeqCst);
       ping_timer.stop_ping_timer().await;
       // Ignore returns here, we just want to stop.
       info!("Server disconnected, stopping device scanning if it was started...");
       let _ = stop_scanning_fut.await;
-      info!("Server disconnected, stopping all devices...");
-      let _ = stop_fut.await;
+      if let Some(token) = handoff_token {
+        info!(
+          "Server disconnected, leaving devices running for {}ms for the client to come back.",
+          handler.handoff_timeout
+        );
+        *handler.pending_handoff.lock().unwrap() = Some(token);
+        handler.stop_devices_after(Duration::from_millis(handler.handoff_timeout));
+        return Ok(());
+      }
+      match handler.disconnect_stop_policy {
+        DisconnectStopPolicy::StopImmediately => {
+          info!("Server disconnected, stopping all devices...");
+          let _ = handler.device_manager.stop_client_devices().await;
+        }
+        DisconnectStopPolicy::StopAfterGracePeriod(grace_period) => {
+          info!(
+            "Server disconnected, stopping all devices in {}ms unless a client connects.",
+            grace_period
+          );
+          handler.stop_devices_after(Duration::from_millis(grace_period));
+        }
+        DisconnectStopPolicy::KeepRunning => {
+          info!("Server disconnected, leaving devices running.");
+        }
+      }
       Ok(())
     })
   }
 
   // This is the only method that returns ButtplugServerResult, as it handles
   // the packing of the message ID.
-  pub fn parse_message(
-    &self,
-    msg: ButtplugClientMessage,
-  ) -> BoxFuture<'static, Result<ButtplugServerMessage, messages::Error>> {
+  pub fn parse_message(&self, msg: ButtplugClientMessage) -> ButtplugServerReplyFuture {
     trace!(
       "Buttplug Server {} received message to client parse: {:?}",
-      self.server_name,
+      self.handler.server_name,
       msg
     );
     let id = msg.id();
+    let span = info_span!("Buttplug Server Message", id = id);
     if !self.connected() {
       // Check for ping timeout first! There's no way we should've pinged out if
       // we haven't received RequestServerInfo first, but we do want to know if
       // we pinged out.
-      let error = if self.ping_timer.pinged_out() {
-        Some(messages::Error::from(ButtplugError::from(
-          ButtplugPingError::PingedOut,
-        )))
+      let error = if self.handler.ping_timer.pinged_out() {
+        Some(ButtplugError::from(ButtplugPingError::PingedOut))
       } else if !matches!(msg, ButtplugClientMessage::RequestServerInfo(_)) {
-        Some(messages::Error::from(ButtplugError::from(
+        Some(ButtplugError::from(
           ButtplugHandshakeError::RequestServerInfoExpected,
-        )))
+        ))
       } else {
         None
       };
-      if let Some(mut return_error) = error {
-        return_error.set_id(msg.id());
-        return Box::pin(future::ready(Err(return_error)));
+      if let Some(error) = error {
+        return ButtplugServerReplyFuture::new(
+          id,
+          span,
+          ButtplugReadyOrBoxedFuture::ready(Err(error)),
+        );
       }
       // If we haven't pinged out and we got an RSI message, fall thru.
     }
     // Produce whatever future is needed to reply to the message, this may be a
     // device command future, or something the server handles. All futures will
-    // return Result<ButtplugServerMessage, ButtplugError>, and we'll handle
-    // tagging the result with the message id in the future we put out as the
-    // return value from this method.
-    let out_fut = if ButtplugDeviceManagerMessageUnion::try_from(msg.clone()).is_ok()
-      || ButtplugDeviceCommandMessageUnion::try_from(msg.clone()).is_ok()
-    {
-      self.device_manager.parse_message(msg.clone())
+    // return Result<ButtplugServerMessage, ButtplugError>, and the reply future
+    // handles tagging the result with the message id.
+    let out_fut = if self.middleware.is_empty() {
+      self.handler.handle(msg)
     } else {
-      match msg {
-        ButtplugClientMessage::RequestServerInfo(rsi_msg) => self.perform_handshake(rsi_msg),
-        ButtplugClientMessage::Ping(p) => self.handle_ping(p),
-        _ => ButtplugMessageError::UnexpectedMessageType(format!("{:?}", msg)).into(),
-      }
+      let handler = self.handler.clone();
+      let middleware = self.middleware.snapshot();
+      // Middleware can take any amount of time, so each message waits for the
+      // one before it to be handed to the server. Otherwise a stop could get
+      // through ahead of the command it was meant to stop.
+      let (dispatched_sender, dispatched_receiver) = oneshot::channel();
+      let previous = self
+        .middleware_dispatched
+        .lock()
+        .unwrap()
+        .replace(dispatched_receiver);
+      ButtplugReadyOrBoxedFuture::boxed(async move {
+        if let Some(previous) = previous {
+          // Errors just mean the previous message was refused or dropped.
+          let _ = previous.await;
+        }
+        let msg = middleware.client_message(msg).await?;
+        let reply = handler.handle(msg);
+        let _ = dispatched_sender.send(());
+        let reply = reply.await?;
+        middleware.server_message(reply).await
+      })
     };
-    // Simple way to set the ID on the way out. Just rewrap
-    // the returned future to make sure it happens.
-    Box::pin(
-      async move {
-        out_fut
-          .await
-          .map(|mut ok_msg| {
-            ok_msg.set_id(id);
-            ok_msg
-          })
-          .map_err(|err| {
-            let mut error = messages::Error::from(err);
-            error.set_id(id);
-            error
-          })
+    ButtplugServerReplyFuture::new(id, span, out_fut)
+  }
+}
+
+impl MessageHandler {
+  fn connected(&self) -> bool {
+    self.connected.load(Ordering::SeqCst)
+  }
+
+  /// Stops devices once `delay` is up, unless a client has connected, or the
+  /// server has disconnected again, since.
+  fn stop_devices_after(self: &Arc<Self>, delay: Duration) {
+    let generation = self
+      .disconnect_stop_generation
+      .fetch_add(1, Ordering::SeqCst)
+      + 1;
+    // Start the wait now, so it runs from the disconnect, not from whenever
+    // the task gets scheduled.
+    let wait = self.clock.sleep(delay);
+    let handler = self.clone();
+    async_manager::spawn(async move {
+      wait.await;
+      if handler.disconnect_stop_generation.load(Ordering::SeqCst) != generation {
+        return;
       }
-      .instrument(info_span!("Buttplug Server Message", id = id)),
-    )
+      *handler.pending_handoff.lock().unwrap() = None;
+      info!("Nobody connected in time, stopping all devices...");
+      if let Err(e) = handler.device_manager.stop_client_devices().await {
+        error!("Could not stop devices after disconnect: {:?}", e);
+      }
+    })
+    .unwrap();
+  }
+
+  fn handle(&self, msg: ButtplugClientMessage) -> ButtplugReadyOrBoxedFuture<ButtplugServerResult> {
+    match msg {
+      ButtplugClientMessage::RequestServerInfo(rsi_msg) => self.perform_handshake(rsi_msg),
+      ButtplugClientMessage::Ping(p) => self.handle_ping(p),
+      ButtplugClientMessage::RequestLog(msg) => self.handle_request_log(msg),
+      // Everything else is either for the device manager or for a device, or
+      // something we don't handle, which the device manager will reject.
+      msg => self.device_manager.parse_message(msg),
+    }
   }
 
-  fn perform_handshake(&self, msg: messages::RequestServerInfo) -> ButtplugServerResultFuture {
+  fn perform_handshake(
+    &self,
+    msg: messages::RequestServerInfo,
+  ) -> ButtplugReadyOrBoxedFuture<ButtplugServerResult> {
     if self.connected() {
-      return ButtplugHandshakeError::HandshakeAlreadyHappened.into();
+      return ButtplugReadyOrBoxedFuture::ready(Err(
+        ButtplugHandshakeError::HandshakeAlreadyHappened.into(),
+      ));
     }
     info!(
       "Performing server handshake check with client {} at message version {}.",
@@ -273,12 +1065,38 @@ impl ButtplugServer {
       msg.message_version()
     );
     if BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION < msg.message_version() {
-      return ButtplugHandshakeError::MessageSpecVersionMismatch(
-        BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION,
-        msg.message_version(),
-      )
-      .into();
+      return ButtplugReadyOrBoxedFuture::ready(Err(
+        ButtplugHandshakeError::MessageSpecVersionMismatch(
+          BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION,
+          msg.message_version(),
+        )
+        .into(),
+      ));
+    }
+    if self.max_session_time > 0 && !self.device_manager.scheduled_stop_active() {
+      self.device_manager.schedule_stop(
+        Duration::from_millis(self.max_session_time),
+        Duration::from_millis(self.session_stop_warning_time),
+      );
     }
+    // Cancels any stop waiting to see if a client comes along.
+    self
+      .disconnect_stop_generation
+      .fetch_add(1, Ordering::SeqCst);
+    let identity_token = self.next_identity_token.lock().unwrap().take();
+    // Devices left running for a handoff only go to the client they were left
+    // for. Anyone else gets them stopped.
+    let handoff_stop = match self.pending_handoff.lock().unwrap().take() {
+      Some(token) if identity_token.as_ref() != Some(&token) => {
+        info!("Client is not the one devices were left running for, stopping them.");
+        Some(self.device_manager.stop_client_devices())
+      }
+      Some(_) => {
+        info!("Client is taking over devices from its last connection.");
+        None
+      }
+      None => None,
+    };
     // Only start the ping timer after we've received the handshake.
     let ping_timer = self.ping_timer.clone();
     let out_msg = messages::ServerInfo::new(
@@ -287,31 +1105,98 @@ impl ButtplugServer {
       self.max_ping_time.try_into().unwrap(),
     );
     let connected = self.connected.clone();
-    Box::pin(async move {
+    let client_info = self.client_info.clone();
+    let info = ButtplugClientInfo {
+      name: msg.client_name().clone(),
+      message_version: msg.message_version(),
+      identity_token,
+    };
+    ButtplugReadyOrBoxedFuture::boxed(async move {
+      if let Some(handoff_stop) = handoff_stop {
+        if let Err(e) = handoff_stop.await {
+          error!("Could not stop devices left for another client: {:?}", e);
+        }
+      }
       ping_timer.start_ping_timer().await;
+      *client_info.write().unwrap() = Some(info);
       connected.store(true, Ordering::SeqCst);
       debug!("Server handshake check successful.");
       Result::Ok(out_msg.into())
     })
   }
 
-  fn handle_ping(&self, msg: messages::Ping) -> ButtplugServerResultFuture {
+  /// Starts, updates, or stops forwarding of log records as Log messages.
+  /// Records only make it here if the application has set up a
+  /// [ButtplugLogLayer][log_forwarding::ButtplugLogLayer].
+  fn handle_request_log(
+    &self,
+    msg: messages::RequestLog,
+  ) -> ButtplugReadyOrBoxedFuture<ButtplugServerResult> {
+    info!("Client requested log level {:?}", msg.log_level());
+    self.log_forwarder.set_level(msg.log_level().clone());
+    ButtplugReadyOrBoxedFuture::ready(Ok(messages::Ok::default().into()))
+  }
+
+  fn handle_ping(&self, msg: messages::Ping) -> ButtplugReadyOrBoxedFuture<ButtplugServerResult> {
     if self.max_ping_time == 0 {
-      return ButtplugPingError::PingTimerNotRunning.into();
+      return ButtplugReadyOrBoxedFuture::ready(Err(ButtplugPingError::PingTimerNotRunning.into()));
     }
     let fut = self.ping_timer.update_ping_time();
-    Box::pin(async move {
+    ButtplugReadyOrBoxedFuture::boxed(async move {
       fut.await;
       Result::Ok(messages::Ok::new(msg.id()).into())
     })
   }
 }
 
+/// Future for the reply to a message passed to [ButtplugServer::parse_message].
+///
+/// Sets the message id on whatever comes back. Messages the server can answer
+/// right away (handshake errors, pings without a ping timer, unknown device
+/// indexes, etc) don't allocate.
+pub struct ButtplugServerReplyFuture {
+  id: u32,
+  span: tracing::Span,
+  inner: ButtplugReadyOrBoxedFuture<ButtplugServerResult>,
+}
+
+impl ButtplugServerReplyFuture {
+  fn new(
+    id: u32,
+    span: tracing::Span,
+    inner: ButtplugReadyOrBoxedFuture<ButtplugServerResult>,
+  ) -> Self {
+    Self { id, span, inner }
+  }
+}
+
+impl Future for ButtplugServerReplyFuture {
+  type Output = Result<ButtplugServerMessage, messages::Error>;
+
+  fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+    let this = self.get_mut();
+    let id = this.id;
+    let _enter = this.span.enter();
+    Pin::new(&mut this.inner).poll(cx).map(|result| {
+      result
+        .map(|mut ok_msg| {
+          ok_msg.set_id(id);
+          ok_msg
+        })
+        .map_err(|err| {
+          let mut error = messages::Error::from(err);
+          error.set_id(id);
+          error
+        })
+    })
+  }
+}
+
 #[cfg(test)]
 mod test {
   use crate::{
-    core::messages::{self, BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION},
-    server::ButtplugServer,
+    core::messages::{self, ButtplugMessageSpecVersion, BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION},
+    server::{ButtplugClientInfo, ButtplugServer},
     util::async_manager,
   };
 
@@ -340,4 +1225,25 @@ mod test {
       );
     });
   }
+
+  #[test]
+  fn test_client_info() {
+    async_manager::block_on(async {
+      let server = ButtplugServer::default();
+      assert_eq!(server.client_info(), None);
+      let msg =
+        messages::RequestServerInfo::new("Old Client", ButtplugMessageSpecVersion::Version1);
+      server.parse_message(msg.into()).await.unwrap();
+      assert_eq!(
+        server.client_info(),
+        Some(ButtplugClientInfo {
+          name: "Old Client".to_owned(),
+          message_version: ButtplugMessageSpecVersion::Version1,
+          identity_token: None,
+        })
+      );
+      server.disconnect().await.unwrap();
+      assert_eq!(server.client_info(), None);
+    });
+  }
 }
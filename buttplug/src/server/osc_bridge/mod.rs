@@ -0,0 +1,409 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2021 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! OSC (Open Sound Control) bridge.
+//!
+//! Lets OSC senders (VRChat avatars being the main use case) drive devices
+//! connected to a server, and reports device sensor values back out over OSC.
+//! The bridge is configured with a mapping table in [ButtplugServerOptions],
+//! and talks to devices directly rather than through the client protocol, so
+//! it works whether or not a client is connected.
+//!
+//! [ButtplugServerOptions]: super::ButtplugServerOptions
+
+mod osc_packet;
+
+pub use osc_packet::{OscArgument, OscMessage};
+
+use crate::{
+  core::messages::{
+    BatteryLevelCmd, ButtplugDeviceCommandMessageUnion, ButtplugDeviceMessageType,
+    ButtplugServerMessage, LinearCmd, RSSILevelCmd, RotateCmd, RotationSubcommand,
+    VectorSubcommand, VibrateCmd, VibrateSubcommand,
+  },
+  device::{ButtplugDevice, ButtplugDeviceResultFuture},
+  util::async_manager,
+};
+use dashmap::DashMap;
+use futures::{select, FutureExt};
+use futures_timer::Delay;
+use std::{
+  net::SocketAddr,
+  sync::{Arc, Weak},
+  time::Duration,
+};
+use tokio::net::UdpSocket;
+
+/// Duration used for LinearCmd moves triggered over OSC. OSC parameters are a
+/// stream of positions with no timing information, so we assume updates come
+/// in at roughly this rate.
+const OSC_LINEAR_MOVE_DURATION_MS: u32 = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OscActuatorType {
+  /// Value is vibration speed, 0.0-1.0.
+  Vibrate,
+  /// Value is rotation speed, -1.0-1.0. Negative values rotate
+  /// counterclockwise.
+  Rotate,
+  /// Value is position, 0.0-1.0.
+  Linear,
+}
+
+impl OscActuatorType {
+  fn message_type(&self) -> ButtplugDeviceMessageType {
+    match self {
+      OscActuatorType::Vibrate => ButtplugDeviceMessageType::VibrateCmd,
+      OscActuatorType::Rotate => ButtplugDeviceMessageType::RotateCmd,
+      OscActuatorType::Linear => ButtplugDeviceMessageType::LinearCmd,
+    }
+  }
+}
+
+/// Maps an incoming OSC address to device actuators.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OscInputMapping {
+  pub osc_address: String,
+  pub actuator: OscActuatorType,
+  /// Address of the device to control. If None, every connected device with
+  /// the actuator type is controlled.
+  pub device_address: Option<String>,
+  /// Feature index to control. If None, all features of the actuator type on
+  /// the device are set to the same value.
+  pub feature_index: Option<u32>,
+}
+
+impl OscInputMapping {
+  pub fn new(osc_address: &str, actuator: OscActuatorType) -> Self {
+    Self {
+      osc_address: osc_address.to_owned(),
+      actuator,
+      device_address: None,
+      feature_index: None,
+    }
+  }
+
+  pub fn device_address(mut self, address: &str) -> Self {
+    self.device_address = Some(address.to_owned());
+    self
+  }
+
+  pub fn feature_index(mut self, index: u32) -> Self {
+    self.feature_index = Some(index);
+    self
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OscSensorType {
+  /// Sent as a float, 0.0-1.0.
+  Battery,
+  /// Sent as an int, in dBm.
+  Rssi,
+}
+
+/// Maps a device sensor to an outgoing OSC address. Sensors are polled at the
+/// bridge's sensor poll interval.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OscOutputMapping {
+  pub osc_address: String,
+  pub sensor: OscSensorType,
+  pub device_address: String,
+}
+
+impl OscOutputMapping {
+  pub fn new(osc_address: &str, sensor: OscSensorType, device_address: &str) -> Self {
+    Self {
+      osc_address: osc_address.to_owned(),
+      sensor,
+      device_address: device_address.to_owned(),
+    }
+  }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct OscBridgeConfig {
+  /// Address to receive OSC messages on. Defaults to 127.0.0.1:9001, which is
+  /// where VRChat sends avatar parameters.
+  pub listen_address: SocketAddr,
+  /// Address to send sensor values to. Defaults to 127.0.0.1:9000, which is
+  /// where VRChat listens. If None, output mappings are ignored.
+  pub send_address: Option<SocketAddr>,
+  pub inputs: Vec<OscInputMapping>,
+  pub outputs: Vec<OscOutputMapping>,
+  pub sensor_poll_interval: Duration,
+}
+
+impl Default for OscBridgeConfig {
+  fn default() -> Self {
+    Self {
+      listen_address: "127.0.0.1:9001".parse().unwrap(),
+      send_address: Some("127.0.0.1:9000".parse().unwrap()),
+      inputs: vec![],
+      outputs: vec![],
+      sensor_poll_interval: Duration::from_secs(5),
+    }
+  }
+}
+
+/// Builds the device commands an OSC value should turn into, using the
+/// device's attributes to figure out which features exist. Returns None if the
+/// mapping doesn't apply to the device.
+fn build_command(
+  mapping: &OscInputMapping,
+  device: &ButtplugDevice,
+  value: f64,
+) -> Option<ButtplugDeviceCommandMessageUnion> {
+  if let Some(address) = &mapping.device_address {
+    if address != device.address() {
+      return None;
+    }
+  }
+  let feature_count = device
+    .message_attributes()
+    .get(&mapping.actuator.message_type())?
+    .feature_count
+    .unwrap_or(0);
+  let indexes: Vec<u32> = match mapping.feature_index {
+    Some(index) if index < feature_count => vec![index],
+    Some(index) => {
+      warn!(
+        "OSC mapping {} uses feature index {}, but device {} only has {} features.",
+        mapping.osc_address,
+        index,
+        device.address(),
+        feature_count
+      );
+      return None;
+    }
+    None => (0..feature_count).collect(),
+  };
+  if indexes.is_empty() {
+    return None;
+  }
+  let cmd: ButtplugDeviceCommandMessageUnion = match mapping.actuator {
+    OscActuatorType::Vibrate => {
+      let speed = value.max(0.0).min(1.0);
+      VibrateCmd::new(
+        0,
+        indexes
+          .iter()
+          .map(|index| VibrateSubcommand::new(*index, speed))
+          .collect(),
+      )
+      .into()
+    }
+    OscActuatorType::Rotate => {
+      let speed = value.abs().min(1.0);
+      RotateCmd::new(
+        0,
+        indexes
+          .iter()
+          .map(|index| RotationSubcommand::new(*index, speed, value >= 0.0))
+          .collect(),
+      )
+      .into()
+    }
+    OscActuatorType::Linear => {
+      let position = value.max(0.0).min(1.0);
+      LinearCmd::new(
+        0,
+        indexes
+          .iter()
+          .map(|index| VectorSubcommand::new(*index, OSC_LINEAR_MOVE_DURATION_MS, position))
+          .collect(),
+      )
+      .into()
+    }
+  };
+  Some(cmd)
+}
+
+/// Turns an OSC message into device command futures, for every mapping and
+/// device it applies to.
+fn handle_osc_message(
+  config: &OscBridgeConfig,
+  devices: &DashMap<u32, Arc<ButtplugDevice>>,
+  message: &OscMessage,
+) -> Vec<ButtplugDeviceResultFuture> {
+  let mut futures = vec![];
+  let value = match message.args.first() {
+    Some(arg) => arg.as_f64(),
+    None => return futures,
+  };
+  for mapping in config
+    .inputs
+    .iter()
+    .filter(|mapping| mapping.osc_address == message.address)
+  {
+    for device in devices.iter() {
+      if let Some(cmd) = build_command(mapping, device.value(), value) {
+        futures.push(device.value().parse_message(cmd));
+      }
+    }
+  }
+  futures
+}
+
+async fn send_sensor_values(
+  socket: &UdpSocket,
+  send_address: SocketAddr,
+  outputs: &[OscOutputMapping],
+  devices: &DashMap<u32, Arc<ButtplugDevice>>,
+) {
+  for output in outputs {
+    let device = match devices
+      .iter()
+      .find(|device| device.value().address() == output.device_address)
+    {
+      Some(device) => device.value().clone(),
+      None => continue,
+    };
+    let cmd: ButtplugDeviceCommandMessageUnion = match output.sensor {
+      OscSensorType::Battery => BatteryLevelCmd::new(0).into(),
+      OscSensorType::Rssi => RSSILevelCmd::new(0).into(),
+    };
+    let arg = match device.parse_message(cmd).await {
+      Ok(ButtplugServerMessage::BatteryLevelReading(reading)) => {
+        OscArgument::Float(reading.battery_level() as f32)
+      }
+      Ok(ButtplugServerMessage::RSSILevelReading(reading)) => {
+        OscArgument::Int(reading.rssi_level())
+      }
+      Ok(msg) => {
+        warn!("Unexpected sensor response for OSC output: {:?}", msg);
+        continue;
+      }
+      Err(err) => {
+        debug!(
+          "Cannot read sensor for OSC address {}: {:?}",
+          output.osc_address, err
+        );
+        continue;
+      }
+    };
+    let packet = OscMessage::new(&output.osc_address, vec![arg]).encode();
+    if let Err(err) = socket.send_to(&packet, send_address).await {
+      error!("Cannot send OSC packet to {}: {:?}", send_address, err);
+    }
+  }
+}
+
+/// Runs the bridge until the device map it was handed goes away, which
+/// happens when the server owning it is dropped.
+async fn run_osc_bridge(config: OscBridgeConfig, devices: Weak<DashMap<u32, Arc<ButtplugDevice>>>) {
+  let socket = match UdpSocket::bind(config.listen_address).await {
+    Ok(socket) => socket,
+    Err(err) => {
+      error!(
+        "Cannot bind OSC bridge to {}, bridge will not run: {:?}",
+        config.listen_address, err
+      );
+      return;
+    }
+  };
+  info!("OSC bridge listening on {}", config.listen_address);
+  let mut buf = vec![0u8; 65536];
+  // Kept across packets, so a steady stream of input can't keep pushing the
+  // next sensor poll back.
+  let mut sensor_poll = Delay::new(config.sensor_poll_interval);
+  loop {
+    select! {
+      result = socket.recv_from(&mut buf).fuse() => {
+        let devices = match devices.upgrade() {
+          Some(devices) => devices,
+          None => break,
+        };
+        let len = match result {
+          Ok((len, _)) => len,
+          Err(err) => {
+            error!("Error receiving OSC packet: {:?}", err);
+            continue;
+          }
+        };
+        match osc_packet::decode_packet(&buf[..len]) {
+          Ok(messages) => {
+            for message in &messages {
+              for fut in handle_osc_message(&config, &devices, message) {
+                let address = message.address.clone();
+                async_manager::spawn(async move {
+                  if let Err(err) = fut.await {
+                    error!("Error running device command for OSC address {}: {:?}", address, err);
+                  }
+                })
+                .unwrap();
+              }
+            }
+          }
+          Err(err) => warn!("Received invalid OSC packet: {}", err),
+        }
+      },
+      _ = (&mut sensor_poll).fuse() => {
+        sensor_poll.reset(config.sensor_poll_interval);
+        let devices = match devices.upgrade() {
+          Some(devices) => devices,
+          None => break,
+        };
+        if let Some(send_address) = config.send_address {
+          send_sensor_values(&socket, send_address, &config.outputs, &devices).await;
+        }
+      }
+    }
+  }
+  info!("Server dropped, exiting OSC bridge.");
+}
+
+pub(super) fn start_osc_bridge(
+  config: OscBridgeConfig,
+  devices: &Arc<DashMap<u32, Arc<ButtplugDevice>>>,
+) {
+  let devices = Arc::downgrade(devices);
+  async_manager::spawn_named("osc bridge", async move {
+    run_osc_bridge(config, devices).await;
+  })
+  .unwrap();
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::{
+    device::{DeviceImplCommand, DeviceWriteCmd, Endpoint},
+    test::{check_test_recv_value, new_bluetoothle_test_device},
+  };
+
+  #[test]
+  fn test_osc_input_mapping() {
+    async_manager::block_on(async move {
+      let (device, test_device) = new_bluetoothle_test_device("Massage Demo").await.unwrap();
+      let command_receiver = test_device.get_endpoint_receiver(&Endpoint::Tx).unwrap();
+      let devices = DashMap::new();
+      devices.insert(0, Arc::new(device));
+      let mut config = OscBridgeConfig::default();
+      config.inputs.push(
+        OscInputMapping::new("/avatar/parameters/Vibe", OscActuatorType::Vibrate).feature_index(1),
+      );
+      // Linear mappings don't apply to a vibrator, and should be skipped.
+      config.inputs.push(OscInputMapping::new(
+        "/avatar/parameters/Vibe",
+        OscActuatorType::Linear,
+      ));
+      let packet =
+        OscMessage::new("/avatar/parameters/Vibe", vec![OscArgument::Float(0.5)]).encode();
+      let messages = osc_packet::decode_packet(&packet).unwrap();
+      let futures = handle_osc_message(&config, &devices, &messages[0]);
+      assert_eq!(futures.len(), 1);
+      for fut in futures {
+        fut.await.unwrap();
+      }
+      check_test_recv_value(
+        &command_receiver,
+        DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![0xF2, 64], false)),
+      );
+    });
+  }
+}
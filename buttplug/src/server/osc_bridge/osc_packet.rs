@@ -0,0 +1,202 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2021 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Minimal OSC 1.0 packet encoding/decoding.
+//!
+//! Only covers what the bridge needs: messages (possibly wrapped in bundles)
+//! with int, float, double and boolean arguments. Anything else is rejected.
+
+use std::convert::TryInto;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum OscArgument {
+  Int(i32),
+  Float(f32),
+  Double(f64),
+  Bool(bool),
+}
+
+impl OscArgument {
+  /// Numeric value of the argument, with booleans treated as 0.0/1.0, which
+  /// is how VRChat avatar toggles are usually meant to be read.
+  pub fn as_f64(&self) -> f64 {
+    match self {
+      OscArgument::Int(value) => *value as f64,
+      OscArgument::Float(value) => *value as f64,
+      OscArgument::Double(value) => *value,
+      OscArgument::Bool(value) => {
+        if *value {
+          1.0
+        } else {
+          0.0
+        }
+      }
+    }
+  }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct OscMessage {
+  pub address: String,
+  pub args: Vec<OscArgument>,
+}
+
+impl OscMessage {
+  pub fn new(address: &str, args: Vec<OscArgument>) -> Self {
+    Self {
+      address: address.to_owned(),
+      args,
+    }
+  }
+
+  pub fn encode(&self) -> Vec<u8> {
+    let mut buf = vec![];
+    write_string(&mut buf, &self.address);
+    let mut tags = ",".to_owned();
+    for arg in &self.args {
+      tags.push(match arg {
+        OscArgument::Int(_) => 'i',
+        OscArgument::Float(_) => 'f',
+        OscArgument::Double(_) => 'd',
+        OscArgument::Bool(true) => 'T',
+        OscArgument::Bool(false) => 'F',
+      });
+    }
+    write_string(&mut buf, &tags);
+    for arg in &self.args {
+      match arg {
+        OscArgument::Int(value) => buf.extend_from_slice(&value.to_be_bytes()),
+        OscArgument::Float(value) => buf.extend_from_slice(&value.to_be_bytes()),
+        OscArgument::Double(value) => buf.extend_from_slice(&value.to_be_bytes()),
+        OscArgument::Bool(_) => {}
+      }
+    }
+    buf
+  }
+}
+
+/// Decodes a packet into the messages it contains, flattening bundles. Bundle
+/// timetags are ignored, everything is handled as soon as it arrives.
+pub fn decode_packet(data: &[u8]) -> Result<Vec<OscMessage>, String> {
+  let mut messages = vec![];
+  decode_into(data, &mut messages)?;
+  Ok(messages)
+}
+
+fn decode_into(data: &[u8], messages: &mut Vec<OscMessage>) -> Result<(), String> {
+  let mut pos = 0;
+  let address = read_string(data, &mut pos)?;
+  if address == "#bundle" {
+    // Skip the timetag.
+    read_bytes(data, &mut pos, 8)?;
+    while pos < data.len() {
+      let size = i32::from_be_bytes(read_bytes(data, &mut pos, 4)?.try_into().unwrap());
+      if size < 0 {
+        return Err(format!("Invalid bundle element size {}", size));
+      }
+      let element = read_bytes(data, &mut pos, size as usize)?;
+      decode_into(element, messages)?;
+    }
+    return Ok(());
+  }
+  if !address.starts_with('/') {
+    return Err(format!("Invalid OSC address {}", address));
+  }
+  // Type tags are technically optional in old OSC implementations, treat
+  // their absence as no arguments.
+  let tags = if pos < data.len() {
+    read_string(data, &mut pos)?
+  } else {
+    ",".to_owned()
+  };
+  if !tags.starts_with(',') {
+    return Err(format!("Invalid OSC type tag string {}", tags));
+  }
+  let mut args = vec![];
+  for tag in tags.chars().skip(1) {
+    let arg = match tag {
+      'i' => OscArgument::Int(i32::from_be_bytes(
+        read_bytes(data, &mut pos, 4)?.try_into().unwrap(),
+      )),
+      'f' => OscArgument::Float(f32::from_be_bytes(
+        read_bytes(data, &mut pos, 4)?.try_into().unwrap(),
+      )),
+      'd' => OscArgument::Double(f64::from_be_bytes(
+        read_bytes(data, &mut pos, 8)?.try_into().unwrap(),
+      )),
+      'T' => OscArgument::Bool(true),
+      'F' => OscArgument::Bool(false),
+      _ => return Err(format!("Unsupported OSC type tag {}", tag)),
+    };
+    args.push(arg);
+  }
+  messages.push(OscMessage { address, args });
+  Ok(())
+}
+
+fn read_bytes<'a>(data: &'a [u8], pos: &mut usize, len: usize) -> Result<&'a [u8], String> {
+  if *pos + len > data.len() {
+    return Err("OSC packet truncated".to_owned());
+  }
+  let bytes = &data[*pos..*pos + len];
+  *pos += len;
+  Ok(bytes)
+}
+
+fn read_string(data: &[u8], pos: &mut usize) -> Result<String, String> {
+  let remaining = &data[*pos..];
+  let end = remaining
+    .iter()
+    .position(|b| *b == 0)
+    .ok_or_else(|| "Unterminated OSC string".to_owned())?;
+  let value = String::from_utf8(remaining[..end].to_vec())
+    .map_err(|_| "OSC string is not valid UTF-8".to_owned())?;
+  // Strings are null terminated, then padded out to a multiple of 4 bytes.
+  let padded_len = (end + 4) & !3;
+  read_bytes(data, pos, padded_len)?;
+  Ok(value)
+}
+
+fn write_string(buf: &mut Vec<u8>, value: &str) {
+  buf.extend_from_slice(value.as_bytes());
+  let padding = 4 - (value.len() % 4);
+  buf.extend(std::iter::repeat(0).take(padding));
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_osc_message_round_trip() {
+    let msg = OscMessage::new(
+      "/avatar/parameters/Vibe",
+      vec![OscArgument::Float(0.5), OscArgument::Bool(true)],
+    );
+    let encoded = msg.encode();
+    assert_eq!(encoded.len() % 4, 0);
+    assert_eq!(decode_packet(&encoded).unwrap(), vec![msg]);
+  }
+
+  #[test]
+  fn test_osc_bundle_decode() {
+    let first = OscMessage::new("/a", vec![OscArgument::Int(3)]).encode();
+    let second = OscMessage::new("/b", vec![]).encode();
+    let mut bundle = vec![];
+    write_string(&mut bundle, "#bundle");
+    bundle.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 1]);
+    for element in [&first, &second].iter() {
+      bundle.extend_from_slice(&(element.len() as i32).to_be_bytes());
+      bundle.extend_from_slice(element);
+    }
+    let messages = decode_packet(&bundle).unwrap();
+    assert_eq!(messages.len(), 2);
+    assert_eq!(messages[0].args, vec![OscArgument::Int(3)]);
+    assert_eq!(messages[1].address, "/b");
+    assert!(decode_packet(&bundle[..bundle.len() - 2]).is_err());
+  }
+}
@@ -1,6 +1,5 @@
-use crate::util::async_manager;
+use crate::util::{async_manager, clock::Clock};
 use futures::{Future, FutureExt};
-use futures_timer::Delay;
 use std::{
   sync::{
     atomic::{AtomicBool, Ordering},
@@ -22,12 +21,13 @@ async fn ping_timer(
   mut ping_msg_receiver: mpsc::Receiver<PingMessage>,
   notifier: Arc<Notify>,
   pinged_out_status: Arc<AtomicBool>,
+  clock: Arc<dyn Clock>,
 ) {
   let mut started = false;
   let mut pinged = false;
   loop {
     select! {
-      _ = Delay::new(Duration::from_millis(max_ping_time)).fuse() => {
+      _ = clock.sleep(Duration::from_millis(max_ping_time)).fuse() => {
         if started {
           if !pinged {
             notifier.notify_waiters();
@@ -74,7 +74,8 @@ impl Drop for PingTimer {
 }
 
 impl PingTimer {
-  pub fn new(max_ping_time: u64) -> Self {
+  /// Creates a timer that measures ping time with the given clock.
+  pub fn new_with_clock(max_ping_time: u64, clock: Arc<dyn Clock>) -> Self {
     let ping_timeout_notifier = Arc::new(Notify::new());
     let (sender, receiver) = mpsc::channel(256);
     let pinged_out = Arc::new(AtomicBool::new(false));
@@ -84,8 +85,9 @@ impl PingTimer {
         receiver,
         ping_timeout_notifier.clone(),
         pinged_out.clone(),
+        clock,
       );
-      async_manager::spawn(async move { fut.await }).unwrap();
+      async_manager::spawn_named("ping timer", async move { fut.await }).unwrap();
     }
     Self {
       max_ping_time,
@@ -137,3 +139,35 @@ impl PingTimer {
     self.pinged_out.load(Ordering::SeqCst)
   }
 }
+
+#[cfg(test)]
+mod test {
+  use super::PingTimer;
+  use crate::util::{async_manager, clock::ManualClock};
+  use std::{sync::Arc, time::Duration};
+
+  #[test]
+  fn test_ping_timer_manual_clock() {
+    async_manager::block_on(async move {
+      let clock = ManualClock::new();
+      let timer = PingTimer::new_with_clock(100, Arc::new(clock.clone()));
+      // The timer loop starts a new wait each time it handles a message or
+      // its wait runs out, so counting sleeps tells us where the loop is.
+      clock.wait_for_sleeps(1).await;
+      timer.start_ping_timer().await;
+      clock.wait_for_sleeps(2).await;
+      timer.update_ping_time().await;
+      clock.wait_for_sleeps(3).await;
+      // Pinged in time, so the timer keeps going.
+      clock.advance(Duration::from_millis(100));
+      clock.wait_for_sleeps(4).await;
+      assert!(!timer.pinged_out());
+      // No ping this time around.
+      clock.advance(Duration::from_millis(100));
+      while !timer.pinged_out() {
+        async_manager::sleep(Duration::from_millis(1)).await;
+      }
+      assert_eq!(clock.sleeps_started(), 4);
+    });
+  }
+}
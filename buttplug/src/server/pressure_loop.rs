@@ -0,0 +1,445 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2022 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Closed loop vibration driven by a pressure sensor.
+//!
+//! Devices like the kGoal or Lelo F1s report how hard they're being
+//! squeezed. A pressure loop subscribes to one of those sensors and sets
+//! vibration from every reading, through a [PressureCurve], so the device
+//! responds to the user without a client in the middle. Loops are started
+//! and stopped by the application, with
+//! [ButtplugServer::start_pressure_loop][super::ButtplugServer::start_pressure_loop]
+//! and
+//! [ButtplugServer::stop_pressure_loop][super::ButtplugServer::stop_pressure_loop].
+
+use super::emergency_stop::{cancel_runners, Runner, Runners};
+use crate::{
+  core::{
+    errors::{ButtplugDeviceError, ButtplugError},
+    messages::{
+      ButtplugDeviceCommandMessageUnion, ButtplugDeviceMessageType, SensorSubscribeCmd, SensorType,
+      SensorUnsubscribeCmd, VibrateCmd, VibrateSubcommand,
+    },
+  },
+  device::{ButtplugDevice, ButtplugDeviceEvent},
+  util::async_manager,
+};
+use dashmap::{DashMap, DashSet};
+use futures::{future::BoxFuture, select, FutureExt};
+use std::sync::{
+  atomic::{AtomicU32, Ordering},
+  Arc, Weak,
+};
+use tokio::sync::broadcast;
+use tokio_util::sync::CancellationToken;
+
+/// How pressure, scaled to 0.0-1.0 over the configured pressure range, turns
+/// into intensity, also 0.0-1.0 before it's scaled to the intensity range.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PressureCurve {
+  /// Intensity goes up in step with pressure.
+  Linear,
+  /// Pressure raised to the given power. Above 1.0, light squeezes barely
+  /// register and the top of the range ramps up fast. Below 1.0, the
+  /// opposite.
+  Power(f64),
+  /// Off below the given pressure, full intensity at or above it.
+  Threshold(f64),
+  /// Full intensity with no pressure, dropping off as pressure goes up.
+  Inverse,
+}
+
+impl PressureCurve {
+  fn apply(&self, pressure: f64) -> f64 {
+    match self {
+      PressureCurve::Linear => pressure,
+      PressureCurve::Power(exponent) => pressure.powf(*exponent),
+      PressureCurve::Threshold(threshold) => {
+        if pressure >= *threshold {
+          1.0
+        } else {
+          0.0
+        }
+      }
+      PressureCurve::Inverse => 1.0 - pressure,
+    }
+  }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PressureLoopConfig {
+  pub sensor_device_index: u32,
+  /// Index of the pressure sensor on the device, as used in
+  /// SensorSubscribeCmd.
+  pub sensor_index: u32,
+  /// Device to vibrate. Defaults to the sensor device.
+  pub vibrate_device_index: u32,
+  /// Vibrator to drive. If None, all vibrators on the device are set to the
+  /// same intensity.
+  pub vibrate_feature_index: Option<u32>,
+  /// Raw sensor values that map to no pressure and full pressure. Readings
+  /// outside the range are clamped to it.
+  pub pressure_range: (i32, i32),
+  /// Vibration speeds that no pressure and full pressure map to, after the
+  /// curve is applied.
+  pub intensity_range: (f64, f64),
+  pub curve: PressureCurve,
+}
+
+impl PressureLoopConfig {
+  pub fn new(device_index: u32, sensor_index: u32, pressure_range: (i32, i32)) -> Self {
+    Self {
+      sensor_device_index: device_index,
+      sensor_index,
+      vibrate_device_index: device_index,
+      vibrate_feature_index: None,
+      pressure_range,
+      intensity_range: (0.0, 1.0),
+      curve: PressureCurve::Linear,
+    }
+  }
+
+  pub fn vibrate_device(mut self, device_index: u32, feature_index: Option<u32>) -> Self {
+    self.vibrate_device_index = device_index;
+    self.vibrate_feature_index = feature_index;
+    self
+  }
+
+  pub fn intensity_range(mut self, min: f64, max: f64) -> Self {
+    self.intensity_range = (min, max);
+    self
+  }
+
+  pub fn curve(mut self, curve: PressureCurve) -> Self {
+    self.curve = curve;
+    self
+  }
+
+  /// Vibration speed for a reading. Sensors reporting several values (one
+  /// per touch zone, etc) are driven by the highest.
+  fn intensity(&self, data: &[i32]) -> Option<f64> {
+    let raw = *data.iter().max()?;
+    let (min, max) = self.pressure_range;
+    let pressure = if max == min {
+      if raw >= max {
+        1.0
+      } else {
+        0.0
+      }
+    } else {
+      ((raw - min) as f64 / (max - min) as f64).max(0.0).min(1.0)
+    };
+    let shaped = self.curve.apply(pressure).max(0.0).min(1.0);
+    let (low, high) = self.intensity_range;
+    Some((low + shaped * (high - low)).max(0.0).min(1.0))
+  }
+}
+
+/// Running pressure loops.
+pub(crate) struct PressureLoops {
+  devices: Arc<DashMap<u32, Arc<ButtplugDevice>>>,
+  loops: Arc<Runners>,
+  next_id: AtomicU32,
+}
+
+impl PressureLoops {
+  pub fn new(devices: Arc<DashMap<u32, Arc<ButtplugDevice>>>) -> Self {
+    Self {
+      devices,
+      loops: Arc::new(DashMap::new()),
+      next_id: AtomicU32::new(0),
+    }
+  }
+
+  fn device(&self, device_index: u32) -> Result<Arc<ButtplugDevice>, ButtplugError> {
+    self
+      .devices
+      .get(&device_index)
+      .map(|device| device.value().clone())
+      .ok_or_else(|| ButtplugDeviceError::DeviceNotAvailable(device_index).into())
+  }
+
+  /// Subscribes to the sensor and starts the loop. Resolves to the loop's
+  /// id, for stopping it later.
+  pub fn start(
+    &self,
+    config: PressureLoopConfig,
+  ) -> BoxFuture<'static, Result<u32, ButtplugError>> {
+    let checked = self
+      .device(config.sensor_device_index)
+      .and_then(|sensor_device| {
+        check_pressure_sensor(&sensor_device, config.sensor_index)?;
+        let vibrate_device = self.device(config.vibrate_device_index)?;
+        let features = vibrate_features(&vibrate_device, config.vibrate_feature_index)?;
+        Ok((sensor_device, vibrate_device, features))
+      });
+    let (sensor_device, vibrate_device, features) = match checked {
+      Ok(checked) => checked,
+      Err(err) => return Box::pin(async move { Err(err) }),
+    };
+    let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+    let loops = self.loops.clone();
+    Box::pin(async move {
+      // Listen before subscribing, so the first reading isn't missed.
+      let readings = sensor_device.sensor_event_stream();
+      sensor_device
+        .parse_message(
+          SensorSubscribeCmd::new(config.sensor_device_index, config.sensor_index).into(),
+        )
+        .await?;
+      let token = CancellationToken::new();
+      loops.insert(
+        id,
+        Runner {
+          token: token.clone(),
+          device_address: vibrate_device.address().to_owned(),
+        },
+      );
+      info!(
+        "Starting pressure loop {} from device {} sensor {}",
+        id, config.sensor_device_index, config.sensor_index
+      );
+      let pressure_loop = PressureLoop {
+        config,
+        sensor_device: Arc::downgrade(&sensor_device),
+        vibrate_device: Arc::downgrade(&vibrate_device),
+        features,
+      };
+      async_manager::spawn_named("pressure loop", async move {
+        pressure_loop.run(readings, token).await;
+        loops.remove(&id);
+      })
+      .unwrap();
+      Ok(id)
+    })
+  }
+
+  /// Stops a loop and its vibration. Returns false if no loop with the id is
+  /// running.
+  pub fn stop(&self, id: u32) -> bool {
+    match self.loops.remove(&id) {
+      Some((_, runner)) => {
+        runner.token.cancel();
+        true
+      }
+      None => false,
+    }
+  }
+
+  /// The running loops, for stopping them from elsewhere.
+  pub fn runners(&self) -> &Arc<Runners> {
+    &self.loops
+  }
+
+  /// Stops every running loop, for when devices are being stopped and
+  /// nothing should turn them back on.
+  pub fn stop_all(&self) {
+    cancel_runners(&self.loops, None);
+  }
+
+  /// Stops the loops that vibrate devices with addresses in `addresses`,
+  /// leaving the rest running.
+  pub fn stop_for_devices(&self, addresses: &DashSet<String>) {
+    cancel_runners(&self.loops, Some(addresses));
+  }
+}
+
+fn check_pressure_sensor(device: &ButtplugDevice, sensor_index: u32) -> Result<(), ButtplugError> {
+  let sensors = device
+    .message_attributes()
+    .get(&ButtplugDeviceMessageType::SensorSubscribeCmd)
+    .and_then(|attrs| attrs.sensor_type.clone())
+    .ok_or(ButtplugDeviceError::MessageNotSupported(
+      ButtplugDeviceMessageType::SensorSubscribeCmd,
+    ))?;
+  match sensors.get(sensor_index as usize) {
+    Some(SensorType::Pressure) => Ok(()),
+    Some(sensor_type) => Err(
+      ButtplugDeviceError::ProtocolRequirementError(format!(
+        "Sensor {} is a {:?} sensor, pressure loops need a pressure sensor.",
+        sensor_index, sensor_type
+      ))
+      .into(),
+    ),
+    None => {
+      Err(ButtplugDeviceError::DeviceFeatureIndexError(sensors.len() as u32, sensor_index).into())
+    }
+  }
+}
+
+fn vibrate_features(
+  device: &ButtplugDevice,
+  feature_index: Option<u32>,
+) -> Result<Vec<u32>, ButtplugError> {
+  let feature_count = device
+    .message_attributes()
+    .get(&ButtplugDeviceMessageType::VibrateCmd)
+    .and_then(|attrs| attrs.feature_count)
+    .ok_or(ButtplugDeviceError::MessageNotSupported(
+      ButtplugDeviceMessageType::VibrateCmd,
+    ))?;
+  match feature_index {
+    Some(index) if index < feature_count => Ok(vec![index]),
+    Some(index) => Err(ButtplugDeviceError::DeviceFeatureIndexError(feature_count, index).into()),
+    None => Ok((0..feature_count).collect()),
+  }
+}
+
+struct PressureLoop {
+  config: PressureLoopConfig,
+  // Loops don't keep devices around after they disconnect.
+  sensor_device: Weak<ButtplugDevice>,
+  vibrate_device: Weak<ButtplugDevice>,
+  features: Vec<u32>,
+}
+
+impl PressureLoop {
+  fn vibrate_cmd(&self, speed: f64) -> ButtplugDeviceCommandMessageUnion {
+    VibrateCmd::new(
+      self.config.vibrate_device_index,
+      self
+        .features
+        .iter()
+        .map(|index| VibrateSubcommand::new(*index, speed))
+        .collect(),
+    )
+    .into()
+  }
+
+  async fn run(
+    self,
+    mut readings: broadcast::Receiver<ButtplugDeviceEvent>,
+    token: CancellationToken,
+  ) {
+    let mut last_intensity = None;
+    loop {
+      select! {
+        event = readings.recv().fuse() => match event {
+          Ok(ButtplugDeviceEvent::SensorReading(_, reading))
+            if reading.sensor_index() == self.config.sensor_index =>
+          {
+            let intensity = match self.config.intensity(reading.data()) {
+              Some(intensity) => intensity,
+              None => continue,
+            };
+            if last_intensity == Some(intensity) {
+              continue;
+            }
+            let device = match self.vibrate_device.upgrade() {
+              Some(device) => device,
+              None => break,
+            };
+            if let Err(err) = device.parse_message(self.vibrate_cmd(intensity)).await {
+              error!("Pressure loop could not set vibration: {:?}", err);
+            }
+            last_intensity = Some(intensity);
+          }
+          Ok(_) => continue,
+          Err(broadcast::error::RecvError::Lagged(_)) => continue,
+          Err(broadcast::error::RecvError::Closed) => break,
+        },
+        _ = token.cancelled().fuse() => break,
+      }
+    }
+    info!("Pressure loop stopped.");
+    if let Some(device) = self.vibrate_device.upgrade() {
+      if let Err(err) = device.parse_message(self.vibrate_cmd(0.0)).await {
+        error!("Pressure loop could not stop vibration: {:?}", err);
+      }
+    }
+    if let Some(device) = self.sensor_device.upgrade() {
+      let unsubscribe =
+        SensorUnsubscribeCmd::new(self.config.sensor_device_index, self.config.sensor_index);
+      if let Err(err) = device.parse_message(unsubscribe.into()).await {
+        debug!("Pressure loop could not unsubscribe from sensor: {:?}", err);
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::{PressureCurve, PressureLoopConfig, PressureLoops};
+  use crate::{
+    device::{ButtplugDeviceEvent, DeviceImplCommand, DeviceWriteCmd, Endpoint},
+    test::{check_test_recv_empty, check_test_recv_value, new_bluetoothle_test_device},
+    util::async_manager,
+  };
+  use dashmap::DashMap;
+  use std::{sync::Arc, time::Duration};
+
+  #[test]
+  fn test_pressure_curves() {
+    let config = PressureLoopConfig::new(0, 0, (10, 110));
+    assert_eq!(config.intensity(&[60]), Some(0.5));
+    assert_eq!(config.intensity(&[0, 200]), Some(1.0));
+    assert_eq!(config.intensity(&[]), None);
+    let config = config
+      .intensity_range(0.2, 0.6)
+      .curve(PressureCurve::Inverse);
+    assert_eq!(config.intensity(&[110]), Some(0.2));
+    let config = PressureLoopConfig::new(0, 0, (0, 100)).curve(PressureCurve::Power(2.0));
+    assert_eq!(config.intensity(&[50]), Some(0.25));
+    let config = PressureLoopConfig::new(0, 0, (0, 100)).curve(PressureCurve::Threshold(0.5));
+    assert_eq!(config.intensity(&[49]), Some(0.0));
+    assert_eq!(config.intensity(&[50]), Some(1.0));
+  }
+
+  #[test]
+  fn test_pressure_loop() {
+    async_manager::block_on(async {
+      let (device, test_device) = new_bluetoothle_test_device("Pearl2").await.unwrap();
+      let command_receiver = test_device.get_endpoint_receiver(&Endpoint::Tx).unwrap();
+      let devices = Arc::new(DashMap::new());
+      devices.insert(0, Arc::new(device));
+      let loops = PressureLoops::new(devices);
+      // Sensor 1 on the Pearl2 is the accelerometer.
+      assert!(loops
+        .start(PressureLoopConfig::new(0, 1, (0, 200)))
+        .await
+        .is_err());
+      assert!(loops
+        .start(PressureLoopConfig::new(1, 0, (0, 200)))
+        .await
+        .is_err());
+      let id = loops
+        .start(PressureLoopConfig::new(0, 0, (0, 200)))
+        .await
+        .unwrap();
+      test_device.send_event(ButtplugDeviceEvent::Notification(
+        test_device.address(),
+        Endpoint::RxTouch,
+        vec![100],
+      ));
+      async_manager::sleep(Duration::from_millis(50)).await;
+      check_test_recv_value(
+        &command_receiver,
+        DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![50, 0, 0], false)),
+      );
+      assert!(loops.stop(id));
+      assert!(!loops.stop(id));
+      async_manager::sleep(Duration::from_millis(50)).await;
+      check_test_recv_value(
+        &command_receiver,
+        DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![0, 0, 0], false)),
+      );
+
+      loops
+        .start(PressureLoopConfig::new(0, 0, (0, 200)))
+        .await
+        .unwrap();
+      loops.stop_all();
+      test_device.send_event(ButtplugDeviceEvent::Notification(
+        test_device.address(),
+        Endpoint::RxTouch,
+        vec![100],
+      ));
+      async_manager::sleep(Duration::from_millis(50)).await;
+      assert!(check_test_recv_empty(&command_receiver));
+    });
+  }
+}
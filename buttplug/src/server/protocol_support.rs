@@ -0,0 +1,128 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2022 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Capabilities of every protocol the library implements, for building device
+//! support tables in UIs and docs without having any devices connected.
+
+use crate::{
+  core::messages::{ActuatorType, ButtplugDeviceMessageType},
+  device::{
+    configuration_manager::{DeviceConfigurationManager, ProtocolDefinition},
+    Endpoint,
+  },
+};
+use std::collections::{BTreeMap, HashSet};
+
+/// What a protocol can do across all of the devices it handles.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ProtocolSupport {
+  /// Message types at least one device of the protocol takes.
+  pub message_types: HashSet<ButtplugDeviceMessageType>,
+  /// Kinds of actuators at least one device of the protocol has.
+  pub actuator_types: HashSet<ActuatorType>,
+  /// Whether the protocol's devices are only created when the server allows
+  /// power devices.
+  pub power_device: bool,
+}
+
+impl ProtocolSupport {
+  fn new(definition: &ProtocolDefinition, handled: &[ButtplugDeviceMessageType]) -> Self {
+    let mut support = ProtocolSupport {
+      power_device: definition.power_device,
+      ..Default::default()
+    };
+    // Every protocol handles stopping, whether or not the config lists it.
+    support
+      .message_types
+      .insert(ButtplugDeviceMessageType::StopDeviceCmd);
+    let has_battery_endpoint = definition.btle.as_ref().map_or(false, |btle| {
+      btle
+        .services
+        .values()
+        .any(|endpoints| endpoints.contains_key(&Endpoint::RxBLEBattery))
+    });
+    for messages in definition.message_attribute_maps() {
+      for (message_type, attributes) in messages {
+        // The config says what devices have, the handler says what the
+        // library can drive. Only claim what both agree on.
+        let supported = handled.contains(message_type)
+          || (*message_type == ButtplugDeviceMessageType::BatteryLevelCmd && has_battery_endpoint);
+        if !supported {
+          continue;
+        }
+        support.message_types.insert(*message_type);
+        support.actuator_types.extend(
+          attributes
+            .features_for(*message_type)
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|feature| feature.actuator_type),
+        );
+      }
+    }
+    support
+  }
+}
+
+/// Returns the capabilities of each protocol, keyed by protocol identifier.
+///
+/// A message is only listed for a protocol if the built in device
+/// configuration says some of its devices take it, and the protocol's
+/// implementation handles it (see `ButtplugProtocol::handled_message_types`).
+/// Only protocols that are compiled in are included. Raw messages are left
+/// out, as they depend on server options rather than the protocol.
+pub fn protocol_support_matrix() -> BTreeMap<String, ProtocolSupport> {
+  let config = DeviceConfigurationManager::default();
+  config
+    .protocol_configurations()
+    .iter()
+    .filter_map(|(name, definition)| {
+      let handled = config.handled_message_types(name)?;
+      Some((name.clone(), ProtocolSupport::new(definition, handled)))
+    })
+    .collect()
+}
+
+#[cfg(test)]
+mod test {
+  use super::protocol_support_matrix;
+  use crate::core::messages::{ActuatorType, ButtplugDeviceMessageType};
+
+  #[test]
+  fn test_protocol_support_matrix() {
+    let matrix = protocol_support_matrix();
+    let lovense = matrix.get("lovense").unwrap();
+    assert!(lovense
+      .message_types
+      .contains(&ButtplugDeviceMessageType::VibrateCmd));
+    assert!(lovense
+      .message_types
+      .contains(&ButtplugDeviceMessageType::StopDeviceCmd));
+    assert!(lovense.actuator_types.contains(&ActuatorType::Vibrate));
+    assert!(lovense.actuator_types.contains(&ActuatorType::Rotate));
+    assert!(!lovense.power_device);
+    let aneros = matrix.get("aneros").unwrap();
+    assert!(!aneros.actuator_types.contains(&ActuatorType::Rotate));
+    // Listed in the device configuration, but the protocol doesn't implement
+    // them, so they'd fail on a real device.
+    let vorze = matrix.get("vorze-sa").unwrap();
+    assert!(!vorze
+      .message_types
+      .contains(&ButtplugDeviceMessageType::LinearCmd));
+    assert!(!vorze.actuator_types.contains(&ActuatorType::Position));
+    let motorbunny = matrix.get("motorbunny").unwrap();
+    assert!(!motorbunny
+      .message_types
+      .contains(&ButtplugDeviceMessageType::RotateCmd));
+    // Battery reads from the standard BLE endpoint work for any protocol.
+    assert!(matrix
+      .get("magic-motion-1")
+      .unwrap()
+      .message_types
+      .contains(&ButtplugDeviceMessageType::BatteryLevelCmd));
+  }
+}
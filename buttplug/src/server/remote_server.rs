@@ -1,10 +1,16 @@
-use super::{ButtplugServer, ButtplugServerOptions, ButtplugServerError};
+#[cfg(feature = "engine-control")]
+use super::engine_control::EngineControl;
+use super::{
+  comm_managers::DeviceCommunicationManagerCapabilities, device_filter::DeviceFilter,
+  ButtplugServer, ButtplugServerError, ButtplugServerOptions, ButtplugServerResultFuture,
+};
 use crate::{
   connector::ButtplugConnector,
   core::{
     errors::ButtplugError,
     messages::{
-      self, ButtplugClientMessage, ButtplugMessage, ButtplugMessageValidator, ButtplugServerMessage,
+      self, ButtplugClientMessage, ButtplugMessage, ButtplugMessageSpecVersion,
+      ButtplugMessageValidator, ButtplugServerMessage,
     },
   },
   device::protocol::ButtplugProtocol,
@@ -12,8 +18,8 @@ use crate::{
   test::TestDeviceCommunicationManagerHelper,
   util::{async_manager, stream::convert_broadcast_receiver_to_stream},
 };
-use futures::{future::Future, select, FutureExt, Stream, StreamExt};
-use std::sync::Arc;
+use futures::{future::Future, FutureExt, Stream, StreamExt};
+use std::{collections::HashMap, sync::Arc};
 use thiserror::Error;
 use tokio::sync::{broadcast, mpsc, Notify};
 
@@ -26,6 +32,36 @@ pub enum ButtplugRemoteServerEvent {
   Disconnected,
 }
 
+/// Connection status of a [ButtplugRemoteServer], for showing in UIs.
+// Clone derived here to satisfy tokio broadcast requirements.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ButtplugRemoteServerConnectionEvent {
+  /// The connector has a client, which hasn't finished its handshake yet.
+  ClientConnecting,
+  /// The client finished its handshake.
+  ClientConnected {
+    name: String,
+    message_version: ButtplugMessageSpecVersion,
+  },
+  /// The connection is over, and the server has stopped devices.
+  ClientDisconnected {
+    reason: ButtplugRemoteServerDisconnectReason,
+  },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ButtplugRemoteServerDisconnectReason {
+  /// The client, or the connector underneath it, closed the connection.
+  ClientClosed,
+  /// [ButtplugRemoteServer::disconnect] was called, or the server was
+  /// dropped or shut down.
+  ServerRequested,
+  /// The server stopped sending events, which only happens if it's gone.
+  ServerStopped,
+  /// Messages couldn't be sent to the client.
+  ConnectorError(String),
+}
+
 #[derive(Error, Debug)]
 pub enum ButtplugServerConnectorError {
   #[error("Can't connect")]
@@ -39,12 +75,14 @@ pub enum ButtplugServerCommand {
 pub struct ButtplugRemoteServer {
   server: Arc<ButtplugServer>,
   event_sender: broadcast::Sender<ButtplugRemoteServerEvent>,
+  connection_event_sender: broadcast::Sender<ButtplugRemoteServerConnectionEvent>,
   disconnect_notifier: Arc<Notify>,
 }
 
 async fn run_server<ConnectorType>(
   server: Arc<ButtplugServer>,
   remote_event_sender: broadcast::Sender<ButtplugRemoteServerEvent>,
+  connection_event_sender: broadcast::Sender<ButtplugRemoteServerConnectionEvent>,
   connector: ConnectorType,
   mut connector_receiver: mpsc::Receiver<ButtplugClientMessage>,
   disconnect_notifier: Arc<Notify>,
@@ -52,57 +90,85 @@ async fn run_server<ConnectorType>(
   ConnectorType: ButtplugConnector<ButtplugServerMessage, ButtplugClientMessage> + 'static,
 {
   info!("Starting remote server loop");
-  let shared_connector = Arc::new(connector);
-  let server_receiver = server.event_stream();
+  // Replies to client requests and system events (message id 0) come in on
+  // separate channels, and this loop is the only thing that writes to the
+  // connector. Waiting replies always go out before waiting events, so a
+  // flood of sensor readings can't hold up request handling.
+  let (reply_sender, mut reply_receiver) = mpsc::channel::<ButtplugServerMessage>(256);
+  let server_receiver = server.system_event_stream();
   pin_mut!(server_receiver);
-  loop {
-    select! {
+  // Nobody listening for connection events is fine.
+  let _ = connection_event_sender.send(ButtplugRemoteServerConnectionEvent::ClientConnecting);
+  let reason = loop {
+    select_biased! {
+      reply = reply_receiver.recv().fuse() => {
+        // We hold a sender, so the channel can't close on us.
+        if let Some(reply) = reply {
+          if let Err(e) = connector.send(reply).await {
+            error!("Cannot send reply to client, exiting remote server loop.");
+            break ButtplugRemoteServerDisconnectReason::ConnectorError(e.to_string());
+          }
+        }
+      },
       connector_msg = connector_receiver.recv().fuse() => match connector_msg {
         None => {
           info!("Connector disconnected, exiting loop.");
-          break;
+          break ButtplugRemoteServerDisconnectReason::ClientClosed;
         }
         Some(client_message) => {
           trace!("Got message from connector: {:?}", client_message);
+          if let ButtplugClientMessage::RequestServerInfo(_) = &client_message {
+            // Identity tokens ride along with the connection, not the handshake.
+            server.set_client_identity_token(connector.identity_token());
+          }
           let server_clone = server.clone();
-          let connector_clone = shared_connector.clone();
+          let reply_sender_clone = reply_sender.clone();
           let remote_event_sender_clone = remote_event_sender.clone();
+          let connection_event_sender_clone = connection_event_sender.clone();
           async_manager::spawn(async move {
-            if let Err(e) = client_message.is_valid() {
+            let reply = if let Err(e) = client_message.is_valid() {
               error!("Message not valid: {:?} - Error: {}", client_message, e);
               let mut err_msg = messages::Error::from(ButtplugError::from(e));
               err_msg.set_id(client_message.id());
-              connector_clone.send(err_msg.into());
-              return;
-            }
-            match server_clone.parse_message(client_message.clone()).await {
-              Ok(ret_msg) => {
-                if let ButtplugClientMessage::RequestServerInfo(rsi) = client_message {
-                  if remote_event_sender_clone.send(ButtplugRemoteServerEvent::Connected(rsi.client_name().clone())).is_err() {
-                    error!("Cannot send event to owner, dropping and assuming local server thread has exited.");
+              err_msg.into()
+            } else {
+              // Hold on to the client name and version, so we don't have to copy
+              // the whole message on the off chance it's a handshake.
+              let client_info = if let ButtplugClientMessage::RequestServerInfo(rsi) = &client_message {
+                Some((rsi.client_name().clone(), rsi.message_version()))
+              } else {
+                None
+              };
+              match server_clone.parse_message(client_message).await {
+                Ok(ret_msg) => {
+                  if let Some((name, message_version)) = client_info {
+                    let _ = connection_event_sender_clone.send(ButtplugRemoteServerConnectionEvent::ClientConnected {
+                      name: name.clone(),
+                      message_version,
+                    });
+                    if remote_event_sender_clone.send(ButtplugRemoteServerEvent::Connected(name)).is_err() {
+                      error!("Cannot send event to owner, dropping and assuming local server thread has exited.");
+                    }
                   }
-                }
-                if connector_clone.send(ret_msg).await.is_err() {
-                  error!("Cannot send reply to server, dropping and assuming remote server thread has exited.");
-                }
-              },
-              Err(err_msg) => {
-                if connector_clone.send(err_msg.into()).await.is_err() {
-                  error!("Cannot send reply to server, dropping and assuming remote server thread has exited.");
-                }
+                  ret_msg
+                },
+                Err(err_msg) => err_msg.into(),
               }
+            };
+            if reply_sender_clone.send(reply).await.is_err() {
+              error!("Cannot send reply to server, dropping and assuming remote server thread has exited.");
             }
           }).unwrap();
         }
       },
       _ = disconnect_notifier.notified().fuse() => {
         info!("Server disconnected via controller disappearance, exiting loop.");
-        break;
+        break ButtplugRemoteServerDisconnectReason::ServerRequested;
       },
       server_msg = server_receiver.next().fuse() => match server_msg {
         None => {
           info!("Server disconnected via server disappearance, exiting loop.");
-          break;
+          break ButtplugRemoteServerDisconnectReason::ServerStopped;
         }
         Some(msg) => {
           match &msg {
@@ -118,17 +184,18 @@ async fn run_server<ConnectorType>(
             },
             _ => {}
           }
-          let connector_clone = shared_connector.clone();
-          if connector_clone.send(msg).await.is_err() {
+          if connector.send(msg).await.is_err() {
             error!("Server disappeared, exiting remote server thread.");
           }
         }
       },
     };
-  }
+  };
   if let Err(err) = server.disconnect().await {
     error!("Error disconnecting server: {:?}", err);
   }
+  let _ = connection_event_sender
+    .send(ButtplugRemoteServerConnectionEvent::ClientDisconnected { reason });
   info!("Exiting remote server loop");
 }
 
@@ -142,8 +209,10 @@ impl ButtplugRemoteServer {
   pub fn new_with_options(options: &ButtplugServerOptions) -> Result<Self, ButtplugError> {
     let server = ButtplugServer::new_with_options(options)?;
     let (event_sender, _) = broadcast::channel(256);
+    let (connection_event_sender, _) = broadcast::channel(256);
     Ok(Self {
       event_sender,
+      connection_event_sender,
       server: Arc::new(server),
       disconnect_notifier: Arc::new(Notify::new()),
     })
@@ -153,6 +222,12 @@ impl ButtplugRemoteServer {
     convert_broadcast_receiver_to_stream(self.event_sender.subscribe())
   }
 
+  /// Stream of changes in the connection to the client, from the connector
+  /// getting a client through to the server stopping devices once it's gone.
+  pub fn connection_event_stream(&self) -> impl Stream<Item = ButtplugRemoteServerConnectionEvent> {
+    convert_broadcast_receiver_to_stream(self.connection_event_sender.subscribe())
+  }
+
   pub fn start<ConnectorType>(
     &self,
     mut connector: ConnectorType,
@@ -162,6 +237,7 @@ impl ButtplugRemoteServer {
   {
     let server_clone = self.server.clone();
     let event_sender_clone = self.event_sender.clone();
+    let connection_event_sender = self.connection_event_sender.clone();
     let disconnect_notifier = self.disconnect_notifier.clone();
     async move {
       let (connector_sender, connector_receiver) = mpsc::channel(256);
@@ -172,6 +248,7 @@ impl ButtplugRemoteServer {
       run_server(
         server_clone,
         event_sender_clone,
+        connection_event_sender,
         connector,
         connector_receiver,
         disconnect_notifier,
@@ -181,23 +258,55 @@ impl ButtplugRemoteServer {
     }
   }
 
+  /// Creates an engine control handle for this server. Processing a Shutdown
+  /// request through it will also stop the connector loop started by
+  /// [ButtplugRemoteServer::start].
+  #[cfg(feature = "engine-control")]
+  pub fn engine_control(&self) -> EngineControl {
+    EngineControl::new_with_shutdown_notifier(self.server.clone(), self.disconnect_notifier.clone())
+  }
+
   pub async fn disconnect(&self) -> Result<(), ButtplugError> {
     self.disconnect_notifier.notify_waiters();
     Ok(())
   }
 
-  pub fn add_comm_manager<T>(&self, builder: T) -> Result<(), ButtplugServerError> where T: DeviceCommunicationManagerBuilder
+  /// Stops all devices, whether or not a client is connected.
+  pub fn stop_all_devices(&self) -> ButtplugServerResultFuture {
+    self.server.stop_all_devices()
+  }
+
+  pub fn add_comm_manager<T>(&self, builder: T) -> Result<(), ButtplugServerError>
+  where
+    T: DeviceCommunicationManagerBuilder,
   {
     self.server.add_comm_manager(builder)
   }
 
+  pub fn add_default_comm_managers(&self) -> Result<(), ButtplugServerError> {
+    self.server.add_default_comm_managers()
+  }
+
   pub fn add_test_comm_manager(
     &self,
   ) -> Result<TestDeviceCommunicationManagerHelper, ButtplugServerError> {
     self.server.add_test_comm_manager()
   }
 
-  pub fn add_protocol<T>(&self, protocol_name: &str) -> Result<(), ButtplugServerError> where T: ButtplugProtocol {
+  pub fn remove_comm_manager(&self, name: &str) -> Result<(), ButtplugServerError> {
+    self.server.remove_comm_manager(name)
+  }
+
+  pub fn comm_manager_capabilities(
+    &self,
+  ) -> HashMap<String, DeviceCommunicationManagerCapabilities> {
+    self.server.comm_manager_capabilities()
+  }
+
+  pub fn add_protocol<T>(&self, protocol_name: &str) -> Result<(), ButtplugServerError>
+  where
+    T: ButtplugProtocol,
+  {
     self.server.add_protocol::<T>(protocol_name)
   }
 
@@ -208,6 +317,18 @@ impl ButtplugRemoteServer {
   pub fn remove_all_protocols(&self) {
     self.server.remove_all_protocols();
   }
+
+  pub fn add_device_group(
+    &self,
+    group_name: &str,
+    member_addresses: &[String],
+  ) -> Result<(), ButtplugServerError> {
+    self.server.add_device_group(group_name, member_addresses)
+  }
+
+  pub fn set_device_filter(&self, filter: DeviceFilter) {
+    self.server.set_device_filter(filter);
+  }
 }
 
 impl Drop for ButtplugRemoteServer {
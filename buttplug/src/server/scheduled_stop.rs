@@ -0,0 +1,183 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2022 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Hard stops that end a session at a set time, no matter what the client
+//! does.
+//!
+//! Once a scheduled stop fires, every device is stopped and device commands
+//! are refused with [ButtplugDeviceError::DevicesStoppedBySchedule] until the
+//! application calls
+//! [ButtplugServer::cancel_scheduled_stop][super::ButtplugServer::cancel_scheduled_stop].
+
+use crate::{
+  core::{errors::ButtplugDeviceError, messages},
+  device::ButtplugDevice,
+  util::{async_manager, stream::convert_broadcast_receiver_to_stream},
+};
+use dashmap::DashMap;
+use futures::{future, Stream};
+use std::{
+  sync::{Arc, Mutex},
+  time::{Duration, Instant},
+};
+use tokio::sync::broadcast;
+
+/// Events sent as a scheduled stop comes due.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ScheduledStopEvent {
+  /// The stop will happen in the given amount of time.
+  Warning(Duration),
+  /// All devices have been stopped.
+  Stopped,
+}
+
+#[derive(Default)]
+struct ScheduledStopState {
+  // Bumped every time a stop is scheduled or cancelled, so tasks for stops
+  // that were replaced know to bail out.
+  generation: u64,
+  deadline: Option<Instant>,
+  expired: bool,
+}
+
+pub(crate) struct ScheduledStop {
+  state: Arc<Mutex<ScheduledStopState>>,
+  event_sender: broadcast::Sender<ScheduledStopEvent>,
+  devices: Arc<DashMap<u32, Arc<ButtplugDevice>>>,
+}
+
+impl ScheduledStop {
+  pub fn new(devices: Arc<DashMap<u32, Arc<ButtplugDevice>>>) -> Self {
+    let (event_sender, _) = broadcast::channel(256);
+    Self {
+      state: Arc::new(Mutex::new(ScheduledStopState::default())),
+      event_sender,
+      devices,
+    }
+  }
+
+  pub fn event_stream(&self) -> impl Stream<Item = ScheduledStopEvent> {
+    convert_broadcast_receiver_to_stream(self.event_sender.subscribe())
+  }
+
+  /// Arms a stop `delay` from now, replacing any stop already armed. If
+  /// `warning` isn't zero, a [ScheduledStopEvent::Warning] is sent that long
+  /// before the stop, or right away if the stop is closer than that.
+  pub fn schedule(&self, delay: Duration, warning: Duration) {
+    let deadline = Instant::now() + delay;
+    let generation = {
+      let mut state = self.state.lock().unwrap();
+      state.generation += 1;
+      state.deadline = Some(deadline);
+      state.generation
+    };
+    info!("Scheduled stop armed for {:?} from now.", delay);
+    let state = self.state.clone();
+    let event_sender = self.event_sender.clone();
+    let devices = self.devices.clone();
+    async_manager::spawn_named("scheduled stop", async move {
+      if !warning.is_zero() {
+        async_manager::sleep(delay.saturating_sub(warning)).await;
+        if state.lock().unwrap().generation != generation {
+          return;
+        }
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        warn!("Scheduled stop in {:?}.", remaining);
+        // No one listening is fine, the stop happens either way.
+        let _ = event_sender.send(ScheduledStopEvent::Warning(remaining));
+      }
+      async_manager::sleep(deadline.saturating_duration_since(Instant::now())).await;
+      {
+        let mut state = state.lock().unwrap();
+        if state.generation != generation {
+          return;
+        }
+        state.deadline = None;
+        state.expired = true;
+      }
+      warn!("Scheduled stop reached, stopping all devices.");
+      // Stop everything, whether or not the client can see it.
+      let fut_vec: Vec<_> = devices
+        .iter()
+        .map(|dev| {
+          dev
+            .value()
+            .parse_message_instrumented(*dev.key(), messages::StopDeviceCmd::new(1).into())
+        })
+        .collect();
+      future::join_all(fut_vec).await;
+      let _ = event_sender.send(ScheduledStopEvent::Stopped);
+    })
+    .unwrap();
+  }
+
+  /// Disarms any pending stop and lets devices take commands again if a stop
+  /// already fired.
+  pub fn cancel(&self) {
+    let mut state = self.state.lock().unwrap();
+    state.generation += 1;
+    state.deadline = None;
+    state.expired = false;
+  }
+
+  /// Time left until the armed stop, if there is one.
+  pub fn remaining(&self) -> Option<Duration> {
+    self
+      .state
+      .lock()
+      .unwrap()
+      .deadline
+      .map(|deadline| deadline.saturating_duration_since(Instant::now()))
+  }
+
+  /// True if a stop is armed or has already fired.
+  pub fn active(&self) -> bool {
+    let state = self.state.lock().unwrap();
+    state.deadline.is_some() || state.expired
+  }
+
+  /// Errors if a stop is due or has fired, and hasn't been cancelled since.
+  /// Goes by the deadline rather than waiting for the stop task to mark it,
+  /// so nothing slips through while that task is still waking up.
+  pub fn check_expired(&self) -> Result<(), ButtplugDeviceError> {
+    let state = self.state.lock().unwrap();
+    let due = state
+      .deadline
+      .map_or(false, |deadline| Instant::now() >= deadline);
+    if state.expired || due {
+      Err(ButtplugDeviceError::DevicesStoppedBySchedule)
+    } else {
+      Ok(())
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::ScheduledStop;
+  use crate::{core::errors::ButtplugDeviceError, util::async_manager};
+  use dashmap::DashMap;
+  use std::{sync::Arc, time::Duration};
+
+  #[test]
+  fn test_check_expired_at_deadline() {
+    async_manager::block_on(async {
+      let stop = ScheduledStop::new(Arc::new(DashMap::new()));
+      stop.schedule(Duration::from_secs(60), Duration::ZERO);
+      assert!(stop.check_expired().is_ok());
+      // Due right away. Commands are refused whether or not the stop task
+      // has gotten to run yet.
+      stop.schedule(Duration::ZERO, Duration::ZERO);
+      assert!(matches!(
+        stop.check_expired(),
+        Err(ButtplugDeviceError::DevicesStoppedBySchedule)
+      ));
+      stop.cancel();
+      assert!(stop.check_expired().is_ok());
+    });
+  }
+}
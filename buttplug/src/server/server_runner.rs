@@ -0,0 +1,399 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2022 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Config file driven setup for headless server processes.
+//!
+//! A [ServerRunner] builds a [ButtplugRemoteServer] from a JSON config file,
+//! with the comm managers and websocket transport it names, then serves
+//! clients until the process gets Ctrl-C/SIGINT (or SIGTERM on unix, as sent
+//! by systemd, docker, etc), stopping devices on the way out. That leaves
+//! very little for an Intiface style daemon to do:
+//!
+//! ```no_run
+//! use buttplug::{server::server_runner::ServerRunner, util::async_manager};
+//!
+//! fn main() {
+//!   tracing_subscriber::fmt::init();
+//!   let runner = match ServerRunner::from_config_file("buttplug-server.json") {
+//!     Ok(runner) => runner,
+//!     Err(err) => {
+//!       eprintln!("{}", err);
+//!       std::process::exit(1);
+//!     }
+//!   };
+//!   if let Err(err) = async_manager::block_on(runner.run()) {
+//!     eprintln!("{}", err);
+//!     std::process::exit(1);
+//!   }
+//! }
+//! ```
+//!
+//! Config files look like this. Only `transport` is required.
+//!
+//! ```json
+//! {
+//!   "server-name": "Headless Server",
+//!   "transport": { "websocket-server": { "port": 12345, "all-interfaces": false } },
+//!   "comm-managers": ["btleplug", "serial", "lovense-dongle"],
+//!   "max-ping-time": 0,
+//!   "allow-raw-messages": false,
+//!   "device-config-file": "buttplug-device-config.json",
+//!   "user-device-config-file": "buttplug-user-device-config.json",
+//!   "stay-open": true
+//! }
+//! ```
+//!
+//! Servers can also connect out to a client listening for them, with
+//! `{ "websocket-client": { "address": "ws://127.0.0.1:12345" } }` as the
+//! transport. Without `comm-managers`, every comm manager compiled in that
+//! doesn't need settings of its own is added. Comm managers that do (MQTT,
+//! HTTP, etc) can be added through [ServerRunner::server] before running.
+
+use super::{
+  remote_server::ButtplugServerConnectorError, ButtplugRemoteServer, ButtplugServerError,
+  ButtplugServerOptions,
+};
+use crate::{
+  connector::{
+    ButtplugRemoteServerConnector, ButtplugWebsocketClientTransport,
+    ButtplugWebsocketServerTransport, ButtplugWebsocketServerTransportOptions,
+  },
+  core::{errors::ButtplugError, messages::serializer::BUTTPLUG_SERVER_SUBPROTOCOLS},
+  util::async_manager,
+};
+use futures::{
+  future::{self, BoxFuture},
+  pin_mut, FutureExt,
+};
+use serde::Deserialize;
+use std::{
+  fs,
+  future::Future,
+  path::{Path, PathBuf},
+  time::Duration,
+};
+use thiserror::Error;
+
+/// How long a shutdown waits for the connected client's session to wind
+/// down before stopping devices anyway.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Error)]
+pub enum ServerRunnerError {
+  #[error("Cannot read {0}: {1}")]
+  FileReadError(String, std::io::Error),
+  #[error("Invalid server config: {0}")]
+  ConfigError(String),
+  #[error("Unknown comm manager {0}, or it wasn't compiled in.")]
+  UnknownCommManager(String),
+  #[error("Connection to client failed: {0}")]
+  ConnectorError(#[from] ButtplugServerConnectorError),
+  #[error(transparent)]
+  ServerError(#[from] ButtplugServerError),
+  #[error(transparent)]
+  ButtplugError(#[from] ButtplugError),
+}
+
+/// How the server talks to clients.
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ServerRunnerTransport {
+  /// Listens for a client on a websocket port.
+  #[serde(rename_all = "kebab-case")]
+  WebsocketServer {
+    port: u16,
+    /// Listens on all interfaces, instead of only 127.0.0.1.
+    #[serde(default)]
+    all_interfaces: bool,
+  },
+  /// Connects out to a client listening on a websocket address.
+  WebsocketClient { address: String },
+}
+
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct ServerRunnerConfig {
+  #[serde(default = "default_server_name")]
+  pub server_name: String,
+  pub transport: ServerRunnerTransport,
+  /// Comm managers to add, by name: `btleplug`, `serial`, `usb`, `xinput`,
+  /// `lovense-dongle` or `lovense-connect`. Defaults to all of them that were
+  /// compiled in.
+  #[serde(default)]
+  pub comm_managers: Option<Vec<String>>,
+  #[serde(default)]
+  pub max_ping_time: u64,
+  #[serde(default)]
+  pub allow_raw_messages: bool,
+  /// Replaces the device configuration built into the library.
+  #[serde(default)]
+  pub device_config_file: Option<PathBuf>,
+  #[serde(default)]
+  pub user_device_config_file: Option<PathBuf>,
+  /// Keeps serving clients after the first one disconnects, instead of
+  /// returning from [ServerRunner::run].
+  #[serde(default)]
+  pub stay_open: bool,
+}
+
+fn default_server_name() -> String {
+  ButtplugServerOptions::default().name
+}
+
+async fn ctrl_c() {
+  if let Err(err) = tokio::signal::ctrl_c().await {
+    error!("Cannot listen for Ctrl-C: {}", err);
+    future::pending::<()>().await;
+  }
+  info!("Got Ctrl-C, shutting down server.");
+}
+
+#[cfg(unix)]
+async fn terminate() {
+  use tokio::signal::unix::{signal, SignalKind};
+  match signal(SignalKind::terminate()) {
+    Ok(mut sigterm) => {
+      sigterm.recv().await;
+    }
+    Err(err) => {
+      error!("Cannot listen for SIGTERM: {}", err);
+      future::pending::<()>().await;
+    }
+  }
+  info!("Got SIGTERM, shutting down server.");
+}
+
+#[cfg(not(unix))]
+async fn terminate() {
+  future::pending::<()>().await;
+}
+
+fn read_file(path: &Path) -> Result<String, ServerRunnerError> {
+  fs::read_to_string(path)
+    .map_err(|err| ServerRunnerError::FileReadError(path.display().to_string(), err))
+}
+
+impl ServerRunnerConfig {
+  pub fn from_json(json: &str) -> Result<Self, ServerRunnerError> {
+    serde_json::from_str(json).map_err(|err| ServerRunnerError::ConfigError(err.to_string()))
+  }
+
+  /// Server options for the config, with device config files read in.
+  pub fn server_options(&self) -> Result<ButtplugServerOptions, ServerRunnerError> {
+    Ok(ButtplugServerOptions {
+      name: self.server_name.clone(),
+      max_ping_time: self.max_ping_time,
+      allow_raw_messages: self.allow_raw_messages,
+      device_configuration_json: self
+        .device_config_file
+        .as_deref()
+        .map(read_file)
+        .transpose()?,
+      user_device_configuration_json: self
+        .user_device_config_file
+        .as_deref()
+        .map(read_file)
+        .transpose()?,
+      ..Default::default()
+    })
+  }
+}
+
+fn add_comm_manager(server: &ButtplugRemoteServer, name: &str) -> Result<(), ServerRunnerError> {
+  match name {
+    #[cfg(feature = "btleplug-manager")]
+    "btleplug" => {
+      use super::comm_managers::btleplug::BtlePlugCommunicationManagerBuilder;
+      server.add_comm_manager(BtlePlugCommunicationManagerBuilder::default())?;
+    }
+    #[cfg(feature = "serial-manager")]
+    "serial" => {
+      use super::comm_managers::serialport::SerialPortCommunicationManagerBuilder;
+      server.add_comm_manager(SerialPortCommunicationManagerBuilder::default())?;
+    }
+    #[cfg(feature = "usb-manager")]
+    "usb" => {
+      use super::comm_managers::usb::UsbCommunicationManagerBuilder;
+      server.add_comm_manager(UsbCommunicationManagerBuilder::default())?;
+    }
+    #[cfg(all(feature = "xinput-manager", target_os = "windows"))]
+    "xinput" => {
+      use super::comm_managers::xinput::XInputDeviceCommunicationManagerBuilder;
+      server.add_comm_manager(XInputDeviceCommunicationManagerBuilder::default())?;
+    }
+    #[cfg(feature = "lovense-dongle-manager")]
+    "lovense-dongle" => {
+      use super::comm_managers::lovense_dongle::{
+        LovenseHIDDongleCommunicationManagerBuilder, LovenseSerialDongleCommunicationManagerBuilder,
+      };
+      server.add_comm_manager(LovenseHIDDongleCommunicationManagerBuilder::default())?;
+      server.add_comm_manager(LovenseSerialDongleCommunicationManagerBuilder::default())?;
+    }
+    #[cfg(feature = "lovense-connect-service-manager")]
+    "lovense-connect" => {
+      use super::comm_managers::lovense_connect_service::LovenseConnectServiceCommunicationManagerBuilder;
+      server.add_comm_manager(LovenseConnectServiceCommunicationManagerBuilder::default())?;
+    }
+    _ => return Err(ServerRunnerError::UnknownCommManager(name.to_owned())),
+  }
+  Ok(())
+}
+
+/// A remote server set up from a [ServerRunnerConfig], ready to serve
+/// clients.
+pub struct ServerRunner {
+  config: ServerRunnerConfig,
+  server: ButtplugRemoteServer,
+}
+
+impl ServerRunner {
+  pub fn new(config: ServerRunnerConfig) -> Result<Self, ServerRunnerError> {
+    let server = ButtplugRemoteServer::new_with_options(&config.server_options()?)?;
+    match &config.comm_managers {
+      Some(names) => {
+        for name in names {
+          add_comm_manager(&server, name)?;
+        }
+      }
+      None => server.add_default_comm_managers()?,
+    }
+    Ok(Self { config, server })
+  }
+
+  pub fn from_config_file(path: impl AsRef<Path>) -> Result<Self, ServerRunnerError> {
+    Self::new(ServerRunnerConfig::from_json(&read_file(path.as_ref())?)?)
+  }
+
+  /// The server clients are served by, for adding comm managers, watching
+  /// events, etc, before running.
+  pub fn server(&self) -> &ButtplugRemoteServer {
+    &self.server
+  }
+
+  /// Serves clients until the process gets Ctrl-C/SIGINT or, on unix,
+  /// SIGTERM, or, unless `stay-open` is set, the first client disconnects.
+  pub async fn run(&self) -> Result<(), ServerRunnerError> {
+    self
+      .run_until(future::select(Box::pin(ctrl_c()), Box::pin(terminate())).map(|_| ()))
+      .await
+  }
+
+  /// Like [ServerRunner::run], but shuts down when `shutdown` resolves,
+  /// instead of on Ctrl-C or SIGTERM.
+  pub async fn run_until(
+    &self,
+    shutdown: impl Future<Output = ()>,
+  ) -> Result<(), ServerRunnerError> {
+    let shutdown = shutdown.fuse();
+    pin_mut!(shutdown);
+    loop {
+      info!("Server runner waiting for a client.");
+      let connection = self.connect().fuse();
+      pin_mut!(connection);
+      select! {
+        result = connection => {
+          result?;
+          if !self.config.stay_open {
+            return Ok(());
+          }
+          info!("Client disconnected, waiting for the next one.");
+        }
+        _ = shutdown => {
+          // Ending the session stops devices following the server's
+          // disconnect policy, but the process is going away, so make
+          // sure they're stopped either way.
+          self.server.disconnect().await?;
+          select! {
+            _ = connection => {}
+            _ = async_manager::sleep(SHUTDOWN_TIMEOUT).fuse() => {
+              warn!("Client session didn't end in time, stopping devices anyway.");
+            }
+          }
+          self.server.stop_all_devices().await?;
+          return Ok(());
+        }
+      }
+    }
+  }
+
+  fn connect(&self) -> BoxFuture<'static, Result<(), ButtplugServerConnectorError>> {
+    match &self.config.transport {
+      ServerRunnerTransport::WebsocketServer {
+        port,
+        all_interfaces,
+      } => {
+        let transport =
+          ButtplugWebsocketServerTransport::new(ButtplugWebsocketServerTransportOptions {
+            ws_listen_on_all_interfaces: *all_interfaces,
+            ws_insecure_port: *port,
+            ws_subprotocols: BUTTPLUG_SERVER_SUBPROTOCOLS
+              .iter()
+              .map(|subprotocol| subprotocol.to_string())
+              .collect(),
+          });
+        Box::pin(
+          self
+            .server
+            .start(ButtplugRemoteServerConnector::new(transport)),
+        )
+      }
+      ServerRunnerTransport::WebsocketClient { address } => {
+        let transport = ButtplugWebsocketClientTransport::new_insecure_connector(address);
+        Box::pin(
+          self
+            .server
+            .start(ButtplugRemoteServerConnector::new(transport)),
+        )
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::{ServerRunner, ServerRunnerConfig, ServerRunnerError, ServerRunnerTransport};
+
+  #[test]
+  fn test_server_runner_config() {
+    let config = ServerRunnerConfig::from_json(
+      r#"{ "transport": { "websocket-server": { "port": 12345 } }, "stay-open": true }"#,
+    )
+    .unwrap();
+    assert_eq!(config.server_name, "Buttplug Server");
+    assert_eq!(
+      config.transport,
+      ServerRunnerTransport::WebsocketServer {
+        port: 12345,
+        all_interfaces: false,
+      }
+    );
+    assert!(config.stay_open);
+    assert_eq!(config.comm_managers, None);
+    // Typos shouldn't be quietly ignored.
+    assert!(matches!(
+      ServerRunnerConfig::from_json(
+        r#"{ "transport": { "websocket-server": { "port": 12345 } }, "stay-opn": true }"#,
+      ),
+      Err(ServerRunnerError::ConfigError(_))
+    ));
+  }
+
+  #[test]
+  fn test_server_runner_unknown_comm_manager() {
+    let config = ServerRunnerConfig::from_json(
+      r#"{
+        "transport": { "websocket-client": { "address": "ws://127.0.0.1:12345" } },
+        "comm-managers": ["carrier-pigeon"]
+      }"#,
+    )
+    .unwrap();
+    assert!(matches!(
+      ServerRunner::new(config),
+      Err(ServerRunnerError::UnknownCommManager(name)) if name == "carrier-pigeon"
+    ));
+  }
+}
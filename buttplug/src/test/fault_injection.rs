@@ -0,0 +1,178 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2022 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Fault injection for test devices, for exercising retry, reconnection and
+//! error handling without real hardware misbehaving on cue.
+//!
+//! Faults are picked with a seeded generator, so a test gets the same faults
+//! in the same order every run, as long as it does things in the same order.
+
+use std::{sync::Mutex, time::Duration};
+
+/// How often devices roll for disconnects, unless told otherwise.
+pub const DEFAULT_DISCONNECT_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Faults a test device injects. Rates are chances from 0.0 (never) to 1.0
+/// (every time).
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TestFaultConfig {
+  /// Seed for picking faults. Runs with the same seed get the same faults.
+  pub seed: u64,
+  /// Chance of a write failing with a communication error.
+  pub write_failure_rate: f64,
+  /// Chance of the device disconnecting each
+  /// [disconnect_check_interval][TestFaultConfig::disconnect_check_interval]
+  /// while connected, whether or not anything is being written to it.
+  pub disconnect_rate: f64,
+  /// How often, by the device's clock, to roll for a disconnect.
+  /// [DEFAULT_DISCONNECT_CHECK_INTERVAL] if not set.
+  pub disconnect_check_interval: Option<Duration>,
+  /// Time notifications from the device are held before being delivered, by
+  /// the device's clock.
+  pub notification_delay: Option<Duration>,
+  /// Time writes take to complete, by the device's clock. The write reaches
+  /// the endpoint channel right away.
+  pub write_latency: Option<Duration>,
+  /// Chance of the device dropping out of range right after it's found when
+  /// scanning. The found device can't be connected to, and the device is
+  /// found again right after.
+  pub advertisement_flap_rate: f64,
+}
+
+pub(super) struct FaultInjector {
+  config: TestFaultConfig,
+  state: Mutex<u64>,
+}
+
+impl FaultInjector {
+  pub fn new(config: TestFaultConfig) -> Self {
+    Self {
+      state: Mutex::new(config.seed),
+      config,
+    }
+  }
+
+  pub fn config(&self) -> &TestFaultConfig {
+    &self.config
+  }
+
+  pub fn disconnect_check_interval(&self) -> Duration {
+    self
+      .config
+      .disconnect_check_interval
+      .unwrap_or(DEFAULT_DISCONNECT_CHECK_INTERVAL)
+  }
+
+  /// Returns true with the given chance.
+  pub fn roll(&self, rate: f64) -> bool {
+    if rate <= 0.0 {
+      return false;
+    }
+    // splitmix64, good enough for picking faults and needs no dependencies.
+    let mut state = self.state.lock().unwrap();
+    *state = state.wrapping_add(0x9e3779b97f4a7c15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^= z >> 31;
+    ((z >> 11) as f64 / (1u64 << 53) as f64) < rate
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::{FaultInjector, TestFaultConfig};
+  use crate::{
+    device::{ButtplugDeviceEvent, DeviceImplInternal, DeviceWriteCmd, Endpoint},
+    test::{TestDevice, TestDeviceInternal},
+    util::{async_manager, clock::ManualClock},
+  };
+  use std::{sync::Arc, time::Duration};
+  use tokio::sync::broadcast::error::TryRecvError;
+
+  fn faulty_device(faults: TestFaultConfig, clock: &ManualClock) -> TestDeviceInternal {
+    let mut device = TestDeviceInternal::new_with_faults("Faulty", "faulty-test", faults);
+    device.set_clock(Arc::new(clock.clone()));
+    device
+  }
+
+  #[test]
+  fn test_fault_rolls_are_deterministic() {
+    let config = TestFaultConfig {
+      seed: 1234,
+      ..Default::default()
+    };
+    let rolls = |injector: FaultInjector| (0..64).map(|_| injector.roll(0.5)).collect::<Vec<_>>();
+    let first = rolls(FaultInjector::new(config.clone()));
+    assert_eq!(first, rolls(FaultInjector::new(config)));
+    assert!(first.contains(&true) && first.contains(&false));
+    let injector = FaultInjector::new(TestFaultConfig::default());
+    assert!((0..64).all(|_| !injector.roll(0.0)));
+    assert!((0..64).all(|_| injector.roll(1.0)));
+  }
+
+  #[test]
+  fn test_disconnect_without_writes() {
+    async_manager::block_on(async {
+      let clock = ManualClock::new();
+      let internal = faulty_device(
+        TestFaultConfig {
+          disconnect_rate: 1.0,
+          disconnect_check_interval: Some(Duration::from_secs(1)),
+          ..Default::default()
+        },
+        &clock,
+      );
+      let device = TestDevice::new(&internal);
+      let mut events = device.event_stream();
+      // Nothing is ever written, the device still drops out once its check
+      // comes around.
+      clock.wait_for_sleeps(1).await;
+      clock.advance(Duration::from_millis(999));
+      assert!(device.connected());
+      assert!(matches!(events.try_recv(), Err(TryRecvError::Empty)));
+      clock.advance(Duration::from_millis(1));
+      assert!(matches!(
+        events.recv().await,
+        Ok(ButtplugDeviceEvent::Removed(address)) if address == "faulty-test"
+      ));
+      assert!(!device.connected());
+      assert!(device
+        .write_value(DeviceWriteCmd::new(Endpoint::Tx, vec![0], false))
+        .await
+        .is_err());
+    });
+  }
+
+  #[test]
+  fn test_notification_delay() {
+    async_manager::block_on(async {
+      let clock = ManualClock::new();
+      let internal = faulty_device(
+        TestFaultConfig {
+          notification_delay: Some(Duration::from_secs(1)),
+          ..Default::default()
+        },
+        &clock,
+      );
+      let mut events = internal.sender().subscribe();
+      internal.send_event(ButtplugDeviceEvent::Notification(
+        "faulty-test".to_owned(),
+        Endpoint::Rx,
+        vec![1, 2, 3],
+      ));
+      clock.wait_for_sleeps(1).await;
+      clock.advance(Duration::from_millis(999));
+      assert!(matches!(events.try_recv(), Err(TryRecvError::Empty)));
+      clock.advance(Duration::from_millis(1));
+      assert!(matches!(
+        events.recv().await,
+        Ok(ButtplugDeviceEvent::Notification(_, Endpoint::Rx, data)) if data == vec![1, 2, 3]
+      ));
+    });
+  }
+}
@@ -0,0 +1,330 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2021 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+use super::{
+  report::{ConformanceReport, StepOutcome, StepReport},
+  script::{default_script, HardwareTestStep},
+};
+use crate::{
+  client::{
+    ButtplugClient, ButtplugClientDevice, ButtplugClientError, ButtplugClientEvent, LinearCommand,
+    RotateCommand, VibrateCommand,
+  },
+  connector::ButtplugConnectorError,
+  server::{device_filter::DeviceFilter, ButtplugServerOptions},
+};
+use displaydoc::Display;
+use futures::{FutureExt, StreamExt};
+use futures_timer::Delay;
+use std::{
+  env, fs,
+  io::{self, Write},
+  path::PathBuf,
+  sync::Arc,
+  time::{Duration, Instant},
+};
+use thiserror::Error;
+
+const DEFAULT_DISCOVERY_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Error, Display)]
+pub enum HardwareTestError {
+  /// Client error: {0}
+  ClientError(#[from] ButtplugClientError),
+  /// No device with address {0} showed up within {1} seconds.
+  DeviceNotFound(String, u64),
+  /// Invalid hardware test configuration: {0}
+  ConfigError(String),
+  /// Cannot write report to {0}: {1}
+  ReportError(String, String),
+}
+
+/// Whoever is watching the device while the test runs.
+pub trait HardwareTestOperator: Send + Sync {
+  /// Tells the operator what's going on.
+  fn notify(&self, message: &str);
+  /// Asks the operator a yes/no question. Returns None if there's nobody to
+  /// answer.
+  fn confirm(&self, prompt: &str) -> Option<bool>;
+}
+
+/// Prompts on stdout, and reads answers from stdin.
+#[derive(Default)]
+pub struct ConsoleOperator {}
+
+impl HardwareTestOperator for ConsoleOperator {
+  fn notify(&self, message: &str) {
+    println!("{}", message);
+  }
+
+  fn confirm(&self, prompt: &str) -> Option<bool> {
+    loop {
+      print!("{} [y/n] ", prompt);
+      io::stdout().flush().ok()?;
+      let mut answer = String::new();
+      if io::stdin().read_line(&mut answer).ok()? == 0 {
+        return None;
+      }
+      match answer.trim().to_lowercase().as_str() {
+        "y" | "yes" => return Some(true),
+        "n" | "no" => return Some(false),
+        _ => continue,
+      }
+    }
+  }
+}
+
+/// For running without anyone around, i.e. on a CI machine with a device
+/// attached. Only checks that commands go through.
+#[derive(Default)]
+pub struct UnattendedOperator {}
+
+impl HardwareTestOperator for UnattendedOperator {
+  fn notify(&self, message: &str) {
+    info!("{}", message);
+  }
+
+  fn confirm(&self, _prompt: &str) -> Option<bool> {
+    None
+  }
+}
+
+#[derive(Debug, Clone)]
+pub struct HardwareTestConfig {
+  /// Address of the device to test, as reported by its comm manager.
+  pub address: String,
+  pub discovery_timeout: Duration,
+  /// Steps to run. If None, uses [default_script].
+  pub script: Option<Vec<HardwareTestStep>>,
+  /// Where to write the report. If None, the report is printed.
+  pub report_path: Option<PathBuf>,
+  pub unattended: bool,
+  pub allow_power_devices: bool,
+}
+
+impl HardwareTestConfig {
+  pub fn new(address: &str) -> Self {
+    Self {
+      address: address.to_owned(),
+      discovery_timeout: DEFAULT_DISCOVERY_TIMEOUT,
+      script: None,
+      report_path: None,
+      unattended: false,
+      allow_power_devices: false,
+    }
+  }
+
+  /// Reads the configuration from `BUTTPLUG_HW_TEST_*` environment variables
+  /// (see the [module documentation][super]). Returns None if no device
+  /// address is set.
+  pub fn from_env() -> Result<Option<Self>, HardwareTestError> {
+    let address = match env::var("BUTTPLUG_HW_TEST_ADDRESS") {
+      Ok(address) if !address.is_empty() => address,
+      _ => return Ok(None),
+    };
+    let mut config = Self::new(&address);
+    if let Ok(timeout) = env::var("BUTTPLUG_HW_TEST_TIMEOUT") {
+      let seconds = timeout.parse::<u64>().map_err(|_| {
+        HardwareTestError::ConfigError(format!(
+          "BUTTPLUG_HW_TEST_TIMEOUT should be a number of seconds, got {}",
+          timeout
+        ))
+      })?;
+      config.discovery_timeout = Duration::from_secs(seconds);
+    }
+    if let Ok(script_path) = env::var("BUTTPLUG_HW_TEST_SCRIPT") {
+      let script_json = fs::read_to_string(&script_path).map_err(|err| {
+        HardwareTestError::ConfigError(format!("Cannot read script {}: {}", script_path, err))
+      })?;
+      config.script = Some(serde_json::from_str(&script_json).map_err(|err| {
+        HardwareTestError::ConfigError(format!("Cannot parse script {}: {}", script_path, err))
+      })?);
+    }
+    config.report_path = env::var_os("BUTTPLUG_HW_TEST_REPORT").map(PathBuf::from);
+    config.unattended = env::var_os("BUTTPLUG_HW_TEST_UNATTENDED").is_some();
+    config.allow_power_devices = env::var_os("BUTTPLUG_HW_TEST_ALLOW_POWER_DEVICES").is_some();
+    Ok(Some(config))
+  }
+
+  pub fn operator(&self) -> Box<dyn HardwareTestOperator> {
+    if self.unattended {
+      Box::new(UnattendedOperator::default())
+    } else {
+      Box::new(ConsoleOperator::default())
+    }
+  }
+
+  pub fn write_report(&self, report: &ConformanceReport) -> Result<(), HardwareTestError> {
+    match &self.report_path {
+      Some(path) => fs::write(path, report.to_json())
+        .map_err(|err| HardwareTestError::ReportError(path.display().to_string(), err.to_string())),
+      None => {
+        println!("{}", report.to_json());
+        Ok(())
+      }
+    }
+  }
+}
+
+async fn wait_for_device(
+  client: &ButtplugClient,
+  config: &HardwareTestConfig,
+) -> Result<Arc<ButtplugClientDevice>, HardwareTestError> {
+  // The server only exposes the device we're looking for, so the first one
+  // to show up is ours.
+  let events = client.event_stream();
+  pin_mut!(events);
+  client.start_scanning().await?;
+  let mut timeout = Delay::new(config.discovery_timeout).fuse();
+  loop {
+    select! {
+      event = events.next().fuse() => match event {
+        Some(ButtplugClientEvent::DeviceAdded(device)) => return Ok(device),
+        Some(_) => continue,
+        None => {
+          return Err(
+            ButtplugClientError::from(ButtplugConnectorError::ConnectorChannelClosed).into(),
+          )
+        }
+      },
+      _ = timeout => {
+        return Err(HardwareTestError::DeviceNotFound(
+          config.address.clone(),
+          config.discovery_timeout.as_secs(),
+        ))
+      }
+    }
+  }
+}
+
+fn command_outcome(result: Result<(), ButtplugClientError>) -> (StepOutcome, Option<String>) {
+  match result {
+    Ok(()) => (StepOutcome::Passed, None),
+    Err(err) => (StepOutcome::Failed, Some(err.to_string())),
+  }
+}
+
+async fn run_step(
+  device: &ButtplugClientDevice,
+  step: &HardwareTestStep,
+  operator: &dyn HardwareTestOperator,
+) -> (StepOutcome, Option<String>) {
+  if let Some(message) = step.required_message() {
+    if !device.allowed_messages.contains_key(&message) {
+      return (
+        StepOutcome::Skipped,
+        Some(format!("Device does not support {}", message)),
+      );
+    }
+  }
+  match step {
+    HardwareTestStep::Vibrate { speed } => {
+      command_outcome(device.vibrate(VibrateCommand::Speed(*speed)).await)
+    }
+    HardwareTestStep::Rotate { speed, clockwise } => command_outcome(
+      device
+        .rotate(RotateCommand::Rotate(*speed, *clockwise))
+        .await,
+    ),
+    HardwareTestStep::Linear { duration, position } => command_outcome(
+      device
+        .linear(LinearCommand::Linear(*duration, *position))
+        .await,
+    ),
+    HardwareTestStep::Battery => match device.battery_level().await {
+      Ok(level) if (0.0..=1.0).contains(&level) => (
+        StepOutcome::Passed,
+        Some(format!("Battery level {}", level)),
+      ),
+      Ok(level) => (
+        StepOutcome::Failed,
+        Some(format!("Battery level {} out of range", level)),
+      ),
+      Err(err) => (StepOutcome::Failed, Some(err.to_string())),
+    },
+    HardwareTestStep::Stop => command_outcome(device.stop().await),
+    HardwareTestStep::Wait { milliseconds } => {
+      Delay::new(Duration::from_millis(*milliseconds)).await;
+      (StepOutcome::Passed, None)
+    }
+    HardwareTestStep::Confirm { prompt } => match operator.confirm(prompt) {
+      Some(true) => (StepOutcome::Passed, None),
+      Some(false) => (
+        StepOutcome::Failed,
+        Some("Operator reported failure".to_owned()),
+      ),
+      None => (StepOutcome::Unconfirmed, None),
+    },
+  }
+}
+
+async fn run_script(
+  device: &ButtplugClientDevice,
+  config: &HardwareTestConfig,
+  operator: &dyn HardwareTestOperator,
+) -> ConformanceReport {
+  let script = config
+    .script
+    .clone()
+    .unwrap_or_else(|| default_script(device));
+  let mut report = ConformanceReport::new(
+    &config.address,
+    &device.name,
+    device.allowed_messages.keys().cloned().collect(),
+  );
+  for step in script {
+    let start = Instant::now();
+    let (outcome, detail) = run_step(device, &step, operator).await;
+    debug!("Hardware test step {:?}: {:?} {:?}", step, outcome, detail);
+    report.steps.push(StepReport {
+      step,
+      outcome,
+      detail,
+      elapsed_ms: start.elapsed().as_millis() as u64,
+    });
+  }
+  // Don't leave the device running if the script didn't stop it.
+  if let Err(err) = device.stop().await {
+    warn!("Cannot stop device after hardware test: {}", err);
+  }
+  report
+}
+
+/// Connects to the device in the configuration, runs the test script against
+/// it, and reports the results.
+///
+/// Failing steps are recorded in the report, errors are only returned if the
+/// test couldn't run at all.
+pub async fn run_hardware_test(
+  config: &HardwareTestConfig,
+  operator: &dyn HardwareTestOperator,
+) -> Result<ConformanceReport, HardwareTestError> {
+  let client = ButtplugClient::new("Buttplug Hardware Test");
+  let options = ButtplugServerOptions {
+    allow_power_devices: config.allow_power_devices,
+    device_filter: DeviceFilter::default().allow_address(&config.address),
+    ..Default::default()
+  };
+  client.connect_in_process(&options).await?;
+  operator.notify(&format!(
+    "Looking for device {}. Make sure it is on and ready to connect.",
+    config.address
+  ));
+  let device = wait_for_device(&client, config).await;
+  if let Err(err) = client.stop_scanning().await {
+    warn!("Cannot stop scanning: {}", err);
+  }
+  let result = match device {
+    Ok(device) => {
+      operator.notify(&format!("Found {}, starting test.", device.name));
+      Ok(run_script(&device, config, operator).await)
+    }
+    Err(err) => Err(err),
+  };
+  client.disconnect().await?;
+  result
+}
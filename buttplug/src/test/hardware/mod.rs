@@ -0,0 +1,50 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2021 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Harness for running scripted tests against real hardware.
+//!
+//! Most of the library is tested against simulated devices, which can only
+//! tell us that we send the bytes we think we should. This harness connects to
+//! an actual device, identified by its address, runs a script of commands
+//! against it, and asks whoever is sitting next to the device whether it did
+//! what it was supposed to. The result is a [ConformanceReport] that can be
+//! saved as JSON and compared across runs, platforms, and firmware versions.
+//!
+//! The `test_hardware` integration test drives this using environment
+//! variables:
+//!
+//! - `BUTTPLUG_HW_TEST_ADDRESS`: Address of the device to test (required, the
+//!   test is skipped without it). This is whatever address the comm manager
+//!   reports, i.e. a bluetooth MAC on Linux/Windows or a UUID on macOS.
+//! - `BUTTPLUG_HW_TEST_TIMEOUT`: Seconds to wait for the device to show up.
+//!   Defaults to 30.
+//! - `BUTTPLUG_HW_TEST_SCRIPT`: Path to a JSON array of [HardwareTestStep]s.
+//!   If not set, a script is built from the messages the device supports.
+//! - `BUTTPLUG_HW_TEST_REPORT`: Path to write the report to. If not set, the
+//!   report is printed.
+//! - `BUTTPLUG_HW_TEST_UNATTENDED`: If set, operator prompts are skipped and
+//!   recorded as unconfirmed.
+//! - `BUTTPLUG_HW_TEST_ALLOW_POWER_DEVICES`: If set, allows testing devices
+//!   marked as power devices in the device configuration.
+//!
+//! Since prompts read from stdin, run the test with output capture off, i.e.
+//!
+//! ```text
+//! BUTTPLUG_HW_TEST_ADDRESS=aa:bb:cc:dd:ee:ff cargo test --features hardware-tests \
+//!   --test test_hardware -- --nocapture
+//! ```
+
+mod harness;
+mod report;
+mod script;
+
+pub use harness::{
+  run_hardware_test, ConsoleOperator, HardwareTestConfig, HardwareTestError, HardwareTestOperator,
+  UnattendedOperator,
+};
+pub use report::{ConformanceReport, StepOutcome, StepReport};
+pub use script::{default_script, HardwareTestStep};
@@ -0,0 +1,72 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2021 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+use super::script::HardwareTestStep;
+use crate::core::messages::ButtplugCurrentSpecDeviceMessageType;
+use serde::{Deserialize, Serialize};
+
+/// What happened when a step ran.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum StepOutcome {
+  Passed,
+  Failed,
+  /// The device doesn't support the message the step needs.
+  Skipped,
+  /// The step needed an operator, and there wasn't one.
+  Unconfirmed,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StepReport {
+  pub step: HardwareTestStep,
+  pub outcome: StepOutcome,
+  /// Error message, reading, or other information about the outcome.
+  pub detail: Option<String>,
+  pub elapsed_ms: u64,
+}
+
+/// Results of running a hardware test script against a device.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ConformanceReport {
+  /// Version of the library that ran the test.
+  pub library_version: String,
+  pub address: String,
+  pub device_name: String,
+  /// Messages the device was exposed with, sorted by name.
+  pub device_messages: Vec<ButtplugCurrentSpecDeviceMessageType>,
+  pub steps: Vec<StepReport>,
+}
+
+impl ConformanceReport {
+  pub fn new(
+    address: &str,
+    device_name: &str,
+    mut device_messages: Vec<ButtplugCurrentSpecDeviceMessageType>,
+  ) -> Self {
+    device_messages.sort_by_key(|message| message.to_string());
+    Self {
+      library_version: env!("CARGO_PKG_VERSION").to_owned(),
+      address: address.to_owned(),
+      device_name: device_name.to_owned(),
+      device_messages,
+      steps: vec![],
+    }
+  }
+
+  /// A run passes if no step failed. Skipped and unconfirmed steps don't
+  /// count against it.
+  pub fn passed(&self) -> bool {
+    self
+      .steps
+      .iter()
+      .all(|step| step.outcome != StepOutcome::Failed)
+  }
+
+  pub fn to_json(&self) -> String {
+    serde_json::to_string_pretty(self).expect("Reports should always serialize.")
+  }
+}
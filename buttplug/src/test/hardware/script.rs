@@ -0,0 +1,132 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2021 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+use crate::{client::ButtplugClientDevice, core::messages::ButtplugCurrentSpecDeviceMessageType};
+use serde::{Deserialize, Serialize};
+
+/// A single step in a hardware test script.
+///
+/// Scripts are stored as JSON arrays of these, i.e.
+/// `[{"Vibrate": {"speed": 0.5}}, {"Wait": {"milliseconds": 1000}}, "Stop"]`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum HardwareTestStep {
+  /// Sets all vibration features to the same speed.
+  Vibrate { speed: f64 },
+  /// Sets all rotation features to the same speed and direction.
+  Rotate { speed: f64, clockwise: bool },
+  /// Moves all linear features to a position over a duration (in ms).
+  Linear { duration: u32, position: f64 },
+  /// Reads the battery level, which should be between 0.0 and 1.0.
+  Battery,
+  /// Stops the device.
+  Stop,
+  /// Waits before running the next step.
+  Wait { milliseconds: u64 },
+  /// Asks the operator whether the device did what it should have.
+  Confirm { prompt: String },
+}
+
+impl HardwareTestStep {
+  /// The message the device needs to support for this step to run, if any.
+  pub fn required_message(&self) -> Option<ButtplugCurrentSpecDeviceMessageType> {
+    match self {
+      HardwareTestStep::Vibrate { .. } => Some(ButtplugCurrentSpecDeviceMessageType::VibrateCmd),
+      HardwareTestStep::Rotate { .. } => Some(ButtplugCurrentSpecDeviceMessageType::RotateCmd),
+      HardwareTestStep::Linear { .. } => Some(ButtplugCurrentSpecDeviceMessageType::LinearCmd),
+      HardwareTestStep::Battery => Some(ButtplugCurrentSpecDeviceMessageType::BatteryLevelCmd),
+      HardwareTestStep::Stop => Some(ButtplugCurrentSpecDeviceMessageType::StopDeviceCmd),
+      HardwareTestStep::Wait { .. } | HardwareTestStep::Confirm { .. } => None,
+    }
+  }
+}
+
+fn wait(milliseconds: u64) -> HardwareTestStep {
+  HardwareTestStep::Wait { milliseconds }
+}
+
+fn confirm(prompt: &str) -> HardwareTestStep {
+  HardwareTestStep::Confirm {
+    prompt: prompt.to_owned(),
+  }
+}
+
+/// Builds a script that exercises every actuator message the device supports,
+/// with an operator check after each.
+pub fn default_script(device: &ButtplugClientDevice) -> Vec<HardwareTestStep> {
+  let supports = |message| device.allowed_messages.contains_key(&message);
+  let mut steps = vec![];
+  if supports(ButtplugCurrentSpecDeviceMessageType::VibrateCmd) {
+    for speed in &[0.25, 0.5, 1.0] {
+      steps.push(HardwareTestStep::Vibrate { speed: *speed });
+      steps.push(wait(1000));
+    }
+    steps.push(HardwareTestStep::Stop);
+    steps.push(confirm(
+      "Did the device vibrate, getting stronger each second, then stop?",
+    ));
+  }
+  if supports(ButtplugCurrentSpecDeviceMessageType::RotateCmd) {
+    steps.push(HardwareTestStep::Rotate {
+      speed: 0.5,
+      clockwise: true,
+    });
+    steps.push(wait(1500));
+    steps.push(HardwareTestStep::Rotate {
+      speed: 0.5,
+      clockwise: false,
+    });
+    steps.push(wait(1500));
+    steps.push(HardwareTestStep::Stop);
+    steps.push(confirm(
+      "Did the device rotate one way, then the other, then stop?",
+    ));
+  }
+  if supports(ButtplugCurrentSpecDeviceMessageType::LinearCmd) {
+    for position in &[0.0, 1.0, 0.0] {
+      steps.push(HardwareTestStep::Linear {
+        duration: 1000,
+        position: *position,
+      });
+      steps.push(wait(1500));
+    }
+    steps.push(confirm(
+      "Did the device move to one end, then the other, then back?",
+    ));
+  }
+  if supports(ButtplugCurrentSpecDeviceMessageType::BatteryLevelCmd) {
+    steps.push(HardwareTestStep::Battery);
+  }
+  steps.push(HardwareTestStep::Stop);
+  steps
+}
+
+#[cfg(test)]
+mod test {
+  use super::HardwareTestStep;
+
+  #[test]
+  fn test_script_deserialization() {
+    let script_json = r#"[
+      {"Vibrate": {"speed": 0.5}},
+      {"Wait": {"milliseconds": 1000}},
+      "Stop",
+      {"Confirm": {"prompt": "Did it vibrate?"}}
+    ]"#;
+    let script: Vec<HardwareTestStep> = serde_json::from_str(script_json).unwrap();
+    assert_eq!(
+      script,
+      vec![
+        HardwareTestStep::Vibrate { speed: 0.5 },
+        HardwareTestStep::Wait { milliseconds: 1000 },
+        HardwareTestStep::Stop,
+        HardwareTestStep::Confirm {
+          prompt: "Did it vibrate?".to_owned()
+        },
+      ]
+    );
+  }
+}
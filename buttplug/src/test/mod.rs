@@ -1,3 +1,6 @@
+mod fault_injection;
+#[cfg(feature = "hardware-tests")]
+pub mod hardware;
 mod test_device;
 #[cfg(feature = "server")]
 mod test_device_comm_manager;
@@ -6,16 +9,20 @@ use crate::{
   device::DeviceImplCommand,
   util::stream::{iffy_is_empty_check, recv_now},
 };
+pub use fault_injection::{TestFaultConfig, DEFAULT_DISCONNECT_CHECK_INTERVAL};
 use std::sync::{Arc, Mutex};
 pub use test_device::{
   TestDevice, TestDeviceEndpointChannel, TestDeviceImplCreator, TestDeviceInternal,
 };
 #[cfg(feature = "server")]
 pub use test_device_comm_manager::{
-  new_bluetoothle_test_device, TestDeviceCommunicationManager, TestDeviceCommunicationManagerHelper,
+  new_bluetoothle_test_device, new_bluetoothle_test_device_with_clock,
+  TestDeviceCommunicationManager, TestDeviceCommunicationManagerHelper,
 };
 use tokio::sync::mpsc::Receiver;
 
+/// Panics unless the next command the device got on the endpoint is
+/// `command`. Doesn't wait for one to arrive.
 #[allow(dead_code)]
 pub fn check_test_recv_value(
   receiver: &Arc<Mutex<Receiver<DeviceImplCommand>>>,
@@ -27,6 +34,7 @@ pub fn check_test_recv_value(
   );
 }
 
+/// True if the device hasn't gotten any more commands on the endpoint.
 #[allow(dead_code)]
 pub fn check_test_recv_empty(receiver: &Arc<Mutex<Receiver<DeviceImplCommand>>>) -> bool {
   iffy_is_empty_check(&mut receiver.lock().unwrap())
@@ -1,3 +1,4 @@
+use super::fault_injection::{FaultInjector, TestFaultConfig};
 use crate::{
   core::{
     errors::{ButtplugDeviceError, ButtplugError},
@@ -10,13 +11,20 @@ use crate::{
     DeviceImplInternal, DeviceReadCmd, DeviceSubscribeCmd, DeviceUnsubscribeCmd, DeviceWriteCmd,
     Endpoint,
   },
+  util::{
+    async_manager,
+    clock::{Clock, SystemClock},
+  },
 };
 use async_trait::async_trait;
 use dashmap::DashMap;
 use futures::future::{self, BoxFuture};
 use std::{
   fmt::{self, Debug},
-  sync::Arc,
+  sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+  },
 };
 use tokio::sync::{broadcast, mpsc};
 
@@ -34,6 +42,15 @@ impl TestDeviceImplCreator {
     }
   }
 
+  /// Creator for a device that stopped advertising right after it was
+  /// found, so connecting always fails.
+  pub(super) fn new_out_of_range(specifier: DeviceSpecifier) -> Self {
+    Self {
+      specifier,
+      device_impl: None,
+    }
+  }
+
   pub fn device(&self) -> &Option<Arc<TestDeviceInternal>> {
     &self.device_impl
   }
@@ -57,7 +74,9 @@ impl ButtplugDeviceImplCreator for TestDeviceImplCreator {
     &mut self,
     protocol: ProtocolDefinition,
   ) -> Result<DeviceImpl, ButtplugError> {
-    let device = self.device_impl.take().unwrap();
+    let device = self.device_impl.take().ok_or_else(|| {
+      ButtplugDeviceError::DeviceConnectionError("Test device is out of range".to_owned())
+    })?;
     if let Some(btle) = &protocol.btle {
       for endpoint_map in btle.services.values() {
         for endpoint in endpoint_map.keys() {
@@ -71,11 +90,12 @@ impl ButtplugDeviceImplCreator for TestDeviceImplCreator {
       .map(|el| *el.key())
       .collect();
     let device_impl_internal = TestDevice::new(&device);
-    let device_impl = DeviceImpl::new(
+    let device_impl = DeviceImpl::new_with_clock(
       &device.name(),
       &device.address(),
       &endpoints,
       Box::new(device_impl_internal),
+      device.clock.clone(),
     );
     Ok(device_impl)
   }
@@ -105,25 +125,58 @@ pub struct TestDeviceInternal {
   address: String,
   endpoint_channels: Arc<DashMap<Endpoint, TestDeviceEndpointChannel>>,
   event_sender: broadcast::Sender<ButtplugDeviceEvent>,
+  faults: Arc<FaultInjector>,
+  clock: Arc<dyn Clock>,
 }
 
 impl TestDeviceInternal {
   pub fn new(name: &str, address: &str) -> Self {
+    Self::new_with_faults(name, address, TestFaultConfig::default())
+  }
+
+  pub fn new_with_faults(name: &str, address: &str, faults: TestFaultConfig) -> Self {
     let (event_sender, _) = broadcast::channel(256);
     Self {
       name: name.to_owned(),
       address: address.to_owned(),
       endpoint_channels: Arc::new(DashMap::new()),
       event_sender,
+      faults: Arc::new(FaultInjector::new(faults)),
+      clock: Arc::new(SystemClock),
     }
   }
 
+  /// Sets the clock the device's protocol keeps time with, so tests can step
+  /// through command loops with a
+  /// [ManualClock][crate::util::clock::ManualClock]. Needs to be set before
+  /// the device is connected.
+  pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+    self.clock = clock;
+  }
+
+  pub(super) fn faults(&self) -> &FaultInjector {
+    &self.faults
+  }
+
   pub fn sender(&self) -> broadcast::Sender<ButtplugDeviceEvent> {
     self.event_sender.clone()
   }
 
   pub fn send_event(&self, event: ButtplugDeviceEvent) {
-    self.event_sender.send(event).unwrap();
+    match (&event, self.faults.config().notification_delay) {
+      (ButtplugDeviceEvent::Notification(..), Some(delay)) => {
+        let sender = self.event_sender.clone();
+        let clock = self.clock.clone();
+        async_manager::spawn(async move {
+          clock.sleep(delay).await;
+          let _ = sender.send(event);
+        })
+        .unwrap();
+      }
+      _ => {
+        self.event_sender.send(event).unwrap();
+      }
+    }
   }
 
   pub fn name(&self) -> String {
@@ -144,6 +197,15 @@ impl TestDeviceInternal {
       .map(|el| el.value().receiver.clone())
   }
 
+  /// Endpoints the device was connected with.
+  pub fn endpoints(&self) -> Vec<Endpoint> {
+    self
+      .endpoint_channels
+      .iter()
+      .map(|channel| *channel.key())
+      .collect()
+  }
+
   pub async fn add_endpoint(&self, endpoint: &Endpoint) {
     if !self.endpoint_channels.contains_key(endpoint) {
       let (sender, receiver) = mpsc::channel(256);
@@ -171,16 +233,52 @@ pub struct TestDevice {
   // matters here.
   pub endpoint_channels: Arc<DashMap<Endpoint, TestDeviceEndpointChannel>>,
   event_sender: broadcast::Sender<ButtplugDeviceEvent>,
+  faults: Arc<FaultInjector>,
+  connected: Arc<AtomicBool>,
+  clock: Arc<dyn Clock>,
 }
 
 impl TestDevice {
   #[allow(dead_code)]
   pub fn new(internal_device: &TestDeviceInternal) -> Self {
-    Self {
+    let device = Self {
       address: internal_device.address(),
       endpoint_channels: internal_device.endpoint_channels.clone(),
       event_sender: internal_device.sender(),
+      faults: internal_device.faults.clone(),
+      connected: Arc::new(AtomicBool::new(true)),
+      clock: internal_device.clock.clone(),
+    };
+    if device.faults.config().disconnect_rate > 0.0 {
+      device.start_disconnect_faults();
     }
+    device
+  }
+
+  // Disconnects happen on their own schedule, the way a device walking out
+  // of range does, rather than only when something is written to it. The
+  // task only holds a weak ref, so it ends once the device is dropped.
+  fn start_disconnect_faults(&self) {
+    let connected = Arc::downgrade(&self.connected);
+    let faults = self.faults.clone();
+    let clock = self.clock.clone();
+    let event_sender = self.event_sender.clone();
+    let address = self.address.clone();
+    async_manager::spawn(async move {
+      loop {
+        clock.sleep(faults.disconnect_check_interval()).await;
+        let connected = match connected.upgrade() {
+          Some(connected) if connected.load(Ordering::SeqCst) => connected,
+          _ => break,
+        };
+        if faults.roll(faults.config().disconnect_rate) {
+          connected.store(false, Ordering::SeqCst);
+          let _ = event_sender.send(ButtplugDeviceEvent::Removed(address));
+          break;
+        }
+      }
+    })
+    .unwrap();
   }
 }
 
@@ -190,7 +288,7 @@ impl DeviceImplInternal for TestDevice {
   }
 
   fn connected(&self) -> bool {
-    true
+    self.connected.load(Ordering::SeqCst)
   }
 
   fn disconnect(&self) -> ButtplugResultFuture {
@@ -210,13 +308,31 @@ impl DeviceImplInternal for TestDevice {
   }
 
   fn write_value(&self, msg: DeviceWriteCmd) -> ButtplugResultFuture {
+    if !self.connected() {
+      return Box::pin(future::ready(Err(
+        ButtplugDeviceError::DeviceNotConnected(self.address.clone()).into(),
+      )));
+    }
+    // Roll for faults now rather than in the future, so they're picked in
+    // the order writes were made.
+    let faults = self.faults.config();
+    if self.faults.roll(faults.write_failure_rate) {
+      return Box::pin(future::ready(Err(
+        ButtplugDeviceError::DeviceCommunicationError("Injected write failure".to_owned()).into(),
+      )));
+    }
     let channels = self.endpoint_channels.clone();
+    let latency = faults.write_latency;
+    let clock = self.clock.clone();
     Box::pin(async move {
       // Since we're only accessing a channel, we can use a read lock here.
       match channels.get(&msg.endpoint) {
         Some(device_channel) => {
           // We hold both ends, can unwrap.
           device_channel.sender.send(msg.into()).await.unwrap();
+          if let Some(latency) = latency {
+            clock.sleep(latency).await;
+          }
           Ok(())
         }
         None => Err(ButtplugDeviceError::InvalidEndpoint(msg.endpoint).into()),
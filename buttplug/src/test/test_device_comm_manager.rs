@@ -1,13 +1,14 @@
-use super::{TestDeviceImplCreator, TestDeviceInternal};
+use super::{TestDeviceImplCreator, TestDeviceInternal, TestFaultConfig};
 use crate::{
   core::{errors::ButtplugError, ButtplugResultFuture},
   device::{
     configuration_manager::{BluetoothLESpecifier, DeviceConfigurationManager, DeviceSpecifier},
-    ButtplugDevice,
+    ButtplugDevice, ButtplugDeviceImplCreator,
   },
   server::comm_managers::{
     DeviceCommunicationEvent, DeviceCommunicationManager, DeviceCommunicationManagerBuilder,
   },
+  util::clock::Clock,
 };
 use futures::future;
 use std::{
@@ -18,22 +19,25 @@ use tokio::sync::{mpsc::Sender, Mutex};
 
 type WaitingDeviceList = Arc<Mutex<Vec<TestDeviceImplCreator>>>;
 
+// Vaguely, not really random number. Works well enough to be an address that
+// doesn't collide.
+fn new_test_device_address() -> String {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .unwrap()
+    .subsec_nanos()
+    .to_string()
+}
+
 #[allow(dead_code)]
 fn new_uninitialized_ble_test_device(
   name: &str,
   address: Option<String>,
+  faults: TestFaultConfig,
 ) -> (Arc<TestDeviceInternal>, TestDeviceImplCreator) {
-  // Vaguely, not really random number. Works well enough to be an address that
-  // doesn't collide.
-  let address = address.unwrap_or_else(|| {
-    SystemTime::now()
-      .duration_since(UNIX_EPOCH)
-      .unwrap()
-      .subsec_nanos()
-      .to_string()
-  });
+  let address = address.unwrap_or_else(new_test_device_address);
   let specifier = DeviceSpecifier::BluetoothLE(BluetoothLESpecifier::new_from_device(name));
-  let device_impl = Arc::new(TestDeviceInternal::new(name, &address));
+  let device_impl = Arc::new(TestDeviceInternal::new_with_faults(name, &address, faults));
   let device_impl_clone = device_impl.clone();
   let device_impl_creator = TestDeviceImplCreator::new(specifier, device_impl);
   (device_impl_clone, device_impl_creator)
@@ -42,23 +46,43 @@ fn new_uninitialized_ble_test_device(
 async fn new_bluetoothle_test_device_with_cfg(
   name: &str,
   device_config_mgr: Option<Arc<DeviceConfigurationManager>>,
+  clock: Option<Arc<dyn Clock>>,
 ) -> Result<(ButtplugDevice, Arc<TestDeviceInternal>), ButtplugError> {
   let config_mgr =
     device_config_mgr.unwrap_or_else(|| Arc::new(DeviceConfigurationManager::default()));
-  let (device_impl, device_impl_creator) = new_uninitialized_ble_test_device(name, None);
-  let device_impl_clone = device_impl.clone();
+  let mut device_impl = TestDeviceInternal::new(name, &new_test_device_address());
+  if let Some(clock) = clock {
+    device_impl.set_clock(clock);
+  }
+  let device_impl = Arc::new(device_impl);
+  let device_impl_creator = TestDeviceImplCreator::new(
+    DeviceSpecifier::BluetoothLE(BluetoothLESpecifier::new_from_device(name)),
+    device_impl.clone(),
+  );
   let device: ButtplugDevice =
     ButtplugDevice::try_create_device(config_mgr, Box::new(device_impl_creator))
       .await
       .unwrap()
       .unwrap();
-  Ok((device, device_impl_clone))
+  Ok((device, device_impl))
 }
 
+/// Connects a test device with the given Bluetooth LE name, using the built
+/// in device configuration.
 pub async fn new_bluetoothle_test_device(
   name: &str,
 ) -> Result<(ButtplugDevice, Arc<TestDeviceInternal>), ButtplugError> {
-  new_bluetoothle_test_device_with_cfg(name, None).await
+  new_bluetoothle_test_device_with_cfg(name, None, None).await
+}
+
+/// Same as [new_bluetoothle_test_device], but the device keeps time with the
+/// given clock, usually a [ManualClock][crate::util::clock::ManualClock], so
+/// tests can step through protocols that send commands on a timer.
+pub async fn new_bluetoothle_test_device_with_clock(
+  name: &str,
+  clock: Arc<dyn Clock>,
+) -> Result<(ButtplugDevice, Arc<TestDeviceInternal>), ButtplugError> {
+  new_bluetoothle_test_device_with_cfg(name, None, Some(clock)).await
 }
 
 pub struct TestDeviceCommunicationManagerHelper {
@@ -73,7 +97,18 @@ impl TestDeviceCommunicationManagerHelper {
   }
 
   pub async fn add_ble_device(&self, name: &str) -> Arc<TestDeviceInternal> {
-    let (device, creator) = new_uninitialized_ble_test_device(name, None);
+    self
+      .add_ble_device_with_faults(name, TestFaultConfig::default())
+      .await
+  }
+
+  /// Adds a device that misbehaves as set up in `faults`.
+  pub async fn add_ble_device_with_faults(
+    &self,
+    name: &str,
+    faults: TestFaultConfig,
+  ) -> Arc<TestDeviceInternal> {
+    let (device, creator) = new_uninitialized_ble_test_device(name, None, faults);
     self.devices.lock().await.push(creator);
     device
   }
@@ -83,7 +118,11 @@ impl TestDeviceCommunicationManagerHelper {
     name: &str,
     address: &str,
   ) -> Arc<TestDeviceInternal> {
-    let (device, creator) = new_uninitialized_ble_test_device(name, Some(address.to_owned()));
+    let (device, creator) = new_uninitialized_ble_test_device(
+      name,
+      Some(address.to_owned()),
+      TestFaultConfig::default(),
+    );
     self.devices.lock().await.push(creator);
     device
   }
@@ -136,6 +175,23 @@ impl DeviceCommunicationManager for TestDeviceCommunicationManager {
         panic!("No devices for test device comm manager to emit!");
       }
       while let Some(d) = devices.pop() {
+        if let Some(device) = d.device() {
+          let faults = device.faults();
+          if faults.roll(faults.config().advertisement_flap_rate) {
+            info!("Injecting advertisement flap for {}", device.address());
+            if device_sender
+              .send(DeviceCommunicationEvent::DeviceFound {
+                name: device.name(),
+                address: device.address(),
+                creator: Box::new(TestDeviceImplCreator::new_out_of_range(d.get_specifier())),
+              })
+              .await
+              .is_err()
+            {
+              error!("Device channel no longer open.");
+            }
+          }
+        }
         if device_sender
           .send(DeviceCommunicationEvent::DeviceFound {
             name: d
@@ -175,6 +231,7 @@ mod test {
   use crate::{
     core::messages::{self, ButtplugMessageSpecVersion, ButtplugServerMessage},
     server::ButtplugServer,
+    test::TestFaultConfig,
     util::async_manager,
   };
   use futures::StreamExt;
@@ -222,4 +279,44 @@ mod test {
       panic!("Shouldn't get here!");
     });
   }
+
+  #[test]
+  fn test_test_device_injected_faults() {
+    async_manager::block_on(async {
+      let server = ButtplugServer::default();
+      let recv = server.event_stream();
+      pin_mut!(recv);
+      let helper = server.add_test_comm_manager().unwrap();
+      helper
+        .add_ble_device_with_faults(
+          "Massage Demo",
+          TestFaultConfig {
+            write_failure_rate: 1.0,
+            advertisement_flap_rate: 1.0,
+            ..Default::default()
+          },
+        )
+        .await;
+      let msg =
+        messages::RequestServerInfo::new("Test Client", ButtplugMessageSpecVersion::Version2);
+      server.parse_message(msg.into()).await.unwrap();
+      server
+        .parse_message(messages::StartScanning::default().into())
+        .await
+        .unwrap();
+      // The flapped advertisement fails to connect, the one after it works.
+      let mut device_index = None;
+      while let Some(msg) = recv.next().await {
+        if let ButtplugServerMessage::DeviceAdded(da) = msg {
+          device_index = Some(da.device_index());
+          break;
+        }
+      }
+      let vibrate = messages::VibrateCmd::new(
+        device_index.unwrap(),
+        vec![messages::VibrateSubcommand::new(0, 0.5)],
+      );
+      assert!(server.parse_message(vibrate.into()).await.is_err());
+    });
+  }
 }
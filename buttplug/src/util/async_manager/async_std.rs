@@ -0,0 +1,68 @@
+use async_std::task;
+use futures::{
+  future::Future,
+  task::{FutureObj, Spawn, SpawnError, SpawnExt},
+};
+
+#[derive(Default)]
+pub struct AsyncStdAsyncManager {}
+
+impl Spawn for AsyncStdAsyncManager {
+  fn spawn_obj(&self, future: FutureObj<'static, ()>) -> Result<(), SpawnError> {
+    task::spawn(future);
+    Ok(())
+  }
+}
+
+pub fn spawn<Fut>(future: Fut) -> Result<(), SpawnError>
+where
+  Fut: Future<Output = ()> + Send + 'static,
+{
+  AsyncStdAsyncManager::default().spawn(future)
+}
+
+#[cfg(feature = "task-instrumentation")]
+pub fn spawn_named<Fut>(name: &str, future: Fut) -> Result<(), SpawnError>
+where
+  Fut: Future<Output = ()> + Send + 'static,
+{
+  task::Builder::new()
+    .name(name.to_owned())
+    .spawn(future)
+    .map(|_| ())
+    .map_err(|_| SpawnError::shutdown())
+}
+
+pub async fn spawn_blocking<F, T>(func: F) -> Result<T, SpawnError>
+where
+  F: FnOnce() -> T + Send + 'static,
+  T: Send + 'static,
+{
+  Ok(task::spawn_blocking(func).await)
+}
+
+pub fn block_on<F>(f: F) -> <F as Future>::Output
+where
+  F: Future,
+{
+  task::block_on(f)
+}
+
+#[cfg(test)]
+mod test {
+  use super::{block_on, spawn, spawn_blocking};
+  use futures::channel::oneshot;
+
+  #[test]
+  fn test_async_std_backend() {
+    block_on(async {
+      let (sender, receiver) = oneshot::channel();
+      spawn(async move {
+        sender.send(1).unwrap();
+      })
+      .unwrap();
+      assert_eq!(receiver.await.unwrap(), 1);
+      assert_eq!(spawn_blocking(|| 2).await.unwrap(), 2);
+    });
+  }
+}
@@ -1,5 +1,5 @@
 use futures::{
-  future::{Future, RemoteHandle},
+  future::Future,
   task::{FutureObj, Spawn, SpawnError},
 };
 
@@ -19,10 +19,18 @@ where
   unimplemented!("Dummy executor can't actually spawn!")
 }
 
-pub fn spawn_with_handle<Fut>(_: Fut) -> Result<RemoteHandle<Fut::Output>, SpawnError>
+#[cfg(feature = "task-instrumentation")]
+pub fn spawn_named<Fut>(_: &str, _: Fut) -> Result<(), SpawnError>
 where
-  Fut: Future + Send + 'static,
-  Fut::Output: Send,
+  Fut: Future<Output = ()> + Send + 'static,
+{
+  unimplemented!("Dummy executor can't actually spawn!")
+}
+
+pub async fn spawn_blocking<F, T>(_: F) -> Result<T, SpawnError>
+where
+  F: FnOnce() -> T + Send + 'static,
+  T: Send + 'static,
 {
   unimplemented!("Dummy executor can't actually spawn!")
 }
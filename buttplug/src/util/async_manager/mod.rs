@@ -1,15 +1,214 @@
+//! Runtime abstraction for spawning tasks, running blocking calls, and
+//! sleeping.
+//!
+//! The backend is chosen at compile time with one of the runtime features
+//! (`tokio-runtime`, `async-std-runtime`, `wasm-bindgen-runtime`,
+//! `dummy-runtime`). Embedders that already own an executor (game engines,
+//! FFI hosts, etc) can hand it to the library with [set_runtime], after which
+//! spawning, blocking calls and timers all go through it instead of the
+//! compiled in backend.
+//!
+//! With the `task-instrumentation` feature, spawned tasks are named and
+//! tracked, see the `task_inventory` module. In debug builds, spawned tasks
+//! are also watched for blocking calls, see the
+//! [blocking][crate::util::blocking] module.
+
+#[cfg(all(feature = "wasm-bindgen-runtime", not(feature = "dummy-runtime")))]
+use futures::future::LocalBoxFuture;
+use futures::{
+  future::{BoxFuture, Future, FutureExt, RemoteHandle},
+  task::SpawnError,
+};
+use futures_timer::Delay;
+use once_cell::sync::OnceCell;
+use std::{sync::Arc, time::Duration};
+
+#[cfg(feature = "task-instrumentation")]
+pub mod task_inventory;
+
 cfg_if::cfg_if! {
   if #[cfg(feature = "dummy-runtime")] {
     mod dummy;
-    pub use dummy::{DummyAsyncManager as AsyncManager, spawn, spawn_with_handle, block_on};
+    use dummy as backend;
+    pub use dummy::{DummyAsyncManager as AsyncManager, block_on};
   } else if #[cfg(feature = "wasm-bindgen-runtime")] {
     mod wasm_bindgen;
-    pub use self::wasm_bindgen::{WasmBindgenAsyncManager as AsyncManager, spawn, spawn_with_handle, block_on};
+    use self::wasm_bindgen as backend;
+    pub use self::wasm_bindgen::{WasmBindgenAsyncManager as AsyncManager, block_on};
+  } else if #[cfg(feature = "async-std-runtime")] {
+    mod async_std;
+    use self::async_std as backend;
+    pub use self::async_std::{AsyncStdAsyncManager as AsyncManager, block_on};
   } else if #[cfg(feature = "tokio-runtime")] {
     mod tokio;
-    pub use self::tokio::{TokioAsyncManager as AsyncManager, spawn, spawn_with_handle, block_on};
+    use self::tokio as backend;
+    pub use self::tokio::{TokioAsyncManager as AsyncManager, block_on};
   }
   else {
-    std::compile_error!("Please choose a runtime feature: tokio-runtime, wasm-bindgen-runtime, dummy-runtime");
+    std::compile_error!("Please choose a runtime feature: tokio-runtime, async-std-runtime, wasm-bindgen-runtime, dummy-runtime");
+  }
+}
+
+/// An executor provided by the embedding application.
+pub trait AsyncRuntime: Send + Sync {
+  /// Runs a future to completion in the background.
+  fn spawn(&self, future: BoxFuture<'static, ()>) -> Result<(), SpawnError>;
+  /// Runs a blocking closure somewhere it won't stall other tasks. The
+  /// returned future resolves once the closure has finished.
+  fn spawn_blocking(
+    &self,
+    func: Box<dyn FnOnce() + Send + 'static>,
+  ) -> Result<BoxFuture<'static, ()>, SpawnError>;
+  /// Resolves after the duration has passed.
+  fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()>;
+  /// Runs a future that isn't `Send` in the background, on the current
+  /// thread. Only the wasm backend needs this, as wasm futures usually aren't
+  /// Send.
+  #[cfg(all(feature = "wasm-bindgen-runtime", not(feature = "dummy-runtime")))]
+  fn spawn_local(&self, future: LocalBoxFuture<'static, ()>) -> Result<(), SpawnError>;
+}
+
+static RUNTIME: OnceCell<Arc<dyn AsyncRuntime>> = OnceCell::new();
+
+/// Routes all spawning and timers through an embedder provided runtime. Can
+/// only be called once per process, and should be called before any client or
+/// server is created. If a runtime has already been set, the one passed in is
+/// handed back as the error.
+pub fn set_runtime(runtime: Arc<dyn AsyncRuntime>) -> Result<(), Arc<dyn AsyncRuntime>> {
+  RUNTIME.set(runtime)
+}
+
+fn runtime() -> Option<&'static Arc<dyn AsyncRuntime>> {
+  RUNTIME.get()
+}
+
+// The dummy runtime wins over the others when several are turned on (which
+// happens with --all-features), so the wasm spawn functions are only used
+// when the wasm backend actually is.
+cfg_if::cfg_if! {
+  if #[cfg(all(feature = "wasm-bindgen-runtime", not(feature = "dummy-runtime")))] {
+    // wasm futures usually aren't Send, and there's only one thread to run
+    // them on anyways, so the wasm backend always spawns locally.
+    pub fn spawn<Fut>(future: Fut) -> Result<(), SpawnError>
+    where
+      Fut: Future<Output = ()> + 'static,
+    {
+      match runtime() {
+        Some(runtime) => runtime.spawn_local(future.boxed_local()),
+        None => self::wasm_bindgen::spawn(future),
+      }
+    }
+
+    pub fn spawn_named<Fut>(_name: &str, future: Fut) -> Result<(), SpawnError>
+    where
+      Fut: Future<Output = ()> + 'static,
+    {
+      spawn(future)
+    }
+  } else {
+    use std::panic::Location;
+
+    #[track_caller]
+    pub fn spawn<Fut>(future: Fut) -> Result<(), SpawnError>
+    where
+      Fut: Future<Output = ()> + Send + 'static,
+    {
+      spawn_task(None, Location::caller(), future)
+    }
+
+    /// Spawns a task with a name, which shows up in the task inventory and
+    /// tokio-console when `task-instrumentation` is on, and is ignored otherwise.
+    /// Long lived tasks (event loops, keepalives, scanning) should use this over
+    /// [spawn], so they're easy to pick out.
+    #[track_caller]
+    pub fn spawn_named<Fut>(name: &str, future: Fut) -> Result<(), SpawnError>
+    where
+      Fut: Future<Output = ()> + Send + 'static,
+    {
+      spawn_task(Some(name), Location::caller(), future)
+    }
+
+    #[cfg(not(feature = "task-instrumentation"))]
+    fn spawn_task<Fut>(
+      name: Option<&str>,
+      location: &'static Location<'static>,
+      future: Fut,
+    ) -> Result<(), SpawnError>
+    where
+      Fut: Future<Output = ()> + Send + 'static,
+    {
+      // Without task-instrumentation, names are only used to point out tasks
+      // making blocking calls, which is only checked in debug builds.
+      #[cfg(not(debug_assertions))]
+      let _ = (name, location);
+      #[cfg(debug_assertions)]
+      let future = super::blocking::detect_slow_polls(
+        name.map_or_else(|| location.to_string(), str::to_owned),
+        future,
+      );
+      match runtime() {
+        Some(runtime) => runtime.spawn(future.boxed()),
+        None => backend::spawn(future),
+      }
+    }
+
+    #[cfg(feature = "task-instrumentation")]
+    fn spawn_task<Fut>(
+      name: Option<&str>,
+      location: &'static Location<'static>,
+      future: Fut,
+    ) -> Result<(), SpawnError>
+    where
+      Fut: Future<Output = ()> + Send + 'static,
+    {
+      let name = name.map_or_else(|| location.to_string(), str::to_owned);
+      #[cfg(debug_assertions)]
+      let future = super::blocking::detect_slow_polls(name.clone(), future);
+      let future = task_inventory::track(&name, location, future);
+      match runtime() {
+        Some(runtime) => runtime.spawn(future.boxed()),
+        None => backend::spawn_named(&name, future),
+      }
+    }
+  }
+}
+
+#[track_caller]
+pub fn spawn_with_handle<Fut>(future: Fut) -> Result<RemoteHandle<Fut::Output>, SpawnError>
+where
+  Fut: Future + Send + 'static,
+  Fut::Output: Send,
+{
+  let (remote, handle) = future.remote_handle();
+  spawn(remote)?;
+  Ok(handle)
+}
+
+/// Runs a blocking call (device enumeration, synchronous OS APIs, etc) off of
+/// the async executor, and returns its result.
+pub async fn spawn_blocking<F, T>(func: F) -> Result<T, SpawnError>
+where
+  F: FnOnce() -> T + Send + 'static,
+  T: Send + 'static,
+{
+  match runtime() {
+    Some(runtime) => {
+      let (sender, receiver) = futures::channel::oneshot::channel();
+      runtime
+        .spawn_blocking(Box::new(move || {
+          let _ = sender.send(func());
+        }))?
+        .await;
+      receiver.await.map_err(|_| SpawnError::shutdown())
+    }
+    None => backend::spawn_blocking(func).await,
+  }
+}
+
+/// Resolves after the duration has passed.
+pub fn sleep(duration: Duration) -> BoxFuture<'static, ()> {
+  match runtime() {
+    Some(runtime) => runtime.sleep(duration),
+    None => Delay::new(duration).boxed(),
   }
 }
@@ -0,0 +1,189 @@
+//! Inventory of tasks spawned through async_manager, for diagnosing hung or
+//! leaked tasks in the field.
+//!
+//! Only available with the `task-instrumentation` feature. Every task spawned
+//! through [spawn][super::spawn] or [spawn_named][super::spawn_named] is
+//! registered here until it finishes or is dropped, and runs inside a
+//! `buttplug task` span carrying its name and id. Tasks spawned with [spawn]
+//! are named after the source location they were spawned from.
+//!
+//! With the tokio runtime, task names are also handed to tokio, so they show
+//! up in [tokio-console](https://github.com/tokio-rs/console). That needs the
+//! application to install console-subscriber and be built with
+//! `RUSTFLAGS="--cfg tokio_unstable"`, same as any other tokio-console setup.
+//!
+//! [spawn]: super::spawn
+
+use dashmap::DashMap;
+use futures::future::{self, Future};
+use once_cell::sync::Lazy;
+use std::{
+  panic::Location,
+  sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, Mutex,
+  },
+  time::{Duration, Instant},
+};
+use tracing_futures::Instrument;
+
+static TASKS: Lazy<DashMap<u64, Arc<TaskState>>> = Lazy::new(DashMap::new);
+static NEXT_TASK_ID: AtomicU64 = AtomicU64::new(0);
+
+struct TaskState {
+  name: String,
+  location: &'static Location<'static>,
+  spawned_at: Instant,
+  poll_count: AtomicU64,
+  last_polled_at: Mutex<Option<Instant>>,
+}
+
+/// Removes the task from the inventory when the task's future is dropped,
+/// whether it ran to completion or was cancelled.
+struct TaskRegistration(u64);
+
+impl Drop for TaskRegistration {
+  fn drop(&mut self) {
+    TASKS.remove(&self.0);
+  }
+}
+
+/// Snapshot of a running task.
+#[derive(Debug, Clone)]
+pub struct TaskInfo {
+  id: u64,
+  name: String,
+  location: String,
+  age: Duration,
+  poll_count: u64,
+  idle_time: Duration,
+}
+
+impl TaskInfo {
+  /// Process unique id of the task, in spawn order.
+  pub fn id(&self) -> u64 {
+    self.id
+  }
+
+  pub fn name(&self) -> &str {
+    &self.name
+  }
+
+  /// Source location the task was spawned from.
+  pub fn location(&self) -> &str {
+    &self.location
+  }
+
+  /// Time since the task was spawned.
+  pub fn age(&self) -> Duration {
+    self.age
+  }
+
+  pub fn poll_count(&self) -> u64 {
+    self.poll_count
+  }
+
+  /// Time since the task was last polled, or since it was spawned if it
+  /// hasn't been polled yet. Long idle times on tasks that should be ticking
+  /// (ping timers, keepalives) usually mean something they're waiting on has
+  /// hung.
+  pub fn idle_time(&self) -> Duration {
+    self.idle_time
+  }
+}
+
+/// Returns all tasks that are currently running, oldest first.
+pub fn running_tasks() -> Vec<TaskInfo> {
+  let now = Instant::now();
+  let mut tasks: Vec<TaskInfo> = TASKS
+    .iter()
+    .map(|entry| {
+      let state = entry.value();
+      let last_active = state
+        .last_polled_at
+        .lock()
+        .unwrap()
+        .unwrap_or(state.spawned_at);
+      TaskInfo {
+        id: *entry.key(),
+        name: state.name.clone(),
+        location: state.location.to_string(),
+        age: now.duration_since(state.spawned_at),
+        poll_count: state.poll_count.load(Ordering::Relaxed),
+        idle_time: now.duration_since(last_active),
+      }
+    })
+    .collect();
+  tasks.sort_by_key(|task| task.id);
+  tasks
+}
+
+/// Registers a task and wraps its future so polls are counted and the task
+/// leaves the inventory once the future is gone.
+pub(super) fn track<Fut>(
+  name: &str,
+  location: &'static Location<'static>,
+  future: Fut,
+) -> impl Future<Output = Fut::Output>
+where
+  Fut: Future,
+{
+  let id = NEXT_TASK_ID.fetch_add(1, Ordering::Relaxed);
+  let state = Arc::new(TaskState {
+    name: name.to_owned(),
+    location,
+    spawned_at: Instant::now(),
+    poll_count: AtomicU64::new(0),
+    last_polled_at: Mutex::new(None),
+  });
+  TASKS.insert(id, state.clone());
+  let registration = TaskRegistration(id);
+  let mut future = Box::pin(future);
+  future::poll_fn(move |cx| {
+    // Keep the registration alive for as long as the future is.
+    let _ = &registration;
+    state.poll_count.fetch_add(1, Ordering::Relaxed);
+    *state.last_polled_at.lock().unwrap() = Some(Instant::now());
+    future.as_mut().poll(cx)
+  })
+  .instrument(trace_span!(
+    "buttplug task",
+    task.name = name,
+    task.id = id,
+    task.location = tracing::field::display(location)
+  ))
+}
+
+#[cfg(test)]
+mod test {
+  use super::running_tasks;
+  use crate::util::async_manager;
+  use futures::channel::oneshot;
+  use std::time::Duration;
+
+  #[test]
+  fn test_task_inventory_tracks_named_tasks() {
+    async_manager::block_on(async {
+      let (sender, receiver) = oneshot::channel::<()>();
+      async_manager::spawn_named("inventory test task", async move {
+        let _ = receiver.await;
+      })
+      .unwrap();
+      let is_running = || {
+        running_tasks()
+          .iter()
+          .any(|task| task.name() == "inventory test task")
+      };
+      assert!(is_running());
+      sender.send(()).unwrap();
+      // The task finishes on another thread, so give it a moment.
+      for _ in 0..100 {
+        if !is_running() {
+          return;
+        }
+        async_manager::sleep(Duration::from_millis(10)).await;
+      }
+      panic!("Finished task was still in the inventory");
+    });
+  }
+}
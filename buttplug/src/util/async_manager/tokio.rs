@@ -1,5 +1,5 @@
 use futures::{
-  future::{Future, RemoteHandle},
+  future::Future,
   task::{FutureObj, Spawn, SpawnError, SpawnExt},
 };
 use tokio;
@@ -21,12 +21,31 @@ where
   TokioAsyncManager::default().spawn(future)
 }
 
-pub fn spawn_with_handle<Fut>(future: Fut) -> Result<RemoteHandle<Fut::Output>, SpawnError>
+#[cfg(feature = "task-instrumentation")]
+pub fn spawn_named<Fut>(name: &str, future: Fut) -> Result<(), SpawnError>
 where
-  Fut: Future + Send + 'static,
-  Fut::Output: Send,
+  Fut: Future<Output = ()> + Send + 'static,
+{
+  // tokio only takes task names (which is what tokio-console shows) when
+  // built with tokio_unstable.
+  #[cfg(tokio_unstable)]
+  tokio::task::Builder::new().name(name).spawn(future);
+  #[cfg(not(tokio_unstable))]
+  {
+    let _ = name;
+    tokio::spawn(future);
+  }
+  Ok(())
+}
+
+pub async fn spawn_blocking<F, T>(func: F) -> Result<T, SpawnError>
+where
+  F: FnOnce() -> T + Send + 'static,
+  T: Send + 'static,
 {
-  TokioAsyncManager::default().spawn_with_handle(future)
+  tokio::task::spawn_blocking(func)
+    .await
+    .map_err(|_| SpawnError::shutdown())
 }
 
 pub fn block_on<F>(f: F) -> <F as Future>::Output
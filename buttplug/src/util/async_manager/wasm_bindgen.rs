@@ -1,6 +1,6 @@
 use futures::{
-  future::{Future, RemoteHandle},
-  task::{FutureObj, Spawn, SpawnError, SpawnExt},
+  future::Future,
+  task::{FutureObj, Spawn, SpawnError},
 };
 
 use wasm_bindgen_futures::spawn_local;
@@ -23,12 +23,14 @@ where
   Ok(())
 }
 
-pub fn spawn_with_handle<Fut>(future: Fut) -> Result<RemoteHandle<Fut::Output>, SpawnError>
+/// There's no thread to hand blocking work off to in the browser, so this just
+/// runs it in place.
+pub async fn spawn_blocking<F, T>(func: F) -> Result<T, SpawnError>
 where
-  Fut: Future + Send + 'static,
-  Fut::Output: Send,
+  F: FnOnce() -> T + Send + 'static,
+  T: Send + 'static,
 {
-  WasmBindgenAsyncManager::default().spawn_with_handle(future)
+  Ok(func())
 }
 
 pub fn block_on<F>(_: F) -> <F as Future>::Output
@@ -0,0 +1,175 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2022 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Keeping blocking calls off of async threads.
+//!
+//! A lot of what comm managers sit on top of only has blocking APIs: std
+//! channel receivers, OS device enumeration, btleplug's manager and
+//! peripherals. Calling those from a task stalls every other task sharing
+//! its executor thread, which shows up as pings timing out and devices
+//! lagging, nowhere near the actual culprit.
+//!
+//! One-off calls should go through
+//! [async_manager::spawn_blocking][super::async_manager::spawn_blocking].
+//! Values that are only usable through blocking calls can be wrapped in a
+//! [Blocking], which only hands them out on a blocking thread, so there's no
+//! way to call them from async code by accident.
+//!
+//! In debug builds, every task spawned through the async manager is also
+//! timed, and any single poll taking longer than [SLOW_POLL_THRESHOLD] is
+//! logged as a warning with the task's name, as that's almost always a
+//! blocking call that slipped through. Release builds skip the timing.
+
+use super::async_manager;
+use futures::task::SpawnError;
+use std::{
+  sync::{Arc, Mutex},
+  time::Duration,
+};
+
+/// Polls longer than this are reported in debug builds.
+pub const SLOW_POLL_THRESHOLD: Duration = Duration::from_millis(100);
+
+/// A value that's only used through blocking calls.
+///
+/// Calls that might not return for a long time, like a receive loop that
+/// runs as long as the device does, should get a thread of their own
+/// instead, as runtimes wait for blocking calls to finish before they shut
+/// down.
+pub struct Blocking<T> {
+  inner: Arc<Mutex<T>>,
+}
+
+impl<T> Clone for Blocking<T> {
+  fn clone(&self) -> Self {
+    Self {
+      inner: self.inner.clone(),
+    }
+  }
+}
+
+impl<T: Send + 'static> Blocking<T> {
+  pub fn new(value: T) -> Self {
+    Self {
+      inner: Arc::new(Mutex::new(value)),
+    }
+  }
+
+  /// Runs `func` with the value on a blocking thread, and returns what it
+  /// returns. Calls on clones of the same value run one at a time.
+  pub async fn run<F, R>(&self, func: F) -> Result<R, SpawnError>
+  where
+    F: FnOnce(&mut T) -> R + Send + 'static,
+    R: Send + 'static,
+  {
+    let inner = self.inner.clone();
+    async_manager::spawn_blocking(move || func(&mut inner.lock().unwrap())).await
+  }
+}
+
+#[cfg(all(debug_assertions, not(feature = "wasm-bindgen-runtime")))]
+pub(crate) use self::slow_polls::detect_slow_polls;
+
+#[cfg(all(debug_assertions, not(feature = "wasm-bindgen-runtime")))]
+mod slow_polls {
+  use super::SLOW_POLL_THRESHOLD;
+  use futures::future::{self, Future};
+  use std::time::{Duration, Instant};
+
+  /// Wraps a task's future, warning about polls that take too long.
+  pub(crate) fn detect_slow_polls<Fut>(
+    task: String,
+    future: Fut,
+  ) -> impl Future<Output = Fut::Output>
+  where
+    Fut: Future,
+  {
+    watch_polls(future, SLOW_POLL_THRESHOLD, move |elapsed| {
+      warn!(
+        "Task \"{}\" held its executor thread for {:?} in one poll, it's probably making a blocking call.",
+        task, elapsed
+      );
+    })
+  }
+
+  pub(super) fn watch_polls<Fut, F>(
+    future: Fut,
+    threshold: Duration,
+    mut on_slow_poll: F,
+  ) -> impl Future<Output = Fut::Output>
+  where
+    Fut: Future,
+    F: FnMut(Duration),
+  {
+    let mut future = Box::pin(future);
+    future::poll_fn(move |cx| {
+      let start = Instant::now();
+      let result = future.as_mut().poll(cx);
+      let elapsed = start.elapsed();
+      if elapsed >= threshold {
+        on_slow_poll(elapsed);
+      }
+      result
+    })
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::Blocking;
+  use crate::util::async_manager;
+  use std::{sync::mpsc, thread, time::Duration};
+
+  #[test]
+  fn test_blocking_run() {
+    async_manager::block_on(async {
+      let (sender, receiver) = mpsc::channel();
+      let receiver = Blocking::new(receiver);
+      thread::spawn(move || {
+        thread::sleep(Duration::from_millis(10));
+        sender.send(5).unwrap();
+      });
+      assert_eq!(
+        receiver.run(|receiver| receiver.recv()).await.unwrap(),
+        Ok(5)
+      );
+      // The sender's gone now.
+      assert!(receiver
+        .clone()
+        .run(|receiver| receiver.recv())
+        .await
+        .unwrap()
+        .is_err());
+    });
+  }
+
+  #[cfg(all(debug_assertions, not(feature = "wasm-bindgen-runtime")))]
+  #[test]
+  fn test_slow_poll_detection() {
+    use super::slow_polls::watch_polls;
+    use std::sync::{Arc, Mutex};
+
+    async_manager::block_on(async {
+      let slow_polls = Arc::new(Mutex::new(vec![]));
+      let slow_polls_clone = slow_polls.clone();
+      watch_polls(
+        async {
+          async_manager::sleep(Duration::from_millis(20)).await;
+          thread::sleep(Duration::from_millis(60));
+        },
+        Duration::from_millis(50),
+        move |elapsed| slow_polls_clone.lock().unwrap().push(elapsed),
+      )
+      .await;
+      // Only the poll that blocked gets flagged, not the time spent waiting
+      // on the timer.
+      let slow_polls = slow_polls.lock().unwrap();
+      assert_eq!(slow_polls.len(), 1);
+      assert!(slow_polls[0] >= Duration::from_millis(60));
+    });
+  }
+}
@@ -0,0 +1,105 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2022 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Information about how the library was built, so bug reports from
+//! applications can say exactly which build they're running.
+
+#[cfg(feature = "serialize-json")]
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+// cfg! only takes literals, so every feature in Cargo.toml needs listing here.
+// "default" is left out, it just turns on other features.
+macro_rules! enabled_features {
+  ($($feature:literal),* $(,)?) => {
+    [$(($feature, cfg!(feature = $feature))),*]
+  };
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serialize-json", derive(Serialize, Deserialize))]
+pub struct BuildInfo {
+  /// Crate version.
+  pub version: String,
+  /// Git commit the library was built from, if the build script could find
+  /// it (or it was set through the BUTTPLUG_GIT_HASH environment variable).
+  pub git_hash: Option<String>,
+  /// Cargo features the library was built with.
+  pub features: Vec<String>,
+}
+
+impl BuildInfo {
+  /// Returns the build information for this copy of the library.
+  pub fn current() -> Self {
+    let features = enabled_features!(
+      "client",
+      "server",
+      "serialize-json",
+      "websockets",
+      "xinput-manager",
+      "btleplug-manager",
+      "serial-manager",
+      "usb-manager",
+      "mqtt-manager",
+      "btclassic-manager",
+      "lovense-dongle-manager",
+      "http-manager",
+      "lovense-connect-service-manager",
+      "autoblow-manager",
+      "engine-control",
+      "osc-bridge",
+      "server-emulator",
+      "device-scripting",
+      "server-runner",
+      "lan-discovery",
+      "json-schema",
+      "ffi",
+      "tokio-runtime",
+      "async-std-runtime",
+      "wasm-bindgen-runtime",
+      "dummy-runtime",
+      "task-instrumentation",
+      "hardware-tests",
+      "unstable",
+    );
+    Self {
+      version: env!("CARGO_PKG_VERSION").to_owned(),
+      git_hash: option_env!("BUTTPLUG_GIT_HASH").map(|hash| hash.to_owned()),
+      features: features
+        .iter()
+        .filter(|(_, enabled)| *enabled)
+        .map(|(feature, _)| (*feature).to_owned())
+        .collect(),
+    }
+  }
+}
+
+impl fmt::Display for BuildInfo {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "buttplug {}", self.version)?;
+    if let Some(hash) = &self.git_hash {
+      write!(f, " ({})", hash)?;
+    }
+    write!(f, " [{}]", self.features.join(", "))
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::BuildInfo;
+
+  #[test]
+  fn test_build_info() {
+    let info = BuildInfo::current();
+    assert_eq!(info.version, env!("CARGO_PKG_VERSION"));
+    assert_eq!(
+      info.features.contains(&"server".to_owned()),
+      cfg!(feature = "server")
+    );
+    assert!(info.to_string().starts_with("buttplug "));
+  }
+}
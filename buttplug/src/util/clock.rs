@@ -0,0 +1,210 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2022 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Time source for timers and loops that wait on the clock (ping timeouts,
+//! device keepalives, command pacing), so tests can move time forward
+//! themselves instead of sleeping.
+
+use super::async_manager;
+use futures::{
+  channel::oneshot,
+  future::{BoxFuture, FutureExt},
+};
+use std::{
+  sync::{Arc, Mutex},
+  time::{Duration, Instant},
+};
+
+/// Something that tells the time and can wait for it to pass.
+pub trait Clock: Send + Sync {
+  /// Current time, by this clock.
+  fn now(&self) -> Instant;
+  /// Resolves once the duration has passed, by this clock.
+  fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()>;
+}
+
+/// Real time, with sleeps going through the
+/// [async manager][crate::util::async_manager].
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+  fn now(&self) -> Instant {
+    Instant::now()
+  }
+
+  fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+    async_manager::sleep(duration)
+  }
+}
+
+struct Sleeper {
+  deadline: Instant,
+  waker: oneshot::Sender<()>,
+}
+
+struct SleepWatcher {
+  count: usize,
+  waker: oneshot::Sender<()>,
+}
+
+struct ManualClockState {
+  now: Instant,
+  sleepers: Vec<Sleeper>,
+  sleeps_started: usize,
+  watchers: Vec<SleepWatcher>,
+}
+
+impl ManualClockState {
+  fn pending_sleepers(&self) -> usize {
+    self
+      .sleepers
+      .iter()
+      .filter(|sleeper| !sleeper.waker.is_canceled())
+      .count()
+  }
+}
+
+/// Clock that only moves when told to, for tests.
+///
+/// Sleeps resolve when [advance][ManualClock::advance] moves the clock past
+/// their deadline, no matter how much real time has gone by. Clones share the
+/// same time.
+#[derive(Clone)]
+pub struct ManualClock {
+  state: Arc<Mutex<ManualClockState>>,
+}
+
+impl ManualClock {
+  pub fn new() -> Self {
+    Self {
+      state: Arc::new(Mutex::new(ManualClockState {
+        now: Instant::now(),
+        sleepers: vec![],
+        sleeps_started: 0,
+        watchers: vec![],
+      })),
+    }
+  }
+
+  /// Moves the clock forward, waking every sleep that's now due.
+  pub fn advance(&self, duration: Duration) {
+    let due = {
+      let mut state = self.state.lock().unwrap();
+      state.now += duration;
+      let now = state.now;
+      let (due, waiting) = state
+        .sleepers
+        .drain(..)
+        .partition(|sleeper| sleeper.deadline <= now);
+      state.sleepers = waiting;
+      due
+    };
+    for sleeper in due {
+      let _ = sleeper.waker.send(());
+    }
+  }
+
+  /// Number of sleeps currently waiting on the clock.
+  pub fn pending_sleepers(&self) -> usize {
+    self.state.lock().unwrap().pending_sleepers()
+  }
+
+  /// Number of sleeps started on the clock since it was created.
+  pub fn sleeps_started(&self) -> usize {
+    self.state.lock().unwrap().sleeps_started
+  }
+
+  /// Resolves once at least `count` sleeps have been started on the clock
+  /// since it was created. Loops usually start one sleep per pass, so tests
+  /// use this to know a task has gotten to its next wait before calling
+  /// [advance][ManualClock::advance].
+  pub fn wait_for_sleeps(&self, count: usize) -> BoxFuture<'static, ()> {
+    let mut state = self.state.lock().unwrap();
+    if state.sleeps_started >= count {
+      return async {}.boxed();
+    }
+    let (waker, waiter) = oneshot::channel();
+    state.watchers.push(SleepWatcher { count, waker });
+    async move {
+      let _ = waiter.await;
+    }
+    .boxed()
+  }
+}
+
+impl Default for ManualClock {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl Clock for ManualClock {
+  fn now(&self) -> Instant {
+    self.state.lock().unwrap().now
+  }
+
+  fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+    if duration == Duration::from_millis(0) {
+      return async {}.boxed();
+    }
+    let (waker, waiter) = oneshot::channel();
+    let mut state = self.state.lock().unwrap();
+    let deadline = state.now + duration;
+    state.sleepers.push(Sleeper { deadline, waker });
+    state.sleeps_started += 1;
+    let started = state.sleeps_started;
+    let (ready, waiting) = state
+      .watchers
+      .drain(..)
+      .partition(|watcher| watcher.count <= started);
+    state.watchers = waiting;
+    for watcher in ready {
+      let _ = watcher.waker.send(());
+    }
+    async move {
+      // If the clock goes away, no one is left to advance it, so this never
+      // resolves.
+      if waiter.await.is_err() {
+        futures::future::pending::<()>().await;
+      }
+    }
+    .boxed()
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::{Clock, ManualClock};
+  use futures::FutureExt;
+  use std::time::Duration;
+
+  #[test]
+  fn test_manual_clock() {
+    let clock = ManualClock::new();
+    let start = clock.now();
+    let mut short = clock.sleep(Duration::from_millis(10));
+    let mut long = clock.sleep(Duration::from_millis(30));
+    assert_eq!(clock.pending_sleepers(), 2);
+    assert!(clock.wait_for_sleeps(2).now_or_never().is_some());
+    let mut watcher = clock.wait_for_sleeps(4);
+    assert!((&mut watcher).now_or_never().is_none());
+    clock.advance(Duration::from_millis(20));
+    assert_eq!(clock.now() - start, Duration::from_millis(20));
+    assert!((&mut short).now_or_never().is_some());
+    assert!((&mut long).now_or_never().is_none());
+    // Dropped sleeps stop counting as pending.
+    drop(long);
+    assert_eq!(clock.pending_sleepers(), 0);
+    let _third = clock.sleep(Duration::from_millis(5));
+    assert!((&mut watcher).now_or_never().is_none());
+    let _fourth = clock.sleep(Duration::from_millis(5));
+    assert!(watcher.now_or_never().is_some());
+    assert_eq!(clock.sleeps_started(), 4);
+    assert_eq!(clock.pending_sleepers(), 2);
+  }
+}
@@ -0,0 +1,235 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2022 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! LAN discovery for servers, so clients don't need to be told where to
+//! connect.
+//!
+//! Applications running a [ButtplugRemoteServer][crate::server::ButtplugRemoteServer]
+//! can start a [DiscoveryResponder] next to it. Clients broadcast a query
+//! over UDP with [discover_servers], and every responder that hears it
+//! replies with a JSON [ServerAdvertisement] describing how to connect.
+//!
+//! The responder doesn't know anything about the server it's advertising,
+//! the application fills in the advertisement, and is responsible for it
+//! matching what the server is actually listening on.
+
+use crate::{core::messages::BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION, util::async_manager};
+use futures::{select, FutureExt};
+use futures_timer::Delay;
+use serde::{Deserialize, Serialize};
+use std::{
+  io,
+  net::{IpAddr, Ipv4Addr, SocketAddr},
+  time::Duration,
+};
+use tokio::net::UdpSocket;
+use tokio_util::sync::CancellationToken;
+
+/// Port responders listen on by default, one up from the default websocket
+/// server port.
+pub const DEFAULT_DISCOVERY_PORT: u16 = 12346;
+
+/// Datagram clients send to ask servers to announce themselves.
+pub const DISCOVERY_QUERY: &[u8] = b"ButtplugDiscover";
+
+/// What a responder tells clients about its server.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ServerAdvertisement {
+  /// Server name, for showing in a server list.
+  pub name: String,
+  /// Port the server's websocket is listening on. The host is whatever
+  /// address the advertisement came from.
+  pub port: u16,
+  /// True if the websocket needs a secure (wss://) connection.
+  pub tls: bool,
+  /// Latest message spec version the server supports.
+  pub message_version: u32,
+}
+
+impl ServerAdvertisement {
+  pub fn new(name: &str, port: u16, tls: bool) -> Self {
+    Self {
+      name: name.to_owned(),
+      port,
+      tls,
+      message_version: BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION as u32,
+    }
+  }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DiscoveryResponderConfig {
+  /// Address to listen for queries on. Defaults to all interfaces, on
+  /// [DEFAULT_DISCOVERY_PORT].
+  pub listen_address: SocketAddr,
+  pub advertisement: ServerAdvertisement,
+}
+
+impl DiscoveryResponderConfig {
+  pub fn new(advertisement: ServerAdvertisement) -> Self {
+    Self {
+      listen_address: SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), DEFAULT_DISCOVERY_PORT),
+      advertisement,
+    }
+  }
+}
+
+/// Answers discovery queries until dropped.
+pub struct DiscoveryResponder {
+  local_address: SocketAddr,
+  token: CancellationToken,
+}
+
+impl DiscoveryResponder {
+  /// Binds the listen address and starts answering queries. Fails if the
+  /// address can't be bound, e.g. because another responder already has it.
+  pub async fn start(config: DiscoveryResponderConfig) -> Result<Self, io::Error> {
+    let socket = UdpSocket::bind(config.listen_address).await?;
+    let local_address = socket.local_addr()?;
+    // Serializing a struct of plain fields can't fail.
+    let reply = serde_json::to_vec(&config.advertisement).unwrap();
+    let token = CancellationToken::new();
+    let child_token = token.child_token();
+    async_manager::spawn_named("discovery responder", async move {
+      run_responder(socket, reply, child_token).await;
+    })
+    .unwrap();
+    info!("Discovery responder listening on {}", local_address);
+    Ok(Self {
+      local_address,
+      token,
+    })
+  }
+
+  /// Address the responder is listening on. Useful when it was started on
+  /// port 0.
+  pub fn local_address(&self) -> SocketAddr {
+    self.local_address
+  }
+}
+
+impl Drop for DiscoveryResponder {
+  fn drop(&mut self) {
+    self.token.cancel();
+  }
+}
+
+async fn run_responder(socket: UdpSocket, reply: Vec<u8>, token: CancellationToken) {
+  // Queries are a fixed string, anything bigger isn't one.
+  let mut buf = [0u8; 64];
+  loop {
+    select! {
+      result = socket.recv_from(&mut buf).fuse() => match result {
+        Ok((len, from)) => {
+          if &buf[..len] != DISCOVERY_QUERY {
+            debug!("Ignoring unknown discovery datagram from {}", from);
+            continue;
+          }
+          if let Err(err) = socket.send_to(&reply, from).await {
+            warn!("Cannot answer discovery query from {}: {:?}", from, err);
+          }
+        }
+        Err(err) => error!("Error receiving discovery query: {:?}", err),
+      },
+      _ = token.cancelled().fuse() => break,
+    }
+  }
+  info!("Discovery responder stopped.");
+}
+
+/// A server that answered a discovery query.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DiscoveredServer {
+  /// Address to connect to, made from the address the answer came from and
+  /// the advertised port.
+  pub address: SocketAddr,
+  pub advertisement: ServerAdvertisement,
+}
+
+impl DiscoveredServer {
+  /// Websocket URL to connect to the server with.
+  pub fn url(&self) -> String {
+    let scheme = if self.advertisement.tls { "wss" } else { "ws" };
+    format!("{}://{}", scheme, self.address)
+  }
+}
+
+/// Sends a discovery query to `discovery_address`, usually the broadcast
+/// address on [DEFAULT_DISCOVERY_PORT], and collects answers for `wait`.
+/// Each server is only listed once, however many times it answers. Errors
+/// receiving answers are logged and skipped, only setting up the query fails.
+pub async fn discover_servers(
+  discovery_address: SocketAddr,
+  wait: Duration,
+) -> Result<Vec<DiscoveredServer>, io::Error> {
+  let socket = UdpSocket::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0)).await?;
+  socket.set_broadcast(true)?;
+  socket.send_to(DISCOVERY_QUERY, discovery_address).await?;
+  let mut servers: Vec<DiscoveredServer> = vec![];
+  let mut buf = vec![0u8; 2048];
+  let mut timeout = Delay::new(wait).fuse();
+  loop {
+    select! {
+      result = socket.recv_from(&mut buf).fuse() => {
+        let (len, from) = match result {
+          Ok(received) => received,
+          Err(err) => {
+            // A single bad datagram (e.g. an ICMP unreachable surfacing as
+            // ECONNREFUSED) shouldn't throw away answers we already have.
+            warn!("Error receiving discovery answer, ignoring: {:?}", err);
+            continue;
+          }
+        };
+        let advertisement: ServerAdvertisement = match serde_json::from_slice(&buf[..len]) {
+          Ok(advertisement) => advertisement,
+          Err(err) => {
+            debug!("Ignoring invalid discovery answer from {}: {:?}", from, err);
+            continue;
+          }
+        };
+        let server = DiscoveredServer {
+          address: SocketAddr::new(from.ip(), advertisement.port),
+          advertisement,
+        };
+        if !servers.contains(&server) {
+          servers.push(server);
+        }
+      },
+      _ = timeout => break,
+    }
+  }
+  Ok(servers)
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_discovery() {
+    async_manager::block_on(async {
+      let mut config =
+        DiscoveryResponderConfig::new(ServerAdvertisement::new("Test Server", 12345, true));
+      config.listen_address = "127.0.0.1:0".parse().unwrap();
+      let responder = DiscoveryResponder::start(config).await.unwrap();
+      let servers = discover_servers(responder.local_address(), Duration::from_millis(200))
+        .await
+        .unwrap();
+      assert_eq!(servers.len(), 1);
+      assert_eq!(servers[0].advertisement.name, "Test Server");
+      assert_eq!(servers[0].url(), "wss://127.0.0.1:12345");
+      let address = responder.local_address();
+      drop(responder);
+      // Give the responder task a chance to see it's been stopped.
+      Delay::new(Duration::from_millis(50)).await;
+      let servers = discover_servers(address, Duration::from_millis(100))
+        .await
+        .unwrap();
+      assert!(servers.is_empty());
+    });
+  }
+}
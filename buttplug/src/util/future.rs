@@ -10,7 +10,7 @@
 
 use core::pin::Pin;
 use futures::{
-  future::Future,
+  future::{BoxFuture, Future},
   task::{Context, Poll, Waker},
 };
 use std::sync::{Arc, Mutex, MutexGuard};
@@ -192,3 +192,54 @@ impl<T> Future for ButtplugFuture<T> {
     }
   }
 }
+
+/// Future that either already has its output, or needs to wait on a boxed
+/// future for it.
+///
+/// A lot of message handling can be answered on the spot (errors, lookups
+/// of things we already have in memory, etc). Boxing a future for each of
+/// those means an allocation per message, which adds up when clients send
+/// streams of device commands, so this lets the ready case skip the box.
+pub enum ButtplugReadyOrBoxedFuture<T> {
+  /// Holds the output until polled. Polling again afterward panics.
+  Ready(Option<T>),
+  Boxed(BoxFuture<'static, T>),
+}
+
+impl<T> ButtplugReadyOrBoxedFuture<T> {
+  pub fn ready(value: T) -> Self {
+    ButtplugReadyOrBoxedFuture::Ready(Some(value))
+  }
+
+  pub fn boxed<F>(future: F) -> Self
+  where
+    F: Future<Output = T> + Send + 'static,
+  {
+    ButtplugReadyOrBoxedFuture::Boxed(Box::pin(future))
+  }
+}
+
+impl<T> From<BoxFuture<'static, T>> for ButtplugReadyOrBoxedFuture<T> {
+  fn from(future: BoxFuture<'static, T>) -> Self {
+    ButtplugReadyOrBoxedFuture::Boxed(future)
+  }
+}
+
+// The output is only ever moved out, never pinned, so we're Unpin regardless
+// of T.
+impl<T> Unpin for ButtplugReadyOrBoxedFuture<T> {}
+
+impl<T> Future for ButtplugReadyOrBoxedFuture<T> {
+  type Output = T;
+
+  fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+    match self.get_mut() {
+      ButtplugReadyOrBoxedFuture::Ready(value) => Poll::Ready(
+        value
+          .take()
+          .expect("ButtplugReadyOrBoxedFuture polled after completion."),
+      ),
+      ButtplugReadyOrBoxedFuture::Boxed(future) => future.as_mut().poll(cx),
+    }
+  }
+}
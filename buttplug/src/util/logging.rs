@@ -1,7 +1,34 @@
 use crate::util::async_manager;
+use once_cell::sync::Lazy;
+use std::{
+  borrow::Cow,
+  collections::{hash_map::RandomState, HashMap},
+  fmt,
+  hash::{BuildHasher, Hash, Hasher},
+  sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, RwLock,
+  },
+};
 use tokio::sync::mpsc::Sender;
+use tracing::Subscriber;
+use tracing_subscriber::{
+  field::RecordFields,
+  fmt::{
+    format::{DefaultFields, FormatFields},
+    Layer, MakeWriter,
+  },
+  registry::LookupSpan,
+};
 
-use tracing_subscriber::fmt::MakeWriter;
+// Identifiers shorter than this are things like "P" or "Max", which show up
+// all over log output and identify nobody.
+const MIN_REDACTED_IDENTIFIER_LENGTH: usize = 4;
+
+// Random keys per process, so hashes are stable within a session but can't be
+// matched up across sessions, or reversed by hashing known addresses.
+static REDACTION_SALT: Lazy<RandomState> = Lazy::new(RandomState::new);
+static REDACTOR: Lazy<Arc<IdentifierRedactor>> = Lazy::new(Default::default);
 
 /// Convenience struct for handling tracing output from Buttplug.
 ///
@@ -45,3 +72,191 @@ impl MakeWriter for ChannelWriter {
     ChannelWriter::new(self.log_sender.clone())
   }
 }
+
+/// Device identifiers to redact, and whether redaction is on. There's one
+/// for the process, but tests use their own.
+#[derive(Default)]
+struct IdentifierRedactor {
+  enabled: AtomicBool,
+  identifiers: RwLock<HashMap<String, String>>,
+}
+
+impl IdentifierRedactor {
+  fn enable(&self) {
+    self.enabled.store(true, Ordering::SeqCst);
+  }
+
+  fn register(&self, identifier: &str) {
+    if !self.enabled.load(Ordering::Relaxed) || identifier.len() < MIN_REDACTED_IDENTIFIER_LENGTH {
+      return;
+    }
+    let mut identifiers = self.identifiers.write().unwrap();
+    if !identifiers.contains_key(identifier) {
+      let mut hasher = REDACTION_SALT.build_hasher();
+      identifier.hash(&mut hasher);
+      let hash = format!("<redacted {:08x}>", hasher.finish() as u32);
+      identifiers.insert(identifier.to_owned(), hash);
+    }
+  }
+
+  fn redact<'a>(&self, text: &'a str) -> Cow<'a, str> {
+    let identifiers = self.identifiers.read().unwrap();
+    if identifiers.is_empty() {
+      return Cow::Borrowed(text);
+    }
+    // Longest first, in case a name contains an address or another name.
+    let mut sorted: Vec<_> = identifiers.iter().collect();
+    sorted.sort_by(|a, b| b.0.len().cmp(&a.0.len()));
+    let mut redacted = Cow::Borrowed(text);
+    for (identifier, hash) in sorted {
+      if redacted.contains(identifier.as_str()) {
+        redacted = Cow::Owned(redacted.replace(identifier.as_str(), hash));
+      }
+    }
+    redacted
+  }
+}
+
+/// Turns on redaction of device addresses and names, for every server in the
+/// process. Usually done through the server options.
+pub fn enable_identifier_redaction() {
+  REDACTOR.enable();
+}
+
+/// Adds a device address or name to the identifiers replaced by
+/// [redact_identifiers]. Does nothing unless redaction is enabled.
+///
+/// Comm managers should register identifiers before they first log them, as
+/// anything logged earlier goes out as is.
+pub fn register_identifier(identifier: &str) {
+  REDACTOR.register(identifier);
+}
+
+/// Replaces every registered identifier in the text with its hash.
+pub fn redact_identifiers(text: &str) -> Cow<'_, str> {
+  REDACTOR.redact(text)
+}
+
+/// Field formatter that redacts device identifiers from field values,
+/// including the message, as events and spans are formatted.
+///
+/// Tracing layers can't change what other layers see, so redaction happens
+/// in the formatting layer. Use [redacting_layer] for a formatting layer
+/// with this set, or set it on whatever formatter the application builds:
+///
+/// ```no_run
+/// use buttplug::util::logging::RedactingFields;
+///
+/// tracing_subscriber::fmt()
+///   .fmt_fields(RedactingFields::default())
+///   .init();
+/// ```
+///
+/// Only identifiers of devices found after redaction was turned on (see
+/// [enable_identifier_redaction]) are replaced.
+pub struct RedactingFields {
+  redactor: Arc<IdentifierRedactor>,
+}
+
+impl Default for RedactingFields {
+  fn default() -> Self {
+    Self {
+      redactor: REDACTOR.clone(),
+    }
+  }
+}
+
+impl<'writer> FormatFields<'writer> for RedactingFields {
+  fn format_fields<R: RecordFields>(
+    &self,
+    writer: &'writer mut dyn fmt::Write,
+    fields: R,
+  ) -> fmt::Result {
+    let mut formatted = String::new();
+    DefaultFields::new().format_fields(&mut formatted, fields)?;
+    writer.write_str(&self.redactor.redact(&formatted))
+  }
+}
+
+/// Formatting layer that writes to stdout, with device identifiers redacted
+/// from field values. See [RedactingFields].
+///
+/// ```no_run
+/// use tracing_subscriber::prelude::*;
+///
+/// tracing_subscriber::registry()
+///   .with(buttplug::util::logging::redacting_layer())
+///   .init();
+/// ```
+pub fn redacting_layer<S>() -> Layer<S, RedactingFields>
+where
+  S: Subscriber + for<'a> LookupSpan<'a>,
+{
+  tracing_subscriber::fmt::layer().fmt_fields(RedactingFields::default())
+}
+
+#[cfg(test)]
+mod test {
+  use super::{IdentifierRedactor, RedactingFields};
+  use std::{
+    io,
+    sync::{Arc, Mutex},
+  };
+
+  struct TestWriter(Arc<Mutex<Vec<u8>>>);
+
+  impl io::Write for TestWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+      self.0.lock().unwrap().extend_from_slice(buf);
+      Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+      Ok(())
+    }
+  }
+
+  #[test]
+  fn test_identifier_redaction() {
+    let redactor = IdentifierRedactor::default();
+    // Nothing's registered until redaction is turned on.
+    redactor.register("AA:BB:CC:DD:EE:FF");
+    assert_eq!(redactor.redact("AA:BB:CC:DD:EE:FF"), "AA:BB:CC:DD:EE:FF");
+    redactor.enable();
+    redactor.register("AA:BB:CC:DD:EE:FF");
+    redactor.register("Toy SN1234");
+    redactor.register("P");
+    let redacted = redactor.redact("Found Toy SN1234 at AA:BB:CC:DD:EE:FF, model P");
+    assert!(!redacted.contains("AA:BB:CC:DD:EE:FF"));
+    assert!(!redacted.contains("SN1234"));
+    assert!(redacted.contains("<redacted "));
+    // Too short to be worth hiding.
+    assert!(redacted.ends_with("model P"));
+    // Same identifier, same hash.
+    assert_eq!(redactor.redact("Toy SN1234"), redactor.redact("Toy SN1234"));
+  }
+
+  #[test]
+  fn test_redacting_fields() {
+    let redactor = Arc::new(IdentifierRedactor::default());
+    redactor.enable();
+    redactor.register("AA:BB:CC:DD:EE:FF");
+    let output = Arc::new(Mutex::new(vec![]));
+    let writer_output = output.clone();
+    let subscriber = tracing_subscriber::fmt()
+      .with_writer(move || TestWriter(writer_output.clone()))
+      .fmt_fields(RedactingFields { redactor })
+      .finish();
+    tracing::subscriber::with_default(subscriber, || {
+      let span = info_span!("device", address = "AA:BB:CC:DD:EE:FF");
+      let _enter = span.enter();
+      info!(
+        address = "AA:BB:CC:DD:EE:FF",
+        "Found device at AA:BB:CC:DD:EE:FF"
+      );
+    });
+    let output = String::from_utf8(output.lock().unwrap().clone()).unwrap();
+    assert!(output.contains("Found device at <redacted "));
+    assert!(!output.contains("AA:BB:CC:DD:EE:FF"));
+  }
+}
@@ -9,7 +9,15 @@
 //! the library.
 
 pub mod async_manager;
+pub mod blocking;
+pub mod build_info;
+pub mod clock;
+#[cfg(feature = "lan-discovery")]
+pub mod discovery;
 pub mod future;
 pub mod json;
 pub mod logging;
+pub mod pattern;
+pub mod scheduler;
 pub mod stream;
+pub mod testing;
@@ -0,0 +1,347 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2022 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Basic vibration patterns, generated as timed sequences of speeds.
+//!
+//! Most apps end up with a "wave" or "pulse" mode, and most of them get the
+//! timing slightly different. A [Pattern] is a shape (sine, pulse, ramp or
+//! random walk), the speed range it's played over, and how often a new speed
+//! is sent. Its [steps][Pattern::steps] are the speeds to send and when to
+//! send them, relative to the start of the pattern, which can be turned into
+//! VibrateCmd messages with [vibrate_commands][Pattern::vibrate_commands].
+//!
+//! ```
+//! use buttplug::util::pattern::Pattern;
+//! use std::time::Duration;
+//!
+//! // A 2 second wave between 20% and 80%, updated every 100ms.
+//! let pattern = Pattern::sine(Duration::from_secs(2)).intensity_range(0.2, 0.8);
+//! let commands = pattern.vibrate_commands(0, 0, Duration::from_secs(10));
+//! assert_eq!(commands.len(), 100);
+//! ```
+//!
+//! Steps are computed from their offset, not by adding up intervals, so
+//! sequences don't drift however long they run.
+
+use crate::core::messages::{VibrateCmd, VibrateSubcommand};
+use std::{f64::consts::PI, time::Duration};
+
+/// How often patterns send a new speed, unless told otherwise.
+pub const DEFAULT_PATTERN_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Shortest time between steps. Shorter intervals, including zero, are
+/// raised to this, otherwise every step would land on the same offset and
+/// anything waiting for the offset to move on would never finish.
+pub const MIN_PATTERN_INTERVAL: Duration = Duration::from_millis(1);
+
+/// Shape of a pattern, as a level from 0.0 to 1.0 over time.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PatternShape {
+  /// Smooth wave, starting at 0.0, peaking at 1.0 halfway through each
+  /// period.
+  Sine { period: Duration },
+  /// 1.0 for the first `duty_cycle` (0.0-1.0) of each period, 0.0 for the
+  /// rest.
+  Pulse { period: Duration, duty_cycle: f64 },
+  /// Straight line from 0.0 to 1.0 over `duration`, then stays at 1.0.
+  Ramp { duration: Duration },
+  /// Starts at 0.5 and moves by up to `max_step` each step, in a random
+  /// direction, staying within 0.0-1.0. The same seed always gives the same
+  /// walk.
+  RandomWalk { max_step: f64, seed: u64 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Pattern {
+  pub shape: PatternShape,
+  /// Speeds (0.0-1.0) the shape's 0.0 and 1.0 are mapped to. The first can
+  /// be higher than the second to flip the shape, e.g. for a ramp down.
+  pub intensity_range: (f64, f64),
+  /// Time between steps. Never less than [MIN_PATTERN_INTERVAL] when
+  /// stepping.
+  pub interval: Duration,
+}
+
+impl Pattern {
+  pub fn new(shape: PatternShape) -> Self {
+    Self {
+      shape,
+      intensity_range: (0.0, 1.0),
+      interval: DEFAULT_PATTERN_INTERVAL,
+    }
+  }
+
+  pub fn sine(period: Duration) -> Self {
+    Self::new(PatternShape::Sine { period })
+  }
+
+  pub fn pulse(period: Duration, duty_cycle: f64) -> Self {
+    Self::new(PatternShape::Pulse { period, duty_cycle })
+  }
+
+  pub fn ramp(duration: Duration) -> Self {
+    Self::new(PatternShape::Ramp { duration })
+  }
+
+  pub fn random_walk(max_step: f64, seed: u64) -> Self {
+    Self::new(PatternShape::RandomWalk { max_step, seed })
+  }
+
+  pub fn intensity_range(mut self, from: f64, to: f64) -> Self {
+    self.intensity_range = (from, to);
+    self
+  }
+
+  /// Sets the time between steps, raised to [MIN_PATTERN_INTERVAL] if
+  /// shorter.
+  pub fn interval(mut self, interval: Duration) -> Self {
+    self.interval = interval.max(MIN_PATTERN_INTERVAL);
+    self
+  }
+
+  /// Steps of the pattern, forever. Use `take` or `take_while` to get a
+  /// finite sequence.
+  pub fn steps(&self) -> PatternSteps {
+    // The field is public, so it may not have gone through interval().
+    let mut pattern = *self;
+    pattern.interval = pattern.interval.max(MIN_PATTERN_INTERVAL);
+    PatternSteps {
+      pattern,
+      step: 0,
+      walk: match self.shape {
+        PatternShape::RandomWalk { seed, .. } => Some(RandomWalk::new(seed)),
+        _ => None,
+      },
+    }
+  }
+
+  /// VibrateCmd messages for a single feature, covering `length` from the
+  /// start of the pattern, along with when to send each.
+  pub fn vibrate_commands(
+    &self,
+    device_index: u32,
+    feature_index: u32,
+    length: Duration,
+  ) -> Vec<(Duration, VibrateCmd)> {
+    self
+      .steps()
+      .take_while(|step| step.offset < length)
+      .map(|step| {
+        (
+          step.offset,
+          VibrateCmd::new(
+            device_index,
+            vec![VibrateSubcommand::new(feature_index, step.intensity)],
+          ),
+        )
+      })
+      .collect()
+  }
+
+  fn scale(&self, level: f64) -> f64 {
+    let (from, to) = self.intensity_range;
+    (from + (to - from) * level.clamp(0.0, 1.0)).clamp(0.0, 1.0)
+  }
+}
+
+/// A speed to send, and when to send it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PatternStep {
+  /// Time from the start of the pattern.
+  pub offset: Duration,
+  /// Speed, 0.0-1.0.
+  pub intensity: f64,
+}
+
+/// Iterator over the steps of a [Pattern].
+#[derive(Debug, Clone)]
+pub struct PatternSteps {
+  pattern: Pattern,
+  step: u32,
+  walk: Option<RandomWalk>,
+}
+
+impl Iterator for PatternSteps {
+  type Item = PatternStep;
+
+  fn next(&mut self) -> Option<PatternStep> {
+    let offset = self.pattern.interval * self.step;
+    let level = match self.pattern.shape {
+      PatternShape::Sine { period } => 0.5 - 0.5 * (2.0 * PI * phase(offset, period)).cos(),
+      PatternShape::Pulse { period, duty_cycle } => {
+        if phase(offset, period) < duty_cycle {
+          1.0
+        } else {
+          0.0
+        }
+      }
+      PatternShape::Ramp { duration } => {
+        if duration.is_zero() {
+          1.0
+        } else {
+          offset.as_secs_f64() / duration.as_secs_f64()
+        }
+      }
+      PatternShape::RandomWalk { max_step, .. } => {
+        // Only None for other shapes.
+        let walk = self.walk.as_mut().unwrap();
+        if self.step > 0 {
+          walk.advance(max_step);
+        }
+        walk.level
+      }
+    };
+    self.step += 1;
+    Some(PatternStep {
+      offset,
+      intensity: self.pattern.scale(level),
+    })
+  }
+}
+
+/// How far through its period (0.0-1.0) a pattern is at `offset`. Done in
+/// whole nanoseconds, so long runs don't pick up float error.
+fn phase(offset: Duration, period: Duration) -> f64 {
+  if period.is_zero() {
+    return 0.0;
+  }
+  (offset.as_nanos() % period.as_nanos()) as f64 / period.as_nanos() as f64
+}
+
+#[derive(Debug, Clone)]
+struct RandomWalk {
+  state: u64,
+  level: f64,
+}
+
+impl RandomWalk {
+  fn new(seed: u64) -> Self {
+    Self {
+      state: seed,
+      level: 0.5,
+    }
+  }
+
+  fn advance(&mut self, max_step: f64) {
+    self.level = (self.level + max_step * (2.0 * self.next_unit() - 1.0)).clamp(0.0, 1.0);
+  }
+
+  // SplitMix64, which is plenty for wiggling a vibrator around, and doesn't
+  // need an extra dependency.
+  fn next_unit(&mut self) -> f64 {
+    self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    let mut z = self.state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^= z >> 31;
+    // Top 53 bits, as a float in 0.0-1.0.
+    (z >> 11) as f64 / (1u64 << 53) as f64
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::{Pattern, PatternStep};
+  use crate::core::messages::{VibrateCmd, VibrateSubcommand};
+  use std::time::Duration;
+
+  fn intensities(pattern: Pattern, count: usize) -> Vec<f64> {
+    pattern
+      .steps()
+      .take(count)
+      .map(|step| (step.intensity * 1000.0).round() / 1000.0)
+      .collect()
+  }
+
+  #[test]
+  fn test_pattern_shapes() {
+    assert_eq!(
+      intensities(Pattern::sine(Duration::from_millis(400)), 5),
+      vec![0.0, 0.5, 1.0, 0.5, 0.0]
+    );
+    assert_eq!(
+      intensities(Pattern::pulse(Duration::from_millis(400), 0.5), 6),
+      vec![1.0, 1.0, 0.0, 0.0, 1.0, 1.0]
+    );
+    assert_eq!(
+      intensities(
+        Pattern::ramp(Duration::from_millis(200)).intensity_range(1.0, 0.2),
+        4
+      ),
+      vec![1.0, 0.6, 0.2, 0.2]
+    );
+  }
+
+  #[test]
+  fn test_random_walk() {
+    let pattern = Pattern::random_walk(0.1, 42).intensity_range(0.2, 0.8);
+    let walk = intensities(pattern, 100);
+    assert_eq!(walk[0], 0.5);
+    assert_eq!(walk, intensities(pattern, 100));
+    assert_ne!(
+      walk,
+      intensities(Pattern::random_walk(0.1, 43).intensity_range(0.2, 0.8), 100)
+    );
+    for pair in walk.windows(2) {
+      assert!((pair[1] - pair[0]).abs() <= 0.061);
+      assert!((0.2..=0.8).contains(&pair[1]));
+    }
+  }
+
+  #[test]
+  fn test_vibrate_commands() {
+    let pattern =
+      Pattern::pulse(Duration::from_millis(100), 0.5).interval(Duration::from_millis(50));
+    let commands = pattern.vibrate_commands(3, 1, Duration::from_millis(150));
+    assert_eq!(
+      commands,
+      vec![
+        (
+          Duration::ZERO,
+          VibrateCmd::new(3, vec![VibrateSubcommand::new(1, 1.0)])
+        ),
+        (
+          Duration::from_millis(50),
+          VibrateCmd::new(3, vec![VibrateSubcommand::new(1, 0.0)])
+        ),
+        (
+          Duration::from_millis(100),
+          VibrateCmd::new(3, vec![VibrateSubcommand::new(1, 1.0)])
+        ),
+      ]
+    );
+    // Offsets come from the step count, so they don't drift.
+    assert_eq!(
+      pattern.steps().nth(1_000_000),
+      Some(PatternStep {
+        offset: Duration::from_secs(50_000),
+        intensity: 1.0,
+      })
+    );
+  }
+
+  #[test]
+  fn test_zero_interval() {
+    let pattern = Pattern::ramp(Duration::from_millis(10)).interval(Duration::ZERO);
+    assert_eq!(pattern.interval, MIN_PATTERN_INTERVAL);
+    assert_eq!(
+      pattern
+        .vibrate_commands(0, 0, Duration::from_millis(10))
+        .len(),
+      10
+    );
+    // Setting the field directly still can't stall stepping.
+    let mut pattern = Pattern::ramp(Duration::from_millis(10));
+    pattern.interval = Duration::ZERO;
+    assert_eq!(
+      pattern
+        .vibrate_commands(0, 0, Duration::from_millis(10))
+        .len(),
+      10
+    );
+  }
+}
@@ -0,0 +1,417 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2022 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Shared timers for device keepalives, output loops and ramps.
+//!
+//! Protocols that resend commands on a schedule used to each spawn a task
+//! that slept between writes, so every connected device added a task and a
+//! timer of its own. With 10+ devices on a small server, that's a lot of
+//! wakeups for very little work. A [Scheduler] runs jobs like these on a
+//! small, fixed pool of worker tasks instead. Each worker keeps its jobs in a
+//! timer wheel, and jobs that come due within the same
+//! [tick][SCHEDULER_RESOLUTION] are run on the same wakeup.
+//!
+//! A job is a closure returning a future, which resolves to when the job
+//! wants to run next, or None once it's done. Each run is driven on its own,
+//! so a job waiting on a slow write only holds up its own next run, not the
+//! other jobs on the worker. Jobs should still be short (a write or two), not
+//! long running loops.
+
+use super::{
+  async_manager,
+  clock::{Clock, SystemClock},
+};
+use futures::{
+  future::{self, BoxFuture, Future, FutureExt},
+  stream::{FuturesUnordered, StreamExt},
+};
+use once_cell::sync::Lazy;
+use std::{
+  sync::{Arc, Mutex},
+  time::{Duration, Instant},
+};
+use tokio::sync::mpsc;
+
+/// Worker tasks in the [shared][Scheduler::shared] scheduler.
+pub const DEFAULT_SCHEDULER_WORKERS: usize = 2;
+/// Width of a timer wheel slot. Jobs due within the same slot are run on the
+/// same wakeup, so they can run up to this much early.
+pub const SCHEDULER_RESOLUTION: Duration = Duration::from_millis(5);
+const WHEEL_SLOTS: u64 = 256;
+
+static SHARED_SCHEDULER: Lazy<Scheduler> =
+  Lazy::new(|| Scheduler::new(DEFAULT_SCHEDULER_WORKERS, Arc::new(SystemClock)));
+
+type JobFn = Box<dyn FnMut() -> BoxFuture<'static, Option<Duration>> + Send>;
+
+struct ScheduledJob {
+  delay: Duration,
+  run: JobFn,
+}
+
+struct SchedulerInner {
+  clock: Arc<dyn Clock>,
+  worker_count: usize,
+  workers: Mutex<Vec<mpsc::UnboundedSender<ScheduledJob>>>,
+  next_worker: Mutex<usize>,
+}
+
+/// Runs timed jobs on a fixed pool of worker tasks. Clones share the same
+/// workers.
+#[derive(Clone)]
+pub struct Scheduler {
+  inner: Arc<SchedulerInner>,
+}
+
+impl Scheduler {
+  /// Creates a scheduler with up to `worker_count` workers, keeping time with
+  /// `clock`. Workers are only started once there are jobs for them.
+  pub fn new(worker_count: usize, clock: Arc<dyn Clock>) -> Self {
+    Self {
+      inner: Arc::new(SchedulerInner {
+        clock,
+        worker_count: worker_count.max(1),
+        workers: Mutex::new(vec![]),
+        next_worker: Mutex::new(0),
+      }),
+    }
+  }
+
+  /// The scheduler devices share, unless they keep their own time.
+  pub fn shared() -> Self {
+    SHARED_SCHEDULER.clone()
+  }
+
+  pub fn clock(&self) -> Arc<dyn Clock> {
+    self.inner.clock.clone()
+  }
+
+  /// Runs `job` after `delay`. The future it returns resolves to how long to
+  /// wait before running it again, or None to stop.
+  pub fn schedule<F, Fut>(&self, delay: Duration, mut job: F)
+  where
+    F: FnMut() -> Fut + Send + 'static,
+    Fut: Future<Output = Option<Duration>> + Send + 'static,
+  {
+    let job = ScheduledJob {
+      delay,
+      run: Box::new(move || job().boxed()),
+    };
+    let mut workers = self.inner.workers.lock().unwrap();
+    let index = {
+      let mut next_worker = self.inner.next_worker.lock().unwrap();
+      let index = *next_worker % self.inner.worker_count;
+      *next_worker = next_worker.wrapping_add(1);
+      index
+    };
+    if index >= workers.len() {
+      workers.push(spawn_worker(self.inner.clock.clone()));
+    }
+    let index = index.min(workers.len() - 1);
+    if let Err(mpsc::error::SendError(job)) = workers[index].send(job) {
+      // Workers go away with the runtime they were spawned on, e.g. when a
+      // block_on call finishes, so start a new one on the current runtime.
+      debug!("Scheduler worker {} stopped, starting a new one.", index);
+      workers[index] = spawn_worker(self.inner.clock.clone());
+      if workers[index].send(job).is_err() {
+        error!(
+          "Scheduler worker {} stopped as soon as it started, dropping job.",
+          index
+        );
+      }
+    }
+  }
+}
+
+fn spawn_worker(clock: Arc<dyn Clock>) -> mpsc::UnboundedSender<ScheduledJob> {
+  let (sender, receiver) = mpsc::unbounded_channel();
+  async_manager::spawn_named("scheduler worker", run_worker(clock, receiver)).unwrap();
+  sender
+}
+
+async fn run_worker(clock: Arc<dyn Clock>, mut receiver: mpsc::UnboundedReceiver<ScheduledJob>) {
+  let mut wheel = TimerWheel::new(clock.now());
+  // Runs that haven't finished yet. Jobs go back in the wheel as their run
+  // finishes, whatever the others are doing.
+  let mut running = FuturesUnordered::new();
+  // Once every scheduler handle is gone, finish the jobs we have and exit.
+  let mut closed = false;
+  loop {
+    for mut job in wheel.expire(clock.now()) {
+      running.push(async move {
+        let next = (job.run)().await;
+        (job, next)
+      });
+    }
+    // Start the new runs before sleeping, so whatever they do right away
+    // happens on this wakeup.
+    while let Some(Some((job, next))) = running.next().now_or_never() {
+      if let Some(delay) = next {
+        wheel.insert(clock.now() + delay, job);
+      }
+    }
+    let sleep = match wheel.next_deadline() {
+      Some(deadline) => {
+        let now = clock.now();
+        if deadline <= now {
+          continue;
+        }
+        clock.sleep(deadline - now)
+      }
+      None if closed && running.is_empty() => return,
+      None => future::pending().boxed(),
+    };
+    let receiving = !closed;
+    let received = async {
+      if !receiving {
+        future::pending::<()>().await;
+      }
+      receiver.recv().await
+    };
+    let finished = async {
+      if running.is_empty() {
+        future::pending::<()>().await;
+      }
+      running.next().await
+    };
+    select! {
+      job = received.fuse() => match job {
+        Some(job) => wheel.insert(clock.now() + job.delay, job),
+        None => closed = true,
+      },
+      ran = finished.fuse() => {
+        if let Some((job, Some(delay))) = ran {
+          wheel.insert(clock.now() + delay, job);
+        }
+      }
+      _ = sleep.fuse() => {}
+    }
+  }
+}
+
+struct WheelEntry<T> {
+  deadline: Instant,
+  tick: u64,
+  item: T,
+}
+
+/// Single level timer wheel. Entries are bucketed by the tick their deadline
+/// falls in, so finding what's due only looks at the slots between the last
+/// check and now, however many entries there are.
+struct TimerWheel<T> {
+  start: Instant,
+  slots: Vec<Vec<WheelEntry<T>>>,
+  // No entries are due before this tick.
+  cursor: u64,
+  len: usize,
+}
+
+impl<T> TimerWheel<T> {
+  fn new(start: Instant) -> Self {
+    Self {
+      start,
+      slots: (0..WHEEL_SLOTS).map(|_| vec![]).collect(),
+      cursor: 0,
+      len: 0,
+    }
+  }
+
+  fn tick(&self, instant: Instant) -> u64 {
+    (instant.saturating_duration_since(self.start).as_nanos() / SCHEDULER_RESOLUTION.as_nanos())
+      as u64
+  }
+
+  fn insert(&mut self, deadline: Instant, item: T) {
+    // Anything already overdue goes in the current slot.
+    let tick = self.tick(deadline).max(self.cursor);
+    self.slots[(tick % WHEEL_SLOTS) as usize].push(WheelEntry {
+      deadline,
+      tick,
+      item,
+    });
+    self.len += 1;
+  }
+
+  /// Earliest deadline in the wheel.
+  fn next_deadline(&self) -> Option<Instant> {
+    if self.len == 0 {
+      return None;
+    }
+    for tick in self.cursor..self.cursor + WHEEL_SLOTS {
+      let deadline = self.slots[(tick % WHEEL_SLOTS) as usize]
+        .iter()
+        .filter(|entry| entry.tick == tick)
+        .map(|entry| entry.deadline)
+        .min();
+      if deadline.is_some() {
+        return deadline;
+      }
+    }
+    // Everything's at least a full turn of the wheel away.
+    self
+      .slots
+      .iter()
+      .flatten()
+      .map(|entry| entry.deadline)
+      .min()
+  }
+
+  /// Removes and returns everything due in or before the tick `now` is in.
+  fn expire(&mut self, now: Instant) -> Vec<T> {
+    let now_tick = self.tick(now);
+    if now_tick < self.cursor {
+      return vec![];
+    }
+    let mut due = vec![];
+    let slots = (now_tick - self.cursor + 1).min(WHEEL_SLOTS);
+    for tick in self.cursor..self.cursor + slots {
+      let slot = &mut self.slots[(tick % WHEEL_SLOTS) as usize];
+      let (ready, waiting): (Vec<_>, Vec<_>) =
+        slot.drain(..).partition(|entry| entry.tick <= now_tick);
+      *slot = waiting;
+      due.extend(ready.into_iter().map(|entry| entry.item));
+    }
+    self.len -= due.len();
+    self.cursor = now_tick;
+    due
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::{Scheduler, TimerWheel, SCHEDULER_RESOLUTION};
+  use crate::util::{async_manager, clock::ManualClock};
+  use futures::{channel::mpsc, FutureExt, StreamExt};
+  use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+  };
+
+  fn collect_now<T>(receiver: &mut mpsc::UnboundedReceiver<T>) -> Vec<T> {
+    let mut items = vec![];
+    while let Some(Some(item)) = receiver.next().now_or_never() {
+      items.push(item);
+    }
+    items
+  }
+
+  #[test]
+  fn test_timer_wheel() {
+    let start = Instant::now();
+    let mut wheel = TimerWheel::new(start);
+    assert_eq!(wheel.next_deadline(), None);
+    wheel.insert(start + Duration::from_millis(12), "soon");
+    wheel.insert(start + Duration::from_millis(13), "same tick");
+    wheel.insert(start + Duration::from_secs(10), "next turn");
+    assert_eq!(
+      wheel.next_deadline(),
+      Some(start + Duration::from_millis(12))
+    );
+    assert!(wheel.expire(start + Duration::from_millis(5)).is_empty());
+    // Everything in the tick goes together.
+    let mut due = wheel.expire(start + Duration::from_millis(12));
+    due.sort();
+    assert_eq!(due, vec!["same tick", "soon"]);
+    // Entries more than a turn out aren't picked up early when their slot
+    // comes around.
+    assert_eq!(wheel.next_deadline(), Some(start + Duration::from_secs(10)));
+    assert!(wheel.expire(start + SCHEDULER_RESOLUTION * 256).is_empty());
+    assert_eq!(
+      wheel.expire(start + Duration::from_secs(11)),
+      vec!["next turn"]
+    );
+    assert_eq!(wheel.next_deadline(), None);
+  }
+
+  #[test]
+  fn test_scheduler_repeating_jobs() {
+    async_manager::block_on(async {
+      let clock = ManualClock::new();
+      let scheduler = Scheduler::new(1, Arc::new(clock.clone()));
+      let (sender, mut receiver) = mpsc::unbounded();
+      let schedule = |name: &'static str, interval: u64| {
+        let sender = sender.clone();
+        let mut runs = 0;
+        scheduler.schedule(Duration::ZERO, move || {
+          runs += 1;
+          let _ = sender.unbounded_send(name);
+          let next = if runs < 3 {
+            Some(Duration::from_millis(interval))
+          } else {
+            None
+          };
+          async move { next }
+        });
+      };
+      // Each job runs as soon as it's scheduled, and the worker then sleeps
+      // until whichever is due first.
+      schedule("slow", 30);
+      clock.wait_for_sleeps(1).await;
+      schedule("fast", 10);
+      clock.wait_for_sleeps(2).await;
+      assert_eq!(collect_now(&mut receiver), vec!["slow", "fast"]);
+      clock.advance(Duration::from_millis(10));
+      clock.wait_for_sleeps(3).await;
+      assert_eq!(collect_now(&mut receiver), vec!["fast"]);
+      // Both are due by now, so they share a wakeup.
+      clock.advance(Duration::from_millis(20));
+      clock.wait_for_sleeps(4).await;
+      let mut both = collect_now(&mut receiver);
+      both.sort_unstable();
+      assert_eq!(both, vec!["fast", "slow"]);
+      // Fast is done, and slow has one run left, after which the worker has
+      // nothing to sleep for.
+      clock.advance(Duration::from_millis(30));
+      assert_eq!(receiver.next().await, Some("slow"));
+      assert_eq!(clock.sleeps_started(), 4);
+    });
+  }
+
+  #[test]
+  fn test_scheduler_slow_job_runs_independently() {
+    async_manager::block_on(async {
+      let clock = ManualClock::new();
+      let scheduler = Scheduler::new(1, Arc::new(clock.clone()));
+      let (sender, mut receiver) = mpsc::unbounded();
+      // Stands in for a job stuck on a slow write.
+      let slow_sender = sender.clone();
+      let slow_clock = clock.clone();
+      scheduler.schedule(Duration::ZERO, move || {
+        let _ = slow_sender.unbounded_send("slow");
+        let sender = slow_sender.clone();
+        let sleep = slow_clock.sleep(Duration::from_millis(100));
+        async move {
+          sleep.await;
+          let _ = sender.unbounded_send("slow done");
+          None
+        }
+      });
+      clock.wait_for_sleeps(1).await;
+      let mut runs = 0;
+      scheduler.schedule(Duration::ZERO, move || {
+        runs += 1;
+        let _ = sender.unbounded_send("fast");
+        let next = if runs < 3 {
+          Some(Duration::from_millis(10))
+        } else {
+          None
+        };
+        async move { next }
+      });
+      clock.wait_for_sleeps(2).await;
+      assert_eq!(collect_now(&mut receiver), vec!["slow", "fast"]);
+      // The fast job keeps its schedule while the slow one is still waiting.
+      clock.advance(Duration::from_millis(10));
+      clock.wait_for_sleeps(3).await;
+      assert_eq!(collect_now(&mut receiver), vec!["fast"]);
+      clock.advance(Duration::from_millis(10));
+      assert_eq!(receiver.next().await, Some("fast"));
+      clock.advance(Duration::from_millis(80));
+      assert_eq!(receiver.next().await, Some("slow done"));
+    });
+  }
+}
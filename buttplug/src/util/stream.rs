@@ -16,6 +16,44 @@ where
   }
 }
 
+/// Like [convert_broadcast_receiver_to_stream], but only yields the values
+/// `filter` maps to Some. Receivers that fall behind skip what they missed,
+/// instead of ending the stream.
+pub fn convert_broadcast_receiver_to_filtered_stream<T, U, F>(
+  receiver: broadcast::Receiver<T>,
+  mut filter: F,
+) -> impl Stream<Item = U>
+where
+  T: Unpin + Clone,
+  F: FnMut(T) -> Option<U>,
+{
+  stream! {
+    pin_mut!(receiver);
+    loop {
+      match receiver.recv().await {
+        Ok(val) => {
+          if let Some(val) = filter(val) {
+            yield val;
+          }
+        }
+        Err(broadcast::error::RecvError::Lagged(skipped)) => {
+          warn!("Stream fell behind, skipping {} values.", skipped);
+        }
+        Err(broadcast::error::RecvError::Closed) => break,
+      }
+    }
+  }
+}
+
+pub fn convert_mpsc_receiver_to_stream<T>(receiver: mpsc::Receiver<T>) -> impl Stream<Item = T> {
+  stream! {
+    pin_mut!(receiver);
+    while let Some(val) = receiver.recv().await {
+      yield val;
+    }
+  }
+}
+
 pub fn recv_now<T>(receiver: &mut mpsc::Receiver<T>) -> Option<Option<T>> {
   receiver.recv().now_or_never()
 }
@@ -0,0 +1,59 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2022 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Utilities for testing protocols and applications without hardware.
+//!
+//! This is the supported way to use the library's test utilities from other
+//! crates. Everything here follows the library's semver guarantees, unlike
+//! the internal [test][crate::test] module it's built on, which can change
+//! in any release.
+//!
+//! - Simulated devices: [new_bluetoothle_test_device] connects a device by
+//!   its Bluetooth LE name, matched against the device configuration like a
+//!   real device. [TestDeviceInternal] records what's written to each
+//!   endpoint, and can send notifications back.
+//! - Assertions: [check_test_recv_value] and [check_test_recv_empty] check
+//!   what a simulated device was sent.
+//! - Timeline control: devices created with
+//!   [new_bluetoothle_test_device_with_clock] keep time with a [ManualClock],
+//!   so tests can step through protocols that send commands on a timer
+//!   instead of sleeping.
+//! - Faults: [TestFaultConfig] makes simulated devices misbehave, for
+//!   testing error handling.
+//!
+//! ```no_run
+//! use buttplug::{
+//!   core::messages::{VibrateCmd, VibrateSubcommand},
+//!   device::{DeviceImplCommand, DeviceWriteCmd, Endpoint},
+//!   util::{async_manager, testing::*},
+//! };
+//!
+//! async_manager::block_on(async {
+//!   let (device, test_device) = new_bluetoothle_test_device("Massage Demo").await.unwrap();
+//!   let command_receiver = test_device.get_endpoint_receiver(&Endpoint::Tx).unwrap();
+//!   device
+//!     .parse_message(VibrateCmd::new(0, vec![VibrateSubcommand::new(0, 0.5)]).into())
+//!     .await
+//!     .unwrap();
+//!   check_test_recv_value(
+//!     &command_receiver,
+//!     DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![0xF1, 64], false)),
+//!   );
+//!   assert!(check_test_recv_empty(&command_receiver));
+//! });
+//! ```
+
+pub use super::clock::{Clock, ManualClock, SystemClock};
+pub use crate::test::{
+  check_test_recv_empty, check_test_recv_value, TestDeviceImplCreator, TestDeviceInternal,
+  TestFaultConfig,
+};
+#[cfg(feature = "server")]
+pub use crate::test::{
+  new_bluetoothle_test_device, new_bluetoothle_test_device_with_clock,
+  TestDeviceCommunicationManager, TestDeviceCommunicationManagerHelper,
+};
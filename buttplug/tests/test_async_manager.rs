@@ -0,0 +1,76 @@
+// set_runtime can only be called once per process, so this lives in its own
+// test binary.
+
+#[cfg(all(feature = "tokio-runtime", not(feature = "async-std-runtime")))]
+mod async_manager_tests {
+  use buttplug::util::async_manager::{self, AsyncRuntime};
+  use futures::{
+    channel::oneshot,
+    future::{BoxFuture, FutureExt},
+    task::SpawnError,
+  };
+  use std::{
+    sync::{
+      atomic::{AtomicUsize, Ordering},
+      Arc,
+    },
+    time::Duration,
+  };
+
+  /// Hands everything to tokio, counting what it was asked to do.
+  #[derive(Default)]
+  struct CountingRuntime {
+    spawns: AtomicUsize,
+    blocking_calls: AtomicUsize,
+    sleeps: AtomicUsize,
+  }
+
+  impl AsyncRuntime for CountingRuntime {
+    fn spawn(&self, future: BoxFuture<'static, ()>) -> Result<(), SpawnError> {
+      self.spawns.fetch_add(1, Ordering::SeqCst);
+      tokio::spawn(future);
+      Ok(())
+    }
+
+    fn spawn_blocking(
+      &self,
+      func: Box<dyn FnOnce() + Send + 'static>,
+    ) -> Result<BoxFuture<'static, ()>, SpawnError> {
+      self.blocking_calls.fetch_add(1, Ordering::SeqCst);
+      let handle = tokio::task::spawn_blocking(func);
+      Ok(async move {
+        let _ = handle.await;
+      }
+      .boxed())
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+      self.sleeps.fetch_add(1, Ordering::SeqCst);
+      futures_timer::Delay::new(duration).boxed()
+    }
+  }
+
+  #[test]
+  fn test_set_runtime() {
+    async_manager::block_on(async {
+      let runtime = Arc::new(CountingRuntime::default());
+      assert!(async_manager::set_runtime(runtime.clone()).is_ok());
+
+      let (sender, receiver) = oneshot::channel();
+      async_manager::spawn(async move {
+        sender.send(1).unwrap();
+      })
+      .unwrap();
+      assert_eq!(receiver.await.unwrap(), 1);
+      assert_eq!(async_manager::spawn_blocking(|| 2).await.unwrap(), 2);
+      async_manager::sleep(Duration::from_millis(1)).await;
+
+      assert_eq!(runtime.spawns.load(Ordering::SeqCst), 1);
+      assert_eq!(runtime.blocking_calls.load(Ordering::SeqCst), 1);
+      assert_eq!(runtime.sleeps.load(Ordering::SeqCst), 1);
+
+      // Only the first runtime sticks.
+      assert!(async_manager::set_runtime(Arc::new(CountingRuntime::default())).is_err());
+    });
+  }
+}
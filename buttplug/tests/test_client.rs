@@ -5,20 +5,34 @@ use buttplug::{
   client::{ButtplugClient, ButtplugClientError, ButtplugClientEvent, VibrateCommand},
   connector::{
     ButtplugConnector, ButtplugConnectorError, ButtplugConnectorResultFuture,
-    ButtplugInProcessClientConnector,
+    ButtplugInProcessClientConnector, ButtplugReconnectOptions,
   },
   core::{
     errors::{ButtplugDeviceError, ButtplugError},
-    messages::{ButtplugCurrentSpecClientMessage, ButtplugCurrentSpecServerMessage},
+    messages::{
+      ButtplugCurrentSpecClientMessage, ButtplugCurrentSpecServerMessage,
+      ButtplugDeviceMessageType, ButtplugMessage, DeviceList, DeviceMessageAttributes,
+      DeviceMessageInfo, ServerInfo, BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION,
+    },
   },
   device::{DeviceImplCommand, DeviceWriteCmd, Endpoint},
   server::ButtplugServerOptions,
   test::check_test_recv_value,
   util::async_manager,
 };
-use futures::{future::BoxFuture, StreamExt};
+use futures::{
+  future::{self, BoxFuture},
+  StreamExt,
+};
 use futures_timer::Delay;
-use std::time::Duration;
+use std::{
+  collections::HashMap,
+  sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, Mutex,
+  },
+  time::Duration,
+};
 use tokio::sync::mpsc::Sender;
 use util::DelayDeviceCommunicationManagerBuilder;
 
@@ -44,6 +58,61 @@ impl ButtplugConnector<ButtplugCurrentSpecClientMessage, ButtplugCurrentSpecServ
   }
 }
 
+/// Answers the handshake with a single device, and lets the test drop the
+/// connection from the server side by taking the sender out of
+/// `server_sender`. While `hang_on_connect` is set, connecting never
+/// finishes.
+struct ButtplugDroppableConnector {
+  server_sender: Arc<Mutex<Option<Sender<ButtplugCurrentSpecServerMessage>>>>,
+  hang_on_connect: Arc<AtomicBool>,
+}
+
+impl ButtplugConnector<ButtplugCurrentSpecClientMessage, ButtplugCurrentSpecServerMessage>
+  for ButtplugDroppableConnector
+{
+  fn connect(
+    &mut self,
+    message_sender: Sender<ButtplugCurrentSpecServerMessage>,
+  ) -> BoxFuture<'static, Result<(), ButtplugConnectorError>> {
+    if self.hang_on_connect.load(Ordering::SeqCst) {
+      return Box::pin(future::pending());
+    }
+    *self.server_sender.lock().unwrap() = Some(message_sender);
+    Box::pin(future::ready(Ok(())))
+  }
+
+  fn disconnect(&self) -> ButtplugConnectorResultFuture {
+    self.server_sender.lock().unwrap().take();
+    Box::pin(future::ready(Ok(())))
+  }
+
+  fn send(&self, msg: ButtplugCurrentSpecClientMessage) -> ButtplugConnectorResultFuture {
+    let mut reply: ButtplugCurrentSpecServerMessage = match &msg {
+      ButtplugCurrentSpecClientMessage::RequestServerInfo(..) => {
+        ServerInfo::new("Test Server", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION, 0).into()
+      }
+      ButtplugCurrentSpecClientMessage::RequestDeviceList(..) => {
+        let mut messages = HashMap::new();
+        messages.insert(
+          ButtplugDeviceMessageType::StopDeviceCmd,
+          DeviceMessageAttributes::default(),
+        );
+        DeviceList::new(vec![DeviceMessageInfo::new(0, "Test Device", messages)]).into()
+      }
+      _ => panic!("Should never be called"),
+    };
+    reply.set_id(msg.id());
+    let sender = self.server_sender.lock().unwrap().clone();
+    Box::pin(async move {
+      sender
+        .ok_or(ButtplugConnectorError::ConnectorNotConnected)?
+        .send(reply)
+        .await
+        .map_err(|_| ButtplugConnectorError::ConnectorChannelClosed)
+    })
+  }
+}
+
 #[cfg(feature = "server")]
 #[test]
 fn test_failing_connection() {
@@ -257,6 +326,111 @@ fn test_stop_all_devices_and_device_command_range() {
   });
 }
 
+#[cfg(feature = "server")]
+#[test]
+fn test_client_reconnect() {
+  async_manager::block_on(async {
+    let server_sender = Arc::new(Mutex::new(None));
+    let factory_sender = server_sender.clone();
+    let client = ButtplugClient::new("Test Client");
+    let mut event_stream = client.event_stream();
+    let options = ButtplugReconnectOptions {
+      initial_delay: Duration::from_millis(10),
+      ..Default::default()
+    };
+    client
+      .connect_with_reconnect(
+        move || ButtplugDroppableConnector {
+          server_sender: factory_sender.clone(),
+          hang_on_connect: Arc::new(AtomicBool::new(false)),
+        },
+        options,
+      )
+      .await
+      .unwrap();
+    assert!(matches!(
+      event_stream.next().await.unwrap(),
+      ButtplugClientEvent::DeviceAdded(..)
+    ));
+    server_sender.lock().unwrap().take();
+    assert!(matches!(
+      event_stream.next().await.unwrap(),
+      ButtplugClientEvent::Reconnecting
+    ));
+    if let ButtplugClientEvent::Reconnected(devices) = event_stream.next().await.unwrap() {
+      assert_eq!(devices.len(), 1);
+      assert_eq!(devices[0].index(), 0);
+    } else {
+      panic!("Expected a Reconnected event");
+    }
+    assert!(client.connected());
+    assert_eq!(client.devices().len(), 1);
+    client.disconnect().await.unwrap();
+    assert!(!client.connected());
+  });
+}
+
+#[cfg(feature = "server")]
+#[test]
+fn test_client_disconnect_while_reconnecting() {
+  async_manager::block_on(async {
+    let server_sender = Arc::new(Mutex::new(None));
+    let factory_sender = server_sender.clone();
+    let hang_on_connect = Arc::new(AtomicBool::new(false));
+    let factory_hang = hang_on_connect.clone();
+    let client = ButtplugClient::new("Test Client");
+    let mut event_stream = client.event_stream();
+    let options = ButtplugReconnectOptions {
+      initial_delay: Duration::from_millis(10),
+      ..Default::default()
+    };
+    client
+      .connect_with_reconnect(
+        move || ButtplugDroppableConnector {
+          server_sender: factory_sender.clone(),
+          hang_on_connect: factory_hang.clone(),
+        },
+        options,
+      )
+      .await
+      .unwrap();
+    assert!(matches!(
+      event_stream.next().await.unwrap(),
+      ButtplugClientEvent::DeviceAdded(..)
+    ));
+    // Reconnect attempts get stuck connecting, and disconnecting has to stop
+    // them.
+    hang_on_connect.store(true, Ordering::SeqCst);
+    server_sender.lock().unwrap().take();
+    assert!(matches!(
+      event_stream.next().await.unwrap(),
+      ButtplugClientEvent::Reconnecting
+    ));
+    Delay::new(Duration::from_millis(50)).await;
+    client.disconnect().await.unwrap();
+    assert!(matches!(
+      event_stream.next().await.unwrap(),
+      ButtplugClientEvent::DeviceRemoved(..)
+    ));
+    assert!(matches!(
+      event_stream.next().await.unwrap(),
+      ButtplugClientEvent::ServerDisconnect
+    ));
+    assert!(!client.connected());
+    assert!(client.devices().is_empty());
+    // Nothing's left reconnecting, so the client can connect again.
+    hang_on_connect.store(false, Ordering::SeqCst);
+    client
+      .connect(ButtplugDroppableConnector {
+        server_sender: server_sender.clone(),
+        hang_on_connect,
+      })
+      .await
+      .unwrap();
+    assert!(client.connected());
+  });
+}
+
 // TODO Test calling connect twice
 // TODO Test calling disconnect twice w/o connection
 // TODO Test invalid return on RequestServerInfo
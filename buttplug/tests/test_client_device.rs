@@ -1,14 +1,20 @@
 mod util;
 use buttplug::{
   client::{
-    ButtplugClient, ButtplugClientDeviceEvent, ButtplugClientError, ButtplugClientEvent,
-    VibrateCommand,
+    ButtplugClient, ButtplugClientDeviceEvent, ButtplugClientDeviceThrottle, ButtplugClientError,
+    ButtplugClientEvent, ButtplugClientFirmwareUpdater, FirmwareUpdateEvent, VibrateCommand,
   },
   connector::ButtplugInProcessClientConnector,
   core::{
     errors::{ButtplugDeviceError, ButtplugError, ButtplugMessageError},
-    messages::{self, ButtplugClientMessage},
+    messages::{
+      self, ButtplugClientMessage, ButtplugDeviceMessageType, ButtplugMessage,
+      DeviceMessageAttributes,
+    },
   },
+  device::{DeviceImplCommand, DeviceWriteCmd, Endpoint},
+  server::ButtplugServerOptions,
+  test::{check_test_recv_empty, check_test_recv_value},
   util::async_manager,
 };
 use futures::{pin_mut, StreamExt};
@@ -245,6 +251,390 @@ fn test_client_repeated_deviceremoved_message() {
   });
 }
 
+#[cfg(feature = "server")]
+#[test]
+fn test_client_device_throttle() {
+  async_manager::block_on(async {
+    let client = ButtplugClient::new("Test Client");
+    let mut event_stream = client.event_stream();
+    let connector = ButtplugInProcessClientConnector::default();
+    let helper = connector.server_ref().add_test_comm_manager().unwrap();
+    let device = helper.add_ble_device("Massage Demo").await;
+    client.connect(connector).await.unwrap();
+    client.start_scanning().await.unwrap();
+    let mut client_device = None;
+    while let Some(msg) = event_stream.next().await {
+      if let ButtplugClientEvent::DeviceAdded(da) = msg {
+        client_device = Some(da);
+        break;
+      }
+    }
+    let throttle =
+      ButtplugClientDeviceThrottle::new(client_device.unwrap(), Duration::from_millis(200));
+    throttle.vibrate(VibrateCommand::Speed(0.5)).await.unwrap();
+    let command_receiver = device.get_endpoint_receiver(&Endpoint::Tx).unwrap();
+    check_test_recv_value(
+      &command_receiver,
+      DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![0xF1, 64], false)),
+    );
+    check_test_recv_value(
+      &command_receiver,
+      DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![0xF2, 64], false)),
+    );
+    // Both of these come in before the interval is up, so only the last one
+    // should make it to the device.
+    throttle.vibrate(VibrateCommand::Speed(0.6)).await.unwrap();
+    throttle.vibrate(VibrateCommand::Speed(1.0)).await.unwrap();
+    assert!(check_test_recv_empty(&command_receiver));
+    Delay::new(Duration::from_millis(400)).await;
+    check_test_recv_value(
+      &command_receiver,
+      DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![0xF1, 127], false)),
+    );
+    check_test_recv_value(
+      &command_receiver,
+      DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![0xF2, 127], false)),
+    );
+    assert!(check_test_recv_empty(&command_receiver));
+
+    // Stopping through the throttle drops the command waiting on the interval.
+    Delay::new(Duration::from_millis(400)).await;
+    throttle.vibrate(VibrateCommand::Speed(0.5)).await.unwrap();
+    check_test_recv_value(
+      &command_receiver,
+      DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![0xF1, 64], false)),
+    );
+    check_test_recv_value(
+      &command_receiver,
+      DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![0xF2, 64], false)),
+    );
+    throttle.vibrate(VibrateCommand::Speed(1.0)).await.unwrap();
+    throttle.stop().await.unwrap();
+    check_test_recv_value(
+      &command_receiver,
+      DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![0xF1, 0], false)),
+    );
+    check_test_recv_value(
+      &command_receiver,
+      DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![0xF2, 0], false)),
+    );
+    Delay::new(Duration::from_millis(400)).await;
+    assert!(check_test_recv_empty(&command_receiver));
+
+    // Stopping around the throttle still makes it forget what it last sent.
+    throttle.vibrate(VibrateCommand::Speed(0.5)).await.unwrap();
+    check_test_recv_value(
+      &command_receiver,
+      DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![0xF1, 64], false)),
+    );
+    check_test_recv_value(
+      &command_receiver,
+      DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![0xF2, 64], false)),
+    );
+    client.stop_all_devices().await.unwrap();
+    check_test_recv_value(
+      &command_receiver,
+      DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![0xF1, 0], false)),
+    );
+    check_test_recv_value(
+      &command_receiver,
+      DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![0xF2, 0], false)),
+    );
+    Delay::new(Duration::from_millis(400)).await;
+    throttle.vibrate(VibrateCommand::Speed(0.5)).await.unwrap();
+    check_test_recv_value(
+      &command_receiver,
+      DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![0xF1, 64], false)),
+    );
+    check_test_recv_value(
+      &command_receiver,
+      DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![0xF2, 64], false)),
+    );
+  });
+}
+
+#[cfg(feature = "server")]
+#[test]
+fn test_client_device_raw_messages() {
+  async_manager::block_on(async {
+    let mut options = ButtplugServerOptions::default();
+    options.allow_raw_messages = true;
+    let client = ButtplugClient::new("Test Client");
+    let mut event_stream = client.event_stream();
+    let connector = ButtplugInProcessClientConnector::new_with_options(&options).unwrap();
+    let helper = connector.server_ref().add_test_comm_manager().unwrap();
+    let device = helper.add_ble_device("Massage Demo").await;
+    client.connect(connector).await.unwrap();
+    client.start_scanning().await.unwrap();
+    let mut client_device = None;
+    while let Some(msg) = event_stream.next().await {
+      if let ButtplugClientEvent::DeviceAdded(da) = msg {
+        client_device = Some(da);
+        break;
+      }
+    }
+    let client_device = client_device.unwrap();
+    assert!(client_device.raw_messages_allowed());
+    assert!(client_device.raw_endpoints().contains(&Endpoint::Tx));
+    assert!(matches!(
+      client_device
+        .raw_write(Endpoint::Firmware, vec![0x01], false)
+        .await,
+      Err(ButtplugClientError::ButtplugError(
+        ButtplugError::ButtplugDeviceError(ButtplugDeviceError::InvalidEndpoint(Endpoint::Firmware))
+      ))
+    ));
+    client_device
+      .raw_write(Endpoint::Tx, vec![0x01, 0x02], false)
+      .await
+      .unwrap();
+    let command_receiver = device.get_endpoint_receiver(&Endpoint::Tx).unwrap();
+    check_test_recv_value(
+      &command_receiver,
+      DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![0x01, 0x02], false)),
+    );
+  });
+}
+
+#[cfg(feature = "server")]
+#[test]
+fn test_client_device_firmware_update() {
+  async_manager::block_on(async {
+    let mut options = ButtplugServerOptions::default();
+    options.allow_raw_messages = true;
+    let client = ButtplugClient::new("Test Client");
+    let mut event_stream = client.event_stream();
+    let connector = ButtplugInProcessClientConnector::new_with_options(&options).unwrap();
+    let helper = connector.server_ref().add_test_comm_manager().unwrap();
+    let device = helper.add_ble_device("Massage Demo").await;
+    client.connect(connector).await.unwrap();
+    client.start_scanning().await.unwrap();
+    let mut client_device = None;
+    while let Some(msg) = event_stream.next().await {
+      if let ButtplugClientEvent::DeviceAdded(da) = msg {
+        client_device = Some(da);
+        break;
+      }
+    }
+    let client_device = client_device.unwrap();
+    let command_receiver = device.get_endpoint_receiver(&Endpoint::Tx).unwrap();
+    // Endpoints are checked before anything is written.
+    assert!(matches!(
+      ButtplugClientFirmwareUpdater::new(client_device.clone(), Endpoint::Firmware)
+        .update(&[0x01])
+        .await,
+      Err(ButtplugClientError::ButtplugError(
+        ButtplugError::ButtplugDeviceError(ButtplugDeviceError::InvalidEndpoint(Endpoint::Firmware))
+      ))
+    ));
+    let updater =
+      ButtplugClientFirmwareUpdater::new(client_device.clone(), Endpoint::Tx).chunk_size(2);
+    let update_events = updater.event_stream();
+    pin_mut!(update_events);
+    updater.update(&[0x01, 0x02, 0x03, 0x04, 0x05]).await.unwrap();
+    // Only the last chunk is written with response.
+    check_test_recv_value(
+      &command_receiver,
+      DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![0x01, 0x02], false)),
+    );
+    check_test_recv_value(
+      &command_receiver,
+      DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![0x03, 0x04], false)),
+    );
+    check_test_recv_value(
+      &command_receiver,
+      DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![0x05], true)),
+    );
+    assert_eq!(
+      update_events.next().await.unwrap(),
+      FirmwareUpdateEvent::Started {
+        total_bytes: 5,
+        total_chunks: 3
+      }
+    );
+    for (chunk, bytes_written) in [(0, 2), (1, 4), (2, 5)] {
+      assert_eq!(
+        update_events.next().await.unwrap(),
+        FirmwareUpdateEvent::ChunkWritten {
+          chunk,
+          bytes_written
+        }
+      );
+    }
+    assert_eq!(
+      update_events.next().await.unwrap(),
+      FirmwareUpdateEvent::Finished
+    );
+    // The test device reads back nothing, so verification fails on the first
+    // chunk, and nothing past it is written.
+    let updater = ButtplugClientFirmwareUpdater::new(client_device, Endpoint::Tx)
+      .chunk_size(2)
+      .verify_with(Endpoint::Tx, 100, |_, written, read| {
+        if written == read {
+          Ok(())
+        } else {
+          Err("Read back doesn't match".to_owned())
+        }
+      });
+    let update_events = updater.event_stream();
+    pin_mut!(update_events);
+    assert!(matches!(
+      updater.update(&[0x01, 0x02, 0x03]).await,
+      Err(ButtplugClientError::ButtplugError(
+        ButtplugError::ButtplugDeviceError(ButtplugDeviceError::FirmwareUpdateError(..))
+      ))
+    ));
+    check_test_recv_value(
+      &command_receiver,
+      DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![0x01, 0x02], true)),
+    );
+    assert!(check_test_recv_empty(&command_receiver));
+    let mut last_event = None;
+    while let Some(event) = update_events.next().await {
+      let failed = matches!(event, FirmwareUpdateEvent::Failed { .. });
+      last_event = Some(event);
+      if failed {
+        break;
+      }
+    }
+    assert!(matches!(
+      last_event,
+      Some(FirmwareUpdateEvent::Failed { chunk: 0, .. })
+    ));
+  });
+}
+
+#[cfg(feature = "server")]
+#[test]
+fn test_client_device_raw_messages_not_allowed() {
+  async_manager::block_on(async {
+    let client = ButtplugClient::new("Test Client");
+    let mut event_stream = client.event_stream();
+    let connector = ButtplugInProcessClientConnector::default();
+    let helper = connector.server_ref().add_test_comm_manager().unwrap();
+    let _ = helper.add_ble_device("Massage Demo").await;
+    client.connect(connector).await.unwrap();
+    client.start_scanning().await.unwrap();
+    let mut client_device = None;
+    while let Some(msg) = event_stream.next().await {
+      if let ButtplugClientEvent::DeviceAdded(da) = msg {
+        client_device = Some(da);
+        break;
+      }
+    }
+    let client_device = client_device.unwrap();
+    assert!(!client_device.raw_messages_allowed());
+    assert!(client_device.raw_endpoints().is_empty());
+    assert!(matches!(
+      client_device.raw_write(Endpoint::Tx, vec![0x01], false).await,
+      Err(ButtplugClientError::ButtplugError(
+        ButtplugError::ButtplugDeviceError(ButtplugDeviceError::RawMessagesNotAllowed(..))
+      ))
+    ));
+  });
+}
+
+#[cfg(feature = "server")]
+#[test]
+fn test_client_device_cached_battery_level() {
+  async_manager::block_on(async move {
+    let helper = Arc::new(util::ChannelClientTestHelper::new());
+    helper.simulate_successful_connect().await;
+    let helper_clone = helper.clone();
+    let mut event_stream = helper.client().event_stream();
+    async_manager::spawn(async move {
+      assert!(matches!(
+        helper_clone.get_next_client_message().await,
+        ButtplugClientMessage::StartScanning(..)
+      ));
+      helper_clone
+        .send_client_incoming(messages::Ok::new(3).into())
+        .await;
+      let mut device_messages = HashMap::new();
+      device_messages.insert(
+        ButtplugDeviceMessageType::BatteryLevelCmd,
+        DeviceMessageAttributes::default(),
+      );
+      let device_added = messages::DeviceAdded::new(1, "Test Device", &device_messages);
+      helper_clone.send_client_incoming(device_added.into()).await;
+      // Only answer one battery request. If the cached read goes to the
+      // server, it'll never get a reply.
+      let battery_cmd = helper_clone.get_next_client_message().await;
+      assert!(matches!(
+        battery_cmd,
+        ButtplugClientMessage::BatteryLevelCmd(..)
+      ));
+      let mut reading = messages::BatteryLevelReading::new(1, 0.5);
+      reading.set_id(battery_cmd.id());
+      helper_clone.send_client_incoming(reading.into()).await;
+    })
+    .unwrap();
+    helper.client().start_scanning().await.unwrap();
+    let device = match event_stream.next().await.unwrap() {
+      ButtplugClientEvent::DeviceAdded(device) => device,
+      event => panic!("Expected DeviceAdded, got {:?}", event),
+    };
+    assert_eq!(device.last_battery_level(), None);
+    assert_eq!(device.battery_level().await.unwrap(), 0.5);
+    assert_eq!(device.last_battery_level(), Some(0.5));
+    assert_eq!(
+      device
+        .cached_battery_level(Duration::from_secs(60))
+        .await
+        .unwrap(),
+      0.5
+    );
+  });
+}
+
+#[cfg(feature = "server")]
+#[test]
+fn test_client_device_message_stream() {
+  async_manager::block_on(async move {
+    let helper = Arc::new(util::ChannelClientTestHelper::new());
+    helper.simulate_successful_connect().await;
+    let helper_clone = helper.clone();
+    let mut event_stream = helper.client().event_stream();
+    async_manager::spawn(async move {
+      assert!(matches!(
+        helper_clone.get_next_client_message().await,
+        ButtplugClientMessage::StartScanning(..)
+      ));
+      helper_clone
+        .send_client_incoming(messages::Ok::new(3).into())
+        .await;
+    })
+    .unwrap();
+    helper.client().start_scanning().await.unwrap();
+    let device_added = messages::DeviceAdded::new(1, "Test Device", &HashMap::new());
+    helper.send_client_incoming(device_added.into()).await;
+    let device = match event_stream.next().await.unwrap() {
+      ButtplugClientEvent::DeviceAdded(device) => device,
+      event => panic!("Expected DeviceAdded, got {:?}", event),
+    };
+    let mut device_events = device.event_stream();
+    let mut device_messages = device.message_stream();
+    let mut reading = messages::RawReading::new(1, Endpoint::Rx, vec![0x01]);
+    reading.set_id(0);
+    helper.send_client_incoming(reading.into()).await;
+    helper
+      .send_client_incoming(messages::DeviceRemoved::new(1).into())
+      .await;
+    assert!(matches!(
+      device_events.next().await.unwrap(),
+      ButtplugClientDeviceEvent::Message(..)
+    ));
+    assert!(matches!(
+      device_events.next().await.unwrap(),
+      ButtplugClientDeviceEvent::DeviceRemoved
+    ));
+    assert!(matches!(
+      device_messages.next().await.unwrap(),
+      messages::ButtplugCurrentSpecServerMessage::RawReading(..)
+    ));
+  });
+}
+
 // TODO Test invalid messages to device
 // TODO Test invalid parameters in message
 // TODO Test device invalidation across client connections (i.e. a device shouldn't be allowed to reconnect even if index is the same)
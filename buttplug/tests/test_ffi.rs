@@ -0,0 +1,97 @@
+#[cfg(feature = "ffi")]
+mod ffi_tests {
+  use buttplug::ffi::{
+    buttplug_ffi_server_create, buttplug_ffi_server_free, buttplug_ffi_server_send,
+    ButtplugFFIResult, ButtplugFFIServerOptions,
+  };
+  use std::{
+    ffi::c_void,
+    ptr, slice,
+    sync::{
+      mpsc::{channel, Receiver, Sender},
+      Mutex,
+    },
+    time::Duration,
+  };
+
+  extern "C" fn message_callback(ctx: *mut c_void, buf: *const u8, len: usize) {
+    // Callbacks can come in from any runtime thread.
+    let sender = unsafe { &*(ctx as *const Mutex<Sender<String>>) };
+    let msg = unsafe { slice::from_raw_parts(buf, len) };
+    let _ = sender
+      .lock()
+      .unwrap()
+      .send(String::from_utf8(msg.to_vec()).unwrap());
+  }
+
+  fn test_options() -> ButtplugFFIServerOptions {
+    ButtplugFFIServerOptions {
+      name: ptr::null(),
+      max_ping_time: 0,
+      allow_raw_messages: false,
+      allow_power_devices: false,
+      device_configuration_json: ptr::null(),
+      user_device_configuration_json: ptr::null(),
+      add_default_comm_managers: false,
+    }
+  }
+
+  fn send(server: *mut buttplug::ffi::ButtplugFFIServer, msg: &str) -> ButtplugFFIResult {
+    unsafe { buttplug_ffi_server_send(server, msg.as_ptr(), msg.len()) }
+  }
+
+  fn next_message(receiver: &Receiver<String>) -> String {
+    receiver
+      .recv_timeout(Duration::from_secs(5))
+      .expect("Should get a reply from the server")
+  }
+
+  #[test]
+  fn test_ffi_handshake_and_ping() {
+    let (sender, receiver) = channel::<String>();
+    let sender = Box::new(Mutex::new(sender));
+    let options = test_options();
+    let server = unsafe {
+      buttplug_ffi_server_create(
+        &options,
+        Some(message_callback),
+        &*sender as *const Mutex<Sender<String>> as *mut c_void,
+      )
+    };
+    assert!(!server.is_null());
+    assert_eq!(
+      send(
+        server,
+        r#"[{"RequestServerInfo":{"Id":1,"ClientName":"FFI Test","MessageVersion":2}}]"#
+      ),
+      ButtplugFFIResult::Ok
+    );
+    let reply = next_message(&receiver);
+    assert!(reply.contains("ServerInfo"), "{}", reply);
+    assert!(reply.contains(r#""Id":1"#), "{}", reply);
+    assert_eq!(
+      send(server, r#"[{"Ping":{"Id":2}}]"#),
+      ButtplugFFIResult::Ok
+    );
+    let reply = next_message(&receiver);
+    assert!(reply.contains(r#""Ok":{"Id":2}"#), "{}", reply);
+    unsafe { buttplug_ffi_server_free(server) };
+  }
+
+  #[test]
+  fn test_ffi_send_invalid_input() {
+    let options = test_options();
+    let server = unsafe { buttplug_ffi_server_create(&options, None, ptr::null_mut()) };
+    assert!(!server.is_null());
+    let invalid = [0xffu8, 0xfe];
+    assert_eq!(
+      unsafe { buttplug_ffi_server_send(server, invalid.as_ptr(), invalid.len()) },
+      ButtplugFFIResult::InvalidUtf8
+    );
+    assert_eq!(
+      unsafe { buttplug_ffi_server_send(server, ptr::null(), 0) },
+      ButtplugFFIResult::NullPointer
+    );
+    unsafe { buttplug_ffi_server_free(server) };
+  }
+}
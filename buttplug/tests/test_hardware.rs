@@ -0,0 +1,28 @@
+// Runs a scripted test against a real device. Skipped unless
+// BUTTPLUG_HW_TEST_ADDRESS is set, see buttplug::test::hardware for the other
+// settings.
+
+#[cfg(feature = "hardware-tests")]
+mod hardware_tests {
+  use buttplug::{
+    test::hardware::{run_hardware_test, HardwareTestConfig},
+    util::async_manager,
+  };
+
+  #[test]
+  fn test_hardware_conformance() {
+    let config = match HardwareTestConfig::from_env().unwrap() {
+      Some(config) => config,
+      None => {
+        println!("BUTTPLUG_HW_TEST_ADDRESS not set, skipping hardware test.");
+        return;
+      }
+    };
+    async_manager::block_on(async move {
+      let operator = config.operator();
+      let report = run_hardware_test(&config, &*operator).await.unwrap();
+      config.write_report(&report).unwrap();
+      assert!(report.passed(), "Hardware test failed: {:?}", report.steps);
+    });
+  }
+}
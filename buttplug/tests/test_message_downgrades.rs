@@ -16,6 +16,7 @@ mod test {
     util::async_manager,
   };
   use futures::{pin_mut, StreamExt};
+  use serde_json::json;
 
   #[test]
   fn test_version0_connection() {
@@ -162,4 +163,65 @@ mod test {
       );
     });
   }
+
+  /// Connects at the given spec version, adds an Aneros Vivi (2 vibrators,
+  /// 127 steps each), and returns the DeviceMessages of its DeviceAdded.
+  async fn device_added_messages_at_version(message_version: u32) -> serde_json::Value {
+    let server = ButtplugServer::default();
+    let recv = server.event_stream();
+    pin_mut!(recv);
+    let serializer = ButtplugServerJSONSerializer::default();
+    let helper = server.add_test_comm_manager().unwrap();
+    helper.add_ble_device("Massage Demo").await;
+    let rsi = format!(
+      r#"[{{"RequestServerInfo":{{"Id": 1, "ClientName": "Test Client", "MessageVersion": {}}}}}]"#,
+      message_version
+    );
+    server
+      .parse_message(serializer.deserialize(rsi.into()).unwrap()[0].clone())
+      .await
+      .unwrap();
+    server
+      .parse_message(messages::StartScanning::default().into())
+      .await
+      .unwrap();
+    while let Some(msg) = recv.next().await {
+      if let messages::ButtplugServerMessage::DeviceAdded(_) = msg {
+        if let ButtplugSerializedMessage::Text(json) = serializer.serialize(vec![msg]) {
+          let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+          return value[0]["DeviceAdded"]["DeviceMessages"].clone();
+        }
+      }
+    }
+    panic!("Never got DeviceAdded");
+  }
+
+  #[test]
+  fn test_version3_device_added_feature_descriptors() {
+    async_manager::block_on(async {
+      let device_messages = device_added_messages_at_version(3).await;
+      assert_eq!(
+        device_messages["VibrateCmd"],
+        json!({
+          "Features": [
+            {"FeatureDescriptor": "", "ActuatorType": "Vibrate", "StepRange": [0, 127]},
+            {"FeatureDescriptor": "", "ActuatorType": "Vibrate", "StepRange": [0, 127]}
+          ]
+        })
+      );
+      assert_eq!(device_messages["StopDeviceCmd"], json!({}));
+    });
+  }
+
+  #[test]
+  fn test_version2_device_added_flattens_features() {
+    async_manager::block_on(async {
+      let device_messages = device_added_messages_at_version(2).await;
+      assert_eq!(
+        device_messages["VibrateCmd"],
+        json!({"FeatureCount": 2, "StepCount": [127, 127]})
+      );
+      assert_eq!(device_messages["StopDeviceCmd"], json!({}));
+    });
+  }
 }
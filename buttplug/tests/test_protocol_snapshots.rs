@@ -0,0 +1,377 @@
+// Snapshot tests for every protocol in the device configuration file.
+//
+// For each protocol, creates a simulated device for every specifier the
+// protocol lists, then sends each actuator message the device advertises at
+// min/mid/max values and records the hardware commands that come out. The
+// recordings are compared against tests/protocol_snapshots/<protocol>.json.
+//
+// - If a snapshot is missing or doesn't match, the new output is written next
+//   to where it should be as <protocol>.json.new and the test fails.
+// - Set BUTTPLUG_UPDATE_SNAPSHOTS=1 to write snapshots for new protocols, or
+//   overwrite them after an intentional protocol change. Snapshots are only
+//   ever written then.
+//
+// Protocols that need replies from the device to initialize (Lovense, etc)
+// will time out during setup with the simulator. That's recorded in the
+// snapshot along with whatever the protocol sent before giving up.
+
+use buttplug::{
+  core::messages::{
+    ButtplugDeviceCommandMessageUnion, ButtplugDeviceMessageType, LinearCmd, RotateCmd,
+    RotationSubcommand, StopDeviceCmd, VectorSubcommand, VibrateCmd, VibrateSubcommand,
+  },
+  device::{
+    configuration_manager::{
+      BluetoothClassicSpecifier, BluetoothLESpecifier, DeviceConfigurationManager,
+      DeviceConfigurationOptions, DeviceSpecifier, HTTPSpecifier, MQTTSpecifier,
+      ProtocolDefinition,
+    },
+    ButtplugDevice, Endpoint,
+  },
+  test::{TestDeviceImplCreator, TestDeviceInternal},
+  util::{async_manager, stream::recv_now},
+};
+use futures::{select, FutureExt};
+use futures_timer::Delay;
+use serde::Serialize;
+use serde_json::json;
+use std::{env, fs, path::PathBuf, sync::Arc, time::Duration};
+
+const DEVICE_CONFIGURATION_JSON: &str =
+  include_str!("../buttplug-device-config/buttplug-device-config.json");
+const INITIALIZATION_TIMEOUT: Duration = Duration::from_millis(500);
+const SNAPSHOT_VALUES: [(&str, f64); 3] = [("min", 0.0), ("mid", 0.5), ("max", 1.0)];
+
+#[derive(Serialize)]
+struct MessageSnapshot {
+  message: String,
+  value: String,
+  result: String,
+  commands: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct DeviceSnapshot {
+  specifier: String,
+  name: Option<String>,
+  initialization: String,
+  initialization_commands: Vec<String>,
+  messages: Vec<MessageSnapshot>,
+}
+
+/// A simulated device that should be picked up by a protocol.
+struct SnapshotDevice {
+  label: String,
+  name: String,
+  specifier: DeviceSpecifier,
+  /// Endpoints the test device creator won't set up on its own.
+  extra_endpoints: Vec<Endpoint>,
+}
+
+impl SnapshotDevice {
+  fn new(label: String, name: &str, specifier: DeviceSpecifier) -> Self {
+    Self {
+      label,
+      name: name.to_owned(),
+      specifier,
+      extra_endpoints: vec![Endpoint::Tx, Endpoint::Rx],
+    }
+  }
+}
+
+fn sorted_names<'a>(names: impl Iterator<Item = &'a String>) -> Vec<String> {
+  let mut names: Vec<String> = names.cloned().collect();
+  names.sort();
+  names
+}
+
+fn snapshot_devices(def: &ProtocolDefinition) -> Vec<SnapshotDevice> {
+  let mut devices = vec![];
+  if let Some(btle) = &def.btle {
+    for name in sorted_names(btle.names.iter()) {
+      // Wildcard names match any device name starting with the prefix.
+      let device_name = match name.strip_suffix('*') {
+        Some(prefix) => format!("{}SNAPSHOT", prefix),
+        None => name.clone(),
+      };
+      let mut device = SnapshotDevice::new(
+        format!("btle {}", name),
+        &device_name,
+        DeviceSpecifier::BluetoothLE(BluetoothLESpecifier::new_from_device(&device_name)),
+      );
+      // The creator adds endpoints for all of the protocol's services.
+      device.extra_endpoints = vec![];
+      devices.push(device);
+    }
+  }
+  if let Some(btclassic) = &def.btclassic {
+    for name in sorted_names(btclassic.names.iter()) {
+      devices.push(SnapshotDevice::new(
+        format!("btclassic {}", name),
+        &name,
+        DeviceSpecifier::BluetoothClassic(BluetoothClassicSpecifier::new_from_device(
+          &name,
+          btclassic.services.clone(),
+        )),
+      ));
+    }
+  }
+  for usb in def.usb.iter().flatten() {
+    devices.push(SnapshotDevice::new(
+      format!("{:?}", usb),
+      "USB Device",
+      DeviceSpecifier::USB(*usb),
+    ));
+  }
+  for hid in def.hid.iter().flatten() {
+    devices.push(SnapshotDevice::new(
+      format!("{:?}", hid),
+      "HID Device",
+      DeviceSpecifier::HID(*hid),
+    ));
+  }
+  for serial in def.serial.iter().flatten() {
+    devices.push(SnapshotDevice::new(
+      format!("serial {}", serial.port),
+      &serial.port,
+      DeviceSpecifier::Serial(serial.clone()),
+    ));
+  }
+  if let Some(xinput) = &def.xinput {
+    devices.push(SnapshotDevice::new(
+      "xinput".to_owned(),
+      "XInput Device",
+      DeviceSpecifier::XInput(*xinput),
+    ));
+  }
+  if let Some(lovense_connect_service) = &def.lovense_connect_service {
+    devices.push(SnapshotDevice::new(
+      "lovense-connect-service".to_owned(),
+      "Lovense Connect Device",
+      DeviceSpecifier::LovenseConnectService(lovense_connect_service.clone()),
+    ));
+  }
+  if let Some(mqtt) = &def.mqtt {
+    for name in sorted_names(mqtt.names.iter()) {
+      devices.push(SnapshotDevice::new(
+        format!("mqtt {}", name),
+        &name,
+        DeviceSpecifier::MQTT(MQTTSpecifier::new_from_identifier(&name)),
+      ));
+    }
+  }
+  if let Some(http) = &def.http {
+    for name in sorted_names(http.names.iter()) {
+      let mut device = SnapshotDevice::new(
+        format!("http {}", name),
+        &name,
+        DeviceSpecifier::HTTP(HTTPSpecifier::new_from_name(&name)),
+      );
+      device
+        .extra_endpoints
+        .extend(http.endpoints.keys().cloned());
+      devices.push(device);
+    }
+  }
+  devices
+}
+
+fn device_endpoints(def: &ProtocolDefinition, device: &SnapshotDevice) -> Vec<Endpoint> {
+  let mut endpoints = device.extra_endpoints.clone();
+  if let DeviceSpecifier::BluetoothLE(_) = device.specifier {
+    if let Some(btle) = &def.btle {
+      for service in btle.services.values() {
+        endpoints.extend(service.keys().cloned());
+      }
+    }
+  }
+  endpoints.sort_by_key(|endpoint| endpoint.to_string());
+  endpoints.dedup();
+  endpoints
+}
+
+/// Drains everything the protocol has sent to the device so far.
+fn drain_commands(test_device: &TestDeviceInternal, endpoints: &[Endpoint]) -> Vec<String> {
+  let mut commands = vec![];
+  for endpoint in endpoints {
+    if let Some(receiver) = test_device.get_endpoint_receiver(endpoint) {
+      let mut receiver = receiver.lock().unwrap();
+      while let Some(Some(command)) = recv_now(&mut receiver) {
+        commands.push(format!("{:?}", command));
+      }
+    }
+  }
+  commands
+}
+
+fn snapshot_messages(
+  message_type: ButtplugDeviceMessageType,
+  feature_count: u32,
+) -> Vec<(String, ButtplugDeviceCommandMessageUnion)> {
+  let values = SNAPSHOT_VALUES
+    .iter()
+    .map(|(label, value)| (label.to_string(), *value));
+  match message_type {
+    ButtplugDeviceMessageType::VibrateCmd => values
+      .map(|(label, value)| {
+        let speeds = (0..feature_count)
+          .map(|index| VibrateSubcommand::new(index, value))
+          .collect();
+        (label, VibrateCmd::new(0, speeds).into())
+      })
+      .collect(),
+    ButtplugDeviceMessageType::RotateCmd => values
+      .map(|(label, value)| {
+        let rotations = (0..feature_count)
+          .map(|index| RotationSubcommand::new(index, value, true))
+          .collect();
+        (label, RotateCmd::new(0, rotations).into())
+      })
+      .collect(),
+    ButtplugDeviceMessageType::LinearCmd => values
+      .map(|(label, value)| {
+        let vectors = (0..feature_count)
+          .map(|index| VectorSubcommand::new(index, 500, value))
+          .collect();
+        (label, LinearCmd::new(0, vectors).into())
+      })
+      .collect(),
+    ButtplugDeviceMessageType::StopDeviceCmd => {
+      vec![("stop".to_owned(), StopDeviceCmd::new(0).into())]
+    }
+    // Reads and sensors need replies from the device, which the simulator
+    // doesn't have.
+    _ => vec![],
+  }
+}
+
+/// Builds a configuration manager that only knows about one protocol, so
+/// devices can't be claimed by some other protocol with an overlapping
+/// specifier.
+fn single_protocol_config_manager(
+  config: &serde_json::Value,
+  protocol_name: &str,
+) -> Arc<DeviceConfigurationManager> {
+  let single_config = json!({
+    "version": config["version"],
+    "protocols": { protocol_name: config["protocols"][protocol_name] },
+  });
+  Arc::new(
+    DeviceConfigurationManager::new_with_options(&DeviceConfigurationOptions {
+      allow_power_devices: true,
+      device_configuration_json: Some(single_config.to_string()),
+      ..Default::default()
+    })
+    .unwrap(),
+  )
+}
+
+async fn snapshot_device(
+  config_mgr: Arc<DeviceConfigurationManager>,
+  def: &ProtocolDefinition,
+  device: SnapshotDevice,
+) -> DeviceSnapshot {
+  let endpoints = device_endpoints(def, &device);
+  let test_device = Arc::new(TestDeviceInternal::new(&device.name, "snapshot"));
+  for endpoint in &device.extra_endpoints {
+    test_device.add_endpoint(endpoint).await;
+  }
+  let creator = TestDeviceImplCreator::new(device.specifier, test_device.clone());
+  let create_fut = ButtplugDevice::try_create_device(config_mgr, Box::new(creator));
+  let created = select! {
+    result = create_fut.fuse() => result,
+    _ = Delay::new(INITIALIZATION_TIMEOUT).fuse() => {
+      return DeviceSnapshot {
+        specifier: device.label,
+        name: None,
+        initialization: "timed out".to_owned(),
+        initialization_commands: drain_commands(&test_device, &endpoints),
+        messages: vec![],
+      };
+    }
+  };
+  let buttplug_device = match created {
+    Ok(Some(buttplug_device)) => buttplug_device,
+    other => {
+      let initialization = match other {
+        Ok(_) => "no protocol implementation".to_owned(),
+        Err(err) => format!("error: {}", err),
+      };
+      return DeviceSnapshot {
+        specifier: device.label,
+        name: None,
+        initialization,
+        initialization_commands: drain_commands(&test_device, &endpoints),
+        messages: vec![],
+      };
+    }
+  };
+  let mut snapshot = DeviceSnapshot {
+    specifier: device.label,
+    name: Some(buttplug_device.name()),
+    initialization: "ok".to_owned(),
+    initialization_commands: drain_commands(&test_device, &endpoints),
+    messages: vec![],
+  };
+  let mut attributes: Vec<_> = buttplug_device.message_attributes().into_iter().collect();
+  attributes.sort_by_key(|(message_type, _)| message_type.to_string());
+  for (message_type, message_attributes) in attributes {
+    let feature_count = message_attributes.feature_count.unwrap_or(0);
+    for (value, message) in snapshot_messages(message_type, feature_count) {
+      let result = match buttplug_device.parse_message(message).await {
+        Ok(_) => "ok".to_owned(),
+        Err(err) => format!("error: {}", err),
+      };
+      snapshot.messages.push(MessageSnapshot {
+        message: message_type.to_string(),
+        value,
+        result,
+        commands: drain_commands(&test_device, &endpoints),
+      });
+    }
+  }
+  snapshot
+}
+
+#[test]
+fn test_protocol_snapshots() {
+  let snapshot_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+    .join("tests")
+    .join("protocol_snapshots");
+  let update = env::var_os("BUTTPLUG_UPDATE_SNAPSHOTS").is_some();
+  let config: serde_json::Value = serde_json::from_str(DEVICE_CONFIGURATION_JSON).unwrap();
+  let mut mismatches = vec![];
+  async_manager::block_on(async {
+    let full_config_mgr = DeviceConfigurationManager::default();
+    let mut protocol_names: Vec<&String> =
+      full_config_mgr.protocol_configurations().keys().collect();
+    protocol_names.sort();
+    for protocol_name in protocol_names {
+      let def = &full_config_mgr.protocol_configurations()[protocol_name];
+      let config_mgr = single_protocol_config_manager(&config, protocol_name);
+      let mut snapshots = vec![];
+      for device in snapshot_devices(def) {
+        snapshots.push(snapshot_device(config_mgr.clone(), def, device).await);
+      }
+      let output = serde_json::to_string_pretty(&snapshots).unwrap() + "\n";
+      let snapshot_path = snapshot_dir.join(format!("{}.json", protocol_name));
+      let matches = fs::read_to_string(&snapshot_path).map_or(false, |existing| existing == output);
+      if matches {
+        continue;
+      }
+      fs::create_dir_all(&snapshot_dir).unwrap();
+      if update {
+        fs::write(&snapshot_path, output).unwrap();
+      } else {
+        fs::write(snapshot_path.with_extension("json.new"), output).unwrap();
+        mismatches.push(protocol_name.clone());
+      }
+    }
+  });
+  assert!(
+    mismatches.is_empty(),
+    "Protocol snapshots missing or changed for {:?}, see the .json.new files in {}. If this was intended, rerun with BUTTPLUG_UPDATE_SNAPSHOTS=1 and commit the snapshots.",
+    mismatches,
+    snapshot_dir.display()
+  );
+}
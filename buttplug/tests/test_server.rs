@@ -1,21 +1,33 @@
 mod util;
 
 use buttplug::{
+  connector::transport::ButtplugTransportIncomingMessage,
   core::{
     errors::{ButtplugDeviceError, ButtplugError, ButtplugHandshakeError},
     messages::{
-      self, ButtplugMessageSpecVersion, ButtplugServerMessage,
-      BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION,
+      self, ButtplugCurrentSpecServerMessage, ButtplugMessage, ButtplugMessageSpecVersion,
+      ButtplugServerMessage, LogLevel, BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION,
     },
   },
   device::{DeviceImplCommand, DeviceWriteCmd, Endpoint},
-  server::{ButtplugServer, ButtplugServerOptions},
-  test::check_test_recv_value,
-  util::async_manager,
+  server::{
+    comm_managers::DeviceCommunicationTransport,
+    device_connection::DeviceConnectionEvent,
+    diagnostics::ServerDiagnostic,
+    event_filter::{EventFilter, ServerEventType},
+    known_devices::{KnownDevice, KnownDeviceEvent},
+    log_forwarding::ButtplugLogLayer,
+    remote_server::{ButtplugRemoteServerConnectionEvent, ButtplugRemoteServerDisconnectReason},
+    ButtplugServer, ButtplugServerBuilder, ButtplugServerError, ButtplugServerOptions,
+    DisconnectStopPolicy,
+  },
+  test::{check_test_recv_empty, check_test_recv_value},
+  util::{async_manager, clock::ManualClock},
 };
 use futures::{pin_mut, Stream, StreamExt};
 use futures_timer::Delay;
-use std::time::Duration;
+use std::{sync::Arc, time::Duration};
+use tracing_subscriber::{layer::SubscriberExt, Registry};
 
 async fn setup_test_server(
   msg_union: messages::ButtplugClientMessage,
@@ -26,7 +38,7 @@ async fn setup_test_server(
   match server.parse_message(msg_union).await.unwrap() {
     ButtplugServerMessage::ServerInfo(s) => assert_eq!(
       s,
-      messages::ServerInfo::new("Buttplug Server", ButtplugMessageSpecVersion::Version2, 0)
+      messages::ServerInfo::new("Buttplug Server", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION, 0)
     ),
     _ => panic!("Should've received ok"),
   }
@@ -186,6 +198,182 @@ fn test_device_stop_on_ping_timeout() {
   });
 }
 
+#[test]
+fn test_server_disconnect_stop_policy() {
+  async_manager::block_on(async {
+    let options = ButtplugServerOptions {
+      disconnect_stop_policy: DisconnectStopPolicy::StopAfterGracePeriod(100),
+      ..Default::default()
+    };
+    let clock = ManualClock::new();
+    let server = ButtplugServer::new_with_clock(&options, Arc::new(clock.clone())).unwrap();
+    let recv = server.event_stream();
+    pin_mut!(recv);
+    let helper = server.add_test_comm_manager().unwrap();
+    let device = helper.add_ble_device("Massage Demo").await;
+    let command_receiver = device.get_endpoint_receiver(&Endpoint::Tx).unwrap();
+    let connect = || {
+      server.parse_message(
+        messages::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION)
+          .into(),
+      )
+    };
+    let vibrate = || {
+      server.parse_message(
+        messages::VibrateCmd::new(0, vec![messages::VibrateSubcommand::new(0, 0.5)]).into(),
+      )
+    };
+    connect().await.unwrap();
+    server
+      .parse_message(messages::StartScanning::default().into())
+      .await
+      .unwrap();
+    while !matches!(
+      recv.next().await,
+      Some(ButtplugServerMessage::DeviceAdded(_))
+    ) {}
+    vibrate().await.unwrap();
+    check_test_recv_value(
+      &command_receiver,
+      DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![0xF1, 64], false)),
+    );
+
+    // Coming back within the grace period keeps devices running.
+    server.disconnect().await.unwrap();
+    assert!(check_test_recv_empty(&command_receiver));
+    clock.advance(Duration::from_millis(50));
+    connect().await.unwrap();
+    clock.advance(Duration::from_millis(200));
+    // Give the stop task a chance to run, if it's going to.
+    Delay::new(Duration::from_millis(50)).await;
+    assert!(check_test_recv_empty(&command_receiver));
+
+    // Staying away stops them.
+    server.disconnect().await.unwrap();
+    clock.advance(Duration::from_millis(99));
+    Delay::new(Duration::from_millis(50)).await;
+    assert!(check_test_recv_empty(&command_receiver));
+    clock.advance(Duration::from_millis(1));
+    Delay::new(Duration::from_millis(50)).await;
+    check_test_recv_value(
+      &command_receiver,
+      DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![0xF1, 0], false)),
+    );
+  });
+}
+
+#[test]
+fn test_server_client_handoff() {
+  async_manager::block_on(async {
+    let options = ButtplugServerOptions {
+      handoff_timeout: 10000,
+      ..Default::default()
+    };
+    let server = ButtplugServer::new_with_options(&options).unwrap();
+    let recv = server.event_stream();
+    pin_mut!(recv);
+    let helper = server.add_test_comm_manager().unwrap();
+    let device = helper.add_ble_device("Massage Demo").await;
+    let command_receiver = device.get_endpoint_receiver(&Endpoint::Tx).unwrap();
+    let rsi =
+      messages::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION);
+    server.set_client_identity_token(Some("scene-player".to_owned()));
+    server.parse_message(rsi.clone().into()).await.unwrap();
+    server
+      .parse_message(messages::StartScanning::default().into())
+      .await
+      .unwrap();
+    while !matches!(
+      recv.next().await,
+      Some(ButtplugServerMessage::DeviceAdded(_))
+    ) {}
+    server
+      .parse_message(
+        messages::VibrateCmd::new(0, vec![messages::VibrateSubcommand::new(0, 0.5)]).into(),
+      )
+      .await
+      .unwrap();
+    check_test_recv_value(
+      &command_receiver,
+      DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![0xF1, 64], false)),
+    );
+
+    // The same client coming back takes over the running device.
+    server.disconnect().await.unwrap();
+    server.set_client_identity_token(Some("scene-player".to_owned()));
+    server.parse_message(rsi.clone().into()).await.unwrap();
+    assert!(check_test_recv_empty(&command_receiver));
+
+    // Anyone else gets it stopped.
+    server.disconnect().await.unwrap();
+    assert!(check_test_recv_empty(&command_receiver));
+    server.parse_message(rsi.into()).await.unwrap();
+    check_test_recv_value(
+      &command_receiver,
+      DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![0xF1, 0], false)),
+    );
+  });
+}
+
+#[test]
+fn test_server_client_handoff_window() {
+  async_manager::block_on(async {
+    let options = ButtplugServerOptions {
+      handoff_timeout: 10000,
+      ..Default::default()
+    };
+    let clock = ManualClock::new();
+    let server = ButtplugServer::new_with_clock(&options, Arc::new(clock.clone())).unwrap();
+    let recv = server.event_stream();
+    pin_mut!(recv);
+    let helper = server.add_test_comm_manager().unwrap();
+    let device = helper.add_ble_device("Massage Demo").await;
+    let command_receiver = device.get_endpoint_receiver(&Endpoint::Tx).unwrap();
+    let rsi =
+      messages::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION);
+    server.set_client_identity_token(Some("scene-player".to_owned()));
+    server.parse_message(rsi.clone().into()).await.unwrap();
+    server
+      .parse_message(messages::StartScanning::default().into())
+      .await
+      .unwrap();
+    while !matches!(
+      recv.next().await,
+      Some(ButtplugServerMessage::DeviceAdded(_))
+    ) {}
+    server
+      .parse_message(
+        messages::VibrateCmd::new(0, vec![messages::VibrateSubcommand::new(0, 0.5)]).into(),
+      )
+      .await
+      .unwrap();
+    check_test_recv_value(
+      &command_receiver,
+      DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![0xF1, 64], false)),
+    );
+
+    // Coming back just before the window closes keeps the device running,
+    // even once the window would have run out.
+    server.disconnect().await.unwrap();
+    clock.advance(Duration::from_millis(9999));
+    server.set_client_identity_token(Some("scene-player".to_owned()));
+    server.parse_message(rsi.into()).await.unwrap();
+    clock.advance(Duration::from_millis(10000));
+    // Give the stop task a chance to run, if it's going to.
+    Delay::new(Duration::from_millis(50)).await;
+    assert!(check_test_recv_empty(&command_receiver));
+
+    // Not coming back at all stops it once the window is up.
+    server.disconnect().await.unwrap();
+    clock.advance(Duration::from_millis(10000));
+    Delay::new(Duration::from_millis(50)).await;
+    check_test_recv_value(
+      &command_receiver,
+      DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![0xF1, 0], false)),
+    );
+  });
+}
+
 #[test]
 fn test_repeated_handshake() {
   let msg = messages::RequestServerInfo::new("Test Client", ButtplugMessageSpecVersion::Version2);
@@ -309,9 +497,614 @@ fn test_server_scanning_finished() {
   });
 }
 
+#[test]
+fn test_server_max_scanning_time() {
+  async_manager::block_on(async {
+    let mut options = ButtplugServerOptions::default();
+    options.max_scanning_time = 100;
+    let server = ButtplugServer::new_with_options(&options).unwrap();
+    let recv = server.event_stream();
+    pin_mut!(recv);
+    // The delay manager only finishes once it's told to stop, so the scan can
+    // only end if the server stops it.
+    server
+      .add_comm_manager(util::DelayDeviceCommunicationManagerBuilder::default())
+      .unwrap();
+    assert!(server
+      .parse_message(
+        messages::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION)
+          .into()
+      )
+      .await
+      .is_ok());
+    assert!(server
+      .parse_message(messages::StartScanning::default().into())
+      .await
+      .is_ok());
+    match recv.next().await {
+      Some(ButtplugServerMessage::ScanningFinished(msg)) => assert!(msg.errors().is_empty()),
+      msg => panic!("Expected ScanningFinished, got {:?}", msg),
+    }
+  });
+}
+
+fn assert_device_list_len(list: Result<ButtplugServerMessage, messages::Error>, len: usize) {
+  match list {
+    Ok(ButtplugServerMessage::DeviceList(list)) => assert_eq!(list.devices().len(), len),
+    msg => panic!("Expected DeviceList, got {:?}", msg),
+  }
+}
+
+#[test]
+fn test_server_device_debounce() {
+  async_manager::block_on(async {
+    let mut options = ButtplugServerOptions::default();
+    options.device_debounce_time = 500;
+    let clock = ManualClock::new();
+    let server = ButtplugServer::new_with_clock(&options, Arc::new(clock.clone())).unwrap();
+    let recv = server.event_stream();
+    pin_mut!(recv);
+    let helper = server.add_test_comm_manager().unwrap();
+    let device = helper.add_ble_device("Massage Demo").await;
+    assert!(server
+      .parse_message(
+        messages::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION)
+          .into()
+      )
+      .await
+      .is_ok());
+    assert!(server
+      .parse_message(messages::StartScanning::default().into())
+      .await
+      .is_ok());
+    // The device is connected once the event loop starts waiting out the
+    // debounce time. Until then, it's left out of device lists.
+    clock.wait_for_sleeps(1).await;
+    assert_device_list_len(
+      server
+        .parse_message(messages::RequestDeviceList::default().into())
+        .await,
+      0,
+    );
+    device.disconnect().await.unwrap();
+    // The device never settled, so the client never hears about it.
+    match recv.next().await {
+      Some(ButtplugServerMessage::ScanningFinished(_)) => {}
+      msg => panic!("Expected ScanningFinished, got {:?}", msg),
+    }
+  });
+}
+
+#[test]
+fn test_server_device_debounce_settles() {
+  async_manager::block_on(async {
+    let mut options = ButtplugServerOptions::default();
+    options.device_debounce_time = 500;
+    let clock = ManualClock::new();
+    let server = ButtplugServer::new_with_clock(&options, Arc::new(clock.clone())).unwrap();
+    let recv = server.event_stream();
+    pin_mut!(recv);
+    let helper = server.add_test_comm_manager().unwrap();
+    helper.add_ble_device("Massage Demo").await;
+    assert!(server
+      .parse_message(
+        messages::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION)
+          .into()
+      )
+      .await
+      .is_ok());
+    assert!(server
+      .parse_message(messages::StartScanning::default().into())
+      .await
+      .is_ok());
+    clock.wait_for_sleeps(1).await;
+    assert_device_list_len(
+      server
+        .parse_message(messages::RequestDeviceList::default().into())
+        .await,
+      0,
+    );
+    clock.advance(Duration::from_millis(500));
+    match recv.next().await {
+      Some(ButtplugServerMessage::DeviceAdded(_)) => {}
+      msg => panic!("Expected DeviceAdded, got {:?}", msg),
+    }
+    match recv.next().await {
+      Some(ButtplugServerMessage::ScanningFinished(_)) => {}
+      msg => panic!("Expected ScanningFinished, got {:?}", msg),
+    }
+    assert_device_list_len(
+      server
+        .parse_message(messages::RequestDeviceList::default().into())
+        .await,
+      1,
+    );
+  });
+}
+
+#[cfg(feature = "server-emulator")]
+#[test]
+fn test_server_emulated_devices() {
+  async_manager::block_on(async {
+    let server = ButtplugServer::new_with_options(&ButtplugServerOptions {
+      emulated_devices_json: Some(
+        r#"{ "devices": [{ "name": "Massage Demo" }, { "name": "Massage Demo" }] }"#.to_owned(),
+      ),
+      ..Default::default()
+    })
+    .unwrap();
+    assert!(server
+      .parse_message(
+        messages::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION)
+          .into()
+      )
+      .await
+      .is_ok());
+    // The list waits for the devices, no scanning needed.
+    match server
+      .parse_message(messages::RequestDeviceList::default().into())
+      .await
+    {
+      Ok(ButtplugServerMessage::DeviceList(list)) => {
+        let indexes: Vec<u32> = list
+          .devices()
+          .iter()
+          .map(|device| device.device_index)
+          .collect();
+        assert_eq!(indexes, vec![0, 1]);
+        assert!(list
+          .devices()
+          .iter()
+          .all(|device| device.device_name == "Aneros Vivi"));
+      }
+      msg => panic!("Expected DeviceList, got {:?}", msg),
+    }
+  });
+}
+
+#[test]
+fn test_server_known_devices() {
+  async_manager::block_on(async {
+    let mut options = ButtplugServerOptions::default();
+    options.known_devices = vec![KnownDevice {
+      identity_key: "00:82:05:9a:d3:bd".to_owned(),
+      address: "00:82:05:9a:d3:bd".to_owned(),
+      name: "Aneros Vivi".to_owned(),
+      protocol: Some("aneros".to_owned()),
+      last_connected: 0,
+    }];
+    let server = ButtplugServer::new_with_options(&options).unwrap();
+    let recv = server.event_stream();
+    pin_mut!(recv);
+    let known_recv = server.known_device_event_stream();
+    pin_mut!(known_recv);
+    let helper = server.add_test_comm_manager().unwrap();
+    helper
+      .add_ble_device_with_address("Massage Demo", "00:82:05:9A:D3:BD")
+      .await;
+    helper.add_ble_device("Massage Demo").await;
+    assert!(server
+      .parse_message(
+        messages::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION)
+          .into()
+      )
+      .await
+      .is_ok());
+    assert!(server
+      .parse_message(messages::StartScanning::default().into())
+      .await
+      .is_ok());
+    match known_recv.next().await {
+      Some(KnownDeviceEvent::InRange(device)) => {
+        assert_eq!(device.identity_key, "00:82:05:9a:d3:bd")
+      }
+      msg => panic!("Expected InRange, got {:?}", msg),
+    }
+    while let Some(msg) = recv.next().await {
+      if matches!(msg, ButtplugServerMessage::ScanningFinished(_)) {
+        break;
+      }
+    }
+    // Both devices connected, so both are known now.
+    let known_devices = server.known_devices();
+    assert_eq!(known_devices.len(), 2);
+    assert!(known_devices
+      .iter()
+      .all(|device| device.last_connected > 0 && device.protocol.as_deref() == Some("aneros")));
+    assert!(server.forget_known_device("00:82:05:9a:d3:bd"));
+    assert_eq!(server.known_devices().len(), 1);
+  });
+}
+
+#[test]
+fn test_server_device_connection_events() {
+  async_manager::block_on(async {
+    let server = ButtplugServer::default();
+    let connection_recv = server.device_connection_event_stream();
+    pin_mut!(connection_recv);
+    let helper = server.add_test_comm_manager().unwrap();
+    helper
+      .add_ble_device_with_address("Massage Demo", "00:82:05:9A:D3:BD")
+      .await;
+    assert!(server
+      .parse_message(
+        messages::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION)
+          .into()
+      )
+      .await
+      .is_ok());
+    assert!(server
+      .parse_message(messages::StartScanning::default().into())
+      .await
+      .is_ok());
+    let name = "Massage Demo".to_owned();
+    let address = "00:82:05:9A:D3:BD".to_owned();
+    assert_eq!(
+      connection_recv.next().await.unwrap(),
+      DeviceConnectionEvent::DeviceConnecting {
+        name: name.clone(),
+        address: address.clone(),
+        protocol: "aneros".to_owned(),
+      }
+    );
+    // Aneros has no initialization stages of its own, so only the start of
+    // initialization is reported.
+    assert_eq!(
+      connection_recv.next().await.unwrap(),
+      DeviceConnectionEvent::DeviceInitializing {
+        name: name.clone(),
+        address: address.clone(),
+        stage: String::new(),
+      }
+    );
+    assert_eq!(
+      connection_recv.next().await.unwrap(),
+      DeviceConnectionEvent::DeviceConnected { name, address }
+    );
+  });
+}
+
+#[test]
+fn test_server_device_info() {
+  async_manager::block_on(async {
+    let server = ButtplugServer::default();
+    let recv = server.event_stream();
+    pin_mut!(recv);
+    let helper = server.add_test_comm_manager().unwrap();
+    helper
+      .add_ble_device_with_address("Massage Demo", "00:82:05:9A:D3:BD")
+      .await;
+    assert!(server
+      .parse_message(
+        messages::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION)
+          .into()
+      )
+      .await
+      .is_ok());
+    assert!(server.device_info(0).is_none());
+    assert!(server
+      .parse_message(messages::StartScanning::default().into())
+      .await
+      .is_ok());
+    while let Some(msg) = recv.next().await {
+      if let ButtplugServerMessage::DeviceAdded(_) = msg {
+        break;
+      }
+    }
+    let info = server.device_info(0).unwrap();
+    assert_eq!(info.index, 0);
+    assert_eq!(info.name, "Aneros Vivi");
+    assert_eq!(info.address, "00:82:05:9A:D3:BD");
+    assert_eq!(info.protocol.as_deref(), Some("aneros"));
+    assert_eq!(
+      info.comm_manager.as_deref(),
+      Some("TestDeviceCommunicationManager")
+    );
+    assert_eq!(info.transport, Some(DeviceCommunicationTransport::Other));
+    assert!(info.endpoints.contains(&Endpoint::Tx));
+    assert_eq!(server.device_info_list(), vec![info]);
+    assert!(server.device_info(1).is_none());
+  });
+}
+
+#[test]
+fn test_server_device_initialization_limit() {
+  async_manager::block_on(async {
+    let options = ButtplugServerOptions {
+      max_concurrent_device_initializations: 1,
+      ..Default::default()
+    };
+    let server = ButtplugServer::new_with_options(&options).unwrap();
+    let connection_recv = server.device_connection_event_stream();
+    pin_mut!(connection_recv);
+    let helper = server.add_test_comm_manager().unwrap();
+    for address in &["00:00:00:00:00:01", "00:00:00:00:00:02", "00:00:00:00:00:03"] {
+      helper
+        .add_ble_device_with_address("Massage Demo", address)
+        .await;
+    }
+    assert!(server
+      .parse_message(
+        messages::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION)
+          .into()
+      )
+      .await
+      .is_ok());
+    assert!(server
+      .parse_message(messages::StartScanning::default().into())
+      .await
+      .is_ok());
+    // With one device at a time, each device finishes connecting before the
+    // next one starts.
+    for _ in 0..3 {
+      let address = match connection_recv.next().await.unwrap() {
+        DeviceConnectionEvent::DeviceConnecting { address, .. } => address,
+        event => panic!("Expected DeviceConnecting, got {:?}", event),
+      };
+      assert!(matches!(
+        connection_recv.next().await.unwrap(),
+        DeviceConnectionEvent::DeviceInitializing { address: ref initializing, .. }
+          if *initializing == address
+      ));
+      assert!(matches!(
+        connection_recv.next().await.unwrap(),
+        DeviceConnectionEvent::DeviceConnected { address: ref connected, .. }
+          if *connected == address
+      ));
+    }
+  });
+}
+
+#[test]
+fn test_server_event_stream_filtered() {
+  async_manager::block_on(async {
+    let server = ButtplugServer::default();
+    let recv = server.event_stream_filtered(
+      EventFilter::default().allow_message_type(ServerEventType::ScanningFinished),
+    );
+    pin_mut!(recv);
+    let helper = server.add_test_comm_manager().unwrap();
+    helper.add_ble_device("Massage Demo").await;
+    assert!(server
+      .parse_message(
+        messages::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION)
+          .into()
+      )
+      .await
+      .is_ok());
+    assert!(server
+      .parse_message(messages::StartScanning::default().into())
+      .await
+      .is_ok());
+    // The DeviceAdded for our device should be filtered out, so the first
+    // thing we see is the end of the scan.
+    assert!(matches!(
+      recv.next().await,
+      Some(ButtplugServerMessage::ScanningFinished(_))
+    ));
+  });
+}
+
+#[test]
+fn test_server_remove_comm_manager() {
+  async_manager::block_on(async {
+    let server = ButtplugServer::default();
+    let recv = server.event_stream();
+    pin_mut!(recv);
+    let helper = server.add_test_comm_manager().unwrap();
+    helper.add_ble_device("Massage Demo").await;
+    assert!(server
+      .parse_message(
+        messages::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION)
+          .into()
+      )
+      .await
+      .is_ok());
+    assert!(server
+      .parse_message(messages::StartScanning::default().into())
+      .await
+      .is_ok());
+    let mut device_index = None;
+    while let Some(msg) = recv.next().await {
+      if let ButtplugServerMessage::DeviceAdded(da) = msg {
+        device_index = Some(da.device_index());
+        break;
+      }
+    }
+    let device_index = device_index.unwrap();
+    server
+      .remove_comm_manager("TestDeviceCommunicationManager")
+      .unwrap();
+    // Devices owned by the manager should be disconnected on removal.
+    while let Some(msg) = recv.next().await {
+      if let ButtplugServerMessage::DeviceRemoved(dr) = msg {
+        assert_eq!(dr.device_index(), device_index);
+        break;
+      }
+    }
+    assert!(server
+      .remove_comm_manager("TestDeviceCommunicationManager")
+      .is_err());
+    // Once removed, the manager can be added again.
+    assert!(server.add_test_comm_manager().is_ok());
+  });
+}
+
+#[test]
+fn test_server_comm_manager_capabilities() {
+  let server = ButtplugServer::default();
+  assert!(server.comm_manager_capabilities().is_empty());
+  server.add_test_comm_manager().unwrap();
+  let capabilities = server.comm_manager_capabilities();
+  let test_capabilities = capabilities.get("TestDeviceCommunicationManager").unwrap();
+  assert_eq!(
+    test_capabilities.transport,
+    DeviceCommunicationTransport::Other
+  );
+  assert!(test_capabilities.unavailable_reason.is_none());
+}
+
+#[test]
+fn test_server_validate() {
+  let server = ButtplugServer::new_with_options(&ButtplugServerOptions {
+    max_ping_time: 10,
+    allow_raw_messages: true,
+    ..Default::default()
+  })
+  .unwrap();
+  assert_eq!(
+    server.validate(),
+    vec![
+      ServerDiagnostic::PingTimeTooLow(10),
+      ServerDiagnostic::RawMessagesWithoutCommManagers,
+      ServerDiagnostic::NoCommManagers,
+    ]
+  );
+  server.add_test_comm_manager().unwrap();
+  assert_eq!(
+    server.validate(),
+    vec![ServerDiagnostic::PingTimeTooLow(10)]
+  );
+}
+
+#[test]
+fn test_server_builder() {
+  let server = ButtplugServerBuilder::default()
+    .comm_manager(util::DelayDeviceCommunicationManagerBuilder::default())
+    .finish()
+    .unwrap();
+  assert!(server
+    .comm_manager_capabilities()
+    .contains_key("DelayDeviceCommunicationManager"));
+  let result = ButtplugServerBuilder::default()
+    .comm_manager(util::DelayDeviceCommunicationManagerBuilder::default())
+    .comm_manager(util::DelayDeviceCommunicationManagerBuilder::default())
+    .finish();
+  assert!(matches!(
+    result,
+    Err(ButtplugServerError::DeviceManagerTypeAlreadyAdded(_))
+  ));
+}
+
+#[test]
+fn test_server_request_log() {
+  let _subscriber_guard = tracing::subscriber::set_default(
+    Registry::default().with(ButtplugLogLayer::default()),
+  );
+  async_manager::block_on(async {
+    let server = ButtplugServer::default();
+    let recv = server.event_stream();
+    pin_mut!(recv);
+    assert!(server
+      .parse_message(
+        messages::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION)
+          .into()
+      )
+      .await
+      .is_ok());
+    assert!(server
+      .parse_message(messages::RequestLog::new(LogLevel::Debug).into())
+      .await
+      .is_ok());
+    tracing::info!(target: "buttplug_log_test", "Forward this record");
+    // Trace is more verbose than what we asked for, so it shouldn't show up.
+    tracing::trace!(target: "buttplug_log_test", "Don't forward this record");
+    loop {
+      match recv.next().await {
+        Some(ButtplugServerMessage::Log(log)) => {
+          if !log.log_message().starts_with("buttplug_log_test") {
+            continue;
+          }
+          assert_eq!(*log.log_level(), LogLevel::Info);
+          assert_eq!(log.log_message(), "buttplug_log_test: Forward this record");
+          break;
+        }
+        Some(_) => continue,
+        None => panic!("Server event stream closed before log record arrived"),
+      }
+    }
+  });
+}
+
 // TODO Test sending system message (Id 0)
 // TODO Test sending system message (Ok but Id > 0)
 // TODO Test repeated handshake
 // TODO Test scan with no comm managers
 // TODO Test message with no RequestServerInfo first
 // TODO Test sending device command for device that doesn't exist (in server)
+
+#[test]
+fn test_remote_server_reply_and_event_ids() {
+  async_manager::block_on(async {
+    let helper = util::ChannelServerTestHelper::new();
+    let test_devices = helper.server().add_test_comm_manager().unwrap();
+    test_devices.add_ble_device("Massage Demo").await;
+    helper.start().await;
+    let mut rsi: messages::ButtplugCurrentSpecClientMessage =
+      messages::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION).into();
+    rsi.set_id(1);
+    helper.send_server_incoming(rsi).await;
+    let reply = helper.get_next_server_message().await;
+    assert!(matches!(reply, ButtplugCurrentSpecServerMessage::ServerInfo(_)));
+    assert_eq!(reply.id(), 1);
+    let mut start_scanning: messages::ButtplugCurrentSpecClientMessage =
+      messages::StartScanning::default().into();
+    start_scanning.set_id(2);
+    helper.send_server_incoming(start_scanning).await;
+    // The reply and the device event can come in either order, but only the
+    // reply gets the request's id.
+    let mut got_ok = false;
+    let mut got_device = false;
+    while !(got_ok && got_device) {
+      let msg = helper.get_next_server_message().await;
+      match msg {
+        ButtplugCurrentSpecServerMessage::Ok(_) => {
+          assert_eq!(msg.id(), 2);
+          got_ok = true;
+        }
+        ButtplugCurrentSpecServerMessage::DeviceAdded(_) => {
+          assert_eq!(msg.id(), 0);
+          got_device = true;
+        }
+        _ => assert_eq!(msg.id(), 0),
+      }
+    }
+  });
+}
+
+#[test]
+fn test_remote_server_connection_events() {
+  async_manager::block_on(async {
+    let helper = util::ChannelServerTestHelper::new();
+    let events = helper.server().connection_event_stream();
+    pin_mut!(events);
+    helper.start().await;
+    assert_eq!(
+      events.next().await,
+      Some(ButtplugRemoteServerConnectionEvent::ClientConnecting)
+    );
+    let mut rsi: messages::ButtplugCurrentSpecClientMessage =
+      messages::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION).into();
+    rsi.set_id(1);
+    helper.send_server_incoming(rsi).await;
+    helper.get_next_server_message().await;
+    assert_eq!(
+      events.next().await,
+      Some(ButtplugRemoteServerConnectionEvent::ClientConnected {
+        name: "Test Client".to_owned(),
+        message_version: BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION,
+      })
+    );
+    helper
+      .send_incoming(ButtplugTransportIncomingMessage::Close(
+        "Test client left".to_owned(),
+      ))
+      .await;
+    assert_eq!(
+      events.next().await,
+      Some(ButtplugRemoteServerConnectionEvent::ClientDisconnected {
+        reason: ButtplugRemoteServerDisconnectReason::ClientClosed,
+      })
+    );
+  });
+}
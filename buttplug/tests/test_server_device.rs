@@ -3,15 +3,24 @@ use buttplug::{
   core::{
     errors::{ButtplugDeviceError, ButtplugError},
     messages::{
-      self, ButtplugDeviceMessageType, ButtplugServerMessage, BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION,
+      self, ButtplugClientMessage, ButtplugDeviceCommandMessageUnion, ButtplugDeviceMessageType,
+      ButtplugServerMessage, BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION,
     },
   },
-  device::Endpoint,
-  server::{ButtplugServer, ButtplugServerOptions},
+  device::{ButtplugDeviceEvent, DeviceImplCommand, DeviceWriteCmd, Endpoint},
+  server::{
+    device_filter::DeviceFilter,
+    device_manager::{DeviceIndexPolicy, StopAllDevicesScope},
+    pressure_loop::PressureLoopConfig,
+    scheduled_stop::ScheduledStopEvent,
+    ButtplugServer, ButtplugServerOptions,
+  },
+  test::{check_test_recv_empty, check_test_recv_value, TestDeviceInternal},
   util::async_manager,
 };
 use futures::{pin_mut, StreamExt};
-use std::matches;
+use futures_timer::Delay;
+use std::{matches, sync::Arc, time::Duration};
 
 // Test devices that have protocols that support movements not all devices do.
 // For instance, the Onyx+ is part of a protocol that supports vibration, but
@@ -277,3 +286,752 @@ fn test_repeated_address_additions() {
     }
   });
 }
+
+#[test]
+fn test_reserved_device_index() {
+  async_manager::block_on(async {
+    let mut options = ButtplugServerOptions::default();
+    options.user_device_configuration_json = Some(
+      r#"{ "protocols": {}, "reserved-indexes": { "reserved-vivi": 0 } }"#.to_owned(),
+    );
+    let server = ButtplugServer::new_with_options(&options).unwrap();
+    let recv = server.event_stream();
+    pin_mut!(recv);
+    let helper = server.add_test_comm_manager().unwrap();
+    helper
+      .add_ble_device_with_address("Onyx+", "unreserved-onyx")
+      .await;
+    helper
+      .add_ble_device_with_address("Massage Demo", "reserved-vivi")
+      .await;
+    server
+      .parse_message(
+        messages::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION)
+          .into(),
+      )
+      .await
+      .unwrap();
+    server
+      .parse_message(messages::StartScanning::default().into())
+      .await
+      .unwrap();
+    let mut devices_added = 0;
+    while let Some(msg) = recv.next().await {
+      if let ButtplugServerMessage::DeviceAdded(da) = msg {
+        if da.device_name() == "Aneros Vivi" {
+          assert_eq!(da.device_index(), 0);
+        } else {
+          // Whichever device connects first, the unreserved one never gets
+          // the reserved index.
+          assert_ne!(da.device_index(), 0);
+        }
+        devices_added += 1;
+        if devices_added == 2 {
+          return;
+        }
+      }
+    }
+    panic!("Did not get both DeviceAdded messages.");
+  });
+}
+
+#[test]
+fn test_recycled_device_index() {
+  async_manager::block_on(async {
+    let mut options = ButtplugServerOptions::default();
+    options.device_index_policy = DeviceIndexPolicy::Recycle;
+    let server = ButtplugServer::new_with_options(&options).unwrap();
+    let recv = server.event_stream();
+    pin_mut!(recv);
+    let helper = server.add_test_comm_manager().unwrap();
+    let first_device = helper
+      .add_ble_device_with_address("Massage Demo", "first-vivi")
+      .await;
+    server
+      .parse_message(
+        messages::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION)
+          .into(),
+      )
+      .await
+      .unwrap();
+    server
+      .parse_message(messages::StartScanning::default().into())
+      .await
+      .unwrap();
+    let mut first_index = None;
+    while let Some(msg) = recv.next().await {
+      match msg {
+        ButtplugServerMessage::DeviceAdded(da) => first_index = Some(da.device_index()),
+        ButtplugServerMessage::ScanningFinished(_) => break,
+        _ => {}
+      }
+    }
+    let first_index = first_index.unwrap();
+    first_device.disconnect().await.unwrap();
+    while let Some(msg) = recv.next().await {
+      if let ButtplugServerMessage::DeviceRemoved(dr) = msg {
+        assert_eq!(dr.device_index(), first_index);
+        break;
+      }
+    }
+    helper
+      .add_ble_device_with_address("Massage Demo", "second-vivi")
+      .await;
+    server
+      .parse_message(messages::StartScanning::default().into())
+      .await
+      .unwrap();
+    while let Some(msg) = recv.next().await {
+      if let ButtplugServerMessage::DeviceAdded(da) = msg {
+        // The first device's index is free again, so the new device gets it.
+        assert_eq!(da.device_index(), first_index);
+        return;
+      }
+    }
+    panic!("Did not get DeviceAdded for the second device.");
+  });
+}
+
+#[test]
+fn test_stop_all_devices_scoped_to_commanded_devices() {
+  async_manager::block_on(async {
+    let mut options = ButtplugServerOptions::default();
+    options.stop_all_devices_scope = StopAllDevicesScope::CommandedDevices;
+    // Pin the indexes so we know which device is which.
+    options.user_device_configuration_json = Some(
+      r#"{ "protocols": {}, "reserved-indexes": { "commanded-vivi": 0, "idle-vivi": 1 } }"#
+        .to_owned(),
+    );
+    let server = ButtplugServer::new_with_options(&options).unwrap();
+    let recv = server.event_stream();
+    pin_mut!(recv);
+    let helper = server.add_test_comm_manager().unwrap();
+    let commanded_device = helper
+      .add_ble_device_with_address("Massage Demo", "commanded-vivi")
+      .await;
+    let idle_device = helper
+      .add_ble_device_with_address("Massage Demo", "idle-vivi")
+      .await;
+    server
+      .parse_message(
+        messages::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION)
+          .into(),
+      )
+      .await
+      .unwrap();
+    server
+      .parse_message(messages::StartScanning::default().into())
+      .await
+      .unwrap();
+    let mut devices_added = 0;
+    while let Some(msg) = recv.next().await {
+      if let ButtplugServerMessage::DeviceAdded(_) = msg {
+        devices_added += 1;
+        if devices_added == 2 {
+          break;
+        }
+      }
+    }
+    server
+      .parse_message(
+        messages::VibrateCmd::new(
+          0,
+          vec![
+            messages::VibrateSubcommand::new(0, 0.5),
+            messages::VibrateSubcommand::new(1, 0.5),
+          ],
+        )
+        .into(),
+      )
+      .await
+      .unwrap();
+    server
+      .parse_message(messages::StopAllDevices::default().into())
+      .await
+      .unwrap();
+    let commanded_receiver = commanded_device
+      .get_endpoint_receiver(&Endpoint::Tx)
+      .unwrap();
+    for command in [[0xF1, 64], [0xF2, 64], [0xF1, 0], [0xF2, 0]] {
+      check_test_recv_value(
+        &commanded_receiver,
+        DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, command.to_vec(), false)),
+      );
+    }
+    // The client never commanded the other device, so it shouldn't be touched.
+    let idle_receiver = idle_device.get_endpoint_receiver(&Endpoint::Tx).unwrap();
+    assert!(check_test_recv_empty(&idle_receiver));
+  });
+}
+
+#[test]
+fn test_stop_all_devices_scope_keeps_other_pressure_loops() {
+  async_manager::block_on(async {
+    let mut options = ButtplugServerOptions::default();
+    options.stop_all_devices_scope = StopAllDevicesScope::CommandedDevices;
+    options.user_device_configuration_json = Some(
+      r#"{ "protocols": {}, "reserved-indexes": { "commanded-vivi": 0, "looped-pearl": 1 } }"#
+        .to_owned(),
+    );
+    let server = ButtplugServer::new_with_options(&options).unwrap();
+    let recv = server.event_stream();
+    pin_mut!(recv);
+    let helper = server.add_test_comm_manager().unwrap();
+    helper
+      .add_ble_device_with_address("Massage Demo", "commanded-vivi")
+      .await;
+    let looped_device = helper
+      .add_ble_device_with_address("Pearl2", "looped-pearl")
+      .await;
+    server
+      .parse_message(
+        messages::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION)
+          .into(),
+      )
+      .await
+      .unwrap();
+    server
+      .parse_message(messages::StartScanning::default().into())
+      .await
+      .unwrap();
+    let mut devices_added = 0;
+    while let Some(msg) = recv.next().await {
+      if let ButtplugServerMessage::DeviceAdded(_) = msg {
+        devices_added += 1;
+        if devices_added == 2 {
+          break;
+        }
+      }
+    }
+    server
+      .parse_message(
+        messages::VibrateCmd::new(0, vec![messages::VibrateSubcommand::new(0, 0.5)]).into(),
+      )
+      .await
+      .unwrap();
+    server
+      .start_pressure_loop(PressureLoopConfig::new(1, 0, (0, 200)))
+      .await
+      .unwrap();
+    server
+      .parse_message(messages::StopAllDevices::default().into())
+      .await
+      .unwrap();
+    // The loop drives a device the client never commanded, so it keeps
+    // following the sensor.
+    looped_device.send_event(ButtplugDeviceEvent::Notification(
+      looped_device.address(),
+      Endpoint::RxTouch,
+      vec![100],
+    ));
+    Delay::new(Duration::from_millis(50)).await;
+    let looped_receiver = looped_device.get_endpoint_receiver(&Endpoint::Tx).unwrap();
+    check_test_recv_value(
+      &looped_receiver,
+      DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![50, 0, 0], false)),
+    );
+  });
+}
+
+#[test]
+fn test_power_off_devices() {
+  async_manager::block_on(async {
+    let server = ButtplugServer::default();
+    let recv = server.event_stream();
+    pin_mut!(recv);
+    let helper = server.add_test_comm_manager().unwrap();
+    let device = helper.add_ble_device("Massage Demo").await;
+    server
+      .parse_message(
+        messages::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION)
+          .into(),
+      )
+      .await
+      .unwrap();
+    server
+      .parse_message(messages::StartScanning::default().into())
+      .await
+      .unwrap();
+    while let Some(msg) = recv.next().await {
+      if let ButtplugServerMessage::DeviceAdded(_) = msg {
+        break;
+      }
+    }
+    // Aneros has no power off command.
+    assert!(matches!(
+      server.power_off_device(0).await,
+      Err(ButtplugError::ButtplugDeviceError(
+        ButtplugDeviceError::UnhandledCommand(_)
+      ))
+    ));
+    assert!(matches!(
+      server.power_off_device(1).await,
+      Err(ButtplugError::ButtplugDeviceError(
+        ButtplugDeviceError::DeviceNotAvailable(1)
+      ))
+    ));
+    // Devices that can't be powered off are stopped instead.
+    server.power_off_all_devices().await.unwrap();
+    let receiver = device.get_endpoint_receiver(&Endpoint::Tx).unwrap();
+    for command in [[0xF1, 0], [0xF2, 0]] {
+      check_test_recv_value(
+        &receiver,
+        DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, command.to_vec(), false)),
+      );
+    }
+  });
+}
+
+#[test]
+fn test_send_synchronized() {
+  async_manager::block_on(async {
+    let mut options = ButtplugServerOptions::default();
+    options.user_device_configuration_json = Some(
+      r#"{ "protocols": {}, "reserved-indexes": { "left-vivi": 0, "right-vivi": 1 } }"#
+        .to_owned(),
+    );
+    let server = ButtplugServer::new_with_options(&options).unwrap();
+    let recv = server.event_stream();
+    pin_mut!(recv);
+    let helper = server.add_test_comm_manager().unwrap();
+    let left_device = helper
+      .add_ble_device_with_address("Massage Demo", "left-vivi")
+      .await;
+    let right_device = helper
+      .add_ble_device_with_address("Massage Demo", "right-vivi")
+      .await;
+    server
+      .parse_message(
+        messages::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION)
+          .into(),
+      )
+      .await
+      .unwrap();
+    server
+      .parse_message(messages::StartScanning::default().into())
+      .await
+      .unwrap();
+    let mut devices_added = 0;
+    while let Some(msg) = recv.next().await {
+      if let ButtplugServerMessage::DeviceAdded(_) = msg {
+        devices_added += 1;
+        if devices_added == 2 {
+          break;
+        }
+      }
+    }
+    let vibrate = |index: u32, speed: f64| -> ButtplugDeviceCommandMessageUnion {
+      messages::VibrateCmd::new(index, vec![messages::VibrateSubcommand::new(0, speed)]).into()
+    };
+    // A set with a missing device, or two commands for one device, is
+    // refused without sending anything.
+    assert!(matches!(
+      server
+        .send_synchronized(vec![vibrate(0, 0.5), vibrate(2, 0.5)])
+        .await,
+      Err(ButtplugError::ButtplugDeviceError(
+        ButtplugDeviceError::DeviceNotAvailable(2)
+      ))
+    ));
+    assert!(server
+      .send_synchronized(vec![vibrate(0, 0.5), vibrate(0, 1.0)])
+      .await
+      .is_err());
+    assert!(server.send_synchronized(vec![]).await.is_err());
+    let left_receiver = left_device.get_endpoint_receiver(&Endpoint::Tx).unwrap();
+    let right_receiver = right_device.get_endpoint_receiver(&Endpoint::Tx).unwrap();
+    assert!(check_test_recv_empty(&left_receiver));
+    assert!(check_test_recv_empty(&right_receiver));
+    server
+      .send_synchronized(vec![vibrate(0, 0.5), vibrate(1, 1.0)])
+      .await
+      .unwrap();
+    check_test_recv_value(
+      &left_receiver,
+      DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![0xF1, 64], false)),
+    );
+    check_test_recv_value(
+      &right_receiver,
+      DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![0xF1, 127], false)),
+    );
+  });
+}
+
+#[test]
+fn test_emergency_stop() {
+  async_manager::block_on(async {
+    let server = ButtplugServer::default();
+    let recv = server.event_stream();
+    pin_mut!(recv);
+    let helper = server.add_test_comm_manager().unwrap();
+    let device = helper.add_ble_device("Massage Demo").await;
+    server
+      .parse_message(
+        messages::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION)
+          .into(),
+      )
+      .await
+      .unwrap();
+    server
+      .parse_message(messages::StartScanning::default().into())
+      .await
+      .unwrap();
+    while let Some(msg) = recv.next().await {
+      if let ButtplugServerMessage::DeviceAdded(_) = msg {
+        break;
+      }
+    }
+    server
+      .parse_message(
+        messages::VibrateCmd::new(
+          0,
+          vec![
+            messages::VibrateSubcommand::new(0, 0.5),
+            messages::VibrateSubcommand::new(1, 0.5),
+          ],
+        )
+        .into(),
+      )
+      .await
+      .unwrap();
+    // Panic buttons are usually watched from threads outside the runtime.
+    let emergency_stop = server.emergency_stop_handle();
+    std::thread::spawn(move || emergency_stop.trigger())
+      .join()
+      .unwrap();
+    Delay::new(Duration::from_millis(100)).await;
+    let receiver = device.get_endpoint_receiver(&Endpoint::Tx).unwrap();
+    for command in [[0xF1, 64], [0xF2, 64], [0xF1, 0], [0xF2, 0]] {
+      check_test_recv_value(
+        &receiver,
+        DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, command.to_vec(), false)),
+      );
+    }
+    assert!(check_test_recv_empty(&receiver));
+  });
+}
+
+#[test]
+fn test_scheduled_stop() {
+  async_manager::block_on(async {
+    let server = ButtplugServer::default();
+    let recv = server.event_stream();
+    pin_mut!(recv);
+    let helper = server.add_test_comm_manager().unwrap();
+    let device = helper.add_ble_device("Massage Demo").await;
+    server
+      .parse_message(
+        messages::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION)
+          .into(),
+      )
+      .await
+      .unwrap();
+    server
+      .parse_message(messages::StartScanning::default().into())
+      .await
+      .unwrap();
+    while let Some(msg) = recv.next().await {
+      if let ButtplugServerMessage::DeviceAdded(_) = msg {
+        break;
+      }
+    }
+    let vibrate_msg = messages::VibrateCmd::new(
+      0,
+      vec![
+        messages::VibrateSubcommand::new(0, 0.5),
+        messages::VibrateSubcommand::new(1, 0.5),
+      ],
+    );
+    server
+      .parse_message(vibrate_msg.clone().into())
+      .await
+      .unwrap();
+    let stop_events = server.scheduled_stop_event_stream();
+    pin_mut!(stop_events);
+    server.schedule_stop_after(Duration::from_millis(100), Duration::from_millis(50));
+    assert!(server.scheduled_stop_remaining().is_some());
+    assert!(matches!(
+      stop_events.next().await,
+      Some(ScheduledStopEvent::Warning(_))
+    ));
+    assert_eq!(stop_events.next().await, Some(ScheduledStopEvent::Stopped));
+    assert!(server.scheduled_stop_remaining().is_none());
+    let command_receiver = device.get_endpoint_receiver(&Endpoint::Tx).unwrap();
+    for command in [[0xF1, 64], [0xF2, 64], [0xF1, 0], [0xF2, 0]] {
+      check_test_recv_value(
+        &command_receiver,
+        DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, command.to_vec(), false)),
+      );
+    }
+    // Devices stay off until the application cancels the stop.
+    let err = server
+      .parse_message(vibrate_msg.clone().into())
+      .await
+      .unwrap_err();
+    assert!(matches!(
+      err.original_error(),
+      ButtplugError::ButtplugDeviceError(ButtplugDeviceError::DevicesStoppedBySchedule)
+    ));
+    assert!(check_test_recv_empty(&command_receiver));
+    server.cancel_scheduled_stop();
+    server.parse_message(vibrate_msg.into()).await.unwrap();
+    check_test_recv_value(
+      &command_receiver,
+      DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![0xF1, 64], false)),
+    );
+  });
+}
+
+const DEVICE_CONFIGURATION_JSON: &str =
+  include_str!("../buttplug-device-config/buttplug-device-config.json");
+
+/// Sets up a server where the Aneros protocol (used by the "Massage Demo"
+/// test device) has a short duty cycle limit, and connects a device to it.
+async fn setup_duty_cycle_server(
+  ignore_duty_cycle_limits: bool,
+) -> (ButtplugServer, Arc<TestDeviceInternal>) {
+  let mut config: serde_json::Value = serde_json::from_str(DEVICE_CONFIGURATION_JSON).unwrap();
+  config["protocols"]["aneros"]["duty-cycle"] = serde_json::json!({
+    "intensity": 0.5,
+    "max-continuous-seconds": 0.1,
+    "cooldown-seconds": 10
+  });
+  let mut options = ButtplugServerOptions::default();
+  options.device_configuration_json = Some(config.to_string());
+  options.ignore_duty_cycle_limits = ignore_duty_cycle_limits;
+  let server = ButtplugServer::new_with_options(&options).unwrap();
+  let recv = server.event_stream();
+  pin_mut!(recv);
+  let helper = server.add_test_comm_manager().unwrap();
+  let device = helper.add_ble_device("Massage Demo").await;
+  server
+    .parse_message(
+      messages::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION)
+        .into(),
+    )
+    .await
+    .unwrap();
+  server
+    .parse_message(messages::StartScanning::default().into())
+    .await
+    .unwrap();
+  while let Some(msg) = recv.next().await {
+    if let ButtplugServerMessage::DeviceAdded(_) = msg {
+      break;
+    }
+  }
+  (server, device)
+}
+
+fn vibrate_first_motor(speed: f64) -> ButtplugClientMessage {
+  messages::VibrateCmd::new(0, vec![messages::VibrateSubcommand::new(0, speed)]).into()
+}
+
+#[test]
+fn test_duty_cycle_derating() {
+  async_manager::block_on(async {
+    let (server, device) = setup_duty_cycle_server(false).await;
+    let command_receiver = device.get_endpoint_receiver(&Endpoint::Tx).unwrap();
+    server.parse_message(vibrate_first_motor(1.0)).await.unwrap();
+    check_test_recv_value(
+      &command_receiver,
+      DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![0xF1, 127], false)),
+    );
+    // Once the limit runs out, the server turns the motor down on its own.
+    Delay::new(Duration::from_millis(300)).await;
+    check_test_recv_value(
+      &command_receiver,
+      DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![0xF1, 64], false)),
+    );
+    // While cooling down, commands are capped at the limit's intensity.
+    server.parse_message(vibrate_first_motor(1.0)).await.unwrap();
+    assert!(check_test_recv_empty(&command_receiver));
+    server.parse_message(vibrate_first_motor(0.1)).await.unwrap();
+    check_test_recv_value(
+      &command_receiver,
+      DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![0xF1, 13], false)),
+    );
+  });
+}
+
+#[test]
+fn test_duty_cycle_ignored() {
+  async_manager::block_on(async {
+    let (server, device) = setup_duty_cycle_server(true).await;
+    let command_receiver = device.get_endpoint_receiver(&Endpoint::Tx).unwrap();
+    server.parse_message(vibrate_first_motor(1.0)).await.unwrap();
+    check_test_recv_value(
+      &command_receiver,
+      DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![0xF1, 127], false)),
+    );
+    Delay::new(Duration::from_millis(300)).await;
+    assert!(check_test_recv_empty(&command_receiver));
+  });
+}
+
+#[test]
+fn test_server_device_group() {
+  async_manager::block_on(async {
+    let server = ButtplugServer::default();
+    let recv = server.event_stream();
+    pin_mut!(recv);
+    server
+      .add_device_group(
+        "Vivi Pair",
+        &["first-vivi".to_owned(), "second-vivi".to_owned()],
+      )
+      .unwrap();
+    let helper = server.add_test_comm_manager().unwrap();
+    helper
+      .add_ble_device_with_address("Massage Demo", "first-vivi")
+      .await;
+    let second_device = helper
+      .add_ble_device_with_address("Massage Demo", "second-vivi")
+      .await;
+    server
+      .parse_message(
+        messages::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION)
+          .into(),
+      )
+      .await
+      .unwrap();
+    server
+      .parse_message(messages::StartScanning::default().into())
+      .await
+      .unwrap();
+    // The group device is rebuilt as each member connects, so wait until it
+    // shows all four vibrators.
+    let mut group_index = None;
+    while let Some(msg) = recv.next().await {
+      if let ButtplugServerMessage::DeviceAdded(da) = msg {
+        assert_eq!(da.device_name(), "Vivi Pair");
+        let vibrators = da
+          .device_messages()
+          .get(&ButtplugDeviceMessageType::VibrateCmd)
+          .unwrap()
+          .feature_count;
+        if vibrators == Some(4) {
+          group_index = Some(da.device_index());
+          break;
+        }
+      }
+    }
+    let group_index = group_index.unwrap();
+    assert!(server
+      .parse_message(
+        messages::VibrateCmd::new(group_index, vec![messages::VibrateSubcommand::new(3, 0.5)])
+          .into()
+      )
+      .await
+      .is_ok());
+    second_device.disconnect().await.unwrap();
+    while let Some(msg) = recv.next().await {
+      if let ButtplugServerMessage::DeviceAdded(da) = msg {
+        assert_eq!(da.device_index(), group_index);
+        assert_eq!(
+          da.device_messages()
+            .get(&ButtplugDeviceMessageType::VibrateCmd)
+            .unwrap()
+            .feature_count,
+          Some(2)
+        );
+        return;
+      }
+    }
+  });
+}
+
+#[test]
+fn test_server_device_filter() {
+  async_manager::block_on(async {
+    let mut options = ButtplugServerOptions::default();
+    options.device_filter = DeviceFilter::default().allow_protocol("aneros");
+    let server = ButtplugServer::new_with_options(&options).unwrap();
+    let recv = server.event_stream();
+    pin_mut!(recv);
+    let helper = server.add_test_comm_manager().unwrap();
+    helper.add_ble_device("Onyx+").await;
+    helper.add_ble_device("Massage Demo").await;
+    server
+      .parse_message(
+        messages::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION)
+          .into(),
+      )
+      .await
+      .unwrap();
+    server
+      .parse_message(messages::StartScanning::default().into())
+      .await
+      .unwrap();
+    while let Some(msg) = recv.next().await {
+      if let ButtplugServerMessage::DeviceAdded(da) = msg {
+        assert_eq!(da.device_name(), "Aneros Vivi");
+        break;
+      }
+    }
+    let device_list = server
+      .parse_message(messages::RequestDeviceList::default().into())
+      .await
+      .unwrap();
+    if let ButtplugServerMessage::DeviceList(list) = device_list {
+      assert!(list
+        .devices()
+        .iter()
+        .all(|device| device.device_name == "Aneros Vivi"));
+    } else {
+      panic!("Expected a DeviceList message.");
+    }
+  });
+}
+
+#[test]
+fn test_server_sensor_reading() {
+  async_manager::block_on(async {
+    let server = ButtplugServer::default();
+    let recv = server.event_stream();
+    pin_mut!(recv);
+    let helper = server.add_test_comm_manager().unwrap();
+    let device = helper.add_ble_device("Pearl2").await;
+    server
+      .parse_message(
+        messages::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION)
+          .into(),
+      )
+      .await
+      .unwrap();
+    server
+      .parse_message(messages::StartScanning::default().into())
+      .await
+      .unwrap();
+    let mut device_index = None;
+    while let Some(msg) = recv.next().await {
+      if let ButtplugServerMessage::DeviceAdded(da) = msg {
+        assert!(da
+          .device_messages()
+          .contains_key(&ButtplugDeviceMessageType::SensorSubscribeCmd));
+        device_index = Some(da.device_index());
+        break;
+      }
+    }
+    let device_index = device_index.unwrap();
+    server
+      .parse_message(messages::SensorSubscribeCmd::new(device_index, 0).into())
+      .await
+      .unwrap();
+    device.send_event(ButtplugDeviceEvent::Notification(
+      device.address(),
+      Endpoint::RxTouch,
+      vec![1, 0],
+    ));
+    while let Some(msg) = recv.next().await {
+      if let ButtplugServerMessage::SensorReading(reading) = msg {
+        assert_eq!(
+          reading,
+          messages::SensorReading::new(device_index, 0, messages::SensorType::Pressure, vec![1, 0])
+        );
+        return;
+      }
+    }
+    panic!("Did not receive sensor reading.");
+  });
+}
@@ -9,7 +9,7 @@ mod websocket_connector_tests {
       ButtplugWebsocketClientTransport, ButtplugWebsocketServerTransport,
       ButtplugWebsocketServerTransportOptions,
     },
-    core::messages::serializer::{ButtplugClientJSONSerializer, ButtplugServerJSONSerializer},
+    core::messages::serializer::ButtplugClientJSONSerializer,
     server::ButtplugRemoteServer,
     util::async_manager,
   };
@@ -24,13 +24,11 @@ mod websocket_connector_tests {
       let server = Arc::new(test_server);
       let server_clone = server.clone();
       async_manager::spawn(async move {
-        let connector = ButtplugRemoteServerConnector::<
-          ButtplugWebsocketServerTransport,
-          ButtplugServerJSONSerializer,
-        >::new(ButtplugWebsocketServerTransport::new(
+        let connector = ButtplugRemoteServerConnector::new(ButtplugWebsocketServerTransport::new(
           ButtplugWebsocketServerTransportOptions {
             ws_listen_on_all_interfaces: false,
             ws_insecure_port: 12345u16,
+            ..Default::default()
           },
         ));
         server_clone.start(connector).await.unwrap();
@@ -64,12 +62,9 @@ mod websocket_connector_tests {
       let server = Arc::new(test_server);
       let server_clone = server.clone();
       async_manager::spawn(async move {
-        let connector = ButtplugRemoteServerConnector::<
-          ButtplugWebsocketClientTransport,
-          ButtplugServerJSONSerializer,
-        >::new(ButtplugWebsocketClientTransport::new_insecure_connector(
-          "ws://127.0.0.1:12347",
-        ));
+        let connector = ButtplugRemoteServerConnector::new(
+          ButtplugWebsocketClientTransport::new_insecure_connector("ws://127.0.0.1:12347"),
+        );
         server_clone.start(connector).await.unwrap();
       })
       .unwrap();
@@ -83,6 +78,7 @@ mod websocket_connector_tests {
           ButtplugWebsocketServerTransportOptions {
             ws_listen_on_all_interfaces: false,
             ws_insecure_port: 12347u16,
+            ..Default::default()
           },
         ));
 
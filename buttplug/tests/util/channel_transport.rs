@@ -12,8 +12,8 @@ use buttplug::{
     serializer::{
       ButtplugClientJSONSerializer, ButtplugSerializedMessage, ButtplugServerJSONSerializer,
     },
-    ButtplugClientMessage, ButtplugCurrentSpecClientMessage, ButtplugMessage,
-    ButtplugServerMessage, BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION,
+    ButtplugClientMessage, ButtplugCurrentSpecClientMessage, ButtplugCurrentSpecServerMessage,
+    ButtplugMessage, ButtplugServerMessage, BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION,
   },
   server::ButtplugRemoteServer,
   util::async_manager,
@@ -218,9 +218,7 @@ pub struct ChannelServerTestHelper {
   server: Arc<ButtplugRemoteServer>,
   sender: Sender<ButtplugTransportIncomingMessage>,
   receiver: Arc<Mutex<Receiver<ButtplugSerializedMessage>>>,
-  connector: Arc<
-    Mutex<Option<ButtplugRemoteServerConnector<ChannelTransport, ButtplugServerJSONSerializer>>>,
-  >,
+  connector: Arc<Mutex<Option<ButtplugRemoteServerConnector<ChannelTransport>>>>,
   server_serializer: ButtplugServerJSONSerializer,
   client_serializer: ButtplugClientJSONSerializer,
 }
@@ -230,13 +228,9 @@ impl ChannelServerTestHelper {
     let server = Arc::new(ButtplugRemoteServer::default());
     let (incoming_sender, incoming_receiver) = channel(256);
     let (outgoing_sender, outgoing_receiver) = channel(256);
-    let connector = Arc::new(Mutex::new(Some(ButtplugRemoteServerConnector::<
-      ChannelTransport,
-      ButtplugServerJSONSerializer,
-    >::new(ChannelTransport::new(
-      incoming_receiver,
-      outgoing_sender,
-    )))));
+    let connector = Arc::new(Mutex::new(Some(ButtplugRemoteServerConnector::new(
+      ChannelTransport::new(incoming_receiver, outgoing_sender),
+    ))));
     let client_serializer = ButtplugClientJSONSerializer::default();
     let server_serializer = ButtplugServerJSONSerializer::default();
     Self {
@@ -253,6 +247,25 @@ impl ChannelServerTestHelper {
     &self.server
   }
 
+  pub async fn start(&self) {
+    let connector = self.connector.lock().await.take().unwrap();
+    let server = self.server.clone();
+    async_manager::spawn(async move {
+      if let Err(e) = server.start(connector).await {
+        assert!(false, "Error starting server: {:?}", e);
+      }
+    })
+    .unwrap();
+  }
+
+  pub async fn get_next_server_message(&self) -> ButtplugCurrentSpecServerMessage {
+    self
+      .client_serializer
+      .deserialize(self.recv_outgoing().await.unwrap())
+      .unwrap()[0]
+      .clone()
+  }
+
   pub async fn recv_outgoing(&self) -> Option<ButtplugSerializedMessage> {
     // If this ever conflicts, its the tests fault, so just panic.
     self.receiver.try_lock().unwrap().recv().await